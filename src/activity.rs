@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+use crate::doctor::unescape_mount_field;
+use crate::storage::ScannedFile;
+
+pub const DEFAULT_BURST_FILES: usize = 5;
+pub const DEFAULT_BURST_WINDOW_SEC: u64 = 3600;
+
+// File heat for mounts where neither atimes nor fanotify say when files
+// are read, as in unprivileged containers, from what was written instead:
+//   "access_tracking": "activity",
+//   "activity_heat": { "burst_files": 5, "burst_window_sec": 3600 }
+// A file counts as read when it was last written. A directory that had
+// burst_files or more written within burst_window_sec of its latest write,
+// like a season folder new episodes land in, is in use, and every file in
+// it counts as read at that latest write.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActivityHeat {
+    pub burst_files: usize,
+    pub burst_window: Duration,
+}
+
+impl Default for ActivityHeat {
+    fn default() -> Self {
+        Self { burst_files: DEFAULT_BURST_FILES, burst_window: Duration::from_secs(DEFAULT_BURST_WINDOW_SEC) }
+    }
+}
+
+impl ActivityHeat {
+    pub fn from_config(config: &Value) -> Self {
+        let Some(section) = config.get("activity_heat") else { return Self::default() };
+        Self {
+            burst_files: section.get("burst_files").and_then(Value::as_u64).map_or(DEFAULT_BURST_FILES, |files| files.max(1) as usize),
+            burst_window: Duration::from_secs(section.get("burst_window_sec").and_then(Value::as_u64).unwrap_or(DEFAULT_BURST_WINDOW_SEC)),
+        }
+    }
+
+    // Replace each scanned file's atime with when it counts as read
+    pub fn apply(&self, files: &mut [ScannedFile]) {
+        let mut writes: HashMap<PathBuf, Vec<SystemTime>> = HashMap::new();
+        for file in files.iter() {
+            writes.entry(parent(&file.path)).or_default().push(file.modified);
+        }
+        let active: HashMap<PathBuf, SystemTime> = writes.into_iter().filter_map(|(dir, times)| {
+            let latest = times.iter().max().copied()?;
+            let recent = times.iter().filter(|time| latest.duration_since(**time).unwrap_or_default() <= self.burst_window).count();
+            (recent >= self.burst_files).then_some((dir, latest))
+        }).collect();
+        for file in files.iter_mut() {
+            file.accessed = active.get(&parent(&file.path)).map_or(file.modified, |latest| file.modified.max(*latest));
+        }
+    }
+}
+
+fn parent(path: &Path) -> PathBuf {
+    path.parent().unwrap_or(Path::new("")).to_path_buf()
+}
+
+// Whether every one of `mounts` is mounted noatime, going by `proc_mounts`,
+// the content of /proc/self/mounts, so atimes say nothing about reads
+pub fn noatime(proc_mounts: &str, mounts: &[PathBuf]) -> bool {
+    let options: HashMap<PathBuf, &str> = proc_mounts.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields.len() >= 4).then(|| (PathBuf::from(unescape_mount_field(fields[1])), fields[3]))
+    }).collect();
+    !mounts.is_empty() && mounts.iter().all(|mount| options.get(mount).is_some_and(|options| options.split(',').any(|option| option == "noatime")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::placement::Placement;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+
+    fn file(path: &str, modified: u64) -> ScannedFile {
+        ScannedFile {
+            path: path.into(), tier: "cold".to_string(), branch: "WD-1".to_string(), accessed: UNIX_EPOCH,
+            modified: UNIX_EPOCH + Duration::from_secs(modified), size: 1, placement: Placement::default(), hardlink: None,
+        }
+    }
+
+    #[test]
+    fn test_activity_heat() {
+        let heat = ActivityHeat::from_config(&json!({ "activity_heat": { "burst_files": 3, "burst_window_sec": 100 } }));
+        let mut files = vec![
+            file("tv/show/e01.mkv", 1000), file("tv/show/e02.mkv", 5000), file("tv/show/e03.mkv", 5050), file("tv/show/e04.mkv", 5080),
+            file("movies/a.mkv", 2000), file("movies/b.mkv", 9000),
+        ];
+        heat.apply(&mut files);
+        let accessed: Vec<u64> = files.iter().map(|file| file.accessed.duration_since(UNIX_EPOCH).unwrap().as_secs()).collect();
+        // three episodes landed together, so the whole season is warm
+        assert_eq!(accessed, [5080, 5080, 5080, 5080, 2000, 9000]);
+        assert_eq!(ActivityHeat::from_config(&json!({})), ActivityHeat::default());
+    }
+
+    #[test]
+    fn test_noatime() {
+        let mounts = "pool /mnt/pool/hot fuse.mergerfs rw,noatime,user_id=0 0 0\npool /mnt/pool/cold\\040tier fuse.mergerfs rw,relatime 0 0\n";
+        assert!(noatime(mounts, &[PathBuf::from("/mnt/pool/hot")]));
+        assert!(!noatime(mounts, &[PathBuf::from("/mnt/pool/hot"), PathBuf::from("/mnt/pool/cold tier")]));
+        assert!(!noatime(mounts, &[]));
+    }
+}
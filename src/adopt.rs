@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use serde_json::{json, Map, Value};
+use crate::config::SCHEMA_VERSION;
+use crate::doctor::unescape_mount_field;
+use crate::drive_manager::DriveManager;
+use crate::grouping::glob_match;
+
+// the conf.d file `drive-manager adopt` writes its drives to
+pub const ADOPTED_FILE: &str = "50-adopted.json";
+
+// A drive from an existing mergerfs setup, used where it is already mounted:
+//   "adopted_drives": { "WD-123": { "mountpoint": "/mnt/disk1", "tier": "cold" } }
+// `drive-manager adopt` writes these. Adopted drives are never mounted or
+// formatted, and sit in the tier they were given rather than the one their
+// class would put them in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdoptedDrive {
+    pub serial: String,
+    pub mountpoint: PathBuf,
+    pub tier: String,
+}
+
+pub fn adopted_drives(config: &Value) -> Vec<AdoptedDrive> {
+    let Some(drives) = config.get("adopted_drives").and_then(Value::as_object) else { return Vec::new() };
+    drives.iter().filter_map(|(serial, drive)| Some(AdoptedDrive {
+        serial: serial.clone(),
+        mountpoint: PathBuf::from(drive.get("mountpoint").and_then(Value::as_str)?),
+        tier: drive.get("tier").and_then(Value::as_str).filter(|tier| DriveManager::TIERS.contains(tier))?.to_string(),
+    })).collect()
+}
+
+// The tier of each adopted drive, by serial
+pub fn drive_tiers(config: &Value) -> HashMap<String, String> {
+    adopted_drives(config).into_iter().map(|drive| (drive.serial, drive.tier)).collect()
+}
+
+// The conf.d fragment recording `drives`
+pub fn fragment(drives: &[AdoptedDrive]) -> Value {
+    let drives: Map<String, Value> = drives.iter()
+        .map(|drive| (drive.serial.clone(), json!({ "mountpoint": drive.mountpoint, "tier": drive.tier })))
+        .collect();
+    json!({ "schema_version": SCHEMA_VERSION, "adopted_drives": drives })
+}
+
+// The branches of the mergerfs mount at `mount` as written, from its
+// /etc/fstab entry, in either of
+//   /mnt/disk*:/mnt/ssd=NC  /mnt/storage  fuse.mergerfs  defaults  0 0
+//   mergerfs#/mnt/disk1:/mnt/disk2  /mnt/storage  fuse  defaults  0 0
+// or from /proc/mounts when fstab has none. mergerfs shortens the source
+// it shows there unless fsname is set, so only absolute branches count.
+pub fn branch_specs(fstab: &str, mounts: &str, mount: &Path) -> Option<Vec<String>> {
+    let entry = |table: &str| table.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0].starts_with('#') || Path::new(&unescape_mount_field(fields[1])) != mount {
+            return None;
+        }
+        let source = unescape_mount_field(fields[0]);
+        match fields[2] {
+            "fuse.mergerfs" => Some(source),
+            "fuse" => source.strip_prefix("mergerfs#").map(str::to_string),
+            _ => None,
+        }
+    });
+    let specs = |source: String| -> Vec<String> {
+        source.split(':').map(|branch| branch.split('=').next().unwrap_or_default().to_string()).filter(|branch| !branch.is_empty()).collect()
+    };
+    entry(fstab).map(specs).or_else(|| entry(mounts).map(specs).filter(|specs| specs.iter().all(|spec| spec.starts_with('/'))))
+}
+
+// The directories a branch spec names, its * and ? patterns expanded the
+// way mergerfs does
+pub fn expand(spec: &str) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("/")];
+    for component in Path::new(spec).components() {
+        let Component::Normal(name) = component else { continue };
+        let pattern = name.as_bytes();
+        if !pattern.contains(&b'*') && !pattern.contains(&b'?') {
+            paths = paths.into_iter().map(|path| path.join(name)).filter(|path| path.is_dir()).collect();
+            continue;
+        }
+        paths = paths.iter().flat_map(|dir| {
+            let mut matches: Vec<PathBuf> = fs::read_dir(dir).map(|entries| {
+                entries.filter_map(Result::ok)
+                    .filter(|entry| glob_match(pattern, entry.file_name().as_bytes()))
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_dir())
+                    .collect()
+            }).unwrap_or_default();
+            matches.sort();
+            matches
+        }).collect();
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_branch_specs() {
+        let fstab = "# /mnt/old  /mnt/storage  fuse.mergerfs  defaults 0 0\n\
+                     /dev/sdb1  /mnt/disk1  xfs  defaults  0 0\n\
+                     /mnt/disk*:/mnt/ssd=NC,100G  /mnt/storage  fuse.mergerfs  defaults  0 0\n\
+                     mergerfs#/mnt/a:/mnt/b  /mnt/old\\040pool  fuse  defaults  0 0\n";
+        assert_eq!(branch_specs(fstab, "", Path::new("/mnt/storage")).unwrap(), ["/mnt/disk*", "/mnt/ssd"]);
+        assert_eq!(branch_specs(fstab, "", Path::new("/mnt/old pool")).unwrap(), ["/mnt/a", "/mnt/b"]);
+        assert_eq!(branch_specs(fstab, "", Path::new("/mnt/disk1")), None);
+        let mounts = "/mnt/disk1:/mnt/disk2 /mnt/pool fuse.mergerfs rw 0 0\ndisk1:disk2 /mnt/short fuse.mergerfs rw 0 0\n";
+        assert_eq!(branch_specs("", mounts, Path::new("/mnt/pool")).unwrap(), ["/mnt/disk1", "/mnt/disk2"]);
+        assert_eq!(branch_specs("", mounts, Path::new("/mnt/short")), None);
+    }
+
+    #[test]
+    fn test_expand() {
+        let dir = tempdir().unwrap();
+        for name in ["disk2", "disk1", "ssd"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        fs::write(dir.path().join("disk.txt"), "").unwrap();
+        let root = dir.path().display();
+        assert_eq!(expand(&format!("{}/disk*", root)), [dir.path().join("disk1"), dir.path().join("disk2")]);
+        assert_eq!(expand(&format!("{}/ssd", root)), [dir.path().join("ssd")]);
+        assert!(expand(&format!("{}/missing", root)).is_empty());
+    }
+
+    #[test]
+    fn test_adopted_drives() {
+        let drive = AdoptedDrive { serial: "WD-1".to_string(), mountpoint: PathBuf::from("/mnt/disk1"), tier: "warm".to_string() };
+        let config = fragment(std::slice::from_ref(&drive));
+        assert_eq!(adopted_drives(&config), [drive]);
+        assert_eq!(drive_tiers(&config)["WD-1"], "warm");
+        assert!(adopted_drives(&json!({ "adopted_drives": { "WD-2": { "mountpoint": "/mnt/x", "tier": "tepid" } } })).is_empty());
+    }
+}
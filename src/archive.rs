@@ -0,0 +1,186 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const ARCHIVE_TIER: &str = "archive";
+// uploaded per check unless archive.max_gb_per_check says otherwise
+pub const MAX_GB_PER_CHECK: u64 = 100;
+// the first line of every stub; the rest is the Stub as JSON
+const STUB_HEADER: &str = "# drive-manager archive stub: read this file and the next promotion check fetches it back";
+// a stub is never bigger than this, so larger files are not read to check
+const MAX_STUB_BYTES: u64 = 4096;
+
+// The archive tier below cold, in S3-compatible object storage:
+//   "archive": { "bucket": "nas-archive", "prefix": "drive-manager/", "after_days": 365,
+//                "endpoint": "https://s3.example.com", "region": "us-east-1",
+//                "storage_class": "DEEP_ARCHIVE", "max_gb_per_check": 100 }
+// Files on cold that nobody read for after_days are uploaded by demotion
+// checks and replaced by a small stub, and a stub that is read is fetched
+// back onto cold by the next promotion check. Transfers go through the aws
+// CLI, which finds its credentials the usual way: the environment,
+// ~/.aws or an instance role.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Archive {
+    pub bucket: String,
+    pub prefix: String,
+    pub after: Duration,
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub storage_class: Option<String>,
+    pub max_bytes_per_check: u64,
+}
+
+// What a stub says about the file it stands in for. The checksum is taken
+// before the upload, for what comes back to be checked against; stubs left
+// before there was one only have the size to go on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stub {
+    pub url: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<StubChecksum>,
+}
+
+// A checksum is only comparable with one taken in segments of the same size
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StubChecksum {
+    pub algorithm: String,
+    pub segment_bytes: u64,
+    pub value: String,
+}
+
+impl Archive {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("archive")?;
+        let string = |key: &str| section.get(key).and_then(Value::as_str).map(str::to_string);
+        Some(Self {
+            bucket: string("bucket")?,
+            prefix: string("prefix").unwrap_or_default(),
+            after: Duration::from_secs_f64(section.get("after_days").and_then(Value::as_f64).unwrap_or(365.0).max(0.0) * 86400.0),
+            endpoint: string("endpoint"),
+            region: string("region"),
+            storage_class: string("storage_class"),
+            max_bytes_per_check: section.get("max_gb_per_check").and_then(Value::as_u64).unwrap_or(MAX_GB_PER_CHECK) << 30,
+        })
+    }
+
+    // Where the file at `path`, relative to the pool, is kept. Object keys
+    // are text, so a name that is not UTF-8 cannot be archived.
+    pub fn url(&self, path: &Path) -> io::Result<String> {
+        let path = path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not UTF-8, which an object key must be", path.display())))?;
+        Ok(format!("s3://{}/{}{}", self.bucket, self.prefix, path.trim_start_matches('/')))
+    }
+
+    fn aws(&self) -> Vec<OsString> {
+        let mut cmd: Vec<OsString> = vec!["aws".into()];
+        if let Some(endpoint) = &self.endpoint {
+            cmd.extend(["--endpoint-url".into(), endpoint.into()]);
+        }
+        if let Some(region) = &self.region {
+            cmd.extend(["--region".into(), region.into()]);
+        }
+        cmd.extend(["s3".into(), "cp".into(), "--only-show-errors".into()]);
+        cmd
+    }
+
+    pub fn upload_command(&self, local: &Path, url: &str) -> Vec<OsString> {
+        let mut cmd = self.aws();
+        if let Some(class) = &self.storage_class {
+            cmd.extend(["--storage-class".into(), class.into()]);
+        }
+        cmd.extend([local.into(), url.into()]);
+        cmd
+    }
+
+    pub fn download_command(&self, url: &str, local: &Path) -> Vec<OsString> {
+        let mut cmd = self.aws();
+        cmd.extend([url.into(), local.into()]);
+        cmd
+    }
+}
+
+pub fn stub_contents(stub: &Stub) -> io::Result<String> {
+    Ok(format!("{}\n{}\n", STUB_HEADER, serde_json::to_string(stub)?))
+}
+
+// Write `stub` to `path` and sync it, so it is on disk before it is
+// renamed over the file it stands in for
+pub fn write_stub(path: &Path, stub: &Stub) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(stub_contents(stub)?.as_bytes())?;
+    file.sync_all()
+}
+
+// The stub at `path`, or None when it is an ordinary file
+pub fn read_stub(path: &Path) -> io::Result<Option<Stub>> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_file() || metadata.len() > MAX_STUB_BYTES {
+        return Ok(None);
+    }
+    let mut lines = BufReader::new(fs::File::open(path)?.take(MAX_STUB_BYTES)).lines();
+    if lines.next().transpose()?.as_deref() != Some(STUB_HEADER) {
+        return Ok(None);
+    }
+    let stub = lines.next().transpose()?.unwrap_or_default();
+    serde_json::from_str(&stub).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{} is a damaged archive stub: {}", path.display(), e)))
+}
+
+// A name beside `file` to stage a stub or a download under before it is
+// renamed over the file
+pub fn staging_path(file: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(file.file_name().unwrap_or_default());
+    name.push(".drive-manager-archive");
+    file.with_file_name(name)
+}
+
+// Give `to` the owner and mode of `from`
+pub fn copy_ownership(from: &fs::Metadata, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::chown(to, Some(from.uid()), Some(from.gid()))?;
+    fs::set_permissions(to, from.permissions())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::ffi::OsStrExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(Archive::from_config(&json!({ "archive": {} })), None);
+        let archive = Archive::from_config(&json!({ "archive": { "bucket": "nas", "prefix": "dm/", "after_days": 30, "endpoint": "https://s3.local", "storage_class": "GLACIER" } })).unwrap();
+        assert_eq!((archive.after, archive.max_bytes_per_check), (Duration::from_secs(30 * 86400), 100 << 30));
+        let url = archive.url(Path::new("tv/a b.mkv")).unwrap();
+        assert_eq!(url, "s3://nas/dm/tv/a b.mkv");
+        assert_eq!(archive.upload_command(Path::new("/mnt/physical/hdd/WD-1/tv/a b.mkv"), &url), [
+            "aws", "--endpoint-url", "https://s3.local", "s3", "cp", "--only-show-errors", "--storage-class", "GLACIER", "/mnt/physical/hdd/WD-1/tv/a b.mkv", "s3://nas/dm/tv/a b.mkv",
+        ]);
+        assert!(archive.download_command(&url, Path::new("/tmp/x")).ends_with(&["s3://nas/dm/tv/a b.mkv".into(), "/tmp/x".into()]));
+        let invalid = PathBuf::from(std::ffi::OsStr::from_bytes(b"\xff.mkv"));
+        assert!(archive.url(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_stub() {
+        let dir = tempdir().unwrap();
+        let stub = Stub { url: "s3://nas/a.mkv".to_string(), size: 1 << 40, checksum: Some(StubChecksum { algorithm: "xxh3".to_string(), segment_bytes: 256 << 20, value: "ab12".to_string() }) };
+        let path = dir.path().join("a.mkv");
+        write_stub(&path, &stub).unwrap();
+        assert_eq!(read_stub(&path).unwrap(), Some(stub));
+        // left before stubs had checksums
+        fs::write(&path, format!("{}\n{{\"url\":\"s3://nas/a.mkv\",\"size\":9}}\n", STUB_HEADER)).unwrap();
+        assert_eq!(read_stub(&path).unwrap(), Some(Stub { url: "s3://nas/a.mkv".to_string(), size: 9, checksum: None }));
+        fs::write(&path, "test data").unwrap();
+        assert_eq!(read_stub(&path).unwrap(), None);
+        fs::write(&path, format!("{}\nnot json\n", STUB_HEADER)).unwrap();
+        assert_eq!(read_stub(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(staging_path(&path), dir.path().join(".a.mkv.drive-manager-archive"));
+    }
+}
@@ -0,0 +1,429 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use crate::control::ControlCommand;
+use crate::drive_manager::DriveManager;
+use crate::exit_code::{CliError, ErrorKind};
+
+pub const CONFIG_FILE_PATH: &str = "/etc/drive-manager/config.json";
+pub const IO_THREADS: usize = 4;
+
+pub const USAGE: &str = "\
+Utility to format, mount and pool drives together.
+
+Usage: drive-manager [OPTIONS] [COMMAND]
+
+Commands:
+  run                      Discover, mount and pool drives, then run tiering (default)
+  plan                     Run one tiering check as --observe would and list each move it would make and why, then exit
+  adopt <MOUNT>            Take over the drives of an existing mergerfs mount where they are mounted
+  generate systemd|nixos   Print a systemd service unit or NixOS module for this config
+  failures [list]          List moves waiting to be retried and moves that failed after all retries
+  failures retry           Ask the running service to retry every failed move
+  failures drop <PATH>     Give up on a failed or retrying move
+  problem-files [list]     List files quarantined after repeated read or checksum errors, with their error history
+  problem-files clear <PATH>
+                           Let automatic moves have a quarantined file again
+  status [TIER]            Show each tier's files, drives and copying moves, or just one tier's
+  list-drives              List every drive with its class, tier, mount point, temperature and SMART health
+  check-now                Ask the running service for a tiering check now
+  drives                   List every drive the service has set up, with its tier and state
+  drives reset <SERIAL>    Put a quarantined or retired drive back to use
+  writes                   Show what each drive has written, flag drives taking most of their tier's writes and how to even them out
+  drain <SERIAL>           Have the running service move everything off a drive onto its tier's others, then unmount it
+  maintenance <SERIAL> --enable|--disable
+                           Have the running service take a drive out of the pools and unmount it, or bring it back
+  ctl pause|resume         Hold moves on the running service, or let them go again
+  ctl run-tiering|reload   Have the running service run a tiering check or reload its config now
+  pin <PATH> <TIER>        Keep a file, directory or glob in the pool on a tier
+  unpin <PATH>             Drop a pin made with pin
+  pins                     List pins from the config and from pin
+  reload-config            Check the config, show what changed and have the running service restart on it
+  job start <NAME>         Pause tiering on an external job's drives once jobs sharing them and copying moves finish
+  job finish <NAME>        Resume tiering after an external job
+  jobs                     List external jobs that tiering is paused for
+  stage <NAME> <PATH>...   Reserve room on the hot tier for a scheduler's input files and have the service move them there
+  release <NAME>           Let a staging's files go back to the tiering rules
+  stagings                 List stagings with what they reserve and how many of their files are on hot
+  history [HOURS]          Show what the service did in the last HOURS (default 24), from the event log
+  doctor                   Check mounts, tools, the database and stuck moves, with fixes
+
+Options:
+      --dryrun             Run in dryrun mode. Does not format or mount drives, just prints actions
+      --observe            Discover drives, scan and report what tiering would do, without mounting, formatting or moving
+      --simulate           Run the tiering policies against in-memory drives over simulated time
+  -c, --config <FILE>      Override config file (TOML, YAML or JSON). Default: /etc/drive-manager/config.json
+                           *.toml, *.yaml and *.json files in conf.d beside it are merged in by name
+                           Values \"@file:PATH\" and \"@keyring:KEY\" are read from a file or secret-tool
+  -p, --profile <NAME>     Apply the config's profiles.<NAME> section over the rest
+      --write-config       Save a config in an older layout back upgraded, keeping a .bak copy
+      --force              Let reload-config apply changes that would format or remount drives
+      --allow-bulk-format  Format more than max_formats_per_run (default 1) new drives in one run
+      --assume-yes         Format drives without asking, even ones holding a filesystem or a RAID or LVM signature
+  -t, --threads <N>        Rsync threads per tier move lane unless move_workers overrides it. Default: 4
+  -h, --help               Print this help
+
+Exit codes (the last line of stderr is a JSON object with the same \"error\" and \"code\"):
+  1 failure  2 usage  3 config  4 permission  5 drive_conflict  6 partial_failure  7 database";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GenerateTarget {
+    Systemd,
+    Nixos,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Run,
+    Plan,
+    Adopt(PathBuf),
+    Generate(GenerateTarget),
+    Failures,
+    RetryFailures,
+    DropFailure(PathBuf),
+    ProblemFiles,
+    ClearProblemFile(PathBuf),
+    Status(Option<String>),
+    ListDrives,
+    Drives,
+    ResetDrive(String),
+    Writes,
+    CheckNow,
+    Drain(String),
+    // the drive's serial, and whether to take it out or bring it back
+    Maintenance(String, bool),
+    Ctl(ControlCommand),
+    Pin(PathBuf, String),
+    Unpin(PathBuf),
+    Pins,
+    ReloadConfig,
+    JobStart(String),
+    JobFinish(String),
+    Jobs,
+    // a staging's name and the files or directories it holds on hot
+    Stage(String, Vec<PathBuf>),
+    Release(String),
+    Stagings,
+    // hours back to show events from
+    History(u64),
+    Doctor,
+    Help,
+}
+
+#[derive(Clone, Debug)]
+pub struct Args {
+    pub dryrun: bool,
+    // use the drives where they are already mounted and only report what
+    // would be mounted, formatted or moved
+    pub observe: bool,
+    pub simulate: bool,
+    pub config: String,
+    // a section of the config's "profiles" to apply over the rest
+    pub profile: Option<String>,
+    // save the config file back when it was upgraded from an older schema
+    pub write_config: bool,
+    // lift the limit on how many drives one run formats
+    pub allow_bulk_format: bool,
+    // format without asking, whatever the drive holds
+    pub assume_yes: bool,
+    // let reload-config through when the change is destructive
+    pub force: bool,
+    pub threads: usize,
+    pub command: Command,
+}
+
+impl Args {
+    pub fn parse() -> Self {
+        match Self::parse_from(std::env::args_os().skip(1)) {
+            Ok(args) => args,
+            Err(e) => {
+                eprintln!("{}\n", USAGE);
+                CliError::new(ErrorKind::Usage, e).exit();
+            }
+        }
+    }
+
+    pub fn parse_from<I, S>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let mut dryrun = false;
+        let mut observe = false;
+        let mut simulate = false;
+        let mut config = CONFIG_FILE_PATH.to_string();
+        let mut profile = None;
+        let mut write_config = false;
+        let mut allow_bulk_format = false;
+        let mut assume_yes = false;
+        let mut force = false;
+        let mut threads = IO_THREADS;
+        let mut positional = Vec::new();
+
+        // paths given as operands need not be UTF-8; options and names must
+        let mut iter = args.into_iter().map(Into::into);
+        while let Some(arg) = iter.next() {
+            let text = arg.to_string_lossy().into_owned();
+            let (flag, inline_value) = match text.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (text.clone(), None),
+            };
+            let mut value = |name: &str| match inline_value.clone() {
+                Some(value) => Ok(value),
+                None => iter.next().ok_or(format!("missing value for {}", name))?
+                    .into_string().map_err(|value| format!("{} is not valid UTF-8: {}", name, value.to_string_lossy())),
+            };
+            match flag.as_str() {
+                "--dryrun" => dryrun = true,
+                "--observe" => observe = true,
+                "--simulate" => simulate = true,
+                "-c" | "--config" => config = value("--config")?,
+                "-p" | "--profile" => profile = Some(value("--profile")?),
+                "--write-config" => write_config = true,
+                "--allow-bulk-format" => allow_bulk_format = true,
+                "--assume-yes" => assume_yes = true,
+                "--force" => force = true,
+                "-t" | "--threads" => {
+                    threads = value("--threads")?.parse().map_err(|e| format!("invalid --threads: {}", e))?
+                }
+                // maintenance's, which say what to do rather than how
+                "--enable" | "--disable" => positional.push(arg),
+                "-h" | "--help" => return Ok(Self { dryrun, observe, simulate, config, profile, write_config, allow_bulk_format, assume_yes, force, threads, command: Command::Help }),
+                _ if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+                _ => positional.push(arg),
+            }
+        }
+
+        let command = Command::from_positional(&positional)?;
+        Ok(Self { dryrun, observe, simulate, config, profile, write_config, allow_bulk_format, assume_yes, force, threads, command })
+    }
+}
+
+impl Command {
+    // Path operands are taken as given; everything else is matched as text
+    fn from_positional(positional: &[OsString]) -> Result<Self, String> {
+        let text: Vec<String> = positional.iter().map(|word| word.to_string_lossy().into_owned()).collect();
+        let words: Vec<&str> = text.iter().map(String::as_str).collect();
+        let path = |index: usize| PathBuf::from(&positional[index]);
+        match words.as_slice() {
+            [] | ["run"] => Ok(Command::Run),
+            ["plan"] => Ok(Command::Plan),
+            ["help"] => Ok(Command::Help),
+            ["adopt", _] => Ok(Command::Adopt(path(1))),
+            ["adopt", ..] => Err("adopt expects the mount point of a mergerfs pool".to_string()),
+            ["generate", "systemd"] => Ok(Command::Generate(GenerateTarget::Systemd)),
+            ["generate", "nixos"] => Ok(Command::Generate(GenerateTarget::Nixos)),
+            ["generate", ..] => Err("generate expects one of: systemd, nixos".to_string()),
+            ["failures"] | ["failures", "list"] => Ok(Command::Failures),
+            ["failures", "retry"] => Ok(Command::RetryFailures),
+            ["failures", "drop", _] => Ok(Command::DropFailure(path(2))),
+            ["failures", "drop"] => Err("failures drop expects the path of a move".to_string()),
+            ["problem-files"] | ["problem-files", "list"] => Ok(Command::ProblemFiles),
+            ["problem-files", "clear", _] => Ok(Command::ClearProblemFile(path(2))),
+            ["problem-files", "clear"] => Err("problem-files clear expects the path of a file".to_string()),
+            ["status"] => Ok(Command::Status(None)),
+            ["status", tier] if DriveManager::TIERS.contains(tier) => Ok(Command::Status(Some(tier.to_string()))),
+            ["status", ..] => Err(format!("status expects one of: {}", DriveManager::TIERS.join(", "))),
+            ["list-drives"] => Ok(Command::ListDrives),
+            ["drives"] => Ok(Command::Drives),
+            ["drives", "reset", serial] => Ok(Command::ResetDrive(serial.to_string())),
+            ["drives", ..] => Err("drives expects nothing, or reset and the serial of one drive".to_string()),
+            ["writes"] => Ok(Command::Writes),
+            ["check-now"] => Ok(Command::CheckNow),
+            ["drain", serial] => Ok(Command::Drain(serial.to_string())),
+            ["drain", ..] => Err("drain expects the serial of one drive".to_string()),
+            ["maintenance", serial, "--enable"] | ["maintenance", "--enable", serial] => Ok(Command::Maintenance(serial.to_string(), true)),
+            ["maintenance", serial, "--disable"] | ["maintenance", "--disable", serial] => Ok(Command::Maintenance(serial.to_string(), false)),
+            ["maintenance", ..] => Err("maintenance expects the serial of one drive and --enable or --disable".to_string()),
+            ["pin", _, tier] if DriveManager::TIERS.contains(tier) => Ok(Command::Pin(path(1), tier.to_string())),
+            ["pin", ..] => Err(format!("pin expects a path and one of: {}", DriveManager::TIERS.join(", "))),
+            ["unpin", _] => Ok(Command::Unpin(path(1))),
+            ["unpin", ..] => Err("unpin expects the path of a pin".to_string()),
+            ["pins"] => Ok(Command::Pins),
+            ["reload-config"] => Ok(Command::ReloadConfig),
+            ["ctl", name] => ControlCommand::parse(name).map(Command::Ctl).ok_or("ctl expects one of: pause, resume, run-tiering, reload".to_string()),
+            ["ctl", ..] => Err("ctl expects one of: pause, resume, run-tiering, reload".to_string()),
+            ["job", "start", name] => Ok(Command::JobStart(name.to_string())),
+            ["job", "finish", name] => Ok(Command::JobFinish(name.to_string())),
+            ["job", ..] => Err("job expects: start <name> or finish <name>".to_string()),
+            ["jobs"] => Ok(Command::Jobs),
+            ["stage", name, paths @ ..] if !paths.is_empty() => Ok(Command::Stage(name.to_string(), (2..words.len()).map(path).collect())),
+            ["stage", ..] => Err("stage expects a name and the files to stage".to_string()),
+            ["release", name] => Ok(Command::Release(name.to_string())),
+            ["release", ..] => Err("release expects the name of a staging".to_string()),
+            ["stagings"] => Ok(Command::Stagings),
+            ["history"] => Ok(Command::History(24)),
+            ["history", hours] => hours.parse().map(Command::History).map_err(|_| format!("history expects a number of hours, not {}", hours)),
+            ["history", ..] => Err("history expects a number of hours".to_string()),
+            ["doctor"] => Ok(Command::Doctor),
+            _ => Err(format!("unknown command {}", words.join(" "))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_defaults() {
+        let args = Args::parse_from(Vec::<String>::new()).unwrap();
+        assert!(!args.dryrun);
+        assert!(!args.observe);
+        assert!(!args.simulate);
+        assert_eq!(args.config, CONFIG_FILE_PATH);
+        assert_eq!(args.threads, IO_THREADS);
+        assert_eq!(args.command, Command::Run);
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = Args::parse_from(["--dryrun", "--simulate", "-c", "/path/to/config", "--threads=8"]).unwrap();
+        assert!(args.dryrun);
+        assert!(args.simulate);
+        assert_eq!(args.config, "/path/to/config");
+        assert_eq!(args.threads, 8);
+        assert_eq!(args.profile, None);
+        assert!(!args.write_config);
+        assert!(Args::parse_from(["--write-config"]).unwrap().write_config);
+        assert!(Args::parse_from(["--observe"]).unwrap().observe);
+        assert!(!args.allow_bulk_format);
+        assert!(!args.assume_yes);
+        assert!(Args::parse_from(["--assume-yes"]).unwrap().assume_yes);
+        assert_eq!(Args::parse_from(["--profile", "travel"]).unwrap().profile.as_deref(), Some("travel"));
+    }
+
+    #[test]
+    fn test_parse_generate() {
+        let args = Args::parse_from(["generate", "nixos", "--config", "/tmp/c.json"]).unwrap();
+        assert_eq!(args.command, Command::Generate(GenerateTarget::Nixos));
+        assert_eq!(args.config, "/tmp/c.json");
+        assert!(Args::parse_from(["generate", "upstart"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_adopt() {
+        assert_eq!(Args::parse_from(["adopt", "/mnt/storage"]).unwrap().command, Command::Adopt("/mnt/storage".into()));
+        assert!(Args::parse_from(["adopt"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_reload_config() {
+        let args = Args::parse_from(["reload-config", "--force"]).unwrap();
+        assert_eq!(args.command, Command::ReloadConfig);
+        assert!(args.force);
+        assert!(!Args::parse_from(["reload-config"]).unwrap().force);
+    }
+
+    #[test]
+    fn test_parse_ctl() {
+        assert_eq!(Args::parse_from(["ctl", "run-tiering"]).unwrap().command, Command::Ctl(ControlCommand::RunTiering));
+        assert!(Args::parse_from(["ctl", "stop"]).is_err());
+        assert!(Args::parse_from(["ctl"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_pin() {
+        assert_eq!(Args::parse_from(["pin", "vms", "hot"]).unwrap().command, Command::Pin("vms".into(), "hot".to_string()));
+        assert!(Args::parse_from(["pin", "vms", "nvme"]).is_err());
+        assert_eq!(Args::parse_from(["unpin", "vms"]).unwrap().command, Command::Unpin("vms".into()));
+        assert!(Args::parse_from(["unpin"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_check_now() {
+        assert_eq!(Args::parse_from(["check-now"]).unwrap().command, Command::CheckNow);
+        assert!(Args::parse_from(["check-now", "hot"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_drain() {
+        assert_eq!(Args::parse_from(["drain", "WD-WX11D"]).unwrap().command, Command::Drain("WD-WX11D".to_string()));
+        assert!(Args::parse_from(["drain"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_maintenance() {
+        assert_eq!(Args::parse_from(["maintenance", "WD-1", "--enable"]).unwrap().command, Command::Maintenance("WD-1".to_string(), true));
+        assert_eq!(Args::parse_from(["maintenance", "--disable", "WD-1"]).unwrap().command, Command::Maintenance("WD-1".to_string(), false));
+        assert!(Args::parse_from(["maintenance", "WD-1"]).is_err());
+        assert!(Args::parse_from(["--enable"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_failures() {
+        assert_eq!(Args::parse_from(["failures"]).unwrap().command, Command::Failures);
+        assert_eq!(Args::parse_from(["failures", "retry"]).unwrap().command, Command::RetryFailures);
+        assert_eq!(Args::parse_from(["failures", "list"]).unwrap().command, Command::Failures);
+        assert_eq!(Args::parse_from(["failures", "drop", "TV/a.mkv"]).unwrap().command, Command::DropFailure("TV/a.mkv".into()));
+        assert!(Args::parse_from(["failures", "drop"]).is_err());
+        assert!(Args::parse_from(["failures", "purge"]).is_err());
+        assert_eq!(Args::parse_from(["problem-files"]).unwrap().command, Command::ProblemFiles);
+        assert_eq!(Args::parse_from(["problem-files", "clear", "TV/a.mkv"]).unwrap().command, Command::ClearProblemFile("TV/a.mkv".into()));
+        assert!(Args::parse_from(["problem-files", "clear"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(Args::parse_from(["status"]).unwrap().command, Command::Status(None));
+        assert_eq!(Args::parse_from(["status", "warm"]).unwrap().command, Command::Status(Some("warm".to_string())));
+        assert!(Args::parse_from(["status", "lukewarm"]).is_err());
+        assert!(Args::parse_from(["status", "all"]).is_err());
+        assert_eq!(Args::parse_from(["list-drives"]).unwrap().command, Command::ListDrives);
+    }
+
+    #[test]
+    fn test_parse_jobs() {
+        assert_eq!(Args::parse_from(["job", "start", "backup"]).unwrap().command, Command::JobStart("backup".to_string()));
+        assert_eq!(Args::parse_from(["job", "finish", "backup"]).unwrap().command, Command::JobFinish("backup".to_string()));
+        assert_eq!(Args::parse_from(["jobs"]).unwrap().command, Command::Jobs);
+        assert_eq!(Args::parse_from(["doctor"]).unwrap().command, Command::Doctor);
+        assert_eq!(Args::parse_from(["--config", "/tmp/c.json", "plan"]).unwrap().command, Command::Plan);
+        assert!(Args::parse_from(["job", "start"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_stage() {
+        let stage = Command::Stage("render-42".to_string(), vec!["scenes/a.blend".into(), "textures".into()]);
+        assert_eq!(Args::parse_from(["stage", "render-42", "scenes/a.blend", "textures"]).unwrap().command, stage);
+        assert_eq!(Args::parse_from(["release", "render-42"]).unwrap().command, Command::Release("render-42".to_string()));
+        assert_eq!(Args::parse_from(["stagings"]).unwrap().command, Command::Stagings);
+        assert!(Args::parse_from(["stage", "render-42"]).is_err());
+        assert!(Args::parse_from(["release"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_drives() {
+        assert_eq!(Args::parse_from(["drives"]).unwrap().command, Command::Drives);
+        assert_eq!(Args::parse_from(["writes"]).unwrap().command, Command::Writes);
+        assert_eq!(Args::parse_from(["drives", "reset", "WD-1"]).unwrap().command, Command::ResetDrive("WD-1".to_string()));
+        assert!(Args::parse_from(["drives", "reset"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_history() {
+        assert_eq!(Args::parse_from(["history"]).unwrap().command, Command::History(24));
+        assert_eq!(Args::parse_from(["history", "72"]).unwrap().command, Command::History(72));
+        assert!(Args::parse_from(["history", "yesterday"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        // café.mkv in latin-1
+        let latin1 = OsStr::from_bytes(b"TV/caf\xe9.mkv");
+        let parse = |words: &[&OsStr]| Args::parse_from(words.iter().copied()).map(|args| args.command);
+        assert_eq!(parse(&["failures".as_ref(), "drop".as_ref(), latin1]), Ok(Command::DropFailure(latin1.into())));
+        assert_eq!(parse(&["problem-files".as_ref(), "clear".as_ref(), latin1]), Ok(Command::ClearProblemFile(latin1.into())));
+        assert_eq!(parse(&["pin".as_ref(), latin1, "hot".as_ref()]), Ok(Command::Pin(latin1.into(), "hot".to_string())));
+        assert_eq!(parse(&["stage".as_ref(), "render".as_ref(), latin1]), Ok(Command::Stage("render".to_string(), vec![latin1.into()])));
+        assert!(parse(&["--config".as_ref(), latin1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Args::parse_from(["--config"]).is_err());
+        assert!(Args::parse_from(["--threads", "many"]).is_err());
+        assert!(Args::parse_from(["--bogus"]).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use std::ffi::OsString;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::warn;
+use serde_json::Value;
+
+// linux/ioprio.h; libc has no constants for these
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+// a limiter idle this long has no more credit built up
+const BURST: Duration = Duration::from_secs(1);
+
+// How fast moves may copy:
+//   "max_migration_mbps": 200, "max_move_mbps": 80, "mover_ionice": "idle"
+// max_migration_mbps caps all moves together and max_move_mbps each one, in
+// MiB/s. mover_ionice puts the copies in an I/O class, "idle" or
+// "best-effort:0".."best-effort:7", which only the bfq scheduler honours;
+// mover_cgroup sets limits the kernel enforces on any scheduler.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    global: Option<RateLimiter>,
+    per_move_bps: Option<u64>,
+    ionice: Option<IoClass>,
+    // moves copying right now, which share the global limit
+    active: AtomicUsize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoClass {
+    Idle,
+    BestEffort(u8),
+}
+
+// Hands out time to copy at `bps`, so callers that copied more than their
+// share wait until the rate catches up
+#[derive(Debug)]
+pub struct RateLimiter {
+    bps: u64,
+    next: Mutex<Option<Instant>>,
+}
+
+// One move's use of the limits, for as long as it copies
+pub struct Transfer<'a> {
+    bandwidth: &'a Bandwidth,
+    own: Option<RateLimiter>,
+    copied: AtomicU64,
+}
+
+impl Bandwidth {
+    pub fn from_config(config: &Value) -> Self {
+        let bps = |key: &str| config.get(key).and_then(Value::as_f64).filter(|mbps| *mbps > 0.0).map(|mbps| (mbps * 1024.0 * 1024.0) as u64);
+        let ionice = config.get("mover_ionice").and_then(Value::as_str).and_then(IoClass::parse);
+        Self { global: bps("max_migration_mbps").map(RateLimiter::new), per_move_bps: bps("max_move_mbps"), ionice, active: AtomicUsize::new(0) }
+    }
+
+    pub fn ionice(&self) -> Option<IoClass> {
+        self.ionice
+    }
+
+    pub fn start(&self) -> Transfer<'_> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Transfer { bandwidth: self, own: self.per_move_bps.map(RateLimiter::new), copied: AtomicU64::new(0) }
+    }
+}
+
+impl IoClass {
+    pub fn parse(class: &str) -> Option<Self> {
+        match class.split_once(':') {
+            None if class == "idle" => Some(IoClass::Idle),
+            None if class == "best-effort" => Some(IoClass::BestEffort(4)),
+            Some(("best-effort", level)) => level.parse().ok().filter(|level| *level <= 7).map(IoClass::BestEffort),
+            _ => None,
+        }
+    }
+
+    // ionice's arguments for running a command in this class
+    pub fn command(&self) -> Vec<OsString> {
+        match self {
+            IoClass::Idle => vec!["ionice".into(), "-c".into(), "3".into()],
+            IoClass::BestEffort(level) => vec!["ionice".into(), "-c".into(), "2".into(), "-n".into(), level.to_string().into()],
+        }
+    }
+
+    fn ioprio(&self) -> libc::c_int {
+        match self {
+            IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+            IoClass::BestEffort(level) => (2 << IOPRIO_CLASS_SHIFT) | *level as libc::c_int,
+        }
+    }
+}
+
+// Run `f` with the calling thread in `class`, putting its own class back
+// after. A class that cannot be set only costs a warning.
+pub fn with_io_class<T>(class: Option<IoClass>, f: impl FnOnce() -> T) -> T {
+    let Some(class) = class else { return f() };
+    // who 0 is the calling thread, so other threads keep their class
+    let previous = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, class.ioprio()) } != 0 {
+        warn!("Failed to set the mover's I/O class: {}", io::Error::last_os_error());
+        return f();
+    }
+    let result = f();
+    if previous >= 0 {
+        unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, previous) };
+    }
+    result
+}
+
+impl RateLimiter {
+    pub fn new(bps: u64) -> Self {
+        Self { bps: bps.max(1), next: Mutex::new(None) }
+    }
+
+    // How long to wait at `now` before `bytes` more are within the rate
+    pub fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut next = self.next.lock().unwrap();
+        let earliest = now.checked_sub(BURST).unwrap_or(now);
+        let start = next.map_or(earliest, |next| next.max(earliest));
+        let end = start + Duration::from_secs_f64(bytes as f64 / self.bps as f64);
+        *next = Some(end);
+        end.saturating_duration_since(now)
+    }
+}
+
+impl Transfer<'_> {
+    // Called with the bytes copied so far, waiting out whatever the limits
+    // ask for before the copy goes on
+    pub fn copied(&self, total: u64) {
+        let bytes = total.saturating_sub(self.copied.swap(total, Ordering::SeqCst));
+        if bytes == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let wait = [self.bandwidth.global.as_ref(), self.own.as_ref()].into_iter().flatten()
+            .map(|limiter| limiter.reserve(bytes, now))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    // rsync's --bwlimit, in KiB/s: this move's own limit, or its share of the
+    // global one among the moves copying as it starts if that is lower.
+    // rsync cannot be slowed from outside while it runs.
+    pub fn rsync_bwlimit(&self) -> Option<u64> {
+        let share = self.bandwidth.global.as_ref().map(|global| global.bps / self.bandwidth.active.load(Ordering::SeqCst).max(1) as u64);
+        [share, self.bandwidth.per_move_bps].into_iter().flatten().min().map(|bps| (bps / 1024).max(1))
+    }
+}
+
+impl Drop for Transfer<'_> {
+    fn drop(&mut self) {
+        self.bandwidth.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(100);
+        let t0 = Instant::now();
+        // the first second's worth goes straight through
+        assert_eq!(limiter.reserve(100, t0), Duration::ZERO);
+        assert_eq!(limiter.reserve(50, t0), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(50, t0 + Duration::from_millis(500)), Duration::from_millis(500));
+        // idling builds up no more than a second of credit
+        assert_eq!(limiter.reserve(300, t0 + Duration::from_secs(60)), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_rsync_bwlimit() {
+        let bandwidth = Bandwidth::from_config(&json!({ "max_migration_mbps": 100, "max_move_mbps": 40 }));
+        let first = bandwidth.start();
+        assert_eq!(first.rsync_bwlimit(), Some(40 * 1024));
+        let others: Vec<Transfer> = (0..3).map(|_| bandwidth.start()).collect();
+        assert_eq!(others[2].rsync_bwlimit(), Some(25 * 1024));
+        drop(others);
+        assert_eq!(bandwidth.start().rsync_bwlimit(), Some(40 * 1024));
+        assert_eq!(Bandwidth::from_config(&json!({})).start().rsync_bwlimit(), None);
+    }
+
+    #[test]
+    fn test_io_class() {
+        assert_eq!(IoClass::parse("idle"), Some(IoClass::Idle));
+        assert_eq!(IoClass::parse("best-effort:7"), Some(IoClass::BestEffort(7)));
+        assert_eq!(IoClass::parse("best-effort:8"), None);
+        assert_eq!(IoClass::parse("realtime"), None);
+        assert_eq!(IoClass::BestEffort(7).command(), ["ionice", "-c", "2", "-n", "7"]);
+        assert_eq!(Bandwidth::from_config(&json!({ "mover_ionice": "fast" })).ionice(), None);
+        // the thread gets its own class back
+        let before = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+        assert_eq!(with_io_class(Some(IoClass::Idle), || 1), 1);
+        assert_eq!(unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) }, before);
+    }
+}
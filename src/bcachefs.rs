@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use crate::lsblk::BlockDevice;
+
+pub const MOUNTPOINT: &str = "/mnt/bcachefs";
+pub const SYSFS_DIR: &str = "/sys/fs/bcachefs";
+// the filesystem options that place data by tier, with the tier each
+// points at unless the bcachefs section says otherwise
+pub const TARGETS: [(&str, &str); 4] = [
+    ("foreground_target", "hot"),
+    ("promote_target", "hot"),
+    ("metadata_target", "hot"),
+    ("background_target", "cold"),
+];
+
+// The bcachefs backend, used when the config has "backend": "bcachefs".
+// Instead of a filesystem per drive pooled by mergerfs with rsync moving
+// files between tiers, every drive is a member of one bcachefs filesystem,
+// labelled <tier>.<serial>, and bcachefs moves data itself by the
+// filesystem's targets. The bcachefs section:
+//   { "mountpoint": "/mnt/bcachefs", "foreground_target": "hot",
+//     "promote_target": "hot", "metadata_target": "hot",
+//     "background_target": "cold" }
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bcachefs {
+    pub mountpoint: String,
+    // option name and the tier it points at
+    pub targets: Vec<(String, String)>,
+}
+
+// What to do with each discovered drive
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Membership {
+    // the external UUID of the filesystem, when it exists yet
+    pub uuid: Option<String>,
+    pub members: Vec<BlockDevice>,
+    // drives without a bcachefs superblock, to be added
+    pub joining: Vec<BlockDevice>,
+    // members of some other bcachefs filesystem, left alone
+    pub foreign: Vec<BlockDevice>,
+}
+
+impl Bcachefs {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        if config.get("backend").and_then(Value::as_str) != Some("bcachefs") {
+            return None;
+        }
+        let section = config.get("bcachefs");
+        let setting = |key: &str| section.and_then(|section| section.get(key)).and_then(Value::as_str);
+        Some(Self {
+            mountpoint: setting("mountpoint").unwrap_or(MOUNTPOINT).to_string(),
+            targets: TARGETS.iter().map(|(option, tier)| (option.to_string(), setting(option).unwrap_or(tier).to_string())).collect(),
+        })
+    }
+
+    pub fn label(device: &BlockDevice) -> String {
+        format!("{}.{}", device.tier(), device.id())
+    }
+
+    // The bcachefs filesystem a drive belongs to, whether it was formatted
+    // whole or as one partition
+    pub fn member_uuid(device: &BlockDevice) -> Option<&str> {
+        [Some(device), device.children.first()].into_iter().flatten()
+            .find(|device| device.fstype.as_deref() == Some("bcachefs"))
+            .and_then(|device| device.uuid.as_deref())
+    }
+
+    // Sort drives into members, drives to add and drives to leave alone.
+    // If drives from more than one bcachefs filesystem are attached, the
+    // one with the most members is ours.
+    pub fn membership(devices: &[BlockDevice]) -> Membership {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for uuid in devices.iter().filter_map(Self::member_uuid) {
+            *counts.entry(uuid).or_default() += 1;
+        }
+        let uuid = counts.into_iter().max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0))).map(|(uuid, _)| uuid.to_string());
+        let mut membership = Membership { uuid: uuid.clone(), ..Default::default() };
+        for device in devices {
+            match Self::member_uuid(device) {
+                None => membership.joining.push(device.clone()),
+                Some(member) if Some(member) == uuid.as_deref() => membership.members.push(device.clone()),
+                Some(_) => membership.foreign.push(device.clone()),
+            }
+        }
+        membership
+    }
+
+    // Only tiers that have a drive exist as targets
+    fn settable_targets(&self, devices: &[BlockDevice]) -> Vec<(String, String)> {
+        self.targets.iter().filter(|(_, tier)| devices.iter().any(|device| device.tier() == tier)).cloned().collect()
+    }
+
+    // Create the filesystem across `devices`
+    pub fn format_command(&self, devices: &[BlockDevice]) -> Vec<String> {
+        let mut cmd = vec!["bcachefs".to_string(), "format".to_string(), "--force".to_string()];
+        for (option, tier) in self.settable_targets(devices) {
+            cmd.push(format!("--{}={}", option, tier));
+        }
+        for device in devices {
+            cmd.push(format!("--label={}", Self::label(device)));
+            cmd.push(device.path.clone());
+        }
+        cmd
+    }
+
+    pub fn add_command(&self, device: &BlockDevice) -> Vec<String> {
+        vec!["bcachefs".to_string(), "device".to_string(), "add".to_string(), "--force".to_string(),
+            format!("--label={}", Self::label(device)), self.mountpoint.clone(), device.path.clone()]
+    }
+
+    pub fn mount_command(&self, members: &[BlockDevice]) -> Vec<String> {
+        let devices = members.iter().map(|device| device.path.as_str()).collect::<Vec<_>>().join(":");
+        vec!["mount".to_string(), "-t".to_string(), "bcachefs".to_string(), devices, self.mountpoint.clone()]
+    }
+
+    // Point the filesystem's targets at the tiers the config names, through
+    // its options in sysfs. Returns the options that changed.
+    pub fn apply_targets(&self, options_dir: &Path, devices: &[BlockDevice]) -> io::Result<Vec<String>> {
+        let current = read_options(options_dir);
+        let mut changed = Vec::new();
+        for (option, tier) in self.settable_targets(devices) {
+            if current.get(&option).map(String::as_str) != Some(tier.as_str()) {
+                fs::write(options_dir.join(&option), &tier)
+                    .map_err(|e| io::Error::new(e.kind(), format!("failed to set {} to {}: {}", option, tier, e)))?;
+                changed.push(option);
+            }
+        }
+        Ok(changed)
+    }
+}
+
+pub fn options_dir(sysfs_dir: &Path, uuid: &str) -> PathBuf {
+    sysfs_dir.join(uuid).join("options")
+}
+
+// The target options as the filesystem has them; unset targets read "none"
+pub fn read_options(options_dir: &Path) -> HashMap<String, String> {
+    TARGETS.iter().filter_map(|(option, _)| {
+        let value = fs::read_to_string(options_dir.join(option)).ok()?;
+        Some((option.to_string(), value.trim().to_string()))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    fn device(serial: &str, class: &str, path: &str, uuid: Option<&str>) -> BlockDevice {
+        BlockDevice {
+            serial: Some(serial.to_string()),
+            path: path.to_string(),
+            rota: class == "hdd",
+            tran: Some(if class == "nvme" { "nvme" } else { "sata" }.to_string()),
+            fstype: uuid.map(|_| "bcachefs".to_string()),
+            uuid: uuid.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_membership() {
+        let devices = [
+            device("n1", "nvme", "/dev/nvme0n1", Some("fs-a")),
+            device("h1", "hdd", "/dev/sda", Some("fs-a")),
+            device("h2", "hdd", "/dev/sdb", None),
+            device("h3", "hdd", "/dev/sdc", Some("fs-b")),
+        ];
+        let membership = Bcachefs::membership(&devices);
+        assert_eq!(membership.uuid.as_deref(), Some("fs-a"));
+        assert_eq!(membership.members.len(), 2);
+        assert_eq!(membership.joining[0].path, "/dev/sdb");
+        assert_eq!(membership.foreign[0].path, "/dev/sdc");
+        assert_eq!(Bcachefs::membership(&devices[2..3]).uuid, None);
+    }
+
+    #[test]
+    fn test_commands() {
+        assert!(Bcachefs::from_config(&json!({ "filesystem": "ext4" })).is_none());
+        let fs = Bcachefs::from_config(&json!({ "backend": "bcachefs", "bcachefs": { "promote_target": "warm" } })).unwrap();
+        let devices = [device("n1", "nvme", "/dev/nvme0n1", None), device("h1", "hdd", "/dev/sda", None)];
+        // no warm drive, so promote_target is left unset
+        assert_eq!(fs.format_command(&devices), [
+            "bcachefs", "format", "--force", "--foreground_target=hot", "--metadata_target=hot", "--background_target=cold",
+            "--label=hot.n1", "/dev/nvme0n1", "--label=cold.h1", "/dev/sda",
+        ]);
+        assert_eq!(fs.add_command(&devices[1]), ["bcachefs", "device", "add", "--force", "--label=cold.h1", "/mnt/bcachefs", "/dev/sda"]);
+        assert_eq!(fs.mount_command(&devices), ["mount", "-t", "bcachefs", "/dev/nvme0n1:/dev/sda", "/mnt/bcachefs"]);
+    }
+
+    #[test]
+    fn test_apply_targets() {
+        let sysfs = tempdir().unwrap();
+        let options = options_dir(sysfs.path(), "fs-a");
+        fs::create_dir_all(&options).unwrap();
+        for (option, _) in TARGETS {
+            fs::write(options.join(option), "none\n").unwrap();
+        }
+        fs::write(options.join("foreground_target"), "hot\n").unwrap();
+        let bcachefs = Bcachefs::from_config(&json!({ "backend": "bcachefs" })).unwrap();
+        let devices = [device("n1", "nvme", "/dev/nvme0n1", None), device("h1", "hdd", "/dev/sda", None)];
+        assert_eq!(bcachefs.apply_targets(&options, &devices).unwrap(), ["promote_target", "metadata_target", "background_target"]);
+        assert_eq!(read_options(&options)["background_target"], "cold");
+        assert!(bcachefs.apply_targets(&options, &devices).unwrap().is_empty());
+    }
+}
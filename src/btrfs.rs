@@ -0,0 +1,186 @@
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+
+const BTRFS_SUPER_MAGIC: libc::c_long = 0x9123683e;
+// every subvolume's root directory has this inode number
+const SUBVOLUME_INODE: u64 = 256;
+// BTRFS_IOC_SUBVOL_GETFLAGS and its read-only flag; libc has neither
+const SUBVOL_GETFLAGS: libc::c_ulong = 0x80089419;
+const SUBVOL_RDONLY: u64 = 1 << 1;
+// Where a branch keeps the snapshots of subvolumes being sent, relative to
+// its root. Scans skip it.
+pub const SNAPSHOT_DIR: &str = ".drive-manager/snapshots";
+
+// How drives formatted as btrfs are laid out and mounted:
+//   "btrfs": { "compress": "zstd:3", "space_cache": "v2", "subvolumes": true }
+// With subvolumes on, the default, a new drive gets a subvolume named for
+// its tier, @hot, @warm or @cold, made its default so every plain mount of
+// the drive, including the ones written for boot, lands in it. compress
+// and space_cache are passed to mount as they are; left out, the kernel's
+// defaults apply. Moves between subvolumes of one filesystem are reflinked
+// by the native mover rather than copied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BtrfsOptions {
+    pub compress: Option<String>,
+    pub space_cache: Option<String>,
+    pub subvolumes: bool,
+}
+
+impl BtrfsOptions {
+    pub fn from_config(config: &Value) -> Self {
+        let section = config.get("btrfs");
+        let option = |key: &str| section.and_then(|section| section.get(key)).and_then(Value::as_str).map(str::to_string);
+        Self {
+            compress: option("compress"),
+            space_cache: option("space_cache"),
+            subvolumes: section.and_then(|section| section.get("subvolumes")).and_then(Value::as_bool).unwrap_or(true),
+        }
+    }
+
+    pub fn mount_options(&self) -> Vec<String> {
+        let compress = self.compress.as_ref().map(|compress| format!("compress={}", compress));
+        let space_cache = self.space_cache.as_ref().map(|space_cache| format!("space_cache={}", space_cache));
+        compress.into_iter().chain(space_cache).collect()
+    }
+
+    pub fn subvolume_name(tier: &str) -> String {
+        format!("@{}", tier)
+    }
+
+    // The commands that give a freshly made filesystem on `device` its
+    // tier's subvolume, with the top level mounted at `mount_point` for
+    // the while
+    pub fn layout_commands(&self, device: &str, mount_point: &str, tier: &str) -> Vec<Vec<String>> {
+        let subvolume = format!("{}/{}", mount_point, Self::subvolume_name(tier));
+        [
+            vec!["mount", "-o", "subvolid=5", device, mount_point],
+            vec!["btrfs", "subvolume", "create", &subvolume],
+            vec!["btrfs", "subvolume", "set-default", &subvolume],
+            vec!["umount", mount_point],
+        ].into_iter().map(|cmd| cmd.into_iter().map(str::to_string).collect()).collect()
+    }
+}
+
+pub fn is_btrfs(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_type as libc::c_long == BTRFS_SUPER_MAGIC)
+}
+
+pub fn is_subvolume(path: &Path) -> io::Result<bool> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(metadata.is_dir() && metadata.ino() == SUBVOLUME_INODE && is_btrfs(path)?)
+}
+
+// btrfs receive marks a subvolume read-only once it has all of it, so a
+// writable one was cut off partway
+pub fn is_read_only(subvolume: &Path) -> io::Result<bool> {
+    let dir = fs::File::open(subvolume)?;
+    let mut flags: u64 = 0;
+    if unsafe { libc::ioctl(dir.as_raw_fd(), SUBVOL_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & SUBVOL_RDONLY != 0)
+}
+
+// The directory under SNAPSHOT_DIR for the subvolume at `dir`, relative to
+// the branch root. '%' and '/' are escaped so every path gets its own.
+pub fn snapshot_key(dir: &Path) -> String {
+    dir.to_string_lossy().replace('%', "%25").replace('/', "%2F")
+}
+
+// The snapshots in `snapshot_dir`, oldest first. Each is named by its
+// sequence number.
+pub fn snapshots(snapshot_dir: &Path) -> Vec<u64> {
+    let mut numbers: Vec<u64> = fs::read_dir(snapshot_dir).map(|entries| {
+        entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).collect()
+    }).unwrap_or_default();
+    numbers.sort();
+    numbers
+}
+
+// The newest snapshot both ends have whole, which the next one can be sent
+// against so only the changes since go over
+pub fn common_parent(sent: &[u64], received: &[u64]) -> Option<u64> {
+    sent.iter().rev().find(|number| received.contains(number)).copied()
+}
+
+pub fn snapshot_command(subvolume: &Path, snapshot: &Path, read_only: bool) -> Vec<OsString> {
+    let mut cmd: Vec<OsString> = vec!["btrfs".into(), "subvolume".into(), "snapshot".into()];
+    if read_only {
+        cmd.push("-r".into());
+    }
+    cmd.extend([subvolume.into(), snapshot.into()]);
+    cmd
+}
+
+pub fn delete_command(subvolumes: &[PathBuf]) -> Vec<OsString> {
+    let mut cmd: Vec<OsString> = vec!["btrfs".into(), "subvolume".into(), "delete".into()];
+    cmd.extend(subvolumes.iter().map(OsString::from));
+    cmd
+}
+
+// btrfs send of `snapshot`, against `parent` if given, to be piped into
+// receive_command
+pub fn send_command(snapshot: &Path, parent: Option<&Path>) -> Vec<OsString> {
+    let mut cmd: Vec<OsString> = vec!["btrfs".into(), "send".into()];
+    if let Some(parent) = parent {
+        cmd.extend(["-p".into(), parent.into()]);
+    }
+    cmd.push(snapshot.into());
+    cmd
+}
+
+pub fn receive_command(receive_dir: &Path) -> Vec<OsString> {
+    vec!["btrfs".into(), "receive".into(), receive_dir.into()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_snapshots() {
+        let dir = tempdir().unwrap();
+        for name in ["10", "9", "partial.tmp"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        assert_eq!(snapshots(dir.path()), [9, 10]);
+        assert!(snapshots(&dir.path().join("missing")).is_empty());
+        assert_eq!(common_parent(&[1, 2, 3], &[1, 2]), Some(2));
+        assert_eq!(common_parent(&[3], &[1, 2]), None);
+        assert_eq!(snapshot_key(Path::new("TV/100%/Season 1")), "TV%2F100%25%2FSeason 1");
+    }
+
+    #[test]
+    fn test_options() {
+        let options = BtrfsOptions::from_config(&json!({}));
+        assert!(options.subvolumes && options.mount_options().is_empty());
+        let options = BtrfsOptions::from_config(&json!({ "btrfs": { "compress": "zstd:3", "space_cache": "v2", "subvolumes": false } }));
+        assert_eq!(options.mount_options(), ["compress=zstd:3", "space_cache=v2"]);
+        assert!(!options.subvolumes);
+        let layout = options.layout_commands("/dev/sdb1", "/mnt/physical/hdd/WD-1", "cold");
+        assert_eq!(layout[0], ["mount", "-o", "subvolid=5", "/dev/sdb1", "/mnt/physical/hdd/WD-1"]);
+        assert_eq!(layout[2], ["btrfs", "subvolume", "set-default", "/mnt/physical/hdd/WD-1/@cold"]);
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(snapshot_command(Path::new("/b/TV"), Path::new("/b/snap/1"), true), ["btrfs", "subvolume", "snapshot", "-r", "/b/TV", "/b/snap/1"]);
+        assert_eq!(delete_command(&[PathBuf::from("/b/snap/1")]), ["btrfs", "subvolume", "delete", "/b/snap/1"]);
+        assert_eq!(send_command(Path::new("/a/snap/2"), Some(Path::new("/a/snap/1"))), ["btrfs", "send", "-p", "/a/snap/1", "/a/snap/2"]);
+        assert_eq!(send_command(Path::new("/a/snap/1"), None), ["btrfs", "send", "/a/snap/1"]);
+        assert_eq!(receive_command(Path::new("/b/snap")), ["btrfs", "receive", "/b/snap"]);
+    }
+}
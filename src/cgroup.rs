@@ -0,0 +1,170 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use log::info;
+use serde_json::Value;
+use crate::executor::{self, Executor};
+
+pub const CGROUP_PATH: &str = "/sys/fs/cgroup/drive-manager-mover";
+
+// A cgroup v2 group the rsyncs that move files run in, from the
+// mover_cgroup config section:
+//   { "path": "/sys/fs/cgroup/drive-manager-mover", "cpu_weight": 20,
+//     "read_mbps": 100, "write_mbps": 100, "io_max": ["8:16 wiops=200"] }
+// read_mbps and write_mbps cap each branch's disk; io_max lines are written
+// to io.max as they are. Unlike nice levels these are hard limits the
+// kernel enforces. The group sits outside the service's own cgroup, so an
+// rsync still running when the service stops is not killed with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoverCgroup {
+    pub path: PathBuf,
+    pub cpu_weight: Option<u64>,
+    pub read_bps: Option<u64>,
+    pub write_bps: Option<u64>,
+    pub io_max: Vec<String>,
+}
+
+impl MoverCgroup {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("mover_cgroup").filter(|section| section.is_object())?;
+        let mbps = |key: &str| section.get(key).and_then(Value::as_f64).map(|mbps| (mbps * 1024.0 * 1024.0) as u64);
+        Some(Self {
+            path: PathBuf::from(section.get("path").and_then(Value::as_str).unwrap_or(CGROUP_PATH)),
+            cpu_weight: section.get("cpu_weight").and_then(Value::as_u64),
+            read_bps: mbps("read_mbps"),
+            write_bps: mbps("write_mbps"),
+            io_max: section.get("io_max").and_then(Value::as_array)
+                .map(|lines| lines.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    // The io.max lines for the disks under `devices`, as MAJ:MIN of whole
+    // disks, followed by the configured ones
+    pub fn io_max_lines(&self, devices: &[String]) -> Vec<String> {
+        let mut limits = Vec::new();
+        limits.extend(self.read_bps.map(|bps| format!("rbps={}", bps)));
+        limits.extend(self.write_bps.map(|bps| format!("wbps={}", bps)));
+        let mut lines: Vec<String> = if limits.is_empty() {
+            Vec::new()
+        } else {
+            devices.iter().map(|device| format!("{} {}", device, limits.join(" "))).collect()
+        };
+        lines.extend(self.io_max.iter().cloned());
+        lines
+    }
+
+    // Create the group and apply its limits to the disks holding `branches`
+    pub fn setup(&self, sysfs: &Path, branches: &[PathBuf]) -> io::Result<CgroupExecutor> {
+        fs::create_dir_all(&self.path)?;
+        if let Some(parent) = self.path.parent() {
+            // the controllers have to be handed down before the group can use them
+            fs::write(parent.join("cgroup.subtree_control"), "+cpu +io")?;
+        }
+        if let Some(weight) = self.cpu_weight {
+            fs::write(self.path.join("cpu.weight"), weight.to_string())?;
+        }
+        let mut devices: Vec<String> = branches.iter().filter_map(|branch| whole_disk(sysfs, branch).ok()).collect();
+        devices.sort();
+        devices.dedup();
+        // io.max takes one device per write
+        for line in self.io_max_lines(&devices) {
+            fs::write(self.path.join("io.max"), &line)?;
+            info!("Limited mover I/O to {}", line);
+        }
+        Ok(CgroupExecutor { procs: self.path.join("cgroup.procs") })
+    }
+}
+
+// MAJ:MIN of the disk a path is on, rather than its partition
+fn whole_disk(sysfs: &Path, path: &Path) -> io::Result<String> {
+    let dev = fs::metadata(path)?.dev();
+    let device = sysfs.join(format!("dev/block/{}:{}", libc::major(dev), libc::minor(dev)));
+    let disk = if device.join("partition").exists() { device.join("..") } else { device };
+    Ok(fs::read_to_string(disk.join("dev"))?.trim().to_string())
+}
+
+// Runs commands the way SystemExecutor does, but inside the mover cgroup.
+// Each child joins the group before it execs, so none of its I/O happens
+// outside it.
+pub struct CgroupExecutor {
+    procs: PathBuf,
+}
+
+impl CgroupExecutor {
+    fn command(&self, cmd: &[&OsStr]) -> io::Result<(Command, File)> {
+        let mut command = executor::command(cmd)?;
+        // opened here since the child may only make async-signal-safe calls
+        let procs = OpenOptions::new().write(true).open(&self.procs)?;
+        let fd = procs.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                // "0" moves the writing process, which is the child
+                if libc::write(fd, b"0".as_ptr().cast(), 1) != 1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok((command, procs))
+    }
+}
+
+impl Executor for CgroupExecutor {
+    fn status(&self, cmd: &[&OsStr]) -> io::Result<ExitStatus> {
+        let (mut command, _procs) = self.command(cmd)?;
+        command.status()
+    }
+
+    fn output(&self, cmd: &[&OsStr]) -> io::Result<Output> {
+        let (mut command, _procs) = self.command(cmd)?;
+        command.output()
+    }
+
+    fn stream(&self, cmd: &[&OsStr], on_line: &mut dyn FnMut(&str)) -> io::Result<Output> {
+        let (command, _procs) = self.command(cmd)?;
+        executor::stream_lines(command, on_line)
+    }
+
+    fn output_with_input(&self, cmd: &[&OsStr], input: &[u8]) -> io::Result<Output> {
+        let (command, _procs) = self.command(cmd)?;
+        executor::output_with_input(command, input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_config() {
+        let cgroup = MoverCgroup::from_config(&json!({ "mover_cgroup": { "cpu_weight": 20, "write_mbps": 50, "io_max": ["8:16 wiops=200"] } })).unwrap();
+        assert_eq!(cgroup.path, Path::new(CGROUP_PATH));
+        assert_eq!(cgroup.io_max_lines(&["8:0".to_string()]), ["8:0 wbps=52428800", "8:16 wiops=200"]);
+        assert!(MoverCgroup::from_config(&json!({})).is_none());
+    }
+
+    #[test]
+    fn test_setup() {
+        let root = tempdir().unwrap();
+        let cgroup = MoverCgroup { path: root.path().join("mover"), cpu_weight: Some(20), read_bps: None, write_bps: None, io_max: vec!["8:0 rbps=1".to_string()] };
+        let executor = cgroup.setup(root.path(), &[]).unwrap();
+        assert_eq!(fs::read_to_string(root.path().join("cgroup.subtree_control")).unwrap(), "+cpu +io");
+        assert_eq!(fs::read_to_string(root.path().join("mover/cpu.weight")).unwrap(), "20");
+        assert_eq!(fs::read_to_string(root.path().join("mover/io.max")).unwrap(), "8:0 rbps=1");
+
+        // a plain file stands in for cgroup.procs and records what joined
+        fs::write(root.path().join("mover/cgroup.procs"), "").unwrap();
+        let mut lines = Vec::new();
+        assert!(executor.stream(&["echo".as_ref(), "moved".as_ref()], &mut |line| lines.push(line.to_string())).unwrap().status.success());
+        assert_eq!(lines, ["moved"]);
+        assert_eq!(fs::read_to_string(root.path().join("mover/cgroup.procs")).unwrap(), "0");
+    }
+}
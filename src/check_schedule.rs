@@ -0,0 +1,77 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::Duration;
+use serde_json::Value;
+use crate::tiering_manager::TIERING_CHECK_INTERVAL;
+
+// fraction of the interval randomly added or removed, unless
+// tiering_check_jitter says otherwise
+pub const CHECK_JITTER: f64 = 0.1;
+// how often the wait between checks looks for a `drive-manager check-now`
+pub const CHECK_REQUEST_POLL_SEC: u64 = 5;
+
+// When tiering checks run:
+//   "tiering_check_sec": 7200, "tiering_check_jitter": 0.1
+// The jitter keeps hosts started together, or a check that always lands on
+// another nightly job, from lining up every time. Promotions are cheap and
+// demotions are not, so each pass can have its own schedule, and its own
+// rules over the top-level ones:
+//   "promotion": { "interval_sec": 900, "access_count_threshold": 2 },
+//   "demotion": { "interval_sec": 86400, "tier_capacity_threshold": 80 }
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckSchedule {
+    pub interval: Duration,
+    pub jitter: f64,
+}
+
+impl CheckSchedule {
+    pub fn from_config(config: &Value) -> Self {
+        Self {
+            interval: Duration::from_secs(config.get("tiering_check_sec").and_then(Value::as_u64).unwrap_or(TIERING_CHECK_INTERVAL).max(60)),
+            jitter: config.get("tiering_check_jitter").and_then(Value::as_f64).unwrap_or(CHECK_JITTER).clamp(0.0, 1.0),
+        }
+    }
+
+    // The schedule of the promotion or demotion pass, from its section's
+    // interval_sec and jitter, or the tiering check's when it has none
+    pub fn for_pass(config: &Value, pass: &str) -> Self {
+        let check = Self::from_config(config);
+        let section = config.get(pass);
+        let setting = |key: &str| section.and_then(|section| section.get(key));
+        Self {
+            interval: setting("interval_sec").and_then(Value::as_u64).map_or(check.interval, |sec| Duration::from_secs(sec.max(60))),
+            jitter: setting("jitter").and_then(Value::as_f64).map_or(check.jitter, |jitter| jitter.clamp(0.0, 1.0)),
+        }
+    }
+
+    // The wait before the next check, `fraction` being a random value in [0, 1)
+    pub fn delay(&self, fraction: f64) -> Duration {
+        self.interval.mul_f64(1.0 + (fraction * 2.0 - 1.0) * self.jitter)
+    }
+
+    pub fn next_delay(&self) -> Duration {
+        self.delay((RandomState::new().hash_one(self.interval) >> 11) as f64 / (1u64 << 53) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_schedule() {
+        let schedule = CheckSchedule::from_config(&json!({}));
+        assert_eq!(schedule.interval, Duration::from_secs(TIERING_CHECK_INTERVAL));
+        let schedule = CheckSchedule::from_config(&json!({ "tiering_check_sec": 1000, "tiering_check_jitter": 0.2 }));
+        assert_eq!((schedule.delay(0.0), schedule.delay(0.5)), (Duration::from_secs(800), Duration::from_secs(1000)));
+        for _ in 0..100 {
+            let delay = schedule.next_delay();
+            assert!(delay >= Duration::from_secs(800) && delay <= Duration::from_secs(1200));
+        }
+        assert_eq!(CheckSchedule::from_config(&json!({ "tiering_check_sec": 0, "tiering_check_jitter": 0 })).next_delay(), Duration::from_secs(60));
+        let config = json!({ "tiering_check_sec": 1000, "promotion": { "interval_sec": 900, "jitter": 0 } });
+        assert_eq!(CheckSchedule::for_pass(&config, "promotion"), CheckSchedule { interval: Duration::from_secs(900), jitter: 0.0 });
+        assert_eq!(CheckSchedule::for_pass(&config, "demotion"), CheckSchedule::from_config(&config));
+    }
+}
@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+// read at a time while hashing
+const READ_SIZE: usize = 1 << 20;
+pub const SEGMENT_MB: u64 = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+// How file contents are checksummed, from the checksums config section:
+//   "checksums": { "verify": "xxh3", "threads": 4, "segment_mb": 256 }
+// verify is what the native mover checks each copy against its source
+// with before the source goes: xxh3, blake3 or sha256, or "off", the
+// default. xxh3 keeps up with the fastest drives, blake3 and sha256 guard
+// against more than bit rot at a cost. A file bigger than segment_mb is
+// hashed a segment per thread, up to `threads` at once, and its checksum
+// is the hash of theirs, so it is only comparable with checksums taken the
+// same way. rsync checks its copies with its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checksums {
+    pub verify: Option<Algorithm>,
+    pub threads: usize,
+    pub segment_bytes: u64,
+}
+
+enum Hasher {
+    Xxh3(Box<Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "xxh3" => Some(Algorithm::Xxh3),
+            "blake3" => Some(Algorithm::Blake3),
+            "sha256" => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Xxh3 => "xxh3",
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    fn hasher(&self) -> Hasher {
+        match self {
+            Algorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Xxh3(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Xxh3(hasher) => hasher.digest128().to_be_bytes().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+impl Default for Checksums {
+    fn default() -> Self {
+        Self { verify: None, threads: 1, segment_bytes: SEGMENT_MB << 20 }
+    }
+}
+
+impl Checksums {
+    pub fn from_config(config: &Value) -> Self {
+        let Some(section) = config.get("checksums") else { return Self::default() };
+        Self {
+            verify: section.get("verify").and_then(Value::as_str).and_then(Algorithm::parse),
+            threads: section.get("threads").and_then(Value::as_u64).map_or_else(|| thread::available_parallelism().map_or(1, usize::from), |threads| threads as usize).max(1),
+            segment_bytes: section.get("segment_mb").and_then(Value::as_u64).unwrap_or(SEGMENT_MB).max(1) << 20,
+        }
+    }
+
+    // The first `len` bytes of `file` hashed with `algorithm`, in hex
+    pub fn checksum(&self, file: &File, len: u64, algorithm: Algorithm) -> io::Result<String> {
+        let segments = len.div_ceil(self.segment_bytes).max(1) as usize;
+        if segments == 1 {
+            return Ok(hex(&hash_range(file, 0, len, algorithm)?));
+        }
+        let next = AtomicUsize::new(0);
+        let digests = Mutex::new(vec![Vec::new(); segments]);
+        let hash_segments = || -> io::Result<()> {
+            loop {
+                let segment = next.fetch_add(1, Ordering::SeqCst);
+                if segment >= segments {
+                    return Ok(());
+                }
+                let start = segment as u64 * self.segment_bytes;
+                let digest = hash_range(file, start, (len - start).min(self.segment_bytes), algorithm)?;
+                digests.lock().unwrap()[segment] = digest;
+            }
+        };
+        thread::scope(|scope| {
+            let workers: Vec<_> = (1..self.threads.min(segments)).map(|_| scope.spawn(hash_segments)).collect();
+            let mine = hash_segments();
+            workers.into_iter().map(|worker| worker.join().unwrap()).chain([mine]).collect::<io::Result<()>>()
+        })?;
+        let mut hasher = algorithm.hasher();
+        for digest in digests.into_inner().unwrap() {
+            hasher.update(&digest);
+        }
+        Ok(hex(&hasher.finish()))
+    }
+
+    // Fail unless `copy` holds what `source` does, hashing the two at once
+    pub fn verify(&self, source: &File, copy: &File, len: u64, algorithm: Algorithm) -> io::Result<()> {
+        let (source_sum, copy_sum) = thread::scope(|scope| {
+            let source_sum = scope.spawn(|| self.checksum(source, len, algorithm));
+            let copy_sum = self.checksum(copy, len, algorithm);
+            (source_sum.join().unwrap(), copy_sum)
+        });
+        let (source_sum, copy_sum) = (source_sum?, copy_sum?);
+        if source_sum != copy_sum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("the copy failed verification: {:?} checksum {} is not the source's {}", algorithm, copy_sum, source_sum)));
+        }
+        Ok(())
+    }
+}
+
+fn hash_range(file: &File, start: u64, len: u64, algorithm: Algorithm) -> io::Result<Vec<u8>> {
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0; READ_SIZE.min(len as usize)];
+    let mut done = 0;
+    while done < len {
+        let want = (len - done).min(buf.len() as u64) as usize;
+        let n = file.read_at(&mut buf[..want], start + done)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file shrank while being hashed"));
+        }
+        hasher.update(&buf[..n]);
+        done += n as u64;
+    }
+    Ok(hasher.finish())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Write;
+
+    fn file(contents: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_checksum() {
+        let checksums = Checksums::from_config(&json!({ "checksums": { "verify": "blake3", "threads": 3, "segment_mb": 1 } }));
+        assert_eq!((checksums.verify, checksums.threads, checksums.segment_bytes), (Some(Algorithm::Blake3), 3, 1 << 20));
+        assert_eq!(Checksums::from_config(&json!({ "checksums": { "verify": "off" } })).verify, None);
+        let small = file(b"abc");
+        assert_eq!(checksums.checksum(&small, 3, Algorithm::Sha256).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(checksums.checksum(&small, 3, Algorithm::Blake3).unwrap(), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+        assert_eq!(checksums.checksum(&small, 3, Algorithm::Xxh3).unwrap().len(), 32);
+
+        // segments come out the same however many threads hash them
+        let contents: Vec<u8> = (0..(5 << 20) + 7).map(|i| (i % 251) as u8).collect();
+        let big = file(&contents);
+        let single = Checksums { threads: 1, ..checksums.clone() };
+        for algorithm in [Algorithm::Xxh3, Algorithm::Blake3, Algorithm::Sha256] {
+            assert_eq!(checksums.checksum(&big, contents.len() as u64, algorithm).unwrap(), single.checksum(&big, contents.len() as u64, algorithm).unwrap());
+        }
+        let mut damaged = contents.clone();
+        damaged[3 << 20] ^= 1;
+        let damaged = file(&damaged);
+        checksums.verify(&big, &big, contents.len() as u64, Algorithm::Xxh3).unwrap();
+        let e = checksums.verify(&big, &damaged, contents.len() as u64, Algorithm::Xxh3).unwrap_err();
+        assert!(e.to_string().contains("failed verification"), "{}", e);
+    }
+}
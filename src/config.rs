@@ -0,0 +1,323 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use crate::drive_manager::DriveManager;
+use crate::metadata_db::DB_PATH;
+use crate::persist_mounts::Persistence;
+
+pub const INCLUDE_DIR: &str = "conf.d";
+// The config layout this version reads. A file without a schema_version
+// predates it and is version 0.
+pub const SCHEMA_VERSION: u64 = 1;
+
+// Each migration takes a config from the version at its index to the next
+const MIGRATIONS: [fn(&mut Value); SCHEMA_VERSION as usize] = [migrate_v0];
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+// The settings read at start, typed and with their defaults. Sections
+// with a module of their own, such as reserve or power, are read there
+// from the whole document, kept as `raw`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    // unset when bcachefs takes the drives instead
+    pub filesystem: Option<String>,
+    pub exclude_drives: Vec<String>,
+    // when set, the only drives managed; any other is left alone
+    pub include_drives: Vec<String>,
+    // never format a drive, only use those already in the filesystem
+    pub never_format: bool,
+    pub db_path: String,
+    pub hotplug: bool,
+    pub watch_config: bool,
+    pub unmount_on_shutdown: bool,
+    pub scan_threads: usize,
+    pub btrfs_send: bool,
+    // partition new drives rather than format them whole
+    pub partition_drives: bool,
+    pub max_formats_per_run: u64,
+    pub persist_mounts: Persistence,
+    #[serde(skip)]
+    pub raw: Value,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            filesystem: None,
+            exclude_drives: Vec::new(),
+            include_drives: Vec::new(),
+            never_format: false,
+            db_path: DB_PATH.to_string(),
+            hotplug: false,
+            watch_config: true,
+            unmount_on_shutdown: false,
+            scan_threads: 1,
+            btrfs_send: false,
+            partition_drives: true,
+            max_formats_per_run: DriveManager::MAX_FORMATS_PER_RUN,
+            persist_mounts: Persistence::None,
+            raw: Value::Object(Default::default()),
+        }
+    }
+}
+
+impl Config {
+    // A setting of the wrong type is an error naming it, rather than being
+    // taken as unset
+    pub fn from_value(raw: Value) -> io::Result<Self> {
+        let mut config: Config = serde_path_to_error::deserialize(&raw)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", e.path(), e.inner())))?;
+        config.raw = raw;
+        Ok(config)
+    }
+
+    pub fn is_excluded(&self, serial: &str) -> bool {
+        self.exclude_drives.iter().any(|drive| drive == serial) || (!self.include_drives.is_empty() && !self.include_drives.iter().any(|drive| drive == serial))
+    }
+}
+
+// A config file or fragment we read, by its extension
+pub fn is_config_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ["toml", "yaml", "yml", "json"].iter().any(|known| ext == *known))
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml")
+}
+
+// A JSON, TOML or YAML config file, as JSON whichever it is
+pub fn read_file(path: &Path) -> io::Result<Value> {
+    let content = fs::read_to_string(path)?;
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        let table: toml::Table = toml::from_str(&content).map_err(|e| invalid(path, e))?;
+        serde_json::to_value(table).map_err(|e| invalid(path, e))
+    } else if is_yaml(path) {
+        serde_yaml::from_str(&content).map_err(|e| invalid(path, e))
+    } else {
+        serde_json::from_str(&content).map_err(|e| invalid(path, e))
+    }
+}
+
+// Lay `overlay` over `base`. Objects are merged key by key, all the way
+// down; anything else, arrays included, is replaced outright.
+pub fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// v0 is the layout the first releases read: filesystem, exclude_drives
+// as a list of serials and the thresholds, all at the top level. v1 reads
+// every one of them as it was, so only the version changes. There never
+// was a single pool to split, the tiers having had a pool each from the
+// start, and exclude_drives is not turned into include_drives as only the
+// drives plugged in could say what the allowlist should hold.
+fn migrate_v0(_config: &mut Value) {}
+
+// Upgrade `config` to SCHEMA_VERSION in place, returning the version it
+// was. Fragments such as conf.d files go through the same steps.
+pub fn migrate(config: &mut Value, path: &Path) -> io::Result<u64> {
+    let from = match config.get("schema_version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or_else(|| invalid(path, "schema_version is not a whole number"))?,
+    };
+    if from > SCHEMA_VERSION {
+        return Err(invalid(path, format!("schema_version {} is newer than this drive-manager reads ({})", from, SCHEMA_VERSION)));
+    }
+    if !config.is_object() {
+        return Err(invalid(path, "not a table of settings"));
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        info!("Migrating {} from schema_version {} to {}", path.display(), version, version + 1);
+        migration(config);
+    }
+    config["schema_version"] = Value::from(SCHEMA_VERSION);
+    Ok(from)
+}
+
+// Replace the file at `path` with `config`, in the format it was in,
+// keeping the old one beside it as <name>.bak. Each is synced before the
+// rename, and the directory after it.
+fn write_back(path: &Path, config: &Value) -> io::Result<()> {
+    let content = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::to_string_pretty(config).map_err(|e| invalid(path, e))?
+    } else if is_yaml(path) {
+        serde_yaml::to_string(config).map_err(|e| invalid(path, e))?
+    } else {
+        serde_json::to_string_pretty(config).map_err(|e| invalid(path, e))? + "\n"
+    };
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    fs::copy(path, &backup)?;
+    fs::File::open(&backup)?.sync_all()?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    fs::File::open(path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")))?.sync_all()?;
+    // JSON has no comments to lose
+    if path.extension().is_some_and(|ext| ext != "json") {
+        warn!("Comments in {} were not kept; the original, comments and all, is in {}", path.display(), Path::new(&backup).display());
+    }
+    Ok(())
+}
+
+// The *.toml, *.yaml and *.json files in `dir`, in byte order of their names so
+// the merge comes out the same everywhere, e.g. 10-pools.toml before
+// 20-policies.toml
+pub fn includes(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok).map(|entry| entry.path())
+            .filter(|path| path.is_file() && is_config_file(path))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(files)
+}
+
+// Where the fragments merged over the config at `path` are: the
+// include_dir it names, or the conf.d beside it
+pub fn include_dir(path: &Path, config: &Value) -> PathBuf {
+    match config.get("include_dir").and_then(Value::as_str) {
+        Some(dir) => PathBuf::from(dir),
+        None => path.parent().unwrap_or(Path::new(".")).join(INCLUDE_DIR),
+    }
+}
+
+// The config at `path`, with the files in the conf.d beside it (or the
+// include_dir it names) merged over it, then the named profile from its
+// "profiles" section over that. Files in an older layout are upgraded as
+// they are read; with `write_upgraded` the main file is saved that way too.
+pub fn load(path: &Path, profile: Option<&str>, write_upgraded: bool) -> io::Result<Value> {
+    let mut config = read_file(path)?;
+    let from = migrate(&mut config, path)?;
+    if from < SCHEMA_VERSION {
+        if write_upgraded {
+            write_back(path, &config)?;
+            info!("Wrote {} upgraded to schema_version {}", path.display(), SCHEMA_VERSION);
+        } else {
+            warn!("{} uses schema_version {}; run with --write-config to save it upgraded to {}", path.display(), from, SCHEMA_VERSION);
+        }
+    }
+    for include in includes(&include_dir(path, &config))? {
+        let mut fragment = read_file(&include)?;
+        migrate(&mut fragment, &include)?;
+        merge(&mut config, fragment);
+    }
+    let profiles = config.as_object_mut().and_then(|config| config.remove("profiles"));
+    if let Some(name) = profile {
+        let selected = profiles.as_ref().and_then(|profiles| profiles.get(name)).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no profile named {} in {}", name, path.display())))?;
+        merge(&mut config, selected);
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_merge() {
+        let mut base = json!({ "filesystem": "ext4", "load_throttle": { "max_load_per_cpu": 1.0, "check_sec": 30 }, "exclude_drives": ["a"] });
+        merge(&mut base, json!({ "load_throttle": { "check_sec": 10 }, "exclude_drives": ["b"] }));
+        assert_eq!(base, json!({ "filesystem": "ext4", "load_throttle": { "max_load_per_cpu": 1.0, "check_sec": 10 }, "exclude_drives": ["b"] }));
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, r#"{ "filesystem": "ext4", "tier_capacity_threshold": 85, "profiles": { "quiet": { "move_workers": 1 } } }"#).unwrap();
+        fs::create_dir(dir.path().join(INCLUDE_DIR)).unwrap();
+        fs::write(dir.path().join("conf.d/20-late.toml"), "tier_capacity_threshold = 90\n").unwrap();
+        fs::write(dir.path().join("conf.d/10-early.toml"), "tier_capacity_threshold = 80\n[power]\nups = \"ups@nas\"\n").unwrap();
+        fs::write(dir.path().join("conf.d/15-power.yaml"), "power:\n  battery_pause: true\n").unwrap();
+        fs::write(dir.path().join("conf.d/README"), "not config").unwrap();
+
+        let config = load(&path, None, false).unwrap();
+        assert_eq!(config["tier_capacity_threshold"], 90);
+        assert_eq!(config["power"]["ups"], "ups@nas");
+        assert_eq!(config["power"]["battery_pause"], true);
+        assert!(config.get("profiles").is_none());
+        assert_eq!(load(&path, Some("quiet"), false).unwrap()["move_workers"], 1);
+        assert_eq!(load(&path, Some("loud"), false).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        fs::write(dir.path().join("conf.d/30-broken.toml"), "tier_capacity_threshold = \n").unwrap();
+        assert!(load(&path, None, false).unwrap_err().to_string().contains("30-broken.toml"));
+    }
+
+    #[test]
+    fn test_typed_config() {
+        let config = Config::from_value(json!({ "filesystem": "xfs", "exclude_drives": ["WD-1"], "hotplug": true, "power": { "ups": "ups@nas" } })).unwrap();
+        assert_eq!(config.filesystem.as_deref(), Some("xfs"));
+        assert!(config.is_excluded("WD-1"));
+        assert!(config.hotplug && config.watch_config && config.partition_drives);
+        assert_eq!((config.db_path.as_str(), config.scan_threads, config.max_formats_per_run), (DB_PATH, 1, 1));
+        assert_eq!(config.raw["power"]["ups"], "ups@nas");
+        assert!(!config.never_format);
+        let allowlist = Config::from_value(json!({ "include_drives": ["WD-1", "WD-2"], "exclude_drives": ["WD-2"], "never_format": true })).unwrap();
+        assert!(!allowlist.is_excluded("WD-1"));
+        assert!(allowlist.is_excluded("WD-2") && allowlist.is_excluded("WD-3"));
+        assert!(allowlist.never_format);
+        let e = Config::from_value(json!({ "filesystem": "xfs", "scan_threads": "four" })).unwrap_err();
+        assert_eq!(e.to_string(), "scan_threads: invalid type: string \"four\", expected usize");
+        let e = Config::from_value(json!({ "exclude_drives": ["WD-1", 2] })).unwrap_err();
+        assert!(e.to_string().starts_with("exclude_drives[1]: invalid type: integer `2`"));
+        assert_eq!(Config::from_value(json!({ "persist_mounts": "systemd" })).unwrap().persist_mounts, Persistence::Systemd);
+        assert!(Config::from_value(json!({ "persist_mounts": "crontab" })).unwrap_err().to_string().starts_with("persist_mounts: unknown variant `crontab`"));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        // as the first releases had it
+        let v0 = json!({ "filesystem": "ext4", "exclude_drives": ["WD-1"], "tier_capacity_threshold": 85, "access_time_threshold": 28800, "access_count_threshold": 3 });
+        fs::write(&path, v0.to_string()).unwrap();
+
+        let config = load(&path, None, false).unwrap();
+        let mut upgraded = v0.clone();
+        upgraded["schema_version"] = json!(SCHEMA_VERSION);
+        assert_eq!(config, upgraded);
+        // left alone without --write-config
+        assert!(read_file(&path).unwrap().get("schema_version").is_none());
+
+        load(&path, None, true).unwrap();
+        assert_eq!(read_file(&path).unwrap(), config);
+        assert_eq!(read_file(&dir.path().join("config.json.bak")).unwrap(), v0);
+        assert!(!dir.path().join("config.json.tmp").exists());
+
+        let toml = dir.path().join("config.toml");
+        fs::write(&toml, "# the drives to keep\nfilesystem = \"xfs\"\n").unwrap();
+        load(&toml, None, true).unwrap();
+        assert_eq!(read_file(&toml).unwrap(), json!({ "filesystem": "xfs", "schema_version": SCHEMA_VERSION }));
+        assert!(fs::read_to_string(dir.path().join("config.toml.bak")).unwrap().starts_with("# the drives to keep"));
+
+        fs::write(&path, r#"{ "schema_version": 99 }"#).unwrap();
+        assert!(load(&path, None, false).unwrap_err().to_string().contains("newer"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use crate::{config, mount_watch};
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE | libc::IN_ONLYDIR;
+
+// Watches the config file and the fragments in its include dir with
+// inotify. Editors save by writing a new file and renaming it over the old,
+// which leaves a watch on the file itself watching nothing, so the
+// directories are watched and their events matched by name. An include dir
+// made after the watch starts is only seen at the next restart.
+pub struct ConfigWatcher {
+    inotify: File,
+    config_dir: i32,
+    include_dir: Option<i32>,
+    file_name: OsString,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path, include_dir: &Path) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { File::from_raw_fd(fd) };
+        let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file", path.display())))?;
+        let config_dir = add_watch(&inotify, path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")))?;
+        let include_dir = match add_watch(&inotify, include_dir) {
+            Ok(wd) => Some(wd),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self { inotify, config_dir, include_dir, file_name: file_name.to_os_string() })
+    }
+
+    // Block until the config file, or a fragment in the include dir, is
+    // written, replaced or removed
+    pub fn wait(&mut self) -> io::Result<()> {
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            let len = self.inotify.read(&mut buf)?;
+            if mount_watch::parse(&buf[..len]).into_iter().any(|(wd, _, _, name)| self.concerns(wd, name)) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn concerns(&self, wd: i32, name: &OsStr) -> bool {
+        let fragment = config::is_config_file(Path::new(name));
+        (wd == self.config_dir && name == self.file_name) || (Some(wd) == self.include_dir && fragment)
+    }
+}
+
+fn add_watch(inotify: &File, dir: &Path) -> io::Result<i32> {
+    let c_path = CString::new(dir.as_os_str().as_bytes())?;
+    let wd = unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), c_path.as_ptr(), WATCH_MASK) };
+    if wd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(wd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_config_watcher() {
+        let dir = tempdir().unwrap();
+        let config = dir.path().join("config.json");
+        fs::write(&config, "{}").unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        let mut watcher = ConfigWatcher::new(&config, &dir.path().join("conf.d")).unwrap();
+        assert!(watcher.concerns(watcher.config_dir, OsStr::new("config.json")));
+        assert!(!watcher.concerns(watcher.config_dir, OsStr::new("config.json.swp")));
+        assert!(watcher.concerns(watcher.include_dir.unwrap(), OsStr::new("10-pools.toml")));
+        assert!(watcher.concerns(watcher.include_dir.unwrap(), OsStr::new("15-power.yaml")));
+        assert!(!watcher.concerns(watcher.include_dir.unwrap(), OsStr::new("README")));
+        // saved the way editors do, through a rename
+        fs::write(dir.path().join("config.json.new"), "{ \"tier_capacity_threshold\": 70 }").unwrap();
+        fs::rename(dir.path().join("config.json.new"), &config).unwrap();
+        watcher.wait().unwrap();
+        assert!(ConfigWatcher::new(&config, &dir.path().join("missing.d")).unwrap().include_dir.is_none());
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use log::{debug, error};
+use serde_json::Value;
+
+pub const CONTROL_SOCKET: &str = "/run/drive-manager/control.sock";
+
+// Where the service listens for `drive-manager ctl`:
+//   "control_socket": "/run/drive-manager/control.sock"
+pub fn socket_path(config: &Value) -> PathBuf {
+    PathBuf::from(config.get("control_socket").and_then(Value::as_str).unwrap_or(CONTROL_SOCKET))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlCommand {
+    Pause,
+    Resume,
+    RunTiering,
+    Reload,
+}
+
+impl ControlCommand {
+    pub const ALL: [ControlCommand; 4] = [ControlCommand::Pause, ControlCommand::Resume, ControlCommand::RunTiering, ControlCommand::Reload];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ControlCommand::Pause => "pause",
+            ControlCommand::Resume => "resume",
+            ControlCommand::RunTiering => "run-tiering",
+            ControlCommand::Reload => "reload",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|command| command.as_str() == name)
+    }
+}
+
+// A request is one line naming the command. The reply is one line too,
+// "ok <message>" or "error <message>".
+fn reply(result: &io::Result<String>) -> String {
+    match result {
+        Ok(message) => format!("ok {}\n", message),
+        Err(e) => format!("error {}\n", e),
+    }
+}
+
+fn parse_reply(line: &str) -> io::Result<String> {
+    let line = line.trim_end();
+    match line.split_once(' ').unwrap_or((line, "")) {
+        ("ok", message) => Ok(message.to_string()),
+        ("error", message) => Err(io::Error::other(message.to_string())),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", line))),
+    }
+}
+
+// The daemon's end. The socket is left to root alone, as everything it
+// can ask for changes what the service does.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // left behind by a run that did not shut down cleanly
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(Self { listener, path: path.to_path_buf() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Answer each request with what `handler` makes of it, one at a time
+    pub fn spawn<F>(self, handler: F)
+    where
+        F: Fn(ControlCommand) -> io::Result<String> + Send + 'static,
+    {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle_connection(stream, &handler) {
+                            debug!("Bad control request: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to accept a control connection: {}", e),
+                }
+            }
+        });
+    }
+}
+
+fn handle_connection(mut stream: UnixStream, handler: &dyn Fn(ControlCommand) -> io::Result<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let result = match ControlCommand::parse(line.trim()) {
+        Some(command) => handler(command),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown command {:?}", line.trim()))),
+    };
+    stream.write_all(reply(&result).as_bytes())
+}
+
+// The client's end: send `command` and return the service's message
+pub fn send(path: &Path, command: ControlCommand) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    stream.write_all(format!("{}\n", command.as_str()).as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    parse_reply(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_control_socket() {
+        assert_eq!(socket_path(&json!({})), Path::new(CONTROL_SOCKET));
+        for command in ControlCommand::ALL {
+            assert_eq!(ControlCommand::parse(command.as_str()), Some(command));
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/control.sock");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "stale").unwrap();
+        let socket = ControlSocket::bind(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        socket.spawn(|command| match command {
+            ControlCommand::Pause => Ok("moves paused".to_string()),
+            _ => Err(io::Error::other("not now")),
+        });
+        assert_eq!(send(&path, ControlCommand::Pause).unwrap(), "moves paused");
+        assert_eq!(send(&path, ControlCommand::Reload).unwrap_err().to_string(), "not now");
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"explode\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        assert!(parse_reply(&line).unwrap_err().to_string().starts_with("unknown command"));
+    }
+}
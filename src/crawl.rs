@@ -0,0 +1,161 @@
+use std::io;
+use std::thread;
+use std::time::{Instant, SystemTime};
+use log::{info, warn};
+use serde_json::Value;
+use crate::bandwidth::RateLimiter;
+use crate::checksum::Algorithm;
+use crate::file_metadata::CrawlProgress;
+use crate::metadata_db::MetadataDb;
+use crate::storage::{BranchStorage, ScannedFile};
+
+// files recorded, and where the crawl got to saved, at a time
+pub const BATCH: usize = 1000;
+
+// The first crawl of adopted drives, which on an array of many terabytes
+// can take days, run apart from the tiering scans and at a limited rate:
+//   "initial_crawl": { "files_per_sec": 500, "checksum": "xxh3", "checksum_mbps": 50, "batch": 1000 }
+// A drive is left out of the scans until its crawl is done. checksum, off
+// by default, has each file hashed with xxh3, blake3 or sha256 as it is
+// found, reading at up to checksum_mbps MiB/s. Where the crawl got to is
+// saved with each batch, so one cut off by a restart or `ctl pause`
+// carries on from there. `drive-manager status` shows how far each is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InitialCrawl {
+    files_per_sec: Option<u64>,
+    checksum: Option<Algorithm>,
+    checksum_bps: Option<u64>,
+    batch: usize,
+}
+
+impl InitialCrawl {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("initial_crawl")?;
+        Some(Self {
+            files_per_sec: section.get("files_per_sec").and_then(Value::as_u64).filter(|rate| *rate > 0),
+            checksum: section.get("checksum").and_then(Value::as_str).and_then(Algorithm::parse),
+            checksum_bps: section.get("checksum_mbps").and_then(Value::as_f64).filter(|mbps| *mbps > 0.0).map(|mbps| (mbps * 1024.0 * 1024.0) as u64),
+            batch: section.get("batch").and_then(Value::as_u64).map_or(BATCH, |batch| batch.max(1) as usize),
+        })
+    }
+
+    // Crawl a branch on from where it got to, handing each batch of files to
+    // `record`, until it is done or `stop` says to leave off. Returns
+    // whether it is done.
+    pub fn run(&self, storage: &BranchStorage, db: &MetadataDb, serial: &str, record: &dyn Fn(Vec<ScannedFile>) -> io::Result<()>, stop: &dyn Fn() -> bool) -> io::Result<bool> {
+        let now = SystemTime::now();
+        let mut progress = db.crawl(serial)?.unwrap_or_else(|| CrawlProgress {
+            serial: serial.to_string(), started_at: now, updated_at: now, finished_at: None,
+            files: 0, bytes: 0, checksummed_bytes: 0, cursor: None,
+        });
+        if progress.finished_at.is_some() {
+            return Ok(true);
+        }
+        let files_rate = self.files_per_sec.map(RateLimiter::new);
+        let checksum_rate = self.checksum_bps.map(RateLimiter::new);
+        loop {
+            if stop() {
+                return Ok(false);
+            }
+            let Some((files, last)) = storage.crawl(serial, progress.cursor.as_deref(), self.batch)? else {
+                progress.finished_at = Some(SystemTime::now());
+                progress.updated_at = SystemTime::now();
+                db.save_crawl(&progress)?;
+                info!("Finished the first crawl of {}: {} files, {} bytes", serial, progress.files, progress.bytes);
+                return Ok(true);
+            };
+            wait(files_rate.as_ref(), files.len() as u64);
+            let found = files.len();
+            let mut recorded = Vec::new();
+            for file in files {
+                if stop() {
+                    break;
+                }
+                if let Some(algorithm) = self.checksum {
+                    wait(checksum_rate.as_ref(), file.size);
+                    match storage.checksum_file(serial, &file.path, algorithm) {
+                        Ok(Some(checksum)) => {
+                            db.set_checksum(&file.path, algorithm.name(), &checksum, file.size, SystemTime::now())?;
+                            progress.checksummed_bytes += file.size;
+                        }
+                        Ok(None) => {}
+                        Err(e) => warn!("Failed to checksum {} on {}: {}", file.path.display(), serial, e),
+                    }
+                }
+                progress.files += 1;
+                progress.bytes += file.size;
+                recorded.push(file);
+            }
+            // cut off part way, the next run starts after the last file recorded
+            if recorded.len() == found {
+                progress.cursor = Some(last);
+            } else if let Some(file) = recorded.last() {
+                progress.cursor = Some(file.path.clone());
+            }
+            record(recorded)?;
+            progress.updated_at = SystemTime::now();
+            db.save_crawl(&progress)?;
+        }
+    }
+}
+
+fn wait(limiter: Option<&RateLimiter>, amount: u64) {
+    let Some(limiter) = limiter else { return };
+    let wait = limiter.reserve(amount, Instant::now());
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use serde_json::json;
+    use crate::executor::SystemExecutor;
+    use crate::storage::{Branch, Storage};
+
+    #[test]
+    fn test_initial_crawl() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in ["b/2.mkv", "a/1.mkv", "a/sub/3.mkv", "c.mkv", "a0.mkv"] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"abc").unwrap();
+        }
+        let storage = BranchStorage::with_executor(vec![Branch { serial: "WD-1".to_string(), tier: "cold".to_string(), path: dir.path().to_path_buf() }], false, Arc::new(SystemExecutor));
+        storage.set_crawling("WD-1", true);
+        assert!(storage.scan().unwrap().is_empty());
+        let db = MetadataDb::open_in_memory().unwrap();
+        let crawl = InitialCrawl::from_config(&json!({ "initial_crawl": { "checksum": "sha256", "batch": 2 } })).unwrap();
+        let recorded = RefCell::new(Vec::new());
+        let record = |files: Vec<ScannedFile>| {
+            recorded.borrow_mut().extend(files.into_iter().map(|file| file.path));
+            Ok(())
+        };
+        // cut off part way through the second batch, as a restart would
+        let checked = Cell::new(0);
+        let stop = || {
+            checked.set(checked.get() + 1);
+            checked.get() > 5
+        };
+        assert!(!crawl.run(&storage, &db, "WD-1", &record, &stop).unwrap());
+        let progress = db.crawl("WD-1").unwrap().unwrap();
+        assert_eq!((progress.files, progress.bytes, progress.cursor.as_deref()), (3, 9, Some(Path::new("a0.mkv"))));
+        assert!(crawl.run(&storage, &db, "WD-1", &record, &|| false).unwrap());
+        let paths: Vec<PathBuf> = ["a/1.mkv", "a/sub/3.mkv", "a0.mkv", "b/2.mkv", "c.mkv"].iter().map(PathBuf::from).collect();
+        assert_eq!(*recorded.borrow(), paths);
+        let progress = db.crawl("WD-1").unwrap().unwrap();
+        assert!(progress.finished_at.is_some());
+        assert_eq!((progress.files, progress.checksummed_bytes), (5, 15));
+        assert_eq!(db.checksum(Path::new("c.mkv")).unwrap(), Some(("sha256".to_string(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string())));
+        // done, so it is not walked again
+        assert!(crawl.run(&storage, &db, "WD-1", &|_| panic!("crawled again"), &|| false).unwrap());
+        storage.set_crawling("WD-1", false);
+        assert_eq!(storage.scan().unwrap().len(), 5);
+        assert_eq!(InitialCrawl::from_config(&json!({})), None);
+    }
+}
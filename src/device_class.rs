@@ -0,0 +1,87 @@
+use serde_json::Value;
+use crate::grouping::glob_match;
+use crate::lsblk::BlockDevice;
+
+// the classes a drive can be given, fastest first
+pub const CLASSES: [&str; 3] = ["nvme", "ssd", "hdd"];
+
+// words in a model string that only solid-state drives use
+const SSD_MODEL_WORDS: [&str; 3] = ["ssd", "solid state", "nvme"];
+
+pub fn parse_class(name: &str) -> Option<&'static str> {
+    CLASSES.iter().find(|class| **class == name).copied()
+}
+
+// Corrections for drives whose rota and tran lie, as USB-SATA bridges often
+// do, reporting every drive behind them as a spinning disk:
+//   "drive_class": {
+//     "drives": { "S4EWNX0R123456": "ssd" },
+//     "models": [{ "model": "Extreme Pro*", "class": "ssd" }],
+//     "guess_usb": true
+//   }
+// A drive listed by id wins over a model pattern, the first of which that
+// matches wins. Failing both, a USB drive reported as rotational whose model
+// says it is an SSD is taken at its word, unless guess_usb is false.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClassOverrides {
+    drives: Vec<(String, &'static str)>,
+    models: Vec<(String, &'static str)>,
+    guess_usb: bool,
+}
+
+impl ClassOverrides {
+    pub fn from_config(config: &Value) -> Self {
+        let section = config.get("drive_class");
+        let setting = |key: &str| section.and_then(|section| section.get(key));
+        let class = |value: Option<&Value>| value.and_then(Value::as_str).and_then(parse_class);
+        Self {
+            drives: setting("drives").and_then(Value::as_object).map(|drives| {
+                drives.iter().filter_map(|(id, name)| Some((id.clone(), class(Some(name))?))).collect()
+            }).unwrap_or_default(),
+            models: setting("models").and_then(Value::as_array).map(|rules| {
+                rules.iter().filter_map(|rule| Some((rule.get("model")?.as_str()?.to_lowercase(), class(rule.get("class"))?))).collect()
+            }).unwrap_or_default(),
+            guess_usb: setting("guess_usb").and_then(Value::as_bool).unwrap_or(true),
+        }
+    }
+
+    // The class `device` really is, when that is not what lsblk says
+    pub fn class_for(&self, device: &BlockDevice) -> Option<&'static str> {
+        let model = device.model.as_deref().unwrap_or_default().to_lowercase();
+        let class = self.drives.iter().find(|(id, _)| id == device.id()).map(|(_, class)| *class)
+            .or_else(|| self.models.iter().find(|(pattern, _)| glob_match(pattern.as_bytes(), model.as_bytes())).map(|(_, class)| *class))
+            .or_else(|| {
+                let bridged = device.rota && device.tran.as_deref() == Some("usb");
+                (self.guess_usb && bridged && SSD_MODEL_WORDS.iter().any(|word| model.contains(word))).then_some("ssd")
+            })?;
+        (class != device.block_class()).then_some(class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn device(serial: &str, model: &str, rota: bool, tran: &str) -> BlockDevice {
+        BlockDevice { serial: Some(serial.to_string()), model: Some(model.to_string()), rota, tran: Some(tran.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn test_class_for() {
+        let overrides = ClassOverrides::from_config(&json!({ "drive_class": {
+            "drives": { "USB-1": "nvme", "USB-2": "floppy" },
+            "models": [{ "model": "ASM1153*", "class": "ssd" }, { "model": "*", "class": "hdd" }],
+        } }));
+        assert_eq!(overrides.class_for(&device("USB-1", "ASM1153E", true, "usb")), Some("nvme"));
+        assert_eq!(overrides.class_for(&device("USB-2", "asm1153e", true, "usb")), Some("ssd"));
+        assert_eq!(overrides.class_for(&device("WD-1", "WDC WD80EFAX", true, "sata")), None);
+
+        let guessing = ClassOverrides::from_config(&json!({}));
+        assert_eq!(guessing.class_for(&device("USB-3", "Samsung Portable SSD T7", true, "usb")), Some("ssd"));
+        assert_eq!(guessing.class_for(&device("USB-4", "Elements 25A3", true, "usb")), None);
+        assert_eq!(guessing.class_for(&device("S-1", "Samsung SSD 870", false, "sata")), None);
+        let strict = ClassOverrides::from_config(&json!({ "drive_class": { "guess_usb": false } }));
+        assert_eq!(strict.class_for(&device("USB-3", "Samsung Portable SSD T7", true, "usb")), None);
+    }
+}
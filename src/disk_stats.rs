@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+
+// how often the managed drives' I/O is sampled, unless disk_stats_sec says
+// otherwise; 0 turns sampling off
+pub const SAMPLE_SEC: u64 = 60;
+// how long samples are kept, unless disk_stats_keep_days says otherwise
+pub const KEEP_DAYS: u64 = 7;
+
+// The cumulative counters of one disk in /proc/diskstats
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DiskCounters {
+    pub reads: u64,
+    pub sectors_read: u64,
+    pub read_ms: u64,
+    pub writes: u64,
+    pub sectors_written: u64,
+    pub write_ms: u64,
+    // time with I/O in flight
+    pub io_ms: u64,
+}
+
+// A disk's I/O between two samples
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DiskIo {
+    // share of the time the disk had I/O in flight, 0 to 1
+    pub util: f64,
+    // average time each read or write took, 0 without any
+    pub read_latency_ms: f64,
+    pub write_latency_ms: f64,
+    pub iops: f64,
+    pub bytes_written: u64,
+    pub read_bps: f64,
+    pub write_bps: f64,
+}
+
+impl DiskIo {
+    pub fn between(last: &DiskCounters, now: &DiskCounters, elapsed: Duration) -> Self {
+        let elapsed_ms = elapsed.as_millis() as f64;
+        if elapsed_ms <= 0.0 {
+            return Self::default();
+        }
+        let reads = now.reads.saturating_sub(last.reads);
+        let writes = now.writes.saturating_sub(last.writes);
+        let latency = |ms: u64, ios: u64| if ios == 0 { 0.0 } else { ms as f64 / ios as f64 };
+        // diskstats counts 512 byte sectors whatever the disk's own size
+        let bytes_read = now.sectors_read.saturating_sub(last.sectors_read) * 512;
+        let bytes_written = now.sectors_written.saturating_sub(last.sectors_written) * 512;
+        Self {
+            util: (now.io_ms.saturating_sub(last.io_ms) as f64 / elapsed_ms).min(1.0),
+            read_latency_ms: latency(now.read_ms.saturating_sub(last.read_ms), reads),
+            write_latency_ms: latency(now.write_ms.saturating_sub(last.write_ms), writes),
+            iops: (reads + writes) as f64 * 1000.0 / elapsed_ms,
+            bytes_written,
+            read_bps: bytes_read as f64 * 1000.0 / elapsed_ms,
+            write_bps: bytes_written as f64 * 1000.0 / elapsed_ms,
+        }
+    }
+
+    // the slower of reads and writes
+    pub fn latency_ms(&self) -> f64 {
+        self.read_latency_ms.max(self.write_latency_ms)
+    }
+}
+
+// The counters of each whole disk in /proc/diskstats. Partitions, loop and
+// ram devices are left out.
+pub fn parse(diskstats: &str) -> HashMap<String, DiskCounters> {
+    let disks: Vec<(&str, DiskCounters)> = diskstats.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let field = |index: usize| fields.get(index)?.parse().ok();
+        Some((*fields.get(2)?, DiskCounters { reads: field(3)?, sectors_read: field(5)?, read_ms: field(6)?, writes: field(7)?, sectors_written: field(9)?, write_ms: field(10)?, io_ms: field(12)? }))
+    }).collect();
+    disks.iter()
+        .filter(|(name, _)| !["loop", "ram", "zram", "sr"].iter().any(|prefix| name.starts_with(prefix)))
+        .filter(|(name, _)| !disks.iter().any(|(other, _)| is_partition(name, other)))
+        .map(|(name, counters)| (name.to_string(), *counters))
+        .collect()
+}
+
+// sda1 of sda, nvme0n1p1 of nvme0n1, but not sdaa of sda
+pub fn is_partition(name: &str, disk: &str) -> bool {
+    name.strip_prefix(disk).is_some_and(|rest| {
+        let number = rest.strip_prefix('p').unwrap_or(rest);
+        !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit())
+    })
+}
+
+// The name a disk has in /proc/diskstats, from its device path
+pub fn kernel_name(path: &str) -> String {
+    path.strip_prefix("/dev/").unwrap_or(path).to_string()
+}
+
+pub fn sample_interval(config: &Value) -> Option<Duration> {
+    let sec = config.get("disk_stats_sec").and_then(Value::as_u64).unwrap_or(SAMPLE_SEC);
+    (sec > 0).then(|| Duration::from_secs(sec))
+}
+
+pub fn keep_for(config: &Value) -> Duration {
+    Duration::from_secs(config.get("disk_stats_keep_days").and_then(Value::as_u64).unwrap_or(KEEP_DAYS) * 24 * 3600)
+}
+
+// Samples the I/O of the managed drives, given as (serial, kernel name),
+// from a /proc tree. The first sample only sets the baseline.
+pub struct DiskStatsCollector {
+    proc_root: PathBuf,
+    last: Option<(SystemTime, HashMap<String, DiskCounters>)>,
+}
+
+impl DiskStatsCollector {
+    pub fn new(proc_root: &Path) -> Self {
+        Self { proc_root: proc_root.to_path_buf(), last: None }
+    }
+
+    pub fn sample(&mut self, now: SystemTime, drives: &[(String, String)]) -> io::Result<Vec<(String, DiskIo)>> {
+        let disks = parse(&fs::read_to_string(self.proc_root.join("diskstats"))?);
+        let Some((then, last)) = self.last.replace((now, disks.clone())) else { return Ok(Vec::new()) };
+        let elapsed = now.duration_since(then).unwrap_or_default();
+        Ok(drives.iter()
+            .filter_map(|(serial, name)| Some((serial.clone(), DiskIo::between(last.get(name)?, disks.get(name)?, elapsed))))
+            .collect())
+    }
+}
+
+// What a tier's drives did together: average throughput and IOPS over
+// the samples taken, summing the drives sampled at once
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TierIo {
+    pub tier: String,
+    pub read_bps: f64,
+    pub write_bps: f64,
+    pub iops: f64,
+    // the average utilisation of the tier's busiest drive, 0 to 1
+    pub busiest: f64,
+    pub samples: usize,
+}
+
+impl TierIo {
+    // The tier's share of what all the tiers read and did, 0 to 1
+    pub fn read_share(&self, tiers: &[TierIo]) -> f64 {
+        share(self.read_bps, tiers.iter().map(|tier| tier.read_bps).sum())
+    }
+
+    pub fn iops_share(&self, tiers: &[TierIo]) -> f64 {
+        share(self.iops, tiers.iter().map(|tier| tier.iops).sum())
+    }
+}
+
+fn share(part: f64, total: f64) -> f64 {
+    if total > 0.0 { part / total } else { 0.0 }
+}
+
+// The drives' samples, as (serial, sampled at, I/O), totalled by the tier
+// each drive is in, by tier. Drives without a tier are left out.
+pub fn tier_io(branch_tiers: &HashMap<String, String>, samples: &[(String, SystemTime, DiskIo)]) -> Vec<TierIo> {
+    let mut totals: HashMap<&str, TierIo> = HashMap::new();
+    let mut times: HashMap<&str, HashSet<SystemTime>> = HashMap::new();
+    // each drive's summed utilisation and sample count
+    let mut drives: HashMap<&str, (&str, f64, usize)> = HashMap::new();
+    for (serial, sampled_at, io) in samples {
+        let Some(tier) = branch_tiers.get(serial) else { continue };
+        let total = totals.entry(tier).or_insert_with(|| TierIo { tier: tier.clone(), ..Default::default() });
+        total.read_bps += io.read_bps;
+        total.write_bps += io.write_bps;
+        total.iops += io.iops;
+        times.entry(tier).or_default().insert(*sampled_at);
+        let drive = drives.entry(serial).or_insert((tier, 0.0, 0));
+        drive.1 += io.util;
+        drive.2 += 1;
+    }
+    let mut tiers: Vec<TierIo> = totals.into_values().map(|mut total| {
+        total.samples = times[total.tier.as_str()].len();
+        let samples = total.samples as f64;
+        total.read_bps /= samples;
+        total.write_bps /= samples;
+        total.iops /= samples;
+        total.busiest = drives.values().filter(|(tier, _, _)| *tier == total.tier).map(|(_, util, count)| util / *count as f64).fold(0.0, f64::max);
+        total
+    }).collect();
+    tiers.sort_by(|a, b| a.tier.cmp(&b.tier));
+    tiers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write_diskstats(root: &Path, reads: u64, read_ms: u64, io_ms: u64) {
+        fs::write(root.join("diskstats"), format!(
+            "   8       0 sda {} 0 {} {} 10 0 80 50 0 {} 2\n   8       1 sda1 {} 0 {} {} 10 0 80 50 0 {} 2\n   8      16 sdb 1 0 8 1 1 0 8 1 0 0 0\n",
+            reads, reads * 8, read_ms, io_ms, reads, reads * 8, read_ms, io_ms,
+        )).unwrap();
+    }
+
+    #[test]
+    fn test_sample() {
+        let proc_root = tempdir().unwrap();
+        let mut collector = DiskStatsCollector::new(proc_root.path());
+        let drives = vec![("WD-1".to_string(), kernel_name("/dev/sda")), ("gone".to_string(), "sdz".to_string())];
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        write_diskstats(proc_root.path(), 100, 500, 1000);
+        assert!(collector.sample(t0, &drives).unwrap().is_empty());
+        write_diskstats(proc_root.path(), 300, 1500, 6000);
+        let samples = collector.sample(t0 + Duration::from_secs(10), &drives).unwrap();
+        assert_eq!(samples, [("WD-1".to_string(), DiskIo { util: 0.5, read_latency_ms: 5.0, write_latency_ms: 0.0, iops: 20.0, bytes_written: 0, read_bps: 81920.0, write_bps: 0.0 })]);
+        assert_eq!(samples[0].1.latency_ms(), 5.0);
+    }
+
+    #[test]
+    fn test_parse() {
+        let disks = parse("   8       0 sda 1 0 8 1 1 0 8 1 0 7 2\n 259       0 nvme0n1 1 0 8 1 1 0 8 1 0 3 2\n 259       1 nvme0n1p1 1 0 8 1 1 0 8 1 0 3 2\n   7       0 loop0 1 0 8 1 1 0 8 1 0 9 2\n");
+        let mut names: Vec<&String> = disks.keys().collect();
+        names.sort();
+        assert_eq!(names, ["nvme0n1", "sda"]);
+        assert_eq!((disks["sda"].io_ms, disks["sda"].sectors_written), (7, 8));
+    }
+
+    #[test]
+    fn test_tier_io() {
+        let tiers: HashMap<String, String> = [("NVME-1", "hot"), ("WD-1", "cold"), ("WD-2", "cold")].iter().map(|(serial, tier)| (serial.to_string(), tier.to_string())).collect();
+        let at = |sec| SystemTime::UNIX_EPOCH + Duration::from_secs(sec);
+        let io = |util, read_bps, iops| DiskIo { util, read_bps, write_bps: read_bps / 2.0, iops, ..Default::default() };
+        let samples = [
+            ("NVME-1".to_string(), at(60), io(0.1, 300.0, 30.0)), ("WD-1".to_string(), at(60), io(0.2, 100.0, 10.0)), ("WD-2".to_string(), at(60), io(0.6, 0.0, 0.0)),
+            ("NVME-1".to_string(), at(120), io(0.3, 500.0, 50.0)), ("WD-1".to_string(), at(120), io(0.4, 100.0, 10.0)), ("gone".to_string(), at(120), io(1.0, 1000.0, 100.0)),
+        ];
+        let totals = tier_io(&tiers, &samples);
+        assert_eq!(totals, [
+            TierIo { tier: "cold".to_string(), read_bps: 100.0, write_bps: 50.0, iops: 10.0, busiest: 0.6, samples: 2 },
+            TierIo { tier: "hot".to_string(), read_bps: 400.0, write_bps: 200.0, iops: 40.0, busiest: 0.2, samples: 2 },
+        ]);
+        // the hot tier did four fifths of the reads
+        assert_eq!(totals[1].read_share(&totals), 0.8);
+        assert_eq!(totals[0].iops_share(&totals), 0.2);
+        assert!(tier_io(&tiers, &[]).is_empty());
+    }
+}
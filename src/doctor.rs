@@ -0,0 +1,393 @@
+use std::env;
+use std::ffi::{CString, OsStr};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+use crate::archive::Archive;
+use crate::bcachefs::{self, Bcachefs};
+use crate::btrfs::BtrfsOptions;
+use crate::drive_manager::DriveManager;
+use crate::encryption::Encryption;
+use crate::metadata_db::MetadataDb;
+use crate::mover::Mover;
+use crate::project_quota::ProjectQuotas;
+use crate::transcripts::Transcript;
+use crate::zfs::{Dataset, ZfsReport};
+
+// a copy whose progress is older than this has stopped; progress is saved
+// every few seconds while rsync runs
+pub const STUCK_AFTER: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub subject: String,
+    pub detail: String,
+    // what to do about it
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    pub fn ok(subject: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self { severity: Severity::Ok, subject: subject.into(), detail: detail.into(), fix: None }
+    }
+
+    pub fn warn(subject: impl Into<String>, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { severity: Severity::Warn, subject: subject.into(), detail: detail.into(), fix: Some(fix.into()) }
+    }
+
+    pub fn fail(subject: impl Into<String>, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { severity: Severity::Fail, subject: subject.into(), detail: detail.into(), fix: Some(fix.into()) }
+    }
+}
+
+// Where `program` is on the search path, if anywhere
+pub fn which(program: &str, search_path: &OsStr) -> Option<PathBuf> {
+    env::split_paths(search_path)
+        .map(|dir| dir.join(program))
+        .find(|path| fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0))
+}
+
+pub fn check_tools(config: &Value, search_path: &OsStr) -> Vec<Finding> {
+    let mut tools = if Bcachefs::from_config(config).is_some() {
+        vec!["bcachefs".to_string(), "lsblk".to_string(), "mount".to_string(), "umount".to_string(), "wipefs".to_string()]
+    } else {
+        let filesystem = config.get("filesystem").and_then(Value::as_str).unwrap_or("ext4").to_lowercase();
+        let mut tools = vec!["mergerfs".to_string(), "lsblk".to_string(), "mount".to_string(), "umount".to_string(),
+            "wipefs".to_string(), "parted".to_string(), format!("mkfs.{}", filesystem)];
+        if Mover::from_config(config) == Mover::Rsync {
+            tools.insert(0, "rsync".to_string());
+            if config.get("mover_ionice").is_some() {
+                tools.insert(1, "ionice".to_string());
+            }
+        }
+        tools
+    };
+    if config.get("power").and_then(|power| power.get("ups")).is_some() {
+        tools.push("upsc".to_string());
+    }
+    if ProjectQuotas::from_config(config).is_some() {
+        tools.push("xfs_quota".to_string());
+    }
+    let btrfs_send = config.get("btrfs_send").and_then(Value::as_bool).unwrap_or(false);
+    // new btrfs drives get their subvolume with it
+    let btrfs_layout = config.get("filesystem").and_then(Value::as_str).is_some_and(|filesystem| filesystem.eq_ignore_ascii_case("btrfs"))
+        && BtrfsOptions::from_config(config).subvolumes;
+    if btrfs_send || btrfs_layout {
+        tools.push("btrfs".to_string());
+    }
+    if ZfsReport::from_config(config).is_some() {
+        tools.extend(["zfs".to_string(), "zpool".to_string()]);
+    }
+    if Encryption::from_config(config).is_some() {
+        tools.push("cryptsetup".to_string());
+    }
+    if Archive::from_config(config).is_some() {
+        tools.push("aws".to_string());
+    }
+    let missing: Vec<&str> = tools.iter().map(String::as_str).filter(|tool| which(tool, search_path).is_none()).collect();
+    if missing.is_empty() {
+        vec![Finding::ok("tools", tools.join(", "))]
+    } else {
+        vec![Finding::fail("tools", format!("not found on PATH: {}", missing.join(", ")), format!("install {} and make sure the service's PATH includes it", missing.join(", ")))]
+    }
+}
+
+// /proc/self/mounts escapes spaces and the like as octal
+pub fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(u8::is_ascii_digit) {
+            if let Ok(byte) = u8::from_str_radix(&field[i + 1..i + 4], 8) {
+                out.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Whether each tier's pool is mounted, and answering. `mounts` is the
+// content of /proc/self/mounts.
+pub fn check_mounts(mounts: &str) -> Vec<Finding> {
+    let mounted: Vec<(String, String)> = mounts.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields.len() >= 3).then(|| (unescape_mount_field(fields[1]), fields[2].to_string()))
+    }).collect();
+    let mut findings = Vec::new();
+    let drives = mounted.iter().filter(|(mountpoint, _)| mountpoint.starts_with(&format!("{}/", DriveManager::MOUNT_PATH))).count();
+    findings.push(if drives > 0 {
+        Finding::ok("drives", format!("{} mounted under {}", drives, DriveManager::MOUNT_PATH))
+    } else {
+        Finding::fail("drives", format!("nothing is mounted under {}", DriveManager::MOUNT_PATH), "start the service, or check its log with `journalctl -u drive-manager`")
+    });
+    for tier in DriveManager::TIERS {
+        let mount = format!("{}/{}", DriveManager::MERGERFS_MOUNT_PATH, tier);
+        let subject = format!("pool {}", mount);
+        findings.push(match mounted.iter().find(|(mountpoint, _)| *mountpoint == mount) {
+            None => Finding::warn(subject, "not mounted", "fine if there are no drives for this tier; otherwise restart the service"),
+            Some((_, fstype)) if fstype != "fuse.mergerfs" => Finding::warn(subject, format!("mounted as {}, not mergerfs", fstype), format!("unmount whatever is on {} and restart the service", mount)),
+            // a mergerfs process that died leaves "Transport endpoint is not connected"
+            Some(_) => match fs::read_dir(&mount) {
+                Ok(_) => Finding::ok(subject, "mergerfs is answering"),
+                Err(e) => Finding::fail(subject, format!("mergerfs is not answering: {}", e), format!("run `umount -l {}` and restart the service", mount)),
+            },
+        });
+    }
+    findings
+}
+
+// For the bcachefs backend: whether the filesystem is mounted, and whether
+// its targets are what the config says. `sysfs_dir` holds a directory per
+// mounted bcachefs filesystem.
+pub fn check_bcachefs(bcachefs: &Bcachefs, mounts: &str, sysfs_dir: &Path) -> Vec<Finding> {
+    let subject = format!("bcachefs {}", bcachefs.mountpoint);
+    let fstype = mounts.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields.len() >= 3 && unescape_mount_field(fields[1]) == bcachefs.mountpoint).then(|| fields[2].to_string())
+    });
+    match fstype.as_deref() {
+        None => return vec![Finding::fail(subject, "not mounted", "start the service, or check its log with `journalctl -u drive-manager`")],
+        Some("bcachefs") => {}
+        Some(other) => return vec![Finding::fail(subject, format!("mounted as {}, not bcachefs", other), format!("unmount whatever is on {} and restart the service", bcachefs.mountpoint))],
+    }
+    let filesystems: Vec<PathBuf> = fs::read_dir(sysfs_dir).map(|entries| entries.filter_map(Result::ok).map(|entry| entry.path()).collect()).unwrap_or_default();
+    let [filesystem] = filesystems.as_slice() else {
+        return vec![Finding::ok(subject, "mounted; targets not checked with other bcachefs filesystems mounted")];
+    };
+    let options = bcachefs::read_options(&filesystem.join("options"));
+    let mut findings = vec![Finding::ok(subject.clone(), "mounted")];
+    for (option, tier) in &bcachefs.targets {
+        let actual = options.get(option).map_or("unknown", String::as_str);
+        findings.push(if actual == tier {
+            Finding::ok(format!("bcachefs {}", option), tier.clone())
+        } else {
+            Finding::warn(format!("bcachefs {}", option), format!("is {}, the config says {}", actual, tier), "restart the service to apply it, or add a drive for that tier")
+        });
+    }
+    findings
+}
+
+// For the ZFS report: each dataset's fill against max_used_percent
+pub fn check_zfs(report: &ZfsReport, datasets: io::Result<Vec<Dataset>>) -> Vec<Finding> {
+    let datasets = match datasets {
+        Ok(datasets) => datasets,
+        Err(e) => return vec![Finding::fail("zfs", format!("datasets could not be listed: {}", e), "check that ZFS is installed and its pools are imported, or remove the zfs section")],
+    };
+    if datasets.is_empty() {
+        return vec![Finding::warn("zfs", "none of the configured datasets exist", "fix the names in zfs.datasets")];
+    }
+    let over_limit = report.over_limit(&datasets);
+    datasets.iter().map(|dataset| {
+        let detail = format!("{:.0}% used", dataset.used_percent());
+        if over_limit.contains(&dataset) {
+            Finding::warn(format!("zfs {}", dataset.name), format!("{}, past max_used_percent", detail), "free up space or grow the pool")
+        } else {
+            Finding::ok(format!("zfs {}", dataset.name), detail)
+        }
+    }).collect()
+}
+
+pub fn check_db(db: &MetadataDb, now: SystemTime) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    findings.push(match db.integrity_check() {
+        Ok(problems) if problems == ["ok"] => Finding::ok("database", "integrity check passed"),
+        Ok(problems) => Finding::fail("database", format!("integrity check failed: {}", problems.join("; ")),
+            "stop the service and move the database aside; the next scan rebuilds it, losing access history"),
+        Err(e) => Finding::fail("database", format!("could not be checked: {}", e), "make sure the database file is readable and not on a failing disk"),
+    });
+    findings.push(match db.write_locked(Duration::from_secs(2)) {
+        Ok(false) => Finding::ok("database lock", "free"),
+        Ok(true) => Finding::warn("database lock", "another process has held the write lock for over 2s",
+            "if the service is not running, find what holds it with `fuser` on the database file and stop it"),
+        Err(e) => Finding::fail("database lock", format!("could not be taken: {}", e), "check the database directory is writable"),
+    });
+    match db.transfers() {
+        Ok(transfers) => {
+            let stuck: Vec<String> = transfers.iter()
+                .filter(|transfer| now.duration_since(transfer.updated_at).unwrap_or_default() > STUCK_AFTER)
+                .map(|transfer| transfer.info.src.display().to_string())
+                .collect();
+            findings.push(if stuck.is_empty() {
+                Finding::ok("moves", format!("{} copying", transfers.len()))
+            } else {
+                Finding::warn("moves", format!("no progress for over {} minutes: {}", STUCK_AFTER.as_secs() / 60, stuck.join(", ")),
+                    "if the service is stopped these are left from a crash and clear when it starts; otherwise look for a hung rsync with `ps -C rsync`")
+            });
+        }
+        Err(e) => findings.push(Finding::fail("moves", format!("could not be read: {}", e), "see the database findings")),
+    }
+    if let Ok(failures) = db.failures() {
+        findings.push(if failures.is_empty() {
+            Finding::ok("failed moves", "none")
+        } else {
+            Finding::warn("failed moves", format!("{} moves gave up after all retries", failures.len()),
+                "see why with `drive-manager failures`, then `drive-manager failures retry`")
+        });
+    }
+    if let Ok(jobs) = db.running_jobs(now) {
+        for job in jobs {
+            findings.push(Finding::warn(format!("job {}", job.name), "tiering is paused for it",
+                format!("run `drive-manager job finish {}` if it is done", job.name)));
+        }
+    }
+    findings
+}
+
+// how many of the most recent failed commands are shown
+const FAILED_COMMANDS_SHOWN: usize = 5;
+
+// The commands the service ran most recently that failed, with their own
+// account of why
+pub fn check_transcripts(transcripts: &[Transcript]) -> Vec<Finding> {
+    let failed: Vec<&Transcript> = transcripts.iter().filter(|transcript| !transcript.succeeded()).collect();
+    if failed.is_empty() {
+        let detail = if transcripts.is_empty() { "none recorded yet".to_string() } else { format!("the last {} succeeded", transcripts.len()) };
+        return vec![Finding::ok("commands", detail)];
+    }
+    failed.iter().rev().take(FAILED_COMMANDS_SHOWN).map(|transcript| {
+        let age = SystemTime::now().duration_since(transcript.ran_at()).unwrap_or_default();
+        let status = transcript.code.map_or("failed to run".to_string(), |code| format!("exited with {}", code));
+        let stderr = if transcript.stderr.is_empty() { "no output".to_string() } else { transcript.stderr.replace('\n', " / ") };
+        Finding::warn(format!("`{}`", transcript.command), format!("{} {} minutes ago: {}", status, age.as_secs() / 60, stderr),
+            "the output is the command's own reason for failing; the full log is in the service's journal")
+    }).collect()
+}
+
+pub fn check_permissions(db_path: &Path) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    if unsafe { libc::geteuid() } != 0 {
+        findings.push(Finding::warn("user", "not root; mounting, formatting and moving other users' files all need it", "run drive-manager as root"));
+    }
+    let dir = db_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let writable = CString::new(dir.as_os_str().as_bytes()).is_ok_and(|dir| unsafe { libc::access(dir.as_ptr(), libc::W_OK) } == 0);
+    findings.push(if writable {
+        Finding::ok("database directory", format!("{} is writable", dir.display()))
+    } else {
+        Finding::fail("database directory", format!("{} is missing or not writable", dir.display()), format!("create {} and give the service's user write access", dir.display()))
+    });
+    findings
+}
+
+pub fn report(findings: &[Finding]) -> String {
+    let mut out = String::new();
+    for finding in findings {
+        let tag = match finding.severity {
+            Severity::Ok => "[ ok ]",
+            Severity::Warn => "[warn]",
+            Severity::Fail => "[FAIL]",
+        };
+        let _ = writeln!(out, "{} {}: {}", tag, finding.subject, finding.detail);
+        if let Some(fix) = &finding.fix {
+            let _ = writeln!(out, "       fix: {}", fix);
+        }
+    }
+    let problems = findings.iter().filter(|finding| finding.severity != Severity::Ok).count();
+    let _ = writeln!(out, "{}", if problems == 0 { "No problems found".to_string() } else { format!("{} problems found", problems) });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_metadata::{FileMoveInfo, TransferProgress};
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tools() {
+        let dir = tempdir().unwrap();
+        for tool in ["rsync", "mergerfs", "lsblk", "mount", "umount", "wipefs", "parted", "mkfs.ext4"] {
+            fs::write(dir.path().join(tool), "").unwrap();
+            fs::set_permissions(dir.path().join(tool), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        assert_eq!(check_tools(&json!({ "filesystem": "ext4" }), dir.path().as_os_str())[0].severity, Severity::Ok);
+        let missing = check_tools(&json!({ "filesystem": "xfs", "power": { "ups": "ups@nas" } }), dir.path().as_os_str());
+        assert_eq!(missing[0].detail, "not found on PATH: mkfs.xfs, upsc");
+        let missing = check_tools(&json!({ "filesystem": "btrfs", "btrfs_send": true }), dir.path().as_os_str());
+        assert_eq!(missing[0].detail, "not found on PATH: mkfs.btrfs, btrfs");
+        assert_eq!(check_tools(&json!({ "backend": "bcachefs" }), dir.path().as_os_str())[0].detail, "not found on PATH: bcachefs");
+        let missing = check_tools(&json!({ "filesystem": "ext4", "encryption": { "keyfile": "/etc/dm.key" } }), dir.path().as_os_str());
+        assert_eq!(missing[0].detail, "not found on PATH: cryptsetup");
+        let missing = check_tools(&json!({ "filesystem": "ext4", "archive": { "bucket": "nas" } }), dir.path().as_os_str());
+        assert_eq!(missing[0].detail, "not found on PATH: aws");
+    }
+
+    #[test]
+    fn test_bcachefs() {
+        let bcachefs = Bcachefs::from_config(&json!({ "backend": "bcachefs" })).unwrap();
+        let sysfs = tempdir().unwrap();
+        let options = bcachefs::options_dir(sysfs.path(), "fs-a");
+        fs::create_dir_all(&options).unwrap();
+        for (option, tier) in bcachefs::TARGETS {
+            fs::write(options.join(option), format!("{}\n", tier)).unwrap();
+        }
+        fs::write(options.join("background_target"), "none\n").unwrap();
+        assert_eq!(check_bcachefs(&bcachefs, "", sysfs.path())[0].severity, Severity::Fail);
+        let findings = check_bcachefs(&bcachefs, "/dev/nvme0n1:/dev/sda /mnt/bcachefs bcachefs rw 0 0\n", sysfs.path());
+        assert_eq!(findings[0], Finding::ok("bcachefs /mnt/bcachefs", "mounted"));
+        assert_eq!(findings.iter().filter(|finding| finding.severity == Severity::Warn).map(|finding| finding.detail.as_str()).collect::<Vec<_>>(), ["is none, the config says cold"]);
+    }
+
+    #[test]
+    fn test_zfs() {
+        let report = ZfsReport::from_config(&json!({ "zfs": { "max_used_percent": 80 } })).unwrap();
+        let dataset = |name: &str, used| Dataset { name: name.to_string(), used, available: 100 - used, mountpoint: None };
+        let findings = check_zfs(&report, Ok(vec![dataset("tank/media", 90), dataset("tank/vm", 10)]));
+        assert_eq!(findings.iter().map(|finding| finding.severity).collect::<Vec<_>>(), [Severity::Warn, Severity::Ok]);
+        assert_eq!(check_zfs(&report, Err(io::Error::other("zfs: not found")))[0].severity, Severity::Fail);
+    }
+
+    #[test]
+    fn test_mounts() {
+        let findings = check_mounts(
+            "/dev/sda1 /mnt/physical/hdd/SER\\040IAL ext4 rw 0 0\n\
+             /mnt/physical/hdd/SER\\040IAL /mnt/merged/cold fuse rw 0 0\n",
+        );
+        assert_eq!(findings[0], Finding::ok("drives", "1 mounted under /mnt/physical"));
+        assert_eq!(findings[1].severity, Severity::Warn);
+        assert_eq!(findings[3].detail, "mounted as fuse, not mergerfs");
+        assert_eq!(unescape_mount_field("a\\040b"), "a b");
+    }
+
+    #[test]
+    fn test_transcripts() {
+        let transcript = |code| Transcript { ran_at: 0, command: "parted /dev/sdb".to_string(), code, stderr: "Error: busy\nretry".to_string() };
+        assert_eq!(check_transcripts(&[transcript(Some(0))])[0].severity, Severity::Ok);
+        let findings = check_transcripts(&[transcript(Some(1)), transcript(Some(0))]);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].subject, "`parted /dev/sdb`");
+        assert!(findings[0].detail.starts_with("exited with 1 "));
+        assert!(findings[0].detail.ends_with("Error: busy / retry"));
+    }
+
+    #[test]
+    fn test_db() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100_000);
+        let info = FileMoveInfo { src: "a.mkv".into(), source_tier: "hot".to_string(), target_tier: "cold".to_string(), retries: 0 };
+        db.record_progress(&TransferProgress { info, bytes_copied: 1, bytes_total: 2, started_at: now - STUCK_AFTER * 2, updated_at: now - STUCK_AFTER * 2 }).unwrap();
+        let findings = check_db(&db, now);
+        assert_eq!(findings[0], Finding::ok("database", "integrity check passed"));
+        assert_eq!(findings[1].severity, Severity::Ok);
+        assert_eq!(findings[2].severity, Severity::Warn);
+        assert!(report(&findings).ends_with("1 problems found\n"));
+    }
+}
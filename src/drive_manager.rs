@@ -0,0 +1,1059 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal};
+use std::path::Path;
+use std::sync::Arc;
+use serde_json::Value;
+use log::{debug, error, info, warn};
+use crate::adopt;
+use crate::args::Args;
+use crate::bcachefs::{self, Bcachefs};
+use crate::btrfs::BtrfsOptions;
+use crate::config::{self, Config};
+use crate::device_class::ClassOverrides;
+use crate::encryption::Encryption;
+use crate::executor::{self, Executor, SystemExecutor};
+use crate::fill_strategy::FillPolicy;
+use crate::lsblk::{self, BlockDevice};
+use crate::partitions::ManagedPartitions;
+use crate::persist_mounts::{self, MountEntry, Persistence};
+use crate::pool_check;
+use crate::reload;
+use crate::project_quota::ProjectQuotas;
+use crate::reserve::ReservePolicy;
+use crate::secrets;
+use crate::storage::Branch;
+use crate::tiering_manager::tier_rank;
+use crate::zfs;
+
+pub struct DriveManager {
+    pub args: Args,
+    pub config: Config,
+    // each drive's tier in the registry, kept from when it was first set up
+    pub registered_tiers: HashMap<String, String>,
+    pub new_drive_mounted: bool,
+    // drives formatted since this process started
+    pub formatted: usize,
+    pub executor: Arc<dyn Executor>,
+    // asks whether to format a drive; None when there is nobody to ask
+    pub confirm: fn(&str) -> Option<bool>,
+}
+
+impl DriveManager {
+    pub const MOUNT_PATH: &'static str = "/mnt/physical";
+    pub const MERGERFS_MOUNT_PATH: &'static str = "/mnt/merged";
+    // formats allowed per start without --allow-bulk-format, unless the
+    // config's max_formats_per_run says otherwise
+    pub const MAX_FORMATS_PER_RUN: u64 = 1;
+    pub const TIERS: [&'static str; 3] = ["hot", "warm", "cold"];
+    pub const LSBLK_DISCOVER_CMD: [&'static str; 5] = [
+        "--all",
+        "--bytes",
+        "-po",
+        "ALIGNMENT,DISC-ALN,DAX,DISC-GRAN,DISC-MAX,DISC-ZERO,FSAVAIL,FSROOTS,FSSIZE,FSTYPE,FSUSED,FSUSE%,FSVER,GROUP,HCTL,HOTPLUG,KNAME,LABEL,LOG-SEC,MAJ:MIN,MIN-IO,MODE,MODEL,NAME,OPT-IO,OWNER,PARTFLAGS,PARTLABEL,PARTTYPE,PARTTYPENAME,PARTUUID,PATH,PHY-SEC,PKNAME,PTTYPE,PTUUID,RA,RAND,REV,RM,RO,ROTA,RQ-SIZE,SCHED,SERIAL,SIZE,START,STATE,SUBSYSTEMS,MOUNTPOINT,MOUNTPOINTS,TRAN,TYPE,UUID,VENDOR,WSAME,WWN,ZONED,ZONE-SZ,ZONE-WGRAN,ZONE-APP,ZONE-NR,ZONE-OMAX,ZONE-AMAX",
+        "--json",
+    ];
+
+    pub fn new(args: Args) -> io::Result<Self> {
+        let config = Self::read_valid_config(&args)?;
+        Ok(Self::with_config(args, config))
+    }
+
+    pub fn with_config(args: Args, config: Config) -> Self {
+        Self { args, config, registered_tiers: HashMap::new(), new_drive_mounted: false, formatted: 0, executor: Arc::new(SystemExecutor), confirm: ask_terminal }
+    }
+
+    // The config with its secret references resolved, checked against
+    // the types of the settings it holds
+    pub fn read_config(args: &Args) -> io::Result<Config> {
+        let path = Path::new(&args.config);
+        let mut config = config::load(path, args.profile.as_deref(), args.write_config)?;
+        secrets::resolve(&mut config, path.parent().unwrap_or(Path::new(".")), &SystemExecutor)?;
+        Config::from_value(config).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    // The config as read_config gives it, failing on any setting
+    // reload::validate finds wrong: a value of the wrong type or out of
+    // range for any key the modules read, or a section missing the key it
+    // cannot do without, rather than leaving the module to fall back to
+    // its default or skip the section. Keys no module reads are not checked.
+    pub fn read_valid_config(args: &Args) -> io::Result<Config> {
+        let config = Self::read_config(args)?;
+        let problems = reload::validate(&config.raw);
+        if !problems.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", args.config, problems.join("; "))));
+        }
+        Ok(config)
+    }
+
+    pub fn run_command(&self, cmd: &[&str]) -> Result<(), io::Error> {
+        self.run_command_with_input(cmd, b"")
+    }
+
+    // Run a command with `input` on its stdin. Commands are run directly,
+    // never through a shell, so arguments are passed exactly as given.
+    pub fn run_command_with_input(&self, cmd: &[&str], input: &[u8]) -> Result<(), io::Error> {
+        if self.args.dryrun {
+            info!("DRYRUN: {}", cmd.join(" "));
+            return Ok(());
+        }
+        info!("{}", cmd.join(" "));
+        let cmd: Vec<&OsStr> = cmd.iter().map(OsStr::new).collect();
+        let output = executor::checked(&cmd, self.executor.output_with_input(&cmd, input)?)?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !stdout.trim().is_empty() {
+            debug!("{}: {}", cmd[0].to_string_lossy(), stdout.trim());
+        }
+        Ok(())
+    }
+
+    // mkfs for `filesystem`, told not to ask before overwriting what is on
+    // `device`. Filesystems without a force flag answer a prompt on stdin
+    // instead.
+    pub fn mkfs_command(filesystem: &str, device: &str) -> Vec<String> {
+        let filesystem = filesystem.to_lowercase();
+        let force = match filesystem.as_str() {
+            "ext2" | "ext3" | "ext4" => Some("-F"),
+            "xfs" | "btrfs" | "f2fs" | "bcachefs" => Some("-f"),
+            _ => None,
+        };
+        let mut cmd = vec!["mkfs".to_string(), "-t".to_string(), filesystem];
+        cmd.extend(force.map(str::to_string));
+        cmd.push(device.to_string());
+        cmd
+    }
+
+    pub fn block_class_order(block_class: &str) -> i32 {
+        let class_order = HashMap::from([("nvme", 0), ("ssd", 1), ("hdd", 2)]);
+        class_order.get(block_class).copied().unwrap_or(3)
+    }
+
+    pub fn sort_block_device(block_device: &BlockDevice) -> i32 {
+        Self::block_class_order(block_device.block_class())
+    }
+
+    // Options set over the defaults below:
+    //   "mergerfs_options": { "cache.files": "off", "func.getattr": "newest" }
+    // A reload sets changed ones on the mounted pools as they run.
+    pub fn configured_mergerfs_options(config: &Value) -> Vec<(String, String)> {
+        config.get("mergerfs_options").and_then(Value::as_object).into_iter().flatten().filter_map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                _ => return None,
+            };
+            Some((key.clone(), value))
+        }).collect()
+    }
+
+    pub fn mergerfs_options(tier: &str, fill: &FillPolicy, configured: &[(String, String)]) -> Vec<String> {
+        let mergerfs_opts = [
+            "allow_other",
+            "nonempty",
+            "lazy-umount-mountpoint=true",
+            "moveonenospc=true",
+            "cache.files=auto-full",
+            "parallel-direct-writes=true",
+            "cache.writeback=true",
+            "cache.statfs=true",
+            "cache.symlinks=true",
+            "cache.readdir=true",
+            "posix_acl=false",
+            "async_read=false",
+            "dropcacheonclose=true",
+        ];
+        let mut options: Vec<String> = mergerfs_opts.iter().map(|opt| opt.to_string())
+            .chain([format!("category.create={}", fill.mergerfs_policy(tier))])
+            .collect();
+        options.retain(|option| !configured.iter().any(|(key, _)| option.split('=').next() == Some(key)));
+        options.extend(configured.iter().map(|(key, value)| format!("{}={}", key, value)));
+        options
+    }
+
+    // Set options on every mounted pool through its control file, as
+    // mergerfs takes most of them while running
+    pub fn set_pool_options(&self, options: &[(String, String)]) -> io::Result<()> {
+        for tier in Self::TIERS {
+            let control = Path::new(Self::MERGERFS_MOUNT_PATH).join(tier).join(".mergerfs");
+            if !control.exists() {
+                continue;
+            }
+            for (key, value) in options {
+                self.run_command(&["setfattr", "-n", &format!("user.mergerfs.{}", key), "-v", value, &control.to_string_lossy()])?;
+            }
+        }
+        Ok(())
+    }
+
+    // The branches of each pool mounted now, as mergerfs reports them
+    // through its control file. A pool that does not answer is left out.
+    pub fn pool_branches(&self) -> Vec<(String, Vec<String>)> {
+        let mut pools = Vec::new();
+        for tier in Self::TIERS {
+            let control = Path::new(Self::MERGERFS_MOUNT_PATH).join(tier).join(".mergerfs");
+            if !control.exists() {
+                continue;
+            }
+            let cmd = [OsStr::new("getfattr"), OsStr::new("--only-values"), OsStr::new("-n"), OsStr::new("user.mergerfs.branches"), control.as_os_str()];
+            match self.executor.output(&cmd).and_then(|output| executor::checked(&cmd, output)) {
+                Ok(output) => pools.push((tier.to_string(), pool_check::parse_branches(&String::from_utf8_lossy(&output.stdout)))),
+                Err(e) => warn!("Failed to read the branches of the {} pool: {}", tier, e),
+            }
+        }
+        pools
+    }
+
+    // The (serial, mount point) of the drives each tier's pool is set up with
+    pub fn expected_branches(&self, active_block_devices: &[BlockDevice]) -> Vec<(String, Vec<(String, String)>)> {
+        Self::tier_devices(active_block_devices, &self.drive_tiers()).into_iter().map(|(tier, devices)| {
+            let branches = devices.iter().filter_map(|device| Some((device.id().to_string(), device.filesystem_mountpoint()?.to_string()))).collect();
+            (tier.to_string(), branches)
+        }).collect()
+    }
+
+    // The tiers drives were given: the one a drive was adopted into, else
+    // the one the registry has kept for it
+    pub fn drive_tiers(&self) -> HashMap<String, String> {
+        let mut tiers = self.registered_tiers.clone();
+        tiers.extend(adopt::drive_tiers(&self.config.raw));
+        tiers
+    }
+
+    // A device's tier: the one it was given, else its class's
+    pub fn device_tier<'a>(device: &'a BlockDevice, tiers: &'a HashMap<String, String>) -> &'a str {
+        tiers.get(device.id()).map_or(device.tier(), String::as_str)
+    }
+
+    // Each tier's pool has its own drives and those of the tiers below
+    pub fn tier_devices(active_block_devices: &[BlockDevice], tiers: &HashMap<String, String>) -> Vec<(&'static str, Vec<BlockDevice>)> {
+        let mut tier_devices: Vec<(&'static str, Vec<BlockDevice>)> = Self::TIERS.iter().map(|tier| {
+            let devices = active_block_devices.iter().filter(|device| tier_rank(Self::device_tier(device, tiers)) >= tier_rank(tier)).cloned().collect();
+            (*tier, devices)
+        }).collect();
+        for (_, devices) in &mut tier_devices {
+            devices.sort_by_key(Self::sort_block_device);
+        }
+        tier_devices
+    }
+
+    pub fn branches(active_block_devices: &[BlockDevice], tiers: &HashMap<String, String>) -> Vec<Branch> {
+        active_block_devices.iter().filter_map(|device| Some(Branch {
+            serial: device.id().to_string(),
+            tier: Self::device_tier(device, tiers).to_string(),
+            path: device.filesystem_mountpoint()?.into(),
+        })).collect()
+    }
+
+    // A device's branch in a mergerfs pool, with its reserve as the
+    // branch's minfreespace
+    pub fn mergerfs_branch(device: &BlockDevice, reserve: &ReservePolicy) -> Option<String> {
+        let mountpoint = device.filesystem_mountpoint()?;
+        let size = device.filesystem_device().and_then(|filesystem| filesystem.size).or(device.size).unwrap_or(0);
+        Some(match reserve.bytes(device.id(), size) {
+            0 => mountpoint.to_string(),
+            bytes => format!("{}=RW,{}", mountpoint, bytes),
+        })
+    }
+
+    pub fn setup_mergerfs(&self, active_block_devices: &[BlockDevice]) {
+        let reserve = ReservePolicy::from_config(&self.config.raw);
+        let fill = FillPolicy::from_config(&self.config.raw);
+        for (tier, devices) in Self::tier_devices(active_block_devices, &self.drive_tiers()) {
+            info!("{} Devices: {:?}", tier, devices.iter().map(BlockDevice::id).collect::<Vec<_>>());
+            let glob = devices.iter().filter_map(|device| Self::mergerfs_branch(device, &reserve)).collect::<Vec<String>>().join(":");
+            // one pool failing to mount leaves the others up
+            if let Err(e) = self.mount_pool(tier, &glob, &fill) {
+                error!("Failed to mount the {} pool: {}", tier, e);
+            }
+        }
+    }
+
+    fn mount_pool(&self, tier: &str, glob: &str, fill: &FillPolicy) -> io::Result<()> {
+        let mount_point = format!("{}/{}", Self::MERGERFS_MOUNT_PATH, tier);
+        // mounted at boot from fstab or its unit, so only its branches are set
+        let control = Path::new(&mount_point).join(".mergerfs");
+        if control.exists() {
+            return self.run_command(&["setfattr", "-n", "user.mergerfs.branches", "-v", glob, &control.to_string_lossy()]);
+        }
+        fs::create_dir_all(&mount_point)?;
+        let options = Self::mergerfs_options(tier, fill, &Self::configured_mergerfs_options(&self.config.raw)).join(",");
+        self.run_command(&["mergerfs", "-o", &options, glob, &mount_point])
+    }
+
+    // The drive and pool mounts as set up for `active_block_devices`, to
+    // write down for boot
+    pub fn mount_entries(&self, active_block_devices: &[BlockDevice]) -> Vec<MountEntry> {
+        let reserve = ReservePolicy::from_config(&self.config.raw);
+        let fill = FillPolicy::from_config(&self.config.raw);
+        let mut entries: Vec<MountEntry> = active_block_devices.iter().filter_map(|device| {
+            let filesystem = device.filesystem_device()?;
+            let fstype = filesystem.fstype.clone()?;
+            let options = ["defaults".to_string()].into_iter().chain(self.mount_options(&fstype)).collect::<Vec<_>>().join(",");
+            Some(MountEntry {
+                // device names move between boots, filesystem UUIDs do not
+                what: filesystem.uuid.as_ref().map_or(filesystem.path.clone(), |uuid| format!("UUID={}", uuid)),
+                target: filesystem.mountpoint.clone()?,
+                fstype,
+                options: vec![options],
+                requires: Vec::new(),
+            })
+        }).collect();
+        let configured = Self::configured_mergerfs_options(&self.config.raw);
+        for (tier, devices) in Self::tier_devices(active_block_devices, &self.drive_tiers()) {
+            let branches: Vec<String> = devices.iter().filter_map(|device| Self::mergerfs_branch(device, &reserve)).collect();
+            if branches.is_empty() {
+                continue;
+            }
+            entries.push(MountEntry {
+                what: branches.join(":"),
+                target: format!("{}/{}", Self::MERGERFS_MOUNT_PATH, tier),
+                fstype: "fuse.mergerfs".to_string(),
+                options: Self::mergerfs_options(tier, &fill, &configured),
+                requires: devices.iter().filter_map(|device| device.filesystem_mountpoint().map(str::to_string)).collect(),
+            });
+        }
+        entries
+    }
+
+    // Write the mounts to fstab or systemd units, per persist_mounts, so
+    // the pools come back at boot before, or without, the service. The
+    // other of the two is cleared, so a mount is never written twice.
+    pub fn persist_mounts(&self, active_block_devices: &[BlockDevice]) -> io::Result<()> {
+        let entries = match self.config.persist_mounts {
+            Persistence::None => return Ok(()),
+            // nothing at boot holds the keyfile's containers open
+            _ if Encryption::from_config(&self.config.raw).is_some() => {
+                warn!("Not writing mounts for boot: the drives are encrypted and only unlocked by the service");
+                return Ok(());
+            }
+            _ => self.mount_entries(active_block_devices),
+        };
+        if self.args.dryrun {
+            info!("DRYRUN: would write {} mounts to {:?}", entries.len(), self.config.persist_mounts);
+            return Ok(());
+        }
+        let systemd = self.config.persist_mounts == Persistence::Systemd;
+        let units = if systemd { persist_mounts::units(&entries) } else { Vec::new() };
+        let fstab_changed = persist_mounts::write_fstab(Path::new(persist_mounts::FSTAB_PATH), if systemd { &[] } else { &entries })?;
+        let changed = persist_mounts::write_units(Path::new(persist_mounts::UNIT_DIR), &units)?;
+        if !fstab_changed && changed.is_empty() {
+            return Ok(());
+        }
+        // systemd makes units of fstab lines too
+        self.run_command(&["systemctl", "daemon-reload"])?;
+        let enabled: Vec<&str> = units.iter().filter(|(_, _, enable)| *enable).map(|(name, _, _)| name.as_str()).collect();
+        if !enabled.is_empty() {
+            self.run_command(&[&["systemctl", "enable"], enabled.as_slice()].concat())?;
+        }
+        info!("Wrote {} mounts to {}", entries.len(), if systemd { persist_mounts::UNIT_DIR } else { persist_mounts::FSTAB_PATH });
+        Ok(())
+    }
+
+    // Take the merged mounts down, for unmount_on_shutdown. A pool something
+    // still has files open in stays up and is reported.
+    pub fn unmount_pools(&self) {
+        for tier in Self::TIERS {
+            let mount_point = format!("{}/{}", Self::MERGERFS_MOUNT_PATH, tier);
+            if !Path::new(&mount_point).is_dir() {
+                continue;
+            }
+            if let Err(e) = self.run_command(&["umount", &mount_point]) {
+                error!("Failed to unmount the {} pool: {}", tier, e);
+            }
+        }
+    }
+
+    // Add a drive attached while running to its tier's pool and the pools
+    // above, through mergerfs's control file so nothing is remounted. A
+    // pool with no drives yet never mounted, so it is mounted now.
+    pub fn add_to_pools(&self, device: &BlockDevice) -> io::Result<()> {
+        let reserve = ReservePolicy::from_config(&self.config.raw);
+        let fill = FillPolicy::from_config(&self.config.raw);
+        let branch = Self::mergerfs_branch(device, &reserve)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not mounted", device.path)))?;
+        let rank = tier_rank(Self::device_tier(device, &self.drive_tiers()));
+        for tier in Self::TIERS.iter().filter(|tier| tier_rank(tier) <= rank) {
+            let control = Path::new(Self::MERGERFS_MOUNT_PATH).join(tier).join(".mergerfs");
+            if control.exists() {
+                self.run_command(&["setfattr", "-n", "user.mergerfs.branches", "-v", &format!("+>{}", branch), &control.to_string_lossy()])?;
+            } else {
+                self.mount_pool(tier, &branch, &fill)?;
+            }
+            info!("Added {} to the {} pool", device.id(), tier);
+        }
+        Ok(())
+    }
+
+    pub fn mount_point(block_device: &BlockDevice) -> String {
+        format!("{}/{}/{}", Self::MOUNT_PATH, block_device.block_class(), block_device.id())
+    }
+
+    pub fn mount_drive(&mut self, block_device: &BlockDevice) -> io::Result<BlockDevice> {
+        let block_device = &self.unlock(block_device)?;
+        let mount_point = Self::mount_point(block_device);
+        fs::create_dir_all(&mount_point)?;
+        let filesystem = block_device.filesystem_device()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no partitions or filesystem", block_device.path)))?;
+        // only mount if not already mounted in expected location
+        if filesystem.mountpoint.as_deref() != Some(mount_point.as_str()) {
+            self.mount(filesystem, &mount_point)?;
+            self.new_drive_mounted = true;
+        }
+        self.update_block_device(block_device)
+    }
+
+    // Mount options a drive's filesystem takes from the config
+    fn mount_options(&self, fstype: &str) -> Vec<String> {
+        match fstype {
+            // xfs only turns project quotas on at mount
+            "xfs" if ProjectQuotas::from_config(&self.config.raw).is_some() => vec!["prjquota".to_string()],
+            "btrfs" => BtrfsOptions::from_config(&self.config.raw).mount_options(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn mount(&self, filesystem: &BlockDevice, mount_point: &str) -> io::Result<()> {
+        let options = self.mount_options(filesystem.fstype.as_deref().unwrap_or_default()).join(",");
+        if options.is_empty() {
+            self.run_command(&["mount", &filesystem.path, mount_point])
+        } else {
+            self.run_command(&["mount", "-o", &options, &filesystem.path, mount_point])
+        }
+    }
+
+    // Give a freshly made btrfs filesystem on `device` the subvolume for
+    // the drive's tier, when the config's btrfs.subvolumes asks for it
+    fn create_btrfs_layout(&self, block_device: &BlockDevice, device: &str, filesystem: &str) -> io::Result<()> {
+        let options = BtrfsOptions::from_config(&self.config.raw);
+        if !filesystem.eq_ignore_ascii_case("btrfs") || !options.subvolumes {
+            return Ok(());
+        }
+        let mount_point = Self::mount_point(block_device);
+        fs::create_dir_all(&mount_point)?;
+        let tier = Self::device_tier(block_device, &self.drive_tiers()).to_string();
+        for cmd in options.layout_commands(device, &mount_point, &tier) {
+            self.run_command(&cmd.iter().map(String::as_str).collect::<Vec<_>>())?;
+        }
+        info!("Made {} the default subvolume of {} {}", BtrfsOptions::subvolume_name(&tier), device, block_device.id());
+        Ok(())
+    }
+
+    // Open the LUKS container holding the drive's filesystem with the
+    // encryption keyfile, if it is locked. The drive is returned as lsblk
+    // sees it after.
+    pub fn unlock(&self, block_device: &BlockDevice) -> io::Result<BlockDevice> {
+        let Some(container) = block_device.locked_container() else {
+            return Ok(block_device.clone());
+        };
+        let encryption = Encryption::from_config(&self.config.raw)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is encrypted: set encryption.keyfile in the config to unlock it", container.path)))?;
+        let uuid = container.uuid.as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} has no LUKS UUID", container.path)))?;
+        encryption.check_keyfile()?;
+        let open = encryption.open_command(&container.path, uuid);
+        self.run_command(&open.iter().map(String::as_str).collect::<Vec<_>>())?;
+        info!("Unlocked {} {} as {}", container.path, block_device.id(), Encryption::mapper_path(uuid));
+        self.update_block_device(block_device)
+    }
+
+    // Put a LUKS container on `target` and open it, when the config asks
+    // for encryption. Returns what to make the filesystem on: the mapper
+    // device, or `target` itself.
+    fn encrypt(&self, target: &str) -> io::Result<String> {
+        let Some(encryption) = Encryption::from_config(&self.config.raw) else {
+            return Ok(target.to_string());
+        };
+        encryption.check_keyfile()?;
+        let format = encryption.format_command(target);
+        self.run_command(&format.iter().map(String::as_str).collect::<Vec<_>>())?;
+        let container = self.block_device(target)?;
+        let uuid = container.uuid
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} has no LUKS UUID after luksFormat", target)))?;
+        let open = encryption.open_command(target, &uuid);
+        self.run_command(&open.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Ok(Encryption::mapper_path(&uuid))
+    }
+
+    // Mount a pooled drive that went away and came back over its branch,
+    // where the pools still look for it, dropping the mount it left behind.
+    // It is never formatted: one that lost its filesystem is left alone.
+    pub fn remount_branch(&self, block_device: &BlockDevice, mount_point: &Path, filesystem: &str) -> io::Result<()> {
+        let block_device = &self.unlock(block_device)?;
+        let device = block_device.filesystem_device().filter(|device| device.fstype.as_deref() == Some(filesystem))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} no longer holds a {} filesystem", block_device.path, filesystem)))?;
+        let mount_point = mount_point.to_string_lossy();
+        // nothing left mounted there is fine
+        if let Err(e) = self.run_command(&["umount", "-l", &mount_point]) {
+            debug!("Nothing to unmount at {}: {}", mount_point, e);
+        }
+        self.mount(device, &mount_point)
+    }
+
+    // Count a format against the per-run limit, refusing once it is used
+    // up, so a discovery bug or bad config cannot wipe a whole shelf of
+    // newly attached disks in one pass
+    pub fn allow_format(&mut self, block_device: &BlockDevice) -> io::Result<()> {
+        if zfs::is_member(block_device, &HashSet::new()) {
+            return Err(io::Error::other(format!("refusing to format {}: it is a ZFS pool member", block_device.path)));
+        }
+        if self.config.never_format {
+            return Err(io::Error::other(format!("refusing to format {}: never_format is set", block_device.path)));
+        }
+        let limit = self.config.max_formats_per_run;
+        if !self.args.allow_bulk_format && self.formatted as u64 >= limit {
+            return Err(io::Error::other(format!(
+                "refusing to format {}: {} drive(s) already formatted this run, the limit is {} (max_formats_per_run); restart with --allow-bulk-format to format more",
+                block_device.path, self.formatted, limit)));
+        }
+        self.confirm_format(block_device)?;
+        self.formatted += 1;
+        Ok(())
+    }
+
+    // Ask before wipefs and parted run, unless --assume-yes. With nobody to
+    // ask, as under systemd, a blank drive is formatted but one holding a
+    // filesystem, a RAID or LVM signature or a partition table is refused.
+    // A managed partition is all that is looked at, being all that is wiped.
+    fn confirm_format(&self, block_device: &BlockDevice) -> io::Result<()> {
+        if self.args.dryrun {
+            return Ok(());
+        }
+        let target = block_device.managed_partition.as_ref()
+            .and_then(|managed| block_device.children.iter().find(|partition| &partition.path == managed))
+            .unwrap_or(block_device);
+        let data = target.existing_data();
+        if self.args.assume_yes {
+            if let Some(data) = &data {
+                warn!("Formatting {} {}, which holds {}, as --assume-yes is set", block_device.path, block_device.id(), data);
+            }
+            return Ok(());
+        }
+        let question = format!(
+            "Format {} {} ({}, {} bytes){}? Everything on it will be erased.",
+            target.path, block_device.id(), block_device.model.as_deref().unwrap_or("unknown model"), block_device.size.unwrap_or(0),
+            data.as_ref().map_or(String::new(), |data| format!(", which holds {}", data)),
+        );
+        match ((self.confirm)(&question), data) {
+            (Some(true), _) | (None, None) => Ok(()),
+            (Some(false), _) => Err(io::Error::other(format!("refusing to format {}: not confirmed", target.path))),
+            (None, Some(data)) => Err(io::Error::other(format!(
+                "refusing to format {}: it holds {}; run with --assume-yes to format it anyway, or add {} to exclude_drives",
+                target.path, data, block_device.id()))),
+        }
+    }
+
+    pub fn format_drive(&mut self, block_device: &BlockDevice) -> io::Result<BlockDevice> {
+        let filesystem = self.config.filesystem.clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot format {}: filesystem is not set in the config", block_device.path)))?;
+        if let Some(managed) = &block_device.managed_partition {
+            // only the managed partition is formatted, the drive's other
+            // partitions and its partition table are left alone
+            let partition = block_device.filesystem_device()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no partition {} to manage", block_device.path, managed)))?;
+            self.allow_format(block_device)?;
+            let partition = partition.clone();
+            self.close_containers(&partition)?;
+            if partition.mountpoint.is_some() {
+                self.run_command(&["umount", "-l", &partition.path])?;
+            }
+            let target = self.encrypt(&partition.path)?;
+            let mkfs = Self::mkfs_command(&filesystem, &target);
+            self.run_command_with_input(&mkfs.iter().map(String::as_str).collect::<Vec<_>>(), b"y\n")?;
+            self.create_btrfs_layout(block_device, &target, &filesystem)?;
+            let updated_device = self.update_block_device(block_device)?;
+            return self.mount_drive(&updated_device);
+        }
+        self.allow_format(block_device)?;
+        self.release_drive(block_device)?;
+        let path = &block_device.path;
+        // a GPT with one partition before mkfs, unless
+        //   "partition_drives": false
+        // puts the filesystem on the whole disk. Drives already formatted
+        // either way are used as they are.
+        let target = if self.config.partition_drives {
+            self.run_command(&["parted", "-a", "optimal", path, "mklabel", "gpt", "mkpart", "primary", &filesystem, "0%", "100%"])?;
+            let updated_device = self.update_block_device(block_device)?;
+            let partition = updated_device.children.first()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no partitions after partitioning", path)))?;
+            partition.path.clone()
+        } else {
+            path.clone()
+        };
+        let target = self.encrypt(&target)?;
+        let mkfs = Self::mkfs_command(&filesystem, &target);
+        self.run_command_with_input(&mkfs.iter().map(String::as_str).collect::<Vec<_>>(), b"y\n")?;
+        self.create_btrfs_layout(block_device, &target, &filesystem)?;
+        let updated_device = self.update_block_device(block_device)?;
+        self.mount_drive(&updated_device)
+    }
+
+    // Bring up the bcachefs backend: create the filesystem across the
+    // drives if there is none yet, otherwise mount it and add any new
+    // drives, then point its targets at the configured tiers. A drive that
+    // cannot be added is logged and left out.
+    pub fn setup_bcachefs(&mut self, bcachefs: &Bcachefs, devices: &[BlockDevice]) -> io::Result<()> {
+        let mut membership = Bcachefs::membership(devices);
+        for device in &membership.foreign {
+            warn!("{} {} belongs to another bcachefs filesystem, leaving it alone", device.path, device.id());
+        }
+        fs::create_dir_all(&bcachefs.mountpoint)?;
+        let mounted = fs::read_to_string("/proc/self/mounts").is_ok_and(|mounts| {
+            mounts.lines().any(|line| line.split_whitespace().nth(1) == Some(bcachefs.mountpoint.as_str()))
+        });
+        if membership.members.is_empty() {
+            if membership.joining.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no drives to create the bcachefs filesystem on"));
+            }
+            for device in &membership.joining {
+                self.allow_format(device)?;
+            }
+            info!("Creating bcachefs on {:?}", membership.joining.iter().map(BlockDevice::id).collect::<Vec<_>>());
+            for device in &membership.joining {
+                self.release_drive(device)?;
+            }
+            let format = bcachefs.format_command(&membership.joining);
+            self.run_command(&format.iter().map(String::as_str).collect::<Vec<_>>())?;
+            membership.members = std::mem::take(&mut membership.joining);
+            membership.uuid = self.update_block_device(&membership.members[0]).ok()
+                .and_then(|device| Bcachefs::member_uuid(&device).map(str::to_string));
+        }
+        if !mounted {
+            let mount = bcachefs.mount_command(&membership.members);
+            self.run_command(&mount.iter().map(String::as_str).collect::<Vec<_>>())?;
+        }
+        for device in &membership.joining {
+            let added = self.allow_format(device).and_then(|()| self.release_drive(device)).and_then(|()| {
+                let add = bcachefs.add_command(device);
+                self.run_command(&add.iter().map(String::as_str).collect::<Vec<_>>())
+            });
+            match added {
+                Ok(()) => {
+                    info!("Added {} {} to bcachefs as {}", device.path, device.id(), Bcachefs::label(device));
+                    membership.members.push(device.clone());
+                }
+                Err(e) => error!("Failed to add {} {} to bcachefs: {}", device.path, device.id(), e),
+            }
+        }
+        match (&membership.uuid, self.args.dryrun) {
+            (Some(uuid), false) => {
+                let options = bcachefs::options_dir(Path::new(bcachefs::SYSFS_DIR), uuid);
+                for option in bcachefs.apply_targets(&options, &membership.members)? {
+                    info!("Set bcachefs {}", option);
+                }
+            }
+            (Some(_), true) => info!("DRYRUN: would set bcachefs targets {:?}", bcachefs.targets),
+            (None, _) => warn!("Could not find the bcachefs filesystem's UUID, leaving its targets as they are"),
+        }
+        Ok(())
+    }
+
+    // Unmount and close the open LUKS containers on `device` or its
+    // partitions, so what holds them can be wiped
+    fn close_containers(&self, device: &BlockDevice) -> io::Result<()> {
+        for holder in [device].into_iter().chain(&device.children).filter(|holder| holder.is_luks()) {
+            for mapper in &holder.children {
+                if mapper.mountpoint.is_some() {
+                    self.run_command(&["umount", "-l", &mapper.path])?;
+                }
+                self.run_command(&["cryptsetup", "close", &mapper.path])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Unmount and wipe a drive so it can be formatted
+    fn release_drive(&self, block_device: &BlockDevice) -> io::Result<()> {
+        self.close_containers(block_device)?;
+        // a whole disk container's child is its mapper device, closed above
+        for partition in block_device.children.iter().filter(|child| child.device_type != "crypt") {
+            self.run_command(&["umount", "-l", &partition.path])?;
+        }
+        if block_device.mountpoint.is_some() {
+            self.run_command(&["umount", "-l", &block_device.path])?;
+        }
+        self.run_command(&["wipefs", "--all", "--force", &block_device.path])
+    }
+
+    // Run lsblk, retrying with the minimal column set if this util-linux
+    // rejects one of the newer columns
+    fn lsblk(&self, args: &[&str]) -> io::Result<Vec<BlockDevice>> {
+        let overrides = ClassOverrides::from_config(&self.config.raw);
+        let managed_partitions = ManagedPartitions::from_config(&self.config.raw);
+        let mut devices = self.lsblk_devices(args)?;
+        for device in &mut devices {
+            if let Some(class) = overrides.class_for(device) {
+                info!("Treating {} {} ({}) as {} rather than {}", device.path, device.id(), device.model.as_deref().unwrap_or("unknown model"), class, device.block_class());
+                device.class = Some(class);
+            }
+            device.managed_partition = managed_partitions.partition_for(device);
+        }
+        Ok(devices)
+    }
+
+    fn lsblk_devices(&self, args: &[&str]) -> io::Result<Vec<BlockDevice>> {
+        let mut cmd = vec!["lsblk"];
+        cmd.extend(Self::LSBLK_DISCOVER_CMD);
+        cmd.extend(args);
+        let output = self.executor.output(&cmd.iter().map(OsStr::new).collect::<Vec<_>>())?;
+        if output.status.success() {
+            return lsblk::parse(&output.stdout);
+        }
+        warn!("lsblk rejected the full column list ({}), retrying with minimal columns", String::from_utf8_lossy(&output.stderr).trim());
+        let with_fsused = format!("{},{}", lsblk::LSBLK_MINIMAL_COLUMNS, lsblk::LSBLK_FSUSED_COLUMN);
+        let mut rejected = String::new();
+        for columns in [with_fsused.as_str(), lsblk::LSBLK_MINIMAL_COLUMNS] {
+            let mut cmd = vec!["lsblk", "--all", "--bytes", "-po", columns, "--json"];
+            cmd.extend(args);
+            let output = self.executor.output(&cmd.iter().map(OsStr::new).collect::<Vec<_>>())?;
+            if output.status.success() {
+                return lsblk::parse(&output.stdout);
+            }
+            rejected = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        }
+        Err(io::Error::other(format!("lsblk failed: {}", rejected)))
+    }
+
+    // The drive as lsblk sees it now. The managed partition is kept, as
+    // a format may have cleared the label it was found by.
+    pub fn update_block_device(&self, block_device: &BlockDevice) -> io::Result<BlockDevice> {
+        let mut updated = self.block_device(&block_device.path)?;
+        if block_device.managed_partition.is_some() {
+            updated.managed_partition = block_device.managed_partition.clone();
+        }
+        Ok(updated)
+    }
+
+    pub fn block_device(&self, path: &str) -> io::Result<BlockDevice> {
+        self.lsblk(&[path])?.into_iter().next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("lsblk returned nothing for {}", path)))
+    }
+
+    // The devices in imported zpools; none when ZFS is not installed
+    pub fn zfs_member_paths(&self) -> HashSet<String> {
+        let cmd = zfs::ZPOOL_MEMBERS_CMD.map(OsStr::new);
+        match self.executor.output(&cmd).and_then(|output| executor::checked(&cmd, output)) {
+            Ok(output) => zfs::parse_member_paths(&String::from_utf8_lossy(&output.stdout)),
+            Err(e) => {
+                debug!("Not checking zpool members: {}", e);
+                HashSet::new()
+            }
+        }
+    }
+
+    pub fn get_block_devices(&self) -> io::Result<Vec<BlockDevice>> {
+        // skip all non block devices
+        Ok(self.lsblk(&[])?.into_iter().filter(BlockDevice::is_disk).collect())
+    }
+}
+
+// Ask `question` on the terminal we were started from, None without one
+pub fn ask_terminal(question: &str) -> Option<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return None;
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer).ok()?;
+    Some(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::sync::Mutex;
+    use crate::fault_injection::{Fault, FaultInjector, FaultPoint, FaultyExecutor};
+
+    fn test_args() -> Args {
+        Args::parse_from(["--dryrun"]).unwrap()
+    }
+
+    fn typed(config: Value) -> Config {
+        Config::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn test_read_valid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let args = Args::parse_from(["--config", path.to_str().unwrap()]).unwrap();
+        fs::write(&path, r#"{ "filesystem": "xfs", "mover": "native", "tier_capacity_threshold": 80 }"#).unwrap();
+        assert_eq!(DriveManager::read_valid_config(&args).unwrap().filesystem.as_deref(), Some("xfs"));
+        fs::write(&path, r#"{ "filesystem": "xfs", "mover": "cp", "tier_capacity_threshold": 120, "access_tracking": "inotify" }"#).unwrap();
+        let e = DriveManager::read_valid_config(&args).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        for problem in ["mover is \"cp\"", "tier_capacity_threshold is 120", "access_tracking is \"inotify\""] {
+            assert!(e.to_string().contains(problem), "{}", e);
+        }
+        // read_config alone still takes it, as commands that only need the database do
+        assert!(DriveManager::read_config(&args).is_ok());
+    }
+
+    #[test]
+    fn test_run_command() {
+        let drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        let result = drive_manager.run_command(&["echo", "test"]);
+        assert!(result.is_ok());
+
+        let drive_manager = DriveManager::with_config(Args::parse_from(Vec::<String>::new()).unwrap(), typed(json!({})));
+        assert!(drive_manager.run_command(&["true"]).is_ok());
+        let e = drive_manager.run_command(&["ls", "/nonexistent"]).unwrap_err();
+        assert!(e.to_string().contains("No such file or directory"), "{}", e);
+    }
+
+    #[test]
+    fn test_mkfs_command() {
+        assert_eq!(DriveManager::mkfs_command("EXT4", "/dev/sdb1"), ["mkfs", "-t", "ext4", "-F", "/dev/sdb1"]);
+        assert_eq!(DriveManager::mkfs_command("xfs", "/dev/sdb1"), ["mkfs", "-t", "xfs", "-f", "/dev/sdb1"]);
+        assert_eq!(DriveManager::mkfs_command("vfat", "/dev/sdb1"), ["mkfs", "-t", "vfat", "/dev/sdb1"]);
+    }
+
+    #[test]
+    fn test_mergerfs_branch() {
+        let partition = BlockDevice { mountpoint: Some("/mnt/physical/hdd/WD-1".to_string()), size: Some(1000 << 30), ..Default::default() };
+        let drive = BlockDevice { children: vec![partition], ..device("WD-1", true, "sata") };
+        let reserve = ReservePolicy::from_config(&json!({ "reserve": { "WD-1": "1%" } }));
+        assert_eq!(DriveManager::mergerfs_branch(&drive, &reserve).unwrap(), "/mnt/physical/hdd/WD-1=RW,10737418240");
+        assert_eq!(DriveManager::mergerfs_branch(&drive, &ReservePolicy::default()).unwrap(), "/mnt/physical/hdd/WD-1");
+        assert_eq!(DriveManager::mergerfs_branch(&device("WD-2", true, "sata"), &reserve), None);
+        // formatted without a partition table
+        let whole_disk = BlockDevice { fstype: Some("xfs".to_string()), mountpoint: Some("/mnt/physical/hdd/WD-3".to_string()), size: Some(1000 << 30), ..device("WD-3", true, "sata") };
+        let reserve = ReservePolicy::from_config(&json!({ "reserve": { "WD-3": "1%" } }));
+        assert_eq!(DriveManager::mergerfs_branch(&whole_disk, &reserve).unwrap(), "/mnt/physical/hdd/WD-3=RW,10737418240");
+    }
+
+    #[test]
+    fn test_mount_entries() {
+        let ssd_partition = BlockDevice { path: "/dev/sda1".to_string(), fstype: Some("xfs".to_string()), uuid: Some("aaaa".to_string()), mountpoint: Some("/mnt/physical/nvme/N1".to_string()), ..Default::default() };
+        let ssd = BlockDevice { children: vec![ssd_partition], ..device("N1", false, "nvme") };
+        let hdd = BlockDevice { path: "/dev/sdb".to_string(), fstype: Some("ext4".to_string()), mountpoint: Some("/mnt/physical/hdd/WD-1".to_string()), ..device("WD-1", true, "sata") };
+        let drive_manager = DriveManager::with_config(test_args(), typed(json!({ "project_quotas": { "tv": 1001 } })));
+        let entries = drive_manager.mount_entries(&[ssd, hdd]);
+        assert_eq!(entries.iter().map(|entry| (entry.what.as_str(), entry.target.as_str(), entry.fstype.as_str())).collect::<Vec<_>>(), [
+            ("UUID=aaaa", "/mnt/physical/nvme/N1", "xfs"),
+            // no UUID, so by its device
+            ("/dev/sdb", "/mnt/physical/hdd/WD-1", "ext4"),
+            ("/mnt/physical/nvme/N1:/mnt/physical/hdd/WD-1", "/mnt/merged/hot", "fuse.mergerfs"),
+            ("/mnt/physical/hdd/WD-1", "/mnt/merged/warm", "fuse.mergerfs"),
+            ("/mnt/physical/hdd/WD-1", "/mnt/merged/cold", "fuse.mergerfs"),
+        ]);
+        assert_eq!(entries[0].options, ["defaults,prjquota"]);
+        assert_eq!(entries[2].requires, ["/mnt/physical/nvme/N1", "/mnt/physical/hdd/WD-1"]);
+
+        let btrfs = BlockDevice { path: "/dev/sdc".to_string(), fstype: Some("btrfs".to_string()), mountpoint: Some("/mnt/physical/hdd/WD-2".to_string()), ..device("WD-2", true, "sata") };
+        let drive_manager = DriveManager::with_config(test_args(), typed(json!({ "btrfs": { "compress": "zstd", "space_cache": "v2" } })));
+        assert_eq!(drive_manager.mount_entries(&[btrfs])[0].options, ["defaults,compress=zstd,space_cache=v2"]);
+    }
+
+    #[test]
+    fn test_expected_branches() {
+        let ssd = BlockDevice { path: "/dev/sda".to_string(), fstype: Some("xfs".to_string()), mountpoint: Some("/mnt/physical/ssd/S1".to_string()), ..device("S1", false, "sata") };
+        let hdd = BlockDevice { path: "/dev/sdb".to_string(), fstype: Some("xfs".to_string()), mountpoint: Some("/mnt/physical/hdd/WD-1".to_string()), ..device("WD-1", true, "sata") };
+        // not mounted, so it has no branch
+        let unmounted = device("WD-2", true, "sata");
+        let drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        let branch = |serial: &str, path: &str| (serial.to_string(), path.to_string());
+        assert_eq!(drive_manager.expected_branches(&[hdd, unmounted, ssd]), [
+            ("hot".to_string(), vec![branch("S1", "/mnt/physical/ssd/S1"), branch("WD-1", "/mnt/physical/hdd/WD-1")]),
+            ("warm".to_string(), vec![branch("S1", "/mnt/physical/ssd/S1"), branch("WD-1", "/mnt/physical/hdd/WD-1")]),
+            ("cold".to_string(), vec![branch("WD-1", "/mnt/physical/hdd/WD-1")]),
+        ]);
+    }
+
+    #[test]
+    fn test_mergerfs_options() {
+        let configured = DriveManager::configured_mergerfs_options(&json!({ "mergerfs_options": { "cache.files": "off", "cache.attr": 120, "nullrw": false, "bad": [] } }));
+        let options = DriveManager::mergerfs_options("hot", &FillPolicy::default(), &configured);
+        assert!(options.contains(&"allow_other".to_string()));
+        assert!(!options.contains(&"cache.files=auto-full".to_string()));
+        assert!(options.ends_with(&["cache.attr=120".to_string(), "cache.files=off".to_string(), "nullrw=false".to_string()]));
+        assert!(DriveManager::configured_mergerfs_options(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_remount_branch() {
+        let drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        let partition = BlockDevice { path: "/dev/sdc1".to_string(), fstype: Some("ext4".to_string()), ..Default::default() };
+        let drive = BlockDevice { path: "/dev/sdc".to_string(), children: vec![partition], ..device("WD-1", true, "sata") };
+        assert!(drive_manager.remount_branch(&drive, Path::new("/mnt/physical/hdd/WD-1"), "ext4").is_ok());
+        let e = drive_manager.remount_branch(&drive, Path::new("/mnt/physical/hdd/WD-1"), "xfs").unwrap_err();
+        assert_eq!(e.to_string(), "/dev/sdc no longer holds a xfs filesystem");
+    }
+
+    #[test]
+    fn test_unlock() {
+        let container = BlockDevice { path: "/dev/sdc1".to_string(), fstype: Some("crypto_LUKS".to_string()), uuid: Some("beef".to_string()), ..Default::default() };
+        let drive = BlockDevice { path: "/dev/sdc".to_string(), children: vec![container], ..device("WD-1", true, "sata") };
+        let drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        let e = drive_manager.unlock(&drive).unwrap_err();
+        assert_eq!(e.to_string(), "/dev/sdc1 is encrypted: set encryption.keyfile in the config to unlock it");
+        let drive_manager = DriveManager::with_config(test_args(), typed(json!({ "encryption": { "keyfile": "/nonexistent/luks.key" } })));
+        assert_eq!(drive_manager.unlock(&drive).unwrap_err().kind(), io::ErrorKind::NotFound);
+        // a drive in the clear is left as it is
+        let plain = device("WD-2", true, "sata");
+        assert_eq!(drive_manager.unlock(&plain).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_setup_bcachefs_respects_format_limit() {
+        let mountpoint = tempfile::tempdir().unwrap();
+        let config = json!({ "backend": "bcachefs", "bcachefs": { "mountpoint": mountpoint.path() } });
+        let bcachefs = Bcachefs::from_config(&config).unwrap();
+        let mut drive_manager = DriveManager::with_config(test_args(), typed(config));
+        let e = drive_manager.setup_bcachefs(&bcachefs, &[device("h1", true, "sata"), device("h2", true, "sata")]).unwrap_err();
+        assert!(e.to_string().contains("--allow-bulk-format"), "{}", e);
+        assert!(drive_manager.setup_bcachefs(&bcachefs, &[]).is_err());
+    }
+
+    #[test]
+    fn test_allow_format() {
+        let mut drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        assert!(drive_manager.allow_format(&device("a", true, "sata")).is_ok());
+        let e = drive_manager.allow_format(&device("b", true, "sata")).unwrap_err();
+        assert!(e.to_string().contains("--allow-bulk-format"), "{}", e);
+
+        let mut drive_manager = DriveManager::with_config(test_args(), typed(json!({ "max_formats_per_run": 0 })));
+        assert!(drive_manager.allow_format(&device("a", true, "sata")).is_err());
+
+        let mut drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        let pool_member = BlockDevice { fstype: Some("zfs_member".to_string()), ..device("z", true, "sata") };
+        assert!(drive_manager.allow_format(&pool_member).unwrap_err().to_string().contains("ZFS"));
+        // the refusal does not use up the limit
+        assert!(drive_manager.allow_format(&device("a", true, "sata")).is_ok());
+
+        let mut drive_manager = DriveManager::with_config(Args::parse_from(["--dryrun", "--allow-bulk-format"]).unwrap(), typed(json!({})));
+        for serial in ["a", "b", "c"] {
+            assert!(drive_manager.allow_format(&device(serial, true, "sata")).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_confirm_format() {
+        let blank = BlockDevice { path: "/dev/sdb".to_string(), ..device("WD-1", true, "sata") };
+        let raid = BlockDevice { path: "/dev/sdc".to_string(), fstype: Some("linux_raid_member".to_string()), ..device("WD-2", true, "sata") };
+        let mut drive_manager = DriveManager::with_config(Args::parse_from(Vec::<String>::new()).unwrap(), typed(json!({ "max_formats_per_run": 10 })));
+        // nobody to ask: only a blank drive goes ahead
+        drive_manager.confirm = |_| None;
+        assert!(drive_manager.allow_format(&blank).is_ok());
+        let e = drive_manager.allow_format(&raid).unwrap_err();
+        assert!(e.to_string().contains("an md RAID member on /dev/sdc; run with --assume-yes"), "{}", e);
+        drive_manager.confirm = |question| Some(question.contains("md RAID member"));
+        assert!(drive_manager.allow_format(&raid).is_ok());
+        assert!(drive_manager.allow_format(&blank).unwrap_err().to_string().contains("not confirmed"));
+        assert_eq!(drive_manager.formatted, 2);
+
+        let mut drive_manager = DriveManager::with_config(Args::parse_from(["--assume-yes"]).unwrap(), typed(json!({})));
+        drive_manager.confirm = |_| Some(false);
+        assert!(drive_manager.allow_format(&raid).is_ok());
+        let mut drive_manager = DriveManager::with_config(Args::parse_from(["--assume-yes"]).unwrap(), typed(json!({ "never_format": true })));
+        assert!(drive_manager.allow_format(&blank).unwrap_err().to_string().contains("never_format"));
+    }
+
+    fn device(serial: &str, rota: bool, tran: &str) -> BlockDevice {
+        BlockDevice { serial: Some(serial.to_string()), rota, tran: Some(tran.to_string()), ..Default::default() }
+    }
+
+    // Answers every command with the same canned stdout
+    struct FixtureExecutor(&'static [u8]);
+
+    impl Executor for FixtureExecutor {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            self.output(cmd).map(|output| output.status)
+        }
+
+        fn output(&self, _cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            Ok(std::process::Output { status: Default::default(), stdout: self.0.to_vec(), stderr: Vec::new() })
+        }
+    }
+
+    #[test]
+    fn test_sort_block_device() {
+        assert_eq!(DriveManager::sort_block_device(&device("n1", false, "nvme")), 0);
+        assert_eq!(DriveManager::block_class_order("unknown"), 3);
+    }
+
+    #[test]
+    fn test_block_class() {
+        assert_eq!(device("n1", false, "nvme").tier(), "hot");
+        assert_eq!(device("s1", false, "sata").block_class(), "ssd");
+        assert_eq!(device("h1", true, "sata").tier(), "cold");
+    }
+
+    #[test]
+    fn test_tier_devices() {
+        let devices = vec![device("h1", true, "sata"), device("n1", false, "nvme"), device("s1", false, "sata")];
+        let tiers = DriveManager::tier_devices(&devices, &HashMap::new());
+        let serials = |tier: usize| tiers[tier].1.iter().map(BlockDevice::id).collect::<Vec<_>>();
+        assert_eq!(serials(0), ["n1", "s1", "h1"]);
+        assert_eq!(serials(1), ["s1", "h1"]);
+        assert_eq!(serials(2), ["h1"]);
+        // an adopted ssd used as cold storage
+        let tiers = DriveManager::tier_devices(&devices, &HashMap::from([("s1".to_string(), "cold".to_string())]));
+        assert_eq!(tiers[2].1.iter().map(BlockDevice::id).collect::<Vec<_>>(), ["s1", "h1"]);
+    }
+
+    #[test]
+    fn test_get_block_devices() {
+        let mut drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        drive_manager.executor = Arc::new(FixtureExecutor(include_bytes!("../fixtures/lsblk/virtio-null-serial.json")));
+        let devices = drive_manager.get_block_devices().unwrap();
+        assert_eq!(devices.iter().map(BlockDevice::id).collect::<Vec<_>>(), ["0x5000c500a1b2c3d4", "vdb"]);
+        // vdb holds ext4 on the whole disk
+        let branches = DriveManager::branches(&devices, &HashMap::new());
+        assert_eq!(branches.iter().map(|branch| (branch.serial.as_str(), branch.path.as_path())).collect::<Vec<_>>(), [("vdb", Path::new("/data"))]);
+
+        // an lsblk that rejects the column list and has nothing else to say
+        let faults = FaultInjector::new();
+        faults.inject(FaultPoint::Command("lsblk".to_string()), Fault::ExitCode(1));
+        drive_manager.executor = Arc::new(FaultyExecutor::new(Arc::new(SystemExecutor), faults.clone()));
+        assert!(drive_manager.get_block_devices().is_err());
+        assert_eq!(faults.triggered(&FaultPoint::Command("lsblk".to_string())), 3);
+    }
+
+    // An lsblk from before util-linux 2.33, which has no FSUSED
+    struct OldLsblk(Mutex<Vec<String>>);
+
+    impl Executor for OldLsblk {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            self.output(cmd).map(|output| output.status)
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            let columns = cmd[4].to_string_lossy().to_string();
+            self.0.lock().unwrap().push(columns.clone());
+            if columns.split(',').any(|column| column == "FSUSED") {
+                return Ok(std::process::Output { status: ExitStatus::from_raw(1 << 8), stdout: Vec::new(), stderr: b"lsblk: unknown column: FSUSED".to_vec() });
+            }
+            Ok(std::process::Output { status: Default::default(), stdout: include_bytes!("../fixtures/lsblk/util-linux-2.32.json").to_vec(), stderr: Vec::new() })
+        }
+    }
+
+    #[test]
+    fn test_minimal_columns() {
+        let mut drive_manager = DriveManager::with_config(test_args(), typed(json!({})));
+        let lsblk = Arc::new(OldLsblk(Mutex::new(Vec::new())));
+        drive_manager.executor = lsblk.clone();
+        assert!(!drive_manager.get_block_devices().unwrap().is_empty());
+        let asked = lsblk.0.lock().unwrap();
+        assert_eq!(asked.len(), 3);
+        // partitions are still found by their GPT name
+        assert!(asked[2].split(',').any(|column| column == "PARTLABEL"));
+    }
+
+    #[test]
+    fn test_usb_bridge_classes() {
+        let config = json!({ "drive_class": { "drives": { "S6XNNS0T812345": "ssd" }, "models": [{ "model": "ASM1153*", "class": "ssd" }] } });
+        let mut drive_manager = DriveManager::with_config(test_args(), typed(config));
+        drive_manager.executor = Arc::new(FixtureExecutor(include_bytes!("../fixtures/lsblk/usb-bridge.json")));
+        let devices = drive_manager.get_block_devices().unwrap();
+        assert_eq!(devices.iter().map(BlockDevice::tier).collect::<Vec<_>>(), ["warm", "warm", "warm"]);
+        drive_manager.config = typed(json!({ "drive_class": { "guess_usb": false } }));
+        let devices = drive_manager.get_block_devices().unwrap();
+        assert_eq!(devices.iter().map(BlockDevice::tier).collect::<Vec<_>>(), ["cold", "cold", "cold"]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use log::warn;
+use serde_json::Value;
+
+// Drives formatted inside a LUKS2 container, from the encryption config
+// section; without it drives are formatted in the clear:
+//   "encryption": { "keyfile": "/etc/drive-manager/luks.key", "cipher": "aes-xts-plain64" }
+// The keyfile unlocks every container, both those formatted here and the
+// ones found locked at start. cipher is left to cryptsetup's default when
+// it is not set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Encryption {
+    pub keyfile: PathBuf,
+    pub cipher: Option<String>,
+}
+
+impl Encryption {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("encryption")?;
+        Some(Self {
+            keyfile: PathBuf::from(section.get("keyfile").and_then(Value::as_str)?),
+            cipher: section.get("cipher").and_then(Value::as_str).map(str::to_string),
+        })
+    }
+
+    // The device mapper name a container is opened as, the one
+    // systemd-cryptsetup would give it
+    pub fn mapper_name(uuid: &str) -> String {
+        format!("luks-{}", uuid)
+    }
+
+    pub fn mapper_path(uuid: &str) -> String {
+        format!("/dev/mapper/{}", Self::mapper_name(uuid))
+    }
+
+    // A keyfile others can read gives away every drive, so it is called out
+    pub fn check_keyfile(&self) -> io::Result<()> {
+        let metadata = fs::metadata(&self.keyfile)
+            .map_err(|e| io::Error::new(e.kind(), format!("encryption keyfile {}: {}", self.keyfile.display(), e)))?;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!("The encryption keyfile {} can be read by users other than its owner", self.keyfile.display());
+        }
+        Ok(())
+    }
+
+    pub fn format_command(&self, device: &str) -> Vec<String> {
+        let mut cmd: Vec<String> = ["cryptsetup", "luksFormat", "--batch-mode", "--type", "luks2", "--key-file"].map(str::to_string).into();
+        cmd.push(self.keyfile.to_string_lossy().to_string());
+        if let Some(cipher) = &self.cipher {
+            cmd.extend(["--cipher".to_string(), cipher.clone()]);
+        }
+        cmd.push(device.to_string());
+        cmd
+    }
+
+    pub fn open_command(&self, device: &str, uuid: &str) -> Vec<String> {
+        vec![
+            "cryptsetup".to_string(), "open".to_string(), "--key-file".to_string(), self.keyfile.to_string_lossy().to_string(),
+            device.to_string(), Self::mapper_name(uuid),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(Encryption::from_config(&json!({})), None);
+        assert_eq!(Encryption::from_config(&json!({ "encryption": {} })), None);
+        let encryption = Encryption::from_config(&json!({ "encryption": { "keyfile": "/etc/dm.key" } })).unwrap();
+        assert_eq!(encryption.format_command("/dev/sdb1").join(" "), "cryptsetup luksFormat --batch-mode --type luks2 --key-file /etc/dm.key /dev/sdb1");
+        assert_eq!(encryption.open_command("/dev/sdb1", "c0ffee").join(" "), "cryptsetup open --key-file /etc/dm.key /dev/sdb1 luks-c0ffee");
+        assert_eq!(Encryption::mapper_path("c0ffee"), "/dev/mapper/luks-c0ffee");
+        let encryption = Encryption { cipher: Some("aes-xts-plain64".to_string()), ..encryption };
+        assert!(encryption.format_command("/dev/sdb").join(" ").ends_with("--key-file /etc/dm.key --cipher aes-xts-plain64 /dev/sdb"));
+    }
+
+    #[test]
+    fn test_check_keyfile() {
+        let dir = tempdir().unwrap();
+        let encryption = Encryption { keyfile: dir.path().join("luks.key"), cipher: None };
+        let e = encryption.check_keyfile().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains("luks.key"));
+        fs::write(&encryption.keyfile, "secret").unwrap();
+        encryption.check_keyfile().unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::clock::{Clock, SystemClock};
+use crate::metadata_db::{from_unix, to_unix};
+use crate::notify;
+
+pub const DEFAULT_MAX_MB: u64 = 10;
+pub const DEFAULT_ROTATE_HOURS: f64 = 24.0;
+pub const DEFAULT_KEEP: usize = 7;
+pub const DEFAULT_MAX_PER_MINUTE: u32 = 60;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+static EVENT_LOG: OnceLock<EventLog> = OnceLock::new();
+
+// What the service did, one JSON object per line, for `history` to read
+// back without journald
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub at: i64,
+    pub kind: String,
+    #[serde(default)]
+    pub details: Value,
+}
+
+impl Event {
+    pub fn at(&self) -> SystemTime {
+        from_unix(self.at)
+    }
+}
+
+// The event_log config section; the log is only kept when it is set:
+//   "event_log": { "path": "/var/lib/drive-manager/events.jsonl", "max_mb": 10,
+//                  "rotate_hours": 24, "keep": 7, "max_per_minute": 60 }
+// The file is rotated to path.1, path.2, ... once it reaches max_mb or is
+// rotate_hours old, and the keep newest rotations are kept. Past
+// max_per_minute events of one kind the rest are counted instead, and
+// the count written as a "suppressed" event ahead of the first of that
+// kind in a later minute.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventLogSettings {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+    pub rotate_after: Duration,
+    pub keep: usize,
+    pub max_per_minute: u32,
+}
+
+impl EventLogSettings {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("event_log")?;
+        let hours = section.get("rotate_hours").and_then(Value::as_f64).unwrap_or(DEFAULT_ROTATE_HOURS);
+        Some(Self {
+            path: PathBuf::from(section.get("path").and_then(Value::as_str)?),
+            max_bytes: section.get("max_mb").and_then(Value::as_u64).unwrap_or(DEFAULT_MAX_MB) << 20,
+            rotate_after: Duration::from_secs_f64(hours.max(0.0) * 3600.0),
+            keep: section.get("keep").and_then(Value::as_u64).map_or(DEFAULT_KEEP, |keep| keep as usize),
+            max_per_minute: section.get("max_per_minute").and_then(Value::as_u64).map_or(DEFAULT_MAX_PER_MINUTE, |max| max as u32),
+        })
+    }
+
+    // The log's files, oldest rotation first
+    pub fn files(&self) -> Vec<PathBuf> {
+        (1..=self.keep).rev().map(|n| rotated(&self.path, n)).chain([self.path.clone()]).collect()
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// Events of one kind in the current minute
+struct Rate {
+    window_start: SystemTime,
+    written: u32,
+    suppressed: u64,
+}
+
+struct Output {
+    file: Option<File>,
+    size: u64,
+    // when the current file got its first event
+    started: Option<SystemTime>,
+    rates: HashMap<String, Rate>,
+}
+
+pub struct EventLog {
+    settings: EventLogSettings,
+    clock: Arc<dyn Clock>,
+    output: Mutex<Output>,
+}
+
+impl EventLog {
+    pub fn new(settings: EventLogSettings, clock: Arc<dyn Clock>) -> Self {
+        // carry on in the file an earlier run left, from its first event's time
+        let started = File::open(&settings.path).ok()
+            .and_then(|file| BufReader::new(file).lines().next()?.ok())
+            .and_then(|line| serde_json::from_str::<Event>(&line).ok())
+            .map(|event| event.at());
+        let size = fs::metadata(&settings.path).map_or(0, |metadata| metadata.len());
+        Self { settings, clock, output: Mutex::new(Output { file: None, size, started, rates: HashMap::new() }) }
+    }
+
+    pub fn record(&self, kind: &str, details: Value) {
+        let now = self.clock.now();
+        let mut output = self.output.lock().unwrap();
+        let max_per_minute = self.settings.max_per_minute;
+        let rate = output.rates.entry(kind.to_string()).or_insert(Rate { window_start: now, written: 0, suppressed: 0 });
+        let mut suppressed = None;
+        if now.duration_since(rate.window_start).unwrap_or_default() >= RATE_WINDOW {
+            if rate.suppressed > 0 {
+                suppressed = Some(rate.suppressed);
+            }
+            *rate = Rate { window_start: now, written: 0, suppressed: 0 };
+        }
+        if rate.written >= max_per_minute {
+            rate.suppressed += 1;
+            return;
+        }
+        rate.written += 1;
+        let mut events = Vec::new();
+        if let Some(count) = suppressed {
+            events.push(Event { at: to_unix(now), kind: "suppressed".to_string(), details: serde_json::json!({ "kind": kind, "count": count }) });
+        }
+        events.push(Event { at: to_unix(now), kind: kind.to_string(), details });
+        for event in events {
+            if let Err(e) = self.write(&mut output, &event, now) {
+                warn!("Failed to write to the event log {}: {}", self.settings.path.display(), e);
+            }
+        }
+    }
+
+    fn write(&self, output: &mut Output, event: &Event, now: SystemTime) -> io::Result<()> {
+        let line = serde_json::to_string(event)? + "\n";
+        let too_big = output.size > 0 && output.size + line.len() as u64 > self.settings.max_bytes;
+        let too_old = output.started.is_some_and(|started| now.duration_since(started).unwrap_or_default() >= self.settings.rotate_after);
+        if too_big || too_old {
+            output.file = None;
+            self.rotate()?;
+            output.size = 0;
+            output.started = None;
+        }
+        if output.file.is_none() {
+            output.file = Some(OpenOptions::new().create(true).append(true).open(&self.settings.path)?);
+        }
+        output.file.as_mut().unwrap().write_all(line.as_bytes())?;
+        output.size += line.len() as u64;
+        output.started.get_or_insert(now);
+        Ok(())
+    }
+
+    // Shift path.N to path.N+1, dropping the oldest past keep
+    fn rotate(&self) -> io::Result<()> {
+        let path = &self.settings.path;
+        if self.settings.keep == 0 {
+            return fs::remove_file(path);
+        }
+        for n in (1..self.settings.keep).rev() {
+            match fs::rename(rotated(path, n), rotated(path, n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(path, rotated(path, 1))
+    }
+}
+
+// Make `log` the one `record` writes to, for the rest of the process
+pub fn init(log: EventLog) {
+    if EVENT_LOG.set(log).is_err() {
+        warn!("The event log was already set up");
+    }
+}
+
+pub fn init_from_config(config: &Value) {
+    if let Some(settings) = EventLogSettings::from_config(config) {
+        if let Some(dir) = settings.path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                warn!("Not keeping an event log, {} could not be made: {}", dir.display(), e);
+                return;
+            }
+        }
+        init(EventLog::new(settings, Arc::new(SystemClock)));
+    }
+}
+
+// Record an event, when there is an event log, and pass it on to be
+// notified of, ahead of the log's own rate limit
+pub fn record(kind: &str, details: Value) {
+    notify::observe(kind, &details);
+    if let Some(log) = EVENT_LOG.get() {
+        log.record(kind, details);
+    }
+}
+
+// The events kept across the log and its rotations since `since`, oldest
+// first. Lines that do not parse, like one cut off by a crash, are skipped.
+pub fn read(settings: &EventLogSettings, since: SystemTime) -> io::Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for path in settings.files() {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        events.extend(content.lines().filter_map(|line| serde_json::from_str::<Event>(line).ok()).filter(|event| event.at() >= since));
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use serde_json::json;
+    use std::time::UNIX_EPOCH;
+    use tempfile::tempdir;
+
+    fn settings(dir: &Path) -> EventLogSettings {
+        EventLogSettings::from_config(&json!({ "event_log": { "path": dir.join("events.jsonl"), "max_mb": 1, "rotate_hours": 1, "keep": 2, "max_per_minute": 2 } })).unwrap()
+    }
+
+    #[test]
+    fn test_settings() {
+        assert_eq!(EventLogSettings::from_config(&json!({})), None);
+        let settings = EventLogSettings::from_config(&json!({ "event_log": { "path": "/var/log/dm/events.jsonl" } })).unwrap();
+        assert_eq!((settings.max_bytes, settings.rotate_after, settings.keep), (10 << 20, Duration::from_secs(24 * 3600), 7));
+        let settings = EventLogSettings { keep: 2, ..settings };
+        assert_eq!(settings.files(), [
+            PathBuf::from("/var/log/dm/events.jsonl.2"),
+            PathBuf::from("/var/log/dm/events.jsonl.1"),
+            PathBuf::from("/var/log/dm/events.jsonl"),
+        ]);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let dir = tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1000)));
+        let log = EventLog::new(settings(dir.path()), clock.clone());
+        for n in 0..5 {
+            log.record("move_failed", json!({ "n": n }));
+        }
+        log.record("drive_added", json!({}));
+        clock.advance(Duration::from_secs(60));
+        log.record("move_failed", json!({ "n": 5 }));
+        let kinds: Vec<(String, Value)> = read(&settings(dir.path()), UNIX_EPOCH).unwrap().into_iter().map(|event| (event.kind, event.details)).collect();
+        assert_eq!(kinds, [
+            ("move_failed".to_string(), json!({ "n": 0 })),
+            ("move_failed".to_string(), json!({ "n": 1 })),
+            ("drive_added".to_string(), json!({})),
+            ("suppressed".to_string(), json!({ "kind": "move_failed", "count": 3 })),
+            ("move_failed".to_string(), json!({ "n": 5 })),
+        ]);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1000)));
+        let settings = settings(dir.path());
+        let log = EventLog::new(settings.clone(), clock.clone());
+        for n in 0..4 {
+            log.record("check", json!({ "n": n }));
+            clock.advance(Duration::from_secs(3600));
+        }
+        // one file an hour, and only the two newest rotations kept
+        assert!(dir.path().join("events.jsonl.2").exists() && !dir.path().join("events.jsonl.3").exists());
+        let events = read(&settings, UNIX_EPOCH).unwrap();
+        assert_eq!(events.iter().map(|event| event.details["n"].clone()).collect::<Vec<_>>(), [json!(1), json!(2), json!(3)]);
+        assert_eq!(read(&settings, UNIX_EPOCH + Duration::from_secs(1000 + 3 * 3600)).unwrap().len(), 1);
+
+        // a later run carries on in the same file, rotating it on its age
+        let log = EventLog::new(settings.clone(), clock.clone());
+        log.record("check", json!({ "n": 4 }));
+        assert_eq!(fs::read_to_string(&settings.path).unwrap().lines().count(), 1);
+
+        // and by size
+        let settings = EventLogSettings { max_bytes: 100, ..settings };
+        let log = EventLog::new(settings.clone(), clock.clone());
+        log.record("check", json!({ "padding": "x".repeat(60) }));
+        assert_eq!(fs::read_to_string(&settings.path).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,213 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::thread;
+use crate::secrets;
+
+// Runs external commands. Everything that shells out (lsblk, mount, mkfs,
+// rsync, ...) goes through this so it can be swapped for a fake in tests.
+pub trait Executor: Send + Sync {
+    // Run with inherited stdio and wait for the exit status
+    fn status(&self, cmd: &[&OsStr]) -> io::Result<ExitStatus>;
+    // Run with stdout and stderr captured
+    fn output(&self, cmd: &[&OsStr]) -> io::Result<Output>;
+    // Run with stdout passed to on_line as it arrives, split on \n and on the
+    // \r progress meters use to redraw a line, and stderr captured; the
+    // returned stdout is empty. Executors that cannot stream just run the
+    // command.
+    fn stream(&self, cmd: &[&OsStr], _on_line: &mut dyn FnMut(&str)) -> io::Result<Output> {
+        self.status(cmd).map(|status| Output { status, stdout: Vec::new(), stderr: Vec::new() })
+    }
+    // Run with `input` written to stdin, which is then closed, and stdout
+    // and stderr captured. This is what to use instead of piping `yes` into
+    // a command. Executors that cannot feed stdin just run the command.
+    fn output_with_input(&self, cmd: &[&OsStr], _input: &[u8]) -> io::Result<Output> {
+        self.output(cmd)
+    }
+    // Run `source` with its stdout fed into `sink`'s stdin, as the shell's
+    // `source | sink`, and return each one's output with stderr captured.
+    // Executors that cannot pipe one into the other run `source` to the
+    // end first and hand `sink` all it wrote.
+    fn pipe(&self, source: &[&OsStr], sink: &[&OsStr]) -> io::Result<(Output, Output)> {
+        let source_output = self.output(source)?;
+        let sink_output = self.output_with_input(sink, &source_output.stdout)?;
+        Ok((Output { stdout: Vec::new(), ..source_output }, sink_output))
+    }
+}
+
+// The output of a command that exited successfully, or an error carrying
+// what it wrote to stderr
+pub fn checked(cmd: &[&OsStr], output: Output) -> io::Result<Output> {
+    if output.status.success() {
+        return Ok(output);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let status = output.status.code().map_or("a signal".to_string(), |code| format!("status {}", code));
+    Err(io::Error::other(format!("`{}` exited with {}: {}", command_line(cmd), status, stderr.trim())))
+}
+
+// A command built as owned strings, in the form Executor takes
+pub fn as_args(cmd: &[OsString]) -> Vec<&OsStr> {
+    cmd.iter().map(OsString::as_os_str).collect()
+}
+
+pub fn command_line(cmd: &[&OsStr]) -> String {
+    secrets::redact(&cmd.iter().map(|arg| arg.to_string_lossy()).collect::<Vec<_>>().join(" "))
+}
+
+pub fn command(cmd: &[&OsStr]) -> io::Result<Command> {
+    let (program, args) = cmd.split_first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+    let mut command = Command::new(program);
+    command.args(args);
+    Ok(command)
+}
+
+pub struct SystemExecutor;
+
+impl Executor for SystemExecutor {
+    fn status(&self, cmd: &[&OsStr]) -> io::Result<ExitStatus> {
+        command(cmd)?.status()
+    }
+
+    fn output(&self, cmd: &[&OsStr]) -> io::Result<Output> {
+        command(cmd)?.output()
+    }
+
+    fn stream(&self, cmd: &[&OsStr], on_line: &mut dyn FnMut(&str)) -> io::Result<Output> {
+        stream_lines(command(cmd)?, on_line)
+    }
+
+    fn output_with_input(&self, cmd: &[&OsStr], input: &[u8]) -> io::Result<Output> {
+        output_with_input(command(cmd)?, input)
+    }
+
+    fn pipe(&self, source: &[&OsStr], sink: &[&OsStr]) -> io::Result<(Output, Output)> {
+        pipe(command(source)?, command(sink)?)
+    }
+}
+
+// Run `source` piped into `sink` as Executor::pipe describes
+pub fn pipe(mut source: Command, mut sink: Command) -> io::Result<(Output, Output)> {
+    let mut source = source.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = source.stdout.take().unwrap();
+    let sink = match sink.stdin(Stdio::from(stdout)).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(sink) => sink,
+        Err(e) => {
+            let _ = source.kill();
+            let _ = source.wait();
+            return Err(e);
+        }
+    };
+    // source's stderr is read alongside, so a full pipe cannot stall it
+    let source = thread::spawn(move || source.wait_with_output());
+    let sink_output = sink.wait_with_output();
+    let source_output = source.join().unwrap_or_else(|_| Err(io::Error::other("the source command's reader panicked")))?;
+    Ok((source_output, sink_output?))
+}
+
+// Run `command` as Executor::output_with_input describes
+pub fn output_with_input(mut command: Command, input: &[u8]) -> io::Result<Output> {
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // a command that exits without reading all of it is not an error
+    match stdin.write_all(input) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        _ => {}
+    }
+    drop(stdin);
+    child.wait_with_output()
+}
+
+// Run `command`, passing its stdout to on_line a line at a time as
+// Executor::stream describes
+pub fn stream_lines(mut command: Command, on_line: &mut dyn FnMut(&str)) -> io::Result<Output> {
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    // read alongside stdout, so a full stderr pipe cannot stall the child
+    let stderr_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stderr.read_to_end(&mut buf);
+        buf
+    });
+    let (mut buf, mut line) = ([0u8; 4096], Vec::new());
+    loop {
+        let read = match stdout.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+        };
+        for &byte in &buf[..read] {
+            if byte == b'\n' || byte == b'\r' {
+                if !line.is_empty() {
+                    on_line(&String::from_utf8_lossy(&line));
+                    line.clear();
+                }
+            } else {
+                line.push(byte);
+            }
+        }
+    }
+    if !line.is_empty() {
+        on_line(&String::from_utf8_lossy(&line));
+    }
+    let status = child.wait()?;
+    Ok(Output { status, stdout: Vec::new(), stderr: stderr_reader.join().unwrap_or_default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_executor() {
+        let output = SystemExecutor.output(&["echo".as_ref(), "test".as_ref()]).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"test\n");
+        assert!(!SystemExecutor.status(&["false".as_ref()]).unwrap().success());
+        assert!(SystemExecutor.status(&[]).is_err());
+        assert_eq!(command_line(&["rsync".as_ref(), "-a".as_ref()]), "rsync -a");
+    }
+
+    #[test]
+    fn test_output_with_input() {
+        let output = SystemExecutor.output_with_input(&["cat".as_ref()], b"y\n").unwrap();
+        assert_eq!(output.stdout, b"y\n");
+        let cmd: [&OsStr; 2] = ["ls".as_ref(), "/nonexistent".as_ref()];
+        let e = checked(&cmd, SystemExecutor.output(&cmd).unwrap()).unwrap_err();
+        assert!(e.to_string().starts_with("`ls /nonexistent` exited with status 2: "), "{}", e);
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut lines = Vec::new();
+        let output = SystemExecutor.stream(&["printf".as_ref(), "a\\rb\\n\\nc".as_ref()], &mut |line| lines.push(line.to_string())).unwrap();
+        assert!(output.status.success());
+        assert_eq!(lines, ["a", "b", "c"]);
+        let output = SystemExecutor.stream(&["ls".as_ref(), "/nonexistent".as_ref()], &mut |_| {}).unwrap();
+        assert!(String::from_utf8_lossy(&output.stderr).contains("No such file or directory"));
+    }
+
+    #[test]
+    fn test_pipe() {
+        let (source, sink) = SystemExecutor.pipe(&["printf".as_ref(), "abc".as_ref()], &["cat".as_ref()]).unwrap();
+        assert!(source.status.success() && sink.status.success());
+        assert_eq!(sink.stdout, b"abc");
+        // each side's failure and stderr come back on its own
+        let source_cmd: [&OsStr; 3] = ["sh".as_ref(), "-c".as_ref(), "echo sent >&2; exit 3".as_ref()];
+        let sink_cmd: [&OsStr; 3] = ["sh".as_ref(), "-c".as_ref(), "cat >/dev/null; echo received >&2".as_ref()];
+        let (source, sink) = SystemExecutor.pipe(&source_cmd, &sink_cmd).unwrap();
+        assert_eq!((source.status.code(), source.stderr.as_slice()), (Some(3), &b"sent\n"[..]));
+        assert_eq!((sink.status.code(), sink.stderr.as_slice()), (Some(0), &b"received\n"[..]));
+        assert!(SystemExecutor.pipe(&["true".as_ref()], &["/nonexistent".as_ref()]).is_err());
+    }
+}
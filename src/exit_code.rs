@@ -0,0 +1,95 @@
+use std::io;
+use serde_json::json;
+use crate::secrets;
+
+// What went wrong, as far as a script running drive-manager needs to know.
+// Each kind's exit code and name are a contract: they never change meaning,
+// and new kinds get new codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Failure,
+    Usage,
+    Config,
+    Permission,
+    // a drive or mountpoint is in use by something else
+    DriveConflict,
+    // some of the work was done and some was not
+    PartialFailure,
+    Database,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorKind::Failure => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::Config => 3,
+            ErrorKind::Permission => 4,
+            ErrorKind::DriveConflict => 5,
+            ErrorKind::PartialFailure => 6,
+            ErrorKind::Database => 7,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Failure => "failure",
+            ErrorKind::Usage => "usage",
+            ErrorKind::Config => "config",
+            ErrorKind::Permission => "permission",
+            ErrorKind::DriveConflict => "drive_conflict",
+            ErrorKind::PartialFailure => "partial_failure",
+            ErrorKind::Database => "database",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CliError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl CliError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into() }
+    }
+
+    // An I/O error is a permission or drive conflict error whatever it was
+    // doing; otherwise it is `kind`
+    pub fn from_io(kind: ErrorKind, context: &str, e: &io::Error) -> Self {
+        let kind = match e.kind() {
+            io::ErrorKind::PermissionDenied => ErrorKind::Permission,
+            io::ErrorKind::ResourceBusy => ErrorKind::DriveConflict,
+            _ => kind,
+        };
+        Self::new(kind, format!("{}: {}", context, e))
+    }
+
+    pub fn to_json(&self) -> String {
+        json!({ "error": self.kind.name(), "code": self.kind.code(), "message": self.message }).to_string()
+    }
+
+    // Report the error for a person, then as JSON on the last line of
+    // stderr for scripts, and exit with its code
+    pub fn exit(&self) -> ! {
+        let error = Self { message: secrets::redact(&self.message), ..self.clone() };
+        eprintln!("drive-manager: {}", error.message);
+        eprintln!("{}", error.to_json());
+        std::process::exit(self.kind.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(CliError::from_io(ErrorKind::Database, "failed to open db", &denied).kind, ErrorKind::Permission);
+        let missing = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        let error = CliError::from_io(ErrorKind::Config, "failed to read config", &missing);
+        assert_eq!(error.to_json(), r#"{"code":3,"error":"config","message":"failed to read config: no such file"}"#);
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+
+// how long a job pauses tiering when nothing says it finished, unless the
+// job sets max_hours
+pub const DEFAULT_MAX_HOURS: f64 = 12.0;
+// how often a paused mover checks whether the jobs have finished
+pub const PAUSE_POLL_INTERVAL: u64 = 30;
+// how often `job start` checks whether it may go, and how long a waiting
+// job that stopped checking, say because its hook was killed, holds its
+// place in line
+pub const WAIT_POLL_INTERVAL: u64 = 5;
+pub const WAIT_TIMEOUT: u64 = 60;
+
+// A backup, scrub or parity sync that must not see files moving under it,
+// from the external_jobs config section:
+//   { "backup": { "max_hours": 6 }, "snapraid-sync": {},
+//     "scrub-wd1": { "drives": ["WD-1"], "priority": 10 } }
+// Its pre and post hooks run `drive-manager job start <name>` and
+// `drive-manager job finish <name>`, and tiering pauses in between. A job
+// with drives only keeps moves off those drives. Two jobs sharing a drive
+// never run at once: `job start` waits for the one running, and among those
+// waiting the higher priority goes first, then the one that asked first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExternalJob {
+    pub name: String,
+    // a job that never reports finishing stops pausing tiering after this
+    pub max_duration: Duration,
+    // serials of the drives it works on; none means every drive
+    pub drives: Vec<String>,
+    pub priority: i64,
+}
+
+impl ExternalJob {
+    pub fn shares_drive(&self, other: &ExternalJob) -> bool {
+        self.drives.is_empty() || other.drives.is_empty() || self.drives.iter().any(|drive| other.drives.contains(drive))
+    }
+}
+
+// A job that has started and not finished or run out its max duration
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunningJob {
+    pub name: String,
+    pub started_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+pub fn external_jobs(config: &Value) -> Vec<ExternalJob> {
+    let Some(jobs) = config.get("external_jobs").and_then(Value::as_object) else { return Vec::new() };
+    let mut jobs: Vec<ExternalJob> = jobs.iter().map(|(name, job)| {
+        let hours = job.get("max_hours").and_then(Value::as_f64).unwrap_or(DEFAULT_MAX_HOURS);
+        ExternalJob {
+            name: name.clone(),
+            max_duration: Duration::from_secs_f64(hours.max(0.0) * 3600.0),
+            drives: job.get("drives").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str).map(str::to_string).collect(),
+            priority: job.get("priority").and_then(Value::as_i64).unwrap_or(0),
+        }
+    }).collect();
+    jobs.sort_by(|a, b| a.name.cmp(&b.name));
+    jobs
+}
+
+pub fn find<'a>(jobs: &'a [ExternalJob], name: &str) -> Option<&'a ExternalJob> {
+    jobs.iter().find(|job| job.name == name)
+}
+
+// A running or waiting job as configured. One the config no longer has, as
+// after a reload, is taken to use every drive.
+pub fn job_for(jobs: &[ExternalJob], name: &str) -> ExternalJob {
+    find(jobs, name).cloned().unwrap_or_else(|| ExternalJob { name: name.to_string(), ..Default::default() })
+}
+
+// The job `job`, waiting since `since`, has to wait for, if any: a running
+// one sharing a drive with it, or a waiting one sharing a drive that goes
+// first
+pub fn blocker(job: &ExternalJob, since: SystemTime, jobs: &[ExternalJob], running: &[RunningJob], waiting: &[(String, SystemTime)]) -> Option<String> {
+    let running = running.iter().map(|other| &other.name).filter(|name| **name != job.name)
+        .find(|name| job.shares_drive(&job_for(jobs, name)));
+    let waiting = || waiting.iter().filter(|(name, _)| *name != job.name).find(|(name, other_since)| {
+        let other = job_for(jobs, name);
+        job.shares_drive(&other) && (other.priority, since) > (job.priority, *other_since)
+    }).map(|(name, _)| name);
+    running.or_else(waiting).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_config() {
+        let jobs = external_jobs(&json!({ "external_jobs": { "snapraid-sync": {}, "backup": { "max_hours": 1.5 } } }));
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(find(&jobs, "backup").unwrap().max_duration, Duration::from_secs(5400));
+        assert_eq!(find(&jobs, "snapraid-sync").unwrap().max_duration, Duration::from_secs(12 * 3600));
+        assert!(find(&jobs, "scrub").is_none());
+        assert!(external_jobs(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_blocker() {
+        let jobs = external_jobs(&json!({ "external_jobs": {
+            "scrub-wd1": { "drives": ["WD-1"], "priority": 10 },
+            "smart-wd1": { "drives": ["WD-1"] },
+            "smart-wd2": { "drives": ["WD-2"] },
+            "snapraid-sync": {},
+        } }));
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let running = |name: &str| RunningJob { name: name.to_string(), started_at: at(0), expires_at: at(3600) };
+        let smart_wd1 = find(&jobs, "smart-wd1").unwrap();
+        let smart_wd2 = find(&jobs, "smart-wd2").unwrap();
+        assert_eq!(blocker(smart_wd2, at(10), &jobs, &[running("smart-wd1")], &[]), None);
+        assert_eq!(blocker(smart_wd1, at(10), &jobs, &[running("scrub-wd1")], &[]).as_deref(), Some("scrub-wd1"));
+        // a job with no drives shares all of them, as does one the config no longer has
+        assert_eq!(blocker(smart_wd2, at(10), &jobs, &[running("snapraid-sync")], &[]).as_deref(), Some("snapraid-sync"));
+        assert_eq!(blocker(smart_wd2, at(10), &jobs, &[running("gone")], &[]).as_deref(), Some("gone"));
+        // the scrub outranks the SMART test however long that has waited
+        let waiting = [("smart-wd1".to_string(), at(5)), ("scrub-wd1".to_string(), at(20))];
+        assert_eq!(blocker(smart_wd1, at(5), &jobs, &[], &waiting).as_deref(), Some("scrub-wd1"));
+        assert_eq!(blocker(find(&jobs, "scrub-wd1").unwrap(), at(20), &jobs, &[], &waiting), None);
+        // equal priorities go in the order they asked
+        let waiting = [("snapraid-sync".to_string(), at(5)), ("smart-wd2".to_string(), at(8))];
+        assert_eq!(blocker(smart_wd2, at(8), &jobs, &[], &waiting).as_deref(), Some("snapraid-sync"));
+        assert_eq!(blocker(find(&jobs, "snapraid-sync").unwrap(), at(5), &jobs, &[], &waiting), None);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use crate::executor::Executor;
+use crate::open_files::{OpenMode, OpenReader};
+use crate::storage::{BranchUsage, ScannedFile, Storage, TierUsage};
+
+// Where a fault can be injected
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    // an external command, matched on the program name (e.g. "rsync", "lsblk")
+    Command(String),
+    Move,
+    Scan,
+    DbWrite,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    // the command runs but exits with this code, e.g. 23 for a partial rsync transfer
+    ExitCode(i32),
+    // the call fails with this errno, e.g. libc::ENOSPC or libc::EIO
+    Errno(i32),
+    // the drive holding the file vanishes partway through the operation
+    DriveGone,
+}
+
+impl Fault {
+    pub fn to_error(&self) -> io::Error {
+        match self {
+            Fault::ExitCode(code) => io::Error::other(format!("injected fault: exit code {}", code)),
+            Fault::Errno(errno) => io::Error::from_raw_os_error(*errno),
+            Fault::DriveGone => io::Error::from_raw_os_error(libc::ENODEV),
+        }
+    }
+}
+
+struct PlannedFault {
+    point: FaultPoint,
+    fault: Fault,
+    // calls to let through before failing
+    skip: u64,
+    // calls to fail after that, None for every call
+    times: Option<u64>,
+}
+
+// A deterministic schedule of failures shared by the faulty wrappers. Calls
+// at each point are counted, and a planned fault fires on calls
+// skip..skip + times for its point.
+#[derive(Default)]
+pub struct FaultInjector {
+    planned: Mutex<Vec<PlannedFault>>,
+    calls: Mutex<HashMap<FaultPoint, u64>>,
+    triggered: Mutex<HashMap<FaultPoint, u64>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    // Fail every call at this point
+    pub fn inject(&self, point: FaultPoint, fault: Fault) {
+        self.planned.lock().unwrap().push(PlannedFault { point, fault, skip: 0, times: None });
+    }
+
+    // Let `skip` calls through, then fail the next `times`
+    pub fn inject_after(&self, point: FaultPoint, fault: Fault, skip: u64, times: u64) {
+        self.planned.lock().unwrap().push(PlannedFault { point, fault, skip, times: Some(times) });
+    }
+
+    pub fn clear(&self) {
+        self.planned.lock().unwrap().clear();
+    }
+
+    // Record a call at this point, returning the fault to raise if one is due
+    pub fn check(&self, point: &FaultPoint) -> Option<Fault> {
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            let count = calls.entry(point.clone()).or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        let fault = self.planned.lock().unwrap().iter()
+            .find(|planned| planned.point == *point && call >= planned.skip && planned.times.is_none_or(|times| call < planned.skip + times))
+            .map(|planned| planned.fault.clone())?;
+        *self.triggered.lock().unwrap().entry(point.clone()).or_insert(0) += 1;
+        Some(fault)
+    }
+
+    pub fn calls(&self, point: &FaultPoint) -> u64 {
+        self.calls.lock().unwrap().get(point).copied().unwrap_or(0)
+    }
+
+    // How many times a fault actually fired at this point
+    pub fn triggered(&self, point: &FaultPoint) -> u64 {
+        self.triggered.lock().unwrap().get(point).copied().unwrap_or(0)
+    }
+}
+
+pub struct FaultyExecutor {
+    inner: Arc<dyn Executor>,
+    faults: Arc<FaultInjector>,
+}
+
+impl FaultyExecutor {
+    pub fn new(inner: Arc<dyn Executor>, faults: Arc<FaultInjector>) -> Self {
+        Self { inner, faults }
+    }
+
+    fn fault(&self, cmd: &[&OsStr]) -> Option<Fault> {
+        let program = cmd.first()?.to_string_lossy().to_string();
+        self.faults.check(&FaultPoint::Command(program))
+    }
+
+    fn faulted_output(fault: Fault) -> io::Result<Output> {
+        match fault {
+            Fault::ExitCode(code) => Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: Vec::new(),
+                stderr: b"injected fault\n".to_vec(),
+            }),
+            fault => Err(fault.to_error()),
+        }
+    }
+}
+
+impl Executor for FaultyExecutor {
+    fn status(&self, cmd: &[&OsStr]) -> io::Result<ExitStatus> {
+        match self.fault(cmd) {
+            Some(Fault::ExitCode(code)) => Ok(ExitStatus::from_raw(code << 8)),
+            Some(fault) => Err(fault.to_error()),
+            None => self.inner.status(cmd),
+        }
+    }
+
+    fn output(&self, cmd: &[&OsStr]) -> io::Result<Output> {
+        match self.fault(cmd) {
+            Some(fault) => Self::faulted_output(fault),
+            None => self.inner.output(cmd),
+        }
+    }
+
+    fn output_with_input(&self, cmd: &[&OsStr], input: &[u8]) -> io::Result<Output> {
+        match self.fault(cmd) {
+            Some(fault) => Self::faulted_output(fault),
+            None => self.inner.output_with_input(cmd, input),
+        }
+    }
+
+    fn stream(&self, cmd: &[&OsStr], on_line: &mut dyn FnMut(&str)) -> io::Result<Output> {
+        match self.fault(cmd) {
+            Some(fault) => Self::faulted_output(fault),
+            None => self.inner.stream(cmd, on_line),
+        }
+    }
+
+    // a faulted source leaves the sink nothing to read; a faulted sink is
+    // all that runs
+    fn pipe(&self, source: &[&OsStr], sink: &[&OsStr]) -> io::Result<(Output, Output)> {
+        if let Some(fault) = self.fault(source) {
+            return Ok((Self::faulted_output(fault)?, self.output_with_input(sink, &[])?));
+        }
+        if let Some(fault) = self.fault(sink) {
+            return Ok((Output { status: ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() }, Self::faulted_output(fault)?));
+        }
+        self.inner.pipe(source, sink)
+    }
+}
+
+pub struct FaultyStorage {
+    inner: Arc<dyn Storage>,
+    faults: Arc<FaultInjector>,
+}
+
+impl FaultyStorage {
+    pub fn new(inner: Arc<dyn Storage>, faults: Arc<FaultInjector>) -> Self {
+        Self { inner, faults }
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn scan(&self) -> io::Result<Vec<ScannedFile>> {
+        match self.faults.check(&FaultPoint::Scan) {
+            Some(fault) => Err(fault.to_error()),
+            None => self.inner.scan(),
+        }
+    }
+
+    fn tier_usage(&self, tier: &str) -> io::Result<TierUsage> {
+        self.inner.tier_usage(tier)
+    }
+
+    fn branch_usage(&self) -> io::Result<Vec<BranchUsage>> {
+        self.inner.branch_usage()
+    }
+
+    fn branch_of(&self, path: &Path, tier: &str) -> Option<String> {
+        self.inner.branch_of(path, tier)
+    }
+
+    fn file_size(&self, path: &Path, tier: &str) -> Option<u64> {
+        self.inner.file_size(path, tier)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn open_mode(&self, path: &Path, tier: &str) -> io::Result<Option<OpenMode>> {
+        self.inner.open_mode(path, tier)
+    }
+
+    fn readers(&self) -> io::Result<Vec<OpenReader>> {
+        self.inner.readers()
+    }
+
+    fn move_file(&self, path: &Path, source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+        match self.faults.check(&FaultPoint::Move) {
+            Some(fault) => Err(fault.to_error()),
+            None => self.inner.move_file(path, source_tier, target_tier, progress),
+        }
+    }
+
+    fn move_link_group(&self, paths: &[PathBuf], source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+        match self.faults.check(&FaultPoint::Move) {
+            Some(fault) => Err(fault.to_error()),
+            None => self.inner.move_link_group(paths, source_tier, target_tier, progress),
+        }
+    }
+
+    fn delete_file(&self, path: &Path, tier: &str) -> io::Result<()> {
+        self.inner.delete_file(path, tier)
+    }
+
+    fn move_directory(&self, dir: &Path, source_tier: &str, target_tier: &str) -> io::Result<()> {
+        match self.faults.check(&FaultPoint::Move) {
+            Some(fault) => Err(fault.to_error()),
+            None => self.inner.move_directory(dir, source_tier, target_tier),
+        }
+    }
+
+    fn remove_temp_files(&self, interrupted: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        self.inner.remove_temp_files(interrupted)
+    }
+
+    fn settle_interrupted_move(&self, path: &Path, source_tier: &str, target_tier: &str) -> io::Result<bool> {
+        self.inner.settle_interrupted_move(path, source_tier, target_tier)
+    }
+
+    fn avoid_branch(&self, serial: &str) {
+        self.inner.avoid_branch(serial)
+    }
+
+    fn hold_branches(&self, serials: &HashSet<String>) {
+        self.inner.hold_branches(serials)
+    }
+
+    fn offline_branches(&self) -> HashSet<String> {
+        self.inner.offline_branches()
+    }
+
+    fn detach_branch(&self, serial: &str) -> io::Result<()> {
+        self.inner.detach_branch(serial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::SystemExecutor;
+
+    #[test]
+    fn test_inject_after() {
+        let faults = FaultInjector::new();
+        faults.inject_after(FaultPoint::Move, Fault::Errno(libc::ENOSPC), 1, 2);
+        let fired: Vec<bool> = (0..4).map(|_| faults.check(&FaultPoint::Move).is_some()).collect();
+        assert_eq!(fired, [false, true, true, false]);
+        assert_eq!(faults.calls(&FaultPoint::Move), 4);
+        assert_eq!(faults.triggered(&FaultPoint::Move), 2);
+        assert_eq!(faults.check(&FaultPoint::Scan), None);
+    }
+
+    #[test]
+    fn test_faulty_executor() {
+        let faults = FaultInjector::new();
+        let executor = FaultyExecutor::new(Arc::new(SystemExecutor), faults.clone());
+        faults.inject(FaultPoint::Command("rsync".to_string()), Fault::ExitCode(23));
+        faults.inject(FaultPoint::Command("lsblk".to_string()), Fault::DriveGone);
+        assert_eq!(executor.status(&["rsync".as_ref()]).unwrap().code(), Some(23));
+        assert_eq!(executor.output(&["lsblk".as_ref()]).unwrap_err().raw_os_error(), Some(libc::ENODEV));
+        assert!(executor.status(&["true".as_ref()]).unwrap().success());
+    }
+
+    #[test]
+    fn test_fault_errors() {
+        assert_eq!(Fault::Errno(libc::ENOSPC).to_error().kind(), io::ErrorKind::StorageFull);
+        assert_eq!(Fault::DriveGone.to_error().raw_os_error(), Some(libc::ENODEV));
+    }
+}
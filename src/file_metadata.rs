@@ -0,0 +1,290 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileMoveInfo {
+    // relative to the branch root; not necessarily valid UTF-8
+    pub src: PathBuf,
+    pub source_tier: String,
+    pub target_tier: String,
+    pub retries: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileMetadata {
+    pub last_access_time: SystemTime,
+    pub access_count: u64,
+    pub file_size: u64,
+    pub tier: String,
+    pub last_tier_move: Option<SystemTime>,
+}
+
+// A move that used up its retries and was set aside for inspection
+#[derive(Clone, Debug, PartialEq)]
+pub struct FailedMove {
+    pub info: FileMoveInfo,
+    pub error: String,
+    pub failed_at: SystemTime,
+}
+
+// A failed move waiting out its backoff
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingRetry {
+    pub info: FileMoveInfo,
+    pub due_at: SystemTime,
+}
+
+// A move as written to the journal when it is queued, kept until it is
+// done so a crash does not lose it. user moves are taken first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournaledMove {
+    pub info: FileMoveInfo,
+    pub user: bool,
+    pub size: u64,
+    pub queued_at: SystemTime,
+}
+
+// A move whose copy is under way, as shown by `drive-manager status`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferProgress {
+    pub info: FileMoveInfo,
+    pub bytes_copied: u64,
+    pub bytes_total: u64,
+    pub started_at: SystemTime,
+    pub updated_at: SystemTime,
+}
+
+impl TransferProgress {
+    // Average bytes per second since the copy started
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.updated_at.duration_since(self.started_at).unwrap_or_default().as_secs_f64();
+        if elapsed > 0.0 { self.bytes_copied as f64 / elapsed } else { 0.0 }
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        (rate > 0.0).then(|| Duration::from_secs_f64(self.bytes_total.saturating_sub(self.bytes_copied) as f64 / rate))
+    }
+}
+
+// The latest pass of one of the service's background loops, as shown by
+// `drive-manager status`. While a pass is running, duration and error are
+// still those of the one before.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopRun {
+    pub name: String,
+    pub started_at: SystemTime,
+    pub running: bool,
+    pub duration: Duration,
+    pub error: Option<String>,
+    pub next_run: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DrainState {
+    // asked for with `drive-manager drain`, not yet picked up
+    Requested,
+    Moving,
+    // emptied, verified and unmounted
+    Done,
+    Failed,
+}
+
+impl DrainState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DrainState::Requested => "requested",
+            DrainState::Moving => "moving",
+            DrainState::Done => "done",
+            DrainState::Failed => "failed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [DrainState::Requested, DrainState::Moving, DrainState::Done, DrainState::Failed].into_iter().find(|state| state.as_str() == name)
+    }
+}
+
+// Emptying one drive onto the others in its tier so it can be taken out
+#[derive(Clone, Debug, PartialEq)]
+pub struct Drain {
+    pub serial: String,
+    pub requested_at: SystemTime,
+    pub state: DrainState,
+    // files still to move off it
+    pub files_left: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaintenanceState {
+    // asked for with `drive-manager maintenance --enable`, not yet picked up
+    Requested,
+    // out of the pools and unmounted
+    Active,
+    // asked to end with --disable, not yet back in the pools
+    Ending,
+    Failed,
+}
+
+impl MaintenanceState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MaintenanceState::Requested => "requested",
+            MaintenanceState::Active => "active",
+            MaintenanceState::Ending => "ending",
+            MaintenanceState::Failed => "failed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [MaintenanceState::Requested, MaintenanceState::Active, MaintenanceState::Ending, MaintenanceState::Failed].into_iter().find(|state| state.as_str() == name)
+    }
+}
+
+// A drive taken out of the pools and unmounted for a while, for a firmware
+// update or to reseat its cable, with its files unavailable meanwhile
+#[derive(Clone, Debug, PartialEq)]
+pub struct Maintenance {
+    pub serial: String,
+    pub requested_at: SystemTime,
+    pub state: MaintenanceState,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DriveState {
+    // in the pools, or to be put in them when attached
+    Active,
+    // being drained
+    Evacuating,
+    // gave I/O errors, so moves no longer write to it
+    Quarantined,
+    // drained and taken out; left out of the pools until reset
+    Retired,
+    // not attached, or gone from the pools while running
+    Missing,
+}
+
+impl DriveState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DriveState::Active => "active",
+            DriveState::Evacuating => "evacuating",
+            DriveState::Quarantined => "quarantined",
+            DriveState::Retired => "retired",
+            DriveState::Missing => "missing",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [DriveState::Active, DriveState::Evacuating, DriveState::Quarantined, DriveState::Retired, DriveState::Missing].into_iter().find(|state| state.as_str() == name)
+    }
+}
+
+// A drive in the registry, kept from the first time it was set up so it
+// keeps its tier across restarts and hotplugs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Drive {
+    pub serial: String,
+    pub tier: String,
+    pub state: DriveState,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    // when tiering first found it in the pools
+    pub pooled_at: Option<SystemTime>,
+}
+
+// A drive moves have hit I/O errors on, which moves stop writing to
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuspectDrive {
+    pub serial: String,
+    pub errors: u64,
+    pub last_at: SystemTime,
+    pub last_error: String,
+}
+
+// A file uploaded to the archive tier, with a stub left in its place
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchivedFile {
+    pub path: PathBuf,
+    pub url: String,
+    pub size: u64,
+    pub archived_at: SystemTime,
+}
+
+// A file kept out of automatic moves after it kept failing to read
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuarantinedFile {
+    pub path: PathBuf,
+    pub quarantined_at: SystemTime,
+    pub reason: String,
+}
+
+// How far the first crawl of an adopted drive has got, picked up from
+// `cursor` when it was cut off
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrawlProgress {
+    pub serial: String,
+    pub started_at: SystemTime,
+    pub updated_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+    pub files: u64,
+    pub bytes: u64,
+    pub checksummed_bytes: u64,
+    // the last file recorded, in the order the crawl walks the drive
+    pub cursor: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_move_info_clone() {
+        let file_info = FileMoveInfo {
+            src: PathBuf::from("test_file"),
+            source_tier: "hot".to_string(),
+            target_tier: "warm".to_string(),
+            retries: 0,
+        };
+        let cloned_info = file_info.clone();
+        assert_eq!(file_info.src, cloned_info.src);
+        assert_eq!(file_info.source_tier, cloned_info.source_tier);
+        assert_eq!(file_info.target_tier, cloned_info.target_tier);
+        assert_eq!(file_info.retries, cloned_info.retries);
+    }
+
+    #[test]
+    fn test_file_metadata_clone() {
+        let file_metadata = FileMetadata {
+            last_access_time: SystemTime::now(),
+            access_count: 1,
+            file_size: 1024,
+            tier: "hot".to_string(),
+            last_tier_move: None,
+        };
+        let cloned_metadata = file_metadata.clone();
+        assert_eq!(file_metadata.last_access_time, cloned_metadata.last_access_time);
+        assert_eq!(file_metadata.access_count, cloned_metadata.access_count);
+        assert_eq!(file_metadata.file_size, cloned_metadata.file_size);
+        assert_eq!(file_metadata.tier, cloned_metadata.tier);
+    }
+
+    #[test]
+    fn test_transfer_rate() {
+        let started_at = SystemTime::now();
+        let mut progress = TransferProgress {
+            info: FileMoveInfo { src: PathBuf::from("a.mkv"), source_tier: "hot".to_string(), target_tier: "cold".to_string(), retries: 0 },
+            bytes_copied: 0,
+            bytes_total: 1000,
+            started_at,
+            updated_at: started_at,
+        };
+        assert_eq!(progress.eta(), None);
+        progress.bytes_copied = 250;
+        progress.updated_at = started_at + Duration::from_secs(5);
+        assert_eq!(progress.rate(), 50.0);
+        assert_eq!(progress.eta(), Some(Duration::from_secs(15)));
+    }
+}
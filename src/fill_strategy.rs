@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use serde_json::Value;
+
+// How a destination branch is chosen within a tier, for new files through
+// mergerfs and for the mover alike
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FillStrategy {
+    MostFreeSpace,
+    RoundRobin,
+    // the branch with the fewest moves writing to it
+    LeastUsedSpindle,
+    // the branch that already has the most of the file's directory
+    ExistingPath,
+}
+
+// A branch that has room for the file, as a strategy sees it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub room: u64,
+    // how many of the file's parent directories the branch already has
+    pub existing_depth: usize,
+    // moves writing to the branch right now
+    pub busy: usize,
+}
+
+impl FillStrategy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "most-free-space" => Some(FillStrategy::MostFreeSpace),
+            "round-robin" => Some(FillStrategy::RoundRobin),
+            "least-used-spindle" => Some(FillStrategy::LeastUsedSpindle),
+            "existing-path" => Some(FillStrategy::ExistingPath),
+            _ => None,
+        }
+    }
+
+    // The closest mergerfs create policy. mergerfs has no round-robin and
+    // cannot see disk load, so both spread new files at random.
+    pub fn mergerfs_policy(&self) -> &'static str {
+        match self {
+            FillStrategy::MostFreeSpace => "mfs",
+            FillStrategy::RoundRobin | FillStrategy::LeastUsedSpindle => "rand",
+            FillStrategy::ExistingPath => "epmfs",
+        }
+    }
+
+    // The index of the candidate to use, `turn` counting the choices made in
+    // the tier so far. Ties go to the one with the most room.
+    pub fn choose(&self, candidates: &[Candidate], turn: usize) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let best_by = |key: &dyn Fn(&Candidate) -> (i64, u64)| {
+            (0..candidates.len()).max_by_key(|index| key(&candidates[*index]))
+        };
+        match self {
+            FillStrategy::MostFreeSpace => best_by(&|candidate| (0, candidate.room)),
+            FillStrategy::RoundRobin => Some(turn % candidates.len()),
+            FillStrategy::LeastUsedSpindle => best_by(&|candidate| (-(candidate.busy as i64), candidate.room)),
+            FillStrategy::ExistingPath => best_by(&|candidate| (candidate.existing_depth as i64, candidate.room)),
+        }
+    }
+}
+
+// The fill_strategy config, one strategy for every tier or one per tier:
+//   "fill_strategy": "most-free-space"
+//   "fill_strategy": { "hot": "existing-path", "cold": "round-robin" }
+// A tier without one keeps the defaults: mergerfs fills the first branch
+// found (most free space in cold) and the mover picks the most free space.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FillPolicy {
+    pub default: Option<FillStrategy>,
+    pub tiers: HashMap<String, FillStrategy>,
+}
+
+impl FillPolicy {
+    pub fn from_config(config: &Value) -> Self {
+        match config.get("fill_strategy") {
+            Some(Value::Object(tiers)) => Self {
+                default: None,
+                tiers: tiers.iter().filter_map(|(tier, name)| Some((tier.clone(), FillStrategy::parse(name.as_str()?)?))).collect(),
+            },
+            Some(Value::String(name)) => Self { default: FillStrategy::parse(name), tiers: HashMap::new() },
+            _ => Self::default(),
+        }
+    }
+
+    pub fn for_tier(&self, tier: &str) -> Option<FillStrategy> {
+        self.tiers.get(tier).copied().or(self.default)
+    }
+
+    // The mergerfs create policy for a tier's pool
+    pub fn mergerfs_policy(&self, tier: &str) -> &'static str {
+        match self.for_tier(tier) {
+            Some(strategy) => strategy.mergerfs_policy(),
+            None if tier == "cold" => "mfs",
+            None => "ff",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn candidate(room: u64, existing_depth: usize, busy: usize) -> Candidate {
+        Candidate { room, existing_depth, busy }
+    }
+
+    #[test]
+    fn test_choose() {
+        let candidates = [candidate(10, 2, 1), candidate(30, 0, 2), candidate(20, 2, 0)];
+        assert_eq!(FillStrategy::MostFreeSpace.choose(&candidates, 0), Some(1));
+        assert_eq!(FillStrategy::ExistingPath.choose(&candidates, 0), Some(2));
+        assert_eq!(FillStrategy::LeastUsedSpindle.choose(&candidates, 0), Some(2));
+        assert_eq!([0, 1, 2, 3].map(|turn| FillStrategy::RoundRobin.choose(&candidates, turn)), [Some(0), Some(1), Some(2), Some(0)]);
+        assert_eq!(FillStrategy::RoundRobin.choose(&[], 0), None);
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = FillPolicy::from_config(&json!({ "fill_strategy": { "hot": "existing-path", "warm": "nonsense" } }));
+        assert_eq!(policy.for_tier("hot"), Some(FillStrategy::ExistingPath));
+        assert_eq!(policy.for_tier("warm"), None);
+        assert_eq!((policy.mergerfs_policy("hot"), policy.mergerfs_policy("warm"), policy.mergerfs_policy("cold")), ("epmfs", "ff", "mfs"));
+        let policy = FillPolicy::from_config(&json!({ "fill_strategy": "round-robin" }));
+        assert_eq!(policy.mergerfs_policy("cold"), "rand");
+    }
+}
@@ -0,0 +1,185 @@
+use std::env;
+use serde_json::Value;
+use crate::args::{Args, CONFIG_FILE_PATH};
+use crate::drive_manager::DriveManager;
+
+pub const DEFAULT_WATCHDOG_SEC: u64 = 300;
+pub const SERVICE_NAME: &str = "drive-manager";
+
+// Settings shared by the systemd unit and the NixOS module, derived from the
+// command line and the current config.
+struct ServiceSettings {
+    exec_args: Vec<String>,
+    watchdog_sec: u64,
+    requires_mounts_for: Vec<String>,
+}
+
+impl ServiceSettings {
+    fn new(args: &Args, config: &Value) -> Self {
+        let mut exec_args = vec!["--config".to_string(), args.config.clone()];
+        if let Some(profile) = &args.profile {
+            exec_args.extend(["--profile".to_string(), profile.clone()]);
+        }
+        exec_args.extend(["--threads".to_string(), args.threads.to_string()]);
+        if args.observe {
+            exec_args.push("--observe".to_string());
+        }
+        let watchdog_sec = config.get("watchdog_sec").and_then(Value::as_u64).unwrap_or(DEFAULT_WATCHDOG_SEC);
+        // the config and metadata DB must be reachable before the daemon can start
+        let config_dir = std::path::Path::new(&args.config).parent().map(|p| p.display().to_string()).unwrap_or_else(|| "/etc/drive-manager".to_string());
+        let mut requires_mounts_for = vec![config_dir];
+        if let Some(db_path) = config.get("db_path").and_then(Value::as_str) {
+            if let Some(db_dir) = std::path::Path::new(db_path).parent() {
+                requires_mounts_for.push(db_dir.display().to_string());
+            }
+        }
+        requires_mounts_for.dedup();
+        Self { exec_args, watchdog_sec, requires_mounts_for }
+    }
+
+    fn merged_mounts() -> Vec<String> {
+        DriveManager::TIERS.iter().map(|tier| format!("{}/{}", DriveManager::MERGERFS_MOUNT_PATH, tier)).collect()
+    }
+}
+
+// systemd unit files don't quote like a shell, but arguments with spaces still need quoting
+fn quote_exec_arg(arg: &str) -> String {
+    if arg.chars().any(|c| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.to_string()
+    }
+}
+
+pub fn systemd_unit(args: &Args, config: &Value) -> String {
+    let settings = ServiceSettings::new(args, config);
+    let exe = env::current_exe().map(|p| p.display().to_string()).unwrap_or_else(|_| "/usr/bin/drive-manager".to_string());
+    let exec_start = std::iter::once(exe.as_str()).chain(settings.exec_args.iter().map(String::as_str)).map(quote_exec_arg).collect::<Vec<_>>().join(" ");
+    format!(
+        "# Generated by `drive-manager generate systemd`.
+# Services that use the pooled mounts ({merged}) should declare
+# After=drive-manager.service and Requires=drive-manager.service.
+[Unit]
+Description=Drive Manager - drive pooling and tiering
+Documentation=https://github.com/projectinitiative/drive-manager
+After=local-fs.target systemd-udev-settle.service
+Wants=systemd-udev-settle.service
+RequiresMountsFor={requires}
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={exec_start}
+WatchdogSec={watchdog}
+Restart=on-failure
+RestartSec=30
+TimeoutStartSec=infinity
+TimeoutStopSec=300
+KillMode=mixed
+
+[Install]
+WantedBy=multi-user.target
+",
+        merged = ServiceSettings::merged_mounts().join(", "),
+        requires = settings.requires_mounts_for.join(" "),
+        exec_start = exec_start,
+        watchdog = settings.watchdog_sec,
+    )
+}
+
+// Escape text for a Nix indented string (''...'')
+fn nix_indented_string(text: &str) -> String {
+    text.replace("''", "'''").replace("${", "''${")
+}
+
+fn nix_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"").replace("${", "\\${"))
+}
+
+pub fn nixos_module(args: &Args, config: &Value) -> String {
+    // NixOS manages the config through environment.etc, so it always lives at the default path
+    let args = Args { config: CONFIG_FILE_PATH.to_string(), ..args.clone() };
+    let settings = ServiceSettings::new(&args, config);
+    let config_json = serde_json::to_string_pretty(config).unwrap();
+    let exec_args = settings.exec_args.iter().map(|arg| nix_string(arg)).collect::<Vec<_>>().join(" ");
+    let requires = settings.requires_mounts_for.iter().map(|path| nix_string(path)).collect::<Vec<_>>().join(" ");
+    let config_target = CONFIG_FILE_PATH.trim_start_matches("/etc/");
+    format!(
+        "# Generated by `drive-manager generate nixos`.
+{{ config, lib, pkgs, ... }}:
+
+let
+  # Point this at the drive-manager package, e.g. the flake's packages.default.
+  drive-manager = pkgs.callPackage ./package.nix {{ }};
+in
+{{
+  environment.etc.{etc_name}.text = ''
+{config_json}
+  '';
+
+  systemd.services.{service} = {{
+    description = \"Drive Manager - drive pooling and tiering\";
+    after = [ \"local-fs.target\" \"systemd-udev-settle.service\" ];
+    wants = [ \"systemd-udev-settle.service\" ];
+    wantedBy = [ \"multi-user.target\" ];
+    unitConfig.RequiresMountsFor = [ {requires} ];
+    restartTriggers = [ config.environment.etc.{etc_name}.source ];
+    path = drive-manager.propagatedBuildInputs;
+    serviceConfig = {{
+      Type = \"notify\";
+      NotifyAccess = \"main\";
+      ExecStart = lib.escapeShellArgs ([ \"${{drive-manager}}/bin/drive-manager\" ] ++ [ {exec_args} ]);
+      WatchdogSec = {watchdog};
+      Restart = \"on-failure\";
+      RestartSec = 30;
+      TimeoutStartSec = \"infinity\";
+      TimeoutStopSec = 300;
+      KillMode = \"mixed\";
+    }};
+  }};
+}}
+",
+        etc_name = nix_string(config_target),
+        config_json = nix_indented_string(&config_json).lines().map(|line| format!("    {}", line)).collect::<Vec<_>>().join("\n"),
+        service = SERVICE_NAME,
+        requires = requires,
+        exec_args = exec_args,
+        watchdog = settings.watchdog_sec,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_systemd_unit() {
+        let args = Args::parse_from(["generate", "systemd", "-c", "/etc/drive-manager/config.json", "-t", "2"]).unwrap();
+        let unit = systemd_unit(&args, &json!({ "watchdog_sec": 60 }));
+        assert!(unit.contains("After=local-fs.target"));
+        assert!(unit.contains("RequiresMountsFor=/etc/drive-manager\n"));
+        assert!(unit.contains("WatchdogSec=60"));
+        assert!(unit.contains("--config /etc/drive-manager/config.json --threads 2"));
+        assert!(unit.contains("/mnt/merged/hot"));
+        let args = Args::parse_from(["generate", "systemd", "--observe"]).unwrap();
+        assert!(systemd_unit(&args, &json!({})).contains("--threads 4 --observe"));
+    }
+
+    #[test]
+    fn test_quote_exec_arg() {
+        assert_eq!(quote_exec_arg("/etc/config.json"), "/etc/config.json");
+        assert_eq!(quote_exec_arg("/etc/my config.json"), "\"/etc/my config.json\"");
+    }
+
+    #[test]
+    fn test_nixos_module() {
+        let args = Args::parse_from(["generate", "nixos", "-c", "/tmp/config.json"]).unwrap();
+        let module = nixos_module(&args, &json!({ "filesystem": "ext4", "note": "a ''quoted'' ${x}" }));
+        assert!(module.contains("environment.etc.\"drive-manager/config.json\".text"));
+        assert!(module.contains("\"filesystem\": \"ext4\""));
+        assert!(module.contains("a '''quoted''' ''${x}"));
+        assert!(module.contains("\"--config\" \"/etc/drive-manager/config.json\""));
+        assert!(module.contains("WatchdogSec = 300;"));
+    }
+}
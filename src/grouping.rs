@@ -0,0 +1,187 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+
+// A rule from the keep_together config list. Either
+//   { "files": ["mkv", "mp4"], "sidecars": ["srt", "nfo"] }
+// so Movie.mkv moves with Movie.srt and Movie.en.srt next to it, or
+//   { "directory": "Season *" }
+// so everything under a matching directory moves as one.
+#[derive(Clone, Debug, PartialEq)]
+enum Rule {
+    Sidecars { files: Vec<String>, sidecars: Vec<String> },
+    Directory(String),
+}
+
+// Where the other members of a file's group are
+#[derive(Clone, Debug, PartialEq)]
+pub enum GroupScope {
+    // every file under this directory
+    Directory(PathBuf),
+    // some of the files directly in this directory
+    Siblings(PathBuf),
+}
+
+impl GroupScope {
+    pub fn directory(&self) -> &Path {
+        match self {
+            GroupScope::Directory(dir) | GroupScope::Siblings(dir) => dir,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeepTogether {
+    rules: Vec<Rule>,
+}
+
+impl KeepTogether {
+    pub fn from_config(config: &Value) -> Self {
+        let strings = |rule: &Value, key: &str| -> Vec<String> {
+            rule.get(key).and_then(Value::as_array).map(|values| {
+                values.iter().filter_map(Value::as_str).map(|ext| ext.trim_start_matches('.').to_ascii_lowercase()).collect()
+            }).unwrap_or_default()
+        };
+        let rules = config.get("keep_together").and_then(Value::as_array).map(|rules| {
+            rules.iter().filter_map(|rule| {
+                if let Some(pattern) = rule.get("directory").and_then(Value::as_str) {
+                    return Some(Rule::Directory(pattern.to_string()));
+                }
+                let (files, sidecars) = (strings(rule, "files"), strings(rule, "sidecars"));
+                (!files.is_empty() && !sidecars.is_empty()).then_some(Rule::Sidecars { files, sidecars })
+            }).collect()
+        }).unwrap_or_default();
+        Self { rules }
+    }
+
+    // Where to look for the rest of the group `path` belongs to. A matching
+    // directory takes the whole folder, the closest one if several match.
+    pub fn scope(&self, path: &Path) -> Option<GroupScope> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let group_dir = dir.ancestors().filter(|ancestor| !ancestor.as_os_str().is_empty()).find(|ancestor| {
+            let name = ancestor.file_name().unwrap_or_default();
+            self.rules.iter().any(|rule| matches!(rule, Rule::Directory(pattern) if glob_match(pattern.as_bytes(), name.as_bytes())))
+        });
+        if let Some(group_dir) = group_dir {
+            return Some(GroupScope::Directory(group_dir.to_path_buf()));
+        }
+        let ext = extension(path)?;
+        self.rules.iter()
+            .any(|rule| matches!(rule, Rule::Sidecars { files, sidecars } if files.contains(&ext) || sidecars.contains(&ext)))
+            .then(|| GroupScope::Siblings(dir.to_path_buf()))
+    }
+
+    // The files among `candidates`, which should come from scope(path),
+    // that move together with `path`, including `path` itself
+    pub fn members(&self, path: &Path, scope: &GroupScope, candidates: &[PathBuf]) -> Vec<PathBuf> {
+        let dir = match scope {
+            GroupScope::Directory(dir) => return candidates.iter().filter(|candidate| candidate.starts_with(dir)).cloned().collect(),
+            GroupScope::Siblings(dir) => dir,
+        };
+        let siblings: Vec<&PathBuf> = candidates.iter().filter(|candidate| candidate.parent().unwrap_or(Path::new("")) == dir).collect();
+        let mut members = vec![path.to_path_buf()];
+        for rule in &self.rules {
+            let Rule::Sidecars { files, sidecars } = rule else { continue };
+            let is_main = |file: &Path| extension(file).is_some_and(|ext| files.contains(&ext));
+            let is_sidecar_of = |sidecar: &Path, main: &Path| {
+                extension(sidecar).is_some_and(|ext| sidecars.contains(&ext)) && main.file_stem().is_some_and(|stem| {
+                    let mut prefix = stem.as_bytes().to_vec();
+                    prefix.push(b'.');
+                    name(sidecar).starts_with(&prefix)
+                })
+            };
+            // a sidecar goes with the main file whose name shares the longest
+            // prefix, so Movie.Part.2.srt sticks to Movie.Part.2.mkv, not Movie.mkv
+            let owner = |sidecar: &Path| -> Option<&Path> {
+                siblings.iter().map(|sibling| sibling.as_path())
+                    .filter(|sibling| is_main(sibling) && is_sidecar_of(sidecar, sibling))
+                    .max_by_key(|main| name(main).len())
+            };
+            let main = if is_main(path) { Some(path) } else { owner(path) };
+            let Some(main) = main else { continue };
+            for sibling in &siblings {
+                if (sibling.as_path() == main || owner(sibling) == Some(main)) && !members.contains(sibling) {
+                    members.push(sibling.to_path_buf());
+                }
+            }
+        }
+        members
+    }
+}
+
+fn name(path: &Path) -> &[u8] {
+    path.file_name().map(OsStr::as_bytes).unwrap_or_default()
+}
+
+pub fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+}
+
+// Shell-style matching of a file name against a pattern with * and ?
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rules() -> KeepTogether {
+        KeepTogether::from_config(&json!({ "keep_together": [
+            { "files": ["mkv"], "sidecars": [".srt", "NFO"] },
+            { "directory": "Season *" },
+        ] }))
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn test_sidecars() {
+        let rules = rules();
+        let candidates = paths(&["movies/Some.Movie.mkv", "movies/Some.Movie.en.srt", "movies/Some.Movie.nfo", "movies/Some.mkv", "movies/Some.srt", "movies/Other.srt", "movies/x/Some.Movie.srt"]);
+        let video = Path::new("movies/Some.Movie.mkv");
+        let scope = rules.scope(video).unwrap();
+        assert_eq!(scope, GroupScope::Siblings(PathBuf::from("movies")));
+        let expected = paths(&["movies/Some.Movie.mkv", "movies/Some.Movie.en.srt", "movies/Some.Movie.nfo"]);
+        assert_eq!(rules.members(video, &scope, &candidates), expected);
+        // a subtitle pulls its video and the other sidecars along
+        let subtitle = Path::new("movies/Some.Movie.en.srt");
+        let mut members = rules.members(subtitle, &rules.scope(subtitle).unwrap(), &candidates);
+        members.sort();
+        let mut expected = expected;
+        expected.sort();
+        assert_eq!(members, expected);
+        let other = Path::new("movies/Some.mkv");
+        assert_eq!(rules.members(other, &scope, &candidates), paths(&["movies/Some.mkv", "movies/Some.srt"]));
+        assert_eq!(rules.scope(Path::new("movies/cover.jpg")), None);
+    }
+
+    #[test]
+    fn test_directories() {
+        let rules = rules();
+        let episode = Path::new("tv/Show/Season 1/e01.mkv");
+        let scope = rules.scope(episode).unwrap();
+        assert_eq!(scope, GroupScope::Directory(PathBuf::from("tv/Show/Season 1")));
+        let candidates = paths(&["tv/Show/Season 1/e01.mkv", "tv/Show/Season 1/extras/a.mkv", "tv/Show/Season 10/e01.mkv"]);
+        assert_eq!(rules.members(episode, &scope, &candidates), paths(&["tv/Show/Season 1/e01.mkv", "tv/Show/Season 1/extras/a.mkv"]));
+        assert!(KeepTogether::from_config(&json!({})).scope(episode).is_none());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"Season *", b"Season 10"));
+        assert!(glob_match(b"S??", b"S01"));
+        assert!(!glob_match(b"Season *", b"Specials"));
+        assert!(glob_match(b"*", b""));
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use crate::storage::ScannedFile;
+
+pub const MIN_FILES: usize = 100;
+pub const MIN_RATIO: f64 = 0.5;
+
+// What happens to the files in a hardlink farm, from hardlink_farms.action
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FarmAction {
+    // leave the farm where it is
+    Exclude,
+    // tier it, moving every link to a file at once
+    Group,
+}
+
+// Directories mostly made of files with more than one link, like rsnapshot
+// or rsync --link-dest backups, from the hardlink_farms config section:
+//   { "action": "exclude", "min_files": 100, "min_ratio": 0.5 }
+// Moving one link of a file copies it and leaves the others behind, so
+// every snapshot would end up holding its own copy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HardlinkFarms {
+    pub action: FarmAction,
+    pub min_files: usize,
+    pub min_ratio: f64,
+}
+
+impl HardlinkFarms {
+    pub fn from_config(config: &Value) -> Self {
+        let section = config.get("hardlink_farms");
+        let setting = |key: &str| section.and_then(|section| section.get(key));
+        Self {
+            action: match setting("action").and_then(Value::as_str) {
+                Some("group") => FarmAction::Group,
+                _ => FarmAction::Exclude,
+            },
+            min_files: setting("min_files").and_then(Value::as_u64).map_or(MIN_FILES, |files| files as usize),
+            min_ratio: setting("min_ratio").and_then(Value::as_f64).unwrap_or(MIN_RATIO),
+        }
+    }
+
+    // The outermost directories holding at least min_files files, of which
+    // at least min_ratio have other links
+    pub fn detect(&self, files: &[ScannedFile]) -> Vec<PathBuf> {
+        let mut counts: HashMap<&Path, (usize, usize)> = HashMap::new();
+        for file in files {
+            for dir in file.path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty()) {
+                let (total, linked) = counts.entry(dir).or_default();
+                *total += 1;
+                *linked += usize::from(file.hardlink.is_some());
+            }
+        }
+        let mut farms: Vec<&Path> = counts.into_iter()
+            .filter(|(_, (total, linked))| *total >= self.min_files.max(1) && *linked as f64 >= *total as f64 * self.min_ratio)
+            .map(|(dir, _)| dir)
+            .collect();
+        farms.sort();
+        // sorted, a farm's subdirectories come straight after it
+        let mut outermost: Vec<PathBuf> = Vec::new();
+        for dir in farms {
+            if outermost.last().is_none_or(|farm| !dir.starts_with(farm)) {
+                outermost.push(dir.to_path_buf());
+            }
+        }
+        outermost
+    }
+}
+
+pub fn in_farm(farms: &[PathBuf], path: &Path) -> bool {
+    farms.iter().any(|farm| path.starts_with(farm))
+}
+
+// Every path of each file with more than one link, keyed by each of them
+pub fn link_groups(files: &[ScannedFile]) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut inodes: HashMap<(u64, u64), Vec<PathBuf>> = HashMap::new();
+    for file in files {
+        if let Some(inode) = file.hardlink {
+            inodes.entry(inode).or_default().push(file.path.clone());
+        }
+    }
+    let mut groups = HashMap::new();
+    for mut paths in inodes.into_values().filter(|paths| paths.len() > 1) {
+        paths.sort();
+        for path in &paths {
+            groups.insert(path.clone(), paths.clone());
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::SystemTime;
+    use crate::placement::Placement;
+
+    fn file(path: &str, hardlink: Option<(u64, u64)>) -> ScannedFile {
+        ScannedFile { path: path.into(), tier: "hot".to_string(), branch: "SSD1".to_string(), accessed: SystemTime::UNIX_EPOCH, modified: SystemTime::UNIX_EPOCH, size: 1, placement: Placement::default(), hardlink }
+    }
+
+    #[test]
+    fn test_detect() {
+        let farms = HardlinkFarms::from_config(&json!({ "hardlink_farms": { "min_files": 4 } }));
+        assert_eq!(farms.action, FarmAction::Exclude);
+        let mut files = Vec::new();
+        for snapshot in ["daily.0", "daily.1", "daily.2"] {
+            files.push(file(&format!("backups/{}/a", snapshot), Some((1, 10))));
+            files.push(file(&format!("backups/{}/b", snapshot), Some((1, 11))));
+        }
+        files.push(file("backups/daily.0/new", None));
+        files.push(file("media/a.mkv", None));
+        files.push(file("media/b.mkv", Some((1, 12))));
+        files.push(file("media/c.mkv", None));
+        files.push(file("media/d.mkv", None));
+        assert_eq!(farms.detect(&files), [PathBuf::from("backups")]);
+        assert!(in_farm(&[PathBuf::from("backups")], Path::new("backups/daily.1/a")));
+
+        let groups = link_groups(&files);
+        assert_eq!(groups[Path::new("backups/daily.1/a")], [PathBuf::from("backups/daily.0/a"), PathBuf::from("backups/daily.1/a"), PathBuf::from("backups/daily.2/a")]);
+        // the only link left in the scan
+        assert!(!groups.contains_key(Path::new("media/b.mkv")));
+    }
+}
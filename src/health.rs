@@ -0,0 +1,126 @@
+use std::ffi::OsStr;
+use std::io;
+use serde_json::Value;
+use crate::executor::Executor;
+
+// ATA attributes counting sectors the drive has given up on
+const REALLOCATED_SECTORS: u64 = 5;
+const PENDING_SECTORS: u64 = 197;
+
+// smartctl's JSON report on a drive, which needs smartmontools 7. A drive
+// in standby is left asleep rather than spun up to answer.
+pub fn smart_command(path: &str) -> [&str; 5] {
+    ["smartctl", "--json", "--all", "--nocheck=standby", path]
+}
+
+// What SMART says about a drive. Fields smartctl could not get are None.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Health {
+    pub temperature_c: Option<i64>,
+    pub power_on_hours: Option<u64>,
+    // the drive's own overall verdict
+    pub passed: Option<bool>,
+    pub reallocated_sectors: u64,
+    pub pending_sectors: u64,
+    // NVMe wear, which goes past 100 once the rated endurance is used up
+    pub percentage_used: Option<u64>,
+    pub asleep: bool,
+}
+
+impl Health {
+    pub fn parse(output: &[u8]) -> io::Result<Self> {
+        let report: Value = serde_json::from_slice(output).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let attribute = |id: u64| -> u64 {
+            report.pointer("/ata_smart_attributes/table").and_then(Value::as_array).into_iter().flatten()
+                .find(|attribute| attribute.get("id").and_then(Value::as_u64) == Some(id))
+                .and_then(|attribute| attribute.pointer("/raw/value")).and_then(Value::as_u64).unwrap_or(0)
+        };
+        let asleep = report.pointer("/smartctl/messages").and_then(Value::as_array).into_iter().flatten()
+            .filter_map(|message| message.get("string").and_then(Value::as_str))
+            .any(|message| message.contains("STANDBY"));
+        Ok(Self {
+            temperature_c: report.pointer("/temperature/current").and_then(Value::as_i64),
+            power_on_hours: report.pointer("/power_on_time/hours").and_then(Value::as_u64),
+            passed: report.pointer("/smart_status/passed").and_then(Value::as_bool),
+            reallocated_sectors: attribute(REALLOCATED_SECTORS),
+            pending_sectors: attribute(PENDING_SECTORS),
+            percentage_used: report.pointer("/nvme_smart_health_information_log/percentage_used").and_then(Value::as_u64),
+            asleep,
+        })
+    }
+
+    // One word on the drive's state, with what is wearing if anything is
+    pub fn summary(&self) -> String {
+        if self.asleep {
+            return "asleep".to_string();
+        }
+        let mut worn = Vec::new();
+        if self.reallocated_sectors > 0 {
+            worn.push(format!("{} reallocated", self.reallocated_sectors));
+        }
+        if self.pending_sectors > 0 {
+            worn.push(format!("{} pending", self.pending_sectors));
+        }
+        if let Some(used) = self.percentage_used.filter(|used| *used >= 80) {
+            worn.push(format!("{}% worn", used));
+        }
+        let verdict = match self.passed {
+            Some(false) => "FAILING",
+            Some(true) if worn.is_empty() => "ok",
+            Some(true) => "worn",
+            None => "unknown",
+        };
+        if worn.is_empty() { verdict.to_string() } else { format!("{} ({})", verdict, worn.join(", ")) }
+    }
+}
+
+// Ask smartctl about the drive at `path`. Its exit status is a bitmask that
+// is set for a failing drive as much as for an error, so only output that
+// is not a report counts as failing to read it.
+pub fn read(executor: &dyn Executor, path: &str) -> io::Result<Health> {
+    let output = executor.output(&smart_command(path).map(OsStr::new))?;
+    Health::parse(&output.stdout).map_err(|e| io::Error::new(e.kind(), format!("smartctl gave no report for {}: {}", path, String::from_utf8_lossy(&output.stderr).trim())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ata() {
+        let health = Health::parse(br#"{
+            "smart_status": { "passed": true },
+            "temperature": { "current": 34 },
+            "power_on_time": { "hours": 21034 },
+            "ata_smart_attributes": { "table": [
+                { "id": 5, "name": "Reallocated_Sector_Ct", "raw": { "value": 8 } },
+                { "id": 197, "name": "Current_Pending_Sector", "raw": { "value": 0 } }
+            ] }
+        }"#).unwrap();
+        assert_eq!((health.temperature_c, health.power_on_hours), (Some(34), Some(21034)));
+        assert_eq!(health.summary(), "worn (8 reallocated)");
+        let failing = Health { passed: Some(false), ..health };
+        assert_eq!(failing.summary(), "FAILING (8 reallocated)");
+    }
+
+    #[test]
+    fn test_parse_nvme() {
+        let health = Health::parse(br#"{
+            "smart_status": { "passed": true },
+            "temperature": { "current": 41 },
+            "power_on_time": { "hours": 512 },
+            "nvme_smart_health_information_log": { "percentage_used": 3 }
+        }"#).unwrap();
+        assert_eq!(health.percentage_used, Some(3));
+        assert_eq!(health.summary(), "ok");
+    }
+
+    #[test]
+    fn test_parse_standby() {
+        let health = Health::parse(br#"{ "smartctl": { "messages": [{ "string": "Device is in STANDBY mode, exit(2)", "severity": "information" }], "exit_status": 2 } }"#).unwrap();
+        assert!(health.asleep);
+        assert_eq!((health.temperature_c, health.summary().as_str()), (None, "asleep"));
+        assert!(Health::parse(b"smartctl: command not found").is_err());
+        assert_eq!(Health::parse(b"{}").unwrap().summary(), "unknown");
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+
+// the netlink group udev passes events on to once its rules have run, by
+// which time lsblk can see the drive's serial and model
+const UDEV_GROUP: u32 = 2;
+const LIBUDEV_PREFIX: &[u8] = b"libudev\0";
+
+// One device event, as the kernel sends it or as udev passes it on
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Uevent {
+    pub action: String,
+    pub subsystem: String,
+    pub devtype: String,
+    // the /dev path
+    pub devname: String,
+}
+
+impl Uevent {
+    // The /dev path of the whole disk just attached, if that is what this is.
+    // zram devices are disks too, but made at runtime and not for keeping.
+    pub fn added_disk(&self) -> Option<&str> {
+        let is_disk = self.subsystem == "block" && self.devtype == "disk" && !self.devname.starts_with("/dev/zram");
+        (self.action == "add" && is_disk).then_some(self.devname.as_str())
+    }
+}
+
+// Parse a uevent, either the kernel's "add@/devices/..." followed by
+// KEY=VALUE lines, or udev's, whose header says where its KEY=VALUE lines
+// are. Either way they are NUL separated.
+pub fn parse(buf: &[u8]) -> Option<Uevent> {
+    let properties = if buf.starts_with(LIBUDEV_PREFIX) {
+        let field = |at: usize| buf.get(at..at + 4).map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()) as usize);
+        let (offset, len) = (field(16)?, field(20)?);
+        buf.get(offset..offset + len)?
+    } else {
+        let start = buf.iter().position(|byte| *byte == 0)? + 1;
+        buf.get(start..)?
+    };
+    let properties: HashMap<&str, &str> = properties.split(|byte| *byte == 0)
+        .filter_map(|line| std::str::from_utf8(line).ok()?.split_once('='))
+        .collect();
+    let devname = properties.get("DEVNAME").map(|name| {
+        if name.starts_with('/') { name.to_string() } else { format!("/dev/{}", name) }
+    }).unwrap_or_default();
+    Some(Uevent {
+        action: properties.get("ACTION")?.to_string(),
+        subsystem: properties.get("SUBSYSTEM").unwrap_or(&"").to_string(),
+        devtype: properties.get("DEVTYPE").unwrap_or(&"").to_string(),
+        devname,
+    })
+}
+
+// Listens for udev's device events. Only root can send to the group, so
+// what arrives came from udev.
+pub struct HotplugMonitor {
+    socket: File,
+}
+
+impl HotplugMonitor {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { File::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = UDEV_GROUP;
+        let bound = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_nl as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { socket })
+    }
+
+    // Block until a whole disk is attached, and return its /dev path
+    pub fn next_disk(&mut self) -> io::Result<String> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = self.socket.read(&mut buf)?;
+            if let Some(path) = parse(&buf[..len]).as_ref().and_then(Uevent::added_disk) {
+                return Ok(path.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kernel() {
+        let event = parse(b"add@/devices/pci0000:00/usb2/2-1/host6/target6:0:0/6:0:0:0/block/sdd\0ACTION=add\0DEVPATH=/devices/.../block/sdd\0SUBSYSTEM=block\0DEVNAME=sdd\0DEVTYPE=disk\0SEQNUM=4211\0").unwrap();
+        assert_eq!(event.added_disk(), Some("/dev/sdd"));
+        let partition = parse(b"add@/block/sdd/sdd1\0ACTION=add\0SUBSYSTEM=block\0DEVNAME=sdd1\0DEVTYPE=partition\0").unwrap();
+        assert_eq!(partition.added_disk(), None);
+        assert_eq!(parse(b"no properties"), None);
+    }
+
+    #[test]
+    fn test_parse_udev() {
+        let properties = b"ACTION=add\0SUBSYSTEM=block\0DEVNAME=/dev/sde\0DEVTYPE=disk\0ID_SERIAL=WD-1\0";
+        let mut buf = LIBUDEV_PREFIX.to_vec();
+        buf.extend(0xfeedcafe_u32.to_be_bytes());
+        buf.extend(40_u32.to_ne_bytes());
+        buf.extend(40_u32.to_ne_bytes());
+        buf.extend((properties.len() as u32).to_ne_bytes());
+        buf.resize(40, 0);
+        buf.extend(properties);
+        assert_eq!(parse(&buf).unwrap().added_disk(), Some("/dev/sde"));
+        let action = buf.windows(10).position(|window| window == b"ACTION=add").unwrap();
+        buf[action + 7..action + 10].copy_from_slice(b"rem");
+        assert_eq!(parse(&buf).unwrap().added_disk(), None);
+        assert_eq!(parse(b"libudev\0short"), None);
+    }
+}
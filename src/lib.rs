@@ -0,0 +1,68 @@
+pub mod activity;
+pub mod adopt;
+pub mod archive;
+pub mod args;
+pub mod bandwidth;
+pub mod bcachefs;
+pub mod btrfs;
+pub mod check_schedule;
+pub mod cgroup;
+pub mod checksum;
+pub mod clock;
+pub mod config;
+pub mod config_watch;
+pub mod control;
+pub mod crawl;
+pub mod device_class;
+pub mod disk_stats;
+pub mod doctor;
+pub mod drive_manager;
+pub mod encryption;
+pub mod events;
+pub mod executor;
+pub mod exit_code;
+pub mod external_jobs;
+pub mod fault_injection;
+pub mod file_metadata;
+pub mod fill_strategy;
+pub mod generate;
+pub mod grouping;
+pub mod hotplug;
+pub mod hardlinks;
+pub mod health;
+pub mod load_monitor;
+pub mod lsblk;
+pub mod media_server;
+pub mod metadata_db;
+pub mod mount_watch;
+pub mod move_queue;
+pub mod mover;
+pub mod notify;
+pub mod open_files;
+pub mod partitions;
+pub mod persist_mounts;
+pub mod pins;
+pub mod placement;
+pub mod plan;
+pub mod pool_check;
+pub mod power;
+pub mod progress;
+pub mod project_quota;
+pub mod read_patterns;
+pub mod read_watch;
+pub mod reload;
+pub mod reserve;
+pub mod retry;
+pub mod scratch;
+pub mod secrets;
+pub mod sd_notify;
+pub mod signals;
+pub mod simulation;
+pub mod staging;
+pub mod storage;
+pub mod tier_rules;
+pub mod tiering_manager;
+pub mod transcripts;
+pub mod ttl;
+pub mod write_skew;
+pub mod zfs;
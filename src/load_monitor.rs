@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+use crate::disk_stats;
+
+pub const MAX_LOAD_PER_CPU: f64 = 1.0;
+pub const MAX_DISK_UTIL_PERCENT: f64 = 80.0;
+pub const MAX_MEMORY_PRESSURE: f64 = 10.0;
+pub const SLOW_AT_PERCENT: f64 = 70.0;
+pub const CHECK_SEC: u64 = 15;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Throttle {
+    #[default]
+    Normal,
+    // one move at a time per lane
+    Slow,
+    // no new moves until the system quiets down
+    Pause,
+}
+
+// When background moves back off, from the load_throttle config section:
+//   { "max_load_per_cpu": 1.0, "max_disk_util_percent": 80,
+//     "max_memory_pressure": 10, "slow_at_percent": 70, "check_sec": 15,
+//     "max_disk_latency_ms": 50 }
+// Moves pause once any reading reaches its limit, and slow down once any
+// reaches slow_at_percent of it. Without the section moves never back off.
+// Latency, from the managed drives' I/O statistics, only counts when
+// max_disk_latency_ms is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadLimits {
+    pub max_load_per_cpu: f64,
+    pub max_disk_util: f64,
+    // PSI "some" avg10 for memory, the percentage of time tasks stalled
+    pub max_memory_pressure: f64,
+    pub max_disk_latency_ms: Option<f64>,
+    pub slow_at: f64,
+    pub check_interval: Duration,
+}
+
+impl LoadLimits {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("load_throttle").filter(|section| section.is_object())?;
+        let setting = |key: &str, default: f64| section.get(key).and_then(Value::as_f64).unwrap_or(default);
+        Some(Self {
+            max_load_per_cpu: setting("max_load_per_cpu", MAX_LOAD_PER_CPU),
+            max_disk_util: setting("max_disk_util_percent", MAX_DISK_UTIL_PERCENT) / 100.0,
+            max_memory_pressure: setting("max_memory_pressure", MAX_MEMORY_PRESSURE),
+            max_disk_latency_ms: section.get("max_disk_latency_ms").and_then(Value::as_f64),
+            slow_at: setting("slow_at_percent", SLOW_AT_PERCENT) / 100.0,
+            check_interval: Duration::from_secs(section.get("check_sec").and_then(Value::as_u64).unwrap_or(CHECK_SEC)),
+        })
+    }
+
+    // How moves should run under `sample`, with the reading that decided it
+    pub fn throttle(&self, sample: &LoadSample) -> (Throttle, Option<String>) {
+        let mut readings = vec![(sample.load_per_cpu / self.max_load_per_cpu, format!("load {:.2} per CPU", sample.load_per_cpu))];
+        if let Some((disk, util)) = &sample.busiest_disk {
+            readings.push((util / self.max_disk_util, format!("{} {:.0}% busy", disk, util * 100.0)));
+        }
+        if let (Some((disk, latency)), Some(max)) = (&sample.slowest_disk, self.max_disk_latency_ms) {
+            readings.push((latency / max, format!("{} taking {:.0}ms per I/O", disk, latency)));
+        }
+        if let Some(pressure) = sample.memory_pressure {
+            readings.push((pressure / self.max_memory_pressure, format!("memory pressure {:.1}%", pressure)));
+        }
+        let (ratio, reason) = readings.into_iter().max_by(|a, b| a.0.total_cmp(&b.0)).unwrap();
+        if ratio >= 1.0 {
+            (Throttle::Pause, Some(reason))
+        } else if ratio >= self.slow_at {
+            (Throttle::Slow, Some(reason))
+        } else {
+            (Throttle::Normal, None)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadSample {
+    pub load_per_cpu: f64,
+    // the disk with the highest utilization since the previous sample
+    pub busiest_disk: Option<(String, f64)>,
+    // the managed drive with the highest I/O latency in milliseconds
+    pub slowest_disk: Option<(String, f64)>,
+    pub memory_pressure: Option<f64>,
+}
+
+// Reads load, disk and memory pressure from a /proc tree. Disk utilization
+// is the share of time a disk had I/O in flight between two samples, so
+// the first sample has none.
+pub struct LoadMonitor {
+    proc_root: PathBuf,
+    cpus: usize,
+    last_disks: Option<(SystemTime, HashMap<String, u64>)>,
+}
+
+impl LoadMonitor {
+    pub fn new(proc_root: &Path, cpus: usize) -> Self {
+        Self { proc_root: proc_root.to_path_buf(), cpus: cpus.max(1), last_disks: None }
+    }
+
+    // Disk readings are skipped when `with_disks` is false, for samples
+    // taken while our own moves keep the disks busy
+    pub fn sample(&mut self, now: SystemTime, with_disks: bool) -> io::Result<LoadSample> {
+        let loadavg = fs::read_to_string(self.proc_root.join("loadavg"))?;
+        let load: f64 = loadavg.split_whitespace().next().and_then(|load| load.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unreadable loadavg"))?;
+        // older kernels have no PSI
+        let memory_pressure = fs::read_to_string(self.proc_root.join("pressure/memory")).ok().and_then(|psi| some_avg10(&psi));
+        let disks = io_ticks(&fs::read_to_string(self.proc_root.join("diskstats"))?);
+        let busiest_disk = match self.last_disks.replace((now, disks.clone())) {
+            Some((then, last)) if with_disks => {
+                let elapsed = now.duration_since(then).unwrap_or_default().as_millis() as f64;
+                disks.iter()
+                    .filter(|_| elapsed > 0.0)
+                    .filter_map(|(disk, ticks)| last.get(disk).map(|last| (disk.clone(), (ticks.saturating_sub(*last) as f64 / elapsed).min(1.0))))
+                    .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+            }
+            _ => None,
+        };
+        Ok(LoadSample { load_per_cpu: load / self.cpus as f64, busiest_disk, slowest_disk: None, memory_pressure })
+    }
+}
+
+fn some_avg10(psi: &str) -> Option<f64> {
+    let some = psi.lines().find(|line| line.starts_with("some "))?;
+    some.split_whitespace().find_map(|field| field.strip_prefix("avg10="))?.parse().ok()
+}
+
+// Milliseconds each whole disk has spent doing I/O
+fn io_ticks(diskstats: &str) -> HashMap<String, u64> {
+    disk_stats::parse(diskstats).into_iter().map(|(name, counters)| (name, counters.io_ms)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    fn write_proc(root: &Path, load: &str, sda_ticks: u64) {
+        fs::create_dir_all(root.join("pressure")).unwrap();
+        fs::write(root.join("loadavg"), format!("{} 0.50 0.40 1/200 1234\n", load)).unwrap();
+        fs::write(root.join("pressure/memory"), "some avg10=2.50 avg60=1.00 avg300=0.50 total=100\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        fs::write(root.join("diskstats"), format!(
+            "   8       0 sda 1 0 8 1 1 0 8 1 0 {} 2\n   8       1 sda1 1 0 8 1 1 0 8 1 0 {} 2\n   7       0 loop0 1 0 8 1 1 0 8 1 0 999999 2\n 259       0 nvme0n1 1 0 8 1 1 0 8 1 0 100 2\n",
+            sda_ticks, sda_ticks,
+        )).unwrap();
+    }
+
+    #[test]
+    fn test_sample() {
+        let proc_root = tempdir().unwrap();
+        let mut monitor = LoadMonitor::new(proc_root.path(), 4);
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        write_proc(proc_root.path(), "2.00", 1000);
+        let first = monitor.sample(t0, true).unwrap();
+        assert_eq!(first, LoadSample { load_per_cpu: 0.5, busiest_disk: None, slowest_disk: None, memory_pressure: Some(2.5) });
+        write_proc(proc_root.path(), "2.00", 10_000);
+        let second = monitor.sample(t0 + Duration::from_secs(10), true).unwrap();
+        assert_eq!(second.busiest_disk, Some(("sda".to_string(), 0.9)));
+        assert_eq!(monitor.sample(t0 + Duration::from_secs(20), false).unwrap().busiest_disk, None);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let limits = LoadLimits::from_config(&json!({ "load_throttle": { "max_disk_util_percent": 50 } })).unwrap();
+        let sample = |load_per_cpu, util| LoadSample { load_per_cpu, busiest_disk: Some(("sda".to_string(), util)), slowest_disk: None, memory_pressure: Some(1.0) };
+        assert_eq!(limits.throttle(&sample(0.2, 0.1)), (Throttle::Normal, None));
+        assert_eq!(limits.throttle(&sample(0.8, 0.1)), (Throttle::Slow, Some("load 0.80 per CPU".to_string())));
+        assert_eq!(limits.throttle(&sample(0.2, 0.6)), (Throttle::Pause, Some("sda 60% busy".to_string())));
+        assert!(LoadLimits::from_config(&json!({})).is_none());
+
+        let slow = LoadSample { slowest_disk: Some(("WD-1".to_string(), 40.0)), ..Default::default() };
+        assert_eq!(limits.throttle(&slow), (Throttle::Normal, None));
+        let limits = LoadLimits::from_config(&json!({ "load_throttle": { "max_disk_latency_ms": 50 } })).unwrap();
+        assert_eq!(limits.throttle(&slow), (Throttle::Slow, Some("WD-1 taking 40ms per I/O".to_string())));
+    }
+}
@@ -0,0 +1,341 @@
+use std::io;
+use serde_json::Value;
+
+// Columns the rest of the code relies on. These exist in every util-linux
+// release we support, so discovery falls back to them when an older lsblk
+// rejects the full column list.
+pub const LSBLK_MINIMAL_COLUMNS: &str = "NAME,KNAME,TYPE,SERIAL,WWN,MODEL,ROTA,TRAN,RM,HOTPLUG,SIZE,FSTYPE,UUID,LABEL,PARTLABEL,PTTYPE,MOUNTPOINT";
+// what a mounted filesystem holds, asked for with the minimal columns
+// where lsblk has it, from util-linux 2.33 on
+pub const LSBLK_FSUSED_COLUMN: &str = "FSUSED";
+
+pub const LUKS_FSTYPE: &str = "crypto_LUKS";
+// signatures other than filesystems, of containers and of RAID and volume
+// manager members, which hold data whatever is layered on them
+const SIGNATURES: [(&str, &str); 5] = [
+    (LUKS_FSTYPE, "a LUKS container"),
+    ("linux_raid_member", "an md RAID member"),
+    ("isw_raid_member", "an Intel RAID member"),
+    ("ddf_raid_member", "a DDF RAID member"),
+    ("LVM2_member", "an LVM physical volume"),
+];
+
+// One entry of `lsblk --json` output, normalised across util-linux versions.
+// Fields that are missing or null in the output are None rather than errors.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockDevice {
+    pub name: String,
+    pub path: String,
+    pub device_type: String,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    pub model: Option<String>,
+    pub rota: bool,
+    pub removable: bool,
+    pub hotplug: bool,
+    pub tran: Option<String>,
+    pub size: Option<u64>,
+    pub fstype: Option<String>,
+    // bytes the filesystem holds, known only while it is mounted
+    pub fsused: Option<u64>,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    // the GPT partition name
+    pub partlabel: Option<String>,
+    // the partition table's type, gpt or dos; a partition has its disk's
+    pub pttype: Option<String>,
+    pub mountpoint: Option<String>,
+    pub children: Vec<BlockDevice>,
+    // set from drive_class config when rota and tran give the wrong class
+    pub class: Option<&'static str>,
+    // set from managed_partitions config: the path of the partition we
+    // use when the drive holds others
+    pub managed_partition: Option<String>,
+}
+
+impl BlockDevice {
+    pub fn from_value(device: &Value) -> Self {
+        let name = string_field(device, "name").unwrap_or_default();
+        // PATH was added in util-linux 2.33; with -p NAME is already the full path
+        let path = string_field(device, "path").unwrap_or_else(|| {
+            if name.starts_with('/') || name.is_empty() { name.clone() } else { format!("/dev/{}", name) }
+        });
+        // 2.37 added MOUNTPOINTS and some builds stopped filling MOUNTPOINT
+        let mountpoint = string_field(device, "mountpoint").or_else(|| {
+            device.get("mountpoints").and_then(Value::as_array)?.iter().find_map(|mountpoint| mountpoint.as_str().map(str::to_string))
+        });
+        Self {
+            device_type: string_field(device, "type").unwrap_or_default(),
+            serial: string_field(device, "serial"),
+            wwn: string_field(device, "wwn"),
+            model: string_field(device, "model"),
+            rota: bool_field(device, "rota").unwrap_or(true),
+            removable: bool_field(device, "rm").unwrap_or(false),
+            hotplug: bool_field(device, "hotplug").unwrap_or(false),
+            tran: string_field(device, "tran"),
+            size: u64_field(device, "size"),
+            fstype: string_field(device, "fstype"),
+            fsused: u64_field(device, "fsused"),
+            uuid: string_field(device, "uuid"),
+            label: string_field(device, "label"),
+            partlabel: string_field(device, "partlabel"),
+            pttype: string_field(device, "pttype"),
+            mountpoint,
+            children: device.get("children").and_then(Value::as_array).map(|children| children.iter().map(Self::from_value).collect()).unwrap_or_default(),
+            class: None,
+            managed_partition: None,
+            name,
+            path,
+        }
+    }
+
+    // Stable identifier used for mount points and exclusion lists. Virtual
+    // disks often report no serial, so fall back to the WWN, then the name.
+    pub fn id(&self) -> &str {
+        self.serial.as_deref().or(self.wwn.as_deref()).unwrap_or(&self.name)
+    }
+
+    pub fn is_disk(&self) -> bool {
+        self.device_type == "disk"
+    }
+
+    pub fn block_class(&self) -> &'static str {
+        if let Some(class) = self.class {
+            class
+        } else if !self.rota {
+            if self.tran.as_deref() == Some("nvme") || self.name.contains("nvme") {
+                "nvme"
+            } else {
+                "ssd"
+            }
+        } else {
+            "hdd"
+        }
+    }
+
+    pub fn tier(&self) -> &'static str {
+        match self.block_class() {
+            "nvme" => "hot",
+            "ssd" => "warm",
+            _ => "cold",
+        }
+    }
+
+    // Whether `name` is this device's name, path, partition label or
+    // filesystem label
+    pub fn is_named(&self, name: &str) -> bool {
+        [Some(&self.name), Some(&self.path), self.partlabel.as_ref(), self.label.as_ref()].into_iter().flatten().any(|own| own == name)
+    }
+
+    // The managed partition if one is set, the first partition, or the disk
+    // itself when it was formatted without a partition table
+    fn filesystem_holder(&self) -> Option<&BlockDevice> {
+        if let Some(managed) = &self.managed_partition {
+            return self.children.iter().find(|partition| &partition.path == managed);
+        }
+        match self.children.first() {
+            Some(partition) => Some(partition),
+            None => self.fstype.is_some().then_some(self),
+        }
+    }
+
+    // What holds the drive's filesystem. For an unlocked LUKS container
+    // that is the mapper device opened on it; a locked one is returned as
+    // it is.
+    pub fn filesystem_device(&self) -> Option<&BlockDevice> {
+        let holder = self.filesystem_holder()?;
+        match holder.children.first() {
+            Some(mapper) if holder.is_luks() => Some(mapper),
+            _ => Some(holder),
+        }
+    }
+
+    pub fn is_luks(&self) -> bool {
+        self.fstype.as_deref() == Some(LUKS_FSTYPE)
+    }
+
+    // The LUKS container holding the drive's filesystem, when it is not
+    // open
+    pub fn locked_container(&self) -> Option<&BlockDevice> {
+        self.filesystem_holder().filter(|holder| holder.is_luks() && holder.children.is_empty())
+    }
+
+    // Where the drive's filesystem is mounted, if anywhere
+    pub fn filesystem_mountpoint(&self) -> Option<&str> {
+        self.filesystem_device()?.mountpoint.as_deref()
+    }
+
+    // What formatting the device would destroy: the first filesystem, RAID
+    // or LVM signature on it or its partitions, with how much the
+    // filesystem holds where lsblk knows, else its partition table or
+    // partitions, whatever lsblk makes of them. None for a blank device.
+    pub fn existing_data(&self) -> Option<String> {
+        let Some(fstype) = &self.fstype else {
+            if let Some(data) = self.children.iter().find_map(BlockDevice::existing_data) {
+                return Some(data);
+            }
+            let partitions = match self.children.len() {
+                0 => String::new(),
+                1 => " holding a partition".to_string(),
+                count => format!(" holding {} partitions", count),
+            };
+            return match &self.pttype {
+                Some(pttype) if self.device_type != "part" => Some(format!("a {} partition table{} on {}", pttype, partitions, self.path)),
+                _ if !self.children.is_empty() => Some(format!("a partition table{} on {}", partitions, self.path)),
+                _ => None,
+            };
+        };
+        let what = SIGNATURES.iter().find(|(signature, _)| signature == fstype).map_or_else(|| format!("an existing {} filesystem", fstype), |(_, what)| what.to_string());
+        Some(match self.fsused.filter(|used| *used > 0) {
+            Some(used) => format!("{} on {} with {} bytes used", what, self.path, used),
+            None => format!("{} on {}", what, self.path),
+        })
+    }
+}
+
+// Parse `lsblk --json` output into block devices. Entries are parsed
+// leniently; only output that is not lsblk JSON at all is an error.
+pub fn parse(output: &[u8]) -> io::Result<Vec<BlockDevice>> {
+    let output: Value = serde_json::from_slice(output).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let devices = output.get("blockdevices").and_then(Value::as_array)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "lsblk output has no blockdevices"))?;
+    Ok(devices.iter().map(BlockDevice::from_value).collect())
+}
+
+fn string_field(device: &Value, key: &str) -> Option<String> {
+    match device.get(key)? {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+// Older releases print every column as a string, so "1"/"0" as well as true/false
+fn bool_field(device: &Value, key: &str) -> Option<bool> {
+    match device.get(key)? {
+        Value::Bool(b) => Some(*b),
+        Value::Number(n) => n.as_u64().map(|n| n != 0),
+        Value::String(s) => match s.trim() {
+            "1" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn u64_field(device: &Value, key: &str) -> Option<u64> {
+    match device.get(key)? {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_util_linux_2_39() {
+        let devices = parse(include_bytes!("../fixtures/lsblk/util-linux-2.39.json")).unwrap();
+        let nvme = &devices[0];
+        assert_eq!((nvme.path.as_str(), nvme.id(), nvme.tier()), ("/dev/nvme0n1", "S4EWNX0R123456", "hot"));
+        assert_eq!(nvme.size, Some(1_000_204_886_016));
+        assert_eq!(nvme.children[0].fstype.as_deref(), Some("xfs"));
+        assert_eq!(nvme.filesystem_mountpoint(), Some("/mnt/physical/nvme/S4EWNX0R123456"));
+        assert_eq!(devices[1].block_class(), "hdd");
+        assert_eq!(devices[1].filesystem_mountpoint(), None);
+    }
+
+    #[test]
+    fn test_parse_util_linux_2_32_strings() {
+        // CentOS 8 era: every value is a string and there is no PATH column
+        let devices = parse(include_bytes!("../fixtures/lsblk/util-linux-2.32.json")).unwrap();
+        assert_eq!(devices[0].path, "/dev/sda");
+        assert!(devices[0].rota);
+        assert!(!devices[1].rota);
+        assert_eq!(devices[1].block_class(), "ssd");
+        assert_eq!(devices[1].size, Some(480_103_981_056));
+        assert_eq!(devices[0].children[0].mountpoint.as_deref(), Some("/boot"));
+        assert_eq!(devices[0].children[0].label, None);
+    }
+
+    #[test]
+    fn test_parse_virtual_disks() {
+        // virtio disks report null serial and transport
+        let devices = parse(include_bytes!("../fixtures/lsblk/virtio-null-serial.json")).unwrap();
+        assert_eq!(devices[0].serial, None);
+        assert_eq!(devices[0].id(), "0x5000c500a1b2c3d4");
+        assert_eq!(devices[1].id(), "vdb");
+        assert_eq!(devices[1].tran, None);
+        assert_eq!(devices[1].tier(), "cold");
+        assert!(devices[2].children.is_empty());
+        assert!(!devices[2].is_disk());
+    }
+
+    #[test]
+    fn test_parse_missing_fields() {
+        let devices = parse(br#"{"blockdevices": [{"name": "sdz"}]}"#).unwrap();
+        assert_eq!(devices[0].path, "/dev/sdz");
+        assert_eq!(devices[0].block_class(), "hdd");
+        assert!(parse(b"lsblk: unknown column: ZONE-APP").is_err());
+        assert!(parse(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_whole_disk_filesystem() {
+        let devices = parse(br#"{"blockdevices": [
+            {"name": "sdb", "serial": "WD-1", "fstype": "xfs", "mountpoint": "/mnt/physical/hdd/WD-1"},
+            {"name": "sdc", "serial": "WD-2"}
+        ]}"#).unwrap();
+        assert_eq!(devices[0].filesystem_device().unwrap().path, "/dev/sdb");
+        assert_eq!(devices[0].filesystem_mountpoint(), Some("/mnt/physical/hdd/WD-1"));
+        assert_eq!(devices[1].filesystem_device(), None);
+    }
+
+    #[test]
+    fn test_existing_data() {
+        let devices = parse(br#"{"blockdevices": [
+            {"name": "sdb", "serial": "WD-1"},
+            {"name": "sdc", "serial": "WD-2", "children": [{"name": "sdc1"}, {"name": "sdc2", "fstype": "LVM2_member"}]},
+            {"name": "sdd", "serial": "WD-3", "children": [{"name": "sdd1", "fstype": "ntfs", "fsused": "4096", "mountpoint": "/media/backup"}]},
+            {"name": "sde", "serial": "WD-4", "fstype": "linux_raid_member"},
+            {"name": "sdf", "type": "disk", "serial": "WD-5", "pttype": "gpt", "children": [{"name": "sdf1", "type": "part", "pttype": "gpt"}]},
+            {"name": "sdg", "type": "disk", "serial": "WD-6", "pttype": "dos"},
+            {"name": "sdh", "type": "disk", "serial": "WD-7", "children": [{"name": "sdh1", "type": "part"}, {"name": "sdh2", "type": "part"}]}
+        ]}"#).unwrap();
+        assert_eq!(devices[0].existing_data(), None);
+        assert_eq!(devices[1].existing_data().as_deref(), Some("an LVM physical volume on /dev/sdc2"));
+        assert_eq!(devices[2].existing_data().as_deref(), Some("an existing ntfs filesystem on /dev/sdd1 with 4096 bytes used"));
+        assert_eq!(devices[3].existing_data().as_deref(), Some("an md RAID member on /dev/sde"));
+        // partitions lsblk cannot identify are data all the same
+        assert_eq!(devices[4].existing_data().as_deref(), Some("a gpt partition table holding a partition on /dev/sdf"));
+        assert_eq!(devices[4].children[0].existing_data(), None);
+        assert_eq!(devices[5].existing_data().as_deref(), Some("a dos partition table on /dev/sdg"));
+        assert_eq!(devices[6].existing_data().as_deref(), Some("a partition table holding 2 partitions on /dev/sdh"));
+    }
+
+    #[test]
+    fn test_luks_filesystem() {
+        let devices = parse(br#"{"blockdevices": [
+            {"name": "sdb", "serial": "WD-1", "children": [
+                {"name": "sdb1", "fstype": "crypto_LUKS", "uuid": "c0ffee", "children": [
+                    {"name": "luks-c0ffee", "path": "/dev/mapper/luks-c0ffee", "type": "crypt", "fstype": "xfs", "mountpoint": "/mnt/physical/hdd/WD-1"}
+                ]}
+            ]},
+            {"name": "sdc", "serial": "WD-2", "children": [{"name": "sdc1", "fstype": "crypto_LUKS", "uuid": "beef"}]},
+            {"name": "sdd", "serial": "WD-3", "fstype": "crypto_LUKS", "children": [
+                {"name": "luks-d00d", "path": "/dev/mapper/luks-d00d", "type": "crypt", "fstype": "ext4"}
+            ]}
+        ]}"#).unwrap();
+        assert_eq!(devices[0].filesystem_device().unwrap().path, "/dev/mapper/luks-c0ffee");
+        assert_eq!(devices[0].filesystem_mountpoint(), Some("/mnt/physical/hdd/WD-1"));
+        assert_eq!(devices[0].locked_container(), None);
+        assert_eq!(devices[1].filesystem_device().unwrap().path, "/dev/sdc1");
+        assert_eq!(devices[1].locked_container().unwrap().uuid.as_deref(), Some("beef"));
+        // a whole disk container
+        assert_eq!(devices[2].filesystem_device().unwrap().path, "/dev/mapper/luks-d00d");
+        assert_eq!(devices[2].locked_container(), None);
+    }
+}
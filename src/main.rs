@@ -1,3 +1,1729 @@
+use drive_manager::args::{Args, Command, GenerateTarget, USAGE};
+use drive_manager::bandwidth::Bandwidth;
+use drive_manager::bcachefs::{self, Bcachefs};
+use drive_manager::cgroup::MoverCgroup;
+use drive_manager::checksum::Checksums;
+use drive_manager::config::Config;
+use drive_manager::config_watch::ConfigWatcher;
+use drive_manager::control::{self, ControlCommand, ControlSocket};
+use drive_manager::crawl::InitialCrawl;
+use drive_manager::drive_manager::DriveManager;
+use drive_manager::events::{self, EventLogSettings};
+use drive_manager::executor::{Executor, SystemExecutor};
+use drive_manager::exit_code::{CliError, ErrorKind};
+use drive_manager::external_jobs::{self, RunningJob};
+use drive_manager::file_metadata::{DrainState, Drive, DriveState, LoopRun, MaintenanceState, TransferProgress};
+use drive_manager::fill_strategy::FillPolicy;
+use drive_manager::health::{self, Health};
+use drive_manager::hotplug::HotplugMonitor;
+use drive_manager::lsblk::BlockDevice;
+use drive_manager::media_server::MediaServer;
+use drive_manager::metadata_db::MetadataDb;
+use drive_manager::mover::Mover;
+use drive_manager::pins::{self, Pin};
+use drive_manager::plan::Plan;
+use drive_manager::pool_check::{self, Discrepancy};
+use drive_manager::progress::{Progress, ProgressItem};
+use drive_manager::project_quota::ProjectQuotas;
+use drive_manager::reserve::ReservePolicy;
+use drive_manager::secrets::RedactingLogger;
+use drive_manager::signals::{Signal, Signals};
+use drive_manager::staging::Staging;
+use drive_manager::storage::{self, BranchStorage, LockedFilePolicy, Storage, SymlinkPolicy};
+use drive_manager::tiering_manager::{tier_rank, TieringManager};
+use drive_manager::transcripts::{RecordingExecutor, TranscriptLog, TRANSCRIPT_FILE};
+use drive_manager::write_skew::{self, WriteSkewPolicy};
+use drive_manager::zfs::{self, ZfsReport};
+use drive_manager::check_schedule::CHECK_REQUEST_POLL_SEC;
+use drive_manager::{adopt, config, disk_stats, doctor, generate, notify, reload, scratch, sd_notify, simulation};
+use log::{error, info, warn, LevelFilter};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use std::thread;
+
+fn run(args: Args) {
+    // before any thread starts, so they all leave the signals to us
+    let signals = Signals::block().unwrap_or_else(|e| CliError::from_io(ErrorKind::Failure, "failed to block signals", &e).exit());
+    let config = read_config(&args);
+    // `plan` is one --observe check that prints its moves and leaves
+    let plan = args.command == Command::Plan;
+    let mut drive_manager = DriveManager::with_config(args, config.clone());
+    let db_path = config.db_path.as_str();
+    let transcripts = Arc::new(TranscriptLog::open(transcript_dir(db_path)));
+    let recording = |executor: Arc<dyn Executor>| -> Arc<dyn Executor> { Arc::new(RecordingExecutor::new(executor, transcripts.clone())) };
+    drive_manager.executor = recording(Arc::new(SystemExecutor));
+    if !plan {
+        events::init_from_config(&config.raw);
+        notify::init_from_config(&config.raw, drive_manager.executor.clone());
+    }
+    events::record("started", json!({ "observe": drive_manager.args.observe }));
+    info!("Excluding drives: {:?}", config.exclude_drives);
+    if !config.include_drives.is_empty() {
+        info!("Only managing drives: {:?}", config.include_drives);
+    }
+
+    // Scan drives
+    let block_devices = drive_manager.get_block_devices()
+        .unwrap_or_else(|e| CliError::from_io(ErrorKind::Failure, "failed to discover drives", &e).exit());
+    // drives in a zpool belong to ZFS, whatever the config says
+    let zfs_members = drive_manager.zfs_member_paths();
+    let (pool_members, block_devices): (Vec<BlockDevice>, Vec<BlockDevice>) = block_devices.into_iter()
+        .partition(|device| zfs::is_member(device, &zfs_members));
+    for device in &pool_members {
+        info!("{} {} is a ZFS pool member, leaving it alone", device.path, device.id());
+    }
+    if let Some(report) = ZfsReport::from_config(&config.raw) {
+        spawn_zfs_report(report, recording(Arc::new(SystemExecutor)));
+    }
+    let observe = drive_manager.args.observe;
+    if let Some(bcachefs) = Bcachefs::from_config(&config.raw) {
+        if observe {
+            info!("Observing: bcachefs would be set up on {}", bcachefs.mountpoint);
+        } else {
+            let devices: Vec<BlockDevice> = block_devices.into_iter()
+                .filter(|device| !config.is_excluded(device.id()))
+                .collect();
+            if let Err(e) = drive_manager.setup_bcachefs(&bcachefs, &devices) {
+                CliError::from_io(ErrorKind::Failure, "failed to set up bcachefs", &e).exit();
+            }
+            info!("bcachefs is up on {}", bcachefs.mountpoint);
+        }
+        // bcachefs moves data between tiers itself
+        if plan {
+            println!("Nothing to plan, bcachefs moves data between tiers itself");
+            return;
+        }
+        serve(&signals, || warn!("Nothing to reload, bcachefs moves data between tiers itself"), || {});
+    }
+    let filesystem = config.filesystem.as_deref()
+        .unwrap_or_else(|| CliError::new(ErrorKind::Config, "filesystem is not set in the config").exit());
+    // drives out for maintenance stay out until it is ended
+    let in_maintenance: Vec<String> = MetadataDb::open(db_path).and_then(|db| db.maintenance()).unwrap_or_default().into_iter()
+        .filter(|maintenance| matches!(maintenance.state, MaintenanceState::Requested | MaintenanceState::Active))
+        .map(|maintenance| maintenance.serial)
+        .collect();
+    // the registry keeps each drive's tier and state across runs
+    let registry = MetadataDb::open(db_path).and_then(|db| db.drives()).unwrap_or_default();
+    drive_manager.registered_tiers = registry.iter().map(|drive| (drive.serial.clone(), drive.tier.clone())).collect();
+    let attached: HashSet<String> = block_devices.iter().map(|device| device.id().to_string()).collect();
+    let mut active_drives = Vec::new();
+    let mut failures = Vec::new();
+    for block_device in block_devices {
+        let (path, serial) = (block_device.path.clone(), block_device.id().to_string());
+        if in_maintenance.contains(&serial) {
+            info!("{} {} is out for maintenance, leaving it out", path, serial);
+            continue;
+        }
+        if registry.iter().any(|drive| drive.serial == serial && drive.state == DriveState::Retired) {
+            info!("{} {} was drained and retired, leaving it out; `drive-manager drives reset {}` puts it back to use", path, serial, serial);
+            continue;
+        }
+        let Some(prepared) = prepare_drive(&mut drive_manager, block_device, filesystem) else { continue };
+        match prepared {
+            Ok(device) => active_drives.push(device),
+            Err(e) => {
+                error!("Failed to prepare {} {}: {}", path, serial, e);
+                failures.push(CliError::from_io(ErrorKind::Failure, &format!("failed to prepare {} {}", path, serial), &e));
+            }
+        }
+    }
+    // with some drives up the pools still go up without the rest
+    if active_drives.is_empty() {
+        if let Some(failure) = failures.first() {
+            failure.exit();
+        }
+    }
+    if let Err(e) = MetadataDb::open(db_path).and_then(|db| register_drives(&db, &drive_manager, &active_drives, &attached, &registry)) {
+        error!("Failed to update the drive registry: {}", e);
+    }
+    let discrepancies = check_pools(&drive_manager, &active_drives, &in_maintenance, db_path);
+    if observe {
+        info!("Observing: leaving the mergerfs pools as they are");
+    } else {
+        drive_manager.setup_mergerfs(&active_drives);
+        if !drive_manager.args.dryrun && discrepancies.iter().any(Discrepancy::repairable) {
+            // setting up the pools rewrote the branches of those already mounted
+            let left: Vec<Discrepancy> = check_pools(&drive_manager, &active_drives, &in_maintenance, db_path).into_iter().filter(Discrepancy::repairable).collect();
+            if left.is_empty() {
+                info!("Repaired the branches of the mounted pools");
+            }
+            for discrepancy in left {
+                error!("Failed to repair the pools: {}", discrepancy);
+            }
+        }
+        if let Err(e) = drive_manager.persist_mounts(&active_drives) {
+            error!("Failed to write the mounts down for boot: {}", e);
+        }
+    }
+
+    let branches = DriveManager::branches(&active_drives, &drive_manager.drive_tiers());
+    let dryrun = drive_manager.args.dryrun || observe;
+    let mut storage = match MoverCgroup::from_config(&config.raw).filter(|_| !dryrun) {
+        Some(cgroup) => {
+            let paths: Vec<PathBuf> = branches.iter().map(|branch| branch.path.clone()).collect();
+            match cgroup.setup(Path::new("/sys"), &paths) {
+                Ok(executor) => BranchStorage::with_executor(branches, dryrun, recording(Arc::new(executor))),
+                Err(e) => {
+                    error!("Failed to set up the mover cgroup {}, moving without it: {}", cgroup.path.display(), e);
+                    BranchStorage::with_executor(branches, dryrun, recording(Arc::new(SystemExecutor)))
+                }
+            }
+        }
+        None => BranchStorage::with_executor(branches, dryrun, recording(Arc::new(SystemExecutor))),
+    };
+    storage.set_symlink_policy(SymlinkPolicy::from_config(&config.raw));
+    storage.set_locked_file_policy(LockedFilePolicy::from_config(&config.raw));
+    storage.set_scan_threads(config.scan_threads);
+    storage.set_scratch_dirs(scratch::scratch_dirs(&config.raw));
+    storage.set_reserve(ReservePolicy::from_config(&config.raw));
+    storage.set_fill_policy(FillPolicy::from_config(&config.raw));
+    storage.set_mover(Mover::from_config(&config.raw));
+    storage.set_bandwidth(Bandwidth::from_config(&config.raw));
+    storage.set_checksums(Checksums::from_config(&config.raw));
+    storage.set_btrfs_send(config.btrfs_send);
+    storage.set_project_quotas(ProjectQuotas::from_config(&config.raw));
+    for serial in &in_maintenance {
+        storage.mark_in_maintenance(serial);
+    }
+    // moves keep off drives quarantined in an earlier run
+    for drive in registry.iter().filter(|drive| drive.state == DriveState::Quarantined) {
+        storage.avoid_branch(&drive.serial);
+    }
+    let storage = Arc::new(storage);
+    let db = MetadataDb::open(db_path).unwrap_or_else(|e| CliError::from_io(ErrorKind::Database, &format!("failed to open {}", db_path), &e).exit());
+    if ProjectQuotas::from_config(&config.raw).is_some() {
+        spawn_subtree_accounting(Arc::clone(&storage), db_path.to_string(), ProjectQuotas::report_interval(&config.raw));
+    }
+    let tiering_manager = Arc::new(TieringManager::new(drive_manager.args.clone(), config.raw.clone(), storage.clone(), db));
+    tiering_manager.set_drives(active_drives.iter().map(|drive| (drive.id().to_string(), disk_stats::kernel_name(&drive.path))).collect());
+    if plan {
+        if let Err(e) = tiering_manager.perform_tiering_check().and_then(|()| print_plan(&tiering_manager.plan()?)) {
+            CliError::from_io(ErrorKind::Failure, "failed to plan the tiering check", &e).exit();
+        }
+        return;
+    }
+    if let Some(crawl) = InitialCrawl::from_config(&config.raw).filter(|_| !observe) {
+        // adopted drives attached now whose first crawl isn't done
+        let pending: Vec<String> = adopt::adopted_drives(&config.raw).into_iter()
+            .map(|drive| drive.serial)
+            .filter(|serial| active_drives.iter().any(|drive| drive.id() == serial))
+            .filter(|serial| !matches!(MetadataDb::open(db_path).and_then(|db| db.crawl(serial)), Ok(Some(progress)) if progress.finished_at.is_some()))
+            .collect();
+        if !pending.is_empty() {
+            spawn_initial_crawl(crawl, Arc::clone(&storage), Arc::clone(&tiering_manager), db_path.to_string(), pending);
+        }
+    }
+    tiering_manager.start_background_process();
+    spawn_reload_watch(another_drive_manager(&drive_manager), Arc::clone(&storage), Arc::clone(&tiering_manager), db_path.to_string());
+    if config.watch_config {
+        spawn_config_watch(&drive_manager.args, &config.raw, db_path.to_string());
+    }
+    spawn_control_socket(Arc::clone(&tiering_manager), &control::socket_path(&config.raw), db_path);
+    // for taking the pools down at shutdown, as the hotplug watch takes ours
+    let pools = another_drive_manager(&drive_manager);
+    if !observe {
+        spawn_maintenance_watch(another_drive_manager(&drive_manager), filesystem.to_string(), Arc::clone(&storage), Arc::clone(&tiering_manager), db_path.to_string());
+    }
+    if config.hotplug {
+        spawn_hotplug_watch(drive_manager, filesystem.to_string(), storage, Arc::clone(&tiering_manager), db_path.to_string());
+    }
+    if let Some(media_server) = MediaServer::from_config(&config.raw) {
+        let listen = media_server.listen.clone();
+        if let Err(e) = media_server.spawn(Arc::clone(&tiering_manager)) {
+            error!("Failed to listen for media server webhooks on {}: {}", listen, e);
+        }
+    }
+
+    // SIGHUP goes through the same request as reload-config
+    let reload = || match MetadataDb::open(db_path).and_then(|db| db.request_reload(SystemTime::now(), false)) {
+        Ok(()) => info!("Reloading the config for SIGHUP"),
+        Err(e) => error!("Failed to ask for a config reload: {}", e),
+    };
+    let unmount_pools = !observe && config.unmount_on_shutdown;
+    serve(&signals, reload, || {
+        info!("Shutting down once copying moves finish");
+        events::record("stopping", json!({}));
+        match tiering_manager.shut_down() {
+            Ok(0) => {}
+            Ok(left) => warn!("{} moves were cut off and resume at the next start", left),
+            Err(e) => error!("Failed to save state on shutdown: {}", e),
+        }
+        // alerts still gathering go out now rather than not at all
+        notify::flush(true);
+        if unmount_pools {
+            pools.unmount_pools();
+        }
+    });
+}
+
+// Get a drive ready to be a branch: used where it is if adopted, otherwise
+// mounted, or formatted when it does not hold the configured filesystem.
+// None when it is to be left out.
+fn prepare_drive(drive_manager: &mut DriveManager, block_device: BlockDevice, filesystem: &str) -> Option<io::Result<BlockDevice>> {
+    let adopted = adopt::adopted_drives(&drive_manager.config.raw);
+    let observe = drive_manager.args.observe;
+    // Check if drive holds the configured filesystem, in its one partition,
+    // the partition we manage among others or on the whole disk
+    let serial = block_device.id().to_string();
+    let path = block_device.path.clone();
+    let block_class = block_device.block_class();
+    let adopted_drive = adopted.iter().find(|drive| drive.serial == serial);
+    if drive_manager.config.is_excluded(&serial) {
+        info!("{} {} to be excluded", path, serial);
+        return None;
+    }
+    // a locked LUKS drive is opened before its filesystem is looked at, and
+    // one that cannot be is reported rather than formatted
+    let block_device = match block_device.locked_container() {
+        Some(_) if observe => {
+            info!("{} {} is encrypted and locked, a run would unlock it", path, serial);
+            return None;
+        }
+        Some(_) if adopted_drive.is_none() => match drive_manager.unlock(&block_device) {
+            Ok(unlocked) => unlocked,
+            Err(e) => return Some(Err(e)),
+        },
+        _ => block_device,
+    };
+    let formatted = (block_device.children.len() <= 1 || block_device.managed_partition.is_some()) && block_device.filesystem_device().is_some_and(|device| device.fstype.as_deref() == Some(filesystem));
+    let prepared = if let Some(drive) = adopted_drive {
+        match block_device.filesystem_mountpoint() {
+            Some(mountpoint) => {
+                info!("{} {} is adopted, using it at {} in {}", path, serial, mountpoint, drive.tier);
+                Ok(block_device)
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("adopted drive is not mounted at {}", drive.mountpoint.display()))),
+        }
+    } else if observe {
+        // tier what is mounted already, wherever it is
+        match (block_device.filesystem_mountpoint(), formatted) {
+            (Some(mountpoint), true) => info!("{} {} is mounted at {} as {}", path, serial, mountpoint, block_class),
+            (Some(mountpoint), false) => warn!("{} {} is mounted at {} but a run would format it as {}", path, serial, mountpoint, block_class),
+            (None, true) => info!("{} {} would be mounted as {}", path, serial, block_class),
+            (None, false) => info!("{} {} would be formatted as {}", path, serial, block_class),
+        }
+        // an unmounted drive is left out
+        block_device.filesystem_mountpoint()?;
+        Ok(block_device)
+    } else if formatted {
+        info!("{} {} to be mounted as {}", path, serial, block_class);
+        drive_manager.mount_drive(&block_device)
+    } else {
+        info!("{} {} to be formatted as {}", path, serial, block_class);
+        drive_manager.format_drive(&block_device)
+    };
+    Some(prepared)
+}
+
+// Bring drives attached while running into the pools the way a start
+// would, formats counting against the same max_formats_per_run
+fn spawn_hotplug_watch(mut drive_manager: DriveManager, filesystem: String, storage: Arc<BranchStorage>, tiering_manager: Arc<TieringManager>, db_path: String) {
+    thread::spawn(move || {
+        let mut monitor = match HotplugMonitor::new() {
+            Ok(monitor) => monitor,
+            Err(e) => return error!("Failed to listen for attached drives: {}", e),
+        };
+        loop {
+            let path = match monitor.next_disk() {
+                Ok(path) => path,
+                Err(e) => return error!("Stopped listening for attached drives: {}", e),
+            };
+            let device = match drive_manager.block_device(&path) {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!("Failed to read attached drive {}: {}", path, e);
+                    continue;
+                }
+            };
+            let serial = device.id().to_string();
+            if storage.in_maintenance(&serial) {
+                info!("{} {} was attached but is out for maintenance, leaving it alone", path, serial);
+                continue;
+            }
+            if let Some(branch) = storage.branches().into_iter().find(|branch| branch.serial == serial) {
+                // back after going offline
+                if storage.offline_branches().contains(&serial) {
+                    info!("{} {} is back, mounting it at {}", path, serial, branch.path.display());
+                    if drive_manager.args.observe {
+                        continue;
+                    }
+                    if let Err(e) = drive_manager.remount_branch(&device, &branch.path, &filesystem).and_then(|()| storage.resume_branch(&serial)) {
+                        error!("Failed to mount returning drive {} {}: {}", path, serial, e);
+                    }
+                }
+                continue;
+            }
+            if zfs::is_member(&device, &drive_manager.zfs_member_paths()) {
+                info!("{} {} was attached but is a ZFS pool member, leaving it alone", path, serial);
+                continue;
+            }
+            let db = match MetadataDb::open(&db_path) {
+                Ok(db) => db,
+                Err(e) => {
+                    error!("Failed to open {} to look up attached drive {} {}: {}", db_path, path, serial, e);
+                    continue;
+                }
+            };
+            if db.drive(&serial).ok().flatten().is_some_and(|drive| drive.state == DriveState::Retired) {
+                info!("{} {} was attached but is retired, leaving it alone", path, serial);
+                continue;
+            }
+            info!("{} {} was attached", path, serial);
+            let Some(prepared) = prepare_drive(&mut drive_manager, device, &filesystem) else { continue };
+            if drive_manager.args.observe {
+                info!("Observing: {} {} would join the pools", path, serial);
+                continue;
+            }
+            let added = prepared.and_then(|device| drive_manager.add_to_pools(&device).map(|()| device));
+            match added {
+                Ok(device) => {
+                    if let Err(e) = register_drives(&db, &drive_manager, std::slice::from_ref(&device), &HashSet::new(), &[]) {
+                        error!("Failed to register {} {}: {}", path, serial, e);
+                    }
+                    for branch in DriveManager::branches(std::slice::from_ref(&device), &drive_manager.drive_tiers()) {
+                        storage.add_branch(branch);
+                    }
+                    tiering_manager.add_drive(serial, disk_stats::kernel_name(&device.path));
+                    if let Err(e) = tiering_manager.seed_new_branches() {
+                        error!("Failed to note the new drive: {}", e);
+                    }
+                    // the pooled drives, read again as the new one is among them now
+                    let pooled: HashSet<String> = storage.branches().into_iter().map(|branch| branch.serial).collect();
+                    let persisted = drive_manager.get_block_devices()
+                        .and_then(|devices| drive_manager.persist_mounts(&devices.into_iter().filter(|device| pooled.contains(device.id())).collect::<Vec<_>>()));
+                    if let Err(e) = persisted {
+                        error!("Failed to write the mounts down for boot: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to add attached drive {} {}: {}", path, serial, e),
+            }
+        }
+    });
+}
+
+// Record the drives set up in the registry with the tiers they were given,
+// and mark the registered drives that are not attached missing
+fn register_drives(db: &MetadataDb, drive_manager: &DriveManager, active_drives: &[BlockDevice], attached: &HashSet<String>, registry: &[Drive]) -> io::Result<()> {
+    let now = SystemTime::now();
+    let tiers = drive_manager.drive_tiers();
+    db.transaction(|db| {
+        for device in active_drives {
+            let tier = DriveManager::device_tier(device, &tiers);
+            // adopted into another tier since it was registered
+            if db.register_drive(device.id(), tier, now)?.tier != tier {
+                db.set_drive_tier(device.id(), tier)?;
+            }
+        }
+        for drive in registry.iter().filter(|drive| !attached.contains(&drive.serial)) {
+            if db.set_drive_state(&drive.serial, &[DriveState::Active], DriveState::Missing)? {
+                warn!("{} is in the registry but not attached", drive.serial);
+            }
+        }
+        Ok(())
+    })
+}
+
+// Compare the pools mounted at start, by fstab, their units or an earlier
+// run, with the drives the registry has active, and report each way they
+// differ. Drives excluded or out for maintenance are left out on purpose.
+// Returns what was found, for setting up the pools to repair.
+fn check_pools(drive_manager: &DriveManager, active_drives: &[BlockDevice], in_maintenance: &[String], db_path: &str) -> Vec<Discrepancy> {
+    let registry = match MetadataDb::open(db_path).and_then(|db| db.drives()) {
+        Ok(registry) => registry.into_iter().filter(|drive| !drive_manager.config.is_excluded(&drive.serial) && !in_maintenance.contains(&drive.serial)).collect::<Vec<_>>(),
+        Err(e) => {
+            error!("Failed to read the drive registry to check the pools against: {}", e);
+            return Vec::new();
+        }
+    };
+    let discrepancies = pool_check::check(&drive_manager.pool_branches(), &drive_manager.expected_branches(active_drives), &registry);
+    for discrepancy in &discrepancies {
+        warn!("{}", discrepancy);
+        events::record("pool_discrepancy", discrepancy.details());
+    }
+    discrepancies
+}
+
+fn another_drive_manager(drive_manager: &DriveManager) -> DriveManager {
+    let mut another = DriveManager::with_config(drive_manager.args.clone(), drive_manager.config.clone());
+    another.registered_tiers = drive_manager.registered_tiers.clone();
+    another.executor = Arc::clone(&drive_manager.executor);
+    another
+}
+
+// Take drives out for `drive-manager maintenance` and bring them back. A
+// drive goes out once no copy is reading from it. One back in the pools is
+// put into use straight away; it is only ever mounted, never formatted.
+fn spawn_maintenance_watch(mut drive_manager: DriveManager, filesystem: String, storage: Arc<BranchStorage>, tiering_manager: Arc<TieringManager>, db_path: String) {
+    thread::spawn(move || {
+        let db = match MetadataDb::open(&db_path) {
+            Ok(db) => db,
+            Err(e) => return error!("Failed to open {} to watch for maintenance requests: {}", db_path, e),
+        };
+        loop {
+            thread::sleep(Duration::from_secs(CHECK_REQUEST_POLL_SEC));
+            let requests = match db.maintenance() {
+                Ok(requests) => requests,
+                Err(e) => {
+                    warn!("Failed to read maintenance requests: {}", e);
+                    continue;
+                }
+            };
+            for request in requests {
+                let result = match request.state {
+                    MaintenanceState::Requested => start_maintenance(&storage, &tiering_manager, &request.serial),
+                    MaintenanceState::Ending => end_maintenance(&mut drive_manager, &filesystem, &storage, &tiering_manager, &request.serial),
+                    MaintenanceState::Active | MaintenanceState::Failed => continue,
+                };
+                let recorded = match result {
+                    Ok(None) => Ok(()),
+                    Ok(Some(MaintenanceState::Active)) => {
+                        info!("{} is out of the pools and unmounted for maintenance", request.serial);
+                        events::record("maintenance_started", json!({ "serial": request.serial }));
+                        db.update_maintenance(&request.serial, MaintenanceState::Active, None)
+                    }
+                    Ok(Some(_)) => {
+                        info!("{} is back in the pools after maintenance", request.serial);
+                        events::record("maintenance_ended", json!({ "serial": request.serial }));
+                        db.end_maintenance(&request.serial)
+                    }
+                    Err(e) => {
+                        error!("Maintenance of {} failed: {}", request.serial, e);
+                        events::record("maintenance_failed", json!({ "serial": request.serial, "error": e.to_string() }));
+                        db.update_maintenance(&request.serial, MaintenanceState::Failed, Some(&e.to_string()))
+                    }
+                };
+                if let Err(e) = recorded {
+                    error!("Failed to record maintenance of {}: {}", request.serial, e);
+                }
+            }
+        }
+    });
+}
+
+// The state the drive reached, or None while copies still read from it
+fn start_maintenance(storage: &BranchStorage, tiering_manager: &TieringManager, serial: &str) -> io::Result<Option<MaintenanceState>> {
+    // left out at startup
+    if !storage.branches().iter().any(|branch| branch.serial == serial) {
+        return Ok(Some(MaintenanceState::Active));
+    }
+    storage.avoid_branch(serial);
+    if tiering_manager.copying_from(serial)? {
+        return Ok(None);
+    }
+    storage.suspend_branch(serial)?;
+    Ok(Some(MaintenanceState::Active))
+}
+
+fn end_maintenance(drive_manager: &mut DriveManager, filesystem: &str, storage: &BranchStorage, tiering_manager: &TieringManager, serial: &str) -> io::Result<Option<MaintenanceState>> {
+    let branch = storage.branches().into_iter().find(|branch| branch.serial == serial);
+    // never taken out, as going out failed
+    if branch.is_some() && !storage.offline_branches().contains(serial) {
+        storage.resume_branch(serial)?;
+        return Ok(Some(MaintenanceState::Ending));
+    }
+    let device = drive_manager.get_block_devices()?.into_iter().find(|device| device.id() == serial)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not attached", serial)))?;
+    match branch {
+        Some(branch) => {
+            drive_manager.remount_branch(&device, &branch.path, filesystem)?;
+            drive_manager.add_to_pools(&drive_manager.block_device(&device.path)?)?;
+            storage.resume_branch(serial)?;
+        }
+        // out since before this run started
+        None => {
+            let device = drive_manager.mount_drive(&device)?;
+            drive_manager.add_to_pools(&device)?;
+            for branch in DriveManager::branches(std::slice::from_ref(&device), &drive_manager.drive_tiers()) {
+                storage.add_branch(branch);
+            }
+            tiering_manager.add_drive(serial.to_string(), disk_stats::kernel_name(&device.path));
+            storage.resume_branch(serial)?;
+        }
+    }
+    Ok(Some(MaintenanceState::Ending))
+}
+
+// The config as written, secrets left as references, for recording what the
+// service runs with and comparing against
+fn config_as_written(args: &Args) -> io::Result<Value> {
+    config::load(Path::new(&args.config), args.profile.as_deref(), false)
+}
+
+// Record the config this run applies, then wait for `reload-config`. A
+// reload checks the config again. Changes the service can take as it runs
+// are applied in place; any other lets the copying moves finish and
+// replaces the process with a fresh run of the same command line.
+fn spawn_reload_watch(pools: DriveManager, storage: Arc<BranchStorage>, tiering_manager: Arc<TieringManager>, db_path: String) {
+    let args = pools.args.clone();
+    thread::spawn(move || {
+        let db = match MetadataDb::open(&db_path) {
+            Ok(db) => db,
+            Err(e) => return error!("Failed to open {} to watch for config reloads: {}", db_path, e),
+        };
+        match config_as_written(&args) {
+            Ok(config) => {
+                if let Err(e) = db.record_applied_config(&config, SystemTime::now()) {
+                    error!("Failed to record the applied config: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to read {} back: {}", args.config, e),
+        }
+        loop {
+            thread::sleep(Duration::from_secs(CHECK_REQUEST_POLL_SEC));
+            match db.take_reload_request() {
+                Ok(Some(force)) => apply_reload(&pools, &storage, &tiering_manager, &db, force),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to read config reload requests: {}", e),
+            }
+        }
+    });
+}
+
+fn apply_reload(pools: &DriveManager, storage: &BranchStorage, tiering_manager: &TieringManager, db: &MetadataDb, force: bool) {
+    let args = &pools.args;
+    let (config, resolved) = match config_as_written(args).and_then(|config| Ok((config, DriveManager::read_config(args)?))) {
+        Ok(configs) => configs,
+        Err(e) => return error!("Not reloading, the config could not be read: {}", e),
+    };
+    let problems = reload::validate(&resolved.raw);
+    if !problems.is_empty() {
+        return error!("Not reloading, the config has problems: {}", problems.join("; "));
+    }
+    let applied = db.applied_config().ok().flatten().unwrap_or_default();
+    let changes = reload::diff(&applied, &config);
+    if changes.is_empty() {
+        return info!("Config reload asked for, but nothing changed");
+    }
+    let destructive = reload::destructive(&applied, &config);
+    if !destructive.is_empty() && !force {
+        return error!("Not reloading without --force: {}", destructive.join("; "));
+    }
+    for change in &changes {
+        info!("Config change: {}", change);
+    }
+    let restart = reload::needs_restart(&changes);
+    events::record("config_reloaded", json!({ "changes": changes.iter().map(ToString::to_string).collect::<Vec<_>>(), "restart": restart }));
+    if restart.is_empty() {
+        return apply_live(pools, storage, tiering_manager, db, &applied, &config, resolved.raw);
+    }
+    info!("Restarting for {} once copying moves finish", restart.join(", "));
+    let _ = sd_notify::notify("RELOADING=1");
+    tiering_manager.drain_for_reload();
+    let e = match env::current_exe() {
+        Ok(exe) => process::Command::new(exe).args(env::args_os().skip(1)).exec(),
+        Err(e) => e,
+    };
+    error!("Failed to restart with the new config: {}", e);
+    tiering_manager.cancel_reload();
+    let _ = sd_notify::notify("READY=1");
+}
+
+// Apply a reload that needs no restart where the service stands: newly
+// excluded drives leave the pools once no copy reads from them, changed
+// mergerfs options are set on the mounted pools and the tiering manager
+// takes the rest as it next reads them
+fn apply_live(pools: &DriveManager, storage: &BranchStorage, tiering_manager: &TieringManager, db: &MetadataDb, applied: &Value, config: &Value, resolved: Value) {
+    let observe = pools.args.observe;
+    for serial in reload::newly_excluded(applied, config) {
+        if !storage.branches().iter().any(|branch| branch.serial == serial) || storage.in_maintenance(&serial) {
+            continue;
+        }
+        if observe {
+            info!("Observing: {} would leave the pools", serial);
+            continue;
+        }
+        storage.avoid_branch(&serial);
+        let excluded = loop {
+            match tiering_manager.copying_from(&serial) {
+                Ok(true) => thread::sleep(Duration::from_secs(1)),
+                Ok(false) => break storage.exclude_branch(&serial),
+                Err(e) => break Err(e),
+            }
+        };
+        if let Err(e) = excluded {
+            error!("Failed to take newly excluded {} out of the pools: {}", serial, e);
+        }
+    }
+    let before = DriveManager::configured_mergerfs_options(applied);
+    let options: Vec<(String, String)> = DriveManager::configured_mergerfs_options(&resolved).into_iter().filter(|option| !before.contains(option)).collect();
+    if !options.is_empty() && !observe {
+        if let Err(e) = pools.set_pool_options(&options) {
+            error!("Failed to set mergerfs options on the pools: {}", e);
+        }
+    }
+    tiering_manager.update_config(resolved);
+    if let Err(e) = db.record_applied_config(config, SystemTime::now()) {
+        error!("Failed to record the applied config: {}", e);
+    }
+    info!("Applied the config changes without restarting");
+}
+
+// Reload when the config file or a fragment beside it changes, through the
+// same request as reload-config. The reload watch takes requests once per
+// poll, so the several writes of one save make one reload.
+fn spawn_config_watch(args: &Args, config: &Value, db_path: String) {
+    let path = PathBuf::from(&args.config);
+    let include_dir = config::include_dir(&path, config);
+    let mut watcher = match ConfigWatcher::new(&path, &include_dir) {
+        Ok(watcher) => watcher,
+        Err(e) => return error!("Failed to watch {} for changes: {}", path.display(), e),
+    };
+    thread::spawn(move || {
+        let db = match MetadataDb::open(&db_path) {
+            Ok(db) => db,
+            Err(e) => return error!("Failed to open {} to reload on config changes: {}", db_path, e),
+        };
+        loop {
+            if let Err(e) = watcher.wait() {
+                return error!("Stopped watching {} for changes: {}", path.display(), e);
+            }
+            info!("{} changed, reloading", path.display());
+            if let Err(e) = db.request_reload(SystemTime::now(), false) {
+                error!("Failed to ask for a config reload: {}", e);
+            }
+        }
+    });
+}
+
+// Answer `drive-manager ctl`. A pause lasts until resumed or the service
+// restarts; checks and reloads go through the same requests as check-now
+// and reload-config, so the service picks them up within a poll.
+fn spawn_control_socket(tiering_manager: Arc<TieringManager>, path: &Path, db_path: &str) {
+    let db = match MetadataDb::open(db_path) {
+        Ok(db) => db,
+        Err(e) => return error!("Failed to open {} for the control socket: {}", db_path, e),
+    };
+    let socket = match ControlSocket::bind(path) {
+        Ok(socket) => socket,
+        Err(e) => return error!("Failed to listen on {}, ctl will not reach this run: {}", path.display(), e),
+    };
+    info!("Listening for ctl commands on {}", socket.path().display());
+    socket.spawn(move |command| {
+        info!("ctl {}", command.as_str());
+        match command {
+            ControlCommand::Pause => Ok(match tiering_manager.pause() {
+                true => format!("moves paused; {} still copying will finish", tiering_manager.transfers().len()),
+                false => "moves were already paused".to_string(),
+            }),
+            ControlCommand::Resume => Ok(match tiering_manager.resume() {
+                true => "moves resumed".to_string(),
+                false => "moves were not paused".to_string(),
+            }),
+            ControlCommand::RunTiering => {
+                db.request_check(SystemTime::now())?;
+                Ok(match tiering_manager.is_paused() {
+                    true => "tiering check requested; its moves wait for ctl resume".to_string(),
+                    false => "tiering check requested".to_string(),
+                })
+            }
+            ControlCommand::Reload => {
+                db.request_reload(SystemTime::now(), false)?;
+                Ok("reload requested; the service checks the config and applies it, restarting once copying moves finish if it has to".to_string())
+            }
+        }
+    });
+}
+
+// Check the config as fully as a reload will, show how it differs from
+// what the service runs with, and ask the service to apply it
+fn request_reload(args: &Args) -> Result<(), CliError> {
+    let resolved = DriveManager::read_config(args)
+        .map_err(|e| CliError::from_io(ErrorKind::Config, &format!("failed to read config {}", args.config), &e))?;
+    let problems = reload::validate(&resolved.raw);
+    if !problems.is_empty() {
+        return Err(CliError::new(ErrorKind::Config, format!("the config has problems: {}", problems.join("; "))));
+    }
+    let config = config_as_written(args).map_err(|e| CliError::from_io(ErrorKind::Config, &format!("failed to read config {}", args.config), &e))?;
+    let db = open_db(args);
+    let applied = db.applied_config()
+        .map_err(|e| CliError::from_io(ErrorKind::Database, "failed to read the applied config", &e))?
+        .ok_or_else(|| CliError::new(ErrorKind::Failure, "the service has not recorded the config it runs with; is it running?"))?;
+    let changes = reload::diff(&applied, &config);
+    if changes.is_empty() {
+        println!("Nothing changed");
+        return Ok(());
+    }
+    for change in &changes {
+        println!("{}", change);
+    }
+    let destructive = reload::destructive(&applied, &config);
+    if !destructive.is_empty() && !args.force {
+        return Err(CliError::new(ErrorKind::Config, format!("refusing to reload: {}; rerun with --force to apply anyway", destructive.join("; "))));
+    }
+    db.request_reload(SystemTime::now(), args.force).map_err(|e| CliError::from_io(ErrorKind::Database, "failed to request a reload", &e))?;
+    match reload::needs_restart(&changes).as_slice() {
+        [] => println!("Asked the running service to reload; it applies these changes without restarting"),
+        restart => println!("Asked the running service to reload; it restarts for {} once copying moves finish", restart.join(", ")),
+    }
+    Ok(())
+}
+
+// Keep the quota subtrees' projects set, and record what each takes on
+// each branch for `drive-manager status`
+fn spawn_subtree_accounting(storage: Arc<BranchStorage>, db_path: String, interval: Duration) {
+    thread::spawn(move || {
+        let db = match MetadataDb::open(&db_path) {
+            Ok(db) => db,
+            Err(e) => return error!("Failed to open {} for subtree accounting: {}", db_path, e),
+        };
+        loop {
+            storage.assign_projects();
+            match storage.subtree_usage() {
+                Ok(usage) => {
+                    if let Err(e) = db.record_subtree_usage(&usage, SystemTime::now()) {
+                        error!("Failed to record subtree usage: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to account subtrees: {}", e),
+            }
+            thread::sleep(interval);
+        }
+    });
+}
+
+// Crawl each of `serials` once, apart from the scans, which leave them out
+// until their crawl is done. A pause leaves off between files.
+fn spawn_initial_crawl(crawl: InitialCrawl, storage: Arc<BranchStorage>, tiering_manager: Arc<TieringManager>, db_path: String, serials: Vec<String>) {
+    for serial in &serials {
+        storage.set_crawling(serial, true);
+    }
+    thread::spawn(move || {
+        let db = match MetadataDb::open(&db_path) {
+            Ok(db) => db,
+            Err(e) => return error!("Failed to open {} for the first crawl: {}", db_path, e),
+        };
+        for serial in serials {
+            info!("Starting the first crawl of {}", serial);
+            loop {
+                if tiering_manager.is_stopping() {
+                    return;
+                }
+                if tiering_manager.is_paused() {
+                    thread::sleep(Duration::from_secs(CHECK_REQUEST_POLL_SEC));
+                    continue;
+                }
+                let record = |files| tiering_manager.record_crawled(files);
+                let stop = || tiering_manager.is_stopping() || tiering_manager.is_paused();
+                match crawl.run(&storage, &db, &serial, &record, &stop) {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("Failed the first crawl of {}, scanning it as usual: {}", serial, e);
+                        break;
+                    }
+                }
+            }
+            storage.set_crawling(&serial, false);
+        }
+    });
+}
+
+// Log the ZFS datasets' usage and the pools' I/O now and then, warning
+// about datasets past max_used_percent
+fn spawn_zfs_report(report: ZfsReport, executor: Arc<dyn Executor>) {
+    thread::spawn(move || loop {
+        match report.datasets(executor.as_ref()) {
+            Ok(datasets) => {
+                for dataset in &datasets {
+                    info!("ZFS {}  {} used  {} free ({:.0}%)", dataset.name, format_bytes(dataset.used as f64), format_bytes(dataset.available as f64), dataset.used_percent());
+                }
+                for dataset in report.over_limit(&datasets) {
+                    warn!("ZFS {} is {:.0}% full, past max_used_percent", dataset.name, dataset.used_percent());
+                }
+            }
+            Err(e) => warn!("Failed to list ZFS datasets: {}", e),
+        }
+        match report.pool_io(executor.as_ref()) {
+            Ok(pools) => {
+                for pool in &pools {
+                    info!("zpool {}  {} read ops  {} write ops  read {}/s  write {}/s", pool.name, pool.read_ops, pool.write_ops, format_bytes(pool.read_bytes as f64), format_bytes(pool.write_bytes as f64));
+                }
+            }
+            Err(e) => warn!("Failed to read zpool iostat: {}", e),
+        }
+        thread::sleep(report.interval);
+    });
+}
+
+// Tell systemd we are up and keep its watchdog fed, then handle signals
+// until one says to stop
+fn serve(signals: &Signals, reload: impl Fn(), shut_down: impl FnOnce()) -> ! {
+    if let Err(e) = sd_notify::notify("READY=1") {
+        warn!("Failed to notify systemd: {}", e);
+    }
+    let interval = sd_notify::watchdog_interval().unwrap_or(Duration::from_secs(3600));
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let _ = sd_notify::notify("WATCHDOG=1");
+    });
+    loop {
+        match signals.wait() {
+            Ok(Signal::Reload) => reload(),
+            Ok(Signal::Shutdown) => break,
+            Err(e) => error!("Failed to wait for signals: {}", e),
+        }
+    }
+    let _ = sd_notify::notify("STOPPING=1");
+    shut_down();
+    info!("Shut down");
+    process::exit(0)
+}
+
+// Transcripts of the commands the service ran are kept beside the database
+fn transcript_dir(db_path: &str) -> &Path {
+    Path::new(db_path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."))
+}
+
+fn read_config(args: &Args) -> Config {
+    DriveManager::read_valid_config(args).unwrap_or_else(|e| CliError::from_io(ErrorKind::Config, &format!("failed to read config {}", args.config), &e).exit())
+}
+
+fn open_db(args: &Args) -> MetadataDb {
+    // the database location is all these commands need from the config
+    let db_path = DriveManager::read_config(args).unwrap_or_default().db_path;
+    MetadataDb::open(&db_path).unwrap_or_else(|e| CliError::from_io(ErrorKind::Database, &format!("failed to open {}", db_path), &e).exit())
+}
+
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs if secs < 3600 => format!("{}m ago", secs / 60),
+        secs if secs < 86400 => format!("{}h ago", secs / 3600),
+        secs => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn format_eta(eta: Duration) -> String {
+    match eta.as_secs() {
+        secs if secs < 60 => format!("{}s", secs),
+        secs if secs < 3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        secs => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn transfer_item(transfer: &TransferProgress) -> ProgressItem {
+    let eta = transfer.eta().map_or("unknown".to_string(), format_eta);
+    ProgressItem {
+        label: transfer.info.src.display().to_string(),
+        done: transfer.bytes_copied,
+        total: transfer.bytes_total,
+        detail: format!(
+            "{} -> {}  {} / {}  {}/s  ETA {}",
+            transfer.info.source_tier, transfer.info.target_tier,
+            format_bytes(transfer.bytes_copied as f64), format_bytes(transfer.bytes_total as f64), format_bytes(transfer.rate()), eta,
+        ),
+    }
+}
+
+// When a background loop last ran, how it went and when it runs next. One
+// running well past its interval, or overdue, is stuck.
+fn loop_line(run: &LoopRun, now: SystemTime) -> String {
+    let since_start = now.duration_since(run.started_at).unwrap_or_default();
+    let mut line = if run.running {
+        format!("{}  running for {}", run.name, format_eta(since_start))
+    } else {
+        format!("{}  ran {}  took {}", run.name, format_age(since_start), format_eta(run.duration))
+    };
+    if let Some(error) = &run.error {
+        line += &format!("  last error: {}", error);
+    }
+    match run.next_run.map(|next| next.duration_since(now)) {
+        Some(Ok(left)) => line += &format!("  next in {}", format_eta(left)),
+        Some(Err(late)) if !run.running => line += &format!("  overdue by {}", format_eta(late.duration())),
+        _ => {}
+    }
+    line
+}
+
+// Every line names its tier so the output of several tiers can be told
+// apart, and `tier` keeps to one
+fn print_status(db: &MetadataDb, tier: Option<&str>) -> io::Result<()> {
+    let shown = |t: &str| tier.is_none_or(|tier| tier == t);
+    let mut totals = db.tier_totals()?;
+    totals.sort_by_key(|(tier, _, _)| tier_rank(tier));
+    for (tier, files, bytes) in totals.iter().filter(|(tier, _, _)| shown(tier)) {
+        println!("{}  {} files  {} tracked", tier, files, format_bytes(*bytes as f64));
+    }
+    let branch_tiers = db.branch_tiers()?;
+    let tier_of = |serial: &str| branch_tiers.get(serial).map_or("unknown", String::as_str);
+    for (serial, bytes) in db.branch_bytes()?.into_iter().filter(|(serial, _)| shown(tier_of(serial))) {
+        println!("{} {}  {} tracked", tier_of(&serial), serial, format_bytes(bytes as f64));
+    }
+    for (serial, sampled_at, io) in db.latest_disk_io()?.into_iter().filter(|(serial, _, _)| shown(tier_of(serial))) {
+        let age = SystemTime::now().duration_since(sampled_at).unwrap_or_default();
+        println!(
+            "{} {}  {:.0}% busy  {:.0} IOPS  read {:.1}ms  write {:.1}ms  sampled {}",
+            tier_of(&serial), serial, io.util * 100.0, io.iops, io.read_latency_ms, io.write_latency_ms, format_age(age),
+        );
+    }
+    // whether the hot tier takes the reads the policy means it to
+    let mut tier_io = disk_stats::tier_io(&branch_tiers, &db.disk_io_since(SystemTime::now() - Duration::from_secs(3600))?);
+    tier_io.sort_by_key(|io| tier_rank(&io.tier));
+    for io in tier_io.iter().filter(|io| shown(&io.tier)) {
+        println!(
+            "{}  last hour: read {}/s ({:.0}% of reads)  write {}/s  {:.0} IOPS ({:.0}%)  busiest drive {:.0}% busy  {} samples",
+            io.tier, format_bytes(io.read_bps), io.read_share(&tier_io) * 100.0, format_bytes(io.write_bps),
+            io.iops, io.iops_share(&tier_io) * 100.0, io.busiest * 100.0, io.samples,
+        );
+    }
+    for (usage, sampled_at) in db.subtree_usage()?.into_iter().filter(|(usage, _)| shown(&usage.tier)) {
+        let age = SystemTime::now().duration_since(sampled_at).unwrap_or_default();
+        println!("{}  {} on {} ({})  sampled {}", usage.subtree.display(), format_bytes(usage.bytes as f64), usage.serial, usage.tier, format_age(age));
+    }
+    for suspect in db.suspect_drives()?.into_iter().filter(|suspect| shown(tier_of(&suspect.serial))) {
+        let age = SystemTime::now().duration_since(suspect.last_at).unwrap_or_default();
+        println!("{} {}  suspect, {} I/O errors, last {}: {}", tier_of(&suspect.serial), suspect.serial, suspect.errors, format_age(age), suspect.last_error);
+    }
+    for maintenance in db.maintenance()?.into_iter().filter(|maintenance| shown(tier_of(&maintenance.serial))) {
+        let state = match (maintenance.state, &maintenance.error) {
+            (MaintenanceState::Requested, _) => "going out for maintenance".to_string(),
+            (MaintenanceState::Active, _) => "out for maintenance".to_string(),
+            (MaintenanceState::Ending, _) => "coming back from maintenance".to_string(),
+            (MaintenanceState::Failed, error) => format!("maintenance failed: {}", error.as_deref().unwrap_or("unknown error")),
+        };
+        let age = SystemTime::now().duration_since(maintenance.requested_at).unwrap_or_default();
+        println!("{} {}  {}  requested {}", tier_of(&maintenance.serial), maintenance.serial, state, format_age(age));
+    }
+    for (serial, since) in db.offline_branches()?.into_iter().filter(|(serial, _)| shown(tier_of(serial))) {
+        let age = SystemTime::now().duration_since(since).unwrap_or_default();
+        println!("{} {}  offline, went missing {}; its files are unavailable", tier_of(&serial), serial, format_age(age));
+    }
+    for drain in db.drains()?.into_iter().filter(|drain| shown(tier_of(&drain.serial))) {
+        let state = match (drain.state, &drain.error) {
+            (DrainState::Requested, _) => "drain requested".to_string(),
+            (DrainState::Moving, _) => format!("draining, {} files left", drain.files_left),
+            (DrainState::Done, _) => "drained and unmounted".to_string(),
+            (DrainState::Failed, error) => format!("drain failed: {}", error.as_deref().unwrap_or("unknown error")),
+        };
+        let age = SystemTime::now().duration_since(drain.requested_at).unwrap_or_default();
+        println!("{} {}  {}  requested {}", tier_of(&drain.serial), drain.serial, state, format_age(age));
+    }
+    for crawl in db.crawls()?.into_iter().filter(|crawl| shown(tier_of(&crawl.serial))) {
+        let state = match crawl.finished_at {
+            Some(finished_at) => format!("first crawl done {}", format_age(SystemTime::now().duration_since(finished_at).unwrap_or_default())),
+            None => "first crawl running".to_string(),
+        };
+        let checksummed = if crawl.checksummed_bytes > 0 { format!(", {} checksummed", format_bytes(crawl.checksummed_bytes as f64)) } else { String::new() };
+        let age = SystemTime::now().duration_since(crawl.started_at).unwrap_or_default();
+        println!("{} {}  {}: {} files, {} found{}, started {}", tier_of(&crawl.serial), crawl.serial, state, crawl.files, format_bytes(crawl.bytes as f64), checksummed, format_age(age));
+    }
+    // the service's background loops aren't tied to a tier
+    if tier.is_none() {
+        for run in db.loop_runs()? {
+            println!("{}", loop_line(&run, SystemTime::now()));
+        }
+    }
+    let transfers: Vec<TransferProgress> = db.transfers()?.into_iter()
+        .filter(|transfer| shown(&transfer.info.source_tier) || shown(&transfer.info.target_tier))
+        .collect();
+    if transfers.is_empty() {
+        println!("No moves in progress");
+        return Ok(());
+    }
+    let mut progress = Progress::stdout();
+    if progress.is_tty() {
+        return progress.draw(&transfers.iter().map(transfer_item).collect::<Vec<_>>());
+    }
+    for transfer in &transfers {
+        let percent = (transfer.bytes_copied * 100).checked_div(transfer.bytes_total).unwrap_or(0);
+        let eta = transfer.eta().map_or("unknown".to_string(), format_eta);
+        let age = SystemTime::now().duration_since(transfer.updated_at).unwrap_or_default();
+        println!(
+            "{}  {} -> {}  {} / {} ({}%)  {}/s  ETA {}  updated {}",
+            transfer.info.src.display(), transfer.info.source_tier, transfer.info.target_tier,
+            format_bytes(transfer.bytes_copied as f64), format_bytes(transfer.bytes_total as f64), percent,
+            format_bytes(transfer.rate()), eta, format_age(age),
+        );
+    }
+    Ok(())
+}
+
+// Only drives the service has pooled can be drained
+fn request_drain(db: &MetadataDb, serial: &str) -> Result<(), CliError> {
+    let known = db.known_branches().map_err(|e| CliError::from_io(ErrorKind::Database, "failed to read the pooled drives", &e))?;
+    if !known.contains(serial) {
+        return Err(CliError::new(ErrorKind::Usage, format!("{} is not a drive in the pools", serial)));
+    }
+    db.request_drain(serial, SystemTime::now()).map_err(|e| CliError::from_io(ErrorKind::Database, &format!("failed to request a drain of {}", serial), &e))?;
+    println!("Asked the running service to drain {}; follow it with drive-manager status", serial);
+    Ok(())
+}
+
+fn request_maintenance(db: &MetadataDb, serial: &str, enable: bool) -> Result<(), CliError> {
+    let known = db.known_branches().map_err(|e| CliError::from_io(ErrorKind::Database, "failed to read the pooled drives", &e))?;
+    if !known.contains(serial) {
+        return Err(CliError::new(ErrorKind::Usage, format!("{} is not a drive in the pools", serial)));
+    }
+    let requested = db.request_maintenance(serial, enable, SystemTime::now())
+        .map_err(|e| CliError::from_io(ErrorKind::Database, &format!("failed to request maintenance of {}", serial), &e))?;
+    match (enable, requested) {
+        (true, _) => println!("Asked the running service to take {} out of the pools and unmount it; follow it with drive-manager status", serial),
+        (false, true) => println!("Asked the running service to mount {} and put it back in the pools", serial),
+        (false, false) => return Err(CliError::new(ErrorKind::Usage, format!("{} is not out for maintenance", serial))),
+    }
+    Ok(())
+}
+
+// Pins take hold at the service's next scan, so ask for a check to get
+// there now
+fn pin_path(db: &MetadataDb, path: &Path, tier: &str) -> Result<(), CliError> {
+    let pin = Pin { path: pins::pool_relative(path), tier: tier.to_string() };
+    if pin.path.as_os_str().is_empty() {
+        return Err(CliError::new(ErrorKind::Usage, "pin expects a path inside the pool"));
+    }
+    let failed = |e: io::Error| CliError::from_io(ErrorKind::Database, &format!("failed to pin {}", pin.path.display()), &e);
+    db.add_pin(&pin, SystemTime::now()).map_err(failed)?;
+    db.request_check(SystemTime::now()).map_err(failed)?;
+    println!("Pinned {} to {}; the running service moves it there at its next check", pin.path.display(), pin.tier);
+    Ok(())
+}
+
+fn unpin_path(args: &Args, db: &MetadataDb, path: &Path) -> Result<(), CliError> {
+    let path = pins::pool_relative(path);
+    if db.remove_pin(&path).map_err(|e| CliError::from_io(ErrorKind::Database, &format!("failed to unpin {}", path.display()), &e))? {
+        println!("Unpinned {}", path.display());
+        return Ok(());
+    }
+    let config = DriveManager::read_config(args).unwrap_or_default();
+    if pins::pins(&config.raw).iter().any(|pin| pin.path == path) {
+        return Err(CliError::new(ErrorKind::Config, format!("{} is pinned in the config; remove it there", path.display())));
+    }
+    Err(CliError::new(ErrorKind::Failure, format!("{} is not pinned", path.display())))
+}
+
+fn print_pins(args: &Args, db: &MetadataDb) -> io::Result<()> {
+    let config = DriveManager::read_config(args).unwrap_or_default();
+    let runtime = db.pins()?;
+    let configured = pins::pins(&config.raw);
+    if runtime.is_empty() && configured.is_empty() {
+        println!("Nothing is pinned");
+    }
+    for (pin, source) in runtime.iter().map(|pin| (pin, "pin")).chain(configured.iter().map(|pin| (pin, "config"))) {
+        println!("{:<5} {}  ({})", pin.tier, pin.path.display(), source);
+    }
+    Ok(())
+}
+
+// Every drive with what the service makes of it and what SMART says about
+// it, read live. Drives asleep are left that way.
+fn list_drives(args: &Args) -> Result<(), CliError> {
+    let config = read_config(args);
+    let mut drive_manager = DriveManager::with_config(args.clone(), config.clone());
+    let devices = drive_manager.get_block_devices().map_err(|e| CliError::from_io(ErrorKind::Failure, "failed to discover drives", &e))?;
+    // drives the service has not set up yet are not in the registry
+    let registry = MetadataDb::open(&config.db_path).and_then(|db| db.drives()).unwrap_or_default();
+    drive_manager.registered_tiers = registry.iter().map(|drive| (drive.serial.clone(), drive.tier.clone())).collect();
+    let tiers = drive_manager.drive_tiers();
+    let mut unreadable = None;
+    for device in &devices {
+        let state = registry.iter().find(|drive| drive.serial == device.id()).map(|drive| drive.state).filter(|state| *state != DriveState::Active);
+        let tier = match state {
+            _ if config.is_excluded(device.id()) => "excluded".to_string(),
+            Some(state) => format!("{} ({})", DriveManager::device_tier(device, &tiers), state.as_str()),
+            None => DriveManager::device_tier(device, &tiers).to_string(),
+        };
+        let size = device.size.map_or("unknown size".to_string(), |size| format_bytes(size as f64));
+        let mountpoint = device.filesystem_mountpoint().unwrap_or("not mounted");
+        let health = health::read(drive_manager.executor.as_ref(), &device.path).unwrap_or_else(|e| {
+            unreadable.get_or_insert(e);
+            Health::default()
+        });
+        let temperature = health.temperature_c.map_or("-".to_string(), |celsius| format!("{}°C", celsius));
+        let hours = health.power_on_hours.map_or("-".to_string(), |hours| format!("{}h on", hours));
+        println!(
+            "{}  {}  {} {}  {}  {}  {}  {}  {}",
+            device.path, device.id(), device.block_class(), tier, size, mountpoint, temperature, hours, health.summary(),
+        );
+    }
+    if let Some(e) = unreadable {
+        eprintln!("Some drives' SMART data could not be read, is smartmontools 7 or later installed? {}", e);
+    }
+    Ok(())
+}
+
+// Take over the drives behind an existing mergerfs mount where they are
+// mounted: ask which tier each goes in, save that to conf.d for `run`,
+// and record what is on them, all without moving or remounting anything
+fn adopt_mount(args: &Args, mount: &Path) -> Result<(), CliError> {
+    let config = read_config(args);
+    let read = |path: &str| fs::read_to_string(path).unwrap_or_default();
+    let specs = adopt::branch_specs(&read("/etc/fstab"), &read("/proc/self/mounts"), mount)
+        .ok_or_else(|| CliError::new(ErrorKind::Failure, format!("{} is not a mergerfs mount in /etc/fstab or /proc/self/mounts", mount.display())))?;
+    let drive_manager = DriveManager::with_config(args.clone(), config.clone());
+    let devices = drive_manager.get_block_devices().map_err(|e| CliError::from_io(ErrorKind::Failure, "failed to discover drives", &e))?;
+    let interactive = io::stdin().is_terminal();
+    let mut adopted = Vec::new();
+    let mut adopted_devices = Vec::new();
+    for branch in specs.iter().flat_map(|spec| adopt::expand(spec)) {
+        let Some(device) = devices.iter().find(|device| device.filesystem_mountpoint().map(Path::new) == Some(branch.as_path())) else {
+            println!("{} is not where a drive's partition is mounted, leaving it out", branch.display());
+            continue;
+        };
+        let tier = if interactive { ask_tier(&branch, device)? } else { device.tier().to_string() };
+        println!("{}  {} ({})  {}", branch.display(), device.id(), device.block_class(), tier);
+        adopted.push(adopt::AdoptedDrive { serial: device.id().to_string(), mountpoint: branch, tier });
+        adopted_devices.push(device.clone());
+    }
+    if adopted.is_empty() {
+        return Err(CliError::new(ErrorKind::Failure, format!("no branch of {} is a drive's mount point", mount.display())));
+    }
+    let fragment_path = config::include_dir(Path::new(&args.config), &config.raw).join(adopt::ADOPTED_FILE);
+    let saved = fs::create_dir_all(fragment_path.parent().unwrap())
+        .and_then(|()| fs::write(&fragment_path, serde_json::to_string_pretty(&adopt::fragment(&adopted))? + "\n"));
+    saved.map_err(|e| CliError::from_io(ErrorKind::Config, &format!("failed to write {}", fragment_path.display()), &e))?;
+    println!("Wrote {}", fragment_path.display());
+
+    // a dry-run storage, so the scan reads the branches and nothing else
+    let branches = DriveManager::branches(&adopted_devices, &adopt::drive_tiers(&adopt::fragment(&adopted)));
+    let tiering_manager = TieringManager::new(args.clone(), config.raw, Arc::new(BranchStorage::new(branches, true)), open_db(args));
+    let seeded = tiering_manager.seed_new_branches().and_then(|()| tiering_manager.update_file_metadata());
+    seeded.map_err(|e| CliError::from_io(ErrorKind::Database, "failed to record the adopted drives' files", &e))?;
+    println!("Adopted {} drives; `drive-manager run` now manages them where they are mounted", adopted.len());
+    Ok(())
+}
+
+fn ask_tier(branch: &Path, device: &BlockDevice) -> Result<String, CliError> {
+    loop {
+        print!("Tier for {} ({}, {}) [{}]: ", branch.display(), device.id(), device.block_class(), device.tier());
+        io::stdout().flush().map_err(|e| CliError::from_io(ErrorKind::Failure, "failed to prompt", &e))?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).map_err(|e| CliError::from_io(ErrorKind::Failure, "failed to read the answer", &e))?;
+        match answer.trim() {
+            "" => return Ok(device.tier().to_string()),
+            tier if DriveManager::TIERS.contains(&tier) => return Ok(tier.to_string()),
+            tier => println!("{} is not a tier; answer one of {}", tier, DriveManager::TIERS.join(", ")),
+        }
+    }
+}
+
+// Wait for the jobs sharing a drive with this one that are running or
+// go first, then pause tiering on its drives and wait out the moves already
+// copying so nothing changes under the job once this returns
+fn start_job(args: &Args, db: &MetadataDb, name: &str) -> Result<(), CliError> {
+    let config = read_config(args);
+    let jobs = external_jobs::external_jobs(&config.raw);
+    let job = external_jobs::find(&jobs, name)
+        .ok_or_else(|| CliError::new(ErrorKind::Config, format!("no job named {} in external_jobs", name)))?;
+    let failed = |e: io::Error| CliError::from_io(ErrorKind::Database, &format!("failed to start job {}", name), &e);
+    let mut waiting_for = None;
+    loop {
+        let now = SystemTime::now();
+        let blocker = db.transaction(|db| {
+            let since = db.wait_for_job(name, now)?;
+            let waiting = db.waiting_jobs(now - Duration::from_secs(external_jobs::WAIT_TIMEOUT))?;
+            let blocker = external_jobs::blocker(job, since, &jobs, &db.running_jobs(now)?, &waiting);
+            if blocker.is_none() {
+                db.start_job(&RunningJob { name: job.name.clone(), started_at: now, expires_at: now + job.max_duration })?;
+                db.stop_waiting_for_job(name)?;
+            }
+            Ok(blocker)
+        }).map_err(failed)?;
+        let Some(blocker) = blocker else { break };
+        if waiting_for.as_ref() != Some(&blocker) {
+            println!("Waiting for job {} to finish", blocker);
+            waiting_for = Some(blocker);
+        }
+        thread::sleep(Duration::from_secs(external_jobs::WAIT_POLL_INTERVAL));
+    }
+    wait_for_transfers(db, name).map_err(failed)
+}
+
+fn wait_for_transfers(db: &MetadataDb, name: &str) -> io::Result<()> {
+    let mut progress = Progress::stdout();
+    let mut copying: Vec<PathBuf> = Vec::new();
+    let mut finished = 0;
+    loop {
+        let transfers = db.transfers()?;
+        let still_copying: Vec<PathBuf> = transfers.iter().map(|transfer| transfer.info.src.clone()).collect();
+        for src in copying.iter().filter(|src| !still_copying.contains(src)) {
+            progress.message(&format!("Finished moving {}", src.display()))?;
+            finished += 1;
+        }
+        if transfers.is_empty() {
+            break;
+        }
+        if copying.is_empty() {
+            progress.message(&format!("Waiting for {} moves to finish", transfers.len()))?;
+        }
+        progress.draw(&transfers.iter().map(transfer_item).collect::<Vec<_>>())?;
+        copying = still_copying;
+        thread::sleep(Duration::from_secs(1));
+    }
+    let waited = if finished > 0 { format!(" after {} moves finished in {}", finished, format_eta(progress.elapsed())) } else { String::new() };
+    progress.finish(&format!("Tiering paused for {}{}", name, waited))
+}
+
+fn print_jobs(args: &Args, db: &MetadataDb) -> io::Result<()> {
+    let config = DriveManager::read_config(args).unwrap_or_default();
+    let configured = &external_jobs::external_jobs(&config.raw);
+    let jobs = db.running_jobs(SystemTime::now())?;
+    let waiting = db.waiting_jobs(SystemTime::now() - Duration::from_secs(external_jobs::WAIT_TIMEOUT))?;
+    if jobs.is_empty() && waiting.is_empty() {
+        println!("No external jobs running");
+        return Ok(());
+    }
+    for job in &jobs {
+        let age = SystemTime::now().duration_since(job.started_at).unwrap_or_default();
+        let left = job.expires_at.duration_since(SystemTime::now()).unwrap_or_default();
+        let drives = external_jobs::job_for(configured, &job.name).drives;
+        let holds = if drives.is_empty() { "pauses tiering".to_string() } else { format!("holds {}", drives.join(", ")) };
+        println!("{}  started {}  {} for at most {} more", job.name, format_age(age), holds, format_eta(left));
+    }
+    for (name, since) in waiting {
+        let age = SystemTime::now().duration_since(since).unwrap_or_default();
+        println!("{}  waiting since {}", name, format_age(age));
+    }
+    Ok(())
+}
+
+// Reserve the files' bytes against the hot tier and hold them there. The
+// whole staging is refused when the reservations would pass the share of
+// the hot tier stagings may take, so a job never starts on a promise the
+// tier cannot keep.
+fn stage(args: &Args, db: &MetadataDb, name: &str, paths: &[PathBuf]) -> Result<(), CliError> {
+    let staging = Staging::from_config(&read_config(args).raw);
+    let mut files = Vec::new();
+    for path in paths {
+        let path = pins::pool_relative(path);
+        let failed = |e: io::Error| CliError::from_io(ErrorKind::Database, &format!("failed to look up {}", path.display()), &e);
+        match db.get(&path).map_err(failed)? {
+            Some(metadata) => files.push((path, metadata.file_size)),
+            None => {
+                let under = db.entries_under(&path).map_err(failed)?;
+                if under.is_empty() || path.as_os_str().is_empty() {
+                    return Err(CliError::new(ErrorKind::Usage, format!("{} is not a file or directory the service tracks", path.display())));
+                }
+                files.extend(under.into_iter().map(|(path, metadata)| (path, metadata.file_size)));
+            }
+        }
+    }
+    let hot = Path::new(DriveManager::MERGERFS_MOUNT_PATH).join("hot");
+    let hot_total = storage::disk_usage(&hot).map_err(|e| CliError::from_io(ErrorKind::Failure, &format!("failed to read the size of {}", hot.display()), &e))?.total;
+    let now = SystemTime::now();
+    let reserved = db.transaction(|db| {
+        db.stage_files(name, &files, now, now + staging.max_duration)?;
+        let reserved = db.staged_bytes(now)?;
+        if !staging.admits(reserved, hot_total) {
+            return Ok(Err(reserved));
+        }
+        db.request_check(now)?;
+        Ok(Ok(reserved))
+    }).map_err(|e| CliError::from_io(ErrorKind::Database, &format!("failed to stage {}", name), &e))?;
+    let limit = format_bytes(staging.limit(hot_total) as f64);
+    match reserved {
+        Ok(reserved) => {
+            let bytes: u64 = files.iter().map(|(_, size)| size).sum();
+            println!("Staged {} files ({}) for {}; {} of {} reserved on hot", files.len(), format_bytes(bytes as f64), name, format_bytes(reserved as f64), limit);
+            Ok(())
+        }
+        // staging the files again replaced the old reservation, so drop it
+        // along with the new one rather than leave the old in place
+        Err(reserved) => {
+            db.release_staging(name).map_err(|e| CliError::from_io(ErrorKind::Database, &format!("failed to drop {}", name), &e))?;
+            Err(CliError::new(ErrorKind::Failure, format!("staging {} would reserve {} of the {} hot tier stagings may hold", name, format_bytes(reserved as f64), limit)))
+        }
+    }
+}
+
+fn print_stagings(db: &MetadataDb) -> io::Result<()> {
+    let now = SystemTime::now();
+    let stagings = db.stagings(now)?;
+    if stagings.is_empty() {
+        println!("Nothing is staged");
+        return Ok(());
+    }
+    for staged in stagings {
+        let age = now.duration_since(staged.staged_at).unwrap_or_default();
+        let left = staged.expires_at.duration_since(now).unwrap_or_default();
+        println!(
+            "{}  {} of {} files on hot  {} reserved  staged {}  held for at most {} more",
+            staged.name, staged.on_hot, staged.files, format_bytes(staged.bytes as f64), format_age(age), format_eta(left),
+        );
+    }
+    Ok(())
+}
+
+fn print_history(args: &Args, hours: u64) -> Result<(), CliError> {
+    let config = read_config(args);
+    let settings = EventLogSettings::from_config(&config.raw)
+        .ok_or_else(|| CliError::new(ErrorKind::Config, "there is no event log, set event_log.path in the config to keep one"))?;
+    let now = SystemTime::now();
+    let events = events::read(&settings, now - Duration::from_secs(hours * 3600))
+        .map_err(|e| CliError::from_io(ErrorKind::Failure, &format!("failed to read {}", settings.path.display()), &e))?;
+    if events.is_empty() {
+        println!("Nothing happened in the last {}h", hours);
+    }
+    for event in events {
+        let age = now.duration_since(event.at()).unwrap_or_default();
+        println!("{}  {}  {}", format_age(age), event.kind, event.details);
+    }
+    Ok(())
+}
+
+fn print_drives(db: &MetadataDb) -> io::Result<()> {
+    let drives = db.drives()?;
+    if drives.is_empty() {
+        println!("No drives have been set up yet");
+        return Ok(());
+    }
+    let now = SystemTime::now();
+    for drive in drives {
+        let first_seen = now.duration_since(drive.first_seen).unwrap_or_default();
+        let last_seen = now.duration_since(drive.last_seen).unwrap_or_default();
+        println!("{} {}  {}  first set up {}  last set up {}", drive.tier, drive.serial, drive.state.as_str(), format_age(first_seen), format_age(last_seen));
+    }
+    Ok(())
+}
+
+fn print_writes(args: &Args, db: &MetadataDb) -> io::Result<()> {
+    let config = read_config(args).raw;
+    let policy = WriteSkewPolicy::from_config(&config);
+    let now = SystemTime::now();
+    let lifetime = db.drive_writes()?;
+    if lifetime.is_empty() {
+        println!("No writes sampled yet; the running service samples them every disk_stats_sec");
+        return Ok(());
+    }
+    let branch_tiers = db.branch_tiers()?;
+    let tier_of = |serial: &str| branch_tiers.get(serial).map_or("unknown", String::as_str);
+    let tiers = write_skew::tier_writes(&branch_tiers, &db.bytes_written_since(now - policy.window)?);
+    let skewed = policy.skewed(&tiers);
+    let hours = policy.window.as_secs() / 3600;
+    let mut lifetime = lifetime;
+    lifetime.sort_by_key(|(serial, _, _)| (tier_rank(tier_of(serial)), serial.clone()));
+    for (serial, bytes, since) in &lifetime {
+        let tier = tier_of(serial);
+        let drives = tiers.get(tier).map_or(&[][..], Vec::as_slice);
+        let recent = drives.iter().find(|(drive, _)| drive == serial).map_or(0, |(_, bytes)| *bytes);
+        let total: u64 = drives.iter().map(|(_, bytes)| bytes).sum();
+        let share = if total == 0 { 0.0 } else { recent as f64 * 100.0 / total as f64 };
+        let flag = if skewed.iter().any(|skew| &skew.serial == serial) { "  skewed" } else { "" };
+        println!(
+            "{} {}  {} written since {}  {} in the last {}h, {:.0}% of the tier's{}",
+            tier, serial, format_bytes(*bytes as f64), format_age(now.duration_since(*since).unwrap_or_default()), format_bytes(recent as f64), hours, share, flag,
+        );
+    }
+    let mut tiers_skewed: Vec<&str> = skewed.iter().map(|skew| skew.tier.as_str()).collect();
+    tiers_skewed.dedup();
+    for tier in tiers_skewed {
+        if let Some(advice) = write_skew::policy_advice(&config, tier) {
+            println!("{}: {}", tier, advice);
+        }
+        for planned in write_skew::rebalance_plan(&tiers[tier]) {
+            println!("{}: {}", tier, planned);
+        }
+    }
+    Ok(())
+}
+
+fn reset_drive(db: &MetadataDb, serial: &str) -> Result<(), CliError> {
+    let reset = db.reset_drive(serial).map_err(|e| CliError::from_io(ErrorKind::Database, &format!("failed to reset {}", serial), &e))?;
+    if !reset {
+        return Err(CliError::new(ErrorKind::Usage, format!("{} is not a quarantined or retired drive", serial)));
+    }
+    println!("{} is active again; the running service uses it once it is restarted or the drive is attached again", serial);
+    Ok(())
+}
+
+fn print_failures(db: &MetadataDb) -> io::Result<()> {
+    let now = SystemTime::now();
+    let retries = db.pending_retries()?;
+    let failures = db.failures()?;
+    if retries.is_empty() && failures.is_empty() {
+        println!("No failed moves");
+        return Ok(());
+    }
+    // every attempt that failed, oldest first, under each move
+    let print_errors = |path: &Path| -> io::Result<()> {
+        for (failed_at, error) in db.move_errors(path)? {
+            println!("    {}  {}", format_age(now.duration_since(failed_at).unwrap_or_default()), error);
+        }
+        Ok(())
+    };
+    for retry in &retries {
+        let wait = retry.due_at.duration_since(now).unwrap_or_default();
+        println!(
+            "{}  {} -> {}  retry {} in {}",
+            retry.info.src.display(), retry.info.source_tier, retry.info.target_tier, retry.info.retries, format_eta(wait),
+        );
+        print_errors(&retry.info.src)?;
+    }
+    for failure in &failures {
+        let age = now.duration_since(failure.failed_at).unwrap_or_default();
+        println!(
+            "{}  {} -> {}  {} retries, {}  {}",
+            failure.info.src.display(), failure.info.source_tier, failure.info.target_tier, failure.info.retries, format_age(age), failure.error,
+        );
+        print_errors(&failure.info.src)?;
+    }
+    println!(
+        "{} moves waiting to retry, {} failed moves; run `drive-manager failures retry` to retry the failed ones or `drive-manager failures drop <PATH>` to give up on one",
+        retries.len(), failures.len(),
+    );
+    Ok(())
+}
+
+fn print_problem_files(db: &MetadataDb) -> io::Result<()> {
+    let now = SystemTime::now();
+    let files = db.quarantined_files()?;
+    if files.is_empty() {
+        println!("No problem files");
+        return Ok(());
+    }
+    for file in &files {
+        let age = now.duration_since(file.quarantined_at).unwrap_or_default();
+        println!("{}  quarantined {} after {}", file.path.display(), format_age(age), file.reason);
+        for (failed_at, error) in db.move_errors(&file.path)? {
+            println!("    {}  {}", format_age(now.duration_since(failed_at).unwrap_or_default()), error);
+        }
+    }
+    println!(
+        "{} problem files, left where they are; check the drives they are on, then run `drive-manager problem-files clear <PATH>` to let one move again",
+        files.len(),
+    );
+    Ok(())
+}
+
+fn print_plan(plan: &Plan) -> io::Result<()> {
+    if plan.moves.is_empty() {
+        println!("Nothing to move");
+        return Ok(());
+    }
+    let rows: Vec<[String; 5]> = plan.moves.iter().map(|planned| {
+        [planned.path.display().to_string(), format_bytes(planned.size as f64), planned.source_tier.clone(), planned.target_tier.clone(), planned.reason.clone()]
+    }).collect();
+    let header = ["FILE", "SIZE", "FROM", "TO", "REASON"].map(String::from);
+    let widths: Vec<usize> = (0..4).map(|column| rows.iter().chain([&header]).map(|row| row[column].chars().count()).max().unwrap_or(0)).collect();
+    let mut out = io::stdout().lock();
+    for row in [&header].into_iter().chain(&rows) {
+        writeln!(out, "{:<w0$}  {:>w1$}  {:<w2$}  {:<w3$}  {}", row[0], row[1], row[2], row[3], row[4], w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3])?;
+    }
+    writeln!(out)?;
+    write!(out, "{}", plan)
+}
+
+// Everything doctor checks, carrying on past whatever it cannot read
+fn diagnose(args: &Args) -> Vec<doctor::Finding> {
+    let mut findings = Vec::new();
+    let config = DriveManager::read_config(args).unwrap_or_else(|e| {
+        findings.push(doctor::Finding::fail("config", format!("{} could not be read: {}", args.config, e), "fix the file, or point at another with --config"));
+        Config::default()
+    });
+    findings.extend(doctor::check_tools(&config.raw, &std::env::var_os("PATH").unwrap_or_default()));
+    match std::fs::read_to_string("/proc/self/mounts") {
+        Ok(mounts) => match Bcachefs::from_config(&config.raw) {
+            Some(bcachefs) => findings.extend(doctor::check_bcachefs(&bcachefs, &mounts, Path::new(bcachefs::SYSFS_DIR))),
+            None => findings.extend(doctor::check_mounts(&mounts)),
+        },
+        Err(e) => findings.push(doctor::Finding::fail("mounts", format!("/proc/self/mounts could not be read: {}", e), "run doctor on the host running the service")),
+    }
+    if let Some(report) = ZfsReport::from_config(&config.raw) {
+        findings.extend(doctor::check_zfs(&report, report.datasets(&SystemExecutor)));
+    }
+    let db_path = config.db_path.as_str();
+    findings.extend(doctor::check_permissions(Path::new(db_path)));
+    let transcripts = TranscriptLog::read(&transcript_dir(db_path).join(TRANSCRIPT_FILE)).unwrap_or_default();
+    findings.extend(doctor::check_transcripts(&transcripts));
+    match MetadataDb::open(db_path) {
+        Ok(db) => findings.extend(doctor::check_db(&db, SystemTime::now())),
+        Err(e) => findings.push(doctor::Finding::fail("database", format!("{} could not be opened: {}", db_path, e), "check the path and its permissions; a corrupt file can be moved aside and is rebuilt by the next scan")),
+    }
+    findings
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args = Args::parse();
+    match args.command {
+        Command::Help => println!("{}", USAGE),
+        Command::Generate(target) => {
+            // secret references are kept as they are, not written out resolved
+            let config = config::load(Path::new(&args.config), args.profile.as_deref(), args.write_config)
+                .unwrap_or_else(|e| CliError::from_io(ErrorKind::Config, &format!("failed to read config {}", args.config), &e).exit());
+            match target {
+                GenerateTarget::Systemd => print!("{}", generate::systemd_unit(&args, &config)),
+                GenerateTarget::Nixos => print!("{}", generate::nixos_module(&args, &config)),
+            }
+        }
+        Command::Failures => {
+            if let Err(e) = print_failures(&open_db(&args)) {
+                CliError::from_io(ErrorKind::Database, "failed to read failed moves", &e).exit();
+            }
+        }
+        Command::Status(ref tier) => {
+            if let Err(e) = print_status(&open_db(&args), tier.as_deref()) {
+                CliError::from_io(ErrorKind::Database, "failed to read move progress", &e).exit();
+            }
+        }
+        Command::ListDrives => {
+            if let Err(e) = list_drives(&args) {
+                e.exit();
+            }
+        }
+        Command::Adopt(ref mount) => {
+            if let Err(e) = adopt_mount(&args, mount) {
+                e.exit();
+            }
+        }
+        Command::ReloadConfig => {
+            if let Err(e) = request_reload(&args) {
+                e.exit();
+            }
+        }
+        Command::Ctl(command) => {
+            let config = DriveManager::read_config(&args).unwrap_or_default();
+            let path = control::socket_path(&config.raw);
+            match control::send(&path, command) {
+                Ok(message) => println!("{}", message),
+                Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => {
+                    CliError::from_io(ErrorKind::Failure, &format!("no service listening on {}; is it running?", path.display()), &e).exit()
+                }
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    CliError::from_io(ErrorKind::Permission, &format!("cannot reach {}", path.display()), &e).exit()
+                }
+                Err(e) => CliError::from_io(ErrorKind::Failure, &format!("ctl {} failed", command.as_str()), &e).exit(),
+            }
+        }
+        Command::CheckNow => match open_db(&args).request_check(SystemTime::now()) {
+            Ok(()) => println!("Asked the running service for a tiering check"),
+            Err(e) => CliError::from_io(ErrorKind::Database, "failed to request a tiering check", &e).exit(),
+        },
+        Command::Drain(ref serial) => {
+            if let Err(e) = request_drain(&open_db(&args), serial) {
+                e.exit();
+            }
+        }
+        Command::Maintenance(ref serial, enable) => {
+            if let Err(e) = request_maintenance(&open_db(&args), serial, enable) {
+                e.exit();
+            }
+        }
+        Command::Pin(ref path, ref tier) => {
+            if let Err(e) = pin_path(&open_db(&args), path, tier) {
+                e.exit();
+            }
+        }
+        Command::Unpin(ref path) => {
+            if let Err(e) = unpin_path(&args, &open_db(&args), path) {
+                e.exit();
+            }
+        }
+        Command::Pins => {
+            if let Err(e) = print_pins(&args, &open_db(&args)) {
+                CliError::from_io(ErrorKind::Database, "failed to read pins", &e).exit();
+            }
+        }
+        Command::JobStart(ref name) => {
+            if let Err(e) = start_job(&args, &open_db(&args), name) {
+                e.exit();
+            }
+        }
+        Command::JobFinish(ref name) => match open_db(&args).finish_job(name) {
+            Ok(true) => println!("Tiering resumed after {}", name),
+            Ok(false) => println!("Job {} was not running", name),
+            Err(e) => CliError::from_io(ErrorKind::Database, &format!("failed to finish job {}", name), &e).exit(),
+        },
+        Command::Jobs => {
+            if let Err(e) = print_jobs(&args, &open_db(&args)) {
+                CliError::from_io(ErrorKind::Database, "failed to read running jobs", &e).exit();
+            }
+        }
+        Command::Stage(ref name, ref paths) => {
+            if let Err(e) = stage(&args, &open_db(&args), name, paths) {
+                e.exit();
+            }
+        }
+        Command::Release(ref name) => match open_db(&args).release_staging(name) {
+            Ok(0) => println!("Nothing is staged for {}", name),
+            Ok(files) => println!("Released {} files staged for {}; the tiering rules place them again", files, name),
+            Err(e) => CliError::from_io(ErrorKind::Database, &format!("failed to release {}", name), &e).exit(),
+        },
+        Command::Drives => {
+            if let Err(e) = print_drives(&open_db(&args)) {
+                CliError::from_io(ErrorKind::Database, "failed to read the drive registry", &e).exit();
+            }
+        }
+        Command::Writes => {
+            if let Err(e) = print_writes(&args, &open_db(&args)) {
+                CliError::from_io(ErrorKind::Database, "failed to read drive writes", &e).exit();
+            }
+        }
+        Command::ResetDrive(ref serial) => {
+            if let Err(e) = reset_drive(&open_db(&args), serial) {
+                e.exit();
+            }
+        }
+        Command::Stagings => {
+            if let Err(e) = print_stagings(&open_db(&args)) {
+                CliError::from_io(ErrorKind::Database, "failed to read stagings", &e).exit();
+            }
+        }
+        Command::History(hours) => {
+            if let Err(e) = print_history(&args, hours) {
+                e.exit();
+            }
+        }
+        Command::Doctor => {
+            let findings = diagnose(&args);
+            print!("{}", doctor::report(&findings));
+            let failed = findings.iter().filter(|finding| finding.severity == doctor::Severity::Fail).count();
+            if failed > 0 {
+                CliError::new(ErrorKind::PartialFailure, format!("{} checks failed", failed)).exit();
+            }
+        }
+        Command::DropFailure(ref path) => match open_db(&args).drop_move(path) {
+            Ok(true) => println!("Dropped the move of {}", path.display()),
+            Ok(false) => CliError::new(ErrorKind::Failure, format!("no failed or retrying move of {}", path.display())).exit(),
+            Err(e) => CliError::from_io(ErrorKind::Database, &format!("failed to drop the move of {}", path.display()), &e).exit(),
+        },
+        Command::ProblemFiles => {
+            if let Err(e) = print_problem_files(&open_db(&args)) {
+                CliError::from_io(ErrorKind::Database, "failed to read problem files", &e).exit();
+            }
+        }
+        Command::ClearProblemFile(ref path) => match open_db(&args).release_file(path) {
+            Ok(true) => println!("Cleared {}; the next check can move it again", path.display()),
+            Ok(false) => CliError::new(ErrorKind::Failure, format!("{} is not quarantined", path.display())).exit(),
+            Err(e) => CliError::from_io(ErrorKind::Database, &format!("failed to clear {}", path.display()), &e).exit(),
+        },
+        Command::RetryFailures => match open_db(&args).request_retry_all() {
+            Ok(count) => println!("Queued {} failed moves for retry", count),
+            Err(e) => CliError::from_io(ErrorKind::Database, "failed to queue retries", &e).exit(),
+        },
+        Command::Run if args.simulate => {
+            RedactingLogger::init(LevelFilter::Off).unwrap();
+            // simulation needs no real config, only policy overrides if one exists
+            let config = DriveManager::read_config(&args).unwrap_or_default();
+            match simulation::run(&args, &config.raw) {
+                Ok(report) => print!("{}", report),
+                Err(e) => CliError::from_io(ErrorKind::Failure, "simulation failed", &e).exit(),
+            }
+        }
+        Command::Run => {
+            RedactingLogger::init(LevelFilter::Info).unwrap();
+            run(args);
+        }
+        Command::Plan => {
+            // only trouble is logged over the table
+            RedactingLogger::init(LevelFilter::Warn).unwrap();
+            run(Args { observe: true, ..args });
+        }
+    }
 }
@@ -0,0 +1,258 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use crate::drive_manager::DriveManager;
+use crate::tiering_manager::TieringManager;
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8789";
+pub const NEXT_EPISODES: usize = 2;
+// how long a stream stays protected when no stop event arrives
+pub const STREAM_TIMEOUT_SEC: u64 = 4 * 3600;
+// webhook bodies are small JSON documents; Plex adds a thumbnail at most
+const MAX_BODY: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayState {
+    Started,
+    Stopped,
+}
+
+// A playback webhook, with the file as the media server sees it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayEvent {
+    pub state: PlayState,
+    pub file: PathBuf,
+}
+
+// The media_server config section:
+//   { "listen": "127.0.0.1:8789", "roots": ["/media"], "next_episodes": 2 }
+// roots are where the media server sees the pool; files under them map to
+// the same relative path on the branches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaServer {
+    pub listen: String,
+    pub roots: Vec<PathBuf>,
+    pub next_episodes: usize,
+    pub stream_timeout: Duration,
+}
+
+impl MediaServer {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("media_server")?;
+        let mut roots: Vec<PathBuf> = section.get("roots").and_then(Value::as_array)
+            .map(|roots| roots.iter().filter_map(Value::as_str).map(PathBuf::from).collect())
+            .unwrap_or_default();
+        roots.extend(DriveManager::TIERS.iter().map(|tier| Path::new(DriveManager::MERGERFS_MOUNT_PATH).join(tier)));
+        Some(Self {
+            listen: section.get("listen").and_then(Value::as_str).unwrap_or(DEFAULT_LISTEN).to_string(),
+            roots,
+            next_episodes: section.get("next_episodes").and_then(Value::as_u64).map_or(NEXT_EPISODES, |count| count as usize),
+            stream_timeout: Duration::from_secs(section.get("stream_timeout_sec").and_then(Value::as_u64).unwrap_or(STREAM_TIMEOUT_SEC)),
+        })
+    }
+
+    // The branch-relative path of a file the media server reported
+    pub fn relative_path(&self, file: &Path) -> Option<PathBuf> {
+        self.roots.iter()
+            .filter_map(|root| file.strip_prefix(root).ok())
+            .find(|relative| !relative.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+    }
+
+    pub fn spawn(self, tm: Arc<TieringManager>) -> io::Result<()> {
+        let listener = TcpListener::bind(&self.listen)?;
+        info!("Listening for media server webhooks on {}", self.listen);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.handle_connection(stream, &tm) {
+                            debug!("Bad webhook request: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to accept webhook connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream, tm: &TieringManager) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let request = read_request(&mut BufReader::new(&stream));
+        let status = match &request {
+            Ok(request) if request.method != "POST" => "405 Method Not Allowed",
+            Ok(request) => {
+                if let Some(event) = parse_event(&request.content_type, &request.body) {
+                    self.handle_event(&event, tm);
+                }
+                "204 No Content"
+            }
+            Err(_) => "400 Bad Request",
+        };
+        write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status)?;
+        request.map(|_| ())
+    }
+
+    pub fn handle_event(&self, event: &PlayEvent, tm: &TieringManager) {
+        let Some(path) = self.relative_path(&event.file) else {
+            debug!("Ignoring playback of {}, it is outside the pool", event.file.display());
+            return;
+        };
+        match event.state {
+            PlayState::Started => {
+                info!("Playback started for {}", path.display());
+                if let Err(e) = tm.stream_started(&path, self.next_episodes, self.stream_timeout) {
+                    warn!("Failed to promote {} for playback: {}", path.display(), e);
+                }
+            }
+            PlayState::Stopped => tm.stream_stopped(&path),
+        }
+    }
+}
+
+pub struct Request {
+    pub method: String,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+// Just enough HTTP/1.1 for webhook POSTs with a Content-Length
+pub fn read_request(reader: &mut impl BufRead) -> io::Result<Request> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let method = line.split_whitespace().next().ok_or_else(|| invalid("empty request"))?.to_string();
+    let (mut content_type, mut content_length) = (String::new(), 0);
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("truncated headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else { continue };
+        if name.eq_ignore_ascii_case("content-type") {
+            content_type = value.trim().to_string();
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| invalid("bad content length"))?;
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(invalid("body too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, content_type, body })
+}
+
+// Plex posts multipart forms with the JSON in a "payload" field. Jellyfin's
+// webhook plugin and Tautulli post JSON bodies shaped by a template, so the
+// common field names for the event and file are all accepted.
+pub fn parse_event(content_type: &str, body: &[u8]) -> Option<PlayEvent> {
+    let json = match content_type.split(';').find_map(|param| param.trim().strip_prefix("boundary=")) {
+        Some(boundary) => multipart_field(body, boundary.trim_matches('"'), "payload")?,
+        None => body,
+    };
+    let payload: Value = serde_json::from_slice(json).ok()?;
+    let event = ["event", "NotificationType", "action"].iter().find_map(|key| payload.get(key).and_then(Value::as_str))?;
+    let state = match event {
+        "media.play" | "media.resume" | "PlaybackStart" | "play" | "resume" => PlayState::Started,
+        "media.stop" | "PlaybackStop" | "stop" => PlayState::Stopped,
+        _ => return None,
+    };
+    let file = ["file", "ItemPath", "Path", "path"].iter().find_map(|key| payload.get(key).and_then(Value::as_str))
+        .or_else(|| payload.pointer("/Metadata/Media/0/Part/0/file").and_then(Value::as_str))?;
+    Some(PlayEvent { state, file: PathBuf::from(file) })
+}
+
+fn multipart_field<'a>(body: &'a [u8], boundary: &str, name: &str) -> Option<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let disposition = format!("name=\"{}\"", name);
+    split(body, delimiter.as_bytes()).into_iter().find_map(|part| {
+        let header_end = find(part, b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        headers.contains(&disposition).then(|| {
+            let content = &part[header_end + 4..];
+            content.strip_suffix(b"\r\n").unwrap_or(content)
+        })
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn split<'a>(mut haystack: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    while let Some(index) = find(haystack, delimiter) {
+        parts.push(&haystack[..index]);
+        haystack = &haystack[index + delimiter.len()..];
+    }
+    parts.push(haystack);
+    parts
+}
+
+// The files after `path` in its directory with the same extension, in name
+// order, which for a season folder are the next episodes
+pub fn next_episodes(path: &Path, candidates: &[PathBuf], count: usize) -> Vec<PathBuf> {
+    let mut next: Vec<&PathBuf> = candidates.iter()
+        .filter(|candidate| candidate.parent() == path.parent() && candidate.extension() == path.extension())
+        .filter(|candidate| candidate.as_path() > path)
+        .collect();
+    next.sort();
+    next.into_iter().take(count).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_event() {
+        let jellyfin = br#"{"NotificationType": "PlaybackStart", "ItemPath": "/media/tv/Show/S01E01.mkv"}"#;
+        assert_eq!(parse_event("application/json", jellyfin), Some(PlayEvent { state: PlayState::Started, file: "/media/tv/Show/S01E01.mkv".into() }));
+        let tautulli = br#"{"action": "stop", "file": "/media/a.mkv"}"#;
+        assert_eq!(parse_event("application/json", tautulli).unwrap().state, PlayState::Stopped);
+        assert_eq!(parse_event("application/json", br#"{"action": "pause", "file": "/media/a.mkv"}"#), None);
+
+        let plex = b"--abc\r\nContent-Disposition: form-data; name=\"payload\"\r\nContent-Type: application/json\r\n\r\n\
+            {\"event\": \"media.play\", \"Metadata\": {\"Media\": [{\"Part\": [{\"file\": \"/media/b.mkv\"}]}]}}\r\n\
+            --abc\r\nContent-Disposition: form-data; name=\"thumb\"\r\n\r\nJPEG\r\n--abc--\r\n";
+        let event = parse_event("multipart/form-data; boundary=abc", plex).unwrap();
+        assert_eq!(event.file, Path::new("/media/b.mkv"));
+    }
+
+    #[test]
+    fn test_read_request() {
+        let body = r#"{"action": "play"}"#;
+        let raw = format!("POST /hook HTTP/1.1\r\nHost: x\r\ncontent-type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let request = read_request(&mut io::Cursor::new(raw)).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.content_type, "application/json");
+        assert_eq!(request.body, body.as_bytes());
+        assert!(read_request(&mut io::Cursor::new("POST / HTTP/1.1\r\nContent-Length: 9\r\n")).is_err());
+    }
+
+    #[test]
+    fn test_paths() {
+        let server = MediaServer::from_config(&json!({ "media_server": { "roots": ["/media"] } })).unwrap();
+        assert_eq!(server.relative_path(Path::new("/media/tv/a.mkv")), Some(PathBuf::from("tv/a.mkv")));
+        assert_eq!(server.relative_path(Path::new("/mnt/merged/cold/tv/a.mkv")), Some(PathBuf::from("tv/a.mkv")));
+        assert_eq!(server.relative_path(Path::new("/srv/a.mkv")), None);
+        assert!(MediaServer::from_config(&json!({})).is_none());
+
+        let candidates: Vec<PathBuf> = ["tv/S1/e03.mkv", "tv/S1/e01.mkv", "tv/S1/e02.mkv", "tv/S1/e02.srt", "tv/S1/e04.mkv", "tv/S2/e01.mkv"]
+            .iter().map(PathBuf::from).collect();
+        assert_eq!(next_episodes(Path::new("tv/S1/e01.mkv"), &candidates, 2), [PathBuf::from("tv/S1/e02.mkv"), PathBuf::from("tv/S1/e03.mkv")]);
+        assert!(next_episodes(Path::new("tv/S1/e04.mkv"), &candidates, 2).is_empty());
+    }
+}
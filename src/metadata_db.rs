@@ -0,0 +1,1830 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde_json::Value;
+use crate::disk_stats::DiskIo;
+use crate::external_jobs::RunningJob;
+use crate::fault_injection::{FaultInjector, FaultPoint};
+use crate::file_metadata::{ArchivedFile, CrawlProgress, Drain, DrainState, Drive, DriveState, FailedMove, FileMetadata, FileMoveInfo, JournaledMove, LoopRun, Maintenance, MaintenanceState, PendingRetry, QuarantinedFile, SuspectDrive, TransferProgress};
+use crate::pins::Pin;
+use crate::placement::{Placement, PlacementPriority};
+use crate::project_quota::SubtreeUsage;
+use crate::staging::StagedSet;
+
+pub const DB_PATH: &str = "/etc/drive-manager/file_metadata.db";
+
+pub fn db_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+pub fn to_unix(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+pub fn from_unix(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+// File paths are stored as their raw bytes so names that are not valid
+// UTF-8 round-trip exactly
+fn path_key(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+fn path_from_row(row: &Row, index: usize) -> rusqlite::Result<PathBuf> {
+    Ok(PathBuf::from(OsString::from_vec(row.get(index)?)))
+}
+
+pub struct MetadataDb {
+    conn: Connection,
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl MetadataDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let db = Self { conn: Connection::open(path).map_err(db_error)?, faults: None };
+        db.setup_database()?;
+        Ok(db)
+    }
+
+    pub fn open_in_memory() -> io::Result<Self> {
+        let db = Self { conn: Connection::open_in_memory().map_err(db_error)?, faults: None };
+        db.setup_database()?;
+        Ok(db)
+    }
+
+    // Fail writes according to the injector's DbWrite schedule
+    pub fn inject_faults(&mut self, faults: Arc<FaultInjector>) {
+        self.faults = Some(faults);
+    }
+
+    fn check_write_fault(&self) -> io::Result<()> {
+        match self.faults.as_ref().and_then(|faults| faults.check(&FaultPoint::DbWrite)) {
+            Some(fault) => Err(fault.to_error()),
+            None => Ok(()),
+        }
+    }
+
+    fn setup_database(&self) -> io::Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS file_metadata (
+                file_path BLOB PRIMARY KEY,
+                last_access_time INTEGER NOT NULL,
+                access_count INTEGER NOT NULL,
+                last_tier_move INTEGER,
+                file_size INTEGER NOT NULL,
+                tier TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS file_metadata_tier ON file_metadata (tier, last_access_time);
+            CREATE TABLE IF NOT EXISTS failed_moves (
+                file_path BLOB PRIMARY KEY,
+                source_tier TEXT NOT NULL,
+                target_tier TEXT NOT NULL,
+                retries INTEGER NOT NULL,
+                error TEXT NOT NULL,
+                failed_at INTEGER NOT NULL,
+                retry_requested INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS pending_retries (
+                file_path BLOB PRIMARY KEY,
+                source_tier TEXT NOT NULL,
+                target_tier TEXT NOT NULL,
+                retries INTEGER NOT NULL,
+                due_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS move_errors (
+                file_path BLOB NOT NULL,
+                failed_at INTEGER NOT NULL,
+                error TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS move_errors_path ON move_errors (file_path, failed_at);
+            CREATE TABLE IF NOT EXISTS file_placement (
+                file_path BLOB PRIMARY KEY,
+                tier TEXT,
+                priority INTEGER NOT NULL DEFAULT 0,
+                exclude INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS file_branch (
+                file_path BLOB PRIMARY KEY,
+                serial TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS move_progress (
+                file_path BLOB PRIMARY KEY,
+                source_tier TEXT NOT NULL,
+                target_tier TEXT NOT NULL,
+                bytes_copied INTEGER NOT NULL,
+                bytes_total INTEGER NOT NULL,
+                started_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS move_journal (
+                file_path BLOB PRIMARY KEY,
+                source_tier TEXT NOT NULL,
+                target_tier TEXT NOT NULL,
+                retries INTEGER NOT NULL,
+                user INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                queued_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS drives (
+                serial TEXT PRIMARY KEY,
+                tier TEXT NOT NULL,
+                state TEXT NOT NULL,
+                first_seen INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                pooled_at INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS disk_io (
+                serial TEXT NOT NULL,
+                sampled_at INTEGER NOT NULL,
+                util REAL NOT NULL,
+                read_latency_ms REAL NOT NULL,
+                write_latency_ms REAL NOT NULL,
+                iops REAL NOT NULL,
+                bytes_written INTEGER NOT NULL DEFAULT 0,
+                read_bps REAL NOT NULL DEFAULT 0,
+                write_bps REAL NOT NULL DEFAULT 0,
+                PRIMARY KEY (serial, sampled_at)
+            );
+            CREATE TABLE IF NOT EXISTS drive_writes (
+                serial TEXT PRIMARY KEY,
+                bytes INTEGER NOT NULL,
+                since INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS subtree_usage (
+                subtree BLOB NOT NULL,
+                serial TEXT NOT NULL,
+                tier TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                sampled_at INTEGER NOT NULL,
+                PRIMARY KEY (subtree, serial)
+            );
+            CREATE TABLE IF NOT EXISTS check_requests (
+                requested_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS reload_requests (
+                requested_at INTEGER NOT NULL,
+                force INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS applied_config (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                config TEXT NOT NULL,
+                applied_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS lane_throughput (
+                source_tier TEXT NOT NULL,
+                target_tier TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                millis INTEGER NOT NULL,
+                PRIMARY KEY (source_tier, target_tier)
+            );
+            CREATE TABLE IF NOT EXISTS loop_runs (
+                name TEXT PRIMARY KEY,
+                started_at INTEGER NOT NULL,
+                running INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                error TEXT,
+                next_run INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS drains (
+                serial TEXT PRIMARY KEY,
+                requested_at INTEGER NOT NULL,
+                state TEXT NOT NULL,
+                files_left INTEGER NOT NULL,
+                error TEXT
+            );
+            CREATE TABLE IF NOT EXISTS maintenance (
+                serial TEXT PRIMARY KEY,
+                requested_at INTEGER NOT NULL,
+                state TEXT NOT NULL,
+                error TEXT
+            );
+            CREATE TABLE IF NOT EXISTS suspect_drives (
+                serial TEXT PRIMARY KEY,
+                errors INTEGER NOT NULL,
+                last_at INTEGER NOT NULL,
+                last_error TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS offline_branches (
+                serial TEXT PRIMARY KEY,
+                since INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS pins (
+                path BLOB PRIMARY KEY,
+                tier TEXT NOT NULL,
+                pinned_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS staged_files (
+                name TEXT NOT NULL,
+                file_path BLOB NOT NULL,
+                file_size INTEGER NOT NULL,
+                staged_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                PRIMARY KEY (name, file_path)
+            );
+            CREATE TABLE IF NOT EXISTS running_jobs (
+                name TEXT PRIMARY KEY,
+                started_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS waiting_jobs (
+                name TEXT PRIMARY KEY,
+                since INTEGER NOT NULL,
+                seen_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS archived_files (
+                file_path BLOB PRIMARY KEY,
+                url TEXT NOT NULL,
+                file_size INTEGER NOT NULL,
+                archived_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS quarantined_files (
+                file_path BLOB PRIMARY KEY,
+                quarantined_at INTEGER NOT NULL,
+                reason TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS crawls (
+                serial TEXT PRIMARY KEY,
+                started_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                finished_at INTEGER,
+                files INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                checksummed_bytes INTEGER NOT NULL,
+                cursor BLOB
+            );
+            CREATE TABLE IF NOT EXISTS file_checksums (
+                file_path BLOB PRIMARY KEY,
+                algorithm TEXT NOT NULL,
+                checksum TEXT NOT NULL,
+                file_size INTEGER NOT NULL,
+                taken_at INTEGER NOT NULL
+            );
+            -- databases from before paths were stored as bytes have text keys
+            UPDATE file_metadata SET file_path = CAST(file_path AS BLOB) WHERE typeof(file_path) = 'text';
+            UPDATE failed_moves SET file_path = CAST(file_path AS BLOB) WHERE typeof(file_path) = 'text';
+            UPDATE file_placement SET file_path = CAST(file_path AS BLOB) WHERE typeof(file_path) = 'text';
+            UPDATE pins SET path = CAST(path AS BLOB) WHERE typeof(path) = 'text';",
+        ).map_err(db_error)?;
+        // the drives tiering had seen were kept apart before the registry
+        let legacy: i64 = self.conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'known_branches'", [], |row| row.get(0)).map_err(db_error)?;
+        if legacy > 0 {
+            self.conn.execute_batch(
+                "BEGIN;
+                INSERT OR IGNORE INTO drives (serial, tier, state, first_seen, last_seen, pooled_at)
+                    SELECT serial, tier, 'active', first_seen, first_seen, first_seen FROM known_branches;
+                DROP TABLE known_branches;
+                COMMIT;",
+            ).map_err(db_error)?;
+        }
+        // disk_io from before writes were counted
+        let counted: i64 = self.conn.query_row("SELECT COUNT(*) FROM pragma_table_info('disk_io') WHERE name = 'bytes_written'", [], |row| row.get(0)).map_err(db_error)?;
+        if counted == 0 {
+            self.conn.execute_batch("ALTER TABLE disk_io ADD COLUMN bytes_written INTEGER NOT NULL DEFAULT 0").map_err(db_error)?;
+        }
+        // and before throughput was
+        let rated: i64 = self.conn.query_row("SELECT COUNT(*) FROM pragma_table_info('disk_io') WHERE name = 'read_bps'", [], |row| row.get(0)).map_err(db_error)?;
+        if rated == 0 {
+            self.conn.execute_batch(
+                "ALTER TABLE disk_io ADD COLUMN read_bps REAL NOT NULL DEFAULT 0;
+                 ALTER TABLE disk_io ADD COLUMN write_bps REAL NOT NULL DEFAULT 0;",
+            ).map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    // Run a batch of statements atomically; scans touch every row and are far
+    // cheaper in a single transaction.
+    pub fn transaction<T>(&self, f: impl FnOnce(&Self) -> io::Result<T>) -> io::Result<T> {
+        self.conn.execute_batch("BEGIN").map_err(db_error)?;
+        match f(self) {
+            Ok(value) => {
+                self.conn.execute_batch("COMMIT").map_err(db_error)?;
+                Ok(value)
+            }
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+
+    fn row_to_metadata(row: &Row) -> rusqlite::Result<(PathBuf, FileMetadata)> {
+        Ok((path_from_row(row, 0)?, FileMetadata {
+            last_access_time: from_unix(row.get(1)?),
+            access_count: row.get::<_, i64>(2)? as u64,
+            last_tier_move: row.get::<_, Option<i64>>(3)?.map(from_unix),
+            file_size: row.get::<_, i64>(4)? as u64,
+            tier: row.get(5)?,
+        }))
+    }
+
+    // Get everything committed into the main database file, for when power
+    // may be lost. Only a database in WAL mode has anything left to write.
+    pub fn flush(&self) -> io::Result<()> {
+        self.conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(())).map_err(db_error)
+    }
+
+    // What PRAGMA integrity_check finds wrong, or just "ok"
+    pub fn integrity_check(&self) -> io::Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| row.get(0)).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Whether some other connection keeps the write lock for all of `wait`
+    pub fn write_locked(&self, wait: Duration) -> io::Result<bool> {
+        self.conn.busy_timeout(wait).map_err(db_error)?;
+        match self.conn.execute_batch("BEGIN IMMEDIATE; ROLLBACK") {
+            Ok(()) => Ok(false),
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::DatabaseBusy => Ok(true),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, file_path: P) -> io::Result<Option<FileMetadata>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT file_path, last_access_time, access_count, last_tier_move, file_size, tier FROM file_metadata WHERE file_path = ?1",
+        ).map_err(db_error)?;
+        stmt.query_row(params![path_key(file_path.as_ref())], Self::row_to_metadata).optional().map(|row| row.map(|(_, metadata)| metadata)).map_err(db_error)
+    }
+
+    pub fn insert<P: AsRef<Path>>(&self, file_path: P, metadata: &FileMetadata) -> io::Result<()> {
+        self.check_write_fault()?;
+        let mut stmt = self.conn.prepare_cached(
+            "INSERT OR REPLACE INTO file_metadata (file_path, last_access_time, access_count, last_tier_move, file_size, tier)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        ).map_err(db_error)?;
+        stmt.execute(params![
+                path_key(file_path.as_ref()),
+                to_unix(metadata.last_access_time),
+                metadata.access_count as i64,
+                metadata.last_tier_move.map(to_unix),
+                metadata.file_size as i64,
+                metadata.tier,
+            ]).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn remove<P: AsRef<Path>>(&self, file_path: P) -> io::Result<()> {
+        self.check_write_fault()?;
+        let key = path_key(file_path.as_ref());
+        self.conn.execute("DELETE FROM file_metadata WHERE file_path = ?1", params![key]).map_err(db_error)?;
+        self.conn.execute("DELETE FROM file_placement WHERE file_path = ?1", params![key]).map_err(db_error)?;
+        self.conn.execute("DELETE FROM file_checksums WHERE file_path = ?1", params![key]).map_err(db_error)?;
+        self.conn.execute("DELETE FROM file_branch WHERE file_path = ?1", params![key]).map(|_| ()).map_err(db_error)
+    }
+
+    // Carry everything recorded about `from` over to `to`, replacing what
+    // `to` had. Nothing changes when `from` is not tracked.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        self.check_write_fault()?;
+        let (from, to) = (path_key(from.as_ref()), path_key(to.as_ref()));
+        for table in ["file_metadata", "file_placement", "file_branch", "failed_moves", "pending_retries", "quarantined_files", "file_checksums"] {
+            self.conn.execute(&format!("UPDATE OR REPLACE {} SET file_path = ?2 WHERE file_path = ?1", table), params![from, to]).map_err(db_error)?;
+        }
+        self.conn.execute(
+            "DELETE FROM move_errors WHERE file_path = ?2 AND EXISTS (SELECT 1 FROM move_errors WHERE file_path = ?1)",
+            params![from, to],
+        ).map_err(db_error)?;
+        self.conn.execute("UPDATE move_errors SET file_path = ?2 WHERE file_path = ?1", params![from, to]).map(|_| ()).map_err(db_error)
+    }
+
+    // The serial of the branch each file was last seen on
+    pub fn branches(&self) -> io::Result<HashMap<PathBuf, String>> {
+        let mut stmt = self.conn.prepare("SELECT file_path, serial FROM file_branch").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((path_from_row(row, 0)?, row.get(1)?))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<HashMap<_, _>>>().map_err(db_error)
+    }
+
+    pub fn set_branch<P: AsRef<Path>>(&self, file_path: P, serial: &str) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO file_branch (file_path, serial) VALUES (?1, ?2)",
+            params![path_key(file_path.as_ref()), serial],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Bytes of tracked files on each branch, by serial
+    pub fn branch_bytes(&self) -> io::Result<Vec<(String, u64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT b.serial, SUM(m.file_size) FROM file_branch b JOIN file_metadata m ON m.file_path = b.file_path GROUP BY b.serial ORDER BY b.serial",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Files and bytes tracked in each tier
+    pub fn tier_totals(&self) -> io::Result<Vec<(String, u64, u64)>> {
+        let mut stmt = self.conn.prepare("SELECT tier, COUNT(*), SUM(file_size) FROM file_metadata GROUP BY tier").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as u64))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Only files with tags have a row
+    pub fn set_placement<P: AsRef<Path>>(&self, file_path: P, placement: &Placement) -> io::Result<()> {
+        self.check_write_fault()?;
+        let key = path_key(file_path.as_ref());
+        if placement.is_default() {
+            return self.conn.execute("DELETE FROM file_placement WHERE file_path = ?1", params![key]).map(|_| ()).map_err(db_error);
+        }
+        self.conn.execute(
+            "INSERT OR REPLACE INTO file_placement (file_path, tier, priority, exclude) VALUES (?1, ?2, ?3, ?4)",
+            params![key, placement.tier, placement.priority as i64, placement.exclude],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn placements(&self) -> io::Result<HashMap<PathBuf, Placement>> {
+        let mut stmt = self.conn.prepare("SELECT file_path, tier, priority, exclude FROM file_placement").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((path_from_row(row, 0)?, Placement {
+            tier: row.get(1)?,
+            priority: PlacementPriority::from_i64(row.get(2)?),
+            exclude: row.get(3)?,
+        }))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<HashMap<_, _>>>().map_err(db_error)
+    }
+
+    pub fn placement<P: AsRef<Path>>(&self, file_path: P) -> io::Result<Placement> {
+        self.conn.query_row(
+            "SELECT tier, priority, exclude FROM file_placement WHERE file_path = ?1",
+            params![path_key(file_path.as_ref())],
+            |row| Ok(Placement { tier: row.get(0)?, priority: PlacementPriority::from_i64(row.get(1)?), exclude: row.get(2)? }),
+        ).optional().map(Option::unwrap_or_default).map_err(db_error)
+    }
+
+    pub fn entries(&self) -> io::Result<Vec<(PathBuf, FileMetadata)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT file_path, last_access_time, access_count, last_tier_move, file_size, tier FROM file_metadata ORDER BY file_path",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], Self::row_to_metadata).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Files anywhere below `dir`, which is a branch-relative directory
+    pub fn entries_under(&self, dir: &Path) -> io::Result<Vec<(PathBuf, FileMetadata)>> {
+        if dir.as_os_str().is_empty() {
+            return self.entries();
+        }
+        // keys in [dir + "/", dir + "0") share the prefix, as '0' follows '/'
+        let (mut low, mut high) = (path_key(dir).to_vec(), path_key(dir).to_vec());
+        low.push(b'/');
+        high.push(b'0');
+        let mut stmt = self.conn.prepare(
+            "SELECT file_path, last_access_time, access_count, last_tier_move, file_size, tier FROM file_metadata
+             WHERE file_path >= ?1 AND file_path < ?2 ORDER BY file_path",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map(params![low, high], Self::row_to_metadata).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn record_failure(&self, failure: &FailedMove) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO failed_moves (file_path, source_tier, target_tier, retries, error, failed_at, retry_requested)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0)",
+            params![
+                path_key(&failure.info.src),
+                failure.info.source_tier,
+                failure.info.target_tier,
+                failure.info.retries,
+                failure.error,
+                to_unix(failure.failed_at),
+            ],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    fn row_to_failure(row: &Row) -> rusqlite::Result<FailedMove> {
+        Ok(FailedMove {
+            info: FileMoveInfo { src: path_from_row(row, 0)?, source_tier: row.get(1)?, target_tier: row.get(2)?, retries: row.get(3)? },
+            error: row.get(4)?,
+            failed_at: from_unix(row.get(5)?),
+        })
+    }
+
+    // Dead-lettered moves, oldest first
+    pub fn failures(&self) -> io::Result<Vec<FailedMove>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT file_path, source_tier, target_tier, retries, error, failed_at FROM failed_moves ORDER BY failed_at, file_path",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], Self::row_to_failure).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Flag every dead-lettered move for another attempt. The running service
+    // picks them up with take_retry_requests(). Returns how many were flagged.
+    pub fn request_retry_all(&self) -> io::Result<usize> {
+        self.check_write_fault()?;
+        self.conn.execute("UPDATE failed_moves SET retry_requested = 1", []).map_err(db_error)
+    }
+
+    pub fn take_retry_requests(&self) -> io::Result<Vec<FailedMove>> {
+        self.transaction(|db| {
+            let mut stmt = db.conn.prepare(
+                "SELECT file_path, source_tier, target_tier, retries, error, failed_at FROM failed_moves WHERE retry_requested = 1 ORDER BY failed_at, file_path",
+            ).map_err(db_error)?;
+            let failures = stmt.query_map([], Self::row_to_failure).map_err(db_error)?.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)?;
+            db.conn.execute("DELETE FROM failed_moves WHERE retry_requested = 1", []).map_err(db_error)?;
+            Ok(failures)
+        })
+    }
+
+    // Failed moves waiting out their backoff, kept so a restart picks them
+    // up again instead of forgetting them
+    pub fn schedule_retry(&self, retry: &PendingRetry) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO pending_retries (file_path, source_tier, target_tier, retries, due_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![path_key(&retry.info.src), retry.info.source_tier, retry.info.target_tier, retry.info.retries, to_unix(retry.due_at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Soonest due first
+    pub fn pending_retries(&self) -> io::Result<Vec<PendingRetry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT file_path, source_tier, target_tier, retries, due_at FROM pending_retries ORDER BY due_at, file_path",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(PendingRetry {
+            info: FileMoveInfo { src: path_from_row(row, 0)?, source_tier: row.get(1)?, target_tier: row.get(2)?, retries: row.get(3)? },
+            due_at: from_unix(row.get(4)?),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn is_retry_pending(&self, file_path: &Path) -> io::Result<bool> {
+        self.conn.query_row("SELECT 1 FROM pending_retries WHERE file_path = ?1", params![path_key(file_path)], |_| Ok(()))
+            .optional().map(|row| row.is_some()).map_err(db_error)
+    }
+
+    pub fn remove_pending_retry(&self, file_path: &Path) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM pending_retries WHERE file_path = ?1", params![path_key(file_path)]).map(|_| ()).map_err(db_error)
+    }
+
+    // One line of a move's error history, kept until the move succeeds or
+    // is dropped
+    pub fn record_move_error(&self, file_path: &Path, failed_at: SystemTime, error: &str) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT INTO move_errors (file_path, failed_at, error) VALUES (?1, ?2, ?3)",
+            params![path_key(file_path), to_unix(failed_at), error],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Oldest first
+    pub fn move_errors(&self, file_path: &Path) -> io::Result<Vec<(SystemTime, String)>> {
+        let mut stmt = self.conn.prepare("SELECT failed_at, error FROM move_errors WHERE file_path = ?1 ORDER BY failed_at, rowid").map_err(db_error)?;
+        let rows = stmt.query_map(params![path_key(file_path)], |row| Ok((from_unix(row.get(0)?), row.get(1)?))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Forget a move that went through after failing before
+    pub fn clear_move_errors(&self, file_path: &Path) -> io::Result<()> {
+        self.check_write_fault()?;
+        let key = path_key(file_path);
+        self.conn.execute("DELETE FROM pending_retries WHERE file_path = ?1", params![key]).map_err(db_error)?;
+        self.conn.execute("DELETE FROM move_errors WHERE file_path = ?1", params![key]).map(|_| ()).map_err(db_error)
+    }
+
+    // Give up on a move, whether it is waiting to be retried or dead-lettered,
+    // for `drive-manager failures drop`. Returns whether there was one.
+    pub fn drop_move(&self, file_path: &Path) -> io::Result<bool> {
+        self.check_write_fault()?;
+        let key = path_key(file_path);
+        self.transaction(|db| {
+            let mut dropped = 0;
+            for table in ["failed_moves", "pending_retries"] {
+                dropped += db.conn.execute(&format!("DELETE FROM {} WHERE file_path = ?1", table), params![key]).map_err(db_error)?;
+            }
+            db.conn.execute("DELETE FROM move_errors WHERE file_path = ?1", params![key]).map_err(db_error)?;
+            Ok(dropped > 0)
+        })
+    }
+
+    // Written by the running service so `drive-manager status` can show
+    // moves that are still copying
+    pub fn record_progress(&self, progress: &TransferProgress) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO move_progress (file_path, source_tier, target_tier, bytes_copied, bytes_total, started_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                path_key(&progress.info.src),
+                progress.info.source_tier,
+                progress.info.target_tier,
+                progress.bytes_copied as i64,
+                progress.bytes_total as i64,
+                to_unix(progress.started_at),
+                to_unix(progress.updated_at),
+            ],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn remove_progress(&self, file_path: &Path) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM move_progress WHERE file_path = ?1", params![path_key(file_path)]).map(|_| ()).map_err(db_error)
+    }
+
+    // Rows left behind by a service that did not shut down cleanly
+    pub fn clear_progress(&self) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM move_progress", []).map(|_| ()).map_err(db_error)
+    }
+
+    // Written before a move is queued and removed once it is done, retried
+    // later or given up on, so the moves a crash cut off can be picked up
+    pub fn journal_move(&self, journaled: &JournaledMove) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO move_journal (file_path, source_tier, target_tier, retries, user, size, queued_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                path_key(&journaled.info.src),
+                journaled.info.source_tier,
+                journaled.info.target_tier,
+                journaled.info.retries,
+                journaled.user,
+                journaled.size as i64,
+                to_unix(journaled.queued_at),
+            ],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn remove_journaled_move(&self, file_path: &Path) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM move_journal WHERE file_path = ?1", params![path_key(file_path)]).map(|_| ()).map_err(db_error)
+    }
+
+    // Oldest first
+    pub fn journaled_moves(&self) -> io::Result<Vec<JournaledMove>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT file_path, source_tier, target_tier, retries, user, size, queued_at FROM move_journal ORDER BY queued_at, file_path",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(JournaledMove {
+            info: FileMoveInfo { src: path_from_row(row, 0)?, source_tier: row.get(1)?, target_tier: row.get(2)?, retries: row.get(3)? },
+            user: row.get(4)?,
+            size: row.get::<_, i64>(5)? as u64,
+            queued_at: from_unix(row.get(6)?),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn clear_journal(&self) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM move_journal", []).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn transfers(&self) -> io::Result<Vec<TransferProgress>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT file_path, source_tier, target_tier, bytes_copied, bytes_total, started_at, updated_at FROM move_progress ORDER BY started_at, file_path",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(TransferProgress {
+            info: FileMoveInfo { src: path_from_row(row, 0)?, source_tier: row.get(1)?, target_tier: row.get(2)?, retries: 0 },
+            bytes_copied: row.get::<_, i64>(3)? as u64,
+            bytes_total: row.get::<_, i64>(4)? as u64,
+            started_at: from_unix(row.get(5)?),
+            updated_at: from_unix(row.get(6)?),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Serials of every branch tiering has seen in the pools
+    pub fn known_branches(&self) -> io::Result<HashSet<String>> {
+        let mut stmt = self.conn.prepare("SELECT serial FROM drives WHERE pooled_at IS NOT NULL").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| row.get(0)).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<HashSet<_>>>().map_err(db_error)
+    }
+
+    // The tier each registered drive was given, by serial
+    pub fn branch_tiers(&self) -> io::Result<HashMap<String, String>> {
+        let mut stmt = self.conn.prepare("SELECT serial, tier FROM drives").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<HashMap<_, _>>>().map_err(db_error)
+    }
+
+    // Note that tiering found the branch in the pools, registering the
+    // drive if it was not already
+    pub fn add_known_branch(&self, serial: &str, tier: &str, first_seen: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT INTO drives (serial, tier, state, first_seen, last_seen, pooled_at) VALUES (?1, ?2, ?3, ?4, ?4, ?4)
+             ON CONFLICT (serial) DO UPDATE SET pooled_at = COALESCE(pooled_at, excluded.pooled_at)",
+            params![serial, tier, DriveState::Active.as_str(), to_unix(first_seen)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Record a drive set up at `seen`, with the tier it gets the first
+    // time. One that was missing is active again; a quarantined or retired
+    // one stays so. Returns the drive as registered.
+    pub fn register_drive(&self, serial: &str, tier: &str, seen: SystemTime) -> io::Result<Drive> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT INTO drives (serial, tier, state, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT (serial) DO UPDATE SET last_seen = excluded.last_seen,
+                 state = CASE WHEN state = 'missing' THEN excluded.state ELSE state END",
+            params![serial, tier, DriveState::Active.as_str(), to_unix(seen)],
+        ).map_err(db_error)?;
+        self.drive(serial)?.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} was not registered", serial)))
+    }
+
+    // Give a registered drive another tier, as adopting one does
+    pub fn set_drive_tier(&self, serial: &str, tier: &str) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("UPDATE drives SET tier = ?2 WHERE serial = ?1", params![serial, tier]).map(|_| ()).map_err(db_error)
+    }
+
+    // Move a drive to `state` if it is in one of `from`. Returns whether it was.
+    pub fn set_drive_state(&self, serial: &str, from: &[DriveState], state: DriveState) -> io::Result<bool> {
+        self.check_write_fault()?;
+        let from: Vec<&str> = from.iter().map(|state| state.as_str()).collect();
+        let changed = self.conn.execute(
+            "UPDATE drives SET state = ?2 WHERE serial = ?1 AND state IN (SELECT value FROM json_each(?3))",
+            params![serial, state.as_str(), serde_json::to_string(&from).unwrap()],
+        ).map_err(db_error)?;
+        Ok(changed > 0)
+    }
+
+    fn row_to_drive(row: &Row) -> rusqlite::Result<Drive> {
+        Ok(Drive {
+            serial: row.get(0)?,
+            tier: row.get(1)?,
+            state: DriveState::parse(&row.get::<_, String>(2)?).unwrap_or(DriveState::Missing),
+            first_seen: from_unix(row.get(3)?),
+            last_seen: from_unix(row.get(4)?),
+            pooled_at: row.get::<_, Option<i64>>(5)?.map(from_unix),
+        })
+    }
+
+    pub fn drive(&self, serial: &str) -> io::Result<Option<Drive>> {
+        self.conn.query_row(
+            "SELECT serial, tier, state, first_seen, last_seen, pooled_at FROM drives WHERE serial = ?1",
+            params![serial],
+            Self::row_to_drive,
+        ).optional().map_err(db_error)
+    }
+
+    // Every drive ever set up, by tier then serial
+    pub fn drives(&self) -> io::Result<Vec<Drive>> {
+        let mut stmt = self.conn.prepare("SELECT serial, tier, state, first_seen, last_seen, pooled_at FROM drives ORDER BY tier, serial").map_err(db_error)?;
+        let rows = stmt.query_map([], Self::row_to_drive).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Put a quarantined or retired drive back to use, forgetting the I/O
+    // errors or drain that put it there. Returns whether it was either.
+    pub fn reset_drive(&self, serial: &str) -> io::Result<bool> {
+        self.transaction(|db| {
+            db.conn.execute("DELETE FROM suspect_drives WHERE serial = ?1", params![serial]).map_err(db_error)?;
+            db.conn.execute("DELETE FROM drains WHERE serial = ?1 AND state = ?2", params![serial, DrainState::Done.as_str()]).map_err(db_error)?;
+            db.set_drive_state(serial, &[DriveState::Quarantined, DriveState::Retired], DriveState::Active)
+        })
+    }
+
+    // A branch that went missing, with when it was first missed. Its files
+    // stay tracked, as unavailable, until it is back. Returns whether it
+    // was online until now.
+    pub fn set_branch_offline(&self, serial: &str, since: SystemTime) -> io::Result<bool> {
+        self.check_write_fault()?;
+        let offline = self.conn.execute(
+            "INSERT OR IGNORE INTO offline_branches (serial, since) VALUES (?1, ?2)",
+            params![serial, to_unix(since)],
+        ).map_err(db_error)?;
+        self.set_drive_state(serial, &[DriveState::Active], DriveState::Missing)?;
+        Ok(offline > 0)
+    }
+
+    // Whether the branch was offline until now
+    pub fn set_branch_online(&self, serial: &str) -> io::Result<bool> {
+        self.check_write_fault()?;
+        let online = self.conn.execute("DELETE FROM offline_branches WHERE serial = ?1", params![serial]).map_err(db_error)?;
+        self.set_drive_state(serial, &[DriveState::Missing], DriveState::Active)?;
+        Ok(online > 0)
+    }
+
+    pub fn offline_branches(&self) -> io::Result<Vec<(String, SystemTime)>> {
+        let mut stmt = self.conn.prepare("SELECT serial, since FROM offline_branches ORDER BY serial").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, from_unix(row.get(1)?)))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Tracked files last seen on a branch that is offline
+    pub fn unavailable_files(&self) -> io::Result<HashSet<PathBuf>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT b.file_path FROM file_branch b JOIN offline_branches o ON o.serial = b.serial",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], |row| path_from_row(row, 0)).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<HashSet<_>>>().map_err(db_error)
+    }
+
+    // Keep a sample, adding what the drive wrote to its running total
+    pub fn record_disk_io(&self, serial: &str, sampled_at: SystemTime, io: &DiskIo) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO disk_io (serial, sampled_at, util, read_latency_ms, write_latency_ms, iops, bytes_written, read_bps, write_bps) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![serial, to_unix(sampled_at), io.util, io.read_latency_ms, io.write_latency_ms, io.iops, io.bytes_written as i64, io.read_bps, io.write_bps],
+        ).map_err(db_error)?;
+        self.conn.execute(
+            "INSERT INTO drive_writes (serial, bytes, since) VALUES (?1, ?2, ?3)
+             ON CONFLICT (serial) DO UPDATE SET bytes = bytes + excluded.bytes",
+            params![serial, io.bytes_written as i64, to_unix(sampled_at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // What each drive wrote in the samples from `since` on, by serial
+    pub fn bytes_written_since(&self, since: SystemTime) -> io::Result<HashMap<String, u64>> {
+        let mut stmt = self.conn.prepare("SELECT serial, SUM(bytes_written) FROM disk_io WHERE sampled_at >= ?1 GROUP BY serial").map_err(db_error)?;
+        let rows = stmt.query_map(params![to_unix(since)], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<HashMap<_, _>>>().map_err(db_error)
+    }
+
+    // What each drive has written since it was first sampled, as (serial,
+    // bytes, since), by serial
+    pub fn drive_writes(&self) -> io::Result<Vec<(String, u64, SystemTime)>> {
+        let mut stmt = self.conn.prepare("SELECT serial, bytes, since FROM drive_writes ORDER BY serial").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, from_unix(row.get(2)?)))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    fn row_to_disk_io(row: &Row) -> rusqlite::Result<(String, SystemTime, DiskIo)> {
+        Ok((row.get(0)?, from_unix(row.get(1)?), DiskIo {
+            util: row.get(2)?,
+            read_latency_ms: row.get(3)?,
+            write_latency_ms: row.get(4)?,
+            iops: row.get(5)?,
+            bytes_written: row.get::<_, i64>(6)? as u64,
+            read_bps: row.get(7)?,
+            write_bps: row.get(8)?,
+        }))
+    }
+
+    // A drive's samples from `since` on, oldest first
+    pub fn disk_io_history(&self, serial: &str, since: SystemTime) -> io::Result<Vec<(SystemTime, DiskIo)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT serial, sampled_at, util, read_latency_ms, write_latency_ms, iops, bytes_written, read_bps, write_bps FROM disk_io WHERE serial = ?1 AND sampled_at >= ?2 ORDER BY sampled_at",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map(params![serial, to_unix(since)], |row| Self::row_to_disk_io(row).map(|(_, at, io)| (at, io))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Every drive's samples from `since` on, as (serial, sampled at, I/O)
+    pub fn disk_io_since(&self, since: SystemTime) -> io::Result<Vec<(String, SystemTime, DiskIo)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT serial, sampled_at, util, read_latency_ms, write_latency_ms, iops, bytes_written, read_bps, write_bps FROM disk_io WHERE sampled_at >= ?1 ORDER BY sampled_at, serial",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map(params![to_unix(since)], Self::row_to_disk_io).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // The most recent sample of each drive, by serial
+    pub fn latest_disk_io(&self) -> io::Result<Vec<(String, SystemTime, DiskIo)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT serial, sampled_at, util, read_latency_ms, write_latency_ms, iops, bytes_written, read_bps, write_bps FROM disk_io d
+             WHERE sampled_at = (SELECT MAX(sampled_at) FROM disk_io WHERE serial = d.serial) ORDER BY serial",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], Self::row_to_disk_io).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn prune_disk_io(&self, before: SystemTime) -> io::Result<usize> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM disk_io WHERE sampled_at < ?1", params![to_unix(before)]).map_err(db_error)
+    }
+
+    // Replaces the last report of what each quota subtree takes
+    pub fn record_subtree_usage(&self, usage: &[SubtreeUsage], sampled_at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.transaction(|db| {
+            db.conn.execute("DELETE FROM subtree_usage", []).map_err(db_error)?;
+            for subtree in usage {
+                db.conn.execute(
+                    "INSERT INTO subtree_usage (subtree, serial, tier, bytes, sampled_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![path_key(&subtree.subtree), subtree.serial, subtree.tier, subtree.bytes as i64, to_unix(sampled_at)],
+                ).map_err(db_error)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn subtree_usage(&self) -> io::Result<Vec<(SubtreeUsage, SystemTime)>> {
+        let mut stmt = self.conn.prepare("SELECT subtree, serial, tier, bytes, sampled_at FROM subtree_usage ORDER BY subtree, serial").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((SubtreeUsage {
+            subtree: path_from_row(row, 0)?,
+            serial: row.get(1)?,
+            tier: row.get(2)?,
+            bytes: row.get::<_, i64>(3)? as u64,
+        }, from_unix(row.get(4)?)))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Ask the running service for a tiering check, from `drive-manager check-now`
+    pub fn request_check(&self, requested_at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("INSERT INTO check_requests (requested_at) VALUES (?1)", params![to_unix(requested_at)]).map(|_| ()).map_err(db_error)
+    }
+
+    // Whether a check was asked for since the last call
+    pub fn take_check_request(&self) -> io::Result<bool> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM check_requests", []).map(|count| count > 0).map_err(db_error)
+    }
+
+    pub fn request_reload(&self, requested_at: SystemTime, force: bool) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("INSERT INTO reload_requests (requested_at, force) VALUES (?1, ?2)", params![to_unix(requested_at), force]).map(|_| ()).map_err(db_error)
+    }
+
+    // Whether a reload was asked for since the last call, and if so whether
+    // any of the requests was forced
+    pub fn take_reload_request(&self) -> io::Result<Option<bool>> {
+        self.check_write_fault()?;
+        self.transaction(|db| {
+            let force: Option<bool> = db.conn.query_row("SELECT MAX(force) FROM reload_requests", [], |row| row.get(0)).map_err(db_error)?;
+            db.conn.execute("DELETE FROM reload_requests", []).map_err(db_error)?;
+            Ok(force)
+        })
+    }
+
+    // The config the service is running with, as written, secrets unresolved
+    pub fn record_applied_config(&self, config: &Value, applied_at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO applied_config (id, config, applied_at) VALUES (0, ?1, ?2)",
+            params![config.to_string(), to_unix(applied_at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn applied_config(&self) -> io::Result<Option<Value>> {
+        let config: Option<String> = self.conn.query_row("SELECT config FROM applied_config", [], |row| row.get(0)).optional().map_err(db_error)?;
+        config.map(|config| serde_json::from_str(&config).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))).transpose()
+    }
+
+    // Count a finished move towards its lane's throughput
+    pub fn record_throughput(&self, source_tier: &str, target_tier: &str, bytes: u64, took: Duration) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT INTO lane_throughput (source_tier, target_tier, bytes, millis) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (source_tier, target_tier) DO UPDATE SET bytes = bytes + excluded.bytes, millis = millis + excluded.millis",
+            params![source_tier, target_tier, bytes as i64, took.as_millis() as i64],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Bytes per second each lane has moved at, over every move recorded
+    pub fn lane_rates(&self) -> io::Result<HashMap<(String, String), f64>> {
+        let mut stmt = self.conn.prepare("SELECT source_tier, target_tier, bytes, millis FROM lane_throughput WHERE millis > 0").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| {
+            Ok(((row.get(0)?, row.get(1)?), row.get::<_, i64>(2)? as f64 * 1000.0 / row.get::<_, i64>(3)? as f64))
+        }).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<HashMap<_, _>>>().map_err(db_error)
+    }
+
+    // A pass of the background loop `name` has started; what the last one
+    // did is kept until it finishes
+    pub fn start_loop_run(&self, name: &str, started_at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT INTO loop_runs (name, started_at, running, duration_ms) VALUES (?1, ?2, 1, 0)
+             ON CONFLICT (name) DO UPDATE SET started_at = excluded.started_at, running = 1",
+            params![name, to_unix(started_at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn finish_loop_run(&self, name: &str, duration: Duration, error: Option<&str>, next_run: Option<SystemTime>) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "UPDATE loop_runs SET running = 0, duration_ms = ?2, error = ?3, next_run = ?4 WHERE name = ?1",
+            params![name, duration.as_millis() as i64, error, next_run.map(to_unix)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn loop_runs(&self) -> io::Result<Vec<LoopRun>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, started_at, running, duration_ms, error, next_run FROM loop_runs ORDER BY name",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(LoopRun {
+            name: row.get(0)?,
+            started_at: from_unix(row.get(1)?),
+            running: row.get(2)?,
+            duration: Duration::from_millis(row.get::<_, i64>(3)? as u64),
+            error: row.get(4)?,
+            next_run: row.get::<_, Option<i64>>(5)?.map(from_unix),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Ask the service to drain a drive, starting over if it was drained before
+    pub fn request_drain(&self, serial: &str, requested_at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO drains (serial, requested_at, state, files_left) VALUES (?1, ?2, ?3, 0)",
+            params![serial, to_unix(requested_at), DrainState::Requested.as_str()],
+        ).map_err(db_error)?;
+        self.set_drive_state(serial, &[DriveState::Active, DriveState::Quarantined], DriveState::Evacuating).map(|_| ())
+    }
+
+    pub fn update_drain(&self, serial: &str, state: DrainState, files_left: u64, error: Option<&str>) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "UPDATE drains SET state = ?2, files_left = ?3, error = ?4 WHERE serial = ?1",
+            params![serial, state.as_str(), files_left as i64, error],
+        ).map_err(db_error)?;
+        match state {
+            DrainState::Done => self.set_drive_state(serial, &[DriveState::Evacuating], DriveState::Retired).map(|_| ()),
+            // a failed drain leaves the drive in use
+            DrainState::Failed => self.set_drive_state(serial, &[DriveState::Evacuating], DriveState::Active).map(|_| ()),
+            DrainState::Requested | DrainState::Moving => Ok(()),
+        }
+    }
+
+    pub fn drains(&self) -> io::Result<Vec<Drain>> {
+        let mut stmt = self.conn.prepare("SELECT serial, requested_at, state, files_left, error FROM drains ORDER BY requested_at, serial").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(Drain {
+            serial: row.get(0)?,
+            requested_at: from_unix(row.get(1)?),
+            state: DrainState::parse(&row.get::<_, String>(2)?).unwrap_or(DrainState::Failed),
+            files_left: row.get::<_, i64>(3)? as u64,
+            error: row.get(4)?,
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Ask the service to take a drive out for maintenance, or to bring it
+    // back. Bringing back a drive that is not out returns false.
+    pub fn request_maintenance(&self, serial: &str, enable: bool, requested_at: SystemTime) -> io::Result<bool> {
+        self.check_write_fault()?;
+        let changed = if enable {
+            self.conn.execute(
+                "INSERT OR REPLACE INTO maintenance (serial, requested_at, state) VALUES (?1, ?2, ?3)",
+                params![serial, to_unix(requested_at), MaintenanceState::Requested.as_str()],
+            )
+        } else {
+            self.conn.execute(
+                "UPDATE maintenance SET requested_at = ?2, state = ?3, error = NULL WHERE serial = ?1",
+                params![serial, to_unix(requested_at), MaintenanceState::Ending.as_str()],
+            )
+        };
+        changed.map(|count| count > 0).map_err(db_error)
+    }
+
+    pub fn update_maintenance(&self, serial: &str, state: MaintenanceState, error: Option<&str>) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "UPDATE maintenance SET state = ?2, error = ?3 WHERE serial = ?1",
+            params![serial, state.as_str(), error],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // The drive is back in the pools
+    pub fn end_maintenance(&self, serial: &str) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM maintenance WHERE serial = ?1", params![serial]).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn maintenance(&self) -> io::Result<Vec<Maintenance>> {
+        let mut stmt = self.conn.prepare("SELECT serial, requested_at, state, error FROM maintenance ORDER BY requested_at, serial").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(Maintenance {
+            serial: row.get(0)?,
+            requested_at: from_unix(row.get(1)?),
+            state: MaintenanceState::parse(&row.get::<_, String>(2)?).unwrap_or(MaintenanceState::Failed),
+            error: row.get(3)?,
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn record_io_error(&self, serial: &str, at: SystemTime, error: &str) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT INTO suspect_drives (serial, errors, last_at, last_error) VALUES (?1, 1, ?2, ?3)
+             ON CONFLICT (serial) DO UPDATE SET errors = errors + 1, last_at = excluded.last_at, last_error = excluded.last_error",
+            params![serial, to_unix(at), error],
+        ).map_err(db_error)?;
+        self.set_drive_state(serial, &[DriveState::Active], DriveState::Quarantined).map(|_| ())
+    }
+
+    // Pins made with `drive-manager pin`, on top of the config's
+    pub fn add_pin(&self, pin: &Pin, at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO pins (path, tier, pinned_at) VALUES (?1, ?2, ?3)",
+            params![path_key(&pin.path), pin.tier, to_unix(at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn remove_pin<P: AsRef<Path>>(&self, path: P) -> io::Result<bool> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM pins WHERE path = ?1", params![path_key(path.as_ref())]).map(|removed| removed > 0).map_err(db_error)
+    }
+
+    // Newest first, so a pin wins over older ones covering the same files
+    pub fn pins(&self) -> io::Result<Vec<Pin>> {
+        let mut stmt = self.conn.prepare("SELECT path, tier FROM pins ORDER BY pinned_at DESC, path").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(Pin { path: path_from_row(row, 0)?, tier: row.get(1)? })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Stage `files` with their sizes under `name`, in place of whatever it
+    // staged before
+    pub fn stage_files(&self, name: &str, files: &[(PathBuf, u64)], staged_at: SystemTime, expires_at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM staged_files WHERE name = ?1", params![name]).map_err(db_error)?;
+        let mut stmt = self.conn.prepare(
+            "INSERT OR REPLACE INTO staged_files (name, file_path, file_size, staged_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        ).map_err(db_error)?;
+        for (path, size) in files {
+            stmt.execute(params![name, path_key(path), *size as i64, to_unix(staged_at), to_unix(expires_at)]).map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    // How many files the staging held
+    pub fn release_staging(&self, name: &str) -> io::Result<usize> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM staged_files WHERE name = ?1", params![name]).map_err(db_error)
+    }
+
+    // Bytes reserved by the stagings still held at `now`, counting a file
+    // staged by several once
+    pub fn staged_bytes(&self, now: SystemTime) -> io::Result<u64> {
+        self.conn.query_row(
+            "SELECT COALESCE(SUM(file_size), 0) FROM (SELECT file_path, MAX(file_size) AS file_size FROM staged_files WHERE expires_at > ?1 GROUP BY file_path)",
+            params![to_unix(now)],
+            |row| row.get::<_, i64>(0),
+        ).map(|bytes| bytes as u64).map_err(db_error)
+    }
+
+    // Files held by the stagings at `now`; those past their expiry are left out
+    pub fn staged_files(&self, now: SystemTime) -> io::Result<Vec<PathBuf>> {
+        let mut stmt = self.conn.prepare("SELECT DISTINCT file_path FROM staged_files WHERE expires_at > ?1 ORDER BY file_path").map_err(db_error)?;
+        let rows = stmt.query_map(params![to_unix(now)], |row| path_from_row(row, 0)).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn stagings(&self, now: SystemTime) -> io::Result<Vec<StagedSet>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.name, COUNT(*), SUM(s.file_size), SUM(m.tier = 'hot'), MIN(s.staged_at), MIN(s.expires_at) FROM staged_files s
+             LEFT JOIN file_metadata m ON m.file_path = s.file_path
+             WHERE s.expires_at > ?1 GROUP BY s.name ORDER BY MIN(s.staged_at), s.name",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map(params![to_unix(now)], |row| Ok(StagedSet {
+            name: row.get(0)?,
+            files: row.get::<_, i64>(1)? as u64,
+            bytes: row.get::<_, i64>(2)? as u64,
+            on_hot: row.get::<_, Option<i64>>(3)?.unwrap_or(0) as u64,
+            staged_at: from_unix(row.get(4)?),
+            expires_at: from_unix(row.get(5)?),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn suspect_drives(&self) -> io::Result<Vec<SuspectDrive>> {
+        let mut stmt = self.conn.prepare("SELECT serial, errors, last_at, last_error FROM suspect_drives ORDER BY serial").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(SuspectDrive {
+            serial: row.get(0)?,
+            errors: row.get::<_, i64>(1)? as u64,
+            last_at: from_unix(row.get(2)?),
+            last_error: row.get(3)?,
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Written by `drive-manager job start`; the service pauses tiering while
+    // any job is running
+    pub fn start_job(&self, job: &RunningJob) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO running_jobs (name, started_at, expires_at) VALUES (?1, ?2, ?3)",
+            params![job.name, to_unix(job.started_at), to_unix(job.expires_at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Whether the job was running
+    pub fn finish_job(&self, name: &str) -> io::Result<bool> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM running_jobs WHERE name = ?1", params![name]).map(|count| count > 0).map_err(db_error)
+    }
+
+    // Jobs still running at `now`; those past their expiry are left out
+    pub fn running_jobs(&self, now: SystemTime) -> io::Result<Vec<RunningJob>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, started_at, expires_at FROM running_jobs WHERE expires_at > ?1 ORDER BY started_at, name",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map(params![to_unix(now)], |row| Ok(RunningJob {
+            name: row.get(0)?,
+            started_at: from_unix(row.get(1)?),
+            expires_at: from_unix(row.get(2)?),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    // Put a job in line to start, or note that it is still waiting. Returns
+    // when it first asked.
+    pub fn wait_for_job(&self, name: &str, now: SystemTime) -> io::Result<SystemTime> {
+        self.check_write_fault()?;
+        self.conn.query_row(
+            "INSERT INTO waiting_jobs (name, since, seen_at) VALUES (?1, ?2, ?2)
+             ON CONFLICT (name) DO UPDATE SET seen_at = excluded.seen_at RETURNING since",
+            params![name, to_unix(now)],
+            |row| row.get(0),
+        ).map(from_unix).map_err(db_error)
+    }
+
+    pub fn stop_waiting_for_job(&self, name: &str) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM waiting_jobs WHERE name = ?1", params![name]).map(|_| ()).map_err(db_error)
+    }
+
+    // Jobs in line to start, with when each asked, leaving out those that
+    // stopped checking in before `seen_after`
+    pub fn waiting_jobs(&self, seen_after: SystemTime) -> io::Result<Vec<(String, SystemTime)>> {
+        let mut stmt = self.conn.prepare("SELECT name, since FROM waiting_jobs WHERE seen_at > ?1 ORDER BY since, name").map_err(db_error)?;
+        let rows = stmt.query_map(params![to_unix(seen_after)], |row| Ok((row.get(0)?, from_unix(row.get(1)?)))).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn record_archived(&self, file: &ArchivedFile) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO archived_files (file_path, url, file_size, archived_at) VALUES (?1, ?2, ?3, ?4)",
+            params![path_key(&file.path), file.url, file.size as i64, to_unix(file.archived_at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Whether the file was archived
+    pub fn remove_archived(&self, file_path: &Path) -> io::Result<bool> {
+        self.check_write_fault()?;
+        self.conn.execute("DELETE FROM archived_files WHERE file_path = ?1", params![path_key(file_path)]).map(|count| count > 0).map_err(db_error)
+    }
+
+    pub fn archived_files(&self) -> io::Result<Vec<ArchivedFile>> {
+        let mut stmt = self.conn.prepare("SELECT file_path, url, file_size, archived_at FROM archived_files ORDER BY file_path").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(ArchivedFile {
+            path: path_from_row(row, 0)?,
+            url: row.get(1)?,
+            size: row.get::<_, i64>(2)? as u64,
+            archived_at: from_unix(row.get(3)?),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn quarantine_file(&self, file_path: &Path, quarantined_at: SystemTime, reason: &str) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO quarantined_files (file_path, quarantined_at, reason) VALUES (?1, ?2, ?3)",
+            params![path_key(file_path), to_unix(quarantined_at), reason],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // Let automatic moves have the file again, forgetting its error history.
+    // Returns whether it was quarantined.
+    pub fn release_file(&self, file_path: &Path) -> io::Result<bool> {
+        self.check_write_fault()?;
+        let key = path_key(file_path);
+        self.transaction(|db| {
+            db.conn.execute("DELETE FROM move_errors WHERE file_path = ?1", params![key]).map_err(db_error)?;
+            db.conn.execute("DELETE FROM quarantined_files WHERE file_path = ?1", params![key]).map(|count| count > 0).map_err(db_error)
+        })
+    }
+
+    pub fn is_quarantined(&self, file_path: &Path) -> io::Result<bool> {
+        self.conn.query_row("SELECT 1 FROM quarantined_files WHERE file_path = ?1", params![path_key(file_path)], |_| Ok(()))
+            .optional().map(|row| row.is_some()).map_err(db_error)
+    }
+
+    pub fn quarantined_files(&self) -> io::Result<Vec<QuarantinedFile>> {
+        let mut stmt = self.conn.prepare("SELECT file_path, quarantined_at, reason FROM quarantined_files ORDER BY file_path").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(QuarantinedFile {
+            path: path_from_row(row, 0)?,
+            quarantined_at: from_unix(row.get(1)?),
+            reason: row.get(2)?,
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn save_crawl(&self, progress: &CrawlProgress) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO crawls (serial, started_at, updated_at, finished_at, files, bytes, checksummed_bytes, cursor) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                progress.serial, to_unix(progress.started_at), to_unix(progress.updated_at), progress.finished_at.map(to_unix),
+                progress.files as i64, progress.bytes as i64, progress.checksummed_bytes as i64, progress.cursor.as_deref().map(path_key),
+            ],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    pub fn crawl(&self, serial: &str) -> io::Result<Option<CrawlProgress>> {
+        Ok(self.crawls()?.into_iter().find(|crawl| crawl.serial == serial))
+    }
+
+    pub fn crawls(&self) -> io::Result<Vec<CrawlProgress>> {
+        let mut stmt = self.conn.prepare("SELECT serial, started_at, updated_at, finished_at, files, bytes, checksummed_bytes, cursor FROM crawls ORDER BY serial").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok(CrawlProgress {
+            serial: row.get(0)?,
+            started_at: from_unix(row.get(1)?),
+            updated_at: from_unix(row.get(2)?),
+            finished_at: row.get::<_, Option<i64>>(3)?.map(from_unix),
+            files: row.get::<_, i64>(4)? as u64,
+            bytes: row.get::<_, i64>(5)? as u64,
+            checksummed_bytes: row.get::<_, i64>(6)? as u64,
+            cursor: row.get::<_, Option<Vec<u8>>>(7)?.map(|cursor| PathBuf::from(OsString::from_vec(cursor))),
+        })).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+
+    pub fn set_checksum(&self, file_path: &Path, algorithm: &str, checksum: &str, file_size: u64, taken_at: SystemTime) -> io::Result<()> {
+        self.check_write_fault()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO file_checksums (file_path, algorithm, checksum, file_size, taken_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![path_key(file_path), algorithm, checksum, file_size as i64, to_unix(taken_at)],
+        ).map(|_| ()).map_err(db_error)
+    }
+
+    // The algorithm and checksum last taken of a file
+    pub fn checksum(&self, file_path: &Path) -> io::Result<Option<(String, String)>> {
+        self.conn.query_row("SELECT algorithm, checksum FROM file_checksums WHERE file_path = ?1", params![path_key(file_path)], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional().map_err(db_error)
+    }
+
+    // Least recently accessed files first, low priority tags before high.
+    // Files pinned to a tier or excluded by their tags are never picked, nor
+    // are files moved into the tier after moved_before or files on an
+    // offline branch.
+    pub fn coldest_in_tier(&self, tier: &str, limit: usize, moved_before: SystemTime) -> io::Result<Vec<(PathBuf, FileMetadata)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT m.file_path, m.last_access_time, m.access_count, m.last_tier_move, m.file_size, m.tier FROM file_metadata m
+             LEFT JOIN file_placement p ON p.file_path = m.file_path
+             WHERE m.tier = ?1 AND p.tier IS NULL AND COALESCE(p.exclude, 0) = 0 AND COALESCE(m.last_tier_move, 0) < ?3
+               AND m.file_path NOT IN (SELECT b.file_path FROM file_branch b JOIN offline_branches o ON o.serial = b.serial)
+             ORDER BY COALESCE(p.priority, 0) ASC, m.last_access_time ASC, m.file_path ASC LIMIT ?2",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map(params![tier, limit.min(i64::MAX as usize) as i64, to_unix(moved_before)], Self::row_to_metadata).map_err(db_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(db_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault_injection::Fault;
+    use serde_json::json;
+
+    fn metadata(tier: &str, atime: i64) -> FileMetadata {
+        FileMetadata { last_access_time: from_unix(atime), access_count: 1, file_size: 10, tier: tier.to_string(), last_tier_move: None }
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.insert("movies/a.mkv", &metadata("hot", 100)).unwrap();
+        assert_eq!(db.get("movies/a.mkv").unwrap(), Some(metadata("hot", 100)));
+        db.remove("movies/a.mkv").unwrap();
+        assert_eq!(db.get("movies/a.mkv").unwrap(), None);
+    }
+
+    #[test]
+    fn test_coldest_in_tier() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.insert("b", &metadata("hot", 200)).unwrap();
+        db.insert("a", &metadata("hot", 300)).unwrap();
+        db.insert("c", &metadata("hot", 100)).unwrap();
+        db.insert("d", &metadata("cold", 50)).unwrap();
+        let coldest: Vec<String> = db.coldest_in_tier("hot", 2, SystemTime::now()).unwrap().into_iter().map(|(path, _)| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(coldest, ["c", "b"]);
+        assert_eq!(db.entries().unwrap().len(), 4);
+        // just promoted
+        db.insert("c", &FileMetadata { last_tier_move: Some(from_unix(1000)), ..metadata("hot", 100) }).unwrap();
+        let coldest: Vec<String> = db.coldest_in_tier("hot", 2, from_unix(1000)).unwrap().into_iter().map(|(path, _)| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(coldest, ["b", "a"]);
+    }
+
+    #[test]
+    fn test_placement_shapes_demotion() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        for (path, atime) in [("a", 100), ("b", 200), ("c", 300), ("d", 400)] {
+            db.insert(path, &metadata("hot", atime)).unwrap();
+        }
+        db.set_placement("a", &Placement { priority: PlacementPriority::High, ..Default::default() }).unwrap();
+        db.set_placement("b", &Placement { tier: Some("hot".to_string()), ..Default::default() }).unwrap();
+        db.set_placement("d", &Placement { priority: PlacementPriority::Low, ..Default::default() }).unwrap();
+        let coldest: Vec<String> = db.coldest_in_tier("hot", 10, SystemTime::now()).unwrap().into_iter().map(|(path, _)| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(coldest, ["d", "c", "a"]);
+        assert_eq!(db.placements().unwrap().len(), 3);
+        db.set_placement("d", &Placement::default()).unwrap();
+        db.remove("a").unwrap();
+        assert_eq!(db.placements().unwrap().into_keys().collect::<Vec<_>>(), [PathBuf::from("b")]);
+    }
+
+    #[test]
+    fn test_injected_write_fault() {
+        let mut db = MetadataDb::open_in_memory().unwrap();
+        let faults = FaultInjector::new();
+        faults.inject_after(FaultPoint::DbWrite, Fault::Errno(libc::EIO), 1, 1);
+        db.inject_faults(faults);
+        db.insert("a", &metadata("hot", 1)).unwrap();
+        assert!(db.transaction(|db| db.insert("b", &metadata("hot", 1))).is_err());
+        db.remove("a").unwrap();
+        assert!(db.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failures() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let failure = FailedMove {
+            info: FileMoveInfo { src: PathBuf::from("a"), source_tier: "hot".to_string(), target_tier: "cold".to_string(), retries: 8 },
+            error: "No space left on device".to_string(),
+            failed_at: from_unix(100),
+        };
+        db.record_failure(&failure).unwrap();
+        assert_eq!(db.failures().unwrap(), std::slice::from_ref(&failure));
+        assert!(db.take_retry_requests().unwrap().is_empty());
+        assert_eq!(db.request_retry_all().unwrap(), 1);
+        assert_eq!(db.take_retry_requests().unwrap(), [failure]);
+        assert!(db.failures().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pending_retries() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let info = FileMoveInfo { src: PathBuf::from("a"), source_tier: "hot".to_string(), target_tier: "cold".to_string(), retries: 1 };
+        db.record_move_error(Path::new("a"), from_unix(100), "Input/output error").unwrap();
+        db.schedule_retry(&PendingRetry { info: info.clone(), due_at: from_unix(160) }).unwrap();
+        db.record_move_error(Path::new("a"), from_unix(160), "No space left on device").unwrap();
+        db.schedule_retry(&PendingRetry { info: FileMoveInfo { retries: 2, ..info.clone() }, due_at: from_unix(280) }).unwrap();
+        assert_eq!(db.pending_retries().unwrap(), [PendingRetry { info: FileMoveInfo { retries: 2, ..info.clone() }, due_at: from_unix(280) }]);
+        assert!(db.is_retry_pending(Path::new("a")).unwrap());
+        db.rename("a", "b").unwrap();
+        assert_eq!(db.move_errors(Path::new("b")).unwrap(), [
+            (from_unix(100), "Input/output error".to_string()),
+            (from_unix(160), "No space left on device".to_string()),
+        ]);
+        assert!(db.drop_move(Path::new("b")).unwrap());
+        assert!(!db.drop_move(Path::new("b")).unwrap());
+        assert!(db.pending_retries().unwrap().is_empty());
+        assert!(db.move_errors(Path::new("b")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_move_journal() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let journaled = |name: &str, target_tier: &str, queued_at: i64| JournaledMove {
+            info: FileMoveInfo { src: PathBuf::from(name), source_tier: "hot".to_string(), target_tier: target_tier.to_string(), retries: 0 },
+            user: name == "b", size: 10, queued_at: from_unix(queued_at),
+        };
+        db.journal_move(&journaled("a", "warm", 100)).unwrap();
+        db.journal_move(&journaled("b", "cold", 50)).unwrap();
+        // queued again for another tier
+        db.journal_move(&journaled("a", "cold", 120)).unwrap();
+        assert_eq!(db.journaled_moves().unwrap(), [journaled("b", "cold", 50), journaled("a", "cold", 120)]);
+        db.remove_journaled_move(Path::new("b")).unwrap();
+        assert_eq!(db.journaled_moves().unwrap().len(), 1);
+        db.clear_journal().unwrap();
+        assert!(db.journaled_moves().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_requests() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        assert!(!db.take_check_request().unwrap());
+        db.request_check(from_unix(100)).unwrap();
+        db.request_check(from_unix(101)).unwrap();
+        assert!(db.take_check_request().unwrap());
+        assert!(!db.take_check_request().unwrap());
+    }
+
+    #[test]
+    fn test_reload_requests() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        assert_eq!(db.take_reload_request().unwrap(), None);
+        db.request_reload(from_unix(100), false).unwrap();
+        db.request_reload(from_unix(101), true).unwrap();
+        assert_eq!(db.take_reload_request().unwrap(), Some(true));
+        assert_eq!(db.take_reload_request().unwrap(), None);
+        assert_eq!(db.applied_config().unwrap(), None);
+        db.record_applied_config(&json!({ "filesystem": "xfs" }), from_unix(100)).unwrap();
+        db.record_applied_config(&json!({ "filesystem": "ext4" }), from_unix(200)).unwrap();
+        assert_eq!(db.applied_config().unwrap(), Some(json!({ "filesystem": "ext4" })));
+    }
+
+    #[test]
+    fn test_lane_rates() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.record_throughput("hot", "warm", 100 << 20, Duration::from_secs(1)).unwrap();
+        db.record_throughput("hot", "warm", 200 << 20, Duration::from_secs(5)).unwrap();
+        db.record_throughput("warm", "cold", 10, Duration::ZERO).unwrap();
+        let rates = db.lane_rates().unwrap();
+        assert_eq!(rates.len(), 1);
+        assert_eq!(rates[&("hot".to_string(), "warm".to_string())], (50 << 20) as f64);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        assert!(!db.request_maintenance("WD-1", false, from_unix(100)).unwrap());
+        assert!(db.request_maintenance("WD-1", true, from_unix(100)).unwrap());
+        db.update_maintenance("WD-1", MaintenanceState::Failed, Some("umount: target is busy")).unwrap();
+        assert!(db.request_maintenance("WD-1", false, from_unix(110)).unwrap());
+        assert_eq!(db.maintenance().unwrap(), [
+            Maintenance { serial: "WD-1".to_string(), requested_at: from_unix(110), state: MaintenanceState::Ending, error: None },
+        ]);
+        db.end_maintenance("WD-1").unwrap();
+        assert!(db.maintenance().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_drains() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.request_drain("WD-1", from_unix(100)).unwrap();
+        db.update_drain("WD-1", DrainState::Failed, 2, Some("2 files could not be moved")).unwrap();
+        db.request_drain("WD-2", from_unix(110)).unwrap();
+        db.update_drain("WD-2", DrainState::Moving, 40, None).unwrap();
+        assert_eq!(db.drains().unwrap()[0].error.as_deref(), Some("2 files could not be moved"));
+        db.request_drain("WD-1", from_unix(120)).unwrap();
+        assert_eq!(db.drains().unwrap(), [
+            Drain { serial: "WD-2".to_string(), requested_at: from_unix(110), state: DrainState::Moving, files_left: 40, error: None },
+            Drain { serial: "WD-1".to_string(), requested_at: from_unix(120), state: DrainState::Requested, files_left: 0, error: None },
+        ]);
+    }
+
+    #[test]
+    fn test_suspect_drives() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.record_io_error("WD-1", from_unix(100), "Input/output error (os error 5)").unwrap();
+        db.record_io_error("WD-1", from_unix(160), "rsync: read errors mapping a: Input/output error (5)").unwrap();
+        assert_eq!(db.suspect_drives().unwrap(), [SuspectDrive {
+            serial: "WD-1".to_string(), errors: 2, last_at: from_unix(160), last_error: "rsync: read errors mapping a: Input/output error (5)".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_pins() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let pin = |path: &str, tier: &str| Pin { path: PathBuf::from(path), tier: tier.to_string() };
+        db.add_pin(&pin("vms", "hot"), from_unix(100)).unwrap();
+        db.add_pin(&pin("vms/old", "cold"), from_unix(110)).unwrap();
+        assert_eq!(db.pins().unwrap(), [pin("vms/old", "cold"), pin("vms", "hot")]);
+        db.add_pin(&pin("vms", "warm"), from_unix(120)).unwrap();
+        assert!(db.remove_pin("vms/old").unwrap());
+        assert!(!db.remove_pin("vms/old").unwrap());
+        assert_eq!(db.pins().unwrap(), [pin("vms", "warm")]);
+        let latin1 = Pin { path: PathBuf::from(OsString::from_vec(b"TV/caf\xe9".to_vec())), tier: "hot".to_string() };
+        db.add_pin(&latin1, from_unix(130)).unwrap();
+        assert_eq!(db.pins().unwrap()[0], latin1);
+        assert!(db.remove_pin(&latin1.path).unwrap());
+    }
+
+    #[test]
+    fn test_stagings() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.insert("a", &metadata("hot", 100)).unwrap();
+        db.insert("b", &metadata("cold", 200)).unwrap();
+        let files = |paths: &[&str]| paths.iter().map(|path| (PathBuf::from(path), 100)).collect::<Vec<_>>();
+        db.stage_files("render", &files(&["a", "b"]), from_unix(100), from_unix(1000)).unwrap();
+        db.stage_files("ci", &files(&["b", "c"]), from_unix(110), from_unix(500)).unwrap();
+        // b is reserved once for both
+        assert_eq!(db.staged_bytes(from_unix(200)).unwrap(), 300);
+        let render = StagedSet { name: "render".to_string(), files: 2, bytes: 200, on_hot: 1, staged_at: from_unix(100), expires_at: from_unix(1000) };
+        assert_eq!(db.stagings(from_unix(200)).unwrap()[0], render);
+        // ci has expired by now
+        assert_eq!(db.staged_files(from_unix(600)).unwrap(), [PathBuf::from("a"), PathBuf::from("b")]);
+        db.stage_files("render", &files(&["a"]), from_unix(700), from_unix(1000)).unwrap();
+        assert_eq!(db.staged_bytes(from_unix(700)).unwrap(), 100);
+        assert_eq!(db.release_staging("render").unwrap(), 1);
+        assert_eq!(db.release_staging("render").unwrap(), 0);
+        assert!(db.stagings(from_unix(700)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_archived_files() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let archived = ArchivedFile { path: PathBuf::from("tv/a.mkv"), url: "s3://nas/tv/a.mkv".to_string(), size: 1 << 40, archived_at: from_unix(100) };
+        db.record_archived(&archived).unwrap();
+        assert_eq!(db.archived_files().unwrap(), std::slice::from_ref(&archived));
+        assert!(db.remove_archived(&archived.path).unwrap());
+        assert!(!db.remove_archived(&archived.path).unwrap());
+        assert!(db.archived_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_quarantined_files() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.record_move_error(Path::new("bad.mkv"), from_unix(100), "Input/output error").unwrap();
+        db.quarantine_file(Path::new("bad.mkv"), from_unix(100), "3 read or checksum errors").unwrap();
+        assert!(db.is_quarantined(Path::new("bad.mkv")).unwrap());
+        assert!(!db.is_quarantined(Path::new("good.mkv")).unwrap());
+        db.rename("bad.mkv", "worse.mkv").unwrap();
+        assert_eq!(db.quarantined_files().unwrap(), [
+            QuarantinedFile { path: PathBuf::from("worse.mkv"), quarantined_at: from_unix(100), reason: "3 read or checksum errors".to_string() },
+        ]);
+        assert!(db.release_file(Path::new("worse.mkv")).unwrap());
+        assert!(!db.release_file(Path::new("worse.mkv")).unwrap());
+        assert!(db.quarantined_files().unwrap().is_empty());
+        assert!(db.move_errors(Path::new("worse.mkv")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_crawls() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let mut crawl = CrawlProgress {
+            serial: "WD-1".to_string(), started_at: from_unix(100), updated_at: from_unix(100), finished_at: None,
+            files: 0, bytes: 0, checksummed_bytes: 0, cursor: None,
+        };
+        db.save_crawl(&crawl).unwrap();
+        assert_eq!(db.crawl("WD-1").unwrap().as_ref(), Some(&crawl));
+        crawl = CrawlProgress { updated_at: from_unix(200), files: 2, bytes: 30, checksummed_bytes: 30, cursor: Some(PathBuf::from("tv/b.mkv")), ..crawl };
+        db.save_crawl(&crawl).unwrap();
+        assert_eq!(db.crawls().unwrap(), [crawl]);
+        assert_eq!(db.crawl("WD-2").unwrap(), None);
+
+        db.set_checksum(Path::new("tv/a.mkv"), "xxh3", "00ff", 10, from_unix(200)).unwrap();
+        db.rename("tv/a.mkv", "tv/c.mkv").unwrap();
+        assert_eq!(db.checksum(Path::new("tv/c.mkv")).unwrap(), Some(("xxh3".to_string(), "00ff".to_string())));
+        db.remove("tv/c.mkv").unwrap();
+        assert_eq!(db.checksum(Path::new("tv/c.mkv")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_drives() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let drive = db.register_drive("WD-1", "cold", from_unix(100)).unwrap();
+        assert_eq!(drive, Drive { serial: "WD-1".to_string(), tier: "cold".to_string(), state: DriveState::Active, first_seen: from_unix(100), last_seen: from_unix(100), pooled_at: None });
+        // the tier it was first given sticks
+        assert_eq!(db.register_drive("WD-1", "warm", from_unix(200)).unwrap().tier, "cold");
+        assert!(db.known_branches().unwrap().is_empty());
+        db.add_known_branch("WD-1", "warm", from_unix(300)).unwrap();
+        assert_eq!(db.drive("WD-1").unwrap().unwrap().pooled_at, Some(from_unix(300)));
+        assert_eq!(db.known_branches().unwrap(), HashSet::from(["WD-1".to_string()]));
+
+        db.set_branch_offline("WD-1", from_unix(400)).unwrap();
+        assert_eq!(db.drive("WD-1").unwrap().unwrap().state, DriveState::Missing);
+        assert_eq!(db.register_drive("WD-1", "cold", from_unix(500)).unwrap().state, DriveState::Active);
+        db.record_io_error("WD-1", from_unix(600), "EIO").unwrap();
+        assert_eq!(db.drive("WD-1").unwrap().unwrap().state, DriveState::Quarantined);
+        // quarantine outlasts a restart
+        assert_eq!(db.register_drive("WD-1", "cold", from_unix(700)).unwrap().state, DriveState::Quarantined);
+        db.request_drain("WD-1", from_unix(800)).unwrap();
+        assert_eq!(db.drive("WD-1").unwrap().unwrap().state, DriveState::Evacuating);
+        db.update_drain("WD-1", DrainState::Done, 0, None).unwrap();
+        assert_eq!(db.drive("WD-1").unwrap().unwrap().state, DriveState::Retired);
+        assert!(db.reset_drive("WD-1").unwrap());
+        assert!(!db.reset_drive("WD-1").unwrap());
+        assert_eq!(db.drive("WD-1").unwrap().unwrap().state, DriveState::Active);
+        assert!(db.suspect_drives().unwrap().is_empty() && db.drains().unwrap().is_empty());
+        assert_eq!(db.drives().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_known_branches_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata.db");
+        Connection::open(&path).unwrap().execute_batch(
+            "CREATE TABLE known_branches (serial TEXT PRIMARY KEY, tier TEXT NOT NULL, first_seen INTEGER NOT NULL);
+             INSERT INTO known_branches VALUES ('nvme0', 'hot', 100);",
+        ).unwrap();
+        let db = MetadataDb::open(&path).unwrap();
+        assert_eq!(db.known_branches().unwrap(), HashSet::from(["nvme0".to_string()]));
+        assert_eq!(db.branch_tiers().unwrap()["nvme0"], "hot");
+        drop(db);
+        MetadataDb::open(&path).unwrap();
+    }
+
+    #[test]
+    fn test_disk_io_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata.db");
+        Connection::open(&path).unwrap().execute_batch(
+            "CREATE TABLE disk_io (serial TEXT NOT NULL, sampled_at INTEGER NOT NULL, util REAL NOT NULL, read_latency_ms REAL NOT NULL,
+                 write_latency_ms REAL NOT NULL, iops REAL NOT NULL, PRIMARY KEY (serial, sampled_at));
+             INSERT INTO disk_io VALUES ('WD-1', 100, 0.5, 1.0, 2.0, 10.0);",
+        ).unwrap();
+        let db = MetadataDb::open(&path).unwrap();
+        assert_eq!(db.latest_disk_io().unwrap()[0].2.bytes_written, 0);
+        assert_eq!(db.latest_disk_io().unwrap()[0].2.read_bps, 0.0);
+        assert_eq!(db.bytes_written_since(from_unix(0)).unwrap()["WD-1"], 0);
+    }
+
+    #[test]
+    fn test_offline_branches() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.insert("a", &metadata("hot", 100)).unwrap();
+        db.insert("b", &metadata("hot", 200)).unwrap();
+        db.set_branch("a", "nvme1").unwrap();
+        db.set_branch("b", "nvme2").unwrap();
+        assert!(db.set_branch_offline("nvme1", from_unix(500)).unwrap());
+        assert!(!db.set_branch_offline("nvme1", from_unix(600)).unwrap());
+        assert_eq!(db.offline_branches().unwrap(), [("nvme1".to_string(), from_unix(500))]);
+        assert_eq!(db.unavailable_files().unwrap(), HashSet::from([PathBuf::from("a")]));
+        let coldest: Vec<PathBuf> = db.coldest_in_tier("hot", 10, from_unix(1000)).unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(coldest, [PathBuf::from("b")]);
+        assert!(db.set_branch_online("nvme1").unwrap());
+        assert!(!db.set_branch_online("nvme1").unwrap());
+        assert!(db.unavailable_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_waiting_jobs() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        assert_eq!(db.wait_for_job("scrub", from_unix(100)).unwrap(), from_unix(100));
+        db.wait_for_job("smart", from_unix(105)).unwrap();
+        // checking in again keeps its place
+        assert_eq!(db.wait_for_job("scrub", from_unix(160)).unwrap(), from_unix(100));
+        assert_eq!(db.waiting_jobs(from_unix(110)).unwrap(), [("scrub".to_string(), from_unix(100))]);
+        db.stop_waiting_for_job("scrub").unwrap();
+        assert_eq!(db.waiting_jobs(from_unix(0)).unwrap(), [("smart".to_string(), from_unix(105))]);
+    }
+
+    #[test]
+    fn test_loop_runs() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.start_loop_run("retries", from_unix(100)).unwrap();
+        db.finish_loop_run("retries", Duration::from_millis(1500), Some("disk full"), Some(from_unix(105))).unwrap();
+        db.start_loop_run("retries", from_unix(105)).unwrap();
+        db.start_loop_run("maintenance", from_unix(90)).unwrap();
+        db.finish_loop_run("maintenance", Duration::ZERO, None, None).unwrap();
+        assert_eq!(db.loop_runs().unwrap(), [
+            LoopRun { name: "maintenance".to_string(), started_at: from_unix(90), running: false, duration: Duration::ZERO, error: None, next_run: None },
+            LoopRun {
+                name: "retries".to_string(), started_at: from_unix(105), running: true, duration: Duration::from_millis(1500),
+                error: Some("disk full".to_string()), next_run: Some(from_unix(105)),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_disk_io() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let io = |util| DiskIo { util, read_latency_ms: 4.0, write_latency_ms: 8.0, iops: 30.0, bytes_written: 1000, read_bps: 2048.0, write_bps: 16.0 };
+        db.record_disk_io("WD-1", from_unix(100), &io(0.1)).unwrap();
+        db.record_disk_io("WD-1", from_unix(160), &io(0.2)).unwrap();
+        db.record_disk_io("WD-2", from_unix(100), &io(0.3)).unwrap();
+        assert_eq!(db.latest_disk_io().unwrap(), [("WD-1".to_string(), from_unix(160), io(0.2)), ("WD-2".to_string(), from_unix(100), io(0.3))]);
+        assert_eq!(db.disk_io_history("WD-1", from_unix(0)).unwrap(), [(from_unix(100), io(0.1)), (from_unix(160), io(0.2))]);
+        assert_eq!(db.disk_io_since(from_unix(100)).unwrap(), [
+            ("WD-1".to_string(), from_unix(100), io(0.1)), ("WD-2".to_string(), from_unix(100), io(0.3)), ("WD-1".to_string(), from_unix(160), io(0.2)),
+        ]);
+        assert_eq!(db.bytes_written_since(from_unix(150)).unwrap(), HashMap::from([("WD-1".to_string(), 1000)]));
+        assert_eq!(db.prune_disk_io(from_unix(150)).unwrap(), 2);
+        assert_eq!(db.disk_io_history("WD-1", from_unix(0)).unwrap().len(), 1);
+        // the running totals outlast the samples
+        assert_eq!(db.drive_writes().unwrap(), [("WD-1".to_string(), 2000, from_unix(100)), ("WD-2".to_string(), 1000, from_unix(100))]);
+    }
+
+    #[test]
+    fn test_tier_totals() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        db.insert("a", &metadata("hot", 100)).unwrap();
+        db.insert("b", &metadata("hot", 200)).unwrap();
+        db.insert("c", &metadata("cold", 300)).unwrap();
+        let mut totals = db.tier_totals().unwrap();
+        totals.sort();
+        assert_eq!(totals, [("cold".to_string(), 1, 10), ("hot".to_string(), 2, 20)]);
+        db.add_known_branch("WD-1", "cold", from_unix(100)).unwrap();
+        db.add_known_branch("WD-1", "hot", from_unix(200)).unwrap();
+        assert_eq!(db.branch_tiers().unwrap(), HashMap::from([("WD-1".to_string(), "cold".to_string())]));
+    }
+
+    #[test]
+    fn test_subtree_usage() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        let usage = |serial: &str, bytes| SubtreeUsage { subtree: PathBuf::from("tv"), serial: serial.to_string(), tier: "cold".to_string(), bytes };
+        db.record_subtree_usage(&[usage("WD-1", 10), usage("WD-2", 20)], from_unix(100)).unwrap();
+        db.record_subtree_usage(&[usage("WD-2", 30)], from_unix(200)).unwrap();
+        assert_eq!(db.subtree_usage().unwrap(), [(usage("WD-2", 30), from_unix(200))]);
+    }
+
+    #[test]
+    fn test_non_utf8_paths() {
+        let db = MetadataDb::open_in_memory().unwrap();
+        // "caf\xe9.mkv", a latin-1 name
+        let latin1 = PathBuf::from(OsString::from_vec(b"caf\xe9.mkv".to_vec()));
+        db.insert(&latin1, &metadata("hot", 1)).unwrap();
+        db.insert("caf\u{e9}.mkv", &metadata("cold", 1)).unwrap();
+        assert_eq!(db.get(&latin1).unwrap().unwrap().tier, "hot");
+        assert_eq!(db.entries().unwrap().len(), 2);
+        assert_eq!(db.coldest_in_tier("hot", 10, SystemTime::now()).unwrap()[0].0, latin1);
+    }
+
+    #[test]
+    fn test_text_keys_are_converted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file_metadata.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE file_metadata (file_path TEXT PRIMARY KEY, last_access_time INTEGER NOT NULL, access_count INTEGER NOT NULL,
+                last_tier_move INTEGER, file_size INTEGER NOT NULL, tier TEXT NOT NULL);
+             INSERT INTO file_metadata VALUES ('movies/a.mkv', 1, 1, NULL, 10, 'warm');",
+        ).unwrap();
+        drop(conn);
+        let db = MetadataDb::open(&path).unwrap();
+        assert_eq!(db.get("movies/a.mkv").unwrap().unwrap().tier, "warm");
+        db.insert("movies/a.mkv", &metadata("cold", 1)).unwrap();
+        assert_eq!(db.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_open_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file_metadata.db");
+        MetadataDb::open(&path).unwrap().insert("a", &metadata("warm", 1)).unwrap();
+        assert_eq!(MetadataDb::open(&path).unwrap().get("a").unwrap().unwrap().tier, "warm");
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use log::{debug, warn};
+
+const WATCH_MASK: u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE | libc::IN_ONLYDIR | libc::IN_DONT_FOLLOW;
+// size of struct inotify_event before its name
+const EVENT_HEADER: usize = 16;
+
+// A change made through a merged mount, with mount-relative paths, which are
+// the same as the branch-relative ones the DB uses
+#[derive(Clone, Debug, PartialEq)]
+pub enum MountEvent {
+    // with dir set, everything under `from` moved too
+    Renamed { from: PathBuf, to: PathBuf, dir: bool },
+    Deleted { path: PathBuf, dir: bool },
+    // a file was written and closed, or moved in from outside the mounts,
+    // through the mount of `tier`
+    Written { path: PathBuf, tier: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct RawEvent {
+    mask: u32,
+    cookie: u32,
+    path: PathBuf,
+    // the name of the mount, which is its tier
+    tier: String,
+}
+
+// Watches every directory under the merged mounts with inotify. Only
+// changes made through the mounts are seen, which leaves out our own moves
+// since those go between branches directly.
+pub struct MountWatcher {
+    inotify: File,
+    // each watch's mount and the mount-relative directory it is on
+    watches: HashMap<i32, (PathBuf, PathBuf)>,
+}
+
+impl MountWatcher {
+    pub fn new(mounts: &[PathBuf]) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut watcher = Self { inotify: unsafe { File::from_raw_fd(fd) }, watches: HashMap::new() };
+        for mount in mounts {
+            watcher.watch_tree(mount, Path::new(""))?;
+        }
+        Ok(watcher)
+    }
+
+    pub fn watch_count(&self) -> usize {
+        self.watches.len()
+    }
+
+    // Watch `dir` under `mount` and every directory below it. Watching a
+    // directory that already has a watch just updates where it is.
+    fn watch_tree(&mut self, mount: &Path, dir: &Path) -> io::Result<()> {
+        let path = mount.join(dir);
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.watches.insert(wd, (mount.to_path_buf(), dir.to_path_buf()));
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            match self.watch_tree(mount, &dir.join(entry.file_name())) {
+                // gone again before we got to it
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    // Block until something changes, then return the renames and deletions
+    pub fn next_events(&mut self) -> io::Result<Vec<MountEvent>> {
+        let mut buf = vec![0; 64 * 1024];
+        let len = self.inotify.read(&mut buf)?;
+        let mut raw = Vec::new();
+        for (wd, mask, cookie, name) in parse(&buf[..len]) {
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!("Missed changes on the merged mounts, the next scan will pick them up");
+                continue;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.watches.remove(&wd);
+                continue;
+            }
+            let Some((mount, dir)) = self.watches.get(&wd).cloned() else { continue };
+            let path = dir.join(name);
+            // directories created or moved in need watches of their own
+            if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                if let Err(e) = self.watch_tree(&mount, &path) {
+                    warn!("Could not watch {}: {}", mount.join(&path).display(), e);
+                }
+            }
+            let tier = mount.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            raw.push(RawEvent { mask, cookie, path, tier });
+        }
+        Ok(pair(raw))
+    }
+}
+
+// Split a read from an inotify fd into (wd, mask, cookie, name)
+pub fn parse(buf: &[u8]) -> Vec<(i32, u32, u32, &OsStr)> {
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + EVENT_HEADER <= buf.len() {
+        let field = |at: usize| u32::from_ne_bytes(buf[offset + at..offset + at + 4].try_into().unwrap());
+        let (wd, mask, cookie, len) = (field(0) as i32, field(4), field(8), field(12) as usize);
+        let name = &buf[offset + EVENT_HEADER..(offset + EVENT_HEADER + len).min(buf.len())];
+        // the name is padded out with NULs
+        let name = name.split(|&byte| byte == 0).next().unwrap_or_default();
+        events.push((wd, mask, cookie, OsStr::from_bytes(name)));
+        offset += EVENT_HEADER + len;
+    }
+    events
+}
+
+// Join the two halves of each rename by their cookie. A file moved out of
+// the mounts has no second half and is as good as deleted; one moved in has
+// no first half and is as good as written.
+fn pair(raw: Vec<RawEvent>) -> Vec<MountEvent> {
+    let mut events = Vec::new();
+    let mut moved_from: HashMap<u32, usize> = HashMap::new();
+    for event in raw {
+        let dir = event.mask & libc::IN_ISDIR != 0;
+        if event.mask & libc::IN_MOVED_FROM != 0 {
+            moved_from.insert(event.cookie, events.len());
+            events.push(MountEvent::Deleted { path: event.path, dir });
+        } else if event.mask & libc::IN_MOVED_TO != 0 {
+            match moved_from.remove(&event.cookie) {
+                Some(index) => {
+                    if let MountEvent::Deleted { path, .. } = &events[index] {
+                        events[index] = MountEvent::Renamed { from: path.clone(), to: event.path, dir };
+                    }
+                }
+                None if !dir => events.push(MountEvent::Written { path: event.path, tier: event.tier }),
+                None => {}
+            }
+        } else if event.mask & libc::IN_DELETE != 0 {
+            events.push(MountEvent::Deleted { path: event.path, dir });
+        } else if event.mask & libc::IN_CLOSE_WRITE != 0 {
+            events.push(MountEvent::Written { path: event.path, tier: event.tier });
+        }
+    }
+    debug!("Merged mount events: {:?}", events);
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pair() {
+        let event = |mask, cookie, path: &str| RawEvent { mask, cookie, path: path.into(), tier: "hot".to_string() };
+        let events = pair(vec![
+            event(libc::IN_MOVED_FROM, 7, "a.mkv"),
+            event(libc::IN_MOVED_TO, 7, "b.mkv"),
+            event(libc::IN_MOVED_FROM | libc::IN_ISDIR, 8, "show"),
+            event(libc::IN_DELETE, 0, "c.mkv"),
+            event(libc::IN_MOVED_TO, 9, "incoming.mkv"),
+            event(libc::IN_CLOSE_WRITE, 0, "new.iso"),
+        ]);
+        assert_eq!(events, [
+            MountEvent::Renamed { from: "a.mkv".into(), to: "b.mkv".into(), dir: false },
+            MountEvent::Deleted { path: "show".into(), dir: true },
+            MountEvent::Deleted { path: "c.mkv".into(), dir: false },
+            MountEvent::Written { path: "incoming.mkv".into(), tier: "hot".to_string() },
+            MountEvent::Written { path: "new.iso".into(), tier: "hot".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_watch() {
+        let mount = tempdir().unwrap();
+        fs::create_dir_all(mount.path().join("shows/s01")).unwrap();
+        fs::write(mount.path().join("shows/s01/e01.mkv"), "e01").unwrap();
+        fs::write(mount.path().join("old.iso"), "iso").unwrap();
+        let mut watcher = MountWatcher::new(&[mount.path().to_path_buf()]).unwrap();
+        assert_eq!(watcher.watch_count(), 3);
+
+        fs::rename(mount.path().join("shows/s01/e01.mkv"), mount.path().join("shows/s01/pilot.mkv")).unwrap();
+        fs::rename(mount.path().join("shows"), mount.path().join("series")).unwrap();
+        fs::remove_file(mount.path().join("old.iso")).unwrap();
+        assert_eq!(watcher.next_events().unwrap(), [
+            MountEvent::Renamed { from: "shows/s01/e01.mkv".into(), to: "shows/s01/pilot.mkv".into(), dir: false },
+            MountEvent::Renamed { from: "shows".into(), to: "series".into(), dir: true },
+            MountEvent::Deleted { path: "old.iso".into(), dir: false },
+        ]);
+
+        // the watches moved with the directory
+        fs::remove_file(mount.path().join("series/s01/pilot.mkv")).unwrap();
+        assert_eq!(watcher.next_events().unwrap(), [MountEvent::Deleted { path: "series/s01/pilot.mkv".into(), dir: false }]);
+
+        fs::write(mount.path().join("series/s01/e02.mkv"), "e02").unwrap();
+        let tier = mount.path().file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(watcher.next_events().unwrap(), [MountEvent::Written { path: "series/s01/e02.mkv".into(), tier }]);
+    }
+}
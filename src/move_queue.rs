@@ -0,0 +1,299 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Condvar, Mutex};
+use crate::file_metadata::FileMoveInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    // manual migrate/prefetch requests, always taken first
+    User,
+    Background,
+}
+
+pub struct QueuedMove {
+    pub info: FileMoveInfo,
+    pub priority: Priority,
+    // bytes the move will copy, counted against the in-flight cap
+    pub size: u64,
+    // told the outcome once the move completes or is abandoned; several
+    // callers can end up waiting on one move when duplicates are merged
+    pub replies: Vec<Sender<io::Result<()>>>,
+}
+
+impl QueuedMove {
+    pub fn background(info: FileMoveInfo) -> Self {
+        Self { info, priority: Priority::Background, size: 0, replies: Vec::new() }
+    }
+
+    pub fn respond(&self, result: io::Result<()>) {
+        for reply in &self.replies {
+            let message = match &result {
+                Ok(()) => Ok(()),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            // the caller may have stopped waiting
+            let _ = reply.send(message);
+        }
+    }
+}
+
+// What push() did with a move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pushed {
+    Added,
+    // the same move was already queued
+    Merged,
+    // a queued move for the same file now goes to the newer target tier
+    Retargeted,
+    // the file is being moved right now, so the request was dropped
+    InFlight,
+}
+
+fn lane(info: &FileMoveInfo) -> (String, String) {
+    (info.source_tier.clone(), info.target_tier.clone())
+}
+
+// Queued moves are keyed by path so each file is queued at most once; the
+// deques only hold the order paths are taken in.
+#[derive(Default)]
+struct QueueState {
+    moves: HashMap<PathBuf, QueuedMove>,
+    user: VecDeque<PathBuf>,
+    background: VecDeque<PathBuf>,
+    in_flight: HashMap<(String, String), usize>,
+    // size of each move being copied right now
+    in_flight_paths: HashMap<PathBuf, u64>,
+    in_flight_bytes: u64,
+}
+
+impl QueueState {
+    fn push(&mut self, mut queued: QueuedMove, check_in_flight: bool) -> Pushed {
+        let path = queued.info.src.clone();
+        if check_in_flight && self.in_flight_paths.contains_key(&path) {
+            queued.respond(Err(io::Error::new(io::ErrorKind::ResourceBusy, format!("{} is already being moved", path.display()))));
+            return Pushed::InFlight;
+        }
+        let Some(existing) = self.moves.get_mut(&path) else {
+            match queued.priority {
+                Priority::User => self.user.push_back(path.clone()),
+                Priority::Background => self.background.push_back(path.clone()),
+            }
+            self.moves.insert(path, queued);
+            return Pushed::Added;
+        };
+        existing.replies.append(&mut queued.replies);
+        existing.size = queued.size;
+        if queued.priority == Priority::User && existing.priority == Priority::Background {
+            existing.priority = Priority::User;
+            self.user.push_back(path);
+        }
+        if existing.info.target_tier == queued.info.target_tier {
+            Pushed::Merged
+        } else {
+            existing.info.source_tier = queued.info.source_tier;
+            existing.info.target_tier = queued.info.target_tier;
+            Pushed::Retargeted
+        }
+    }
+
+    fn is_current(&self, path: &Path, priority: Priority) -> bool {
+        self.moves.get(path).is_some_and(|queued| queued.priority == priority)
+    }
+
+    // Take the first move in the lane whose source->target lane has a free
+    // worker and that fits under the byte cap, dropping entries left behind
+    // by priority upgrades. A move larger than the cap on its own still runs
+    // once nothing else is in flight.
+    fn take_ready(&mut self, priority: Priority, workers: &impl Fn(&FileMoveInfo) -> usize, byte_cap: Option<u64>) -> Option<QueuedMove> {
+        let mut order = std::mem::take(match priority {
+            Priority::User => &mut self.user,
+            Priority::Background => &mut self.background,
+        });
+        order.retain(|path| self.is_current(path, priority));
+        let index = order.iter().position(|path| {
+            let queued = &self.moves[path];
+            let fits = byte_cap.is_none_or(|cap| self.in_flight_bytes == 0 || self.in_flight_bytes + queued.size <= cap);
+            fits && self.in_flight.get(&lane(&queued.info)).copied().unwrap_or(0) < workers(&queued.info)
+        });
+        let queued = index.and_then(|index| order.remove(index)).and_then(|path| self.moves.remove(&path));
+        *match priority {
+            Priority::User => &mut self.user,
+            Priority::Background => &mut self.background,
+        } = order;
+        queued
+    }
+}
+
+// Pending moves in two lanes. User moves always go before background ones,
+// and moves are only handed out while their source->target lane has a free
+// worker, so a burst of background demotions cannot sit in front of them.
+#[derive(Default)]
+pub struct MoveQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    // most bytes allowed to be copying at once across all lanes
+    max_in_flight_bytes: Option<u64>,
+}
+
+impl MoveQueue {
+    pub fn new(max_in_flight_bytes: Option<u64>) -> Self {
+        Self { max_in_flight_bytes, ..Default::default() }
+    }
+
+    pub fn push(&self, queued: QueuedMove) -> Pushed {
+        let pushed = self.state.lock().unwrap().push(queued, true);
+        self.changed.notify_all();
+        pushed
+    }
+
+    // Put a failed move back. Unlike push() this is expected while the move
+    // still counts as in flight.
+    pub fn requeue(&self, queued: QueuedMove) -> Pushed {
+        let pushed = self.state.lock().unwrap().push(queued, false);
+        self.changed.notify_all();
+        pushed
+    }
+
+    // Take the next move without waiting or counting it as in flight
+    pub fn try_pop(&self) -> Option<QueuedMove> {
+        let mut state = self.state.lock().unwrap();
+        let any = |_: &FileMoveInfo| usize::MAX;
+        state.take_ready(Priority::User, &any, None).or_else(|| state.take_ready(Priority::Background, &any, None))
+    }
+
+    // Block until a move can start given the per-lane worker counts. The move
+    // counts as in flight until finish() is called for it.
+    pub fn pop_ready(&self, workers: impl Fn(&FileMoveInfo) -> usize) -> QueuedMove {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let cap = self.max_in_flight_bytes;
+            if let Some(queued) = state.take_ready(Priority::User, &workers, cap).or_else(|| state.take_ready(Priority::Background, &workers, cap)) {
+                *state.in_flight.entry(lane(&queued.info)).or_insert(0) += 1;
+                state.in_flight_paths.insert(queued.info.src.clone(), queued.size);
+                state.in_flight_bytes += queued.size;
+                return queued;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    pub fn finish(&self, info: &FileMoveInfo) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.in_flight.get_mut(&lane(info)) {
+            *count = count.saturating_sub(1);
+        }
+        if let Some(size) = state.in_flight_paths.remove(&info.src) {
+            state.in_flight_bytes = state.in_flight_bytes.saturating_sub(size);
+        }
+        self.changed.notify_all();
+    }
+
+    pub fn is_queued_or_in_flight(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state.moves.contains_key(path) || state.in_flight_paths.contains_key(path)
+    }
+
+    pub fn in_flight_bytes(&self) -> u64 {
+        self.state.lock().unwrap().in_flight_bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().moves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(src: &str, source_tier: &str, target_tier: &str) -> FileMoveInfo {
+        FileMoveInfo { src: PathBuf::from(src), source_tier: source_tier.to_string(), target_tier: target_tier.to_string(), retries: 0 }
+    }
+
+    #[test]
+    fn test_user_moves_first() {
+        let queue = MoveQueue::default();
+        queue.push(QueuedMove::background(info("a", "hot", "warm")));
+        queue.push(QueuedMove::background(info("b", "hot", "warm")));
+        queue.push(QueuedMove { info: info("c", "cold", "hot"), priority: Priority::User, size: 0, replies: Vec::new() });
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.try_pop().unwrap().info.src, Path::new("c"));
+        assert_eq!(queue.try_pop().unwrap().info.src, Path::new("a"));
+    }
+
+    #[test]
+    fn test_pop_ready_respects_lane_workers() {
+        let queue = MoveQueue::default();
+        queue.push(QueuedMove::background(info("a", "warm", "cold")));
+        queue.push(QueuedMove::background(info("b", "warm", "cold")));
+        queue.push(QueuedMove::background(info("c", "hot", "warm")));
+        let workers = |info: &FileMoveInfo| if info.source_tier == "warm" { 1 } else { 4 };
+        let first = queue.pop_ready(workers);
+        assert_eq!(first.info.src, Path::new("a"));
+        // the warm->cold lane is busy, so the hot->warm move goes next
+        assert_eq!(queue.pop_ready(workers).info.src, Path::new("c"));
+        queue.finish(&first.info);
+        assert_eq!(queue.pop_ready(workers).info.src, Path::new("b"));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_duplicates_are_merged() {
+        let queue = MoveQueue::default();
+        assert_eq!(queue.push(QueuedMove::background(info("a", "hot", "warm"))), Pushed::Added);
+        assert_eq!(queue.push(QueuedMove::background(info("b", "hot", "warm"))), Pushed::Added);
+        assert_eq!(queue.push(QueuedMove::background(info("a", "hot", "warm"))), Pushed::Merged);
+        assert_eq!(queue.push(QueuedMove::background(info("b", "hot", "cold"))), Pushed::Retargeted);
+        assert_eq!(queue.len(), 2);
+
+        let (reply, outcome) = std::sync::mpsc::channel();
+        assert_eq!(queue.push(QueuedMove { info: info("b", "hot", "cold"), priority: Priority::User, size: 0, replies: vec![reply] }), Pushed::Merged);
+        let first = queue.try_pop().unwrap();
+        assert_eq!((first.info.src.to_str().unwrap(), first.info.target_tier.as_str()), ("b", "cold"));
+        first.respond(Ok(()));
+        assert!(outcome.recv().unwrap().is_ok());
+        assert_eq!(queue.try_pop().unwrap().info.src, Path::new("a"));
+        assert!(queue.try_pop().is_none());
+    }
+
+    #[test]
+    fn test_in_flight_moves_are_skipped() {
+        let queue = MoveQueue::default();
+        queue.push(QueuedMove::background(info("a", "hot", "warm")));
+        let moving = queue.pop_ready(|_| 1);
+        assert!(queue.is_queued_or_in_flight(Path::new("a")));
+        assert_eq!(queue.push(QueuedMove::background(info("a", "hot", "warm"))), Pushed::InFlight);
+        // a failed attempt still goes back on the queue
+        assert_eq!(queue.requeue(QueuedMove::background(moving.info.clone())), Pushed::Added);
+        queue.finish(&moving.info);
+        assert_eq!(queue.try_pop().unwrap().info.src, Path::new("a"));
+        assert!(!queue.is_queued_or_in_flight(Path::new("a")));
+    }
+
+    #[test]
+    fn test_in_flight_byte_cap() {
+        let queue = MoveQueue::new(Some(100));
+        for (path, size) in [("a", 60), ("b", 60), ("c", 30), ("d", 500)] {
+            queue.push(QueuedMove { size, ..QueuedMove::background(info(path, "hot", "warm")) });
+        }
+        let a = queue.pop_ready(|_| 8);
+        // b would take the total to 120, so the smaller c goes first
+        let c = queue.pop_ready(|_| 8);
+        assert_eq!((a.info.src.to_str().unwrap(), c.info.src.to_str().unwrap()), ("a", "c"));
+        assert_eq!(queue.in_flight_bytes(), 90);
+        queue.finish(&a.info);
+        queue.finish(&c.info);
+        let b = queue.pop_ready(|_| 8);
+        assert_eq!(b.info.src, Path::new("b"));
+        queue.finish(&b.info);
+        // bigger than the cap, but runs alone
+        assert_eq!(queue.pop_ready(|_| 8).info.src, Path::new("d"));
+        assert_eq!(queue.in_flight_bytes(), 500);
+    }
+}
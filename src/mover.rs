@@ -0,0 +1,315 @@
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde_json::Value;
+use crate::checksum::Checksums;
+use crate::storage::{set_timestamps, timestamps};
+
+// linux/fs.h; libc has no constant for it
+const FICLONE: libc::c_ulong = 0x40049409;
+// bytes copied between progress reports
+const CHUNK: usize = 8 << 20;
+
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// How files are copied between branches:
+//   "mover": "native"
+// The native mover copies in-process, by reflink where both ends are the
+// same filesystem and copy_file_range otherwise. rsync is the default
+// while mover_cgroup is set, as the cgroup only holds the processes a move
+// starts. rsync needs to be 3.2.4 or later, for --fsync.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mover {
+    #[default]
+    Native,
+    Rsync,
+}
+
+impl Mover {
+    pub fn from_config(config: &Value) -> Self {
+        match config.get("mover").and_then(Value::as_str) {
+            Some("rsync") => Mover::Rsync,
+            Some(_) => Mover::Native,
+            None if config.get("mover_cgroup").is_some_and(Value::is_object) => Mover::Rsync,
+            None => Mover::Native,
+        }
+    }
+}
+
+// The name a copy is written under in `temp_dir` before it is renamed into
+// place. It has nothing of the file's own name, which may be too long to
+// take a prefix.
+fn temp_path(temp_dir: &Path) -> PathBuf {
+    temp_dir.join(format!("{}.{}", process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)))
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+// The names of a file's extended attributes, which include its ACLs. None
+// when the filesystem has no xattrs at all.
+fn xattr_names(path: &CString) -> io::Result<Vec<CString>> {
+    let size = unsafe { libc::llistxattr(path.as_ptr(), ptr::null_mut(), 0) };
+    if size < 0 {
+        let e = io::Error::last_os_error();
+        return if e.raw_os_error() == Some(libc::ENOTSUP) { Ok(Vec::new()) } else { Err(e) };
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+    Ok(names.split(|byte| *byte == 0).filter(|name| !name.is_empty()).filter_map(|name| CString::new(name).ok()).collect())
+}
+
+fn xattr_value(path: &CString, name: &CString) -> io::Result<Vec<u8>> {
+    let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), ptr::null_mut(), 0) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut value = vec![0u8; size as usize];
+    let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    value.truncate(size as usize);
+    Ok(value)
+}
+
+// Every xattr of `src` onto `dest`, without following links. A destination
+// that cannot take one fails the copy, as rsync -X would, rather than lose
+// placement tags.
+pub fn copy_xattrs(src: &Path, dest: &Path) -> io::Result<()> {
+    let (src, dest) = (c_path(src)?, c_path(dest)?);
+    for name in xattr_names(&src)? {
+        let value = xattr_value(&src, &name)?;
+        if unsafe { libc::lsetxattr(dest.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) } != 0 {
+            let e = io::Error::last_os_error();
+            return Err(io::Error::new(e.kind(), format!("cannot set {} on {}: {}", name.to_string_lossy(), dest.to_string_lossy(), e)));
+        }
+    }
+    Ok(())
+}
+
+fn lchown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    if unsafe { libc::lchown(c_path(path)?.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Reflink when the filesystem can share the extents, otherwise copy in
+// chunks with copy_file_range, falling back to read and write where the
+// kernel will not copy between these filesystems
+fn copy_contents(src: &File, dest: &File, len: u64, progress: &dyn Fn(u64)) -> io::Result<()> {
+    if unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE, src.as_raw_fd()) } == 0 {
+        progress(len);
+        return Ok(());
+    }
+    // so a full drive fails here rather than partway through
+    if len > 0 && unsafe { libc::fallocate(dest.as_raw_fd(), 0, 0, len as libc::off_t) } != 0 {
+        let e = io::Error::last_os_error();
+        if !matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP)) {
+            return Err(e);
+        }
+    }
+    let mut buf = Vec::new();
+    let mut copied = 0;
+    while copied < len {
+        let chunk = (len - copied).min(CHUNK as u64) as usize;
+        let n = if buf.is_empty() {
+            let n = unsafe { libc::copy_file_range(src.as_raw_fd(), ptr::null_mut(), dest.as_raw_fd(), ptr::null_mut(), chunk, 0) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if !matches!(e.raw_os_error(), Some(libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL)) {
+                    return Err(e);
+                }
+                buf = vec![0; CHUNK];
+                continue;
+            }
+            n as usize
+        } else {
+            let n = (&*src).read(&mut buf[..chunk])?;
+            (&*dest).write_all(&buf[..n])?;
+            n
+        };
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file shrank while being copied"));
+        }
+        copied += n as u64;
+        progress(copied);
+    }
+    Ok(())
+}
+
+// Copy `src` to `temp` with its contents, ownership, mode and xattrs,
+// checking the contents against the source when checksums.verify is set
+fn copy_to_temp(src: &Path, temp: &Path, metadata: &fs::Metadata, checksums: &Checksums, progress: &dyn Fn(u64)) -> io::Result<()> {
+    if metadata.is_symlink() {
+        symlink(fs::read_link(src)?, temp)?;
+        lchown(temp, metadata.uid(), metadata.gid())?;
+        return copy_xattrs(src, temp);
+    }
+    let source = File::open(src)?;
+    let dest = OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(temp)?;
+    copy_contents(&source, &dest, metadata.len(), progress)?;
+    // chown clears setuid and setgid, so the mode goes on after it
+    lchown(temp, metadata.uid(), metadata.gid())?;
+    dest.set_permissions(fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+    copy_xattrs(src, temp)?;
+    dest.sync_all()?;
+    if let Some(algorithm) = checksums.verify {
+        // so the copy is read back from the drive rather than the page cache
+        unsafe { libc::posix_fadvise(dest.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        checksums.verify(&source, &dest, metadata.len(), algorithm)?;
+    }
+    // a file written to while it was copied is left for the next check
+    let after = source.metadata()?;
+    if (after.len(), after.mtime(), after.mtime_nsec()) != (metadata.len(), metadata.mtime(), metadata.mtime_nsec()) {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, format!("{} changed while being copied", src.display())));
+    }
+    Ok(())
+}
+
+// Copy `src` to `dest` through a temp file in `temp_dir`, which must be on
+// the same filesystem as `dest`, keeping the source. Nothing is left in
+// `temp_dir` when it fails.
+pub fn copy_file(src: &Path, dest: &Path, temp_dir: &Path, checksums: &Checksums, progress: &dyn Fn(u64)) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    if !metadata.is_file() && !metadata.is_symlink() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file or a link", src.display())));
+    }
+    let times = timestamps(src)?;
+    fs::create_dir_all(temp_dir)?;
+    let temp = temp_path(temp_dir);
+    let copied = copy_to_temp(src, &temp, &metadata, checksums, progress)
+        .and_then(|()| set_timestamps(&temp, &times))
+        .and_then(|()| fs::rename(&temp, dest));
+    if copied.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    copied?;
+    sync_parent(dest)
+}
+
+// Write out the directory entry for `path`, so a rename or link into it
+// survives a power cut before anything the move removes elsewhere does
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    File::open(path.parent().unwrap_or(Path::new("/")))?.sync_all()
+}
+
+// Copy `src` to `dest`, then remove `src` once the copy is in place
+pub fn move_file(src: &Path, dest: &Path, temp_dir: &Path, checksums: &Checksums, progress: &dyn Fn(u64)) -> io::Result<()> {
+    copy_file(src, dest, temp_dir, checksums, progress)?;
+    fs::remove_file(src)
+}
+
+// Move every link to one file from `branch` to `dest_branch` as links to a
+// single copy there. The sources go only once all the links are made.
+pub fn move_links(branch: &Path, paths: &[PathBuf], dest_branch: &Path, temp_dir: &Path, checksums: &Checksums, progress: &dyn Fn(u64)) -> io::Result<()> {
+    let Some((first, others)) = paths.split_first() else { return Ok(()) };
+    let dest_first = dest_branch.join(first);
+    fs::create_dir_all(dest_first.parent().unwrap())?;
+    copy_file(&branch.join(first), &dest_first, temp_dir, checksums, progress)?;
+    let mut linked = vec![dest_first.clone()];
+    for path in others {
+        let dest = dest_branch.join(path);
+        let link = fs::create_dir_all(dest.parent().unwrap()).and_then(|()| fs::hard_link(&dest_first, &dest));
+        if let Err(e) = link {
+            for dest in &linked {
+                let _ = fs::remove_file(dest);
+            }
+            return Err(e);
+        }
+        linked.push(dest);
+    }
+    // copy_file synced the first link's directory
+    let dirs: HashSet<&Path> = linked[1..].iter().filter_map(|dest| dest.parent()).filter(|dir| Some(*dir) != dest_first.parent()).collect();
+    for dir in dirs {
+        File::open(dir)?.sync_all()?;
+    }
+    for path in paths {
+        fs::remove_file(branch.join(path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Timestamps;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_move_file() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        let temp_dir = cold.path().join(".tmp");
+        let src = hot.path().join("a.mkv");
+        fs::write(&src, vec![7u8; CHUNK + 10]).unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o640)).unwrap();
+        let times = Timestamps { atime: (1_700_000_000, 123_456_789), mtime: (1_600_000_000, 987_654_321) };
+        set_timestamps(&src, &times).unwrap();
+        let tagged = unsafe { libc::setxattr(c_path(&src).unwrap().as_ptr(), c"user.drivemanager.tier".as_ptr(), b"cold".as_ptr() as *const libc::c_void, 4, 0) } == 0;
+        let reported = Mutex::new(Vec::new());
+        let dest = cold.path().join("a.mkv");
+        let checksums = Checksums::from_config(&json!({ "checksums": { "verify": "xxh3" } }));
+        move_file(&src, &dest, &temp_dir, &checksums, &|bytes| reported.lock().unwrap().push(bytes)).unwrap();
+        assert!(!src.exists());
+        // before reading it bumps the atime
+        assert_eq!(timestamps(&dest).unwrap(), times);
+        assert_eq!(fs::read(&dest).unwrap(), vec![7u8; CHUNK + 10]);
+        assert_eq!(fs::metadata(&dest).unwrap().mode() & 0o7777, 0o640);
+        assert_eq!(reported.into_inner().unwrap().last(), Some(&(CHUNK as u64 + 10)));
+        if tagged {
+            // the temp filesystem may not take user xattrs
+            assert_eq!(xattr_value(&c_path(&dest).unwrap(), &CString::new("user.drivemanager.tier").unwrap()).unwrap(), b"cold");
+        }
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        // a failed copy leaves the source and nothing else
+        let src = hot.path().join("b.mkv");
+        fs::write(&src, "test data").unwrap();
+        assert!(move_file(&src, &cold.path().join("missing/b.mkv"), &temp_dir, &checksums, &|_| {}).is_err());
+        assert!(src.exists());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        symlink("a.mkv", hot.path().join("latest.mkv")).unwrap();
+        move_file(&hot.path().join("latest.mkv"), &cold.path().join("latest.mkv"), &temp_dir, &checksums, &|_| {}).unwrap();
+        assert_eq!(fs::read_link(cold.path().join("latest.mkv")).unwrap(), Path::new("a.mkv"));
+    }
+
+    #[test]
+    fn test_move_links() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        fs::create_dir_all(hot.path().join("daily.0")).unwrap();
+        fs::write(hot.path().join("daily.0/a"), "test data").unwrap();
+        fs::hard_link(hot.path().join("daily.0/a"), hot.path().join("a")).unwrap();
+        let links = [PathBuf::from("daily.0/a"), PathBuf::from("a")];
+        move_links(hot.path(), &links, cold.path(), &cold.path().join(".tmp"), &Checksums::default(), &|_| {}).unwrap();
+        let moved = fs::metadata(cold.path().join("a")).unwrap();
+        assert_eq!((moved.nlink(), moved.ino()), (2, fs::metadata(cold.path().join("daily.0/a")).unwrap().ino()));
+        assert!(!hot.path().join("daily.0/a").exists() && !hot.path().join("a").exists());
+    }
+
+    #[test]
+    fn test_mover_from_config() {
+        assert_eq!(Mover::from_config(&json!({})), Mover::Native);
+        assert_eq!(Mover::from_config(&json!({ "mover": "rsync" })), Mover::Rsync);
+        assert_eq!(Mover::from_config(&json!({ "mover_cgroup": { "cpu_weight": 20 } })), Mover::Rsync);
+        assert_eq!(Mover::from_config(&json!({ "mover": "native", "mover_cgroup": { "cpu_weight": 20 } })), Mover::Native);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use log::warn;
+use serde_json::Value;
+use crate::clock::{Clock, SystemClock};
+use crate::executor::{checked, Executor};
+
+pub const DEFAULT_GROUP_SEC: u64 = 300;
+pub const DEFAULT_MAX_PER_HOUR: u64 = 6;
+// the events worth waking someone for, unless kinds says otherwise
+pub const DEFAULT_KINDS: [&str; 10] = [
+    "move_failed", "drive_io_error", "drive_offline", "drain_failed", "maintenance_failed",
+    "file_quarantined", "tier_full", "pool_discrepancy", "write_skew", "on_battery",
+];
+const RATE_WINDOW: Duration = Duration::from_secs(3600);
+// how often alerts waiting to go out are looked at
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Channel {
+    // posted with curl, with the token as a bearer token when there is one
+    Ntfy { url: String, token: Option<String> },
+    // handed to sendmail
+    Email { to: String },
+}
+
+// Alerts for the events the service records, sent to ntfy, email or both:
+//   "notifications": { "ntfy": "https://ntfy.sh/my-nas", "ntfy_token": "@file:ntfy-token",
+//                      "email": "admin@example.com", "group_sec": 300, "max_per_hour": 6,
+//                      "rate_limits": { "move_failed": 2 }, "kinds": ["move_failed", "drive_offline"] }
+// Events of one kind with one cause, the drive or tier they are about,
+// are gathered for group_sec from the first and sent as one alert with
+// their count, so a dead drive failing thousands of moves sends one. Each
+// kind sends at most max_per_hour alerts, or its rate_limits entry; what
+// is held back keeps gathering until it may go. kinds defaults to
+// DEFAULT_KINDS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NotifySettings {
+    pub channels: Vec<Channel>,
+    pub kinds: Vec<String>,
+    pub group_for: Duration,
+    pub max_per_hour: u64,
+    pub rate_limits: HashMap<String, u64>,
+}
+
+// Every event of a kind with one cause that came in before it was sent
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub kind: String,
+    pub cause: String,
+    pub count: u64,
+    pub first_at: SystemTime,
+    pub last_at: SystemTime,
+    // the first event's details, as an example of the rest
+    pub details: Value,
+}
+
+struct State {
+    pending: Vec<Alert>,
+    // when each kind's alerts went out in the last RATE_WINDOW
+    sent: HashMap<String, Vec<SystemTime>>,
+}
+
+pub struct Notifier {
+    settings: NotifySettings,
+    clock: Arc<dyn Clock>,
+    executor: Arc<dyn Executor>,
+    state: Mutex<State>,
+}
+
+impl NotifySettings {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("notifications")?;
+        let text = |key: &str| section.get(key).and_then(Value::as_str).filter(|text| !text.is_empty()).map(str::to_string);
+        let mut channels = Vec::new();
+        if let Some(url) = text("ntfy") {
+            channels.push(Channel::Ntfy { url, token: text("ntfy_token") });
+        }
+        if let Some(to) = text("email") {
+            channels.push(Channel::Email { to });
+        }
+        if channels.is_empty() {
+            return None;
+        }
+        let kinds = match section.get("kinds").and_then(Value::as_array) {
+            Some(kinds) => kinds.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            None => DEFAULT_KINDS.iter().map(|kind| kind.to_string()).collect(),
+        };
+        let rate_limits = section.get("rate_limits").and_then(Value::as_object).into_iter().flatten()
+            .filter_map(|(kind, limit)| Some((kind.clone(), limit.as_u64()?)))
+            .collect();
+        Some(Self {
+            channels,
+            kinds,
+            group_for: Duration::from_secs(section.get("group_sec").and_then(Value::as_u64).unwrap_or(DEFAULT_GROUP_SEC)),
+            max_per_hour: section.get("max_per_hour").and_then(Value::as_u64).unwrap_or(DEFAULT_MAX_PER_HOUR),
+            rate_limits,
+        })
+    }
+
+    fn limit(&self, kind: &str) -> u64 {
+        self.rate_limits.get(kind).copied().unwrap_or(self.max_per_hour)
+    }
+}
+
+// What an event is about, for grouping: its drive, or else its tier
+fn cause(details: &Value) -> String {
+    ["serial", "tier", "from"].iter()
+        .find_map(|key| details.get(key).and_then(Value::as_str))
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl Alert {
+    pub fn title(&self) -> String {
+        let what = if self.count == 1 { self.kind.clone() } else { format!("{} x {}", self.count, self.kind) };
+        if self.cause.is_empty() {
+            format!("drive-manager: {}", what)
+        } else {
+            format!("drive-manager: {} on {}", what, self.cause)
+        }
+    }
+
+    pub fn body(&self) -> String {
+        let mut body = self.details.to_string();
+        if self.count > 1 {
+            let over = self.last_at.duration_since(self.first_at).unwrap_or_default();
+            body.push_str(&format!("\nand {} more like it over {}s", self.count - 1, over.as_secs()));
+        }
+        body
+    }
+}
+
+impl Notifier {
+    pub fn new(settings: NotifySettings, clock: Arc<dyn Clock>, executor: Arc<dyn Executor>) -> Self {
+        Self { settings, clock, executor, state: Mutex::new(State { pending: Vec::new(), sent: HashMap::new() }) }
+    }
+
+    pub fn observe(&self, kind: &str, details: &Value) {
+        if !self.settings.kinds.iter().any(|wanted| wanted == kind) {
+            return;
+        }
+        let cause = cause(details);
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        match state.pending.iter_mut().find(|alert| alert.kind == kind && alert.cause == cause) {
+            Some(alert) => {
+                alert.count += 1;
+                alert.last_at = now;
+            }
+            None => state.pending.push(Alert { kind: kind.to_string(), cause, count: 1, first_at: now, last_at: now, details: details.clone() }),
+        }
+    }
+
+    // The alerts that have gathered for group_for by `now` and are within
+    // their kind's rate limit, taken off the pending list
+    pub fn due(&self, now: SystemTime) -> Vec<Alert> {
+        let mut state = self.state.lock().unwrap();
+        let State { pending, sent } = &mut *state;
+        let mut due = Vec::new();
+        pending.retain(|alert| {
+            if now.duration_since(alert.first_at).unwrap_or_default() < self.settings.group_for {
+                return true;
+            }
+            let sent = sent.entry(alert.kind.clone()).or_default();
+            sent.retain(|at| now.duration_since(*at).unwrap_or_default() < RATE_WINDOW);
+            if sent.len() as u64 >= self.settings.limit(&alert.kind) {
+                return true;
+            }
+            sent.push(now);
+            due.push(alert.clone());
+            false
+        });
+        due
+    }
+
+    // Send what is due, or everything pending when `all` is set, as at
+    // shutdown
+    pub fn flush(&self, all: bool) {
+        let alerts = if all {
+            std::mem::take(&mut self.state.lock().unwrap().pending)
+        } else {
+            self.due(self.clock.now())
+        };
+        for alert in alerts {
+            for channel in &self.settings.channels {
+                if let Err(e) = self.send(channel, &alert) {
+                    warn!("Failed to send the alert \"{}\": {}", alert.title(), e);
+                }
+            }
+        }
+    }
+
+    fn send(&self, channel: &Channel, alert: &Alert) -> io::Result<()> {
+        match channel {
+            Channel::Ntfy { url, token } => {
+                let title = format!("Title: {}", alert.title());
+                let auth = token.as_ref().map(|token| format!("Authorization: Bearer {}", token));
+                let mut cmd: Vec<&OsStr> = vec!["curl".as_ref(), "-fsS".as_ref(), "-m".as_ref(), "30".as_ref(), "-H".as_ref(), title.as_ref()];
+                if let Some(auth) = &auth {
+                    cmd.push("-H".as_ref());
+                    cmd.push(auth.as_ref());
+                }
+                cmd.push("--data-binary".as_ref());
+                cmd.push("@-".as_ref());
+                cmd.push(url.as_ref());
+                checked(&cmd, self.executor.output_with_input(&cmd, alert.body().as_bytes())?).map(|_| ())
+            }
+            Channel::Email { to } => {
+                let message = format!("To: {}\nSubject: {}\n\n{}\n", to, alert.title(), alert.body());
+                let cmd: [&OsStr; 2] = ["sendmail".as_ref(), "-t".as_ref()];
+                checked(&cmd, self.executor.output_with_input(&cmd, message.as_bytes())?).map(|_| ())
+            }
+        }
+    }
+}
+
+// Make `notifier` the one `observe` hands events to, for the rest of the
+// process, and send its alerts as they come due
+pub fn init(notifier: Notifier) {
+    if NOTIFIER.set(notifier).is_err() {
+        warn!("Notifications were already set up");
+        return;
+    }
+    thread::spawn(|| loop {
+        thread::sleep(FLUSH_INTERVAL);
+        flush(false);
+    });
+}
+
+pub fn init_from_config(config: &Value, executor: Arc<dyn Executor>) {
+    if let Some(settings) = NotifySettings::from_config(config) {
+        init(Notifier::new(settings, Arc::new(SystemClock), executor));
+    }
+}
+
+// Called by events::record with each event, when notifications are set up
+pub fn observe(kind: &str, details: &Value) {
+    if let Some(notifier) = NOTIFIER.get() {
+        notifier.observe(kind, details);
+    }
+}
+
+pub fn flush(all: bool) {
+    if let Some(notifier) = NOTIFIER.get() {
+        notifier.flush(all);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use serde_json::json;
+    use std::process::Output;
+    use std::time::UNIX_EPOCH;
+
+    // Keeps the commands alerts are sent with and what they were fed
+    #[derive(Default)]
+    struct Sent(Mutex<Vec<(Vec<String>, String)>>);
+
+    impl Executor for Sent {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            self.output(cmd).map(|output| output.status)
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<Output> {
+            self.output_with_input(cmd, &[])
+        }
+
+        fn output_with_input(&self, cmd: &[&OsStr], input: &[u8]) -> io::Result<Output> {
+            use std::os::unix::process::ExitStatusExt;
+            let cmd = cmd.iter().map(|arg| arg.to_string_lossy().to_string()).collect();
+            self.0.lock().unwrap().push((cmd, String::from_utf8_lossy(input).to_string()));
+            Ok(Output { status: std::process::ExitStatus::from_raw(0), stdout: Vec::new(), stderr: Vec::new() })
+        }
+    }
+
+    #[test]
+    fn test_settings() {
+        assert_eq!(NotifySettings::from_config(&json!({ "notifications": { "group_sec": 60 } })), None);
+        let settings = NotifySettings::from_config(&json!({ "notifications": { "ntfy": "https://ntfy.sh/nas", "email": "admin@example.com", "rate_limits": { "tier_full": 1 } } })).unwrap();
+        assert_eq!(settings.channels, [Channel::Ntfy { url: "https://ntfy.sh/nas".to_string(), token: None }, Channel::Email { to: "admin@example.com".to_string() }]);
+        assert_eq!((settings.group_for, settings.kinds.len()), (Duration::from_secs(DEFAULT_GROUP_SEC), DEFAULT_KINDS.len()));
+        assert_eq!((settings.limit("tier_full"), settings.limit("move_failed")), (1, DEFAULT_MAX_PER_HOUR));
+    }
+
+    #[test]
+    fn test_grouped_alerts() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1000)));
+        let sent = Arc::new(Sent::default());
+        let settings = NotifySettings::from_config(&json!({ "notifications": { "ntfy": "https://ntfy.sh/nas", "ntfy_token": "tk", "group_sec": 60, "rate_limits": { "move_failed": 1 } } })).unwrap();
+        let notifier = Notifier::new(settings, clock.clone(), sent.clone());
+        for n in 0..5000 {
+            notifier.observe("move_failed", &json!({ "path": format!("{}.mkv", n), "serial": "WD-1", "error": "Input/output error" }));
+        }
+        notifier.observe("move_failed", &json!({ "path": "x.mkv", "serial": "WD-2" }));
+        notifier.observe("moved", &json!({ "path": "y.mkv" }));
+        clock.advance(Duration::from_secs(30));
+        notifier.observe("drive_offline", &json!({ "serial": "WD-1" }));
+        assert!(notifier.due(clock.now()).is_empty());
+        clock.advance(Duration::from_secs(30));
+        // WD-2's failure waits for the next hour, drive_offline for its minute
+        notifier.flush(false);
+        let sent_alerts = sent.0.lock().unwrap().clone();
+        assert_eq!(sent_alerts.len(), 1);
+        let (cmd, body) = &sent_alerts[0];
+        assert_eq!(cmd[5], "Title: drive-manager: 5000 x move_failed on WD-1");
+        assert_eq!(cmd[6..9], ["-H", "Authorization: Bearer tk", "--data-binary"]);
+        assert!(body.contains("0.mkv") && body.ends_with("and 4999 more like it over 0s"), "{}", body);
+        clock.advance(Duration::from_secs(30));
+        let titles = |alerts: Vec<Alert>| alerts.iter().map(Alert::title).collect::<Vec<_>>();
+        assert_eq!(titles(notifier.due(clock.now())), ["drive-manager: drive_offline on WD-1"]);
+        notifier.observe("move_failed", &json!({ "path": "z.mkv", "serial": "WD-2" }));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(titles(notifier.due(clock.now())), ["drive-manager: 2 x move_failed on WD-2"]);
+    }
+}
@@ -0,0 +1,174 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OpenMode {
+    Read,
+    Write,
+}
+
+// How careful moves are about files other processes have open, from the
+// open_files config key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpenFilePolicy {
+    // move regardless
+    Ignore,
+    // defer files open for writing, e.g. a download in progress
+    Writers,
+    // also defer files that are only being read, e.g. a video being streamed
+    Any,
+}
+
+impl OpenFilePolicy {
+    pub fn from_config(config: &Value) -> Self {
+        match config.get("open_files").and_then(Value::as_str) {
+            Some("ignore") => OpenFilePolicy::Ignore,
+            Some("writers") => OpenFilePolicy::Writers,
+            _ => OpenFilePolicy::Any,
+        }
+    }
+
+    pub fn defers(&self, mode: Option<OpenMode>) -> bool {
+        match (self, mode) {
+            (OpenFilePolicy::Ignore, _) | (_, None) => false,
+            (OpenFilePolicy::Writers, Some(mode)) => mode == OpenMode::Write,
+            (OpenFilePolicy::Any, Some(_)) => true,
+        }
+    }
+}
+
+// A file descriptor some process has open for reading, and how far into
+// the file it has read
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpenReader {
+    pub pid: u32,
+    pub fd: u32,
+    pub path: PathBuf,
+    pub pos: u64,
+}
+
+// Call visit with the target and fd number of every open fd under
+// proc_root, for each process. Processes that exit or cannot be inspected
+// mid-walk are skipped.
+fn walk_fds(proc_root: &Path, visit: &mut dyn FnMut(u32, &Path, &OsStr, &Path) -> bool) -> io::Result<()> {
+    for entry in fs::read_dir(proc_root)? {
+        let pid_dir = entry?.path();
+        let Some(pid) = pid_dir.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u32>().ok()) else { continue };
+        let Ok(fds) = fs::read_dir(pid_dir.join("fd")) else { continue };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else { continue };
+            if !visit(pid, &pid_dir, &fd.file_name(), &target) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+// The strongest mode any process has one of `files` open with, found by
+// walking the fd tables under proc_root. mergerfs keeps the branch file open
+// for as long as a client has the pooled path open, so branch paths are
+// enough to see readers going through the pool.
+pub fn open_mode(proc_root: &Path, files: &[PathBuf]) -> io::Result<Option<OpenMode>> {
+    let files: Vec<PathBuf> = files.iter().filter_map(|file| file.canonicalize().ok()).collect();
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let mut found = None;
+    walk_fds(proc_root, &mut |_, pid_dir, fd, target| {
+        if !files.iter().any(|file| file == target) {
+            return true;
+        }
+        let mode = fd_info(&pid_dir.join("fdinfo").join(fd)).map_or(OpenMode::Read, |(mode, _)| mode);
+        found = Some(mode);
+        mode != OpenMode::Write
+    })?;
+    Ok(found)
+}
+
+// Every fd open read-only on a file under one of `roots`
+pub fn readers(proc_root: &Path, roots: &[PathBuf]) -> io::Result<Vec<OpenReader>> {
+    let mut readers = Vec::new();
+    walk_fds(proc_root, &mut |pid, pid_dir, fd, target| {
+        if roots.iter().any(|root| target.starts_with(root)) {
+            let info = fd_info(&pid_dir.join("fdinfo").join(fd));
+            if let (Some((OpenMode::Read, pos)), Some(fd)) = (info, fd.to_str().and_then(|fd| fd.parse().ok())) {
+                readers.push(OpenReader { pid, fd, path: target.to_path_buf(), pos });
+            }
+        }
+        true
+    })?;
+    Ok(readers)
+}
+
+// The access mode from the octal flags line of /proc/<pid>/fdinfo/<fd>, and
+// the file offset from its pos line
+fn fd_info(fdinfo: &Path) -> Option<(OpenMode, u64)> {
+    let info = fs::read_to_string(fdinfo).ok()?;
+    let field = |name: &str| info.lines().find_map(|line| line.strip_prefix(name)).map(str::trim);
+    let flags = i32::from_str_radix(field("flags:")?, 8).ok()?;
+    let pos = field("pos:").and_then(|pos| pos.parse().ok()).unwrap_or(0);
+    let mode = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => OpenMode::Read,
+        _ => OpenMode::Write,
+    };
+    Some((mode, pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs::File;
+    use std::io::Read;
+
+    #[test]
+    fn test_open_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.mkv");
+        fs::write(&path, "test data").unwrap();
+        let files = [path.clone(), dir.path().join("missing")];
+        let proc_root = Path::new("/proc");
+        assert_eq!(open_mode(proc_root, &files).unwrap(), None);
+        let reader = File::open(&path).unwrap();
+        assert_eq!(open_mode(proc_root, &files).unwrap(), Some(OpenMode::Read));
+        let writer = File::options().append(true).open(&path).unwrap();
+        assert_eq!(open_mode(proc_root, &files).unwrap(), Some(OpenMode::Write));
+        drop((reader, writer));
+        assert_eq!(open_mode(proc_root, &files).unwrap(), None);
+    }
+
+    #[test]
+    fn test_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("a.mkv");
+        fs::write(&path, "test data").unwrap();
+        let proc_root = Path::new("/proc");
+        let roots = [root];
+        assert!(readers(proc_root, &roots).unwrap().is_empty());
+        let mut reader = File::open(&path).unwrap();
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        let _writer = File::options().append(true).open(&path).unwrap();
+        let found = readers(proc_root, &roots).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].pid, std::process::id());
+        assert_eq!(found[0].path, path);
+        assert_eq!(found[0].pos, 4);
+    }
+
+    #[test]
+    fn test_policy() {
+        assert_eq!(OpenFilePolicy::from_config(&json!({})), OpenFilePolicy::Any);
+        let writers = OpenFilePolicy::from_config(&json!({ "open_files": "writers" }));
+        assert!(!writers.defers(Some(OpenMode::Read)));
+        assert!(writers.defers(Some(OpenMode::Write)));
+        assert!(OpenFilePolicy::Any.defers(Some(OpenMode::Read)));
+        assert!(!OpenFilePolicy::Any.defers(None));
+        assert!(!OpenFilePolicy::Ignore.defers(Some(OpenMode::Write)));
+    }
+}
@@ -0,0 +1,62 @@
+use serde_json::Value;
+use crate::lsblk::BlockDevice;
+
+// Which partition we manage on drives that hold others, such as a boot or
+// scratch partition, which are left alone:
+//   "managed_partitions": { "label": "tiered", "drives": { "WD-1": "sdb3" } }
+// A drive listed by id names its partition by device name, path, partition
+// label or filesystem label. Otherwise a drive with a partition carrying
+// `label` uses that one. A GPT partition label is the one to use, as mkfs
+// clears a filesystem label.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ManagedPartitions {
+    label: Option<String>,
+    drives: Vec<(String, String)>,
+}
+
+impl ManagedPartitions {
+    pub fn from_config(config: &Value) -> Self {
+        let section = config.get("managed_partitions");
+        Self {
+            label: section.and_then(|section| section.get("label")).and_then(Value::as_str).map(str::to_string),
+            drives: section.and_then(|section| section.get("drives")).and_then(Value::as_object).map(|drives| {
+                drives.iter().filter_map(|(id, partition)| Some((id.clone(), partition.as_str()?.to_string()))).collect()
+            }).unwrap_or_default(),
+        }
+    }
+
+    // The partition to manage on `device`, by path, when it is not simply
+    // the drive's only one. A listed drive without that partition gets the
+    // name it was listed with, which matches nothing.
+    pub fn partition_for(&self, device: &BlockDevice) -> Option<String> {
+        if let Some((_, name)) = self.drives.iter().find(|(id, _)| id == device.id()) {
+            let partition = device.children.iter().find(|partition| partition.is_named(name));
+            return Some(partition.map_or(name.clone(), |partition| partition.path.clone()));
+        }
+        let label = self.label.as_deref()?;
+        device.children.iter().find(|partition| partition.is_named(label)).map(|partition| partition.path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn partition(name: &str, partlabel: Option<&str>) -> BlockDevice {
+        BlockDevice { name: name.to_string(), path: format!("/dev/{}", name), partlabel: partlabel.map(str::to_string), ..Default::default() }
+    }
+
+    #[test]
+    fn test_partition_for() {
+        let managed = ManagedPartitions::from_config(&json!({ "managed_partitions": { "label": "tiered", "drives": { "WD-1": "sdb3", "WD-2": "sdc9" } } }));
+        let drive = |serial: &str, children| BlockDevice { serial: Some(serial.to_string()), children, ..Default::default() };
+        let listed = drive("WD-1", vec![partition("sdb1", Some("boot")), partition("sdb3", None)]);
+        assert_eq!(managed.partition_for(&listed).as_deref(), Some("/dev/sdb3"));
+        assert_eq!(managed.partition_for(&drive("WD-2", vec![partition("sdc1", None)])).as_deref(), Some("sdc9"));
+        let labelled = drive("WD-3", vec![partition("sdd1", Some("scratch")), partition("sdd2", Some("tiered"))]);
+        assert_eq!(managed.partition_for(&labelled).as_deref(), Some("/dev/sdd2"));
+        assert_eq!(managed.partition_for(&drive("WD-4", vec![partition("sde1", None)])), None);
+        assert_eq!(ManagedPartitions::from_config(&json!({})).partition_for(&labelled), None);
+    }
+}
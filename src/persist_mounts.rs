@@ -0,0 +1,246 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+pub const FSTAB_PATH: &str = "/etc/fstab";
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+const FSTAB_BEGIN: &str = "# BEGIN drive-manager: written on each start, edits here are replaced";
+const FSTAB_END: &str = "# END drive-manager";
+// marks the units this writes, so those of drives gone since are removed
+// and nobody else's are touched
+const UNIT_HEADER: &str = "# Generated by drive-manager, replaced on each start";
+
+// Where the drive and pool mounts are written down so they come back at
+// boot without the service, from the config's persist_mounts. Left at
+// none, nothing outside /mnt is touched.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Persistence {
+    #[default]
+    None,
+    Fstab,
+    // a .mount unit per drive, and per pool a .mount started on first
+    // access through its .automount, so a late drive does not hold up boot
+    Systemd,
+}
+
+// A mount as fstab and systemd both describe it
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountEntry {
+    pub what: String,
+    pub target: String,
+    pub fstype: String,
+    pub options: Vec<String>,
+    // mount points that must be up first, the branches of a pool
+    pub requires: Vec<String>,
+}
+
+impl MountEntry {
+    fn is_pool(&self) -> bool {
+        self.fstype == "fuse.mergerfs"
+    }
+}
+
+// fstab fields are split on whitespace, so it is written as octal escapes
+fn fstab_escape(field: &str) -> String {
+    field.replace('\\', "\\134").replace(' ', "\\040").replace('\t', "\\011").replace('\n', "\\012")
+}
+
+fn fstab_line(entry: &MountEntry) -> String {
+    let options: Vec<String> = entry.options.iter().cloned()
+        .chain(["nofail".to_string()])
+        .chain(entry.requires.iter().map(|path| format!("x-systemd.requires-mounts-for={}", path)))
+        .collect();
+    format!("{} {} {} {} 0 0", fstab_escape(&entry.what), fstab_escape(&entry.target), entry.fstype, fstab_escape(&options.join(",")))
+}
+
+// `fstab` with its drive-manager block replaced by `entries`, or dropped
+// when there are none. The lines around it are kept as they are.
+pub fn update_fstab(fstab: &str, entries: &[MountEntry]) -> String {
+    let mut lines = Vec::new();
+    let mut inside = false;
+    for line in fstab.lines() {
+        match line {
+            FSTAB_BEGIN => inside = true,
+            FSTAB_END => inside = false,
+            _ if !inside => lines.push(line.to_string()),
+            _ => {}
+        }
+    }
+    if !entries.is_empty() {
+        lines.push(FSTAB_BEGIN.to_string());
+        lines.extend(entries.iter().map(fstab_line));
+        lines.push(FSTAB_END.to_string());
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+// A unit name for a mount point, escaped the way `systemd-escape --path` does
+pub fn unit_name(target: &str, suffix: &str) -> String {
+    let path = target.trim_matches('/');
+    if path.is_empty() {
+        return format!("-.{}", suffix);
+    }
+    let mut name = String::new();
+    for (i, byte) in path.bytes().enumerate() {
+        match byte {
+            b'/' => name.push('-'),
+            b'.' if i == 0 => name.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => name.push(byte as char),
+            _ => name.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    format!("{}.{}", name, suffix)
+}
+
+// The units for `entries`, as (file name, contents), and which of them to
+// enable
+pub fn units(entries: &[MountEntry]) -> Vec<(String, String, bool)> {
+    let mut units = Vec::new();
+    for entry in entries {
+        // the setting takes a space separated list, so a path with spaces is quoted
+        let requires: String = entry.requires.iter()
+            .map(|path| if path.contains(char::is_whitespace) { format!("\"{}\"", path) } else { path.clone() })
+            .map(|path| format!("RequiresMountsFor={}\n", path))
+            .collect();
+        // a pool is started by its automount, a drive at boot
+        let install = if entry.is_pool() { "" } else { "\n[Install]\nWantedBy=local-fs.target\n" };
+        units.push((unit_name(&entry.target, "mount"), format!(
+            "{}\n[Unit]\nDescription=drive-manager mount of {}\n{}\n[Mount]\nWhat={}\nWhere={}\nType={}\nOptions={}\n{}",
+            UNIT_HEADER, entry.target, requires, entry.what, entry.target, entry.fstype, entry.options.join(","), install,
+        ), !entry.is_pool()));
+        if entry.is_pool() {
+            units.push((unit_name(&entry.target, "automount"), format!(
+                "{}\n[Unit]\nDescription=drive-manager automount of {}\n\n[Automount]\nWhere={}\n\n[Install]\nWantedBy=local-fs.target\n",
+                UNIT_HEADER, entry.target, entry.target,
+            ), true));
+        }
+    }
+    units
+}
+
+// Write `fstab` with `entries` in its block, if that changes it. Returns
+// whether it did.
+pub fn write_fstab(fstab: &Path, entries: &[MountEntry]) -> io::Result<bool> {
+    let current = match fs::read_to_string(fstab) {
+        Ok(current) => current,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let updated = update_fstab(&current, entries);
+    if updated == current {
+        return Ok(false);
+    }
+    // a half written fstab can keep the machine from booting
+    let mut staged = fstab.as_os_str().to_owned();
+    staged.push(".drive-manager");
+    fs::write(&staged, updated)?;
+    fs::rename(&staged, fstab)?;
+    Ok(true)
+}
+
+// Write `units` into `dir` and remove the ones written before that are no
+// longer wanted. Returns the units written or removed.
+pub fn write_units(dir: &Path, units: &[(String, String, bool)]) -> io::Result<Vec<PathBuf>> {
+    let mut changed = Vec::new();
+    for (name, contents, _) in units {
+        let path = dir.join(name);
+        if fs::read_to_string(&path).ok().as_deref() != Some(contents.as_str()) {
+            fs::write(&path, contents)?;
+            changed.push(path);
+        }
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(changed),
+        Err(e) => return Err(e),
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let ours = fs::read_to_string(&path).is_ok_and(|contents| contents.starts_with(UNIT_HEADER));
+        if ours && !units.iter().any(|(unit, _, _)| *unit == name) {
+            fs::remove_file(&path)?;
+            changed.push(path);
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn entries() -> Vec<MountEntry> {
+        vec![
+            MountEntry {
+                what: "UUID=1234".to_string(),
+                target: "/mnt/physical/ssd/S1 A".to_string(),
+                fstype: "ext4".to_string(),
+                options: vec!["defaults".to_string()],
+                requires: vec![],
+            },
+            MountEntry {
+                what: "/mnt/physical/ssd/S1 A".to_string(),
+                target: "/mnt/merged/hot".to_string(),
+                fstype: "fuse.mergerfs".to_string(),
+                options: vec!["allow_other".to_string(), "category.create=mfs".to_string()],
+                requires: vec!["/mnt/physical/ssd/S1 A".to_string()],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_update_fstab() {
+        let fstab = "UUID=root / ext4 defaults 0 1\n";
+        let written = update_fstab(fstab, &entries());
+        assert_eq!(written, format!(
+            "UUID=root / ext4 defaults 0 1\n{}\n\
+             UUID=1234 /mnt/physical/ssd/S1\\040A ext4 defaults,nofail 0 0\n\
+             /mnt/physical/ssd/S1\\040A /mnt/merged/hot fuse.mergerfs allow_other,category.create=mfs,nofail,x-systemd.requires-mounts-for=/mnt/physical/ssd/S1\\040A 0 0\n{}\n",
+            FSTAB_BEGIN, FSTAB_END,
+        ));
+        // rewriting replaces the block rather than adding another
+        assert_eq!(update_fstab(&written, &entries()), written);
+        assert_eq!(update_fstab(&(written + "tmpfs /tmp tmpfs defaults 0 0\n"), &[]), "UUID=root / ext4 defaults 0 1\ntmpfs /tmp tmpfs defaults 0 0\n");
+    }
+
+    #[test]
+    fn test_unit_name() {
+        assert_eq!(unit_name("/mnt/merged/hot", "mount"), "mnt-merged-hot.mount");
+        assert_eq!(unit_name("/mnt/physical/ssd/S1 A-2", "mount"), "mnt-physical-ssd-S1\\x20A\\x2d2.mount");
+        assert_eq!(unit_name("/", "mount"), "-.mount");
+    }
+
+    #[test]
+    fn test_write_units() {
+        let dir = tempdir().unwrap();
+        let units = units(&entries());
+        assert_eq!(units.iter().map(|(name, _, enable)| (name.as_str(), *enable)).collect::<Vec<_>>(), [
+            ("mnt-physical-ssd-S1\\x20A.mount", true),
+            ("mnt-merged-hot.mount", false),
+            ("mnt-merged-hot.automount", true),
+        ]);
+        assert!(units[1].1.contains("RequiresMountsFor=\"/mnt/physical/ssd/S1 A\"\n"));
+        assert!(!units[1].1.contains("[Install]"));
+        fs::write(dir.path().join("backup.mount"), "[Mount]\n").unwrap();
+        fs::write(dir.path().join("mnt-physical-hdd-GONE.mount"), format!("{}\n[Mount]\n", UNIT_HEADER)).unwrap();
+        assert_eq!(write_units(dir.path(), &units).unwrap().len(), 4);
+        assert!(dir.path().join("backup.mount").exists());
+        assert!(!dir.path().join("mnt-physical-hdd-GONE.mount").exists());
+        assert!(write_units(dir.path(), &units).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_fstab() {
+        let dir = tempdir().unwrap();
+        let fstab = dir.path().join("fstab");
+        fs::write(&fstab, "UUID=root / ext4 defaults 0 1\n").unwrap();
+        assert!(write_fstab(&fstab, &entries()).unwrap());
+        assert!(!write_fstab(&fstab, &entries()).unwrap());
+        assert!(write_fstab(&fstab, &[]).unwrap());
+        assert_eq!(fs::read_to_string(&fstab).unwrap(), "UUID=root / ext4 defaults 0 1\n");
+    }
+}
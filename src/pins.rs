@@ -0,0 +1,83 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use crate::drive_manager::DriveManager;
+use crate::grouping::glob_match;
+
+// A path held on one tier, from the pins config list
+//   "pins": [{ "path": "vms", "tier": "hot" }, { "path": "*.iso", "tier": "cold" }]
+// or from `drive-manager pin`. A directory pins everything under it and a
+// glob is matched against the whole pool path. Pinned files are left out of
+// promotion and demotion, and moved to their tier if they are elsewhere.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pin {
+    // relative to the pool, as every path we track is, and like them need
+    // not be UTF-8
+    pub path: PathBuf,
+    pub tier: String,
+}
+
+impl Pin {
+    pub fn matches(&self, path: &Path) -> bool {
+        path.starts_with(&self.path) || glob_match(self.path.as_os_str().as_bytes(), path.as_os_str().as_bytes())
+    }
+}
+
+// A path as given on the command line, made relative to the pool. The
+// mergerfs mount of any tier is stripped, as they all show the same files.
+pub fn pool_relative(path: &Path) -> PathBuf {
+    let relative = DriveManager::TIERS.iter()
+        .find_map(|tier| path.strip_prefix(Path::new(DriveManager::MERGERFS_MOUNT_PATH).join(tier)).ok())
+        .unwrap_or(path);
+    let bytes = relative.as_os_str().as_bytes();
+    let start = bytes.iter().position(|byte| *byte != b'/').unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|byte| *byte != b'/').map_or(start, |last| last + 1);
+    PathBuf::from(OsStr::from_bytes(&bytes[start..end]))
+}
+
+pub fn pins(config: &Value) -> Vec<Pin> {
+    config.get("pins").and_then(Value::as_array).into_iter().flatten().filter_map(|pin| {
+        let path = pool_relative(Path::new(pin.get("path").and_then(Value::as_str)?));
+        let tier = pin.get("tier").and_then(Value::as_str).filter(|tier| DriveManager::TIERS.contains(tier))?;
+        (!path.as_os_str().is_empty()).then(|| Pin { path, tier: tier.to_string() })
+    }).collect()
+}
+
+// The first pin holding `path`, if any
+pub fn find<'a>(pins: &'a [Pin], path: &Path) -> Option<&'a Pin> {
+    pins.iter().find(|pin| pin.matches(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_pins() {
+        let pins = pins(&json!({ "pins": [
+            { "path": "/vms/", "tier": "hot" },
+            { "path": "*.iso", "tier": "cold" },
+            { "path": "media", "tier": "frozen" },
+            { "path": "/", "tier": "hot" },
+        ] }));
+        assert_eq!(pins, [
+            Pin { path: PathBuf::from("vms"), tier: "hot".to_string() },
+            Pin { path: PathBuf::from("*.iso"), tier: "cold".to_string() },
+        ]);
+        assert_eq!(find(&pins, Path::new("vms/win11.qcow2")).unwrap().tier, "hot");
+        assert_eq!(find(&pins, Path::new("isos/debian.iso")).unwrap().tier, "cold");
+        assert_eq!(find(&pins, Path::new("vmsbackup/a.img")), None);
+        assert_eq!(pool_relative(Path::new("/mnt/merged/warm/vms/win11.qcow2")), Path::new("vms/win11.qcow2"));
+        assert_eq!(pool_relative(Path::new("vms/")), Path::new("vms"));
+        let latin1 = pool_relative(Path::new(OsStr::from_bytes(b"/mnt/merged/hot/TV/caf\xe9.mkv")));
+        assert_eq!(latin1, Path::new(OsStr::from_bytes(b"TV/caf\xe9.mkv")));
+        // a name that is not UTF-8 is pinned as it is
+        let pins = [Pin { path: latin1.clone(), tier: "hot".to_string() }, Pin { path: PathBuf::from(OsStr::from_bytes(b"TV/*\xe9*")), tier: "cold".to_string() }];
+        assert_eq!(find(&pins, &latin1).unwrap().tier, "hot");
+        assert_eq!(find(&pins, Path::new(OsStr::from_bytes(b"TV/r\xe9sum\xe9.mkv"))).unwrap().tier, "cold");
+        assert_eq!(find(&pins, Path::new("TV/cafe.mkv")), None);
+        assert_eq!(pool_relative(Path::new("/")), Path::new(""));
+    }
+}
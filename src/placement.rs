@@ -0,0 +1,139 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use log::warn;
+use crate::drive_manager::DriveManager;
+
+// Extended attributes applications can set on a file to steer placement,
+// e.g. `setfattr -n user.drivemanager.tier -v hot movie.mkv`. rsync -X
+// carries them along when the file is moved, so a tag only has to be set
+// once. Unknown values are logged and ignored.
+//
+// user.drivemanager.tier      hot | warm | cold  keep the file on this tier
+// user.drivemanager.priority  high | normal | low  low files are demoted first, high last
+// user.drivemanager.exclude   1 | true  never move the file
+pub const XATTR_TIER: &str = "user.drivemanager.tier";
+pub const XATTR_PRIORITY: &str = "user.drivemanager.priority";
+pub const XATTR_EXCLUDE: &str = "user.drivemanager.exclude";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PlacementPriority {
+    Low = -1,
+    #[default]
+    Normal = 0,
+    High = 1,
+}
+
+impl PlacementPriority {
+    pub fn from_i64(value: i64) -> Self {
+        match value {
+            i64::MIN..=-1 => PlacementPriority::Low,
+            0 => PlacementPriority::Normal,
+            _ => PlacementPriority::High,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Placement {
+    // tier the file is pinned to
+    pub tier: Option<String>,
+    pub priority: PlacementPriority,
+    pub exclude: bool,
+}
+
+impl Placement {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // Build a placement from xattr values, skipping ones that do not parse
+    pub fn from_attrs(path: &Path, tier: Option<&str>, priority: Option<&str>, exclude: Option<&str>) -> Self {
+        let mut placement = Self::default();
+        if let Some(tier) = tier {
+            match DriveManager::TIERS.iter().find(|t| **t == tier) {
+                Some(tier) => placement.tier = Some(tier.to_string()),
+                None => warn!("Ignoring {}={} on {}", XATTR_TIER, tier, path.display()),
+            }
+        }
+        match priority {
+            Some("high") => placement.priority = PlacementPriority::High,
+            Some("low") => placement.priority = PlacementPriority::Low,
+            Some("normal") | None => {}
+            Some(other) => warn!("Ignoring {}={} on {}", XATTR_PRIORITY, other, path.display()),
+        }
+        match exclude {
+            Some("1" | "true") => placement.exclude = true,
+            Some("0" | "false") | None => {}
+            Some(other) => warn!("Ignoring {}={} on {}", XATTR_EXCLUDE, other, path.display()),
+        }
+        placement
+    }
+
+    // Read the placement tags on a file. Filesystems without user xattr
+    // support just have no tags.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let tier = get_xattr(path, XATTR_TIER)?;
+        let priority = get_xattr(path, XATTR_PRIORITY)?;
+        let exclude = get_xattr(path, XATTR_EXCLUDE)?;
+        Ok(Self::from_attrs(path, tier.as_deref(), priority.as_deref(), exclude.as_deref()))
+    }
+}
+
+fn get_xattr(path: &Path, name: &str) -> io::Result<Option<String>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    let mut value = [0u8; 64];
+    let len = unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+    if len < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            // longer than any value in the contract
+            Some(libc::ERANGE) => Ok(Some(String::new())),
+            _ => Err(e),
+        };
+    }
+    Ok(Some(String::from_utf8_lossy(&value[..len as usize]).trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_xattr(path: &Path, name: &str, value: &str) -> io::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let c_name = CString::new(name)?;
+        if unsafe { libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_attrs() {
+        let path = Path::new("a.mkv");
+        assert!(Placement::from_attrs(path, None, None, None).is_default());
+        let placement = Placement::from_attrs(path, Some("hot"), Some("low"), Some("1"));
+        assert_eq!(placement, Placement { tier: Some("hot".to_string()), priority: PlacementPriority::Low, exclude: true });
+        assert!(Placement::from_attrs(path, Some("lukewarm"), Some("urgent"), Some("yes")).is_default());
+        assert!(PlacementPriority::Low < PlacementPriority::High);
+        assert_eq!(PlacementPriority::from_i64(PlacementPriority::High as i64), PlacementPriority::High);
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.mkv");
+        std::fs::write(&path, "test data").unwrap();
+        assert!(Placement::read(&path).unwrap().is_default());
+        if let Err(e) = set_xattr(&path, XATTR_TIER, "cold") {
+            // the temp filesystem has no user xattrs
+            assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP));
+            return;
+        }
+        set_xattr(&path, XATTR_PRIORITY, "high").unwrap();
+        assert_eq!(Placement::read(&path).unwrap(), Placement { tier: Some("cold".to_string()), priority: PlacementPriority::High, exclude: false });
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::storage::TierUsage;
+
+const GB: u64 = 1 << 30;
+
+// What the moves one --observe check would make add up to: the data per
+// source->target lane, how long that should take at the throughput each
+// lane has managed before, and how full each tier would be afterwards
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Plan {
+    // files and bytes by (source, target)
+    pub lanes: BTreeMap<(String, String), (usize, u64)>,
+    // bytes per second moved in each lane before, where there is history
+    pub rates: HashMap<(String, String), f64>,
+    pub usage: Vec<(String, TierUsage)>,
+    // each move, in the order the check queued them
+    pub moves: Vec<PlannedMove>,
+}
+
+// One move a check would make, and what made it
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedMove {
+    pub path: PathBuf,
+    pub size: u64,
+    pub source_tier: String,
+    pub target_tier: String,
+    pub reason: String,
+}
+
+impl Plan {
+    pub fn add(&mut self, source_tier: &str, target_tier: &str, bytes: u64) {
+        let lane = self.lanes.entry((source_tier.to_string(), target_tier.to_string())).or_default();
+        lane.0 += 1;
+        lane.1 += bytes;
+    }
+
+    pub fn add_move(&mut self, planned: PlannedMove) {
+        self.add(&planned.source_tier, &planned.target_tier, planned.size);
+        self.moves.push(planned);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.is_empty()
+    }
+
+    pub fn lane_duration(&self, lane: &(String, String)) -> Option<Duration> {
+        let rate = self.rates.get(lane).copied().filter(|rate| *rate > 0.0)?;
+        Some(Duration::from_secs_f64(self.lanes.get(lane)?.1 as f64 / rate))
+    }
+
+    // Lanes move side by side, so the longest one, as long as every lane
+    // has history to go on
+    pub fn duration(&self) -> Option<Duration> {
+        self.lanes.keys().map(|lane| self.lane_duration(lane)).try_fold(Duration::ZERO, |longest, lane| Some(longest.max(lane?)))
+    }
+
+    // Each tier's usage once the moves are done
+    pub fn usage_after(&self) -> Vec<(String, TierUsage)> {
+        self.usage.iter().map(|(tier, usage)| {
+            let (out, into) = self.lanes.iter().fold((0, 0), |(out, into), ((source, target), (_, bytes))| {
+                (out + if source == tier { *bytes } else { 0 }, into + if target == tier { *bytes } else { 0 })
+            });
+            (tier.clone(), TierUsage { total: usage.total, used: (usage.used + into).saturating_sub(out) })
+        }).collect()
+    }
+}
+
+fn hours(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (files, bytes) = self.lanes.values().fold((0, 0), |(files, bytes), lane| (files + lane.0, bytes + lane.1));
+        writeln!(f, "Plan: {} moves, {:.1} GB", files, bytes as f64 / GB as f64)?;
+        for (lane, (files, bytes)) in &self.lanes {
+            let estimate = self.lane_duration(lane).map_or("no past moves to estimate from".to_string(), |duration| format!("about {}", hours(duration)));
+            writeln!(f, "  {} -> {}: {} moves, {:.1} GB, {}", lane.0, lane.1, files, *bytes as f64 / GB as f64, estimate)?;
+        }
+        if let Some(duration) = self.duration() {
+            writeln!(f, "  about {} in all, lanes moving side by side", hours(duration))?;
+        }
+        for ((tier, before), (_, after)) in self.usage.iter().zip(self.usage_after()) {
+            if before.total > 0 {
+                writeln!(f, "  {:<4} {:.1}% -> {:.1}% full", tier, before.usage_percent(), after.usage_percent())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lane(source: &str, target: &str) -> (String, String) {
+        (source.to_string(), target.to_string())
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut plan = Plan::default();
+        plan.add("hot", "warm", 6 * GB);
+        plan.add("hot", "warm", 4 * GB);
+        plan.add("warm", "cold", 20 * GB);
+        plan.usage = vec![
+            ("hot".to_string(), TierUsage { total: 100 * GB, used: 90 * GB }),
+            ("warm".to_string(), TierUsage { total: 100 * GB, used: 50 * GB }),
+            ("cold".to_string(), TierUsage { total: 0, used: 0 }),
+        ];
+        assert_eq!(plan.lanes[&lane("hot", "warm")], (2, 10 * GB));
+        assert_eq!(plan.duration(), None);
+        plan.rates.insert(lane("hot", "warm"), (100 << 20) as f64);
+        plan.rates.insert(lane("warm", "cold"), (50 << 20) as f64);
+        assert_eq!(plan.lane_duration(&lane("hot", "warm")).unwrap().as_secs(), 102);
+        assert_eq!(plan.duration().unwrap().as_secs(), 409);
+        assert_eq!(plan.usage_after()[..2], [
+            ("hot".to_string(), TierUsage { total: 100 * GB, used: 80 * GB }),
+            ("warm".to_string(), TierUsage { total: 100 * GB, used: 40 * GB }),
+        ]);
+        let report = plan.to_string();
+        assert!(report.starts_with("Plan: 3 moves, 30.0 GB\n"), "{}", report);
+        assert!(report.contains("  hot -> warm: 2 moves, 10.0 GB, about 0h 01m\n"), "{}", report);
+        assert!(report.contains("  hot  90.0% -> 80.0% full\n"), "{}", report);
+        assert!(!report.contains("cold "), "{}", report);
+
+        let mut plan = Plan::default();
+        plan.add_move(PlannedMove { path: "a.mkv".into(), size: GB, source_tier: "hot".to_string(), target_tier: "warm".to_string(), reason: "hot is over its capacity threshold".to_string() });
+        assert_eq!(plan.lanes[&lane("hot", "warm")], (1, GB));
+        assert_eq!(plan.moves[0].path, PathBuf::from("a.mkv"));
+    }
+}
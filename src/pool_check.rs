@@ -0,0 +1,122 @@
+use std::fmt;
+use std::path::Path;
+use serde_json::{json, Value};
+use crate::file_metadata::{Drive, DriveState};
+
+// How a merged mount found at start differs from the drives it should pool
+#[derive(Clone, Debug, PartialEq)]
+pub enum Discrepancy {
+    // an active drive's branch the pool does not have
+    Missing { tier: String, serial: String, path: String },
+    // a branch no active drive is mounted at, with the registry's drive of
+    // that name if there is one
+    Stale { tier: String, path: String, drive: Option<(String, DriveState)> },
+    // a drive the registry has active that could not be mounted, so there
+    // is no branch to put back
+    Unavailable { serial: String, tier: String },
+}
+
+impl Discrepancy {
+    // Whether setting the pools' branches puts it right
+    pub fn repairable(&self) -> bool {
+        !matches!(self, Discrepancy::Unavailable { .. })
+    }
+
+    pub fn details(&self) -> Value {
+        match self {
+            Discrepancy::Missing { tier, serial, path } => json!({ "kind": "missing", "tier": tier, "serial": serial, "path": path }),
+            Discrepancy::Stale { tier, path, drive } => json!({ "kind": "stale", "tier": tier, "path": path, "serial": drive.as_ref().map(|(serial, _)| serial) }),
+            Discrepancy::Unavailable { serial, tier } => json!({ "kind": "unavailable", "tier": tier, "serial": serial }),
+        }
+    }
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Discrepancy::Missing { tier, serial, path } => write!(f, "the {} pool is missing the branch {} of active drive {}", tier, path, serial),
+            Discrepancy::Stale { tier, path, drive: None } => write!(f, "the {} pool has the branch {}, which no known drive is mounted at", tier, path),
+            Discrepancy::Stale { tier, path, drive: Some((serial, state)) } => write!(f, "the {} pool has the branch {} of drive {}, which is {}", tier, path, serial, state.as_str()),
+            Discrepancy::Unavailable { serial, tier } => write!(f, "drive {} is active in the {} tier but could not be mounted, so its files are unavailable", serial, tier),
+        }
+    }
+}
+
+// The branch paths in the value of a pool's user.mergerfs.branches, which
+// lists them as "/mnt/a=RW:/mnt/b=RW,1073741824"
+pub fn parse_branches(value: &str) -> Vec<String> {
+    value.trim().split(':').filter(|branch| !branch.is_empty()).map(|branch| {
+        match branch.rfind('=') {
+            Some(at) if ["RW", "RO", "NC"].iter().any(|mode| branch[at + 1..].starts_with(mode)) => branch[..at].to_string(),
+            _ => branch.to_string(),
+        }
+    }).collect()
+}
+
+// Compare the branches of each mounted pool, by tier, with the (serial,
+// mount point) of the drives each should have. The registry names the
+// drive behind a stale branch, from the serial its mount point ends in,
+// and has the active drives that never made it into `expected`.
+pub fn check(current: &[(String, Vec<String>)], expected: &[(String, Vec<(String, String)>)], registry: &[Drive]) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+    for (tier, branches) in current {
+        let wanted = expected.iter().find(|(expected_tier, _)| expected_tier == tier).map_or(&[][..], |(_, drives)| drives.as_slice());
+        for (serial, path) in wanted.iter().filter(|(_, path)| !branches.contains(path)) {
+            discrepancies.push(Discrepancy::Missing { tier: tier.clone(), serial: serial.clone(), path: path.clone() });
+        }
+        for path in branches.iter().filter(|path| !wanted.iter().any(|(_, wanted)| wanted == *path)) {
+            let name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned());
+            let drive = registry.iter().find(|drive| Some(&drive.serial) == name.as_ref()).map(|drive| (drive.serial.clone(), drive.state));
+            discrepancies.push(Discrepancy::Stale { tier: tier.clone(), path: path.clone(), drive });
+        }
+    }
+    let pooled = |serial: &str| expected.iter().any(|(_, drives)| drives.iter().any(|(expected, _)| expected == serial));
+    for drive in registry.iter().filter(|drive| drive.state == DriveState::Active && !pooled(&drive.serial)) {
+        discrepancies.push(Discrepancy::Unavailable { serial: drive.serial.clone(), tier: drive.tier.clone() });
+    }
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn drive(serial: &str, tier: &str, state: DriveState) -> Drive {
+        Drive { serial: serial.to_string(), tier: tier.to_string(), state, first_seen: UNIX_EPOCH, last_seen: UNIX_EPOCH, pooled_at: None }
+    }
+
+    #[test]
+    fn test_parse_branches() {
+        assert_eq!(parse_branches("/mnt/physical/ssd/S1=RW:/mnt/physical/hdd/WD-1=RW,1073741824\n"), ["/mnt/physical/ssd/S1", "/mnt/physical/hdd/WD-1"]);
+        assert_eq!(parse_branches("/mnt/a=b=RO"), ["/mnt/a=b"]);
+        assert_eq!(parse_branches("/mnt/a"), ["/mnt/a"]);
+        assert!(parse_branches("").is_empty());
+    }
+
+    #[test]
+    fn test_check() {
+        let current = vec![
+            ("warm".to_string(), vec!["/mnt/physical/ssd/S1".to_string(), "/mnt/physical/hdd/OLD".to_string(), "/mnt/physical/hdd/WD-1".to_string()]),
+            ("cold".to_string(), vec!["/mnt/physical/hdd/WD-1".to_string(), "/mnt/physical/hdd/GONE".to_string()]),
+        ];
+        let expected = vec![
+            ("hot".to_string(), vec![("N1".to_string(), "/mnt/physical/nvme/N1".to_string())]),
+            ("warm".to_string(), vec![("S1".to_string(), "/mnt/physical/ssd/S1".to_string()), ("WD-1".to_string(), "/mnt/physical/hdd/WD-1".to_string()), ("WD-2".to_string(), "/mnt/physical/hdd/WD-2".to_string())]),
+            ("cold".to_string(), vec![("WD-1".to_string(), "/mnt/physical/hdd/WD-1".to_string()), ("WD-2".to_string(), "/mnt/physical/hdd/WD-2".to_string())]),
+        ];
+        let registry = [drive("OLD", "cold", DriveState::Retired), drive("WD-3", "cold", DriveState::Active), drive("WD-4", "cold", DriveState::Missing)];
+        let discrepancies = check(&current, &expected, &registry);
+        assert_eq!(discrepancies, [
+            Discrepancy::Missing { tier: "warm".to_string(), serial: "WD-2".to_string(), path: "/mnt/physical/hdd/WD-2".to_string() },
+            Discrepancy::Stale { tier: "warm".to_string(), path: "/mnt/physical/hdd/OLD".to_string(), drive: Some(("OLD".to_string(), DriveState::Retired)) },
+            Discrepancy::Missing { tier: "cold".to_string(), serial: "WD-2".to_string(), path: "/mnt/physical/hdd/WD-2".to_string() },
+            Discrepancy::Stale { tier: "cold".to_string(), path: "/mnt/physical/hdd/GONE".to_string(), drive: None },
+            Discrepancy::Unavailable { serial: "WD-3".to_string(), tier: "cold".to_string() },
+        ]);
+        assert_eq!(discrepancies[1].to_string(), "the warm pool has the branch /mnt/physical/hdd/OLD of drive OLD, which is retired");
+        assert!(!discrepancies[4].repairable());
+        // the hot pool is not mounted, so there is nothing to compare
+        assert!(check(&current[..0], &expected, &[]).is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde_json::Value;
+use crate::executor::Executor;
+
+pub const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+pub const CHECK_SEC: u64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerState {
+    Mains,
+    Battery,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PowerSource {
+    // a NUT UPS as upsc names it, e.g. "ups@localhost"
+    Nut(String),
+    // the kernel's power supplies, for laptops and boards on a battery
+    Sysfs(PathBuf),
+}
+
+// Where the power state comes from, from the power config section:
+//   { "ups": "ups@localhost", "check_sec": 30 }
+// or, without a UPS, { "power_supply_dir": "/sys/class/power_supply" }.
+// On battery, background moves are held back until mains power returns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerMonitor {
+    pub source: PowerSource,
+    pub check_interval: Duration,
+}
+
+impl PowerMonitor {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("power").filter(|section| section.is_object())?;
+        let source = match section.get("ups").and_then(Value::as_str) {
+            Some(ups) => PowerSource::Nut(ups.to_string()),
+            None => PowerSource::Sysfs(PathBuf::from(section.get("power_supply_dir").and_then(Value::as_str).unwrap_or(POWER_SUPPLY_DIR))),
+        };
+        let check_interval = Duration::from_secs(section.get("check_sec").and_then(Value::as_u64).unwrap_or(CHECK_SEC));
+        Some(Self { source, check_interval })
+    }
+
+    pub fn state(&self, executor: &dyn Executor) -> io::Result<PowerState> {
+        match &self.source {
+            PowerSource::Nut(ups) => {
+                let output = executor.output(&["upsc".as_ref(), ups.as_ref(), "ups.status".as_ref()])?;
+                if !output.status.success() {
+                    return Err(io::Error::other(format!("upsc {} failed: {}", ups, String::from_utf8_lossy(&output.stderr).trim())));
+                }
+                Ok(ups_state(&String::from_utf8_lossy(&output.stdout)))
+            }
+            PowerSource::Sysfs(dir) => sysfs_state(dir),
+        }
+    }
+}
+
+// ups.status is a list of flags; OB is "on battery"
+pub fn ups_state(status: &str) -> PowerState {
+    let status = status.trim().strip_prefix("ups.status:").unwrap_or(status);
+    if status.split_whitespace().any(|flag| flag == "OB") { PowerState::Battery } else { PowerState::Mains }
+}
+
+// On battery when there are mains or USB supplies and none is online, or
+// when there are none and a battery is discharging
+fn sysfs_state(dir: &Path) -> io::Result<PowerState> {
+    let read = |supply: &Path, name: &str| fs::read_to_string(supply.join(name)).map(|value| value.trim().to_string()).unwrap_or_default();
+    let (mut external, mut online, mut discharging) = (0, 0, false);
+    for entry in fs::read_dir(dir)? {
+        let supply = entry?.path();
+        match read(&supply, "type").as_str() {
+            "Mains" | "USB" => {
+                external += 1;
+                online += usize::from(read(&supply, "online") == "1");
+            }
+            "Battery" => discharging |= read(&supply, "status") == "Discharging",
+            _ => {}
+        }
+    }
+    let on_battery = if external > 0 { online == 0 } else { discharging };
+    Ok(if on_battery { PowerState::Battery } else { PowerState::Mains })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    fn supply(dir: &Path, name: &str, files: &[(&str, &str)]) {
+        fs::create_dir_all(dir.join(name)).unwrap();
+        for (file, value) in files {
+            fs::write(dir.join(name).join(file), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_states() {
+        assert_eq!(ups_state("OB DISCHRG\n"), PowerState::Battery);
+        assert_eq!(ups_state("ups.status: OL CHRG"), PowerState::Mains);
+
+        let dir = tempdir().unwrap();
+        supply(dir.path(), "BAT0", &[("type", "Battery"), ("status", "Discharging")]);
+        assert_eq!(sysfs_state(dir.path()).unwrap(), PowerState::Battery);
+        supply(dir.path(), "AC", &[("type", "Mains"), ("online", "1")]);
+        assert_eq!(sysfs_state(dir.path()).unwrap(), PowerState::Mains);
+        supply(dir.path(), "AC", &[("type", "Mains"), ("online", "0")]);
+        assert_eq!(sysfs_state(dir.path()).unwrap(), PowerState::Battery);
+    }
+
+    #[test]
+    fn test_config() {
+        let nut = PowerMonitor::from_config(&json!({ "power": { "ups": "ups@nas" } })).unwrap();
+        assert_eq!(nut.source, PowerSource::Nut("ups@nas".to_string()));
+        assert_eq!(nut.check_interval, Duration::from_secs(CHECK_SEC));
+        let sysfs = PowerMonitor::from_config(&json!({ "power": {} })).unwrap();
+        assert_eq!(sysfs.source, PowerSource::Sysfs(PathBuf::from(POWER_SUPPLY_DIR)));
+        assert!(PowerMonitor::from_config(&json!({})).is_none());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+// without a terminal, an item is logged again each time it gets this many
+// percent further
+const PLAIN_STEP_PERCENT: u64 = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressItem {
+    pub label: String,
+    pub done: u64,
+    pub total: u64,
+    // rate, ETA and the like, shown after the bar
+    pub detail: String,
+}
+
+impl ProgressItem {
+    pub fn percent(&self) -> u64 {
+        (self.done.min(self.total) * 100).checked_div(self.total).unwrap_or(0)
+    }
+}
+
+pub fn bar(done: u64, total: u64, width: usize) -> String {
+    let filled = if total == 0 { 0 } else { (done.min(total) as u128 * width as u128 / total as u128) as usize };
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(width - filled))
+}
+
+// Output for commands someone runs and watches. On a terminal, each item
+// gets a bar that is redrawn in place, with per-file messages scrolling
+// above the bars. Otherwise, such as when piped or logged, the same things
+// come out as plain lines, with an item repeated only as it makes progress.
+pub struct Progress<W: Write> {
+    out: W,
+    tty: bool,
+    // bar lines currently on screen
+    drawn: usize,
+    // the step each item was last logged at without a terminal
+    logged: HashMap<String, u64>,
+    started: Instant,
+}
+
+impl Progress<io::Stdout> {
+    pub fn stdout() -> Self {
+        let tty = io::stdout().is_terminal() && std::env::var("TERM").map_or(true, |term| term != "dumb");
+        Self::new(io::stdout(), tty)
+    }
+}
+
+impl<W: Write> Progress<W> {
+    pub fn new(out: W, tty: bool) -> Self {
+        Self { out, tty, drawn: 0, logged: HashMap::new(), started: Instant::now() }
+    }
+
+    pub fn is_tty(&self) -> bool {
+        self.tty
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn draw(&mut self, items: &[ProgressItem]) -> io::Result<()> {
+        if !self.tty {
+            for item in items {
+                let step = item.percent() / PLAIN_STEP_PERCENT;
+                if self.logged.insert(item.label.clone(), step) != Some(step) {
+                    writeln!(self.out, "{}  {}%  {}", item.label, item.percent(), item.detail)?;
+                }
+            }
+            return self.out.flush();
+        }
+        self.clear()?;
+        for item in items {
+            writeln!(self.out, "{} {:>3}%  {}  {}", bar(item.done, item.total, BAR_WIDTH), item.percent(), item.label, item.detail)?;
+        }
+        self.drawn = items.len();
+        self.out.flush()
+    }
+
+    // A one-off message, such as a file finishing
+    pub fn message(&mut self, message: &str) -> io::Result<()> {
+        self.clear()?;
+        writeln!(self.out, "{}", message)?;
+        self.out.flush()
+    }
+
+    // Take the bars down and print what was done overall
+    pub fn finish(mut self, summary: &str) -> io::Result<()> {
+        self.message(summary)
+    }
+
+    // Erase the bars so what comes next is drawn where they were
+    fn clear(&mut self) -> io::Result<()> {
+        if self.drawn > 0 {
+            write!(self.out, "\x1b[{}A\x1b[J", self.drawn)?;
+            self.drawn = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(label: &str, done: u64) -> ProgressItem {
+        ProgressItem { label: label.to_string(), done, total: 100, detail: "hot -> cold".to_string() }
+    }
+
+    #[test]
+    fn test_bar() {
+        assert_eq!(bar(5, 10, 4), "[##--]");
+        assert_eq!(bar(20, 10, 4), "[####]");
+        assert_eq!(bar(0, 0, 4), "[----]");
+    }
+
+    #[test]
+    fn test_plain() {
+        let mut out = Vec::new();
+        let mut progress = Progress::new(&mut out, false);
+        progress.draw(&[item("a.mkv", 1)]).unwrap();
+        // not far enough along to repeat
+        progress.draw(&[item("a.mkv", 5)]).unwrap();
+        progress.draw(&[item("a.mkv", 12)]).unwrap();
+        progress.finish("1 move finished").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a.mkv  1%  hot -> cold\na.mkv  12%  hot -> cold\n1 move finished\n");
+    }
+
+    #[test]
+    fn test_tty() {
+        let mut out = Vec::new();
+        let mut progress = Progress::new(&mut out, true);
+        progress.draw(&[item("a.mkv", 50), item("b.mkv", 0)]).unwrap();
+        progress.message("finished c.mkv").unwrap();
+        progress.draw(&[item("a.mkv", 100)]).unwrap();
+        progress.finish("done").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], format!("{}  50%  a.mkv  hot -> cold", bar(50, 100, BAR_WIDTH)));
+        // the bars are erased before the message goes out
+        assert_eq!(lines[2], "\x1b[2A\x1b[Jfinished c.mkv");
+        assert_eq!(lines[4], "\x1b[1A\x1b[Jdone");
+    }
+}
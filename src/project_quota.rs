@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde_json::Value;
+
+// FS_IOC_FSGETXATTR and FS_IOC_FSSETXATTR, and the flag that has new files
+// in a directory take its project; libc has none of them
+const FS_IOC_FSGETXATTR: libc::c_ulong = 0x801c581f;
+const FS_IOC_FSSETXATTR: libc::c_ulong = 0x401c5820;
+const FS_XFLAG_PROJINHERIT: u32 = 0x200;
+const XFS_SUPER_MAGIC: libc::c_long = 0x58465342;
+// how often the subtrees are accounted, unless project_quota_sec says otherwise
+pub const REPORT_SEC: u64 = 300;
+
+#[repr(C)]
+#[derive(Default)]
+struct FsXattr {
+    xflags: u32,
+    extsize: u32,
+    nextents: u32,
+    projid: u32,
+    cowextsize: u32,
+    pad: [u8; 8],
+}
+
+// Space accounting by subtree through XFS project quotas. Each subtree gets
+// a project ID on every xfs branch it is on, so xfs_quota can say what it
+// takes without walking it:
+//   "project_quotas": { "tv": 1001, "movies": 1002 }
+// The IDs are given rather than numbered so they stay put when subtrees are
+// added or removed. xfs branches are mounted with prjquota for this.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProjectQuotas {
+    pub subtrees: Vec<(PathBuf, u32)>,
+}
+
+// What a subtree takes on one branch
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubtreeUsage {
+    pub subtree: PathBuf,
+    pub serial: String,
+    pub tier: String,
+    pub bytes: u64,
+}
+
+impl ProjectQuotas {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("project_quotas").and_then(Value::as_object)?;
+        let mut subtrees: Vec<(PathBuf, u32)> = section.iter()
+            .filter_map(|(subtree, id)| Some((PathBuf::from(subtree.trim_matches('/')), u32::try_from(id.as_u64()?).ok().filter(|id| *id > 0)?)))
+            .collect();
+        subtrees.sort_by_key(|(_, id)| *id);
+        (!subtrees.is_empty()).then_some(Self { subtrees })
+    }
+
+    pub fn report_interval(config: &Value) -> Duration {
+        Duration::from_secs(config.get("project_quota_sec").and_then(Value::as_u64).unwrap_or(REPORT_SEC).max(1))
+    }
+
+    // Give everything under each subtree on `branch` its project, with new
+    // files inheriting it. A subtree whose top already has its project is
+    // taken as done, so only new subtrees are walked. Returns how many
+    // files and directories changed.
+    pub fn assign(&self, branch: &Path) -> io::Result<usize> {
+        let mut changed = 0;
+        for (subtree, id) in &self.subtrees {
+            let root = branch.join(subtree);
+            if !root.is_dir() || project_id(&root)? == *id {
+                continue;
+            }
+            changed += set_project(&root, *id)? as usize;
+            let mut failed = None;
+            walk_entries(&root, &mut |path| match set_project(path, *id) {
+                Ok(set) => changed += set as usize,
+                Err(e) => failed = failed.take().or(Some(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))),
+            })?;
+            if let Some(e) = failed {
+                return Err(e);
+            }
+        }
+        Ok(changed)
+    }
+}
+
+// Directories and regular files under `root`; links cannot carry a project
+fn walk_entries(root: &Path, visit: &mut dyn FnMut(&Path)) -> io::Result<()> {
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            visit(&path);
+            walk_entries(&path, visit)?;
+        } else if metadata.is_file() {
+            visit(&path);
+        }
+    }
+    Ok(())
+}
+
+pub fn is_xfs(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_type as libc::c_long == XFS_SUPER_MAGIC)
+}
+
+fn get_xattr(file: &fs::File) -> io::Result<FsXattr> {
+    let mut attr = FsXattr::default();
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR, &mut attr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(attr)
+}
+
+pub fn project_id(path: &Path) -> io::Result<u32> {
+    Ok(get_xattr(&fs::File::open(path)?)?.projid)
+}
+
+// Returns whether anything changed
+fn set_project(path: &Path, id: u32) -> io::Result<bool> {
+    let file = fs::File::open(path)?;
+    let mut attr = get_xattr(&file)?;
+    let xflags = if file.metadata()?.is_dir() { attr.xflags | FS_XFLAG_PROJINHERIT } else { attr.xflags };
+    if attr.projid == id && attr.xflags == xflags {
+        return Ok(false);
+    }
+    attr.projid = id;
+    attr.xflags = xflags;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSSETXATTR, &attr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(true)
+}
+
+pub fn report_command(mountpoint: &Path) -> Vec<OsString> {
+    vec!["xfs_quota".into(), "-x".into(), "-c".into(), "report -p -n -b -N".into(), mountpoint.into()]
+}
+
+// Bytes used by each project in `xfs_quota -c 'report -p -n -b -N'`
+// output, which counts 1KiB blocks:
+//   #1001        1048576          0          0     00 [--------]
+pub fn parse_report(report: &str) -> HashMap<u32, u64> {
+    report.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let id = fields.next()?.strip_prefix('#')?.parse().ok()?;
+        let blocks: u64 = fields.next()?.parse().ok()?;
+        Some((id, blocks * 1024))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_config() {
+        let quotas = ProjectQuotas::from_config(&json!({ "project_quotas": { "/movies/": 1002, "tv": 1001, "bad": 0 } })).unwrap();
+        assert_eq!(quotas.subtrees, [(PathBuf::from("tv"), 1001), (PathBuf::from("movies"), 1002)]);
+        assert!(ProjectQuotas::from_config(&json!({ "project_quotas": {} })).is_none());
+        assert!(ProjectQuotas::from_config(&json!({})).is_none());
+    }
+
+    #[test]
+    fn test_parse_report() {
+        let report = parse_report("#0                  0          0          0     00 [--------]\n#1001         1048576          0          0     00 [--------]\nnot a project line\n");
+        assert_eq!(report, HashMap::from([(0, 0), (1001, 1 << 30)]));
+        assert_eq!(report_command(Path::new("/mnt/physical/hdd/WD-1"))[3], "report -p -n -b -N");
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use crate::open_files::OpenReader;
+
+pub const SAMPLE_SEC: u64 = 30;
+pub const MIN_SAMPLES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadPattern {
+    // one pass from front to back, like playing a video or taking a backup
+    Sequential,
+    // seeks backwards, rereads, or too short to tell
+    Random,
+}
+
+impl ReadPattern {
+    // How two reads of the same file combine; any random read wins
+    pub fn merge(self, other: ReadPattern) -> ReadPattern {
+        if self == ReadPattern::Sequential && other == ReadPattern::Sequential { ReadPattern::Sequential } else { ReadPattern::Random }
+    }
+}
+
+struct Session {
+    pos: u64,
+    samples: usize,
+    forward_only: bool,
+}
+
+// Follows open read fds across periodic samples of their offsets. A read
+// that was seen at least min_samples times and only ever moved forward is
+// sequential.
+pub struct ReadTracker {
+    min_samples: usize,
+    sessions: HashMap<(u32, u32, PathBuf), Session>,
+}
+
+impl ReadTracker {
+    pub fn new(min_samples: usize) -> Self {
+        Self { min_samples, sessions: HashMap::new() }
+    }
+
+    // Record one sample of the open readers, returning the reads that have
+    // finished since the previous one
+    pub fn sample(&mut self, readers: Vec<OpenReader>) -> Vec<(PathBuf, ReadPattern)> {
+        let mut open = HashMap::with_capacity(readers.len());
+        for reader in readers {
+            let key = (reader.pid, reader.fd, reader.path);
+            let session = match self.sessions.remove(&key) {
+                Some(session) => Session {
+                    pos: reader.pos,
+                    samples: session.samples + 1,
+                    forward_only: session.forward_only && reader.pos >= session.pos,
+                },
+                None => Session { pos: reader.pos, samples: 1, forward_only: true },
+            };
+            open.insert(key, session);
+        }
+        let finished = std::mem::replace(&mut self.sessions, open);
+        finished.into_iter().map(|((_, _, path), session)| {
+            let pattern = if session.forward_only && session.samples >= self.min_samples { ReadPattern::Sequential } else { ReadPattern::Random };
+            (path, pattern)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(fd: u32, path: &str, pos: u64) -> OpenReader {
+        OpenReader { pid: 1, fd, path: PathBuf::from(path), pos }
+    }
+
+    #[test]
+    fn test_sample() {
+        let mut tracker = ReadTracker::new(3);
+        for pos in [0, 100, 200] {
+            assert!(tracker.sample(vec![reader(3, "movie.mkv", pos), reader(4, "db.sqlite", 500 - pos)]).is_empty());
+        }
+        let mut finished = tracker.sample(vec![reader(5, "short.mkv", 0)]);
+        finished.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(finished, [(PathBuf::from("db.sqlite"), ReadPattern::Random), (PathBuf::from("movie.mkv"), ReadPattern::Sequential)]);
+        // gone after a single sample
+        assert_eq!(tracker.sample(Vec::new()), [(PathBuf::from("short.mkv"), ReadPattern::Random)]);
+        assert_eq!(ReadPattern::Sequential.merge(ReadPattern::Random), ReadPattern::Random);
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+
+// size of struct fanotify_event_metadata
+const EVENT_HEADER: usize = 24;
+
+// Promote a cold file as soon as it is read `reads` times within
+// `window_sec`, rather than at the next check:
+//   "cold_read_promotion": { "reads": 3, "window_sec": 600 }
+// Off unless set; watching reads takes CAP_SYS_ADMIN.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColdReadPromotion {
+    pub reads: usize,
+    pub window: Duration,
+}
+
+impl ColdReadPromotion {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("cold_read_promotion")?;
+        Some(Self {
+            reads: section.get("reads").and_then(Value::as_u64).unwrap_or(3).max(1) as usize,
+            window: Duration::from_secs(section.get("window_sec").and_then(Value::as_u64).unwrap_or(600)),
+        })
+    }
+}
+
+// Where file heat comes from:
+//   "access_tracking": "fanotify"
+// fanotify counts opens through the merged mounts as they happen, which works
+// on relatime and noatime mounts where atimes say little. "atime" compares
+// each file's atime at every scan instead, as is done anyway when fanotify
+// cannot be used. "activity" goes by writes, as activity_heat describes,
+// and is fallen back on when fanotify cannot be used on noatime mounts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AccessTracking {
+    #[default]
+    Fanotify,
+    Atime,
+    Activity,
+}
+
+impl AccessTracking {
+    pub fn from_config(config: &Value) -> Self {
+        match config.get("access_tracking").and_then(Value::as_str) {
+            Some("atime") => AccessTracking::Atime,
+            Some("activity") => AccessTracking::Activity,
+            _ => AccessTracking::Fanotify,
+        }
+    }
+}
+
+// The recent reads of each file, counted towards a promotion
+#[derive(Debug)]
+pub struct ReadBursts {
+    promotion: ColdReadPromotion,
+    reads: HashMap<PathBuf, VecDeque<SystemTime>>,
+}
+
+impl ReadBursts {
+    pub fn new(promotion: ColdReadPromotion) -> Self {
+        Self { promotion, reads: HashMap::new() }
+    }
+
+    // Count a read of `path`, returning whether it makes enough within the
+    // window. The count starts over once it does.
+    pub fn record(&mut self, path: &Path, at: SystemTime) -> bool {
+        let window = self.promotion.window;
+        let reads = self.reads.entry(path.to_path_buf()).or_default();
+        reads.retain(|read| at.duration_since(*read).map_or(true, |age| age < window));
+        reads.push_back(at);
+        if reads.len() < self.promotion.reads {
+            return false;
+        }
+        self.reads.remove(path);
+        true
+    }
+
+    // Drop files with no read inside the window, so the map only holds
+    // what is being read now
+    pub fn expire(&mut self, now: SystemTime) {
+        let window = self.promotion.window;
+        self.reads.retain(|_, reads| reads.back().is_some_and(|read| now.duration_since(*read).map_or(true, |age| age < window)));
+    }
+
+    pub fn len(&self) -> usize {
+        self.reads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty()
+    }
+}
+
+// Watches reads through the merged mounts with fanotify, one event per file
+// closed after being opened without write access, or per open for
+// `opens`. Like the mount watch it sees only what goes through the mounts,
+// not our own moves between branches.
+pub struct ReadWatcher {
+    fanotify: File,
+    mounts: Vec<PathBuf>,
+    mask: u64,
+}
+
+impl ReadWatcher {
+    pub fn new(mounts: &[PathBuf]) -> io::Result<Self> {
+        Self::watch(mounts, libc::FAN_CLOSE_NOWRITE)
+    }
+
+    // Every open of a file, for reading or writing
+    pub fn opens(mounts: &[PathBuf]) -> io::Result<Self> {
+        Self::watch(mounts, libc::FAN_OPEN)
+    }
+
+    fn watch(mounts: &[PathBuf], mask: u64) -> io::Result<Self> {
+        let fd = unsafe { libc::fanotify_init(libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC, (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC) as libc::c_uint) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let watcher = Self { fanotify: unsafe { File::from_raw_fd(fd) }, mounts: mounts.to_vec(), mask };
+        for mount in mounts {
+            let c_path = std::ffi::CString::new(mount.as_os_str().as_bytes())?;
+            let marked = unsafe { libc::fanotify_mark(fd, libc::FAN_MARK_ADD | libc::FAN_MARK_MOUNT, mask, libc::AT_FDCWD, c_path.as_ptr()) };
+            if marked < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(watcher)
+    }
+
+    // Block until files are read, or opened, then return their
+    // mount-relative paths
+    pub fn next_reads(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut buf = vec![0; 64 * 1024];
+        let len = self.fanotify.read(&mut buf)?;
+        let mut paths = Vec::new();
+        for (mask, fd) in parse(&buf[..len]) {
+            // the fd is ours to close whatever it was for
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            if mask & self.mask == 0 {
+                continue;
+            }
+            let Ok(path) = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())) else { continue };
+            if let Some(relative) = self.mounts.iter().find_map(|mount| path.strip_prefix(mount).ok()) {
+                paths.push(relative.to_path_buf());
+            }
+        }
+        Ok(paths)
+    }
+}
+
+// Split a read from a fanotify fd into (mask, fd), leaving out events that
+// came without an fd, such as a queue overflow
+fn parse(buf: &[u8]) -> Vec<(u64, i32)> {
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + EVENT_HEADER <= buf.len() {
+        let event_len = u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+        let mask = u64::from_ne_bytes(buf[offset + 8..offset + 16].try_into().unwrap());
+        let fd = i32::from_ne_bytes(buf[offset + 16..offset + 20].try_into().unwrap());
+        if fd >= 0 {
+            events.push((mask, fd));
+        }
+        if event_len < EVENT_HEADER {
+            break;
+        }
+        offset += event_len;
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(mask: u64, fd: i32) -> Vec<u8> {
+        let mut buf = (EVENT_HEADER as u32).to_ne_bytes().to_vec();
+        buf.extend([libc::FANOTIFY_METADATA_VERSION, 0]);
+        buf.extend((EVENT_HEADER as u16).to_ne_bytes());
+        buf.extend(mask.to_ne_bytes());
+        buf.extend(fd.to_ne_bytes());
+        buf.extend(42_i32.to_ne_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(std::mem::size_of::<libc::fanotify_event_metadata>(), EVENT_HEADER);
+        let mut buf = event(libc::FAN_CLOSE_NOWRITE, 7);
+        buf.extend(event(libc::FAN_Q_OVERFLOW, libc::FAN_NOFD));
+        buf.extend(event(libc::FAN_CLOSE_NOWRITE, 8));
+        assert_eq!(parse(&buf), [(libc::FAN_CLOSE_NOWRITE, 7), (libc::FAN_CLOSE_NOWRITE, 8)]);
+        assert!(parse(&buf[..10]).is_empty());
+    }
+
+    #[test]
+    fn test_access_tracking() {
+        assert_eq!(AccessTracking::from_config(&json!({})), AccessTracking::Fanotify);
+        assert_eq!(AccessTracking::from_config(&json!({ "access_tracking": "atime" })), AccessTracking::Atime);
+    }
+
+    #[test]
+    fn test_read_bursts() {
+        assert_eq!(ColdReadPromotion::from_config(&json!({})), None);
+        let promotion = ColdReadPromotion::from_config(&json!({ "cold_read_promotion": { "reads": 3, "window_sec": 60 } })).unwrap();
+        let mut bursts = ReadBursts::new(promotion);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |secs| start + Duration::from_secs(secs);
+        let movie = Path::new("movies/a.mkv");
+        assert!(!bursts.record(movie, at(0)));
+        assert!(!bursts.record(movie, at(30)));
+        // the first read has left the window by now
+        assert!(!bursts.record(movie, at(70)));
+        assert!(bursts.record(movie, at(80)));
+        assert!(bursts.is_empty());
+        assert!(!bursts.record(movie, at(90)));
+        bursts.expire(at(149));
+        assert_eq!(bursts.len(), 1);
+        bursts.expire(at(150));
+        assert!(bursts.is_empty());
+    }
+}
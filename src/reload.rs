@@ -0,0 +1,664 @@
+use std::fmt;
+use serde_json::Value;
+use crate::adopt;
+use crate::bandwidth::IoClass;
+use crate::checksum::Algorithm;
+use crate::device_class;
+use crate::drive_manager::DriveManager;
+use crate::fill_strategy::FillStrategy;
+use crate::reserve::Reserve;
+
+// One setting that differs between two configs, by its dotted path
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "~ {}: {} -> {}", self.key, old, new),
+            (None, Some(new)) => write!(f, "+ {}: {}", self.key, new),
+            (Some(old), None) => write!(f, "- {}: {}", self.key, old),
+            (None, None) => write!(f, "  {}", self.key),
+        }
+    }
+}
+
+// Every setting that differs, objects compared key by key and anything else
+// as a whole. schema_version is left out; it says nothing about the setup.
+pub fn diff(old: &Value, new: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_into(&mut changes, "", Some(old), Some(new));
+    changes.retain(|change| change.key != "schema_version");
+    changes
+}
+
+fn diff_into(changes: &mut Vec<Change>, key: &str, old: Option<&Value>, new: Option<&Value>) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let mut keys: Vec<&String> = old.keys().chain(new.keys().filter(|key| !old.contains_key(*key))).collect();
+            keys.sort();
+            for name in keys {
+                let path = if key.is_empty() { name.clone() } else { format!("{}.{}", key, name) };
+                diff_into(changes, &path, old.get(name), new.get(name));
+            }
+        }
+        (old, new) if old != new => changes.push(Change { key: key.to_string(), old: old.cloned(), new: new.cloned() }),
+        _ => {}
+    }
+}
+
+// What going from `old` to `new` would do to data already on the drives,
+// which a reload refuses unless forced. A run formats every drive not
+// already in the configured filesystem unless it is excluded or adopted.
+pub fn destructive(old: &Value, new: &Value) -> Vec<String> {
+    let mut reasons = Vec::new();
+    let filesystem = |config: &Value| config.get("filesystem").and_then(Value::as_str).map(str::to_string);
+    if let (Some(old_fs), Some(new_fs)) = (filesystem(old), filesystem(new)) {
+        if old_fs != new_fs {
+            reasons.push(format!("filesystem changes from {} to {}, so drives in {} would be reformatted", old_fs, new_fs, old_fs));
+        }
+    }
+    let still_excluded = drive_list(new.get("exclude_drives"));
+    for drive in drive_list(old.get("exclude_drives")).into_iter().filter(|drive| !still_excluded.contains(drive)) {
+        reasons.push(format!("{} is no longer excluded, so it may be formatted", drive));
+    }
+    let (old_included, new_included) = (drive_list(old.get("include_drives")), drive_list(new.get("include_drives")));
+    if !old_included.is_empty() {
+        if new_included.is_empty() {
+            reasons.push("include_drives is dropped, so any drive may be formatted".to_string());
+        }
+        for drive in new_included.iter().filter(|drive| !old_included.contains(drive)) {
+            reasons.push(format!("{} is newly included, so it may be formatted", drive));
+        }
+    }
+    let never_format = |config: &Value| config.get("never_format").and_then(Value::as_bool).unwrap_or(false);
+    if never_format(old) && !never_format(new) {
+        reasons.push("never_format is turned off, so drives may be formatted".to_string());
+    }
+    let still_adopted = adopt::adopted_drives(new);
+    for drive in adopt::adopted_drives(old).into_iter().filter(|drive| !still_adopted.iter().any(|adopted| adopted.serial == drive.serial)) {
+        reasons.push(format!("{} is no longer adopted, so it would be remounted or formatted", drive.serial));
+    }
+    if old.get("bcachefs") != new.get("bcachefs") {
+        reasons.push("the bcachefs setup changes".to_string());
+    }
+    // a drive no longer matched would have all its partitions wiped
+    if old.get("managed_partitions") != new.get("managed_partitions") {
+        reasons.push("managed_partitions changes, so drives may be formatted whole".to_string());
+    }
+    reasons
+}
+
+fn drive_list(drives: Option<&Value>) -> Vec<String> {
+    drives.and_then(Value::as_array).map(|drives| drives.iter().filter_map(Value::as_str).map(str::to_string).collect()).unwrap_or_default()
+}
+
+// Drives excluded in `new` that were not in `old`
+pub fn newly_excluded(old: &Value, new: &Value) -> Vec<String> {
+    let excluded = drive_list(old.get("exclude_drives"));
+    drive_list(new.get("exclude_drives")).into_iter().filter(|drive| !excluded.contains(drive)).collect()
+}
+
+// Settings the running service reads each time it uses them, or changes in
+// place: mergerfs options through each pool's control file, and a drive
+// newly excluded by taking it out of the pools
+const LIVE_SETTINGS: [&str; 13] = [
+    "tier_capacity_threshold", "emergency_capacity_threshold", "emergency_check_sec", "promotion", "demotion",
+    "tiering_check_sec", "tiering_check_jitter", "rebalance_new_drives", "open_file_defer_sec", "shutdown_timeout_sec",
+    "staging", "exclude_drives", "mergerfs_options",
+];
+
+// The changed settings a reload has to restart the service for, as they
+// are set up once at start. An empty list means the changes can be applied
+// where they stand, leaving the mounts and the moves copying alone. A
+// mergerfs option dropped from the config keeps its value until the pools
+// are mounted again, and a drive no longer excluded is brought in the way
+// a start does, so both restart.
+pub fn needs_restart(changes: &[Change]) -> Vec<String> {
+    changes.iter().filter(|change| {
+        let section = change.key.split('.').next().unwrap_or_default();
+        match section {
+            "mergerfs_options" => change.new.is_none(),
+            "exclude_drives" => {
+                let excluded = drive_list(change.new.as_ref());
+                drive_list(change.old.as_ref()).iter().any(|drive| !excluded.contains(drive))
+            }
+            _ => !LIVE_SETTINGS.contains(&section),
+        }
+    }).map(|change| change.key.clone()).collect()
+}
+
+// What is wrong with a config, beyond it parsing. Settings the service
+// reads leniently, skipping what it does not understand, are checked here
+// so a reload does not quietly drop them.
+pub fn validate(config: &Value) -> Vec<String> {
+    let mut problems = Vec::new();
+    if config.get("bcachefs").is_none() && !config.get("filesystem").is_some_and(Value::is_string) {
+        problems.push("filesystem is not set".to_string());
+    }
+    let is_tier = |value: &Value| value.as_str().is_some_and(|tier| DriveManager::TIERS.contains(&tier));
+    let tier_rules = [
+        ("tier_jumps", config.get("tier_jumps"), &["demote_to", "promote_to"][..]),
+        ("ingest", config.get("ingest").and_then(|ingest| ingest.get("rules")), &["tier"][..]),
+        ("pins", config.get("pins"), &["tier"][..]),
+        ("tier_policy", config.get("tier_policy"), &["tier"][..]),
+        ("ttl_policy", config.get("ttl_policy").and_then(|ttl| ttl.get("rules")), &["demote_to"][..]),
+    ];
+    for (section, rules, keys) in tier_rules {
+        for (index, rule) in rules.and_then(Value::as_array).into_iter().flatten().enumerate() {
+            for key in keys {
+                if rule.get(key).is_some_and(|value| !is_tier(value)) {
+                    problems.push(format!("{} rule {} has {} {}, which is not a tier", section, index + 1, key, rule[key]));
+                }
+            }
+        }
+    }
+    let strategies: Vec<(String, &Value)> = match config.get("fill_strategy") {
+        Some(Value::Object(tiers)) => tiers.iter().map(|(tier, name)| (format!("fill_strategy.{}", tier), name)).collect(),
+        Some(name) => vec![("fill_strategy".to_string(), name)],
+        None => Vec::new(),
+    };
+    for (key, name) in strategies {
+        if name.as_str().and_then(FillStrategy::parse).is_none() {
+            problems.push(format!("{} is {}, which is not a fill strategy", key, name));
+        }
+    }
+    let drive_class = config.get("drive_class");
+    for (id, class) in drive_class.and_then(|section| section.get("drives")).and_then(Value::as_object).into_iter().flatten() {
+        if class.as_str().and_then(device_class::parse_class).is_none() {
+            problems.push(format!("drive_class.drives.{} is {}, which is not a drive class", id, class));
+        }
+    }
+    for (index, rule) in drive_class.and_then(|section| section.get("models")).and_then(Value::as_array).into_iter().flatten().enumerate() {
+        if rule.get("class").and_then(Value::as_str).and_then(device_class::parse_class).is_none() {
+            problems.push(format!("drive_class model rule {} has class {}, which is not a drive class", index + 1, rule.get("class").unwrap_or(&Value::Null)));
+        }
+    }
+    if let Some(threshold) = config.get("tier_capacity_threshold") {
+        if !threshold.as_f64().is_some_and(|percent| (0.0..=100.0).contains(&percent)) {
+            problems.push(format!("tier_capacity_threshold is {}, not a percentage", threshold));
+        }
+    }
+    if let Some(mover) = config.get("mover").filter(|mover| !matches!(mover.as_str(), Some("native" | "rsync"))) {
+        problems.push(format!("mover is {}, not native or rsync", mover));
+    }
+    if let Some(algorithm) = config.get("checksums").and_then(|checksums| checksums.get("verify")).filter(|algorithm| !algorithm.as_str().is_some_and(|name| name == "off" || Algorithm::parse(name).is_some())) {
+        problems.push(format!("checksums.verify is {}, not xxh3, blake3, sha256 or off", algorithm));
+    }
+    if let Some(algorithm) = config.get("initial_crawl").and_then(|crawl| crawl.get("checksum")).filter(|algorithm| algorithm.as_str().and_then(Algorithm::parse).is_none()) {
+        problems.push(format!("initial_crawl.checksum is {}, not xxh3, blake3 or sha256", algorithm));
+    }
+    if let Some(class) = config.get("mover_ionice").filter(|class| class.as_str().and_then(IoClass::parse).is_none()) {
+        problems.push(format!("mover_ionice is {}, not idle or best-effort:0 to best-effort:7", class));
+    }
+    if let Some(tracking) = config.get("access_tracking").filter(|tracking| !matches!(tracking.as_str(), Some("fanotify" | "atime" | "activity"))) {
+        problems.push(format!("access_tracking is {}, not fanotify, atime or activity", tracking));
+    }
+    for (key, value) in config.get("mergerfs_options").and_then(Value::as_object).into_iter().flatten() {
+        if !(value.is_string() || value.is_number() || value.is_boolean()) {
+            problems.push(format!("mergerfs_options.{} is {}, not a string, number or boolean", key, value));
+        }
+    }
+    if let Some(reserve) = config.get("reserve") {
+        let reserves: Vec<(String, &Value)> = match reserve.as_object() {
+            Some(tiers) => tiers.iter().map(|(tier, value)| (format!("reserve.{}", tier), value)).collect(),
+            None => vec![("reserve".to_string(), reserve)],
+        };
+        for (key, value) in reserves.into_iter().filter(|(_, value)| Reserve::parse(value).is_none()) {
+            problems.push(format!("{} is {}, not a size or percentage", key, value));
+        }
+    }
+    let mut settings: Vec<(String, &Value, Kind)> = SETTINGS.iter()
+        .flat_map(|(path, kind)| lookup(config, path).into_iter().map(move |(key, value)| (key, value, *kind)))
+        .collect();
+    for (rule_key, rule) in FILE_RULES.iter().flat_map(|rules| lookup(config, rules)) {
+        settings.extend(FILE_MATCH.iter().filter_map(|(key, kind)| Some((format!("{}.{}", rule_key, key), rule.get(key)?, *kind))));
+    }
+    for (key, value, kind) in settings.into_iter().filter(|(_, value, kind)| !kind.accepts(value)) {
+        problems.push(format!("{} is {}, not {}", key, value, kind));
+    }
+    for (path, required) in REQUIRED {
+        for (key, section) in lookup(config, path).into_iter().filter(|(_, section)| section.is_object()) {
+            if section.get(required).is_none() {
+                problems.push(format!("{} has no {}", key, required));
+            }
+        }
+    }
+    if let Some(notifications) = config.get("notifications").filter(|section| section.get("ntfy").is_none() && section.get("email").is_none()) {
+        if notifications.is_object() {
+            problems.push("notifications has neither ntfy nor email".to_string());
+        }
+    }
+    problems
+}
+
+// What a setting holds, for the modules that read it leniently
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Flag,
+    Count,
+    Integer,
+    Number,
+    Percent,
+    Fraction,
+    Text,
+    Texts,
+    Table,
+    List,
+    Tier,
+    Size,
+    ProjectId,
+    OneOf(&'static [&'static str]),
+}
+
+impl Kind {
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            Kind::Flag => value.is_boolean(),
+            Kind::Count => value.is_u64(),
+            Kind::Integer => value.is_i64(),
+            Kind::Number => value.is_number(),
+            Kind::Percent => value.as_f64().is_some_and(|percent| (0.0..=100.0).contains(&percent)),
+            Kind::Fraction => value.as_f64().is_some_and(|fraction| (0.0..=1.0).contains(&fraction)),
+            Kind::Text => value.is_string(),
+            Kind::Texts => value.as_array().is_some_and(|values| values.iter().all(Value::is_string)),
+            Kind::Table => value.is_object(),
+            Kind::List => value.is_array(),
+            Kind::Tier => value.as_str().is_some_and(|tier| DriveManager::TIERS.contains(&tier)),
+            Kind::Size => matches!(Reserve::parse(value), Some(Reserve::Bytes(_))),
+            Kind::ProjectId => value.as_u64().is_some_and(|id| id > 0 && id <= u64::from(u32::MAX)),
+            Kind::OneOf(names) => value.as_str().is_some_and(|name| names.contains(&name)),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Flag => write!(f, "true or false"),
+            Kind::Count => write!(f, "a whole number"),
+            Kind::Integer => write!(f, "an integer"),
+            Kind::Number => write!(f, "a number"),
+            Kind::Percent => write!(f, "a percentage"),
+            Kind::Fraction => write!(f, "a fraction from 0 to 1"),
+            Kind::Text => write!(f, "a string"),
+            Kind::Texts => write!(f, "a list of strings"),
+            Kind::Table => write!(f, "a table"),
+            Kind::List => write!(f, "a list"),
+            Kind::Tier => write!(f, "a tier"),
+            Kind::Size => write!(f, "a size"),
+            Kind::ProjectId => write!(f, "a project ID from 1 to {}", u32::MAX),
+            Kind::OneOf(names) => match names.split_last() {
+                Some((last, [])) => write!(f, "{}", last),
+                Some((last, rest)) => write!(f, "{} or {}", rest.join(", "), last),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+// The settings read from the raw config, by dotted path: `*` stands for
+// every key of a table and `[]` after a name for every entry of a list.
+// Those with checks of their own in validate are left out.
+const SETTINGS: &[(&str, Kind)] = &[
+    ("schema_version", Kind::Count),
+    ("backend", Kind::OneOf(&["bcachefs"])),
+    ("control_socket", Kind::Text),
+    ("emergency_capacity_threshold", Kind::Percent),
+    ("emergency_check_sec", Kind::Count),
+    ("tiering_check_sec", Kind::Count),
+    ("tiering_check_jitter", Kind::Fraction),
+    ("access_time_threshold", Kind::Count),
+    ("access_count_threshold", Kind::Count),
+    ("promotion_cooldown_sec", Kind::Count),
+    ("batch_size", Kind::Count),
+    ("rebalance_new_drives", Kind::Flag),
+    ("open_file_defer_sec", Kind::Count),
+    ("shutdown_timeout_sec", Kind::Count),
+    ("watch_mounts", Kind::Flag),
+    ("watchdog_sec", Kind::Count),
+    ("max_in_flight_gb", Kind::Number),
+    ("max_migration_mbps", Kind::Number),
+    ("max_move_mbps", Kind::Number),
+    ("sequential_read_min_samples", Kind::Count),
+    ("sequential_read_sample_sec", Kind::Count),
+    ("open_files", Kind::OneOf(&["ignore", "writers", "any"])),
+    ("symlinks", Kind::OneOf(&["skip", "link", "with_target"])),
+    ("immutable_files", Kind::OneOf(&["skip", "clear"])),
+    ("disk_stats_sec", Kind::Count),
+    ("disk_stats_keep_days", Kind::Count),
+    ("project_quota_sec", Kind::Count),
+    ("promotion", Kind::Table),
+    ("promotion.interval_sec", Kind::Count),
+    ("promotion.jitter", Kind::Fraction),
+    ("promotion.access_time_threshold", Kind::Count),
+    ("promotion.access_count_threshold", Kind::Count),
+    ("demotion", Kind::Table),
+    ("demotion.interval_sec", Kind::Count),
+    ("demotion.jitter", Kind::Fraction),
+    ("demotion.tier_capacity_threshold", Kind::Percent),
+    ("demotion.promotion_cooldown_sec", Kind::Count),
+    ("demotion.batch_size", Kind::Count),
+    ("move_workers", Kind::Table),
+    ("move_workers.*", Kind::Count),
+    ("managed_partitions", Kind::Table),
+    ("managed_partitions.label", Kind::Text),
+    ("managed_partitions.drives", Kind::Table),
+    ("managed_partitions.drives.*", Kind::Text),
+    ("encryption", Kind::Table),
+    ("encryption.keyfile", Kind::Text),
+    ("encryption.cipher", Kind::Text),
+    ("activity_heat", Kind::Table),
+    ("activity_heat.burst_files", Kind::Count),
+    ("activity_heat.burst_window_sec", Kind::Count),
+    ("adopted_drives", Kind::Table),
+    ("adopted_drives.*", Kind::Table),
+    ("adopted_drives.*.mountpoint", Kind::Text),
+    ("adopted_drives.*.tier", Kind::Tier),
+    ("archive", Kind::Table),
+    ("archive.bucket", Kind::Text),
+    ("archive.prefix", Kind::Text),
+    ("archive.endpoint", Kind::Text),
+    ("archive.region", Kind::Text),
+    ("archive.storage_class", Kind::Text),
+    ("archive.after_days", Kind::Number),
+    ("archive.max_gb_per_check", Kind::Count),
+    ("bcachefs", Kind::Table),
+    ("bcachefs.mountpoint", Kind::Text),
+    ("bcachefs.foreground_target", Kind::Tier),
+    ("bcachefs.promote_target", Kind::Tier),
+    ("bcachefs.metadata_target", Kind::Tier),
+    ("bcachefs.background_target", Kind::Tier),
+    ("btrfs", Kind::Table),
+    ("btrfs.compress", Kind::Text),
+    ("btrfs.space_cache", Kind::Text),
+    ("btrfs.subvolumes", Kind::Flag),
+    ("mover_cgroup", Kind::Table),
+    ("mover_cgroup.path", Kind::Text),
+    ("mover_cgroup.cpu_weight", Kind::Count),
+    ("mover_cgroup.read_mbps", Kind::Number),
+    ("mover_cgroup.write_mbps", Kind::Number),
+    ("mover_cgroup.io_max", Kind::Texts),
+    ("checksums", Kind::Table),
+    ("checksums.threads", Kind::Count),
+    ("checksums.segment_mb", Kind::Count),
+    ("initial_crawl", Kind::Table),
+    ("initial_crawl.files_per_sec", Kind::Count),
+    ("initial_crawl.checksum_mbps", Kind::Number),
+    ("initial_crawl.batch", Kind::Count),
+    ("drive_class", Kind::Table),
+    ("drive_class.drives", Kind::Table),
+    ("drive_class.models", Kind::List),
+    ("drive_class.models[]", Kind::Table),
+    ("drive_class.models[].model", Kind::Text),
+    ("drive_class.guess_usb", Kind::Flag),
+    ("event_log", Kind::Table),
+    ("event_log.path", Kind::Text),
+    ("event_log.rotate_hours", Kind::Number),
+    ("event_log.max_mb", Kind::Count),
+    ("event_log.keep", Kind::Count),
+    ("event_log.max_per_minute", Kind::Count),
+    ("external_jobs", Kind::Table),
+    ("external_jobs.*", Kind::Table),
+    ("external_jobs.*.max_hours", Kind::Number),
+    ("external_jobs.*.drives", Kind::Texts),
+    ("external_jobs.*.priority", Kind::Integer),
+    ("keep_together", Kind::List),
+    ("keep_together[]", Kind::Table),
+    ("keep_together[].directory", Kind::Text),
+    ("keep_together[].files", Kind::Texts),
+    ("keep_together[].sidecars", Kind::Texts),
+    ("hardlink_farms", Kind::Table),
+    ("hardlink_farms.action", Kind::OneOf(&["exclude", "group"])),
+    ("hardlink_farms.min_files", Kind::Count),
+    ("hardlink_farms.min_ratio", Kind::Number),
+    ("load_throttle", Kind::Table),
+    ("load_throttle.max_load_per_cpu", Kind::Number),
+    ("load_throttle.max_disk_util_percent", Kind::Percent),
+    ("load_throttle.max_memory_pressure", Kind::Number),
+    ("load_throttle.max_disk_latency_ms", Kind::Number),
+    ("load_throttle.slow_at_percent", Kind::Percent),
+    ("load_throttle.check_sec", Kind::Count),
+    ("media_server", Kind::Table),
+    ("media_server.roots", Kind::Texts),
+    ("media_server.listen", Kind::Text),
+    ("media_server.next_episodes", Kind::Count),
+    ("media_server.stream_timeout_sec", Kind::Count),
+    ("notifications", Kind::Table),
+    ("notifications.ntfy", Kind::Text),
+    ("notifications.ntfy_token", Kind::Text),
+    ("notifications.email", Kind::Text),
+    ("notifications.kinds", Kind::Texts),
+    ("notifications.rate_limits", Kind::Table),
+    ("notifications.rate_limits.*", Kind::Count),
+    ("notifications.group_sec", Kind::Count),
+    ("notifications.max_per_hour", Kind::Count),
+    ("pins", Kind::List),
+    ("pins[]", Kind::Table),
+    ("pins[].path", Kind::Text),
+    ("power", Kind::Table),
+    ("power.ups", Kind::Text),
+    ("power.power_supply_dir", Kind::Text),
+    ("power.check_sec", Kind::Count),
+    ("project_quotas", Kind::Table),
+    ("project_quotas.*", Kind::ProjectId),
+    ("cold_read_promotion", Kind::Table),
+    ("cold_read_promotion.reads", Kind::Count),
+    ("cold_read_promotion.window_sec", Kind::Count),
+    ("retry", Kind::Table),
+    ("retry.max_retries", Kind::Count),
+    ("retry.quarantine_after", Kind::Count),
+    ("retry.initial_backoff_sec", Kind::Number),
+    ("retry.max_backoff_sec", Kind::Number),
+    ("retry.multiplier", Kind::Number),
+    ("retry.jitter", Kind::Fraction),
+    ("scratch_dirs", Kind::List),
+    ("scratch_dirs[]", Kind::Table),
+    ("scratch_dirs[].path", Kind::Text),
+    ("scratch_dirs[].tier", Kind::Tier),
+    ("scratch_dirs[].branch", Kind::Text),
+    ("scratch_dirs[].max_age_hours", Kind::Number),
+    ("scratch_dirs[].max_size_gb", Kind::Number),
+    ("staging", Kind::Table),
+    ("staging.max_hours", Kind::Number),
+    ("staging.max_hot_percent", Kind::Percent),
+    ("tier_jumps", Kind::List),
+    ("tier_jumps[]", Kind::Table),
+    ("tier_policy", Kind::List),
+    ("tier_policy[]", Kind::Table),
+    ("tier_policy[].min_age_days", Kind::Number),
+    ("tier_policy[].max_age_days", Kind::Number),
+    ("ingest", Kind::Table),
+    ("ingest.delay_sec", Kind::Count),
+    ("ingest.rules", Kind::List),
+    ("ingest.rules[]", Kind::Table),
+    ("ttl_policy", Kind::Table),
+    ("ttl_policy.rules", Kind::List),
+    ("ttl_policy.rules[]", Kind::Table),
+    ("ttl_policy.rules[].demote_after_days", Kind::Number),
+    ("ttl_policy.rules[].delete_after_days", Kind::Number),
+    ("ttl_policy.rules[].delete", Kind::Flag),
+    ("write_skew", Kind::Table),
+    ("write_skew.window_hours", Kind::Number),
+    ("write_skew.max_ratio", Kind::Number),
+    ("write_skew.min_gb", Kind::Count),
+    ("zfs", Kind::Table),
+    ("zfs.datasets", Kind::Texts),
+    ("zfs.max_used_percent", Kind::Percent),
+    ("zfs.report_sec", Kind::Count),
+    ("simulation", Kind::Table),
+    ("simulation.days", Kind::Count),
+    ("simulation.seed", Kind::Count),
+    ("simulation.files", Kind::Count),
+    ("simulation.accesses_per_hour", Kind::Count),
+    ("simulation.min_file_size_mb", Kind::Count),
+    ("simulation.max_file_size_mb", Kind::Count),
+    ("simulation.working_set_shift_days", Kind::Count),
+    ("simulation.drives", Kind::List),
+    ("simulation.drives[]", Kind::Table),
+    ("simulation.drives[].block_class", Kind::Text),
+    ("simulation.drives[].serial", Kind::Text),
+    ("simulation.drives[].capacity_gb", Kind::Count),
+    ("mergerfs_options", Kind::Table),
+];
+
+// The rule lists matched against files with tier_rules::FileMatch, and
+// what each rule can match on
+const FILE_RULES: [&str; 4] = ["tier_jumps[]", "tier_policy[]", "ingest.rules[]", "ttl_policy.rules[]"];
+const FILE_MATCH: [(&str, Kind); 4] = [("path", Kind::Text), ("extensions", Kind::Texts), ("min_size", Kind::Size), ("max_size", Kind::Size)];
+
+// Settings a section is skipped without, silently turning its feature off
+const REQUIRED: [(&str, &str); 9] = [
+    ("archive", "bucket"),
+    ("encryption", "keyfile"),
+    ("event_log", "path"),
+    ("adopted_drives.*", "mountpoint"),
+    ("adopted_drives.*", "tier"),
+    ("pins[]", "path"),
+    ("pins[]", "tier"),
+    ("tier_policy[]", "tier"),
+    ("scratch_dirs[]", "path"),
+];
+
+// The values at a dotted path, each with the key it is at
+fn lookup<'a>(config: &'a Value, path: &str) -> Vec<(String, &'a Value)> {
+    let mut found = vec![(String::new(), config)];
+    for segment in path.split('.') {
+        let (name, each) = segment.strip_suffix("[]").map_or((segment, false), |name| (name, true));
+        let join = |key: &str, name: &str| if key.is_empty() { name.to_string() } else { format!("{}.{}", key, name) };
+        found = found.into_iter().flat_map(|(key, value)| -> Vec<(String, &Value)> {
+            if name == "*" {
+                value.as_object().into_iter().flatten().map(|(name, child)| (join(&key, name), child)).collect()
+            } else {
+                value.get(name).map(|child| (join(&key, name), child)).into_iter().collect()
+            }
+        }).collect();
+        if each {
+            found = found.into_iter().flat_map(|(key, list)| {
+                list.as_array().into_iter().flatten().enumerate().map(move |(index, entry)| (format!("{}[{}]", key, index + 1), entry))
+            }).collect();
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff() {
+        let old = json!({ "schema_version": 0, "filesystem": "xfs", "promotion": { "interval_sec": 900, "jitter": 0.1 } });
+        let new = json!({ "schema_version": 1, "filesystem": "xfs", "promotion": { "interval_sec": 600 }, "scan_threads": 2 });
+        let changes = diff(&old, &new);
+        assert_eq!(changes.iter().map(ToString::to_string).collect::<Vec<_>>(), [
+            "~ promotion.interval_sec: 900 -> 600",
+            "- promotion.jitter: 0.1",
+            "+ scan_threads: 2",
+        ]);
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_destructive() {
+        let old = json!({ "filesystem": "xfs", "exclude_drives": ["WD-1", "WD-2"], "adopted_drives": { "WD-3": { "mountpoint": "/mnt/d3", "tier": "cold" } } });
+        assert!(destructive(&old, &old).is_empty());
+        let new = json!({ "filesystem": "ext4", "exclude_drives": ["WD-2"] });
+        let reasons = destructive(&old, &new);
+        assert_eq!(reasons.len(), 3);
+        assert!(reasons[0].starts_with("filesystem changes from xfs to ext4"));
+        assert!(reasons[1].starts_with("WD-1 is no longer excluded"));
+        assert!(reasons[2].starts_with("WD-3 is no longer adopted"));
+        let managed = json!({ "filesystem": "xfs", "managed_partitions": { "label": "tiered" } });
+        assert_eq!(destructive(&json!({ "filesystem": "xfs" }), &managed), ["managed_partitions changes, so drives may be formatted whole"]);
+        let allowlist = json!({ "filesystem": "xfs", "include_drives": ["WD-1"], "never_format": true });
+        assert_eq!(destructive(&allowlist, &json!({ "filesystem": "xfs", "include_drives": ["WD-1", "WD-4"] })), [
+            "WD-4 is newly included, so it may be formatted",
+            "never_format is turned off, so drives may be formatted",
+        ]);
+        assert_eq!(destructive(&allowlist, &json!({ "filesystem": "xfs", "never_format": true })), ["include_drives is dropped, so any drive may be formatted"]);
+    }
+
+    #[test]
+    fn test_needs_restart() {
+        let old = json!({ "filesystem": "xfs", "exclude_drives": ["WD-1"], "mergerfs_options": { "cache.files": "off" }, "demotion": { "batch_size": 10 } });
+        let live = json!({
+            "filesystem": "xfs", "exclude_drives": ["WD-1", "WD-2"], "tier_capacity_threshold": 70,
+            "mergerfs_options": { "cache.files": "partial", "func.getattr": "newest" }, "demotion": { "batch_size": 20 },
+        });
+        assert!(needs_restart(&diff(&old, &live)).is_empty());
+        assert_eq!(newly_excluded(&old, &live), ["WD-2"]);
+        let restart = json!({ "filesystem": "xfs", "exclude_drives": ["WD-2"], "mergerfs_options": {}, "demotion": { "batch_size": 10 }, "pins": [] });
+        assert_eq!(needs_restart(&diff(&old, &restart)), ["exclude_drives", "mergerfs_options.cache.files", "pins"]);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&json!({ "filesystem": "xfs", "fill_strategy": { "hot": "round-robin" } })).is_empty());
+        let problems = validate(&json!({
+            "tier_jumps": [{ "path": "*", "demote_to": "frozen" }],
+            "ingest": { "rules": [{ "tier": "cold" }, { "tier": 3 }] },
+            "pins": [{ "path": "vms", "tier": "nvme" }],
+            "fill_strategy": "fullest-first",
+            "tier_capacity_threshold": 120,
+            "mover": "cp",
+            "mover_ionice": "realtime",
+            "checksums": { "verify": "md5" },
+            "initial_crawl": { "checksum": "off" },
+            "access_tracking": "ebpf",
+            "mergerfs_options": { "cache.files": ["off"] },
+            "drive_class": { "drives": { "USB-1": "flash" }, "models": [{ "model": "*", "class": "ssd" }, { "model": "x" }] },
+        }));
+        assert_eq!(problems, [
+            "filesystem is not set",
+            "tier_jumps rule 1 has demote_to \"frozen\", which is not a tier",
+            "ingest rule 2 has tier 3, which is not a tier",
+            "pins rule 1 has tier \"nvme\", which is not a tier",
+            "fill_strategy is \"fullest-first\", which is not a fill strategy",
+            "drive_class.drives.USB-1 is \"flash\", which is not a drive class",
+            "drive_class model rule 2 has class null, which is not a drive class",
+            "tier_capacity_threshold is 120, not a percentage",
+            "mover is \"cp\", not native or rsync",
+            "checksums.verify is \"md5\", not xxh3, blake3, sha256 or off",
+            "initial_crawl.checksum is \"off\", not xxh3, blake3 or sha256",
+            "mover_ionice is \"realtime\", not idle or best-effort:0 to best-effort:7",
+            "access_tracking is \"ebpf\", not fanotify, atime or activity",
+            "mergerfs_options.cache.files is [\"off\"], not a string, number or boolean",
+        ]);
+        let problems = validate(&json!({
+            "filesystem": "xfs",
+            "emergency_capacity_threshold": "95",
+            "reserve": { "hot": "10%", "cold": "lots" },
+            "managed_partitions": { "label": "tiered", "drives": { "WD-1": 3 } },
+            "encryption": { "cipher": "aes-xts-plain64" },
+            "archive": { "prefix": "cold/", "after_days": "90" },
+            "retry": { "max_retries": -1, "jitter": 2 },
+            "project_quotas": { "tv": 0 },
+            "pins": [{ "tier": "hot" }],
+            "tier_policy": [{ "extensions": "iso", "min_size": "10%", "tier": "cold" }],
+            "adopted_drives": { "WD-3": { "mountpoint": "/mnt/d3", "tier": "frozen" } },
+            "notifications": { "kinds": ["move"] },
+        }));
+        assert_eq!(problems, [
+            "reserve.cold is \"lots\", not a size or percentage",
+            "emergency_capacity_threshold is \"95\", not a percentage",
+            "managed_partitions.drives.WD-1 is 3, not a string",
+            "adopted_drives.WD-3.tier is \"frozen\", not a tier",
+            "archive.after_days is \"90\", not a number",
+            "project_quotas.tv is 0, not a project ID from 1 to 4294967295",
+            "retry.max_retries is -1, not a whole number",
+            "retry.jitter is 2, not a fraction from 0 to 1",
+            "tier_policy[1].extensions is \"iso\", not a list of strings",
+            "tier_policy[1].min_size is \"10%\", not a size",
+            "archive has no bucket",
+            "encryption has no keyfile",
+            "pins[1] has no path",
+            "notifications has neither ntfy nor email",
+        ]);
+    }
+}
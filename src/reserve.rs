@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use serde_json::Value;
+
+// Space kept free on a drive, as bytes or a share of its size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reserve {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl Reserve {
+    // A number of bytes, or a string such as "20G", "512M" or "5%"
+    pub fn parse(value: &Value) -> Option<Self> {
+        if let Some(bytes) = value.as_u64() {
+            return Some(Reserve::Bytes(bytes));
+        }
+        let text = value.as_str()?.trim();
+        if let Some(percent) = text.strip_suffix('%') {
+            return percent.trim().parse().ok().filter(|percent| (0.0..=100.0).contains(percent)).map(Reserve::Percent);
+        }
+        let (number, unit) = text.split_at(text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len()));
+        let scale: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches('B') {
+            "" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            "T" => 1 << 40,
+            _ => return None,
+        };
+        number.parse::<f64>().ok().map(|number| Reserve::Bytes((number * scale as f64) as u64))
+    }
+
+    // The bytes to keep free on a drive of `total` bytes
+    pub fn bytes(&self, total: u64) -> u64 {
+        match *self {
+            Reserve::Bytes(bytes) => bytes,
+            Reserve::Percent(percent) => (total as f64 * percent / 100.0).ceil() as u64,
+        }
+    }
+}
+
+// The reserve config, one value for every drive or a table with a default
+// and overrides by serial:
+//   "reserve": "5%"
+//   "reserve": { "default": "20G", "WD-WX11D": "2%" }
+// The mover never fills a drive past its reserve, and mergerfs is given the
+// same as each branch's minfreespace.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReservePolicy {
+    pub default: Option<Reserve>,
+    pub branches: HashMap<String, Reserve>,
+}
+
+impl ReservePolicy {
+    pub fn from_config(config: &Value) -> Self {
+        match config.get("reserve") {
+            Some(Value::Object(table)) => Self {
+                default: table.get("default").and_then(Reserve::parse),
+                branches: table.iter().filter(|(key, _)| *key != "default")
+                    .filter_map(|(serial, value)| Some((serial.clone(), Reserve::parse(value)?)))
+                    .collect(),
+            },
+            Some(value) => Self { default: Reserve::parse(value), branches: HashMap::new() },
+            None => Self::default(),
+        }
+    }
+
+    pub fn for_branch(&self, serial: &str) -> Option<Reserve> {
+        self.branches.get(serial).copied().or(self.default)
+    }
+
+    // Bytes to keep free on the branch `serial` of `total` bytes
+    pub fn bytes(&self, serial: &str, total: u64) -> u64 {
+        self.for_branch(serial).map_or(0, |reserve| reserve.bytes(total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Reserve::parse(&json!(1024)), Some(Reserve::Bytes(1024)));
+        assert_eq!(Reserve::parse(&json!("20G")), Some(Reserve::Bytes(20 << 30)));
+        assert_eq!(Reserve::parse(&json!("1.5 GB")), Some(Reserve::Bytes(3 << 29)));
+        assert_eq!(Reserve::parse(&json!("5%")), Some(Reserve::Percent(5.0)));
+        assert_eq!(Reserve::parse(&json!("120%")), None);
+        assert_eq!(Reserve::parse(&json!("lots")), None);
+        assert_eq!(Reserve::Percent(5.0).bytes(1000), 50);
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = ReservePolicy::from_config(&json!({ "reserve": { "default": "1K", "WD-1": "10%" } }));
+        assert_eq!(policy.bytes("WD-1", 10_000), 1000);
+        assert_eq!(policy.bytes("WD-2", 10_000), 1024);
+        assert_eq!(ReservePolicy::from_config(&json!({ "reserve": "2%" })).bytes("WD-2", 10_000), 200);
+        assert_eq!(ReservePolicy::from_config(&json!({})).bytes("WD-2", 10_000), 0);
+    }
+}
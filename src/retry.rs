@@ -0,0 +1,123 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use serde_json::Value;
+
+pub const MAX_RETRIES: u32 = 8;
+pub const INITIAL_BACKOFF_SEC: f64 = 60.0;
+pub const MAX_BACKOFF_SEC: f64 = 6.0 * 3600.0;
+pub const QUARANTINE_AFTER: u32 = 3;
+
+// How failed moves are retried, from the "retry" config section
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: f64,
+    pub max_backoff: f64,
+    pub multiplier: f64,
+    // fraction of the delay randomly added or removed, so moves that failed
+    // together do not all retry at the same moment
+    pub jitter: f64,
+    // read or checksum errors in a file's history after which it is
+    // quarantined instead of retried
+    pub quarantine_after: u32,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Value) -> Self {
+        let retry = config.get("retry");
+        let get_f64 = |key: &str, default: f64| retry.and_then(|retry| retry.get(key)).and_then(Value::as_f64).unwrap_or(default);
+        Self {
+            max_retries: retry.and_then(|retry| retry.get("max_retries")).and_then(Value::as_u64).map(|n| n as u32).unwrap_or(MAX_RETRIES),
+            initial_backoff: get_f64("initial_backoff_sec", INITIAL_BACKOFF_SEC).max(0.0),
+            max_backoff: get_f64("max_backoff_sec", MAX_BACKOFF_SEC).max(0.0),
+            multiplier: get_f64("multiplier", 2.0).max(1.0),
+            jitter: get_f64("jitter", 0.2).clamp(0.0, 1.0),
+            quarantine_after: retry.and_then(|retry| retry.get("quarantine_after")).and_then(Value::as_u64).map(|n| n.max(1) as u32).unwrap_or(QUARANTINE_AFTER),
+        }
+    }
+
+    // Delay before retry number `retries` (1 for the first retry)
+    pub fn backoff(&self, retries: u32, key: &Path) -> Duration {
+        let base = (self.initial_backoff * self.multiplier.powi(retries.saturating_sub(1) as i32)).min(self.max_backoff);
+        let jitter = if self.jitter > 0.0 { (random_fraction(key, retries) * 2.0 - 1.0) * self.jitter } else { 0.0 };
+        Duration::from_secs_f64((base * (1.0 + jitter)).max(0.0))
+    }
+}
+
+// What kind of failure a move ran into, which decides what happens next
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+    // a write ran out of room; another destination is picked straight away
+    NoSpace,
+    // the drive could not read or write, or the data failed its checksum;
+    // it is marked suspect
+    Io,
+    // retrying will not change it, so the move is given up on at once
+    Permission,
+    Other,
+}
+
+// Classify by errno where the error carries one, and otherwise by the
+// strerror text rsync passes on in its stderr
+pub fn classify(error: &io::Error) -> ErrorClass {
+    let message = error.to_string();
+    let says = |texts: &[&str]| texts.iter().any(|text| message.contains(text));
+    match error.raw_os_error() {
+        Some(libc::ENOSPC | libc::EDQUOT) => ErrorClass::NoSpace,
+        // ZFS reports a checksum failure as EBADE, "Invalid exchange"
+        Some(libc::EIO | libc::EBADE) => ErrorClass::Io,
+        Some(libc::EACCES | libc::EPERM) => ErrorClass::Permission,
+        _ if error.kind() == io::ErrorKind::PermissionDenied || says(&["Permission denied", "Operation not permitted"]) => ErrorClass::Permission,
+        _ if says(&["No space left on device", "Disk quota exceeded"]) => ErrorClass::NoSpace,
+        _ if says(&["Input/output error", "Invalid exchange", "failed verification"]) => ErrorClass::Io,
+        _ => ErrorClass::Other,
+    }
+}
+
+// A value in [0, 1) that differs per key, attempt and process
+fn random_fraction(key: &Path, retries: u32) -> f64 {
+    (RandomState::new().hash_one((key, retries)) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::from_config(&json!({ "retry": { "initial_backoff_sec": 10, "max_backoff_sec": 50, "jitter": 0 } }));
+        assert_eq!((policy.max_retries, policy.quarantine_after), (MAX_RETRIES, QUARANTINE_AFTER));
+        let delays: Vec<u64> = (1..=4).map(|retries| policy.backoff(retries, Path::new("a")).as_secs()).collect();
+        assert_eq!(delays, [10, 20, 40, 50]);
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&io::Error::from_raw_os_error(libc::ENOSPC)), ErrorClass::NoSpace);
+        assert_eq!(classify(&io::Error::from_raw_os_error(libc::EIO)), ErrorClass::Io);
+        assert_eq!(classify(&io::Error::from_raw_os_error(libc::EBADE)), ErrorClass::Io);
+        assert_eq!(classify(&io::Error::from_raw_os_error(libc::EPERM)), ErrorClass::Permission);
+        assert_eq!(classify(&io::Error::from_raw_os_error(libc::EBUSY)), ErrorClass::Other);
+        let rsync = |stderr: &str| io::Error::other(format!("`rsync -axHAXWES a b` exited with status 11: {}", stderr));
+        assert_eq!(classify(&rsync("rsync: [receiver] write failed on \"/mnt/hdd/b\": No space left on device (28)")), ErrorClass::NoSpace);
+        assert_eq!(classify(&rsync("rsync: [sender] read errors mapping \"/mnt/ssd/a\": Input/output error (5)")), ErrorClass::Io);
+        assert_eq!(classify(&rsync("ERROR: a failed verification -- update discarded.")), ErrorClass::Io);
+        // as the error history has them
+        assert_eq!(classify(&io::Error::other(io::Error::from_raw_os_error(libc::EBADE).to_string())), ErrorClass::Io);
+        // picking a destination that has no room is no write running out of it
+        assert_eq!(classify(&io::Error::new(io::ErrorKind::StorageFull, "no branch in tier cold has room for a")), ErrorClass::Other);
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let policy = RetryPolicy::from_config(&json!({}));
+        for retries in 1..20 {
+            let delay = policy.backoff(3, Path::new(&format!("file{}", retries))).as_secs_f64();
+            assert!((192.0..=288.0).contains(&delay), "{}", delay);
+        }
+    }
+}
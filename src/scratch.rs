@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+use crate::file_metadata::FileMetadata;
+
+// A directory for throwaway data, from the scratch_dirs config list:
+//   { "path": "transcode", "tier": "hot", "branch": "<serial>",
+//     "max_age_hours": 24, "max_size_gb": 50 }
+// Its files stay on the tier (and branch, if given) no matter how they are
+// used. With a max age or size the maintenance loop deletes the files read
+// longest ago to stay within them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScratchDir {
+    // relative to the branch root
+    pub path: PathBuf,
+    pub tier: String,
+    pub branch: Option<String>,
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+impl ScratchDir {
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.path) && path != self.path
+    }
+
+    // The files to delete out of `files`, which should be everything in this
+    // directory: those not read within max_age, then the least recently
+    // read until the rest fit in max_bytes
+    pub fn expired(&self, mut files: Vec<(PathBuf, FileMetadata)>, now: SystemTime) -> Vec<PathBuf> {
+        files.sort_by(|(a_path, a), (b_path, b)| a.last_access_time.cmp(&b.last_access_time).then_with(|| a_path.cmp(b_path)));
+        let mut total: u64 = files.iter().map(|(_, metadata)| metadata.file_size).sum();
+        let mut expired = Vec::new();
+        for (path, metadata) in files {
+            let too_old = self.max_age.is_some_and(|max_age| now.duration_since(metadata.last_access_time).unwrap_or_default() > max_age);
+            let too_big = self.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+            if !too_old && !too_big {
+                continue;
+            }
+            total -= metadata.file_size;
+            expired.push(path);
+        }
+        expired
+    }
+}
+
+pub fn scratch_dirs(config: &Value) -> Vec<ScratchDir> {
+    let Some(dirs) = config.get("scratch_dirs").and_then(Value::as_array) else { return Vec::new() };
+    dirs.iter().filter_map(|dir| {
+        // scratch paths are relative to the pool, like every other path we track
+        let path = PathBuf::from(dir.get("path").and_then(Value::as_str)?.trim_matches('/'));
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+        Some(ScratchDir {
+            path,
+            tier: dir.get("tier").and_then(Value::as_str).unwrap_or("hot").to_string(),
+            branch: dir.get("branch").and_then(Value::as_str).map(str::to_string),
+            max_age: dir.get("max_age_hours").and_then(Value::as_f64).map(|hours| Duration::from_secs_f64(hours.max(0.0) * 3600.0)),
+            max_bytes: dir.get("max_size_gb").and_then(Value::as_f64).map(|gb| (gb.max(0.0) * 1024.0 * 1024.0 * 1024.0) as u64),
+        })
+    }).collect()
+}
+
+// The scratch directory a file is in, if any
+pub fn find<'a>(dirs: &'a [ScratchDir], path: &Path) -> Option<&'a ScratchDir> {
+    dirs.iter().find(|dir| dir.contains(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn file(atime: u64, size: u64) -> FileMetadata {
+        FileMetadata {
+            last_access_time: SystemTime::UNIX_EPOCH + Duration::from_secs(atime),
+            access_count: 1,
+            file_size: size,
+            tier: "hot".to_string(),
+            last_tier_move: None,
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let dirs = scratch_dirs(&json!({ "scratch_dirs": [
+            { "path": "/transcode/", "max_age_hours": 1 },
+            { "path": "downloads/incomplete", "tier": "warm", "branch": "S1", "max_size_gb": 2 },
+            { "path": "/" },
+        ] }));
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0].path, Path::new("transcode"));
+        assert_eq!(dirs[0].tier, "hot");
+        assert_eq!(dirs[0].max_age, Some(Duration::from_secs(3600)));
+        assert_eq!(dirs[1].branch.as_deref(), Some("S1"));
+        assert_eq!(dirs[1].max_bytes, Some(2 << 30));
+        assert_eq!(find(&dirs, Path::new("transcode/a.ts")), Some(&dirs[0]));
+        assert_eq!(find(&dirs, Path::new("transcoded/a.ts")), None);
+        assert!(scratch_dirs(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_expired() {
+        let dir = ScratchDir { path: "t".into(), tier: "hot".to_string(), branch: None, max_age: Some(Duration::from_secs(100)), max_bytes: Some(25) };
+        let files = vec![("t/new".into(), file(990, 10)), ("t/old".into(), file(800, 10)), ("t/mid".into(), file(950, 10)), ("t/newer".into(), file(995, 10))];
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        // old is past max_age, then mid goes to get under 25 bytes
+        assert_eq!(dir.expired(files, now), [PathBuf::from("t/old"), PathBuf::from("t/mid")]);
+        let unbounded = ScratchDir { max_age: None, max_bytes: None, ..dir };
+        assert!(unbounded.expired(vec![("t/a".into(), file(0, 10))], now).is_empty());
+    }
+}
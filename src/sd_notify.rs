@@ -0,0 +1,47 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// Send a state update (e.g. "READY=1", "WATCHDOG=1") to systemd. Returns false
+// when not running under a notify-type service.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let socket_path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return Ok(false),
+    };
+    let addr = match socket_path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes())?,
+        None => SocketAddr::from_pathname(&socket_path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
+
+// Half of the watchdog timeout systemd configured for us, if any.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_notify() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&socket_path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &socket_path);
+        assert!(notify("READY=1").unwrap());
+        env::remove_var("NOTIFY_SOCKET");
+        let mut buf = [0u8; 16];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        assert!(!notify("READY=1").unwrap());
+    }
+}
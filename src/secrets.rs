@@ -0,0 +1,154 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::Value;
+use simple_logger::SimpleLogger;
+use crate::executor::{checked, Executor};
+
+// Config strings with these prefixes are references to a secret kept
+// elsewhere, resolved when the config is loaded:
+//   "@file:/etc/drive-manager/smtp-password"  the file's contents, less a
+//                                             trailing newline; relative
+//                                             paths are from the config's dir
+//   "@keyring:smtp"                           the system keyring entry with
+//                                             service=drive-manager key=smtp
+pub const FILE_PREFIX: &str = "@file:";
+pub const KEYRING_PREFIX: &str = "@keyring:";
+pub const KEYRING_SERVICE: &str = "drive-manager";
+pub const REDACTED: &str = "[redacted]";
+
+// Every secret resolved so far, so they can be kept out of what we write
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn is_reference(text: &str) -> bool {
+    text.starts_with(FILE_PREFIX) || text.starts_with(KEYRING_PREFIX)
+}
+
+fn resolve_one(reference: &str, base_dir: &Path, executor: &dyn Executor) -> io::Result<String> {
+    let secret = if let Some(path) = reference.strip_prefix(FILE_PREFIX) {
+        let path = base_dir.join(path);
+        let content = fs::read_to_string(&path).map_err(|e| io::Error::new(e.kind(), format!("secret {}: {}", path.display(), e)))?;
+        content.trim_end_matches(['\n', '\r']).to_string()
+    } else {
+        let key = &reference[KEYRING_PREFIX.len()..];
+        let cmd: [&OsStr; 6] = ["secret-tool".as_ref(), "lookup".as_ref(), "service".as_ref(), KEYRING_SERVICE.as_ref(), "key".as_ref(), key.as_ref()];
+        let output = checked(&cmd, executor.output(&cmd)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("secret {} from the keyring: {}", key, e)))?;
+        String::from_utf8_lossy(&output.stdout).trim_end_matches(['\n', '\r']).to_string()
+    };
+    register(&secret);
+    Ok(secret)
+}
+
+// Replace every secret reference in `config` with the secret itself
+pub fn resolve(config: &mut Value, base_dir: &Path, executor: &dyn Executor) -> io::Result<()> {
+    match config {
+        Value::String(text) if is_reference(text) => *text = resolve_one(text, base_dir, executor)?,
+        Value::Array(values) => {
+            for value in values {
+                resolve(value, base_dir, executor)?;
+            }
+        }
+        Value::Object(values) => {
+            for value in values.values_mut() {
+                resolve(value, base_dir, executor)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn register(secret: &str) {
+    let mut secrets = SECRETS.lock().unwrap();
+    if !secret.is_empty() && !secrets.iter().any(|known| known == secret) {
+        secrets.push(secret.to_string());
+        // longest first, so a secret containing another is hidden whole
+        secrets.sort_by_key(|known| std::cmp::Reverse(known.len()));
+    }
+}
+
+// `text` with every secret resolved so far taken out
+pub fn redact(text: &str) -> String {
+    let secrets = SECRETS.lock().unwrap();
+    secrets.iter().fold(text.to_string(), |text, secret| text.replace(secret.as_str(), REDACTED))
+}
+
+// Passes log records on to a SimpleLogger with any secrets taken out
+pub struct RedactingLogger {
+    inner: SimpleLogger,
+}
+
+impl RedactingLogger {
+    pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+        log::set_max_level(level);
+        log::set_boxed_logger(Box::new(Self { inner: SimpleLogger::new().with_level(level) }))
+    }
+}
+
+impl Log for RedactingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = redact(&record.args().to_string());
+        self.inner.log(&Record::builder()
+            .metadata(record.metadata().clone())
+            .args(format_args!("{}", message))
+            .module_path(record.module_path())
+            .file(record.file())
+            .line(record.line())
+            .build());
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Output;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    // Answers secret-tool lookups from a fixed table
+    struct Keyring(Mutex<Vec<String>>);
+
+    impl Executor for Keyring {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            self.output(cmd).map(|output| output.status)
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<Output> {
+            use std::os::unix::process::ExitStatusExt;
+            let key = cmd[5].to_string_lossy().to_string();
+            self.0.lock().unwrap().push(key.clone());
+            let (code, stdout) = if key == "smtp" { (0, "keyring-pass-1234\n") } else { (1, "") };
+            Ok(Output { status: std::process::ExitStatus::from_raw(code << 8), stdout: stdout.into(), stderr: Vec::new() })
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("api-token"), "file-token-5678\n").unwrap();
+        let keyring = Keyring(Mutex::new(Vec::new()));
+        let mut config = json!({ "alerts": { "smtp_password": "@keyring:smtp", "tokens": ["@file:api-token", "plain"] }, "filesystem": "ext4" });
+        resolve(&mut config, dir.path(), &keyring).unwrap();
+        assert_eq!(config, json!({ "alerts": { "smtp_password": "keyring-pass-1234", "tokens": ["file-token-5678", "plain"] }, "filesystem": "ext4" }));
+        assert_eq!(*keyring.0.lock().unwrap(), ["smtp"]);
+        assert_eq!(redact("login keyring-pass-1234 with file-token-5678"), format!("login {} with {}", REDACTED, REDACTED));
+
+        assert!(resolve(&mut json!({ "password": "@keyring:missing" }), dir.path(), &keyring).unwrap_err().to_string().contains("missing"));
+        assert_eq!(resolve(&mut json!("@file:nope"), dir.path(), &keyring).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
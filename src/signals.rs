@@ -0,0 +1,63 @@
+use std::io;
+use std::mem::MaybeUninit;
+
+// SIGTERM and SIGINT stop the service and SIGHUP reloads its config. The
+// signals are blocked in every thread and taken with sigwait by one, so no
+// handler ever interrupts a thread mid-write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Shutdown,
+    Reload,
+}
+
+pub struct Signals {
+    set: libc::sigset_t,
+}
+
+impl Signals {
+    // Block the signals in the calling thread and every thread it starts
+    // afterwards, so call it before starting any. Commands we run start
+    // with the mask cleared again.
+    pub fn block() -> io::Result<Self> {
+        let set = unsafe {
+            let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(set.as_mut_ptr());
+            for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+                libc::sigaddset(set.as_mut_ptr(), signal);
+            }
+            set.assume_init()
+        };
+        match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+            0 => Ok(Self { set }),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    // Block until one of the signals arrives
+    pub fn wait(&self) -> io::Result<Signal> {
+        let mut signal = 0;
+        match unsafe { libc::sigwait(&self.set, &mut signal) } {
+            0 if signal == libc::SIGHUP => Ok(Signal::Reload),
+            0 => Ok(Signal::Shutdown),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_signals() {
+        // a thread of its own, as the mask stays with the thread
+        thread::spawn(|| {
+            let signals = Signals::block().unwrap();
+            unsafe { libc::raise(libc::SIGHUP) };
+            assert_eq!(signals.wait().unwrap(), Signal::Reload);
+            unsafe { libc::raise(libc::SIGTERM) };
+            assert_eq!(signals.wait().unwrap(), Signal::Shutdown);
+        }).join().unwrap();
+    }
+}
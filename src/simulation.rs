@@ -0,0 +1,627 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use crate::archive::Archive;
+use crate::args::Args;
+use crate::clock::ManualClock;
+use crate::drive_manager::DriveManager;
+use crate::lsblk::BlockDevice;
+use crate::metadata_db::MetadataDb;
+use crate::open_files::{OpenMode, OpenReader};
+use crate::placement::Placement;
+use crate::storage::{self, BranchUsage, ScannedFile, Storage, TierUsage};
+use crate::check_schedule::CheckSchedule;
+use crate::tiering_manager::{tier_rank, TieringManager};
+
+const GB: u64 = 1 << 30;
+const MB: u64 = 1 << 20;
+// what an archive stub takes up on its drive
+const STUB_SIZE: u64 = 256;
+// 2024-01-01T00:00:00Z, so simulated runs are reproducible
+const SIMULATION_START: u64 = 1_704_067_200;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimDrive {
+    pub serial: String,
+    pub block_class: String,
+    pub tier: String,
+    pub capacity: u64,
+}
+
+impl SimDrive {
+    pub fn new(serial: &str, block_class: &str, capacity: u64) -> Self {
+        let tran = if block_class == "nvme" { "nvme" } else { "sata" };
+        let device = BlockDevice { rota: block_class == "hdd", tran: Some(tran.to_string()), ..Default::default() };
+        Self {
+            serial: serial.to_string(),
+            block_class: block_class.to_string(),
+            tier: device.tier().to_string(),
+            capacity,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SimFile {
+    drive: usize,
+    size: u64,
+    accessed: SystemTime,
+    modified: SystemTime,
+    placement: Placement,
+    // shared by hard links to the same file
+    inode: Option<u64>,
+}
+
+// In-memory drives and files standing in for the mounted branches
+pub struct SimulatedStorage {
+    drives: Vec<SimDrive>,
+    files: Mutex<SimFiles>,
+    // drives being drained, detached or giving I/O errors, which take no moves
+    avoided: Mutex<HashSet<String>>,
+    // drives external jobs are working on
+    held: Mutex<HashSet<String>>,
+    // drives pulled along with their files
+    offline: Mutex<HashSet<String>>,
+    // drives left out of scans while their first crawl runs
+    crawling: Mutex<HashSet<String>>,
+    // archived files, with the size they had before their stub
+    bucket: Mutex<HashMap<PathBuf, u64>>,
+}
+
+#[derive(Default)]
+struct SimFiles {
+    files: BTreeMap<PathBuf, SimFile>,
+    used: Vec<u64>,
+    // files some simulated process has open
+    open: HashMap<PathBuf, OpenMode>,
+    readers: Vec<OpenReader>,
+    next_inode: u64,
+}
+
+impl SimFiles {
+    // Links to one file on a drive only take up its space once
+    fn insert(&mut self, path: &Path, file: SimFile) {
+        self.remove(path);
+        if !self.linked(path, &file) {
+            self.used[file.drive] += file.size;
+        }
+        self.files.insert(path.to_path_buf(), file);
+    }
+
+    fn remove(&mut self, path: &Path) -> Option<SimFile> {
+        let file = self.files.remove(path)?;
+        if !self.linked(path, &file) {
+            self.used[file.drive] -= file.size;
+        }
+        Some(file)
+    }
+
+    // Whether another path on the file's drive links to it
+    fn linked(&self, path: &Path, file: &SimFile) -> bool {
+        file.inode.is_some_and(|inode| self.files.iter().any(|(other, other_file)| {
+            other != path && other_file.inode == Some(inode) && other_file.drive == file.drive
+        }))
+    }
+}
+
+impl SimulatedStorage {
+    pub fn new(mut drives: Vec<SimDrive>) -> Self {
+        // same ordering the hot mergerfs pool uses, so "ff" creates land on the fastest drive
+        drives.sort_by_key(|drive| DriveManager::block_class_order(&drive.block_class));
+        let used = vec![0; drives.len()];
+        Self {
+            drives,
+            files: Mutex::new(SimFiles { files: BTreeMap::new(), used, open: HashMap::new(), readers: Vec::new(), next_inode: 1 }),
+            avoided: Mutex::new(HashSet::new()),
+            held: Mutex::new(HashSet::new()),
+            offline: Mutex::new(HashSet::new()),
+            crawling: Mutex::new(HashSet::new()),
+            bucket: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Pull a drive as if it were unplugged, its files with it, or bring it
+    // back
+    pub fn set_offline(&self, serial: &str, offline: bool) {
+        let mut drives = self.offline.lock().unwrap();
+        if offline {
+            drives.insert(serial.to_string());
+        } else {
+            drives.remove(serial);
+        }
+    }
+
+    pub fn set_crawling(&self, serial: &str, crawling: bool) {
+        let mut drives = self.crawling.lock().unwrap();
+        if crawling {
+            drives.insert(serial.to_string());
+        } else {
+            drives.remove(serial);
+        }
+    }
+
+    fn is_online(&self, drive: usize) -> bool {
+        !self.offline.lock().unwrap().contains(&self.drives[drive].serial)
+    }
+
+    fn free(&self, files: &SimFiles, drive: usize) -> u64 {
+        self.drives[drive].capacity.saturating_sub(files.used[drive])
+    }
+
+    // The drive in the target tier with the most free space, if the file fits
+    fn target_drive(&self, files: &SimFiles, path: &Path, target_tier: &str, size: u64) -> io::Result<usize> {
+        let avoided = self.avoided.lock().unwrap();
+        let held = self.held.lock().unwrap();
+        let usable: Vec<usize> = (0..self.drives.len())
+            .filter(|&drive| self.drives[drive].tier == target_tier && !avoided.contains(&self.drives[drive].serial) && self.is_online(drive))
+            .collect();
+        if !usable.is_empty() && usable.iter().all(|&drive| held.contains(&self.drives[drive].serial)) {
+            return Err(storage::held_error(target_tier));
+        }
+        usable.into_iter()
+            .filter(|&drive| !held.contains(&self.drives[drive].serial))
+            .map(|drive| (drive, self.free(files, drive)))
+            .filter(|(_, free)| *free >= size)
+            .max_by_key(|(_, free)| *free)
+            .map(|(drive, _)| drive)
+            .ok_or_else(|| Self::no_space(path))
+    }
+
+    fn no_space(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::StorageFull, format!("no space left for {}", path.display()))
+    }
+
+    // Create a file the way the hot pool's first-found create policy would
+    pub fn create_file<P: AsRef<Path>>(&self, path: P, size: u64, now: SystemTime) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut files = self.files.lock().unwrap();
+        let drive = (0..self.drives.len())
+            .find(|&drive| self.is_online(drive) && self.free(&files, drive) >= size)
+            .ok_or_else(|| Self::no_space(path))?;
+        files.insert(path, SimFile { drive, size, accessed: now, modified: now, placement: Placement::default(), inode: None });
+        Ok(())
+    }
+
+    // Make `link` a hard link to the file at `path`
+    pub fn link_file<P: AsRef<Path>, Q: AsRef<Path>>(&self, path: P, link: Q) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let inode = match files.files.get(path.as_ref()).map(|file| file.inode) {
+            Some(Some(inode)) => inode,
+            Some(None) => {
+                let inode = files.next_inode;
+                files.next_inode += 1;
+                files.files.get_mut(path.as_ref()).unwrap().inode = Some(inode);
+                inode
+            }
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.as_ref().display()))),
+        };
+        let file = SimFile { inode: Some(inode), ..files.files[path.as_ref()].clone() };
+        files.insert(link.as_ref(), file);
+        Ok(())
+    }
+
+    // Move a file to another drive of its tier behind the manager's back, as
+    // mergerfs's moveonenospc does when a write runs out of room
+    pub fn relocate<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tier = self.tier_of(path).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())))?;
+        self.move_file(path, &tier, &tier, &|_| {})
+    }
+
+    // Tag a file as an application would with the user.drivemanager.* xattrs
+    pub fn set_placement<P: AsRef<Path>>(&self, path: P, placement: Placement) {
+        if let Some(file) = self.files.lock().unwrap().files.get_mut(path.as_ref()) {
+            file.placement = placement;
+        }
+    }
+
+    // Mark a file open by some process, or closed again with None
+    pub fn set_open<P: AsRef<Path>>(&self, path: P, mode: Option<OpenMode>) {
+        let mut files = self.files.lock().unwrap();
+        match mode {
+            Some(mode) => files.open.insert(path.as_ref().to_path_buf(), mode),
+            None => files.open.remove(path.as_ref()),
+        };
+    }
+
+    // The read fds the next readers() call reports
+    pub fn set_readers(&self, readers: Vec<OpenReader>) {
+        self.files.lock().unwrap().readers = readers;
+    }
+
+    pub fn remove_file<P: AsRef<Path>>(&self, path: P) {
+        self.files.lock().unwrap().remove(path.as_ref());
+    }
+
+    // Read a file, returning the tier that served it
+    pub fn access<P: AsRef<Path>>(&self, path: P, now: SystemTime) -> Option<String> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.files.get_mut(path.as_ref())?;
+        file.accessed = now;
+        Some(self.drives[file.drive].tier.clone())
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P, now: SystemTime) {
+        if let Some(file) = self.files.lock().unwrap().files.get_mut(path.as_ref()) {
+            file.modified = now;
+        }
+    }
+
+    pub fn tier_of<P: AsRef<Path>>(&self, path: P) -> Option<String> {
+        self.files.lock().unwrap().files.get(path.as_ref()).map(|file| self.drives[file.drive].tier.clone())
+    }
+
+    pub fn size_of<P: AsRef<Path>>(&self, path: P) -> Option<u64> {
+        self.files.lock().unwrap().files.get(path.as_ref()).map(|file| file.size)
+    }
+}
+
+impl Storage for SimulatedStorage {
+    fn scan(&self) -> io::Result<Vec<ScannedFile>> {
+        let files = self.files.lock().unwrap();
+        let crawling = self.crawling.lock().unwrap();
+        Ok(files.files.iter().filter(|(_, file)| self.is_online(file.drive) && !crawling.contains(&self.drives[file.drive].serial)).map(|(path, file)| ScannedFile {
+            path: path.clone(),
+            tier: self.drives[file.drive].tier.clone(),
+            branch: self.drives[file.drive].serial.clone(),
+            accessed: file.accessed,
+            modified: file.modified,
+            size: file.size,
+            placement: file.placement.clone(),
+            hardlink: file.inode.filter(|_| files.linked(path, file)).map(|inode| (file.drive as u64, inode)),
+        }).collect())
+    }
+
+    fn tier_usage(&self, tier: &str) -> io::Result<TierUsage> {
+        let files = self.files.lock().unwrap();
+        let mut usage = TierUsage::default();
+        for (index, drive) in self.drives.iter().enumerate().filter(|(index, drive)| drive.tier == tier && self.is_online(*index)) {
+            usage.total += drive.capacity;
+            usage.used += files.used[index];
+        }
+        Ok(usage)
+    }
+
+    fn branch_usage(&self) -> io::Result<Vec<BranchUsage>> {
+        let files = self.files.lock().unwrap();
+        Ok(self.drives.iter().enumerate().filter(|(index, _)| self.is_online(*index)).map(|(index, drive)| BranchUsage {
+            serial: drive.serial.clone(),
+            tier: drive.tier.clone(),
+            usage: TierUsage { total: drive.capacity, used: files.used[index] },
+        }).collect())
+    }
+
+    fn branch_of(&self, path: &Path, tier: &str) -> Option<String> {
+        let files = self.files.lock().unwrap();
+        files.files.get(path).map(|file| &self.drives[file.drive]).filter(|drive| drive.tier == tier).map(|drive| drive.serial.clone())
+    }
+
+    fn file_size(&self, path: &Path, tier: &str) -> Option<u64> {
+        let files = self.files.lock().unwrap();
+        files.files.get(path).filter(|file| self.drives[file.drive].tier == tier).map(|file| file.size)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().files.contains_key(path)
+    }
+
+    fn open_mode(&self, path: &Path, _tier: &str) -> io::Result<Option<OpenMode>> {
+        Ok(self.files.lock().unwrap().open.get(path).copied())
+    }
+
+    fn readers(&self) -> io::Result<Vec<OpenReader>> {
+        Ok(self.files.lock().unwrap().readers.clone())
+    }
+
+    fn move_file(&self, path: &Path, source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.files.get(path).filter(|file| self.drives[file.drive].tier == source_tier && self.is_online(file.drive)).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", path.display(), source_tier)))?;
+        let drive = self.target_drive(&files, path, target_tier, file.size)?;
+        if drive == file.drive {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, format!("no other drive in tier {} has room for {}", target_tier, path.display())));
+        }
+        files.insert(path, SimFile { drive, ..file });
+        progress(file.size);
+        Ok(())
+    }
+
+    fn move_link_group(&self, paths: &[PathBuf], source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let group: Vec<SimFile> = paths.iter()
+            .map(|path| files.files.get(path).filter(|file| self.drives[file.drive].tier == source_tier).cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", path.display(), source_tier))))
+            .collect::<io::Result<_>>()?;
+        let Some(first) = group.first() else { return Ok(()) };
+        if group.iter().any(|file| file.inode.is_none() || file.inode != first.inode || file.drive != first.drive) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not linked to the rest of its group", paths[0].display())));
+        }
+        let drive = self.target_drive(&files, &paths[0], target_tier, first.size)?;
+        let size = first.size;
+        for (path, file) in paths.iter().zip(group) {
+            files.insert(path, SimFile { drive, ..file });
+        }
+        progress(size);
+        Ok(())
+    }
+
+    fn avoid_branch(&self, serial: &str) {
+        self.avoided.lock().unwrap().insert(serial.to_string());
+    }
+
+    fn hold_branches(&self, serials: &HashSet<String>) {
+        *self.held.lock().unwrap() = serials.clone();
+    }
+
+    fn offline_branches(&self) -> HashSet<String> {
+        self.offline.lock().unwrap().clone()
+    }
+
+    fn crawling_branches(&self) -> HashSet<String> {
+        self.crawling.lock().unwrap().clone()
+    }
+
+    // A detached drive stays in the list, empty and taking no moves
+    fn detach_branch(&self, serial: &str) -> io::Result<()> {
+        let drive = self.drives.iter().position(|drive| drive.serial == serial)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no drive {}", serial)))?;
+        if self.files.lock().unwrap().files.values().any(|file| file.drive == drive) {
+            return Err(io::Error::other(format!("drive {} still has files", serial)));
+        }
+        self.avoid_branch(serial);
+        Ok(())
+    }
+
+    fn delete_file(&self, path: &Path, tier: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        match files.files.get(path) {
+            Some(file) if self.drives[file.drive].tier == tier => {
+                files.remove(path);
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", path.display(), tier))),
+        }
+    }
+
+    fn archive_file(&self, path: &Path, tier: &str, archive: &Archive, record: &dyn Fn(&str) -> io::Result<()>) -> io::Result<String> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.files.get(path).filter(|file| self.drives[file.drive].tier == tier && self.is_online(file.drive)).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", path.display(), tier)))?;
+        if files.linked(path, &file) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file with a single link", path.display())));
+        }
+        let url = archive.url(path)?;
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.contains_key(path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is already archived", path.display())));
+        }
+        record(&url)?;
+        bucket.insert(path.to_path_buf(), file.size);
+        files.insert(path, SimFile { size: STUB_SIZE, ..file });
+        Ok(url)
+    }
+
+    fn restore_file(&self, path: &Path, tier: &str, _archive: &Archive) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.files.get(path).filter(|file| self.drives[file.drive].tier == tier && self.is_online(file.drive)).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", path.display(), tier)))?;
+        let size = self.bucket.lock().unwrap().remove(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not an archive stub", path.display())))?;
+        files.insert(path, SimFile { size, ..file });
+        Ok(())
+    }
+}
+
+// xorshift64*; good enough for synthetic workloads and fully deterministic
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationConfig {
+    pub days: u64,
+    pub seed: u64,
+    pub files: usize,
+    pub accesses_per_hour: u64,
+    pub min_file_size: u64,
+    pub max_file_size: u64,
+    // the popular working set rotates to different files every this many days
+    pub working_set_shift_days: u64,
+    pub drives: Vec<SimDrive>,
+}
+
+impl SimulationConfig {
+    pub fn from_config(config: &Value) -> Self {
+        let sim = config.get("simulation").cloned().unwrap_or_else(|| json!({}));
+        let get_u64 = |key: &str, default: u64| sim.get(key).and_then(Value::as_u64).unwrap_or(default);
+        let drives = sim.get("drives").and_then(Value::as_array).map(|drives| {
+            drives.iter().enumerate().map(|(i, drive)| {
+                let block_class = drive.get("block_class").and_then(Value::as_str).unwrap_or("hdd");
+                let serial = drive.get("serial").and_then(Value::as_str).map(str::to_string).unwrap_or_else(|| format!("sim-{}{}", block_class, i));
+                SimDrive::new(&serial, block_class, drive.get("capacity_gb").and_then(Value::as_u64).unwrap_or(1000) * GB)
+            }).collect()
+        }).unwrap_or_else(|| vec![
+            SimDrive::new("sim-nvme0", "nvme", 500 * GB),
+            SimDrive::new("sim-ssd0", "ssd", 1000 * GB),
+            SimDrive::new("sim-hdd0", "hdd", 8000 * GB),
+            SimDrive::new("sim-hdd1", "hdd", 8000 * GB),
+        ]);
+        Self {
+            days: get_u64("days", 28),
+            seed: get_u64("seed", 1),
+            files: get_u64("files", 5000) as usize,
+            accesses_per_hour: get_u64("accesses_per_hour", 200),
+            min_file_size: get_u64("min_file_size_mb", 1) * MB,
+            max_file_size: get_u64("max_file_size_mb", 8192) * MB,
+            working_set_shift_days: get_u64("working_set_shift_days", 7).max(1),
+            drives,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimulationReport {
+    pub days: u64,
+    pub accesses: u64,
+    pub hits: BTreeMap<String, u64>,
+    pub tiering_checks: u64,
+    pub promotions: u64,
+    pub demotions: u64,
+    pub bytes_moved: u64,
+    pub abandoned_moves: u64,
+    pub final_usage: Vec<(String, TierUsage)>,
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Simulated {} days, {} tiering checks, {} file accesses", self.days, self.tiering_checks, self.accesses)?;
+        for tier in DriveManager::TIERS {
+            let hits = self.hits.get(tier).copied().unwrap_or(0);
+            let percent = if self.accesses > 0 { hits as f64 / self.accesses as f64 * 100.0 } else { 0.0 };
+            writeln!(f, "  reads served from {:<4} {:>10} ({:.1}%)", tier, hits, percent)?;
+        }
+        writeln!(f, "Promotions: {}, demotions: {}, data moved: {:.1} GB", self.promotions, self.demotions, self.bytes_moved as f64 / GB as f64)?;
+        writeln!(f, "Moves dead-lettered after exhausting retries: {}", self.abandoned_moves)?;
+        for (tier, usage) in &self.final_usage {
+            writeln!(f, "  {:<4} {:>10.1} / {:.1} GB ({:.1}%)", tier, usage.used as f64 / GB as f64, usage.total as f64 / GB as f64, usage.usage_percent())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run(args: &Args, config: &Value) -> io::Result<SimulationReport> {
+    let sim = SimulationConfig::from_config(config);
+    let storage = Arc::new(SimulatedStorage::new(sim.drives.clone()));
+    let start = UNIX_EPOCH + Duration::from_secs(SIMULATION_START);
+    let clock = Arc::new(ManualClock::new(start));
+    let tiering_manager = TieringManager::with_clock(args.clone(), config.clone(), storage.clone(), MetadataDb::open_in_memory()?, clock.clone());
+    let mut rng = Rng::new(sim.seed);
+
+    // log-uniform sizes, so most files are small and a few are huge
+    let (log_min, log_max) = ((sim.min_file_size.max(1) as f64).ln(), (sim.max_file_size.max(sim.min_file_size).max(1) as f64).ln());
+    let mut paths = Vec::with_capacity(sim.files);
+    for i in 0..sim.files {
+        let path = format!("data/dir{:03}/file{:06}", i % 100, i);
+        let size = (log_min + rng.next_f64() * (log_max - log_min)).exp() as u64;
+        if storage.create_file(&path, size, start).is_ok() {
+            paths.push(path);
+        }
+    }
+
+    let mut report = SimulationReport { days: sim.days, ..Default::default() };
+    // checks run on the hour, without jitter, so runs can be compared
+    let check_hours = (CheckSchedule::from_config(config).interval.as_secs() / 3600).max(1);
+    let hours = sim.days * 24;
+    for hour in 0..hours {
+        let hour_start = start + Duration::from_secs(hour * 3600);
+        let shift = (hour / 24 / sim.working_set_shift_days) as usize * paths.len() / 4;
+        for _ in 0..sim.accesses_per_hour {
+            if paths.is_empty() {
+                break;
+            }
+            let popularity = (paths.len() as f64 * rng.next_f64().powi(4)) as usize;
+            let path = &paths[(popularity + shift) % paths.len()];
+            let now = hour_start + Duration::from_secs(rng.next_u64() % 3600);
+            if let Some(tier) = storage.access(path, now) {
+                report.accesses += 1;
+                *report.hits.entry(tier).or_insert(0) += 1;
+            }
+        }
+
+        if hour % check_hours == 0 {
+            clock.set(hour_start + Duration::from_secs(3599));
+            tiering_manager.perform_tiering_check()?;
+            report.tiering_checks += 1;
+            let processed = tiering_manager.process_queued_moves();
+            report.abandoned_moves += processed.abandoned.len() as u64;
+            for moved in processed.completed {
+                if tier_rank(&moved.target_tier) < tier_rank(&moved.source_tier) {
+                    report.promotions += 1;
+                } else {
+                    report.demotions += 1;
+                }
+                report.bytes_moved += storage.size_of(&moved.src).unwrap_or(0);
+            }
+        }
+    }
+
+    for tier in DriveManager::TIERS {
+        report.final_usage.push((tier.to_string(), storage.tier_usage(tier)?));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiering_manager::TIERING_CHECK_INTERVAL;
+
+    fn small_config() -> Value {
+        json!({
+            "simulation": {
+                "days": 3,
+                "files": 200,
+                "accesses_per_hour": 30,
+                "max_file_size_mb": 512,
+                "drives": [
+                    { "block_class": "nvme", "capacity_gb": 10 },
+                    { "block_class": "ssd", "capacity_gb": 20 },
+                    { "block_class": "hdd", "capacity_gb": 200 }
+                ]
+            }
+        })
+    }
+
+    #[test]
+    fn test_simulation_config_defaults() {
+        let sim = SimulationConfig::from_config(&json!({}));
+        assert_eq!(sim.days, 28);
+        assert_eq!(sim.drives.len(), 4);
+        assert_eq!(sim.drives[0].tier, "hot");
+        assert_eq!(sim.drives[2].tier, "cold");
+    }
+
+    #[test]
+    fn test_simulated_storage_placement() {
+        let storage = SimulatedStorage::new(vec![SimDrive::new("h", "hdd", 10 * GB), SimDrive::new("n", "nvme", GB)]);
+        let now = UNIX_EPOCH;
+        storage.create_file("a", GB, now).unwrap();
+        storage.create_file("b", GB, now).unwrap();
+        assert_eq!(storage.tier_of("a").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("b").as_deref(), Some("cold"));
+        assert!(storage.move_file(Path::new("b"), "cold", "hot", &|_| {}).is_err());
+        storage.move_file(Path::new("a"), "hot", "cold", &|_| {}).unwrap();
+        assert_eq!(storage.tier_usage("cold").unwrap().used, 2 * GB);
+    }
+
+    #[test]
+    fn test_run_is_deterministic() {
+        let args = Args::parse_from(["--simulate"]).unwrap();
+        let first = run(&args, &small_config()).unwrap();
+        let second = run(&args, &small_config()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.accesses, 3 * 24 * 30);
+        assert_eq!(first.tiering_checks, 3 * 24 * 3600 / TIERING_CHECK_INTERVAL);
+        assert!(first.demotions > 0);
+        assert!(first.to_string().contains("reads served from hot"));
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+
+// how much of the hot tier stagings may hold between them, unless the
+// staging section sets max_hot_percent
+pub const MAX_HOT_PERCENT: f64 = 50.0;
+// how long a staging holds its files when nothing releases it
+pub const DEFAULT_MAX_HOURS: f64 = 24.0;
+
+// Input files an external scheduler (Slurm, a CI runner, a render farm)
+// wants on the hot tier before a job, from the staging config section:
+//   "staging": { "max_hot_percent": 50, "max_hours": 24 }
+// Its prolog runs `drive-manager stage <name> <path>...` and its epilog
+// `drive-manager release <name>`. Staged files are held on hot like a pin
+// until released. Each staging reserves its files' bytes, and one that would
+// take the reservations past max_hot_percent of the hot tier is refused, so
+// stagings never promise more than the tier holds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Staging {
+    pub max_hot_percent: f64,
+    pub max_duration: Duration,
+}
+
+impl Staging {
+    pub fn from_config(config: &Value) -> Self {
+        let section = config.get("staging");
+        let hours = section.and_then(|section| section.get("max_hours")).and_then(Value::as_f64).unwrap_or(DEFAULT_MAX_HOURS);
+        Self {
+            max_hot_percent: section.and_then(|section| section.get("max_hot_percent")).and_then(Value::as_f64).unwrap_or(MAX_HOT_PERCENT).clamp(0.0, 100.0),
+            max_duration: Duration::from_secs_f64(hours.max(0.0) * 3600.0),
+        }
+    }
+
+    // The bytes stagings may reserve on a hot tier of `hot_total` bytes
+    pub fn limit(&self, hot_total: u64) -> u64 {
+        (hot_total as f64 * self.max_hot_percent / 100.0) as u64
+    }
+
+    // Whether reservations of `reserved` bytes in all, counting the new
+    // staging's, fit the hot tier
+    pub fn admits(&self, reserved: u64, hot_total: u64) -> bool {
+        reserved <= self.limit(hot_total)
+    }
+}
+
+// A staging that has not been released or run out its max duration
+#[derive(Clone, Debug, PartialEq)]
+pub struct StagedSet {
+    pub name: String,
+    pub files: u64,
+    pub bytes: u64,
+    // how many of its files are on the hot tier already
+    pub on_hot: u64,
+    pub staged_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_staging() {
+        let staging = Staging::from_config(&json!({}));
+        assert_eq!(staging, Staging { max_hot_percent: 50.0, max_duration: Duration::from_secs(24 * 3600) });
+        assert!(staging.admits(500, 1000));
+        assert!(!staging.admits(501, 1000));
+        let staging = Staging::from_config(&json!({ "staging": { "max_hot_percent": 150, "max_hours": 2 } }));
+        assert_eq!(staging.limit(1000), 1000);
+        assert_eq!(staging.max_duration, Duration::from_secs(7200));
+    }
+}
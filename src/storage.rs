@@ -0,0 +1,2102 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::SystemTime;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use crate::archive::{self, Archive, Stub, StubChecksum};
+use crate::bandwidth::{self, Bandwidth};
+use crate::btrfs;
+use crate::checksum::{Algorithm, Checksums};
+use crate::drive_manager::DriveManager;
+use crate::fill_strategy::{Candidate, FillPolicy, FillStrategy};
+use crate::mover::{self, Mover};
+use crate::executor::{as_args, checked, command_line, Executor, SystemExecutor};
+use crate::open_files::{self, OpenMode, OpenReader};
+use crate::placement::Placement;
+use crate::project_quota::{self, ProjectQuotas, SubtreeUsage};
+use crate::reserve::ReservePolicy;
+use crate::retry::{self, ErrorClass};
+use crate::scratch::{self, ScratchDir};
+use crate::tiering_manager::tier_rank;
+
+// Where the mover writes copies before renaming them into place, relative
+// to the destination branch's root. Scans skip it.
+pub const TEMP_DIR: &str = ".drive-manager/tmp";
+
+// A physical drive mount that is a member of the mergerfs pools
+#[derive(Clone, Debug, PartialEq)]
+pub struct Branch {
+    pub serial: String,
+    pub tier: String,
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannedFile {
+    pub path: PathBuf,
+    pub tier: String,
+    // serial of the branch the file is on
+    pub branch: String,
+    pub accessed: SystemTime,
+    pub modified: SystemTime,
+    pub size: u64,
+    // tags from the file's user.drivemanager.* xattrs
+    pub placement: Placement,
+    // device and inode, for files with more than one hard link
+    pub hardlink: Option<(u64, u64)>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TierUsage {
+    pub total: u64,
+    pub used: u64,
+}
+
+impl TierUsage {
+    pub fn free(&self) -> u64 {
+        self.total.saturating_sub(self.used)
+    }
+
+    pub fn usage_percent(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        (self.used as f64 / self.total as f64) * 100.0
+    }
+}
+
+// How full one branch is
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchUsage {
+    pub serial: String,
+    pub tier: String,
+    pub usage: TierUsage,
+}
+
+// The view of the pooled drives the tiering manager works against. Paths are
+// relative to the root of a branch, which is the same namespace the merged
+// mounts present.
+pub trait Storage: Send + Sync {
+    fn scan(&self) -> io::Result<Vec<ScannedFile>>;
+    fn tier_usage(&self, tier: &str) -> io::Result<TierUsage>;
+    fn branch_usage(&self) -> io::Result<Vec<BranchUsage>>;
+    // The serial of the branch in `tier` holding `path`
+    fn branch_of(&self, path: &Path, tier: &str) -> Option<String>;
+    // The size of the copy of `path` in `tier`
+    fn file_size(&self, path: &Path, tier: &str) -> Option<u64>;
+    fn exists(&self, path: &Path) -> bool;
+    // How the copy of `path` in `tier` is open by other processes, if at all
+    fn open_mode(&self, path: &Path, tier: &str) -> io::Result<Option<OpenMode>>;
+    // Every fd other processes have open for reading on a file in the
+    // branches, with branch-relative paths
+    fn readers(&self) -> io::Result<Vec<OpenReader>>;
+    // progress is called with the bytes copied so far as the move goes on
+    fn move_file(&self, path: &Path, source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()>;
+    // Move every link to one file together, so they are still links to a
+    // single copy on the target tier
+    fn move_link_group(&self, paths: &[PathBuf], source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()>;
+    fn delete_file(&self, path: &Path, tier: &str) -> io::Result<()>;
+    // Move everything under `dir` in one piece, keeping all of its metadata.
+    // Unsupported unless the storage has a way to, in which case the files
+    // move one by one instead.
+    fn move_directory(&self, dir: &Path, _source_tier: &str, _target_tier: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} cannot be moved as a whole", dir.display())))
+    }
+    // Remove the temp files left by copies a crash cut off, given the paths
+    // of the moves that were under way. Returns what was removed.
+    fn remove_temp_files(&self, _interrupted: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+    // Tidy up after a move of `path` a crash cut off, returning whether it
+    // still needs doing: not once the source is gone
+    fn settle_interrupted_move(&self, path: &Path, source_tier: &str, _target_tier: &str) -> io::Result<bool> {
+        Ok(self.branch_of(path, source_tier).is_some())
+    }
+    // Keep moves from choosing the branch as a destination, while it is
+    // drained or once it has given I/O errors
+    fn avoid_branch(&self, _serial: &str) {}
+    // Keep moves from choosing these branches as a destination while
+    // external jobs work on them. Each call replaces the last.
+    fn hold_branches(&self, _serials: &HashSet<String>) {}
+    // Serials of branches that went missing or stopped answering since they
+    // joined. Their files are unavailable, not gone, until they are back.
+    fn offline_branches(&self) -> HashSet<String> {
+        HashSet::new()
+    }
+    // Serials of branches left out of scans while their first crawl runs.
+    // Their files are not gone just because a scan did not see them.
+    fn crawling_branches(&self) -> HashSet<String> {
+        HashSet::new()
+    }
+    // Take a drained branch out of the pools and unmount it
+    fn detach_branch(&self, serial: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("branch {} cannot be detached", serial)))
+    }
+    // Upload the copy of `path` in `tier` to the archive and leave a stub in
+    // its place. `record` is given where it was uploaded to before the stub
+    // goes in, so no crash leaves a stub nothing knows of. Returns the same.
+    fn archive_file(&self, path: &Path, tier: &str, _archive: &Archive, _record: &dyn Fn(&str) -> io::Result<()>) -> io::Result<String> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} in tier {} cannot be archived", path.display(), tier)))
+    }
+    // Fetch an archived file back over its stub in `tier`
+    fn restore_file(&self, path: &Path, tier: &str, _archive: &Archive) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} in tier {} cannot be restored", path.display(), tier)))
+    }
+}
+
+// What a move gets when the only branches it could go to are held, told
+// apart from a busy file by its payload
+#[derive(Debug)]
+struct BranchesHeld(String);
+
+impl fmt::Display for BranchesHeld {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "every branch of tier {} is held by a running job", self.0)
+    }
+}
+
+impl std::error::Error for BranchesHeld {}
+
+pub fn held_error(tier: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ResourceBusy, BranchesHeld(tier.to_string()))
+}
+
+pub fn is_held(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<BranchesHeld>())
+}
+
+// Whether `file_name` is an rsync temp file for `name`: ".<name>." and
+// six random characters
+pub fn is_rsync_temp(file_name: &OsStr, name: &OsStr) -> bool {
+    let (file_name, name) = (file_name.as_bytes(), name.as_bytes());
+    file_name.len() == name.len() + 8
+        && file_name[0] == b'.'
+        && &file_name[1..=name.len()] == name
+        && file_name[name.len() + 1] == b'.'
+        && file_name[name.len() + 2..].iter().all(u8::is_ascii_alphanumeric)
+}
+
+// Write out what is cached for the filesystem holding `path`
+fn sync_filesystem(path: &Path) -> io::Result<()> {
+    let dir = fs::File::open(path)?;
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn disk_usage(path: &Path) -> io::Result<TierUsage> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let total = stat.f_blocks as u64 * stat.f_frsize as u64;
+    let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Ok(TierUsage { total, used: total.saturating_sub(free) })
+}
+
+// atime and mtime as (seconds, nanoseconds), at the full precision of the
+// filesystem
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamps {
+    pub atime: (i64, i64),
+    pub mtime: (i64, i64),
+}
+
+pub fn timestamps(path: &Path) -> io::Result<Timestamps> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(Timestamps { atime: (metadata.atime(), metadata.atime_nsec()), mtime: (metadata.mtime(), metadata.mtime_nsec()) })
+}
+
+// utimensat without following links, so a moved symlink gets its own times back
+pub fn set_timestamps(path: &Path, times: &Timestamps) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let timespecs = [times.atime, times.mtime].map(|(sec, nsec)| libc::timespec { tv_sec: sec as libc::time_t, tv_nsec: nsec as _ });
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), timespecs.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// chattr +i and +a; libc has the ioctls but not the flag values
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+const FS_APPEND_FL: libc::c_int = 0x20;
+pub const LOCKED_FLAGS: libc::c_int = FS_IMMUTABLE_FL | FS_APPEND_FL;
+
+// The inode flags of a regular file, 0 on filesystems that have none
+pub fn file_flags(path: &Path) -> io::Result<libc::c_int> {
+    let file = fs::File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOTTY) | Some(libc::ENOTSUP) => Ok(0),
+            _ => Err(e),
+        };
+    }
+    Ok(flags)
+}
+
+// Needs CAP_LINUX_IMMUTABLE to change the immutable and append-only flags
+pub fn set_file_flags(path: &Path, flags: libc::c_int) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Bytes copied so far from an rsync --info=progress2 line such as
+// "  1,238,099,968  57%  118.06MB/s    0:00:08 (xfr#1, to-chk=0/1)"
+pub fn rsync_progress(line: &str) -> Option<u64> {
+    let mut fields = line.split_whitespace();
+    let bytes = fields.next()?;
+    if !fields.next()?.ends_with('%') {
+        return None;
+    }
+    // the thousands separator follows the locale
+    bytes.chars().filter(|c| !matches!(c, ',' | '.' | '\'')).collect::<String>().parse().ok()
+}
+
+// Visit every regular file and symlink under root. Links are never
+// followed; they are passed with their own lstat metadata. Only failing to
+// read root fails the walk: an entry deleted while it runs or one that
+// cannot be read is skipped.
+pub fn walk_files(root: &Path, visit: &mut dyn FnMut(&Path, &fs::Metadata)) -> io::Result<()> {
+    for entry in fs::read_dir(root)? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                skip_entry(root, &e);
+                continue;
+            }
+        };
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                skip_entry(&path, &e);
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            if let Err(e) = walk_files(&path, visit) {
+                skip_entry(&path, &e);
+            }
+        } else if metadata.is_file() || metadata.is_symlink() {
+            visit(&path, &metadata);
+        }
+    }
+    Ok(())
+}
+
+// Gone since it was listed is expected of a live branch; anything else is
+// worth a look
+fn skip_entry(path: &Path, e: &io::Error) {
+    if e.kind() == io::ErrorKind::NotFound {
+        debug!("Skipping {}, it is gone", path.display());
+    } else {
+        warn!("Skipping {}: {}", path.display(), e);
+    }
+}
+
+// Up to `limit` files under `root`, by path relative to it, that come after
+// `after` in path order, so a walk cut off part way carries on where it
+// was. Directories wholly before `after` are not read again. Entries that
+// cannot be read are skipped, as in walk_files.
+pub fn walk_files_after(root: &Path, after: Option<&Path>, limit: usize, skipped: &[PathBuf]) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+    let mut found = Vec::new();
+    walk_sorted(root, root, after, limit, skipped, &mut found)?;
+    Ok(found)
+}
+
+fn walk_sorted(root: &Path, dir: &Path, after: Option<&Path>, limit: usize, skipped: &[PathBuf], found: &mut Vec<(PathBuf, fs::Metadata)>) -> io::Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.filter_map(|entry| entry.inspect_err(|e| skip_entry(dir, e)).ok()).map(|entry| entry.path()).collect();
+    // sorting each directory walks the tree in path order
+    entries.sort();
+    for path in entries {
+        if found.len() >= limit {
+            break;
+        }
+        let relative = path.strip_prefix(root).unwrap();
+        if skipped.contains(&path) || after.is_some_and(|after| relative <= after && !after.starts_with(relative)) {
+            continue;
+        }
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                skip_entry(&path, &e);
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            if let Err(e) = walk_sorted(root, &path, after, limit, skipped, found) {
+                skip_entry(&path, &e);
+            }
+        } else if (metadata.is_file() || metadata.is_symlink()) && after.is_none_or(|after| relative > after) {
+            found.push((relative.to_path_buf(), metadata));
+        }
+    }
+    Ok(())
+}
+
+// What tiering does with symlinks found on the branches, from the symlinks
+// config key
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SymlinkPolicy {
+    // links are not tracked and stay where they were created
+    #[default]
+    Skip,
+    // the link itself is tracked and moved as a link; its target is never followed
+    Link,
+    // links are not tracked on their own, but move along with the file they
+    // point to when it is on the same branch
+    WithTarget,
+}
+
+impl SymlinkPolicy {
+    pub fn from_config(config: &Value) -> Self {
+        match config.get("symlinks").and_then(Value::as_str) {
+            Some("link") => SymlinkPolicy::Link,
+            Some("with_target") => SymlinkPolicy::WithTarget,
+            _ => SymlinkPolicy::Skip,
+        }
+    }
+}
+
+// What happens to files marked immutable or append-only (chattr +i/+a),
+// which rsync cannot remove from the source, from the immutable_files
+// config key
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LockedFilePolicy {
+    // leave them out of scans and refuse to move them
+    #[default]
+    Skip,
+    // clear the flags for the move and set them again on the copy
+    Clear,
+}
+
+impl LockedFilePolicy {
+    pub fn from_config(config: &Value) -> Self {
+        match config.get("immutable_files").and_then(Value::as_str) {
+            Some("clear") => LockedFilePolicy::Clear,
+            _ => LockedFilePolicy::Skip,
+        }
+    }
+}
+
+pub struct BranchStorage {
+    // grows as drives are hotplugged
+    branches: RwLock<Vec<Branch>>,
+    dryrun: bool,
+    executor: Arc<dyn Executor>,
+    symlinks: SymlinkPolicy,
+    locked_files: LockedFilePolicy,
+    // for SymlinkPolicy::WithTarget, the links pointing at each file as of
+    // the last scan
+    links: Mutex<HashMap<PathBuf, Vec<PathBuf>>>,
+    // scratch directories whose files go to one particular branch
+    scratch_dirs: Vec<ScratchDir>,
+    // space the mover leaves free on each branch
+    reserve: ReservePolicy,
+    // move subvolumes between btrfs branches by send/receive
+    btrfs_send: bool,
+    // subtrees accounted for by XFS project quota
+    project_quotas: Option<ProjectQuotas>,
+    fill: FillPolicy,
+    mover: Mover,
+    bandwidth: Bandwidth,
+    checksums: Checksums,
+    // destinations chosen so far in each tier, for round-robin
+    turns: Mutex<HashMap<String, usize>>,
+    // moves writing to each branch right now, by serial
+    busy: Mutex<HashMap<String, usize>>,
+    // branches being drained or giving I/O errors, which take no moves
+    avoided: Mutex<HashSet<String>>,
+    // branches external jobs are working on, which take no moves meanwhile
+    held: Mutex<HashSet<String>>,
+    // branches whose drive went away, from the last scan
+    offline: Mutex<HashSet<String>>,
+    // the device each branch's root was on when last seen online
+    devices: Mutex<HashMap<String, u64>>,
+    // drives out for maintenance, which hotplug leaves alone
+    maintenance: Mutex<HashSet<String>>,
+    // branches a copy ran out of space on, with the room they showed after,
+    // which are passed over until they show more
+    ran_out: Mutex<HashMap<String, u64>>,
+    // branches walked at once by a scan
+    scan_threads: usize,
+    // adopted branches scans leave to their first crawl until it is done
+    crawling: Mutex<HashSet<String>>,
+}
+
+// A branch's files, and the links pointing at each
+type ScannedBranch = (Vec<ScannedFile>, HashMap<PathBuf, Vec<PathBuf>>);
+
+// Counts a move against its destination branch while it runs
+struct Busy<'a> {
+    busy: &'a Mutex<HashMap<String, usize>>,
+    serial: String,
+}
+
+impl<'a> Busy<'a> {
+    fn new(busy: &'a Mutex<HashMap<String, usize>>, serial: &str) -> Self {
+        *busy.lock().unwrap().entry(serial.to_string()).or_default() += 1;
+        Self { busy, serial: serial.to_string() }
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        if let Some(count) = self.busy.lock().unwrap().get_mut(&self.serial) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl BranchStorage {
+    pub fn new(branches: Vec<Branch>, dryrun: bool) -> Self {
+        Self::with_executor(branches, dryrun, Arc::new(SystemExecutor))
+    }
+
+    pub fn with_executor(branches: Vec<Branch>, dryrun: bool, executor: Arc<dyn Executor>) -> Self {
+        Self {
+            branches: RwLock::new(branches),
+            dryrun,
+            executor,
+            symlinks: SymlinkPolicy::default(),
+            locked_files: LockedFilePolicy::default(),
+            links: Mutex::new(HashMap::new()),
+            scratch_dirs: Vec::new(),
+            reserve: ReservePolicy::default(),
+            btrfs_send: false,
+            project_quotas: None,
+            fill: FillPolicy::default(),
+            mover: Mover::default(),
+            bandwidth: Bandwidth::default(),
+            checksums: Checksums::default(),
+            turns: Mutex::new(HashMap::new()),
+            busy: Mutex::new(HashMap::new()),
+            avoided: Mutex::new(HashSet::new()),
+            held: Mutex::new(HashSet::new()),
+            offline: Mutex::new(HashSet::new()),
+            devices: Mutex::new(HashMap::new()),
+            maintenance: Mutex::new(HashSet::new()),
+            ran_out: Mutex::new(HashMap::new()),
+            scan_threads: 1,
+            crawling: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn set_symlink_policy(&mut self, symlinks: SymlinkPolicy) {
+        self.symlinks = symlinks;
+    }
+
+    pub fn set_locked_file_policy(&mut self, locked_files: LockedFilePolicy) {
+        self.locked_files = locked_files;
+    }
+
+    // Each branch is its own drive, so walking several at once only
+    // competes for CPU and the inode cache, not for one disk's heads
+    pub fn set_scan_threads(&mut self, scan_threads: usize) {
+        self.scan_threads = scan_threads.max(1);
+    }
+
+    pub fn set_scratch_dirs(&mut self, scratch_dirs: Vec<ScratchDir>) {
+        self.scratch_dirs = scratch_dirs;
+    }
+
+    pub fn set_reserve(&mut self, reserve: ReservePolicy) {
+        self.reserve = reserve;
+    }
+
+    pub fn set_btrfs_send(&mut self, btrfs_send: bool) {
+        self.btrfs_send = btrfs_send;
+    }
+
+    pub fn set_fill_policy(&mut self, fill: FillPolicy) {
+        self.fill = fill;
+    }
+
+    pub fn set_mover(&mut self, mover: Mover) {
+        self.mover = mover;
+    }
+
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = bandwidth;
+    }
+
+    pub fn set_checksums(&mut self, checksums: Checksums) {
+        self.checksums = checksums;
+    }
+
+    pub fn set_project_quotas(&mut self, project_quotas: Option<ProjectQuotas>) {
+        self.project_quotas = project_quotas;
+    }
+
+    // Bytes that can go on `branch` without eating into its reserve
+    fn room(&self, branch: &Branch) -> io::Result<u64> {
+        let usage = disk_usage(&branch.path)?;
+        Ok(usage.free().saturating_sub(self.reserve.bytes(&branch.serial, usage.total)))
+    }
+
+    // Note a copy that failed for want of space on `branch`, so the retry
+    // picks another destination, as the room mergerfs and statfs report can
+    // run ahead of what the filesystem will really take
+    fn note_ran_out(&self, branch: &Branch, error: &io::Error) -> bool {
+        if retry::classify(error) != ErrorClass::NoSpace {
+            return false;
+        }
+        let room = self.room(branch).unwrap_or(0);
+        warn!("Branch {} ran out of space with {} bytes free, passing it over until it has more", branch.serial, room);
+        self.ran_out.lock().unwrap().insert(branch.serial.clone(), room);
+        true
+    }
+
+    // Copy `path` with `copy` to the branch of `target_tier` that
+    // destination_branch picks, and on to the next one whenever a copy runs
+    // out of space, so a full drive costs the move no retry. Stops once no
+    // other branch has room, or after trying every branch in the tier.
+    fn copy_to_tier(&self, path: &Path, source_branch: &Branch, target_tier: &str, size: u64, within_tier: bool, copy: &dyn Fn(&Branch) -> io::Result<()>) -> io::Result<Branch> {
+        let mut attempts = self.tier_branches(target_tier).len();
+        loop {
+            let dest_branch = self.destination_branch(path, target_tier, size, within_tier)?;
+            if dest_branch.path == source_branch.path {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, format!("no other branch in tier {} has room for {}", target_tier, path.display())));
+            }
+            let copied = {
+                let _busy = Busy::new(&self.busy, &dest_branch.serial);
+                copy(&dest_branch)
+            };
+            match copied {
+                Err(e) if self.note_ran_out(&dest_branch, &e) && attempts > 1 => {
+                    info!("Copying {} again to another branch of tier {}", path.display(), target_tier);
+                    attempts -= 1;
+                }
+                copied => return copied.map(|()| dest_branch),
+            }
+        }
+    }
+
+    fn online_branches(&self) -> Vec<Branch> {
+        let offline = self.offline.lock().unwrap();
+        self.branches().into_iter().filter(|branch| !offline.contains(&branch.serial)).collect()
+    }
+
+    // Fails when the branch's drive is no longer there: its root cannot be
+    // read, or it is back on the filesystem it was mounted over, as a
+    // drive that was pulled and unmounted leaves it
+    fn check_mounted(&self, branch: &Branch) -> io::Result<()> {
+        let dev = fs::metadata(&branch.path)?.dev();
+        let parent_dev = branch.path.parent().and_then(|parent| fs::metadata(parent).ok()).map(|metadata| metadata.dev());
+        let mut devices = self.devices.lock().unwrap();
+        if devices.get(&branch.serial).is_some_and(|known| *known != dev) && parent_dev == Some(dev) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is no longer mounted", branch.path.display())));
+        }
+        devices.insert(branch.serial.clone(), dev);
+        Ok(())
+    }
+
+    // Note whether a branch could be scanned, logging when it goes offline
+    // or comes back
+    fn note_scanned(&self, branch: &Branch, result: &io::Result<ScannedBranch>) {
+        let mut offline = self.offline.lock().unwrap();
+        match result {
+            Err(e) if offline.insert(branch.serial.clone()) => {
+                error!("Branch {} at {} is offline, carrying on without it: {}", branch.serial, branch.path.display(), e);
+            }
+            Ok(_) if offline.remove(&branch.serial) => info!("Branch {} at {} is back online", branch.serial, branch.path.display()),
+            _ => {}
+        }
+    }
+
+    fn find_branch(&self, serial: &str) -> io::Result<Branch> {
+        self.branches().into_iter().find(|branch| branch.serial == serial)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no branch {}", serial)))
+    }
+
+    // Remove the branch from its pools and unmount it once what was written
+    // to it is on disk
+    fn take_out_of_pools(&self, serial: &str) -> io::Result<Branch> {
+        let branch = self.find_branch(serial)?;
+        let commands: Vec<Vec<OsString>> = DriveManager::TIERS.iter()
+            .filter(|tier| tier_rank(tier) <= tier_rank(&branch.tier))
+            .map(|tier| Path::new(DriveManager::MERGERFS_MOUNT_PATH).join(tier).join(".mergerfs"))
+            .filter(|control| control.exists())
+            .map(|control| {
+                let mut removal = OsString::from("-");
+                removal.push(&branch.path);
+                vec!["setfattr".into(), "-n".into(), "user.mergerfs.branches".into(), "-v".into(), removal, control.into()]
+            })
+            .collect();
+        let unmount: Vec<OsString> = vec!["umount".into(), branch.path.clone().into()];
+        for command in commands.iter().chain([&unmount]) {
+            let command = as_args(command);
+            if self.dryrun {
+                info!("[DRY RUN] Would run {}", command_line(&command));
+                continue;
+            }
+            if command[0] == "umount" {
+                sync_filesystem(&branch.path)?;
+            }
+            info!("Running {}", command_line(&command));
+            checked(&command, self.executor.output(&command)?)?;
+        }
+        Ok(branch)
+    }
+
+    // Take a branch out of the pools and unmount it for maintenance. It
+    // stays a branch, offline, until resume_branch once it is mounted again.
+    pub fn suspend_branch(&self, serial: &str) -> io::Result<()> {
+        let branch = self.leave_pools(serial)?;
+        info!("Branch {} at {} is out for maintenance", serial, branch.path.display());
+        Ok(())
+    }
+
+    // Take a branch newly listed in exclude_drives out of the pools and
+    // unmount it, as a start would have left it. Its files are unavailable
+    // from then on, as they would be after a restart.
+    pub fn exclude_branch(&self, serial: &str) -> io::Result<()> {
+        let branch = self.leave_pools(serial)?;
+        info!("Branch {} at {} is excluded and out of the pools", serial, branch.path.display());
+        Ok(())
+    }
+
+    // Out of the pools and unmounted, and kept out, hotplug included, until
+    // resume_branch
+    fn leave_pools(&self, serial: &str) -> io::Result<Branch> {
+        self.avoided.lock().unwrap().insert(serial.to_string());
+        let branch = self.take_out_of_pools(serial)?;
+        self.maintenance.lock().unwrap().insert(serial.to_string());
+        self.offline.lock().unwrap().insert(serial.to_string());
+        Ok(branch)
+    }
+
+    // Put a branch back into use now it is mounted and in the pools again,
+    // rather than waiting for the next scan to find it
+    pub fn resume_branch(&self, serial: &str) -> io::Result<()> {
+        let branch = self.find_branch(serial)?;
+        self.check_mounted(&branch)?;
+        self.maintenance.lock().unwrap().remove(serial);
+        self.avoided.lock().unwrap().remove(serial);
+        if self.offline.lock().unwrap().remove(serial) {
+            info!("Branch {} at {} is back online", serial, branch.path.display());
+        }
+        Ok(())
+    }
+
+    // A drive left out at startup as it was out for maintenance
+    pub fn mark_in_maintenance(&self, serial: &str) {
+        self.maintenance.lock().unwrap().insert(serial.to_string());
+    }
+
+    pub fn in_maintenance(&self, serial: &str) -> bool {
+        self.maintenance.lock().unwrap().contains(serial)
+    }
+
+    // A drive attached while running, for moves and scans from now on
+    pub fn add_branch(&self, branch: Branch) {
+        self.branches.write().unwrap().push(branch);
+    }
+
+    pub fn branches(&self) -> Vec<Branch> {
+        self.branches.read().unwrap().clone()
+    }
+
+    fn tier_branches(&self, tier: &str) -> Vec<Branch> {
+        self.branches().into_iter().filter(|branch| branch.tier == tier).collect()
+    }
+
+    // The branch a scratch directory names, if `path` is in one and the branch
+    // is in the target tier, otherwise the one the tier's fill strategy
+    // picks, the most free space by default. A move within a tier is a
+    // rebalance and always goes to the most free space. Either way `size`
+    // bytes must fit above the branch's reserve.
+    fn destination_branch(&self, path: &Path, tier: &str, size: u64, within_tier: bool) -> io::Result<Branch> {
+        let full = |what: String| io::Error::new(io::ErrorKind::StorageFull, format!("{} has no room for {} ({} bytes) above its reserve", what, path.display(), size));
+        let pinned = scratch::find(&self.scratch_dirs, path).and_then(|dir| dir.branch.as_deref());
+        let avoided = self.avoided.lock().unwrap().clone();
+        let offline = self.offline.lock().unwrap().clone();
+        let mut branches: Vec<Branch> = self.tier_branches(tier).into_iter()
+            .filter(|branch| !avoided.contains(&branch.serial) && !offline.contains(&branch.serial))
+            .collect();
+        let held = self.held.lock().unwrap().clone();
+        if !branches.is_empty() && branches.iter().all(|branch| held.contains(&branch.serial)) {
+            return Err(held_error(tier));
+        }
+        branches.retain(|branch| !held.contains(&branch.serial));
+        if let Some(branch) = pinned.and_then(|serial| branches.iter().find(|branch| branch.serial == serial)) {
+            if self.room(branch)? < size {
+                return Err(full(format!("branch {}", branch.serial)));
+            }
+            return Ok(branch.clone());
+        }
+        let ran_out = self.ran_out.lock().unwrap().clone();
+        let rooms: Vec<(&Branch, u64)> = branches.iter()
+            .filter_map(|branch| self.room(branch).ok().map(|room| (branch, room)))
+            .filter(|(branch, room)| ran_out.get(&branch.serial).is_none_or(|then| room > then))
+            .collect();
+        if rooms.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no usable branch in tier {}", tier)));
+        }
+        let fits: Vec<(&Branch, u64)> = rooms.into_iter().filter(|(_, room)| *room >= size).collect();
+        let strategy = self.fill.for_tier(tier).filter(|_| !within_tier).unwrap_or(FillStrategy::MostFreeSpace);
+        let candidates: Vec<Candidate> = {
+            let busy = self.busy.lock().unwrap();
+            fits.iter().map(|(branch, room)| Candidate {
+                room: *room,
+                existing_depth: path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty())
+                    .find(|dir| branch.path.join(dir).is_dir())
+                    .map_or(0, |dir| dir.components().count()),
+                busy: busy.get(&branch.serial).copied().unwrap_or(0),
+            }).collect()
+        };
+        let mut turns = self.turns.lock().unwrap();
+        let turn = turns.entry(tier.to_string()).or_default();
+        let chosen = strategy.choose(&candidates, *turn).ok_or_else(|| full(format!("no branch in tier {}", tier)))?;
+        *turn += 1;
+        Ok(fits[chosen].0.clone())
+    }
+
+    // Move `src` to `dest` on `dest_branch` with the configured mover
+    fn transfer(&self, src: &Path, dest: &Path, dest_branch: &Path, progress: &dyn Fn(u64)) -> io::Result<()> {
+        match self.mover {
+            Mover::Rsync => self.rsync(src, dest, dest_branch, progress)
+                .map_err(|e| io::Error::new(e.kind(), format!("rsync of {} failed: {}", src.display(), e))),
+            Mover::Native if self.dryrun => {
+                info!("[DRY RUN] Would move {} to {}", src.display(), dest.display());
+                Ok(())
+            }
+            Mover::Native => self.limited(progress, |progress| mover::move_file(src, dest, &dest_branch.join(TEMP_DIR), &self.checksums, progress))
+                .map_err(|e| io::Error::new(e.kind(), format!("moving {} failed: {}", src.display(), e))),
+        }
+    }
+
+    // Move the links in `paths` from `branch` to `dest_branch` together
+    fn transfer_links(&self, branch: &Path, paths: &[PathBuf], dest_branch: &Path, progress: &dyn Fn(u64)) -> io::Result<()> {
+        let first = branch.join(&paths[0]);
+        match self.mover {
+            Mover::Rsync => self.rsync_links(branch, paths, dest_branch, progress)
+                .map_err(|e| io::Error::new(e.kind(), format!("rsync of the links to {} failed: {}", first.display(), e))),
+            Mover::Native if self.dryrun => {
+                info!("[DRY RUN] Would move the {} links to {} to {}", paths.len(), first.display(), dest_branch.display());
+                Ok(())
+            }
+            Mover::Native => self.limited(progress, |progress| mover::move_links(branch, paths, dest_branch, &dest_branch.join(TEMP_DIR), &self.checksums, progress))
+                .map_err(|e| io::Error::new(e.kind(), format!("moving the links to {} failed: {}", first.display(), e))),
+        }
+    }
+
+    // Run a native copy within the bandwidth limits and in the mover's I/O
+    // class. The copy reports its progress between chunks, which is where
+    // it waits when it gets ahead of the limits.
+    fn limited(&self, progress: &dyn Fn(u64), copy: impl FnOnce(&dyn Fn(u64)) -> io::Result<()>) -> io::Result<()> {
+        let transfer = self.bandwidth.start();
+        let progress = |bytes| {
+            transfer.copied(bytes);
+            progress(bytes);
+        };
+        bandwidth::with_io_class(self.bandwidth.ionice(), || copy(&progress))
+    }
+
+    pub fn rsync(&self, src: &Path, dest: &Path, dest_branch: &Path, progress: &dyn Fn(u64)) -> io::Result<()> {
+        self.run_rsync(&[], &[src], dest, dest_branch, progress)
+    }
+
+    // rsync only keeps hard links between files in the same run. With
+    // --relative each source is recreated at the path after its "/./".
+    fn rsync_links(&self, branch: &Path, paths: &[PathBuf], dest_branch: &Path, progress: &dyn Fn(u64)) -> io::Result<()> {
+        let sources: Vec<PathBuf> = paths.iter().map(|path| branch.join(".").join(path)).collect();
+        let sources: Vec<&Path> = sources.iter().map(PathBuf::as_path).collect();
+        self.run_rsync(&["--relative".as_ref()], &sources, dest_branch, dest_branch, progress)
+    }
+
+    // Fails with what rsync wrote to stderr when it exits unsuccessfully.
+    // Copies are written under TEMP_DIR on the destination branch and
+    // renamed into place, so whatever a crash leaves is in one spot. The
+    // bandwidth limits reach rsync as --bwlimit and the I/O class as ionice.
+    fn run_rsync(&self, extra_args: &[&OsStr], sources: &[&Path], dest: &Path, dest_branch: &Path, progress: &dyn Fn(u64)) -> io::Result<()> {
+        let temp_dir = dest_branch.join(TEMP_DIR);
+        let mut temp_dir_arg = OsString::from("--temp-dir=");
+        temp_dir_arg.push(&temp_dir);
+        let transfer = self.bandwidth.start();
+        let bwlimit = transfer.rsync_bwlimit().map(|kbps| OsString::from(format!("--bwlimit={}", kbps)));
+        let ionice = self.bandwidth.ionice().map(|class| class.command()).unwrap_or_default();
+        let mut rsync_command: Vec<&OsStr> = as_args(&ionice);
+        rsync_command.extend([
+            OsStr::new("rsync"),
+            "-axHAXWES".as_ref(),
+            "--info=progress2".as_ref(),
+            "--preallocate".as_ref(),
+            // each copy reaches the disk before its source is removed
+            "--fsync".as_ref(),
+            "--remove-source-files".as_ref(),
+            temp_dir_arg.as_os_str(),
+        ]);
+        rsync_command.extend(bwlimit.as_deref());
+        rsync_command.extend_from_slice(extra_args);
+        rsync_command.extend(sources.iter().map(|source| source.as_os_str()));
+        rsync_command.push(dest.as_os_str());
+        let display = command_line(&rsync_command);
+        if self.dryrun {
+            info!("[DRY RUN] Would run rsync command: {}", display);
+            return Ok(());
+        }
+        info!("Running rsync command: {}", display);
+        fs::create_dir_all(&temp_dir)?;
+        let mut on_line = |line: &str| {
+            if let Some(bytes) = rsync_progress(line) {
+                progress(bytes);
+            }
+        };
+        let output = checked(&rsync_command, self.executor.stream(&rsync_command, &mut on_line)?)?;
+        // warnings such as a vanished file come with a successful exit
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.trim().is_empty() {
+            warn!("rsync: {}", stderr.trim());
+        }
+        Ok(())
+    }
+
+    fn run_btrfs(&self, cmd: &[OsString]) -> io::Result<()> {
+        let cmd = as_args(cmd);
+        info!("Running {}", command_line(&cmd));
+        checked(&cmd, self.executor.output(&cmd)?)?;
+        Ok(())
+    }
+
+    // btrfs send piped into btrfs receive, failing if either side does
+    fn send_receive(&self, send: &[OsString], receive: &[OsString]) -> io::Result<()> {
+        let (send, receive) = (as_args(send), as_args(receive));
+        info!("Running {} | {}", command_line(&send), command_line(&receive));
+        let (sent, received) = self.executor.pipe(&send, &receive)?;
+        match (checked(&send, sent), checked(&receive, received)) {
+            (Err(send_error), Err(receive_error)) => Err(io::Error::other(format!("{}; {}", send_error, receive_error))),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // Fail unless a download holds what was uploaded, checksummed the way
+    // it was then
+    fn check_download(&self, url: &str, downloaded: &fs::File, len: u64, expected: &StubChecksum) -> io::Result<()> {
+        let algorithm = Algorithm::parse(&expected.algorithm)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("the stub of {} has an unknown checksum algorithm {}", url, expected.algorithm)))?;
+        let checksums = Checksums { segment_bytes: expected.segment_bytes, ..self.checksums.clone() };
+        let checksum = bandwidth::with_io_class(self.bandwidth.ionice(), || checksums.checksum(downloaded, len, algorithm))?;
+        if checksum != expected.value {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} came back with {} checksum {}, not {}", url, expected.algorithm, checksum, expected.value)));
+        }
+        Ok(())
+    }
+
+    fn run_aws(&self, cmd: &[OsString]) -> io::Result<()> {
+        let cmd = as_args(cmd);
+        info!("Running {}", command_line(&cmd));
+        checked(&cmd, self.executor.output(&cmd)?)?;
+        Ok(())
+    }
+
+    fn find_file(&self, path: &Path, tier: &str) -> io::Result<PathBuf> {
+        self.tier_branches(tier).iter()
+            .map(|branch| branch.path.join(path))
+            .find(|file| fs::symlink_metadata(file).is_ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", path.display(), tier)))
+    }
+
+    fn xfs_branches(&self) -> Vec<Branch> {
+        self.branches().into_iter().filter(|branch| project_quota::is_xfs(&branch.path).unwrap_or(false)).collect()
+    }
+
+    // The files on one branch, and for SymlinkPolicy::WithTarget the links
+    // pointing at each
+    fn scan_branch(&self, branch: &Branch) -> io::Result<ScannedBranch> {
+        self.check_mounted(branch)?;
+        let mut files = Vec::new();
+        let mut links: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        let branch_root = branch.path.canonicalize()?;
+        let skipped = [branch.path.join(btrfs::SNAPSHOT_DIR), branch.path.join(TEMP_DIR)];
+        walk_files(&branch.path, &mut |path, metadata| {
+            if skipped.iter().any(|dir| path.starts_with(dir)) {
+                return;
+            }
+            if metadata.is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Skip => return,
+                    SymlinkPolicy::Link => {}
+                    SymlinkPolicy::WithTarget => {
+                        if let Some(target) = path.canonicalize().ok().filter(|target| target.is_file() && target.starts_with(&branch_root)) {
+                            let target = branch.path.join(target.strip_prefix(&branch_root).unwrap());
+                            links.entry(target).or_default().push(path.to_path_buf());
+                        }
+                        return;
+                    }
+                }
+            }
+            files.extend(self.scanned_file(branch, path.strip_prefix(&branch.path).unwrap(), metadata));
+        })?;
+        Ok((files, links))
+    }
+
+    // A file found on `branch` at `relative_path`, unless it is one to skip
+    fn scanned_file(&self, branch: &Branch, relative_path: &Path, metadata: &fs::Metadata) -> Option<ScannedFile> {
+        let path = branch.path.join(relative_path);
+        if metadata.is_file() && self.locked_files == LockedFilePolicy::Skip && file_flags(&path).is_ok_and(|flags| flags & LOCKED_FLAGS != 0) {
+            debug!("Skipping immutable or append-only {}", path.display());
+            return None;
+        }
+        // user xattrs cannot be set on a link itself
+        let placement = if metadata.is_symlink() {
+            Placement::default()
+        } else {
+            Placement::read(&path).unwrap_or_else(|e| {
+                warn!("Could not read placement tags on {}: {}", path.display(), e);
+                Placement::default()
+            })
+        };
+        Some(ScannedFile {
+            path: relative_path.to_path_buf(),
+            tier: branch.tier.clone(),
+            branch: branch.serial.clone(),
+            accessed: metadata.accessed().unwrap(),
+            modified: metadata.modified().unwrap(),
+            size: metadata.len(),
+            placement,
+            hardlink: (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino())),
+        })
+    }
+
+    // Leave the branch out of scans while its first crawl runs, or put it
+    // back once the crawl is done
+    pub fn set_crawling(&self, serial: &str, crawling: bool) {
+        let mut branches = self.crawling.lock().unwrap();
+        if crawling {
+            branches.insert(serial.to_string());
+        } else {
+            branches.remove(serial);
+        }
+    }
+
+    // The next `limit` files on a branch after `after`, for its first crawl,
+    // with the last path walked, which is where the next batch starts. Links
+    // are only crawled when tracked as links; with_target links are picked
+    // up by the scans once the crawl is done. None once there is nothing
+    // left to walk.
+    pub fn crawl(&self, serial: &str, after: Option<&Path>, limit: usize) -> io::Result<Option<(Vec<ScannedFile>, PathBuf)>> {
+        let branch = self.find_branch(serial)?;
+        self.check_mounted(&branch)?;
+        let skipped = [branch.path.join(btrfs::SNAPSHOT_DIR), branch.path.join(TEMP_DIR)];
+        let walked = bandwidth::with_io_class(self.bandwidth.ionice(), || walk_files_after(&branch.path, after, limit.max(1), &skipped))?;
+        let Some((last, _)) = walked.last() else { return Ok(None) };
+        let last = last.clone();
+        let files = walked.iter()
+            .filter(|(_, metadata)| !metadata.is_symlink() || self.symlinks == SymlinkPolicy::Link)
+            .filter_map(|(path, metadata)| self.scanned_file(&branch, path, metadata))
+            .collect();
+        Ok(Some((files, last)))
+    }
+
+    // The checksum of the copy of `path` on a branch, read in the mover's
+    // I/O class. None for a link, which has no contents of its own.
+    pub fn checksum_file(&self, serial: &str, path: &Path, algorithm: Algorithm) -> io::Result<Option<String>> {
+        let path = self.find_branch(serial)?.path.join(path);
+        if fs::symlink_metadata(&path)?.is_symlink() {
+            return Ok(None);
+        }
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        bandwidth::with_io_class(self.bandwidth.ionice(), || self.checksums.checksum(&file, len, algorithm)).map(Some)
+    }
+
+    // Every branch scanned, in order, scan_threads at a time. A branch that
+    // fails to scan is left out as offline rather than failing the scan, so
+    // the pools carry on with the drives that are there.
+    fn scan_branches(&self) -> io::Result<Vec<ScannedBranch>> {
+        let crawling = self.crawling.lock().unwrap().clone();
+        let branches: Vec<Branch> = self.branches().into_iter().filter(|branch| !crawling.contains(&branch.serial)).collect();
+        let workers = self.scan_threads.min(branches.len());
+        let results: Vec<io::Result<ScannedBranch>> = if workers <= 1 {
+            branches.iter().map(|branch| self.scan_branch(branch)).collect()
+        } else {
+            self.scan_in_parallel(&branches, workers)
+        };
+        Ok(branches.iter().zip(results).filter_map(|(branch, result)| {
+            self.note_scanned(branch, &result);
+            result.ok()
+        }).collect())
+    }
+
+    fn scan_in_parallel(&self, branches: &[Branch], workers: usize) -> Vec<io::Result<ScannedBranch>> {
+        let next = AtomicUsize::new(0);
+        let scanned: Mutex<Vec<Option<io::Result<ScannedBranch>>>> = Mutex::new(branches.iter().map(|_| None).collect());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(branch) = branches.get(index) else { break };
+                    let result = self.scan_branch(branch);
+                    scanned.lock().unwrap()[index] = Some(result);
+                });
+            }
+        });
+        scanned.into_inner().unwrap().into_iter().map(|result| result.unwrap()).collect()
+    }
+
+    // Give the quota subtrees on every xfs branch their projects
+    pub fn assign_projects(&self) {
+        let Some(quotas) = &self.project_quotas else { return };
+        for branch in self.xfs_branches() {
+            if self.dryrun {
+                info!("[DRY RUN] Would set project quota IDs on branch {}", branch.serial);
+                continue;
+            }
+            match quotas.assign(&branch.path) {
+                Ok(0) => {}
+                Ok(changed) => info!("Set the project of {} files and directories on branch {}", changed, branch.serial),
+                Err(e) => warn!("Failed to set project quota IDs on branch {}: {}", branch.serial, e),
+            }
+        }
+    }
+
+    // What each quota subtree takes on each xfs branch, from the branch's
+    // project quota report
+    pub fn subtree_usage(&self) -> io::Result<Vec<SubtreeUsage>> {
+        let Some(quotas) = &self.project_quotas else { return Ok(Vec::new()) };
+        let mut usage = Vec::new();
+        for branch in self.xfs_branches() {
+            let cmd = project_quota::report_command(&branch.path);
+            let cmd = as_args(&cmd);
+            let output = checked(&cmd, self.executor.output(&cmd)?)
+                .map_err(|e| io::Error::new(e.kind(), format!("project quota report of branch {}: {}", branch.serial, e)))?;
+            let report = project_quota::parse_report(&String::from_utf8_lossy(&output.stdout));
+            usage.extend(quotas.subtrees.iter().map(|(subtree, id)| SubtreeUsage {
+                subtree: subtree.clone(),
+                serial: branch.serial.clone(),
+                tier: branch.tier.clone(),
+                bytes: report.get(id).copied().unwrap_or(0),
+            }));
+        }
+        Ok(usage)
+    }
+
+    // Snapshot `src` and send it to `received_dir`, against the newest
+    // snapshot that made it there before if any. A receive that was cut
+    // off is cleared away first. Returns the snapshot's number.
+    fn send_snapshot(&self, src: &Path, sent_dir: &Path, received_dir: &Path) -> io::Result<u64> {
+        let partial: Vec<PathBuf> = btrfs::snapshots(received_dir).into_iter()
+            .map(|number| received_dir.join(number.to_string()))
+            .filter(|snapshot| !btrfs::is_read_only(snapshot).unwrap_or(false))
+            .collect();
+        if !partial.is_empty() {
+            self.run_btrfs(&btrfs::delete_command(&partial))?;
+        }
+        let sent = btrfs::snapshots(sent_dir);
+        let parent = btrfs::common_parent(&sent, &btrfs::snapshots(received_dir)).map(|number| sent_dir.join(number.to_string()));
+        let number = sent.last().map_or(1, |last| last + 1);
+        let snapshot = sent_dir.join(number.to_string());
+        self.run_btrfs(&btrfs::snapshot_command(src, &snapshot, true))?;
+        self.send_receive(&btrfs::send_command(&snapshot, parent.as_deref()), &btrfs::receive_command(received_dir))?;
+        Ok(number)
+    }
+
+    // Recreate the links to a moved file at the same relative paths on the
+    // destination branch. Absolute links into the source branch are pointed
+    // at the destination instead.
+    fn move_links(&self, src: &Path, source_branch: &Path, dest_branch: &Path) -> io::Result<()> {
+        let links = self.links.lock().unwrap().remove(src).unwrap_or_default();
+        for link in links {
+            let Ok(target) = fs::read_link(&link) else { continue };
+            let target = match target.strip_prefix(source_branch) {
+                Ok(relative) => dest_branch.join(relative),
+                Err(_) => target,
+            };
+            let dest = dest_branch.join(link.strip_prefix(source_branch).unwrap());
+            if self.dryrun {
+                info!("[DRY RUN] Would move link {} to {}", link.display(), dest.display());
+                continue;
+            }
+            info!("Moving link {} to {}", link.display(), dest.display());
+            fs::create_dir_all(dest.parent().unwrap())?;
+            symlink(&target, &dest)?;
+            fs::remove_file(&link)?;
+        }
+        Ok(())
+    }
+}
+
+// rsync keeps mtime, but only to the precision both ends agree on, and not
+// atime. Backup tools treat any mtime drift as a changed file, so put both
+// back exactly and check they stuck.
+fn restore_timestamps(dest: &Path, times: &Timestamps) {
+    if let Err(e) = set_timestamps(dest, times) {
+        error!("Failed to restore timestamps of {}: {}", dest.display(), e);
+        return;
+    }
+    match timestamps(dest) {
+        Ok(moved) if moved == *times => {}
+        Ok(moved) => error!("Timestamps of {} changed in the move: {:?} became {:?}", dest.display(), times, moved),
+        Err(e) => error!("Failed to verify timestamps of {}: {}", dest.display(), e),
+    }
+}
+
+impl Storage for BranchStorage {
+    fn scan(&self) -> io::Result<Vec<ScannedFile>> {
+        let mut files = Vec::new();
+        let mut links = HashMap::new();
+        for (branch_files, branch_links) in self.scan_branches()? {
+            files.extend(branch_files);
+            links.extend(branch_links);
+        }
+        *self.links.lock().unwrap() = links;
+        Ok(files)
+    }
+
+    fn tier_usage(&self, tier: &str) -> io::Result<TierUsage> {
+        let mut usage = TierUsage::default();
+        for branch in self.online_branches().into_iter().filter(|branch| branch.tier == tier) {
+            let branch_usage = disk_usage(&branch.path)?;
+            usage.total += branch_usage.total;
+            usage.used += branch_usage.used;
+        }
+        Ok(usage)
+    }
+
+    fn branch_usage(&self) -> io::Result<Vec<BranchUsage>> {
+        self.online_branches().into_iter().map(|branch| Ok(BranchUsage {
+            serial: branch.serial.clone(),
+            tier: branch.tier.clone(),
+            usage: disk_usage(&branch.path)?,
+        })).collect()
+    }
+
+    fn branch_of(&self, path: &Path, tier: &str) -> Option<String> {
+        self.tier_branches(tier).into_iter().find(|branch| fs::symlink_metadata(branch.path.join(path)).is_ok()).map(|branch| branch.serial)
+    }
+
+    fn file_size(&self, path: &Path, tier: &str) -> Option<u64> {
+        self.tier_branches(tier).iter().find_map(|branch| fs::symlink_metadata(branch.path.join(path)).ok()).map(|metadata| metadata.len())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.branches().iter().any(|branch| branch.path.join(path).exists())
+    }
+
+    fn open_mode(&self, path: &Path, tier: &str) -> io::Result<Option<OpenMode>> {
+        let files: Vec<PathBuf> = self.tier_branches(tier).iter().map(|branch| branch.path.join(path)).collect();
+        open_files::open_mode(Path::new("/proc"), &files)
+    }
+
+    fn readers(&self) -> io::Result<Vec<OpenReader>> {
+        // fd links point at canonical paths
+        let roots: Vec<PathBuf> = self.branches().iter().filter_map(|branch| branch.path.canonicalize().ok()).collect();
+        let readers = open_files::readers(Path::new("/proc"), &roots)?;
+        Ok(readers.into_iter().filter_map(|reader| {
+            let root = roots.iter().find(|root| reader.path.starts_with(root))?;
+            Some(OpenReader { path: reader.path.strip_prefix(root).ok()?.to_path_buf(), ..reader })
+        }).collect())
+    }
+
+    fn move_file(&self, path: &Path, source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+        let source_branch = self.tier_branches(source_tier).into_iter()
+            .find(|branch| fs::symlink_metadata(branch.path.join(path)).is_ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", path.display(), source_tier)))?;
+        let src = source_branch.path.join(path);
+        // taken before the copy reads the file and bumps its atime
+        let times = timestamps(&src)?;
+        let locked = if fs::symlink_metadata(&src)?.is_file() { file_flags(&src)? & LOCKED_FLAGS } else { 0 };
+        if locked != 0 {
+            if self.locked_files == LockedFilePolicy::Skip {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is immutable or append-only", src.display())));
+            }
+            if !self.dryrun {
+                set_file_flags(&src, file_flags(&src)? & !LOCKED_FLAGS)?;
+            }
+        }
+        let copy = |dest_branch: &Branch| {
+            let dest = dest_branch.path.join(path);
+            if !self.dryrun {
+                fs::create_dir_all(dest.parent().unwrap())?;
+            }
+            self.transfer(&src, &dest, &dest_branch.path, progress)
+        };
+        let moved = self.copy_to_tier(path, &source_branch, target_tier, fs::symlink_metadata(&src)?.len(), source_tier == target_tier, &copy);
+        let dest = moved.as_ref().ok().map(|dest_branch| dest_branch.path.join(path));
+        if let Some(dest) = dest.as_ref().filter(|_| !self.dryrun) {
+            // before the flags go back on, utimensat fails on an immutable file
+            restore_timestamps(dest, &times);
+        }
+        if locked != 0 && !self.dryrun {
+            // back on whichever copy is left
+            let target = dest.as_ref().unwrap_or(&src);
+            if let Err(e) = file_flags(target).and_then(|flags| set_file_flags(target, flags | locked)) {
+                error!("Failed to restore immutable/append-only flags on {}: {}", target.display(), e);
+            }
+        }
+        let dest_branch = moved?;
+        if self.symlinks == SymlinkPolicy::WithTarget {
+            self.move_links(&src, &source_branch.path, &dest_branch.path)?;
+        }
+        Ok(())
+    }
+
+    fn move_link_group(&self, paths: &[PathBuf], source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+        let first = paths.first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty link group"))?;
+        let source_branch = self.tier_branches(source_tier).into_iter()
+            .find(|branch| paths.iter().all(|path| branch.path.join(path).is_file()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("links to {} not found together in tier {}", first.display(), source_tier)))?;
+        let src = source_branch.path.join(first);
+        if file_flags(&src)? & LOCKED_FLAGS != 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is immutable or append-only", src.display())));
+        }
+        // the links share one inode, so one set of timestamps
+        let times = timestamps(&src)?;
+        let copy = |dest_branch: &Branch| self.transfer_links(&source_branch.path, paths, &dest_branch.path, progress);
+        let dest_branch = self.copy_to_tier(first, &source_branch, target_tier, fs::metadata(&src)?.len(), source_tier == target_tier, &copy)?;
+        if !self.dryrun {
+            restore_timestamps(&dest_branch.path.join(first), &times);
+        }
+        Ok(())
+    }
+
+    fn remove_temp_files(&self, interrupted: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+        let mut temp_files = Vec::new();
+        for branch in self.branches() {
+            // nothing is copying yet, so everything here was left behind
+            if let Ok(entries) = fs::read_dir(branch.path.join(TEMP_DIR)) {
+                temp_files.extend(entries.filter_map(|entry| Some(entry.ok()?.path())));
+            }
+            // copies from before TEMP_DIR were written beside their destination
+            for path in interrupted {
+                let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else { continue };
+                let Ok(entries) = fs::read_dir(branch.path.join(dir)) else { continue };
+                temp_files.extend(entries.filter_map(Result::ok).filter(|entry| is_rsync_temp(&entry.file_name(), name)).map(|entry| entry.path()));
+            }
+        }
+        if self.dryrun {
+            for file in &temp_files {
+                info!("[DRY RUN] Would remove temp file {}", file.display());
+            }
+            return Ok(Vec::new());
+        }
+        for file in &temp_files {
+            if fs::symlink_metadata(file)?.is_dir() {
+                fs::remove_dir_all(file)?;
+            } else {
+                fs::remove_file(file)?;
+            }
+        }
+        Ok(temp_files)
+    }
+
+    // Copies are renamed into place whole, so one beside a source still
+    // there was cut off before the source was removed. The source goes, as
+    // the move would have, when the copy has its size and, to the
+    // nanosecond, its mtime, which the mover sets before the rename.
+    // Otherwise either one may hold data the other lacks, so both stay and
+    // the move is dropped. The native mover syncs a copy before renaming
+    // it; one rsync wrote may have been lost to a power cut with its size
+    // and mtime intact, so it must also match the source's checksum or the
+    // move is done again.
+    fn settle_interrupted_move(&self, path: &Path, source_tier: &str, target_tier: &str) -> io::Result<bool> {
+        let Ok(src) = self.find_file(path, source_tier) else { return Ok(false) };
+        let Some(copy) = self.tier_branches(target_tier).iter()
+            .map(|branch| branch.path.join(path))
+            .find(|copy| *copy != src && fs::symlink_metadata(copy).is_ok()) else { return Ok(true) };
+        let (src_metadata, copy_metadata) = (fs::symlink_metadata(&src)?, fs::symlink_metadata(&copy)?);
+        if copy_metadata.len() != src_metadata.len() || copy_metadata.modified()? != src_metadata.modified()? {
+            warn!("Leaving {} and {} after an interrupted move, they differ", src.display(), copy.display());
+            return Ok(false);
+        }
+        if self.mover == Mover::Rsync {
+            let algorithm = self.checksums.verify.unwrap_or(Algorithm::Xxh3);
+            let verified = bandwidth::with_io_class(self.bandwidth.ionice(), || {
+                self.checksums.verify(&fs::File::open(&src)?, &fs::File::open(&copy)?, src_metadata.len(), algorithm)
+            });
+            if let Err(e) = verified {
+                warn!("Moving {} again after an interrupted move, its copy {} cannot be trusted: {}", src.display(), copy.display(), e);
+                return Ok(true);
+            }
+        }
+        if self.dryrun {
+            info!("[DRY RUN] Would remove {} left by an interrupted move", src.display());
+            return Ok(false);
+        }
+        info!("Removing {} left by an interrupted move", src.display());
+        fs::remove_file(&src)?;
+        Ok(false)
+    }
+
+    // A btrfs subvolume goes over by send/receive when both branches are
+    // btrfs. The first send carries the bulk while the subvolume stays in
+    // use, a second only what changed meanwhile, just before the copy takes
+    // its place. The snapshots stay until the move is done, so a failed move
+    // picks up from the last one received.
+    fn move_directory(&self, dir: &Path, source_tier: &str, target_tier: &str) -> io::Result<()> {
+        let unsupported = |why: String| Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} cannot be sent as a whole: {}", dir.display(), why)));
+        if !self.btrfs_send {
+            return unsupported("btrfs_send is off".to_string());
+        }
+        let source_branch = self.tier_branches(source_tier).into_iter()
+            .find(|branch| branch.path.join(dir).is_dir())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in tier {}", dir.display(), source_tier)))?;
+        let src = source_branch.path.join(dir);
+        if !btrfs::is_subvolume(&src)? {
+            return unsupported("not a btrfs subvolume".to_string());
+        }
+        let mut size = 0;
+        walk_files(&src, &mut |_, metadata| size += metadata.len())?;
+        let dest_branch = self.destination_branch(dir, target_tier, size, source_tier == target_tier)?;
+        if dest_branch.path == source_branch.path {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, format!("no other branch in tier {} has room for {}", target_tier, dir.display())));
+        }
+        if !btrfs::is_btrfs(&dest_branch.path)? {
+            return unsupported(format!("branch {} is not btrfs", dest_branch.serial));
+        }
+        let dest = dest_branch.path.join(dir);
+        if fs::symlink_metadata(&dest).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", dest.display())));
+        }
+        if self.dryrun {
+            info!("[DRY RUN] Would send btrfs subvolume {} to {}", src.display(), dest.display());
+            return Ok(());
+        }
+        let key = btrfs::snapshot_key(dir);
+        let sent_dir = source_branch.path.join(btrfs::SNAPSHOT_DIR).join(&key);
+        let received_dir = dest_branch.path.join(btrfs::SNAPSHOT_DIR).join(&key);
+        fs::create_dir_all(&sent_dir)?;
+        fs::create_dir_all(&received_dir)?;
+        self.send_snapshot(&src, &sent_dir, &received_dir)?;
+        let last = self.send_snapshot(&src, &sent_dir, &received_dir)?;
+        fs::create_dir_all(dest.parent().unwrap())?;
+        self.run_btrfs(&btrfs::snapshot_command(&received_dir.join(last.to_string()), &dest, false))?;
+        self.run_btrfs(&btrfs::delete_command(&[src]))?;
+        for snapshot_dir in [&sent_dir, &received_dir] {
+            let snapshots: Vec<PathBuf> = btrfs::snapshots(snapshot_dir).into_iter().map(|number| snapshot_dir.join(number.to_string())).collect();
+            if let Err(e) = self.run_btrfs(&btrfs::delete_command(&snapshots)).and_then(|()| fs::remove_dir(snapshot_dir)) {
+                warn!("Failed to clean up the snapshots in {}: {}", snapshot_dir.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn avoid_branch(&self, serial: &str) {
+        self.avoided.lock().unwrap().insert(serial.to_string());
+    }
+
+    fn hold_branches(&self, serials: &HashSet<String>) {
+        *self.held.lock().unwrap() = serials.clone();
+    }
+
+    fn offline_branches(&self) -> HashSet<String> {
+        self.offline.lock().unwrap().clone()
+    }
+
+    fn crawling_branches(&self) -> HashSet<String> {
+        self.crawling.lock().unwrap().clone()
+    }
+
+    // The branch leaves the pool of its tier and those of the tiers above,
+    // through mergerfs's control file, before it is unmounted
+    fn detach_branch(&self, serial: &str) -> io::Result<()> {
+        self.take_out_of_pools(serial)?;
+        self.branches.write().unwrap().retain(|branch| branch.serial != serial);
+        self.avoided.lock().unwrap().remove(serial);
+        Ok(())
+    }
+
+    fn delete_file(&self, path: &Path, tier: &str) -> io::Result<()> {
+        let file = self.find_file(path, tier)?;
+        if self.dryrun {
+            info!("[DRY RUN] Would delete {}", file.display());
+            return Ok(());
+        }
+        info!("Deleting {}", file.display());
+        fs::remove_file(file)
+    }
+
+    // The stub is renamed over the file only once the upload succeeded, and
+    // keeps its owner, mode and timestamps, so a read shows in its atime
+    fn archive_file(&self, path: &Path, tier: &str, archive: &Archive, record: &dyn Fn(&str) -> io::Result<()>) -> io::Result<String> {
+        let file = self.find_file(path, tier)?;
+        // taken before anything reads the file and bumps its atime
+        let times = timestamps(&file)?;
+        let metadata = fs::symlink_metadata(&file)?;
+        if !metadata.is_file() || metadata.nlink() > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file with a single link", file.display())));
+        }
+        if archive::read_stub(&file)?.is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is already archived", file.display())));
+        }
+        let url = archive.url(path)?;
+        if self.dryrun {
+            info!("[DRY RUN] Would archive {} to {}", file.display(), url);
+            return Ok(url);
+        }
+        let algorithm = self.checksums.verify.unwrap_or(Algorithm::Xxh3);
+        let checksum = bandwidth::with_io_class(self.bandwidth.ionice(), || self.checksums.checksum(&fs::File::open(&file)?, metadata.len(), algorithm))?;
+        self.run_aws(&archive.upload_command(&file, &url))?;
+        // a file written to while it was checksummed or uploaded is left for
+        // the next check
+        let after = fs::symlink_metadata(&file)?;
+        if (after.len(), after.mtime(), after.mtime_nsec(), after.ino()) != (metadata.len(), metadata.mtime(), metadata.mtime_nsec(), metadata.ino()) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, format!("{} changed while being uploaded", file.display())));
+        }
+        let staged = archive::staging_path(&file);
+        let checksum = StubChecksum { algorithm: algorithm.name().to_string(), segment_bytes: self.checksums.segment_bytes, value: checksum };
+        let stub = Stub { url: url.clone(), size: metadata.len(), checksum: Some(checksum) };
+        let result = archive::write_stub(&staged, &stub)
+            .and_then(|()| archive::copy_ownership(&metadata, &staged))
+            .and_then(|()| record(&url))
+            .and_then(|()| fs::rename(&staged, &file));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+        restore_timestamps(&file, &times);
+        // the stub is in and recorded, so this only warns
+        if let Err(e) = mover::sync_parent(&file) {
+            warn!("Failed to sync the directory of {}: {}", file.display(), e);
+        }
+        Ok(url)
+    }
+
+    fn restore_file(&self, path: &Path, tier: &str, archive: &Archive) -> io::Result<()> {
+        let file = self.find_file(path, tier)?;
+        let times = timestamps(&file)?;
+        let stub = archive::read_stub(&file)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not an archive stub", file.display())))?;
+        if self.dryrun {
+            info!("[DRY RUN] Would restore {} from {}", file.display(), stub.url);
+            return Ok(());
+        }
+        let metadata = fs::symlink_metadata(&file)?;
+        let staged = archive::staging_path(&file);
+        let result = self.run_aws(&archive.download_command(&stub.url, &staged)).and_then(|()| {
+            let size = fs::symlink_metadata(&staged)?.len();
+            if size != stub.size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} came back as {} bytes, not {}", stub.url, size, stub.size)));
+            }
+            let downloaded = fs::File::open(&staged)?;
+            if let Some(expected) = &stub.checksum {
+                self.check_download(&stub.url, &downloaded, size, expected)?;
+            }
+            downloaded.sync_all()?;
+            archive::copy_ownership(&metadata, &staged)?;
+            fs::rename(&staged, &file)
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+        restore_timestamps(&file, &times);
+        // the file is back whole, so this only warns
+        if let Err(e) = mover::sync_parent(&file) {
+            warn!("Failed to sync the directory of {}: {}", file.display(), e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, FileTimes};
+    use std::time::Duration;
+    use std::io::Write;
+    use std::ffi::OsStr;
+    use tempfile::tempdir;
+    use crate::fault_injection::{Fault, FaultInjector, FaultPoint, FaultyExecutor};
+
+    #[test]
+    fn test_scan() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        fs::create_dir_all(hot.path().join("movies")).unwrap();
+        writeln!(File::create(hot.path().join("movies/a.mkv")).unwrap(), "test data").unwrap();
+        writeln!(File::create(cold.path().join("b.iso")).unwrap(), "test data").unwrap();
+        let mut storage = BranchStorage::new(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], true);
+        let sequential = storage.scan().unwrap();
+        storage.set_scan_threads(4);
+        let mut files = storage.scan().unwrap();
+        assert_eq!(files, sequential);
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files.len(), 2);
+        assert_eq!((files[0].path.to_str().unwrap(), files[0].tier.as_str()), ("b.iso", "cold"));
+        assert_eq!((files[1].path.to_str().unwrap(), files[1].tier.as_str(), files[1].size), ("movies/a.mkv", "hot", 10));
+        assert!(storage.exists(Path::new("b.iso")));
+        assert!(storage.tier_usage("hot").unwrap().total > 0);
+        assert_eq!(storage.tier_usage("warm").unwrap(), TierUsage::default());
+
+        // a drive hotplugged into warm
+        let warm = tempdir().unwrap();
+        writeln!(File::create(warm.path().join("c.txt")).unwrap(), "test data").unwrap();
+        storage.add_branch(Branch { serial: "s1".to_string(), tier: "warm".to_string(), path: warm.path().to_path_buf() });
+        assert_eq!(storage.file_size(Path::new("c.txt"), "warm"), Some(10));
+        assert_eq!(storage.scan().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_walk_files_skips_vanished_entries() {
+        let dir = tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(sub.join(name), b"abc").unwrap();
+        }
+        // the first file seen deletes the others before they are looked at
+        let mut visited = Vec::new();
+        walk_files(dir.path(), &mut |path, _| {
+            if visited.is_empty() {
+                for other in ["a", "b", "c"].map(|name| sub.join(name)).into_iter().filter(|other| other != path) {
+                    fs::remove_file(other).unwrap();
+                }
+            }
+            visited.push(path.to_path_buf());
+        }).unwrap();
+        assert_eq!(visited.len(), 1);
+        assert_eq!(walk_files(&dir.path().join("gone"), &mut |_, _| {}).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_offline_branch() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        writeln!(File::create(cold.path().join("b.iso")).unwrap(), "test data").unwrap();
+        let storage = BranchStorage::new(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().join("gone") },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], true);
+        assert_eq!(storage.scan().unwrap().len(), 1);
+        assert_eq!(storage.offline_branches(), HashSet::from(["n1".to_string()]));
+        assert_eq!(storage.branch_usage().unwrap().len(), 1);
+        assert!(storage.destination_branch(Path::new("a.mkv"), "hot", 10, false).is_err());
+        // the drive is back
+        fs::create_dir(hot.path().join("gone")).unwrap();
+        storage.scan().unwrap();
+        assert!(storage.offline_branches().is_empty());
+    }
+
+    #[test]
+    fn test_suspend_branch() {
+        let hot = [tempdir().unwrap(), tempdir().unwrap()];
+        let storage = BranchStorage::new(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot[0].path().to_path_buf() },
+            Branch { serial: "n2".to_string(), tier: "hot".to_string(), path: hot[1].path().to_path_buf() },
+        ], true);
+        storage.suspend_branch("n1").unwrap();
+        assert!(storage.in_maintenance("n1"));
+        assert_eq!(storage.offline_branches(), HashSet::from(["n1".to_string()]));
+        assert_eq!(storage.destination_branch(Path::new("a.mkv"), "hot", 10, false).unwrap().serial, "n2");
+        assert_eq!(storage.branches().len(), 2);
+        storage.resume_branch("n1").unwrap();
+        assert!(storage.offline_branches().is_empty());
+        assert!(!storage.in_maintenance("n1"));
+        assert!(storage.resume_branch("n3").is_err());
+    }
+
+    #[test]
+    fn test_reserve() {
+        let hot = tempdir().unwrap();
+        let cold = [tempdir().unwrap(), tempdir().unwrap()];
+        writeln!(File::create(hot.path().join("a.mkv")).unwrap(), "test data").unwrap();
+        let mut storage = BranchStorage::new(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold[0].path().to_path_buf() },
+            Branch { serial: "h2".to_string(), tier: "cold".to_string(), path: cold[1].path().to_path_buf() },
+        ], true);
+        // h1 is all reserve, so the file goes to h2
+        storage.set_reserve(ReservePolicy::from_config(&serde_json::json!({ "reserve": { "h1": "100%" } })));
+        assert_eq!(storage.destination_branch(Path::new("a.mkv"), "cold", 10, false).unwrap().serial, "h2");
+        storage.set_reserve(ReservePolicy::from_config(&serde_json::json!({ "reserve": "100%" })));
+        let e = storage.move_file(Path::new("a.mkv"), "hot", "cold", &|_| {}).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+        assert!(hot.path().join("a.mkv").exists());
+    }
+
+    #[test]
+    fn test_fill_strategy() {
+        let cold = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        fs::create_dir_all(cold[1].path().join("tv/show")).unwrap();
+        let mut storage = BranchStorage::new(cold.iter().enumerate().map(|(index, dir)| {
+            Branch { serial: format!("h{}", index), tier: "cold".to_string(), path: dir.path().to_path_buf() }
+        }).collect(), true);
+        let chosen = |storage: &BranchStorage, path: &str, within_tier| storage.destination_branch(Path::new(path), "cold", 10, within_tier).unwrap().serial.clone();
+        storage.set_fill_policy(FillPolicy::from_config(&serde_json::json!({ "fill_strategy": "round-robin" })));
+        assert_eq!([0, 1, 2, 3].map(|_| chosen(&storage, "a.mkv", false)), ["h0", "h1", "h2", "h0"]);
+        storage.set_fill_policy(FillPolicy::from_config(&serde_json::json!({ "fill_strategy": { "cold": "existing-path" } })));
+        assert_eq!(chosen(&storage, "tv/show/e01.mkv", false), "h1");
+        storage.set_fill_policy(FillPolicy::from_config(&serde_json::json!({ "fill_strategy": "least-used-spindle" })));
+        let _busy = Busy::new(&storage.busy, "h1");
+        assert_ne!(chosen(&storage, "tv/show/e01.mkv", false), "h1");
+    }
+
+    #[test]
+    fn test_remove_temp_files() {
+        let cold = tempdir().unwrap();
+        fs::create_dir_all(cold.path().join(TEMP_DIR)).unwrap();
+        fs::create_dir_all(cold.path().join("tv")).unwrap();
+        for file in [".e01.mkv.Ab3dE9", ".e02.mkv.Ab3dE9", "e01.mkv"] {
+            fs::write(cold.path().join("tv").join(file), "partial").unwrap();
+        }
+        fs::write(cold.path().join(TEMP_DIR).join(".a.mkv.x1Y2z3"), "partial").unwrap();
+        let storage = BranchStorage::new(vec![Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() }], false);
+        assert!(storage.scan().unwrap().iter().all(|file| !file.path.starts_with(".drive-manager")));
+        let mut removed = storage.remove_temp_files(&[PathBuf::from("tv/e01.mkv")]).unwrap();
+        removed.sort();
+        assert_eq!(removed, [cold.path().join(TEMP_DIR).join(".a.mkv.x1Y2z3"), cold.path().join("tv/.e01.mkv.Ab3dE9")]);
+        // not a move that was cut off, so possibly not a temp file at all
+        assert!(cold.path().join("tv/.e02.mkv.Ab3dE9").exists());
+        assert!(cold.path().join("tv/e01.mkv").exists());
+        assert!(!is_rsync_temp(OsStr::new(".e01.mkv.Ab3d-9"), OsStr::new("e01.mkv")));
+    }
+
+    #[test]
+    fn test_settle_interrupted_move() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        let mtime = |nanos| FileTimes::new().set_modified(SystemTime::UNIX_EPOCH + Duration::new(1000, nanos));
+        for (dir, name, contents) in [(&hot, "a.mkv", "abc"), (&cold, "a.mkv", "abc"), (&hot, "b.mkv", "abc"), (&cold, "b.mkv", "other"), (&hot, "c.mkv", "abc"), (&hot, "e.mkv", "abc"), (&cold, "e.mkv", "abc")] {
+            fs::write(dir.path().join(name), contents).unwrap();
+            File::options().write(true).open(dir.path().join(name)).unwrap().set_times(mtime(123)).unwrap();
+        }
+        // written to after the copy was taken
+        File::options().write(true).open(hot.path().join("e.mkv")).unwrap().set_times(mtime(124)).unwrap();
+        let storage = BranchStorage::new(vec![
+            Branch { serial: "h1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "c1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false);
+        // copied whole before the crash, so only the source was left to go
+        assert!(!storage.settle_interrupted_move(Path::new("a.mkv"), "hot", "cold").unwrap());
+        assert!(!hot.path().join("a.mkv").exists() && cold.path().join("a.mkv").exists());
+        // some other file, or a source changed since, and both stay
+        for name in ["b.mkv", "e.mkv"] {
+            assert!(!storage.settle_interrupted_move(Path::new(name), "hot", "cold").unwrap());
+            assert!(hot.path().join(name).exists() && cold.path().join(name).exists());
+        }
+        assert_eq!(fs::read_to_string(cold.path().join("b.mkv")).unwrap(), "other");
+        assert!(storage.settle_interrupted_move(Path::new("c.mkv"), "hot", "cold").unwrap());
+        assert!(!storage.settle_interrupted_move(Path::new("d.mkv"), "hot", "cold").unwrap());
+
+        // an rsync copy that lost its data to a power cut is copied again
+        let mut storage = storage;
+        storage.set_mover(Mover::Rsync);
+        for (name, copied) in [("f.mkv", "abc"), ("g.mkv", "\0\0\0")] {
+            fs::write(hot.path().join(name), "abc").unwrap();
+            fs::write(cold.path().join(name), copied).unwrap();
+            for dir in [&hot, &cold] {
+                File::options().write(true).open(dir.path().join(name)).unwrap().set_times(mtime(123)).unwrap();
+            }
+        }
+        assert!(!storage.settle_interrupted_move(Path::new("f.mkv"), "hot", "cold").unwrap());
+        assert!(!hot.path().join("f.mkv").exists());
+        assert!(storage.settle_interrupted_move(Path::new("g.mkv"), "hot", "cold").unwrap());
+        assert!(hot.path().join("g.mkv").exists());
+    }
+
+    #[test]
+    fn test_copy_moves_on_from_a_full_branch() {
+        let hot = tempdir().unwrap();
+        let cold = [tempdir().unwrap(), tempdir().unwrap()];
+        writeln!(File::create(hot.path().join("a.mkv")).unwrap(), "test data").unwrap();
+        let faults = FaultInjector::new();
+        faults.inject_after(FaultPoint::Command("rsync".to_string()), Fault::Errno(libc::ENOSPC), 0, 1);
+        let mut storage = BranchStorage::with_executor(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold[0].path().to_path_buf() },
+            Branch { serial: "h2".to_string(), tier: "cold".to_string(), path: cold[1].path().to_path_buf() },
+        ], false, Arc::new(FaultyExecutor::new(Arc::new(RenameExecutor), faults.clone())));
+        storage.set_mover(Mover::Rsync);
+        storage.move_file(Path::new("a.mkv"), "hot", "cold", &|_| {}).unwrap();
+        assert_eq!(faults.triggered(&FaultPoint::Command("rsync".to_string())), 1);
+        let full = storage.ran_out.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        assert_eq!(full.len(), 1);
+        let moved_to = storage.branch_of(Path::new("a.mkv"), "cold").unwrap();
+        assert_ne!(moved_to, full[0]);
+
+        // with every branch full the move fails, trying each once
+        writeln!(File::create(hot.path().join("b.mkv")).unwrap(), "test data").unwrap();
+        faults.inject(FaultPoint::Command("rsync".to_string()), Fault::Errno(libc::ENOSPC));
+        assert!(storage.move_file(Path::new("b.mkv"), "hot", "cold", &|_| {}).is_err());
+        assert_eq!(faults.triggered(&FaultPoint::Command("rsync".to_string())), 2);
+        assert!(hot.path().join("b.mkv").exists());
+    }
+
+    #[test]
+    fn test_failed_rsync() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        writeln!(File::create(hot.path().join("a.mkv")).unwrap(), "test data").unwrap();
+        let faults = FaultInjector::new();
+        faults.inject(FaultPoint::Command("rsync".to_string()), Fault::ExitCode(23));
+        let mut storage = BranchStorage::with_executor(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, Arc::new(FaultyExecutor::new(Arc::new(SystemExecutor), faults.clone())));
+        storage.set_mover(Mover::Rsync);
+        assert!(storage.move_file(Path::new("a.mkv"), "hot", "cold", &|_| {}).is_err());
+        assert!(hot.path().join("a.mkv").exists());
+        assert_eq!(faults.triggered(&FaultPoint::Command("rsync".to_string())), 1);
+    }
+
+    // Stands in for rsync by renaming the source into place
+    struct RenameExecutor;
+
+    impl Executor for RenameExecutor {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            fs::rename(cmd[cmd.len() - 2], cmd[cmd.len() - 1])?;
+            SystemExecutor.status(&["true".as_ref()])
+        }
+
+        fn stream(&self, cmd: &[&OsStr], on_line: &mut dyn FnMut(&str)) -> io::Result<std::process::Output> {
+            let size = fs::symlink_metadata(cmd[cmd.len() - 2])?.len();
+            on_line(&format!("{:>15}   0%    0.00kB/s    0:00:00", 0));
+            on_line(&format!("{:>15} 100%   12.34MB/s    0:00:00 (xfr#1, to-chk=0/1)", size));
+            self.status(cmd).map(|status| std::process::Output { status, stdout: Vec::new(), stderr: Vec::new() })
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            SystemExecutor.output(cmd)
+        }
+    }
+
+    // RenameExecutor, noting each command it runs
+    struct LoggingExecutor(Mutex<Vec<String>>);
+
+    impl Executor for LoggingExecutor {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            self.0.lock().unwrap().push(command_line(cmd));
+            RenameExecutor.status(cmd)
+        }
+
+        fn stream(&self, cmd: &[&OsStr], on_line: &mut dyn FnMut(&str)) -> io::Result<std::process::Output> {
+            self.0.lock().unwrap().push(command_line(cmd));
+            RenameExecutor.stream(cmd, on_line)
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            SystemExecutor.output(cmd)
+        }
+    }
+
+    #[test]
+    fn test_rsync_bandwidth() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        fs::write(hot.path().join("a.mkv"), "test data").unwrap();
+        let executor = Arc::new(LoggingExecutor(Mutex::new(Vec::new())));
+        let mut storage = BranchStorage::with_executor(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, executor.clone());
+        storage.set_mover(Mover::Rsync);
+        storage.set_bandwidth(Bandwidth::from_config(&serde_json::json!({ "max_migration_mbps": 100, "max_move_mbps": 40, "mover_ionice": "idle" })));
+        storage.move_file(Path::new("a.mkv"), "hot", "cold", &|_| {}).unwrap();
+        let commands = executor.0.lock().unwrap();
+        assert!(commands[0].starts_with("ionice -c 3 rsync -axHAXWES"), "{}", commands[0]);
+        assert!(commands[0].contains(" --bwlimit=40960 "), "{}", commands[0]);
+        assert!(cold.path().join("a.mkv").exists());
+    }
+
+    // Stands in for rsync without -t: copies the contents but not the times
+    struct CopyExecutor;
+
+    impl Executor for CopyExecutor {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            let (src, dest) = (cmd[cmd.len() - 2], cmd[cmd.len() - 1]);
+            fs::copy(src, dest)?;
+            fs::remove_file(src)?;
+            SystemExecutor.status(&["true".as_ref()])
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            SystemExecutor.output(cmd)
+        }
+    }
+
+    #[test]
+    fn test_timestamps_survive_move() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        let src = hot.path().join("a.mkv");
+        writeln!(File::create(&src).unwrap(), "test data").unwrap();
+        let times = Timestamps { atime: (1_700_000_000, 123_456_789), mtime: (1_600_000_000, 987_654_321) };
+        set_timestamps(&src, &times).unwrap();
+        let mut storage = BranchStorage::with_executor(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, Arc::new(CopyExecutor));
+        storage.set_mover(Mover::Rsync);
+        storage.move_file(Path::new("a.mkv"), "hot", "cold", &|_| {}).unwrap();
+        assert_eq!(timestamps(&cold.path().join("a.mkv")).unwrap(), times);
+    }
+
+    // aws s3 cp against a directory standing in for the bucket
+    struct BucketExecutor(PathBuf);
+
+    impl Executor for BucketExecutor {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            self.output(cmd).map(|output| output.status)
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            let local = |arg: &OsStr| match arg.to_str().and_then(|arg| arg.strip_prefix("s3://")) {
+                Some(key) => self.0.join(key),
+                None => PathBuf::from(arg),
+            };
+            fs::create_dir_all(local(cmd[cmd.len() - 1]).parent().unwrap())?;
+            fs::copy(local(cmd[cmd.len() - 2]), local(cmd[cmd.len() - 1]))?;
+            SystemExecutor.output(&["true".as_ref()])
+        }
+    }
+
+    // writes to each file it uploads once the upload is done
+    struct WritingExecutor(BucketExecutor);
+
+    impl Executor for WritingExecutor {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            self.output(cmd).map(|output| output.status)
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            let output = self.0.output(cmd)?;
+            File::options().append(true).open(cmd[cmd.len() - 2])?.write_all(b" and more")?;
+            Ok(output)
+        }
+    }
+
+    #[test]
+    fn test_archive_and_restore() {
+        let cold = tempdir().unwrap();
+        let bucket = tempdir().unwrap();
+        fs::create_dir(cold.path().join("tv")).unwrap();
+        let file = cold.path().join("tv/a.mkv");
+        fs::write(&file, "test data").unwrap();
+        let times = Timestamps { atime: (1_700_000_000, 0), mtime: (1_600_000_000, 0) };
+        set_timestamps(&file, &times).unwrap();
+        let storage = BranchStorage::with_executor(vec![
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, Arc::new(BucketExecutor(bucket.path().to_path_buf())));
+        let archive = Archive::from_config(&serde_json::json!({ "archive": { "bucket": "nas" } })).unwrap();
+
+        // recorded while the file is still whole
+        let recorded = Mutex::new(Vec::new());
+        let record = |url: &str| {
+            assert_eq!(fs::read_to_string(&file).unwrap(), "test data");
+            recorded.lock().unwrap().push(url.to_string());
+            Ok(())
+        };
+        let url = storage.archive_file(Path::new("tv/a.mkv"), "cold", &archive, &record).unwrap();
+        assert_eq!(url, "s3://nas/tv/a.mkv");
+        assert_eq!(recorded.lock().unwrap().as_slice(), [url.as_str()]);
+        assert_eq!(fs::read_to_string(bucket.path().join("nas/tv/a.mkv")).unwrap(), "test data");
+        assert_eq!(timestamps(&file).unwrap(), times);
+        // reading the stub bumps its atime, which the restored file keeps
+        let stub = archive::read_stub(&file).unwrap().unwrap();
+        assert_eq!((stub.url, stub.size), (url, 9));
+        assert_eq!(stub.checksum.map(|checksum| checksum.algorithm).as_deref(), Some("xxh3"));
+        assert_eq!(storage.archive_file(Path::new("tv/a.mkv"), "cold", &archive, &record).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        let read = timestamps(&file).unwrap();
+        assert_eq!(read.mtime, times.mtime);
+
+        // what comes back must be what went up, not just as long
+        fs::write(bucket.path().join("nas/tv/a.mkv"), "test dat4").unwrap();
+        assert_eq!(storage.restore_file(Path::new("tv/a.mkv"), "cold", &archive).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(archive::read_stub(&file).unwrap().is_some());
+        assert!(!archive::staging_path(&file).exists());
+        fs::write(bucket.path().join("nas/tv/a.mkv"), "test data").unwrap();
+        storage.restore_file(Path::new("tv/a.mkv"), "cold", &archive).unwrap();
+        assert_eq!(timestamps(&file).unwrap(), read);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "test data");
+        assert!(!archive::staging_path(&file).exists());
+        assert_eq!(storage.restore_file(Path::new("tv/a.mkv"), "cold", &archive).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // written to during the upload, so it stays
+        let storage = BranchStorage::with_executor(vec![
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, Arc::new(WritingExecutor(BucketExecutor(bucket.path().to_path_buf()))));
+        fs::write(cold.path().join("tv/b.mkv"), "test data").unwrap();
+        assert_eq!(storage.archive_file(Path::new("tv/b.mkv"), "cold", &archive, &record).unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(fs::read_to_string(cold.path().join("tv/b.mkv")).unwrap(), "test data and more");
+        assert_eq!(recorded.lock().unwrap().len(), 1);
+
+        // the file stays whole when it cannot be recorded
+        let storage = BranchStorage::with_executor(vec![
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, Arc::new(BucketExecutor(bucket.path().to_path_buf())));
+        let failed = storage.archive_file(Path::new("tv/b.mkv"), "cold", &archive, &|_| Err(io::Error::other("database is locked")));
+        assert_eq!(failed.unwrap_err().to_string(), "database is locked");
+        assert_eq!(fs::read_to_string(cold.path().join("tv/b.mkv")).unwrap(), "test data and more");
+        assert!(!archive::staging_path(&cold.path().join("tv/b.mkv")).exists());
+    }
+
+    #[test]
+    fn test_move_progress() {
+        assert_eq!(rsync_progress("  1,238,099,968  57%  118.06MB/s    0:00:08 (xfr#1, to-chk=0/1)"), Some(1_238_099_968));
+        assert_eq!(rsync_progress("1.024 100% 1,00MB/s 0:00:00"), Some(1024));
+        assert_eq!(rsync_progress("sending incremental file list"), None);
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        fs::write(hot.path().join("a.mkv"), "test data").unwrap();
+        let mut storage = BranchStorage::with_executor(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, Arc::new(RenameExecutor));
+        storage.set_mover(Mover::Rsync);
+        let reported = Mutex::new(Vec::new());
+        storage.move_file(Path::new("a.mkv"), "hot", "cold", &|bytes| reported.lock().unwrap().push(bytes)).unwrap();
+        assert_eq!(reported.into_inner().unwrap(), [0, 9]);
+    }
+
+    #[test]
+    fn test_immutable_files() {
+        let (hot, cold, mut storage) = symlink_fixture(SymlinkPolicy::Skip);
+        let src = hot.path().join("movies/a.mkv");
+        let flags = file_flags(&src).unwrap();
+        if set_file_flags(&src, flags | FS_IMMUTABLE_FL).is_err() {
+            // no CAP_LINUX_IMMUTABLE or no inode flags on the temp filesystem
+            return;
+        }
+        assert!(scanned_paths(&storage).is_empty());
+        let e = storage.move_file(Path::new("movies/a.mkv"), "hot", "cold", &|_| {}).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        storage.set_locked_file_policy(LockedFilePolicy::Clear);
+        assert_eq!(scanned_paths(&storage), ["movies/a.mkv"]);
+        storage.move_file(Path::new("movies/a.mkv"), "hot", "cold", &|_| {}).unwrap();
+        let dest = cold.path().join("movies/a.mkv");
+        assert_ne!(file_flags(&dest).unwrap() & FS_IMMUTABLE_FL, 0);
+        set_file_flags(&dest, file_flags(&dest).unwrap() & !LOCKED_FLAGS).unwrap();
+    }
+
+    fn symlink_fixture(symlinks: SymlinkPolicy) -> (tempfile::TempDir, tempfile::TempDir, BranchStorage) {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        fs::create_dir_all(hot.path().join("movies")).unwrap();
+        writeln!(File::create(hot.path().join("movies/a.mkv")).unwrap(), "test data").unwrap();
+        symlink("a.mkv", hot.path().join("movies/latest.mkv")).unwrap();
+        symlink(hot.path().join("movies/a.mkv"), hot.path().join("a-abs.mkv")).unwrap();
+        symlink("missing.mkv", hot.path().join("dangling.mkv")).unwrap();
+        let mut storage = BranchStorage::new(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false);
+        storage.set_symlink_policy(symlinks);
+        (hot, cold, storage)
+    }
+
+    fn scanned_paths(storage: &BranchStorage) -> Vec<String> {
+        let mut paths: Vec<String> = storage.scan().unwrap().into_iter().map(|file| file.path.to_string_lossy().into_owned()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_symlink_policies() {
+        let (_hot, _cold, storage) = symlink_fixture(SymlinkPolicy::Skip);
+        assert_eq!(scanned_paths(&storage), ["movies/a.mkv"]);
+        let (_hot, _cold, storage) = symlink_fixture(SymlinkPolicy::Link);
+        assert_eq!(scanned_paths(&storage), ["a-abs.mkv", "dangling.mkv", "movies/a.mkv", "movies/latest.mkv"]);
+        let (_hot, cold, storage) = symlink_fixture(SymlinkPolicy::Link);
+        storage.move_file(Path::new("dangling.mkv"), "hot", "cold", &|_| {}).unwrap();
+        assert_eq!(fs::read_link(cold.path().join("dangling.mkv")).unwrap(), Path::new("missing.mkv"));
+        assert_eq!(SymlinkPolicy::from_config(&serde_json::json!({ "symlinks": "with_target" })), SymlinkPolicy::WithTarget);
+    }
+
+    #[test]
+    fn test_links_move_with_target() {
+        let (hot, cold, storage) = symlink_fixture(SymlinkPolicy::WithTarget);
+        assert_eq!(scanned_paths(&storage), ["movies/a.mkv"]);
+        storage.move_file(Path::new("movies/a.mkv"), "hot", "cold", &|_| {}).unwrap();
+        assert_eq!(fs::read_link(cold.path().join("movies/latest.mkv")).unwrap(), Path::new("a.mkv"));
+        assert_eq!(fs::read_link(cold.path().join("a-abs.mkv")).unwrap(), cold.path().join("movies/a.mkv"));
+        assert_eq!(fs::read_to_string(cold.path().join("a-abs.mkv")).unwrap(), "test data\n");
+        assert!(fs::symlink_metadata(hot.path().join("movies/latest.mkv")).is_err());
+        assert!(fs::symlink_metadata(hot.path().join("dangling.mkv")).is_ok());
+    }
+
+    // Stands in for rsync -H --relative: every source lands at the path after
+    // its "/./", linked to the first
+    struct RelativeLinkExecutor;
+
+    impl Executor for RelativeLinkExecutor {
+        fn status(&self, cmd: &[&OsStr]) -> io::Result<std::process::ExitStatus> {
+            assert!(cmd.contains(&OsStr::new("--relative")));
+            let dest = Path::new(cmd[cmd.len() - 1]);
+            let sources: Vec<&str> = cmd.iter().filter_map(|arg| arg.to_str()).filter(|arg| arg.contains("/./")).collect();
+            let first = dest.join(sources[0].split_once("/./").unwrap().1);
+            for source in &sources {
+                let target = dest.join(source.split_once("/./").unwrap().1);
+                fs::create_dir_all(target.parent().unwrap())?;
+                if target == first {
+                    fs::copy(source, &target)?;
+                } else {
+                    fs::hard_link(&first, &target)?;
+                }
+                fs::remove_file(source)?;
+            }
+            SystemExecutor.status(&["true".as_ref()])
+        }
+
+        fn output(&self, cmd: &[&OsStr]) -> io::Result<std::process::Output> {
+            SystemExecutor.output(cmd)
+        }
+    }
+
+    #[test]
+    fn test_move_link_group() {
+        let hot = tempdir().unwrap();
+        let cold = tempdir().unwrap();
+        for dir in ["daily.0", "daily.1"] {
+            fs::create_dir_all(hot.path().join(dir)).unwrap();
+        }
+        writeln!(File::create(hot.path().join("daily.0/a")).unwrap(), "test data").unwrap();
+        fs::hard_link(hot.path().join("daily.0/a"), hot.path().join("daily.1/a")).unwrap();
+        writeln!(File::create(hot.path().join("single")).unwrap(), "test data").unwrap();
+        let mut storage = BranchStorage::with_executor(vec![
+            Branch { serial: "n1".to_string(), tier: "hot".to_string(), path: hot.path().to_path_buf() },
+            Branch { serial: "h1".to_string(), tier: "cold".to_string(), path: cold.path().to_path_buf() },
+        ], false, Arc::new(RelativeLinkExecutor));
+        storage.set_mover(Mover::Rsync);
+        let mut files = storage.scan().unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(files[0].hardlink, files[1].hardlink);
+        assert!(files[0].hardlink.is_some() && files[2].hardlink.is_none());
+
+        let links = [PathBuf::from("daily.0/a"), PathBuf::from("daily.1/a")];
+        storage.move_link_group(&links, "hot", "cold", &|_| {}).unwrap();
+        let moved = fs::metadata(cold.path().join("daily.1/a")).unwrap();
+        assert_eq!((moved.nlink(), moved.ino()), (2, fs::metadata(cold.path().join("daily.0/a")).unwrap().ino()));
+        assert!(!hot.path().join("daily.0/a").exists());
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        use std::os::unix::ffi::OsStringExt;
+        let (hot, cold, storage) = symlink_fixture(SymlinkPolicy::Skip);
+        let latin1 = PathBuf::from(std::ffi::OsString::from_vec(b"movies/caf\xe9.mkv".to_vec()));
+        writeln!(File::create(hot.path().join(&latin1)).unwrap(), "test data").unwrap();
+        let files = storage.scan().unwrap();
+        assert!(files.iter().any(|file| file.path == latin1));
+        storage.move_file(&latin1, "hot", "cold", &|_| {}).unwrap();
+        assert!(cold.path().join(&latin1).exists());
+    }
+
+    #[test]
+    fn test_usage_percent() {
+        assert_eq!(TierUsage { total: 200, used: 50 }.usage_percent(), 25.0);
+        assert_eq!(TierUsage::default().usage_percent(), 0.0);
+    }
+}
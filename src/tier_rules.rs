@@ -0,0 +1,246 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+use crate::drive_manager::DriveManager;
+use crate::grouping::{extension, glob_match};
+use crate::reserve::Reserve;
+use crate::tiering_manager::tier_rank;
+
+// how long a new file has to go unwritten before it is placed, unless the
+// ingest section's delay_sec says otherwise
+pub const INGEST_DELAY_SEC: u64 = 60;
+
+// Which files a rule applies to, by any of
+//   "path": "archive/*"         a pattern over the whole path, * crossing /
+//   "extensions": ["tar", "zst"]
+//   "min_size": "1G", "max_size": "64K"
+// all of which have to hold
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileMatch {
+    pub path: Option<String>,
+    pub extensions: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl FileMatch {
+    pub fn from_rule(rule: &Value) -> Self {
+        let size = |key: &str| match rule.get(key).and_then(Reserve::parse) {
+            Some(Reserve::Bytes(bytes)) => Some(bytes),
+            _ => None,
+        };
+        Self {
+            path: rule.get("path").and_then(Value::as_str).map(|path| path.trim_start_matches('/').to_string()),
+            extensions: rule.get("extensions").and_then(Value::as_array).map(|values| {
+                values.iter().filter_map(Value::as_str).map(|ext| ext.trim_start_matches('.').to_ascii_lowercase()).collect()
+            }).unwrap_or_default(),
+            min_size: size("min_size"),
+            max_size: size("max_size"),
+        }
+    }
+
+    pub fn matches(&self, path: &Path, size: u64) -> bool {
+        self.path.as_ref().is_none_or(|pattern| glob_match(pattern.as_bytes(), path.as_os_str().as_bytes()))
+            && (self.extensions.is_empty() || extension(path).is_some_and(|ext| self.extensions.contains(&ext)))
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Jump {
+    files: FileMatch,
+    demote_to: Option<String>,
+    promote_to: Option<String>,
+}
+
+// Files that skip tiers. Demotions otherwise go one tier down and
+// promotions all the way up to hot:
+//   "tier_jumps": [
+//     { "extensions": ["tar", "zst"], "min_size": "1G", "demote_to": "cold" },
+//     { "path": "photos/*", "promote_to": "warm" }
+//   ]
+// The first rule that matches and says where to go wins.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TierJumps {
+    rules: Vec<Jump>,
+}
+
+impl TierJumps {
+    pub fn from_config(config: &Value) -> Self {
+        let tier = |rule: &Value, key: &str| rule.get(key).and_then(Value::as_str).filter(|tier| DriveManager::TIERS.contains(tier)).map(str::to_string);
+        let rules = config.get("tier_jumps").and_then(Value::as_array).map(|rules| {
+            rules.iter().map(|rule| Jump { files: FileMatch::from_rule(rule), demote_to: tier(rule, "demote_to"), promote_to: tier(rule, "promote_to") })
+                .filter(|jump| jump.demote_to.is_some() || jump.promote_to.is_some())
+                .collect()
+        }).unwrap_or_default();
+        Self { rules }
+    }
+
+    // Where a file demoted from `tier` goes, None if it is already as low
+    // as it can go
+    pub fn demotion_target(&self, path: &Path, size: u64, tier: &str) -> Option<String> {
+        let rank = tier_rank(tier);
+        let jump = self.rules.iter().filter(|jump| jump.files.matches(path, size)).find_map(|jump| jump.demote_to.as_ref());
+        match jump {
+            Some(target) if tier_rank(target) > rank => Some(target.clone()),
+            _ => DriveManager::TIERS.get(rank + 1).map(|tier| tier.to_string()),
+        }
+    }
+
+    // Where a file promoted from `tier` goes, None if it is already as high
+    // as its rule lets it go
+    pub fn promotion_target(&self, path: &Path, size: u64, tier: &str) -> Option<String> {
+        let jump = self.rules.iter().filter(|jump| jump.files.matches(path, size)).find_map(|jump| jump.promote_to.as_deref());
+        let target = jump.unwrap_or(DriveManager::TIERS[0]);
+        (tier_rank(target) < tier_rank(tier)).then(|| target.to_string())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct PolicyRule {
+    files: FileMatch,
+    // how long since the file was last read
+    min_age: Option<Duration>,
+    max_age: Option<Duration>,
+    tier: String,
+}
+
+// Tiers for files by what they are, ahead of the access thresholds:
+//   "tier_policy": [
+//     { "path": "appdata/*", "tier": "hot" },
+//     { "extensions": ["iso"], "tier": "cold" },
+//     { "path": "media/*", "min_age_days": 30, "tier": "cold" }
+//   ]
+// with min_age_days and max_age_days counted from the last read. The
+// first rule that matches wins, and the file is kept on its tier much as a
+// tier tag would keep it; files no rule matches are tiered by access.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TierPolicy {
+    rules: Vec<PolicyRule>,
+}
+
+impl TierPolicy {
+    pub fn from_config(config: &Value) -> Self {
+        let days = |rule: &Value, key: &str| rule.get(key).and_then(Value::as_f64).map(|days| Duration::from_secs_f64(days.max(0.0) * 86400.0));
+        let rules = config.get("tier_policy").and_then(Value::as_array).into_iter().flatten().filter_map(|rule| {
+            let tier = rule.get("tier").and_then(Value::as_str).filter(|tier| DriveManager::TIERS.contains(tier))?;
+            Some(PolicyRule { files: FileMatch::from_rule(rule), min_age: days(rule, "min_age_days"), max_age: days(rule, "max_age_days"), tier: tier.to_string() })
+        }).collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // The tier of the first rule matching a file last read at `accessed`
+    pub fn tier_for(&self, path: &Path, size: u64, accessed: SystemTime, now: SystemTime) -> Option<&str> {
+        let age = now.duration_since(accessed).unwrap_or_default();
+        self.rules.iter()
+            .find(|rule| rule.files.matches(path, size) && rule.min_age.is_none_or(|min| age >= min) && rule.max_age.is_none_or(|max| age <= max))
+            .map(|rule| rule.tier.as_str())
+    }
+}
+
+// Where files written through the merged mounts start out, rather than
+// wherever mergerfs's create policy put them:
+//   "ingest": { "delay_sec": 60, "rules": [
+//     { "extensions": ["iso"], "min_size": "1G", "tier": "cold" },
+//     { "path": "downloads/*", "tier": "warm" }
+//   ] }
+// A file is placed once nothing has written to it for delay_sec. The first
+// rule that matches wins; other files stay where they landed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IngestRules {
+    rules: Vec<(FileMatch, String)>,
+    pub delay: Duration,
+}
+
+impl IngestRules {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("ingest")?;
+        let rules: Vec<(FileMatch, String)> = section.get("rules").and_then(Value::as_array)?.iter().filter_map(|rule| {
+            let tier = rule.get("tier").and_then(Value::as_str).filter(|tier| DriveManager::TIERS.contains(tier))?;
+            Some((FileMatch::from_rule(rule), tier.to_string()))
+        }).collect();
+        (!rules.is_empty()).then(|| Self {
+            rules,
+            delay: Duration::from_secs(section.get("delay_sec").and_then(Value::as_u64).unwrap_or(INGEST_DELAY_SEC)),
+        })
+    }
+
+    pub fn tier_for(&self, path: &Path, size: u64) -> Option<&str> {
+        self.rules.iter().find(|(files, _)| files.matches(path, size)).map(|(_, tier)| tier.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_file_match() {
+        let rule = FileMatch::from_rule(&json!({ "path": "/archive/*", "extensions": [".TAR"], "min_size": "1K" }));
+        assert!(rule.matches(Path::new("archive/2020/backup.tar"), 2048));
+        assert!(!rule.matches(Path::new("archive/2020/backup.tar"), 10));
+        assert!(!rule.matches(Path::new("movies/backup.tar"), 2048));
+        assert!(FileMatch::from_rule(&json!({})).matches(Path::new("anything"), 0));
+    }
+
+    #[test]
+    fn test_jumps() {
+        let jumps = TierJumps::from_config(&json!({ "tier_jumps": [
+            { "extensions": ["zst"], "min_size": "1G", "demote_to": "cold" },
+            { "path": "photos/*", "promote_to": "warm" },
+            { "path": "*", "demote_to": "lukewarm" },
+        ] }));
+        let archive = Path::new("backups/db.zst");
+        assert_eq!(jumps.demotion_target(archive, 2 << 30, "hot").as_deref(), Some("cold"));
+        assert_eq!(jumps.demotion_target(archive, 1 << 20, "hot").as_deref(), Some("warm"));
+        assert_eq!(jumps.demotion_target(archive, 2 << 30, "cold"), None);
+        assert_eq!(jumps.promotion_target(Path::new("photos/a.jpg"), 0, "cold").as_deref(), Some("warm"));
+        assert_eq!(jumps.promotion_target(Path::new("photos/a.jpg"), 0, "warm"), None);
+        assert_eq!(jumps.promotion_target(Path::new("notes.txt"), 0, "cold").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_tier_policy() {
+        let policy = TierPolicy::from_config(&json!({ "tier_policy": [
+            { "path": "appdata/**", "tier": "hot" },
+            { "extensions": ["iso"], "tier": "cold" },
+            { "path": "media/*", "min_size": "1G", "min_age_days": 30, "tier": "cold" },
+            { "path": "media/*", "max_age_days": 1, "tier": "warm" },
+            { "path": "*", "tier": "lukewarm" },
+        ] }));
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100 * 86400);
+        let days_ago = |days: u64| now - Duration::from_secs(days * 86400);
+        assert_eq!(policy.tier_for(Path::new("appdata/plex/db/library.db"), 10, now, now), Some("hot"));
+        // the first match wins, wherever the file is
+        assert_eq!(policy.tier_for(Path::new("appdata/images/debian.iso"), 10, now, now), Some("hot"));
+        assert_eq!(policy.tier_for(Path::new("isos/debian.iso"), 10, now, now), Some("cold"));
+        assert_eq!(policy.tier_for(Path::new("media/film.mkv"), 4 << 30, days_ago(45), now), Some("cold"));
+        assert_eq!(policy.tier_for(Path::new("media/film.mkv"), 4 << 30, days_ago(10), now), None);
+        assert_eq!(policy.tier_for(Path::new("media/clip.mkv"), 1 << 20, days_ago(45), now), None);
+        assert_eq!(policy.tier_for(Path::new("media/clip.mkv"), 1 << 20, days_ago(0), now), Some("warm"));
+        // a rule without a known tier is dropped
+        assert_eq!(policy.tier_for(Path::new("notes.txt"), 10, now, now), None);
+        assert!(TierPolicy::from_config(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_ingest_rules() {
+        let ingest = IngestRules::from_config(&json!({ "ingest": { "rules": [
+            { "extensions": ["iso"], "min_size": "1G", "tier": "cold" },
+            { "path": "downloads/*", "tier": "warm" },
+            { "path": "*", "tier": "nowhere" },
+        ] } })).unwrap();
+        assert_eq!(ingest.delay, Duration::from_secs(INGEST_DELAY_SEC));
+        assert_eq!(ingest.tier_for(Path::new("downloads/linux.iso"), 2 << 30), Some("cold"));
+        assert_eq!(ingest.tier_for(Path::new("downloads/linux.iso"), 1 << 20), Some("warm"));
+        assert_eq!(ingest.tier_for(Path::new("notes.txt"), 10), None);
+        assert!(IngestRules::from_config(&json!({ "ingest": { "rules": [] } })).is_none());
+    }
+}
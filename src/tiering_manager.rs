@@ -0,0 +1,3900 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use serde_json::{json, Value};
+use log::{debug, error, info, warn};
+use crate::activity::{self, ActivityHeat};
+use crate::archive::Archive;
+use crate::args::Args;
+use crate::check_schedule::{CheckSchedule, CHECK_REQUEST_POLL_SEC};
+use crate::clock::{Clock, SystemClock};
+use crate::disk_stats::{self, DiskIo, DiskStatsCollector};
+use crate::drive_manager::DriveManager;
+use crate::events;
+use crate::executor::SystemExecutor;
+use crate::external_jobs::{self, ExternalJob, RunningJob};
+use crate::file_metadata::{ArchivedFile, DrainState, FailedMove, FileMetadata, FileMoveInfo, JournaledMove, PendingRetry, TransferProgress};
+use crate::grouping::{GroupScope, KeepTogether};
+use crate::hardlinks::{self, FarmAction, HardlinkFarms};
+use crate::media_server;
+use crate::load_monitor::{LoadLimits, LoadMonitor, Throttle};
+use crate::metadata_db::{to_unix, MetadataDb};
+use crate::mount_watch::{MountEvent, MountWatcher};
+use crate::move_queue::{MoveQueue, Priority, Pushed, QueuedMove};
+use crate::open_files::OpenFilePolicy;
+use crate::pins::{self, Pin};
+use crate::placement::Placement;
+use crate::plan::{Plan, PlannedMove};
+use crate::power::{PowerMonitor, PowerState};
+use crate::read_patterns::{self, ReadPattern, ReadTracker};
+use crate::read_watch::{AccessTracking, ColdReadPromotion, ReadBursts, ReadWatcher};
+use crate::retry::{self, ErrorClass, RetryPolicy};
+use crate::scratch::{self, ScratchDir};
+use crate::storage::{self, BranchUsage, ScannedFile, Storage};
+use crate::tier_rules::{IngestRules, TierJumps, TierPolicy};
+use crate::ttl::TtlPolicy;
+use crate::write_skew::{self, WriteSkewPolicy};
+
+pub const TIERING_CHECK_INTERVAL: u64 = 7200; // 2 hours in seconds
+pub const MAINTENANCE_INTERVAL: u64 = 86400; // once a day
+pub const DEMOTION_BATCH_SIZE: usize = 10;
+// how often due retries and retry requests from `failures retry` are picked up
+pub const RETRY_POLL_INTERVAL: u64 = 5;
+// how often new files are checked for having settled
+pub const INGEST_POLL_INTERVAL: u64 = 5;
+// how long a move waits when its file is open, unless open_file_defer_sec is set
+pub const OPEN_FILE_DEFER_SEC: u64 = 300;
+// how long a file stays out of the demotion candidates after moving tiers,
+// unless promotion_cooldown_sec is set
+pub const PROMOTION_COOLDOWN_SEC: u64 = 6 * 3600;
+// a single branch this full has its coldest files moved off right away,
+// unless emergency_capacity_threshold is set
+pub const EMERGENCY_CAPACITY_THRESHOLD: f64 = 98.0;
+// how often branches are checked against it, unless emergency_check_sec is set
+pub const EMERGENCY_CHECK_INTERVAL: u64 = 60;
+// how often the progress of a copy is written out for `drive-manager status`
+pub const PROGRESS_SAVE_INTERVAL: u64 = 5;
+// how often opens counted by fanotify are written to the database
+pub const ACCESS_FLUSH_INTERVAL: u64 = 60;
+// how long a shutdown waits for copies to finish, unless shutdown_timeout_sec
+// is set; inside the generated unit's TimeoutStopSec of 300
+pub const SHUTDOWN_TIMEOUT_SEC: u64 = 240;
+
+#[derive(Debug, Default)]
+pub struct ProcessedMoves {
+    pub completed: Vec<FileMoveInfo>,
+    // moves that used up their retries and went to the dead-letter list
+    pub abandoned: Vec<FileMoveInfo>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MoveOutcome {
+    Moved,
+    RetryScheduled,
+    DeadLettered,
+    // the file kept failing to read, so it is left where it is
+    Quarantined,
+    // the file was open, so the move was put off without using a retry
+    Deferred,
+    // --observe only reports the moves it would make
+    Observed,
+}
+
+// a file moved off a drive being drained, with its tier and size
+type DrainedFile = (PathBuf, String, u64);
+
+// What a scan holds on a tier over the access rules: staged files on hot,
+// matched by their exact path, then pins
+struct Held {
+    staged: HashSet<PathBuf>,
+    pins: Vec<Pin>,
+}
+
+impl Held {
+    fn tier_for(&self, path: &Path) -> Option<&str> {
+        if self.staged.contains(path) {
+            return Some("hot");
+        }
+        pins::find(&self.pins, path).map(|pin| pin.tier.as_str())
+    }
+}
+
+pub fn tier_rank(tier: &str) -> usize {
+    DriveManager::TIERS.iter().position(|t| *t == tier).unwrap_or(DriveManager::TIERS.len())
+}
+
+// max_in_flight_gb caps the total size of moves copying at once, so a wave
+// of huge demotions cannot fill the target with partial copies
+fn max_in_flight_bytes(config: &Value) -> Option<u64> {
+    config.get("max_in_flight_gb").and_then(Value::as_f64).map(|gb| (gb * 1024.0 * 1024.0 * 1024.0) as u64)
+}
+
+pub struct TieringManager {
+    args: Args,
+    // swapped whole by a reload that changes only settings read as they are
+    // used, see reload::needs_restart
+    config: RwLock<Arc<Value>>,
+    storage: Arc<dyn Storage>,
+    db: Mutex<MetadataDb>,
+    clock: Arc<dyn Clock>,
+    retry_policy: RetryPolicy,
+    open_file_policy: OpenFilePolicy,
+    keep_together: KeepTogether,
+    tier_jumps: TierJumps,
+    ingest: Option<IngestRules>,
+    scratch_dirs: Vec<ScratchDir>,
+    pins: Vec<Pin>,
+    policy: TierPolicy,
+    ttl: TtlPolicy,
+    external_jobs: Vec<ExternalJob>,
+    hardlink_farms: HardlinkFarms,
+    // farms found by the last scan
+    farms: Mutex<Vec<PathBuf>>,
+    // the paths linked to each file with hard links, as of the last scan
+    link_groups: Mutex<HashMap<PathBuf, Vec<PathBuf>>>,
+    move_queue: MoveQueue,
+    load_limits: Option<LoadLimits>,
+    load_monitor: Mutex<LoadMonitor>,
+    // how background moves run given the last load sample
+    throttle: Mutex<Throttle>,
+    // the managed drives as (serial, kernel name), for I/O statistics
+    drives: Mutex<Vec<(String, String)>>,
+    disk_stats: Mutex<DiskStatsCollector>,
+    // each drive's I/O as of the last sample, by serial
+    disk_io: Mutex<HashMap<String, DiskIo>>,
+    // drives taking far more than their share of their tier's writes, as
+    // of the last sample
+    write_skewed: Mutex<HashSet<String>>,
+    power: Option<PowerMonitor>,
+    on_battery: AtomicBool,
+    // failed moves waiting out their backoff and moves deferred because the
+    // file was open, with the time they are due
+    retry_schedule: Mutex<Vec<(SystemTime, QueuedMove)>>,
+    // new files written through the merged mounts, with the tier each landed
+    // in and when it is placed by the ingest rules if nothing writes to it
+    ingest_pending: Mutex<HashMap<PathBuf, (String, SystemTime)>>,
+    // copies under way, with when their progress was last saved
+    transfers: Mutex<HashMap<PathBuf, (TransferProgress, SystemTime)>>,
+    // files a media server is playing, with when their protection runs out
+    streams: Mutex<HashMap<PathBuf, SystemTime>>,
+    read_tracker: Mutex<ReadTracker>,
+    // how each file was read since the last scan, when reads were sampled
+    reads_since_scan: Mutex<HashMap<PathBuf, ReadPattern>>,
+    background_started: AtomicBool,
+    // set while moves drain for a config reload
+    reloading: AtomicBool,
+    // set by `ctl pause` until `ctl resume`
+    paused: AtomicBool,
+    // set once the service is shutting down, for good
+    stopping: AtomicBool,
+    // what the moves queued by the current --observe check add up to
+    plan: Mutex<Plan>,
+    // recent reads of cold files, when cold_read_promotion is set
+    cold_reads: Option<Mutex<ReadBursts>>,
+    access_tracking: AccessTracking,
+    // set while fanotify counts opens, which then stand in for atimes
+    access_watch: AtomicBool,
+    // set while heat comes from writes, by config or for want of anything better
+    activity_watch: AtomicBool,
+    activity_heat: ActivityHeat,
+    // files opened through the merged mounts since the counts were last
+    // written, with when each was last opened
+    opens: Mutex<HashMap<PathBuf, SystemTime>>,
+    // the files each drain in progress queued to move
+    drain_files: Mutex<HashMap<String, Vec<DrainedFile>>>,
+}
+
+impl TieringManager {
+    pub fn new(args: Args, config: Value, storage: Arc<dyn Storage>, db: MetadataDb) -> Self {
+        Self::with_clock(args, config, storage, db, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(args: Args, config: Value, storage: Arc<dyn Storage>, db: MetadataDb, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            args,
+            retry_policy: RetryPolicy::from_config(&config),
+            open_file_policy: OpenFilePolicy::from_config(&config),
+            keep_together: KeepTogether::from_config(&config),
+            tier_jumps: TierJumps::from_config(&config),
+            ingest: IngestRules::from_config(&config),
+            scratch_dirs: scratch::scratch_dirs(&config),
+            pins: pins::pins(&config),
+            policy: TierPolicy::from_config(&config),
+            ttl: TtlPolicy::from_config(&config),
+            external_jobs: external_jobs::external_jobs(&config),
+            hardlink_farms: HardlinkFarms::from_config(&config),
+            move_queue: MoveQueue::new(max_in_flight_bytes(&config)),
+            load_limits: LoadLimits::from_config(&config),
+            load_monitor: Mutex::new(LoadMonitor::new(Path::new("/proc"), thread::available_parallelism().map_or(1, usize::from))),
+            throttle: Mutex::new(Throttle::Normal),
+            drives: Mutex::new(Vec::new()),
+            disk_stats: Mutex::new(DiskStatsCollector::new(Path::new("/proc"))),
+            disk_io: Mutex::new(HashMap::new()),
+            write_skewed: Mutex::new(HashSet::new()),
+            power: PowerMonitor::from_config(&config),
+            on_battery: AtomicBool::new(false),
+            read_tracker: Mutex::new(ReadTracker::new(
+                config.get("sequential_read_min_samples").and_then(Value::as_u64).map_or(read_patterns::MIN_SAMPLES, |samples| samples as usize),
+            )),
+            cold_reads: ColdReadPromotion::from_config(&config).map(|promotion| Mutex::new(ReadBursts::new(promotion))),
+            access_tracking: AccessTracking::from_config(&config),
+            access_watch: AtomicBool::new(false),
+            activity_watch: AtomicBool::new(AccessTracking::from_config(&config) == AccessTracking::Activity),
+            activity_heat: ActivityHeat::from_config(&config),
+            opens: Mutex::new(HashMap::new()),
+            config: RwLock::new(Arc::new(config)),
+            storage,
+            db: Mutex::new(db),
+            clock,
+            retry_schedule: Mutex::new(Vec::new()),
+            ingest_pending: Mutex::new(HashMap::new()),
+            transfers: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+            farms: Mutex::new(Vec::new()),
+            link_groups: Mutex::new(HashMap::new()),
+            reads_since_scan: Mutex::new(HashMap::new()),
+            background_started: AtomicBool::new(false),
+            reloading: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            plan: Mutex::new(Plan::default()),
+            drain_files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn start_background_process(self: &Arc<Self>) {
+        assert!(!self.background_started.swap(true, Ordering::SeqCst), "background process already started");
+        match self.recover_interrupted_moves() {
+            Ok(0) => {}
+            Ok(count) => info!("Resuming {} moves cut off by the last shutdown", count),
+            Err(e) => warn!("Failed to recover moves cut off by the last shutdown: {}", e),
+        }
+        match self.restore_retries() {
+            Ok(0) => {}
+            Ok(count) => info!("Restored {} scheduled retries", count),
+            Err(e) => error!("Failed to restore scheduled retries: {}", e),
+        }
+        let tm = Arc::clone(self);
+        thread::spawn(move || tm.tiering_check_loop());
+        let tm = Arc::clone(self);
+        thread::spawn(move || tm.file_mover_loop());
+        let tm = Arc::clone(self);
+        thread::spawn(move || tm.retry_loop());
+        let tm = Arc::clone(self);
+        thread::spawn(move || tm.maintenance_loop());
+        let tm = Arc::clone(self);
+        thread::spawn(move || tm.emergency_check_loop());
+        let sample_sec = self.config().get("sequential_read_sample_sec").and_then(Value::as_u64).unwrap_or(read_patterns::SAMPLE_SEC);
+        if sample_sec > 0 {
+            let tm = Arc::clone(self);
+            thread::spawn(move || tm.read_sampling_loop(Duration::from_secs(sample_sec)));
+        }
+        if let Some(limits) = &self.load_limits {
+            let interval = limits.check_interval;
+            let tm = Arc::clone(self);
+            thread::spawn(move || tm.load_monitor_loop(interval));
+        }
+        if let Some(interval) = disk_stats::sample_interval(&self.config()) {
+            let tm = Arc::clone(self);
+            thread::spawn(move || tm.disk_stats_loop(interval));
+        }
+        if let Some(power) = &self.power {
+            let interval = power.check_interval;
+            let tm = Arc::clone(self);
+            thread::spawn(move || tm.power_monitor_loop(interval));
+        }
+        if self.config().get("watch_mounts").and_then(Value::as_bool).unwrap_or(true) {
+            let mounts: Vec<PathBuf> = DriveManager::TIERS.iter().map(|tier| Path::new(DriveManager::MERGERFS_MOUNT_PATH).join(tier)).filter(|mount| mount.is_dir()).collect();
+            let tm = Arc::clone(self);
+            if self.cold_reads.is_some() {
+                let mounts = mounts.clone();
+                let tm = Arc::clone(self);
+                thread::spawn(move || tm.cold_read_loop(mounts));
+            }
+            if self.access_tracking == AccessTracking::Fanotify {
+                let mounts = mounts.clone();
+                let tm = Arc::clone(self);
+                thread::spawn(move || tm.access_loop(mounts));
+            }
+            thread::spawn(move || tm.mount_watch_loop(mounts));
+            if self.ingest.is_some() {
+                let tm = Arc::clone(self);
+                thread::spawn(move || tm.ingest_loop());
+            }
+        }
+    }
+
+    // Promotions and demotions each run on their own schedule. While the
+    // two are the same, one check does both off a single scan.
+    pub fn tiering_check_loop(&self) {
+        let (mut next_promotion, mut next_demotion) = (self.clock.now(), self.clock.now());
+        loop {
+            // read each time round, so a reload changes the schedule
+            let promotion = CheckSchedule::for_pass(&self.config(), "promotion");
+            let demotion = CheckSchedule::for_pass(&self.config(), "demotion");
+            let now = self.clock.now();
+            let (promote, demote) = (now >= next_promotion, now >= next_demotion);
+            let started = self.pass_started("tiering check");
+            let result = self.perform_check(promote, demote);
+            if let Err(e) = &result {
+                error!("Error during tiering check: {}", e);
+            }
+            if promotion == demotion {
+                next_promotion = now + promotion.next_delay();
+                next_demotion = next_promotion;
+            } else {
+                if promote {
+                    next_promotion = now + promotion.next_delay();
+                }
+                if demote {
+                    next_demotion = now + demotion.next_delay();
+                }
+            }
+            self.pass_finished("tiering check", started, &result, next_promotion.min(next_demotion));
+            if self.wait_for_next_check(next_promotion.min(next_demotion)) {
+                next_promotion = self.clock.now();
+                next_demotion = next_promotion;
+            }
+        }
+    }
+
+    // Note that a pass of the background loop `name` has started, for
+    // `drive-manager status`
+    fn pass_started(&self, name: &str) -> SystemTime {
+        let now = self.clock.now();
+        if let Err(e) = self.db.lock().unwrap().start_loop_run(name, now) {
+            warn!("Failed to record the start of a {} pass: {}", name, e);
+        }
+        now
+    }
+
+    fn pass_finished<T>(&self, name: &str, started: SystemTime, result: &io::Result<T>, next_run: SystemTime) {
+        let duration = self.clock.now().duration_since(started).unwrap_or_default();
+        let error = result.as_ref().err().map(ToString::to_string);
+        if let Err(e) = self.db.lock().unwrap().finish_loop_run(name, duration, error.as_deref(), Some(next_run)) {
+            warn!("Failed to record the end of a {} pass: {}", name, e);
+        }
+    }
+
+    // A top-level setting, or the promotion or demotion section's own
+    fn pass_setting(&self, pass: &str, key: &str) -> Option<Value> {
+        let config = self.config();
+        config.get(pass).and_then(|section| section.get(key)).or_else(|| config.get(key)).cloned()
+    }
+
+    fn config(&self) -> Arc<Value> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    // Apply a config whose changes are all ones the running service can
+    // take without restarting
+    pub fn update_config(&self, config: Value) {
+        *self.config.write().unwrap() = Arc::new(config);
+    }
+
+    // Sleep until `due`, or until `drive-manager check-now` asks for a check.
+    // Returns whether one was asked for.
+    pub fn wait_for_next_check(&self, due: SystemTime) -> bool {
+        loop {
+            match self.db.lock().unwrap().take_check_request() {
+                Ok(true) => {
+                    info!("Running a tiering check on request");
+                    return true;
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Failed to read tiering check requests: {}", e),
+            }
+            let Ok(left) = due.duration_since(self.clock.now()) else { return false };
+            if left.is_zero() {
+                return false;
+            }
+            self.clock.sleep(left.min(Duration::from_secs(CHECK_REQUEST_POLL_SEC)));
+        }
+    }
+
+    pub fn file_mover_loop(self: &Arc<Self>) {
+        // one pool per source->target lane, so a slow warm->cold rsync cannot
+        // take workers away from hot->warm demotions
+        let mut executors: HashMap<(String, String), threadpool::ThreadPool> = HashMap::new();
+        loop {
+            let queued = self.move_queue.pop_ready(|info| self.lane_limit(&info.source_tier, &info.target_tier));
+            self.wait_for_jobs();
+            self.wait_for_quiet();
+            if queued.priority == Priority::Background {
+                self.wait_for_mains();
+            }
+            let lane = (queued.info.source_tier.clone(), queued.info.target_tier.clone());
+            let executor = executors.entry(lane).or_insert_with(|| {
+                threadpool::ThreadPool::new(self.move_workers(&queued.info.source_tier, &queued.info.target_tier))
+            });
+            let tm = Arc::clone(self);
+            executor.execute(move || {
+                let info = queued.info.clone();
+                tm.move_file(queued);
+                tm.move_queue.finish(&info);
+            });
+        }
+    }
+
+    pub fn load_monitor_loop(&self, interval: Duration) {
+        loop {
+            let started = self.pass_started("load monitor");
+            let result = self.check_load();
+            if let Err(e) = &result {
+                warn!("Failed to read system load: {}", e);
+            }
+            self.pass_finished("load monitor", started, &result, self.clock.now() + interval);
+            self.clock.sleep(interval);
+        }
+    }
+
+    // Sample the system and decide how background moves should run. While
+    // our own moves are copying the disks are busy with them, so only load
+    // and memory pressure count until they finish.
+    pub fn check_load(&self) -> io::Result<Throttle> {
+        let Some(limits) = &self.load_limits else { return Ok(Throttle::Normal) };
+        let copying = !self.transfers.lock().unwrap().is_empty();
+        let mut sample = self.load_monitor.lock().unwrap().sample(self.clock.now(), !copying)?;
+        if !copying {
+            sample.slowest_disk = self.disk_io.lock().unwrap().iter()
+                .map(|(serial, io)| (serial.clone(), io.latency_ms()))
+                .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(&a.0)));
+        }
+        let (throttle, reason) = limits.throttle(&sample);
+        let mut current = self.throttle.lock().unwrap();
+        if *current != throttle {
+            match (throttle, reason) {
+                (Throttle::Pause, Some(reason)) => info!("Pausing background moves, {}", reason),
+                (Throttle::Slow, Some(reason)) => info!("Slowing background moves, {}", reason),
+                _ => info!("System is idle, resuming background moves"),
+            }
+            *current = throttle;
+        }
+        Ok(throttle)
+    }
+
+    // The drives whose I/O is sampled, as (serial, kernel name)
+    pub fn set_drives(&self, drives: Vec<(String, String)>) {
+        *self.drives.lock().unwrap() = drives;
+    }
+
+    // A drive attached while running
+    pub fn add_drive(&self, serial: String, kernel_name: String) {
+        self.drives.lock().unwrap().push((serial, kernel_name));
+    }
+
+    pub fn disk_io(&self) -> HashMap<String, DiskIo> {
+        self.disk_io.lock().unwrap().clone()
+    }
+
+    pub fn disk_stats_loop(&self, interval: Duration) {
+        loop {
+            let started = self.pass_started("disk stats");
+            let result = self.sample_disk_io();
+            if let Err(e) = &result {
+                warn!("Failed to sample drive I/O: {}", e);
+            }
+            self.pass_finished("disk stats", started, &result, self.clock.now() + interval);
+            self.clock.sleep(interval);
+        }
+    }
+
+    // Sample the managed drives' I/O, keeping the history in the database
+    // for `status` and the latest for load throttling
+    pub fn sample_disk_io(&self) -> io::Result<()> {
+        let now = self.clock.now();
+        let drives = self.drives.lock().unwrap().clone();
+        let samples = self.disk_stats.lock().unwrap().sample(now, &drives)?;
+        self.db.lock().unwrap().transaction(|db| {
+            for (serial, io) in &samples {
+                db.record_disk_io(serial, now, io)?;
+            }
+            db.prune_disk_io(now - disk_stats::keep_for(&self.config())).map(|_| ())
+        })?;
+        *self.disk_io.lock().unwrap() = samples.into_iter().collect();
+        self.check_write_skew()
+    }
+
+    // Warn when a drive starts taking far more than its share of its tier's
+    // writes, with a plan to even them out, and again once it no longer does
+    pub fn check_write_skew(&self) -> io::Result<()> {
+        let config = self.config();
+        let policy = WriteSkewPolicy::from_config(&config);
+        let (written, branch_tiers) = {
+            let db = self.db.lock().unwrap();
+            (db.bytes_written_since(self.clock.now() - policy.window)?, db.branch_tiers()?)
+        };
+        let tiers = write_skew::tier_writes(&branch_tiers, &written);
+        let skewed = policy.skewed(&tiers);
+        let mut was_skewed = self.write_skewed.lock().unwrap();
+        for skew in skewed.iter().filter(|skew| !was_skewed.contains(&skew.serial)) {
+            let plan = write_skew::rebalance_plan(&tiers[&skew.tier]);
+            let advice = write_skew::policy_advice(&config, &skew.tier);
+            warn!(
+                "{} took {:.1}x its share of the {} tier's writes over the last {}h{}",
+                skew.serial, skew.ratio, skew.tier, policy.window.as_secs() / 3600,
+                advice.as_ref().map_or(String::new(), |advice| format!(": {}", advice)),
+            );
+            for planned in &plan {
+                info!("To even out the {} tier, {}", skew.tier, planned);
+            }
+            events::record("write_skew", json!({
+                "serial": skew.serial, "tier": skew.tier, "ratio": skew.ratio, "bytes": skew.bytes, "advice": advice,
+                "plan": plan.iter().map(|planned| json!({ "from": planned.from, "to": planned.to, "bytes": planned.bytes })).collect::<Vec<_>>(),
+            }));
+        }
+        let now_skewed: HashSet<String> = skewed.into_iter().map(|skew| skew.serial).collect();
+        for serial in was_skewed.difference(&now_skewed) {
+            info!("{}'s writes are back in line with the rest of its tier", serial);
+            events::record("write_skew_cleared", json!({ "serial": serial }));
+        }
+        *was_skewed = now_skewed;
+        Ok(())
+    }
+
+    pub fn power_monitor_loop(&self, interval: Duration) {
+        loop {
+            let started = self.pass_started("power monitor");
+            let result = self.check_power();
+            if let Err(e) = &result {
+                warn!("Failed to read power state: {}", e);
+            }
+            self.pass_finished("power monitor", started, &result, self.clock.now() + interval);
+            self.clock.sleep(interval);
+        }
+    }
+
+    // Going on battery holds back background moves, which a power cut would
+    // leave half copied, and gets the database safely onto disk
+    pub fn check_power(&self) -> io::Result<PowerState> {
+        let Some(power) = &self.power else { return Ok(PowerState::Mains) };
+        let state = power.state(&SystemExecutor)?;
+        let on_battery = state == PowerState::Battery;
+        if self.on_battery.swap(on_battery, Ordering::SeqCst) != on_battery {
+            if on_battery {
+                warn!("Running on battery, suspending background moves");
+                events::record("on_battery", json!({}));
+                for progress in self.transfers() {
+                    self.save_progress(&progress);
+                }
+                self.db.lock().unwrap().flush()?;
+            } else {
+                info!("Mains power is back, resuming background moves");
+                events::record("on_mains", json!({}));
+            }
+        }
+        Ok(state)
+    }
+
+    fn wait_for_mains(&self) {
+        let Some(power) = &self.power else { return };
+        while self.on_battery.load(Ordering::SeqCst) {
+            self.clock.sleep(power.check_interval);
+        }
+    }
+
+    fn wait_for_quiet(&self) {
+        let Some(limits) = &self.load_limits else { return };
+        while *self.throttle.lock().unwrap() == Throttle::Pause {
+            self.clock.sleep(limits.check_interval);
+        }
+    }
+
+    // Moves allowed at once in a lane right now: one while the system is
+    // busy, otherwise the configured workers
+    fn lane_limit(&self, source_tier: &str, target_tier: &str) -> usize {
+        match *self.throttle.lock().unwrap() {
+            Throttle::Slow => 1,
+            _ => self.move_workers(source_tier, target_tier),
+        }
+    }
+
+    // Concurrent moves allowed from source_tier to target_tier. The
+    // move_workers config takes "hot->warm" style lanes first, then
+    // "promote"/"demote", falling back to --threads.
+    pub fn move_workers(&self, source_tier: &str, target_tier: &str) -> usize {
+        let config = self.config();
+        let workers = config.get("move_workers");
+        let direction = if tier_rank(target_tier) < tier_rank(source_tier) { "promote" } else { "demote" };
+        workers.and_then(|workers| workers.get(format!("{}->{}", source_tier, target_tier)))
+            .or_else(|| workers.and_then(|workers| workers.get(direction)))
+            .and_then(Value::as_u64)
+            .map(|count| count as usize)
+            .unwrap_or(self.args.threads)
+            .max(1)
+    }
+
+    pub fn mount_watch_loop(&self, mounts: Vec<PathBuf>) {
+        let mut watcher = match MountWatcher::new(&mounts) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                // most likely fs.inotify.max_user_watches is too low for the tree
+                warn!("Not watching the merged mounts, renames are picked up by scans instead: {}", e);
+                return;
+            }
+        };
+        info!("Watching {} directories on the merged mounts for renames", watcher.watch_count());
+        loop {
+            match watcher.next_events() {
+                Ok(events) => {
+                    if let Err(e) = self.apply_mount_events(&events) {
+                        error!("Failed to record renames and deletions: {}", e);
+                    }
+                }
+                Err(e) => {
+                    error!("Stopped watching the merged mounts: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn cold_read_loop(&self, mounts: Vec<PathBuf>) {
+        let mut watcher = match ReadWatcher::new(&mounts) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("Not watching reads, cold files wait for the next check to be promoted: {}", e);
+                return;
+            }
+        };
+        info!("Watching reads through the merged mounts to promote cold files");
+        loop {
+            match watcher.next_reads() {
+                Ok(paths) => {
+                    for path in paths {
+                        if let Err(e) = self.record_cold_read(&path) {
+                            error!("Failed to count a read of {}: {}", path.display(), e);
+                        }
+                    }
+                    if let Some(bursts) = &self.cold_reads {
+                        bursts.lock().unwrap().expire(self.clock.now());
+                    }
+                }
+                Err(e) => {
+                    error!("Stopped watching reads: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn access_loop(&self, mounts: Vec<PathBuf>) {
+        let mut watcher = match ReadWatcher::opens(&mounts) {
+            Ok(watcher) => watcher,
+            Err(e) if activity::noatime(&fs::read_to_string("/proc/self/mounts").unwrap_or_default(), &mounts) => {
+                warn!("Not watching opens, and the mounts are noatime, so access counts come from writes at each scan instead: {}", e);
+                self.activity_watch.store(true, Ordering::SeqCst);
+                return;
+            }
+            Err(e) => {
+                warn!("Not watching opens, access counts come from atimes at each scan instead: {}", e);
+                return;
+            }
+        };
+        info!("Counting opens through the merged mounts for file heat");
+        self.access_watch.store(true, Ordering::SeqCst);
+        let mut flushed = self.clock.now();
+        loop {
+            match watcher.next_reads() {
+                Ok(paths) => {
+                    self.record_opens(&paths);
+                    if self.clock.now().duration_since(flushed).is_ok_and(|since| since >= Duration::from_secs(ACCESS_FLUSH_INTERVAL)) {
+                        if let Err(e) = self.flush_opens() {
+                            error!("Failed to record file accesses: {}", e);
+                        }
+                        flushed = self.clock.now();
+                    }
+                }
+                Err(e) => {
+                    error!("Stopped watching opens, access counts come from atimes again: {}", e);
+                    self.access_watch.store(false, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn record_opens(&self, paths: &[PathBuf]) {
+        let now = self.clock.now();
+        let mut opens = self.opens.lock().unwrap();
+        for path in paths {
+            opens.insert(path.clone(), now);
+        }
+    }
+
+    // Count each file opened since the last flush once, as a scan counts a
+    // newer atime. A single sequential pass and scratch files are not counted
+    // towards promotion. Files not scanned yet are left to the scan that
+    // finds them. Returns how many files were counted.
+    pub fn flush_opens(&self) -> io::Result<usize> {
+        let opens = std::mem::take(&mut *self.opens.lock().unwrap());
+        if opens.is_empty() {
+            return Ok(0);
+        }
+        let sequential: HashSet<PathBuf> = self.reads_since_scan.lock().unwrap().iter()
+            .filter(|(_, pattern)| **pattern == ReadPattern::Sequential)
+            .map(|(path, _)| path.clone())
+            .collect();
+        self.db.lock().unwrap().transaction(|db| {
+            let mut counted = 0;
+            for (path, at) in opens {
+                let Some(mut metadata) = db.get(&path)? else { continue };
+                if at <= metadata.last_access_time {
+                    continue;
+                }
+                if scratch::find(&self.scratch_dirs, &path).is_none() && !sequential.contains(&path) {
+                    metadata.access_count += 1;
+                    counted += 1;
+                }
+                metadata.last_access_time = at;
+                db.insert(&path, &metadata)?;
+            }
+            Ok(counted)
+        })
+    }
+
+    // Count a read of a file on the bottom tier, and queue its promotion
+    // once cold_read_promotion's reads land within its window. Files that are
+    // tagged, excluded or scratch are left to their own rules. Returns
+    // whether the file was queued.
+    pub fn record_cold_read(&self, path: &Path) -> io::Result<bool> {
+        let Some(bursts) = &self.cold_reads else { return Ok(false) };
+        let Some(metadata) = self.file_metadata(path)? else { return Ok(false) };
+        if metadata.tier != DriveManager::TIERS[DriveManager::TIERS.len() - 1] || scratch::find(&self.scratch_dirs, path).is_some() {
+            return Ok(false);
+        }
+        let placement = self.db.lock().unwrap().placement(path)?;
+        if placement.exclude || placement.tier.is_some() || !bursts.lock().unwrap().record(path, self.clock.now()) {
+            return Ok(false);
+        }
+        let Some(target_tier) = self.tier_jumps.promotion_target(path, metadata.file_size, &metadata.tier) else { return Ok(false) };
+        info!("{} was read repeatedly from {}, promoting it to {}", path.display(), metadata.tier, target_tier);
+        let reason = format!("read repeatedly from {}", metadata.tier);
+        self.queue_file_move_for(path.to_path_buf(), metadata.tier, target_tier, reason);
+        Ok(true)
+    }
+
+    // Carry a file's heat history over when it is renamed through a merged
+    // mount and forget it when it is deleted, rather than waiting for the
+    // next scan to drop the old path and start the new one from scratch
+    pub fn apply_mount_events(&self, events: &[MountEvent]) -> io::Result<()> {
+        self.track_new_files(events);
+        self.db.lock().unwrap().transaction(|db| {
+            for event in events {
+                match event {
+                    MountEvent::Renamed { from, to, dir: false } => db.rename(from, to)?,
+                    MountEvent::Renamed { from, to, dir: true } => {
+                        for (path, _) in db.entries_under(from)? {
+                            db.rename(&path, to.join(path.strip_prefix(from).unwrap()))?;
+                        }
+                    }
+                    MountEvent::Written { .. } => {}
+                    MountEvent::Deleted { path, dir: false } => db.remove(path)?,
+                    MountEvent::Deleted { path, dir: true } => {
+                        for (path, _) in db.entries_under(path)? {
+                            db.remove(&path)?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Note files written through the mounts for the ingest rules, following
+    // them through renames. Files the scans already know are not new.
+    fn track_new_files(&self, events: &[MountEvent]) {
+        let Some(ingest) = &self.ingest else { return };
+        let mut pending = self.ingest_pending.lock().unwrap();
+        for event in events {
+            match event {
+                MountEvent::Written { path, tier } => {
+                    if pending.contains_key(path) || self.file_metadata(path).is_ok_and(|metadata| metadata.is_none()) {
+                        pending.insert(path.clone(), (tier.clone(), self.clock.now() + ingest.delay));
+                    }
+                }
+                MountEvent::Renamed { from, to, .. } => {
+                    let moved: Vec<PathBuf> = pending.keys().filter(|path| path.starts_with(from)).cloned().collect();
+                    for path in moved {
+                        let entry = pending.remove(&path).unwrap();
+                        pending.insert(to.join(path.strip_prefix(from).unwrap()), entry);
+                    }
+                }
+                MountEvent::Deleted { path, .. } => pending.retain(|pending, _| !pending.starts_with(path)),
+            }
+        }
+    }
+
+    pub fn ingest_loop(&self) {
+        let interval = Duration::from_secs(INGEST_POLL_INTERVAL);
+        loop {
+            let started = self.pass_started("ingest");
+            let placed = self.place_new_files();
+            self.pass_finished("ingest", started, &Ok(placed), self.clock.now() + interval);
+            self.clock.sleep(interval);
+        }
+    }
+
+    // Queue the new files that have settled for the tier their ingest rule
+    // gives them. Returns how many were queued.
+    pub fn place_new_files(&self) -> usize {
+        let Some(ingest) = &self.ingest else { return 0 };
+        let now = self.clock.now();
+        let due: Vec<(PathBuf, String)> = {
+            let mut pending = self.ingest_pending.lock().unwrap();
+            let due: Vec<PathBuf> = pending.iter().filter(|(_, (_, at))| *at <= now).map(|(path, _)| path.clone()).collect();
+            due.into_iter().map(|path| {
+                let (tier, _) = pending.remove(&path).unwrap();
+                (path, tier)
+            }).collect()
+        };
+        let mut queued = 0;
+        for (path, tier) in due {
+            // gone, or moved by something else since
+            let Some(size) = self.storage.file_size(&path, &tier) else { continue };
+            if let Some(target_tier) = ingest.tier_for(&path, size).filter(|target| *target != tier) {
+                info!("Placing new file {} in {}", path.display(), target_tier);
+                self.queue_file_move_for(path, tier, target_tier.to_string(), "new file, placed by the ingest rules".to_string());
+                queued += 1;
+            }
+        }
+        queued
+    }
+
+    pub fn retry_loop(&self) {
+        let interval = Duration::from_secs(RETRY_POLL_INTERVAL);
+        loop {
+            let started = self.pass_started("retries");
+            self.requeue_due_retries();
+            let result = self.requeue_requested_failures();
+            if let Err(e) = &result {
+                error!("Failed to read retry requests: {}", e);
+            }
+            if let Err(e) = self.advance_drains() {
+                error!("Failed to drain drives: {}", e);
+            }
+            self.pass_finished("retries", started, &result, self.clock.now() + interval);
+            self.clock.sleep(interval);
+        }
+    }
+
+    pub fn read_sampling_loop(&self, interval: Duration) {
+        loop {
+            let started = self.pass_started("read sampling");
+            let result = self.sample_reads();
+            if let Err(e) = &result {
+                warn!("Failed to sample open files: {}", e);
+            }
+            self.pass_finished("read sampling", started, &result, self.clock.now() + interval);
+            self.clock.sleep(interval);
+        }
+    }
+
+    // Follow the offsets of open read fds, so a file read once from front to
+    // back, like a movie being played, is not taken as a sign it is hot
+    pub fn sample_reads(&self) -> io::Result<()> {
+        let finished = self.read_tracker.lock().unwrap().sample(self.storage.readers()?);
+        let mut reads = self.reads_since_scan.lock().unwrap();
+        for (path, pattern) in finished {
+            debug!("{:?} read of {} finished", pattern, path.display());
+            let merged = reads.get(&path).map_or(pattern, |previous| previous.merge(pattern));
+            reads.insert(path, merged);
+        }
+        Ok(())
+    }
+
+    pub fn maintenance_loop(&self) {
+        loop {
+            let started = self.pass_started("maintenance");
+            let result = self.validate_and_update_database().and_then(|()| self.clean_scratch_dirs()).and_then(|()| self.expire_ttl_files());
+            let wait = match &result {
+                Ok(()) => Duration::from_secs(MAINTENANCE_INTERVAL),
+                Err(e) => {
+                    error!("Error in maintenance loop: {}", e);
+                    // wait an hour before trying again if there's an error
+                    Duration::from_secs(3600)
+                }
+            };
+            self.pass_finished("maintenance", started, &result, self.clock.now() + wait);
+            self.clock.sleep(wait);
+        }
+    }
+
+    // Schedule a failed move for another attempt after its backoff, or move
+    // it to the dead-letter list once it has used up its retries. Permission
+    // errors, such as an immutable file, go there straight away since
+    // retrying will not change them. A copy that ran out of space is retried
+    // at once, the storage picking another destination, and an I/O error
+    // marks the drive the file is on suspect. A move with nowhere to go but
+    // drives held by running jobs waits for them like a deferred one.
+    fn retry_move(&self, mut queued: QueuedMove, error: &io::Error) -> MoveOutcome {
+        if storage::is_held(error) {
+            info!("Deferring move of {}: {}", queued.info.src.display(), error);
+            return self.defer(queued);
+        }
+        let class = retry::classify(error);
+        if class == ErrorClass::Io {
+            self.mark_suspect(&queued.info, error);
+            if let Some(outcome) = self.quarantine_if_failing(&queued, error) {
+                return outcome;
+            }
+        }
+        if class != ErrorClass::Permission && queued.info.retries < self.retry_policy.max_retries {
+            queued.info.retries += 1;
+            let delay = if class == ErrorClass::NoSpace { Duration::ZERO } else { self.retry_policy.backoff(queued.info.retries, &queued.info.src) };
+            warn!(
+                "Failed to move file {} from {} to {}: {}. Retry {} in {}s.",
+                queued.info.src.display(), queued.info.source_tier, queued.info.target_tier, error, queued.info.retries, delay.as_secs(),
+            );
+            let retry = PendingRetry { info: queued.info.clone(), due_at: self.clock.now() + delay };
+            // saved so the retry outlives a restart
+            let saved = self.db.lock().unwrap().transaction(|db| {
+                db.record_move_error(&retry.info.src, self.clock.now(), &error.to_string())?;
+                db.schedule_retry(&retry)
+            });
+            if let Err(e) = saved {
+                error!("Failed to save retry of {}: {}", queued.info.src.display(), e);
+            }
+            self.retry_schedule.lock().unwrap().push((retry.due_at, queued));
+            MoveOutcome::RetryScheduled
+        } else {
+            error!(
+                "Failed to move file from {} to {} after {} retries: {}: {}",
+                queued.info.source_tier, queued.info.target_tier, queued.info.retries, queued.info.src.display(), error,
+            );
+            let failure = FailedMove { info: queued.info.clone(), error: error.to_string(), failed_at: self.clock.now() };
+            let serial = self.storage.branch_of(&failure.info.src, &failure.info.source_tier);
+            events::record("move_failed", json!({ "path": failure.info.src, "serial": serial, "from": failure.info.source_tier, "to": failure.info.target_tier, "error": failure.error }));
+            let recorded = self.db.lock().unwrap().transaction(|db| {
+                db.record_move_error(&failure.info.src, failure.failed_at, &failure.error)?;
+                db.remove_pending_retry(&failure.info.src)?;
+                db.record_failure(&failure)
+            });
+            if let Err(e) = recorded {
+                error!("Failed to record failed move of {}: {}", queued.info.src.display(), e);
+            }
+            queued.respond(Err(io::Error::new(error.kind(), format!("failed to move {} after {} retries: {}", queued.info.src.display(), queued.info.retries, error))));
+            MoveOutcome::DeadLettered
+        }
+    }
+
+    // Quarantine the file once this error makes quarantine_after read or
+    // checksum errors in its history, which is bad sectors more likely than a
+    // passing fault, rather than retrying it without end. Its history is
+    // kept for `drive-manager problem-files`.
+    fn quarantine_if_failing(&self, queued: &QueuedMove, error: &io::Error) -> Option<MoveOutcome> {
+        let path = queued.info.src.clone();
+        let history = match self.db.lock().unwrap().move_errors(&path) {
+            Ok(history) => history,
+            Err(e) => {
+                error!("Failed to look up the errors of {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let io_errors = 1 + history.iter().filter(|(_, message)| retry::classify(&io::Error::other(message.clone())) == ErrorClass::Io).count();
+        if io_errors < self.retry_policy.quarantine_after as usize {
+            return None;
+        }
+        let reason = format!("{} read or checksum errors", io_errors);
+        warn!("Quarantining {} after {}, it will not be moved again until it is cleared: {}", path.display(), reason, error);
+        events::record("file_quarantined", json!({ "path": path, "tier": queued.info.source_tier, "errors": io_errors, "error": error.to_string() }));
+        let now = self.clock.now();
+        let recorded = self.db.lock().unwrap().transaction(|db| {
+            db.record_move_error(&path, now, &error.to_string())?;
+            db.remove_pending_retry(&path)?;
+            db.quarantine_file(&path, now, &reason)
+        });
+        if let Err(e) = recorded {
+            error!("Failed to quarantine {}: {}", path.display(), e);
+        }
+        queued.respond(Err(io::Error::new(error.kind(), format!("{} was quarantined after {}: {}", path.display(), reason, error))));
+        Some(MoveOutcome::Quarantined)
+    }
+
+    // Stop writing to the drive a move hit an I/O error on, and record it
+    // for `drive-manager status`. The file is still on its source branch.
+    fn mark_suspect(&self, info: &FileMoveInfo, error: &io::Error) {
+        let Some(serial) = self.storage.branch_of(&info.src, &info.source_tier) else { return };
+        warn!("Drive {} gave an I/O error moving {}, no more moves will write to it: {}", serial, info.src.display(), error);
+        self.storage.avoid_branch(&serial);
+        events::record("drive_io_error", json!({ "serial": serial, "path": info.src, "error": error.to_string() }));
+        if let Err(e) = self.db.lock().unwrap().record_io_error(&serial, self.clock.now(), &error.to_string()) {
+            error!("Failed to record the I/O error on {}: {}", serial, e);
+        }
+    }
+
+    // Put retries whose backoff has passed back on the move queue
+    pub fn requeue_due_retries(&self) -> usize {
+        let now = self.clock.now();
+        let mut schedule = self.retry_schedule.lock().unwrap();
+        let (due, waiting): (Vec<_>, Vec<_>) = schedule.drain(..).partition(|(at, _)| *at <= now);
+        *schedule = waiting;
+        drop(schedule);
+        let mut count = 0;
+        for (_, queued) in due {
+            // a retry dropped with `drive-manager failures drop` is gone from
+            // the database; deferred moves that never failed were never in it
+            if queued.info.retries > 0 && matches!(self.db.lock().unwrap().is_retry_pending(&queued.info.src), Ok(false)) {
+                info!("Dropping retry of {}", queued.info.src.display());
+                queued.respond(Err(io::Error::new(io::ErrorKind::Interrupted, format!("retry of {} was dropped", queued.info.src.display()))));
+                continue;
+            }
+            self.move_queue.requeue(queued);
+            count += 1;
+        }
+        count
+    }
+
+    // Pick up the moves the last run did not finish. Those that were
+    // copying, as recorded in move_progress, have their temp files removed
+    // and are settled by the storage: one whose copy was already in place
+    // loses its source, and one whose source is still there is queued
+    // again. Those only queued, as recorded in the move journal, are queued
+    // again while their source is where it was. Returns how many.
+    pub fn recover_interrupted_moves(&self) -> io::Result<usize> {
+        // left for the next run that moves things
+        if self.args.observe {
+            return Ok(0);
+        }
+        let (interrupted, journaled) = {
+            let db = self.db.lock().unwrap();
+            (db.transfers()?, db.journaled_moves()?)
+        };
+        let paths: Vec<PathBuf> = interrupted.iter().map(|transfer| transfer.info.src.clone()).collect();
+        for file in self.storage.remove_temp_files(&paths)? {
+            info!("Removed temp file {} left by an interrupted move", file.display());
+        }
+        // what is queued again goes back in the journal
+        self.db.lock().unwrap().clear_journal()?;
+        let mut resumed = 0;
+        for transfer in interrupted {
+            let info = transfer.info;
+            // a move cut off after the copy was renamed into place and the
+            // source removed is done; the next scan records it
+            if self.storage.settle_interrupted_move(&info.src, &info.source_tier, &info.target_tier)? {
+                info!("Resuming move of {} from {} to {}", info.src.display(), info.source_tier, info.target_tier);
+                self.queue_file_move_for(info.src, info.source_tier, info.target_tier, "resuming an interrupted move".to_string());
+                resumed += 1;
+            }
+        }
+        for journaled in journaled.into_iter().filter(|journaled| !paths.contains(&journaled.info.src)) {
+            let info = journaled.info;
+            if self.storage.branch_of(&info.src, &info.source_tier).is_none() {
+                debug!("Dropping journaled move of {}, it is no longer on {}", info.src.display(), info.source_tier);
+                continue;
+            }
+            info!("Requeueing move of {} from {} to {}", info.src.display(), info.source_tier, info.target_tier);
+            let priority = if journaled.user { Priority::User } else { Priority::Background };
+            self.enqueue(QueuedMove { info, priority, size: journaled.size, replies: Vec::new() });
+            resumed += 1;
+        }
+        // nothing is copying yet; the rows are stale
+        self.db.lock().unwrap().clear_progress()?;
+        Ok(resumed)
+    }
+
+    // Schedule the retries a previous run saved, at the times they were due
+    pub fn restore_retries(&self) -> io::Result<usize> {
+        let pending = self.db.lock().unwrap().pending_retries()?;
+        let mut schedule = self.retry_schedule.lock().unwrap();
+        let mut restored = 0;
+        for retry in pending {
+            if !schedule.iter().any(|(_, queued)| queued.info.src == retry.info.src) {
+                schedule.push((retry.due_at, QueuedMove::background(retry.info)));
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    // Requeue dead-lettered moves flagged by `drive-manager failures retry`
+    pub fn requeue_requested_failures(&self) -> io::Result<usize> {
+        let failures = self.db.lock().unwrap().take_retry_requests()?;
+        for failure in &failures {
+            // the file may have been moved by hand since it failed
+            let source_tier = self.file_metadata(&failure.info.src)?.map(|metadata| metadata.tier).unwrap_or(failure.info.source_tier.clone());
+            info!("Retrying failed move of {} to {}", failure.info.src.display(), failure.info.target_tier);
+            self.queue_file_move_for(failure.info.src.clone(), source_tier, failure.info.target_tier.clone(), "retrying a failed move".to_string());
+        }
+        Ok(failures.len())
+    }
+
+    // Work through the drains asked for with `drive-manager drain`. A drain
+    // moves every file on its branch to the other branches of the tier,
+    // ahead of other moves. Once none are left to try, each file is checked
+    // to be on another branch at its old size, files written to the branch
+    // meanwhile are sent after them, and the branch is taken out of the pools
+    // and unmounted. After a restart the files left are queued again.
+    pub fn advance_drains(&self) -> io::Result<()> {
+        let drains = self.db.lock().unwrap().drains()?;
+        for drain in drains.into_iter().filter(|drain| matches!(drain.state, DrainState::Requested | DrainState::Moving)) {
+            self.storage.avoid_branch(&drain.serial);
+            let queued = self.drain_files.lock().unwrap().get(&drain.serial).cloned();
+            let Some(files) = queued.filter(|_| drain.state == DrainState::Moving) else {
+                let files = self.queue_drain(&drain.serial, &HashSet::new())?;
+                info!("Draining {}: moving {} files to the other drives in its tier", drain.serial, files.len());
+                self.db.lock().unwrap().update_drain(&drain.serial, DrainState::Moving, files.len() as u64, None)?;
+                self.drain_files.lock().unwrap().insert(drain.serial, files);
+                continue;
+            };
+            let pending = files.iter().filter(|(path, _, _)| self.move_queue.is_queued_or_in_flight(path) || self.is_retry_scheduled(path)).count();
+            if pending > 0 {
+                self.db.lock().unwrap().update_drain(&drain.serial, DrainState::Moving, pending as u64, None)?;
+                continue;
+            }
+            let stragglers = self.queue_drain(&drain.serial, &files.iter().map(|(path, _, _)| path.clone()).collect())?;
+            if !stragglers.is_empty() {
+                info!("Draining {}: {} more files were written to it meanwhile", drain.serial, stragglers.len());
+                self.db.lock().unwrap().update_drain(&drain.serial, DrainState::Moving, stragglers.len() as u64, None)?;
+                self.drain_files.lock().unwrap().get_mut(&drain.serial).unwrap().extend(stragglers);
+                continue;
+            }
+            let unmoved: Vec<String> = files.iter().filter(|(path, tier, size)| match self.storage.branch_of(path, tier) {
+                Some(serial) => serial == drain.serial || self.storage.file_size(path, tier) != Some(*size),
+                // deleted, or moved to another tier, meanwhile
+                None => false,
+            }).map(|(path, _, _)| path.display().to_string()).collect();
+            self.drain_files.lock().unwrap().remove(&drain.serial);
+            let result = if unmoved.is_empty() {
+                self.storage.detach_branch(&drain.serial).map_err(|e| format!("could not detach it: {}", e))
+            } else {
+                Err(format!("{} files did not move off it: {}", unmoved.len(), unmoved.iter().take(5).cloned().collect::<Vec<_>>().join(", ")))
+            };
+            match result {
+                Ok(()) => {
+                    info!("Drained {}, it is out of the pools and unmounted", drain.serial);
+                    events::record("drained", json!({ "serial": drain.serial }));
+                    self.db.lock().unwrap().update_drain(&drain.serial, DrainState::Done, 0, None)?;
+                }
+                Err(e) => {
+                    error!("Draining {} failed: {}", drain.serial, e);
+                    events::record("drain_failed", json!({ "serial": drain.serial, "error": e }));
+                    self.db.lock().unwrap().update_drain(&drain.serial, DrainState::Failed, unmoved.len() as u64, Some(&e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Queue a move off `serial` for each file recorded on it but not in
+    // `skip`, returning them
+    fn queue_drain(&self, serial: &str, skip: &HashSet<PathBuf>) -> io::Result<Vec<DrainedFile>> {
+        let mut files = Vec::new();
+        let branches = self.db.lock().unwrap().branches()?;
+        for path in branches.into_iter().filter(|(path, branch)| branch == serial && !skip.contains(path)).map(|(path, _)| path) {
+            let Some(metadata) = self.file_metadata(&path)? else { continue };
+            if self.storage.branch_of(&path, &metadata.tier).as_deref() != Some(serial) {
+                continue;
+            }
+            self.enqueue(QueuedMove {
+                info: FileMoveInfo { src: path.clone(), source_tier: metadata.tier.clone(), target_tier: metadata.tier.clone(), retries: 0 },
+                priority: Priority::User,
+                size: metadata.file_size,
+                replies: Vec::new(),
+            });
+            files.push((path, metadata.tier, metadata.file_size));
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn is_retry_scheduled(&self, path: &Path) -> bool {
+        self.retry_schedule.lock().unwrap().iter().any(|(_, queued)| queued.info.src == path)
+    }
+
+    pub fn perform_tiering_check(&self) -> io::Result<()> {
+        self.perform_check(true, true)
+    }
+
+    // Promote files by the access rules and move files tagged for a tier
+    pub fn perform_promotion_check(&self) -> io::Result<()> {
+        self.perform_check(true, false)
+    }
+
+    // Note new drives and demote the coldest files of tiers that are full
+    pub fn perform_demotion_check(&self) -> io::Result<()> {
+        self.perform_check(false, true)
+    }
+
+    fn perform_check(&self, promote: bool, demote: bool) -> io::Result<()> {
+        let check = match (promote, demote) {
+            (true, false) => "Promotion",
+            (false, true) => "Demotion",
+            _ => "Tiering",
+        };
+        info!("Starting {} check", check.to_lowercase());
+        *self.plan.lock().unwrap() = Plan::default();
+        self.update_file_metadata()?;
+        if let Some(names) = self.paused_for() {
+            info!("Skipping tiering while {} runs", names);
+            return Ok(());
+        }
+        if demote {
+            self.seed_new_branches()?;
+            self.check_tier_capacities()?;
+            self.archive_cold_files()?;
+        }
+        if promote {
+            self.restore_read_archives()?;
+            self.move_files_based_on_rules()?;
+        }
+        if self.args.observe {
+            self.report_plan()?;
+        }
+        info!("{} check completed", check);
+        events::record("check", json!({ "check": check }));
+        Ok(())
+    }
+
+    // The moves the last --observe check queued, with the lane rates and
+    // tier usage to cost them
+    pub fn plan(&self) -> io::Result<Plan> {
+        let mut plan = self.plan.lock().unwrap().clone();
+        plan.rates = self.db.lock().unwrap().lane_rates()?;
+        plan.usage = DriveManager::TIERS.iter().map(|tier| Ok((tier.to_string(), self.storage.tier_usage(tier)?))).collect::<io::Result<_>>()?;
+        Ok(plan)
+    }
+
+    // Log what the moves an --observe check queued would cost
+    fn report_plan(&self) -> io::Result<()> {
+        let plan = self.plan()?;
+        if plan.is_empty() {
+            info!("Plan: nothing to move");
+            return Ok(());
+        }
+        for line in plan.to_string().lines() {
+            info!("{}", line);
+        }
+        Ok(())
+    }
+
+    pub fn update_file_metadata(&self) -> io::Result<()> {
+        self.flush_opens()?;
+        let scanned = self.storage.scan()?;
+        let farms = self.hardlink_farms.detect(&scanned);
+        self.report_farms(&farms);
+        let groups = hardlinks::link_groups(&scanned);
+        let mut excluded = HashSet::new();
+        if self.hardlink_farms.action == FarmAction::Exclude {
+            // a file linked from inside a farm stays put along with it
+            let in_farm = |path: &Path| hardlinks::in_farm(&farms, path) || groups.get(path).is_some_and(|links| links.iter().any(|link| hardlinks::in_farm(&farms, link)));
+            excluded.extend(scanned.iter().map(|file| &file.path).filter(|path| in_farm(path)).cloned());
+        }
+        *self.link_groups.lock().unwrap() = groups;
+        let reads = std::mem::take(&mut *self.reads_since_scan.lock().unwrap());
+        self.db.lock().unwrap().transaction(|db| {
+            self.record_offline_branches(db)?;
+            let held = self.held_files(db, &mut excluded)?;
+            self.record_scan(db, scanned, &reads, &held, &excluded)
+        })
+    }
+
+    // Record the files the first crawl of an adopted drive found, as a scan
+    // that had walked them would
+    pub fn record_crawled(&self, files: Vec<ScannedFile>) -> io::Result<()> {
+        self.db.lock().unwrap().transaction(|db| {
+            let mut excluded = HashSet::new();
+            let held = self.held_files(db, &mut excluded)?;
+            self.record_scan(db, files, &HashMap::new(), &held, &excluded)
+        })
+    }
+
+    // The files a scan holds on a tier, adding the files it leaves where
+    // they are to `excluded`
+    fn held_files(&self, db: &MetadataDb, excluded: &mut HashSet<PathBuf>) -> io::Result<Held> {
+        // staged files are held on hot over any pin, and pins from
+        // `drive-manager pin` win over the config's
+        let staged = db.staged_files(self.clock.now())?.into_iter().collect();
+        let pins = db.pins()?.into_iter().chain(self.pins.iter().cloned()).collect();
+        // a stub stays where it is until it is restored, and a
+        // quarantined file until `drive-manager problem-files clear`
+        excluded.extend(db.archived_files()?.into_iter().map(|file| file.path));
+        excluded.extend(db.quarantined_files()?.into_iter().map(|file| file.path));
+        Ok(Held { staged, pins })
+    }
+
+    // A branch we pooled before that is missing now, or failed its scan, is
+    // offline. Its files stay tracked but unavailable, and the pools carry
+    // on without it until it is back. Drained drives are gone for good.
+    fn record_offline_branches(&self, db: &MetadataDb) -> io::Result<()> {
+        let offline = self.storage.offline_branches();
+        let online: HashSet<String> = self.storage.branch_usage()?.into_iter().map(|branch| branch.serial).filter(|serial| !offline.contains(serial)).collect();
+        let drained: HashSet<String> = db.drains()?.into_iter().filter(|drain| drain.state == DrainState::Done).map(|drain| drain.serial).collect();
+        for serial in db.known_branches()? {
+            if online.contains(&serial) {
+                if db.set_branch_online(&serial)? {
+                    info!("Branch {} is back online", serial);
+                    events::record("drive_online", json!({ "serial": serial }));
+                }
+            } else if !drained.contains(&serial) && db.set_branch_offline(&serial, self.clock.now())? {
+                warn!("Branch {} is offline; its files are unavailable until it is back", serial);
+                events::record("drive_offline", json!({ "serial": serial }));
+            }
+        }
+        Ok(())
+    }
+
+    fn report_farms(&self, farms: &[PathBuf]) {
+        let mut known = self.farms.lock().unwrap();
+        for farm in farms.iter().filter(|farm| !known.contains(farm)) {
+            match self.hardlink_farms.action {
+                FarmAction::Exclude => warn!("{} is mostly hard links, like a backup repository; leaving it out of tiering", farm.display()),
+                FarmAction::Group => warn!("{} is mostly hard links, like a backup repository; moving the links to each file together", farm.display()),
+            }
+        }
+        for farm in known.iter().filter(|farm| !farms.contains(farm)) {
+            info!("{} is no longer mostly hard links", farm.display());
+        }
+        *known = farms.to_vec();
+    }
+
+    // Directories found to be mostly hard links by the last scan
+    pub fn hardlink_farms(&self) -> Vec<PathBuf> {
+        self.farms.lock().unwrap().clone()
+    }
+
+    fn record_scan(&self, db: &MetadataDb, mut scanned: Vec<ScannedFile>, reads: &HashMap<PathBuf, ReadPattern>, held: &Held, excluded: &HashSet<PathBuf>) -> io::Result<()> {
+        let now = self.clock.now();
+        if self.activity_watch.load(Ordering::SeqCst) {
+            self.activity_heat.apply(&mut scanned);
+        }
+        let mut known: HashMap<PathBuf, FileMetadata> = db.entries()?.into_iter().collect();
+        let mut placements = db.placements()?;
+        let mut branches = db.branches()?;
+        let mut relocated = 0;
+        let counting_opens = self.access_watch.load(Ordering::SeqCst);
+        for mut file in scanned {
+            // scratch files are pinned to their directory's tier whatever their tags say
+            let scratch = scratch::find(&self.scratch_dirs, &file.path);
+            if let Some(dir) = scratch {
+                file.placement = Placement { tier: Some(dir.tier.clone()), ..Default::default() };
+            } else {
+                // as is a pinned one, over any tier tag, while ttl_policy and
+                // then tier_policy only place files without a tag
+                if let Some(tier) = held.tier_for(&file.path) {
+                    file.placement.tier = Some(tier.to_string());
+                } else if file.placement.tier.is_none() {
+                    file.placement.tier = self.ttl.tier_for(&file.path, file.size, file.accessed, now)
+                        .or_else(|| self.policy.tier_for(&file.path, file.size, file.accessed, now))
+                        .map(str::to_string);
+                }
+                if excluded.contains(&file.path) {
+                    file.placement.exclude = true;
+                }
+            }
+            if placements.remove(&file.path).unwrap_or_default() != file.placement {
+                db.set_placement(&file.path, &file.placement)?;
+            }
+            let previous = known.remove(&file.path);
+            match branches.remove(&file.path) {
+                Some(serial) if serial == file.branch => {}
+                old => {
+                    // our own moves record the branch they land on, so a file
+                    // that changed branch within its tier was moved by
+                    // mergerfs, as moveonenospc does when a write fills a drive
+                    if let (Some(old), Some(previous)) = (old, &previous) {
+                        if previous.tier == file.tier {
+                            info!("{} was relocated by mergerfs from branch {} to {}", file.path.display(), old, file.branch);
+                            relocated += 1;
+                        }
+                    }
+                    db.set_branch(&file.path, &file.branch)?;
+                }
+            }
+            let metadata = match previous.clone() {
+                Some(mut file_info) => {
+                    // a newer atime than the one we recorded means the file
+                    // was read since the last scan, unless opens are counted
+                    // as they happen instead
+                    if file.accessed > file_info.last_access_time && !counting_opens {
+                        // a single sequential pass is not counted towards
+                        // promotion, and nothing in a scratch directory is
+                        if scratch.is_none() && reads.get(&file.path) != Some(&ReadPattern::Sequential) {
+                            file_info.access_count += 1;
+                        }
+                        file_info.last_access_time = file.accessed;
+                    }
+                    file_info.file_size = file.size;
+                    file_info.tier = file.tier;
+                    file_info
+                }
+                None => FileMetadata {
+                    last_access_time: file.accessed,
+                    access_count: 1,
+                    file_size: file.size,
+                    tier: file.tier,
+                    last_tier_move: None,
+                },
+            };
+            if previous.as_ref() != Some(&metadata) {
+                db.insert(&file.path, &metadata)?;
+            }
+        }
+        if relocated > 0 {
+            warn!("mergerfs relocated {} files between branches since the last scan", relocated);
+        }
+        Ok(())
+    }
+
+    // Upload the files on the lowest tier nobody read for archive.after_days,
+    // least recently read first, up to max_gb_per_check. Files pinned or
+    // tagged to a tier, excluded, hard linked or on an offline branch stay.
+    pub fn archive_cold_files(&self) -> io::Result<()> {
+        let Some(archive) = Archive::from_config(&self.config()) else { return Ok(()) };
+        let tier = DriveManager::TIERS[DriveManager::TIERS.len() - 1];
+        let now = self.clock.now();
+        let mut candidates: Vec<(PathBuf, FileMetadata)> = {
+            let db = self.db.lock().unwrap();
+            let archived: HashSet<PathBuf> = db.archived_files()?.into_iter().map(|file| file.path).collect();
+            let placements = db.placements()?;
+            let unavailable = db.unavailable_files()?;
+            let link_groups = self.link_groups.lock().unwrap();
+            db.entries()?.into_iter().filter(|(path, metadata)| {
+                let placement = placements.get(path).cloned().unwrap_or_default();
+                metadata.tier == tier
+                    && now.duration_since(metadata.last_access_time).unwrap_or_default() >= archive.after
+                    && !archived.contains(path) && !unavailable.contains(path) && !link_groups.contains_key(path)
+                    && placement.tier.is_none() && !placement.exclude
+            }).collect()
+        };
+        candidates.sort_by_key(|(_, metadata)| metadata.last_access_time);
+        let mut uploaded = 0;
+        for (path, metadata) in candidates {
+            if uploaded + metadata.file_size > archive.max_bytes_per_check {
+                break;
+            }
+            if self.has_active_readers(&path, tier) {
+                debug!("Not archiving {}, it is open", path.display());
+                continue;
+            }
+            if self.args.observe {
+                info!("Would archive {} ({} bytes)", path.display(), metadata.file_size);
+                uploaded += metadata.file_size;
+                continue;
+            }
+            // recorded before the stub takes the file's place, and forgotten
+            // again should that fail
+            let record = |url: &str| self.db.lock().unwrap().record_archived(&ArchivedFile { path: path.clone(), url: url.to_string(), size: metadata.file_size, archived_at: now });
+            match self.storage.archive_file(&path, tier, &archive, &record) {
+                Ok(url) => {
+                    uploaded += metadata.file_size;
+                    info!("Archived {} to {}", path.display(), url);
+                    events::record("archived", json!({ "path": path, "url": url, "size": metadata.file_size }));
+                }
+                Err(e) => {
+                    warn!("Failed to archive {}: {}", path.display(), e);
+                    self.db.lock().unwrap().remove_archived(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Fetch back the archived files whose stub was read since it was left.
+    // A stub that was deleted leaves its object in the bucket.
+    pub fn restore_read_archives(&self) -> io::Result<()> {
+        let Some(archive) = Archive::from_config(&self.config()) else { return Ok(()) };
+        let tier = DriveManager::TIERS[DriveManager::TIERS.len() - 1];
+        let archived = self.db.lock().unwrap().archived_files()?;
+        for file in archived {
+            if !self.storage.exists(&file.path) {
+                info!("The stub of {} is gone; its archived copy stays at {}", file.path.display(), file.url);
+                self.db.lock().unwrap().remove_archived(&file.path)?;
+                continue;
+            }
+            let read = self.db.lock().unwrap().get(&file.path)?.is_some_and(|metadata| metadata.last_access_time > file.archived_at);
+            if !read {
+                continue;
+            }
+            if self.args.observe {
+                info!("Would restore {} ({} bytes) from {}", file.path.display(), file.size, file.url);
+                continue;
+            }
+            match self.storage.restore_file(&file.path, tier, &archive) {
+                Ok(()) => {
+                    info!("Restored {} from {}", file.path.display(), file.url);
+                    events::record("restored", json!({ "path": file.path, "url": file.url, "size": file.size }));
+                    self.db.lock().unwrap().remove_archived(&file.path)?;
+                }
+                Err(e) => warn!("Failed to restore {} from {}: {}", file.path.display(), file.url, e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_tier_capacities(&self) -> io::Result<()> {
+        let threshold = self.pass_setting("demotion", "tier_capacity_threshold").as_ref().and_then(Value::as_f64).unwrap_or(85.0);
+        for tier in DriveManager::TIERS {
+            let usage = self.storage.tier_usage(tier)?;
+            if usage.total > 0 && usage.usage_percent() > threshold {
+                info!("Tier {} is {:.1}% full, moving files down", tier, usage.usage_percent());
+                events::record("tier_full", json!({ "tier": tier, "percent": usage.usage_percent() }));
+                self.move_files_down(tier)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn emergency_check_loop(&self) {
+        let interval = self.config().get("emergency_check_sec").and_then(Value::as_u64).unwrap_or(EMERGENCY_CHECK_INTERVAL);
+        let interval = Duration::from_secs(interval);
+        loop {
+            let started = self.pass_started("capacity check");
+            let result = self.check_branch_capacities();
+            if let Err(e) = &result {
+                error!("Error checking branch capacities: {}", e);
+            }
+            self.pass_finished("capacity check", started, &result, self.clock.now() + interval);
+            self.clock.sleep(interval);
+        }
+    }
+
+    // A tier can have room overall while one of its drives is all but full,
+    // since mergerfs places new files by its own policy. Such a branch has
+    // its coldest files moved ahead of other moves until it is back under
+    // tier_capacity_threshold: onto another branch of the tier that is under
+    // it, or else down a tier.
+    pub fn check_branch_capacities(&self) -> io::Result<()> {
+        let emergency = self.config().get("emergency_capacity_threshold").and_then(Value::as_f64).unwrap_or(EMERGENCY_CAPACITY_THRESHOLD);
+        let threshold = self.config().get("tier_capacity_threshold").and_then(Value::as_f64).unwrap_or(85.0);
+        let branches = self.storage.branch_usage()?;
+        for full in branches.iter().filter(|branch| branch.usage.total > 0 && branch.usage.usage_percent() > emergency) {
+            let target = (full.usage.total as f64 * threshold / 100.0) as u64;
+            let mut to_free = full.usage.used.saturating_sub(target);
+            warn!("Branch {} in {} is {:.1}% full, evicting its coldest files", full.serial, full.tier, full.usage.usage_percent());
+            // room left on the other branches of the tier, down to the threshold
+            let mut room: u64 = branches.iter()
+                .filter(|branch| branch.tier == full.tier && branch.serial != full.serial)
+                .map(|branch| ((branch.usage.total as f64 * threshold / 100.0) as u64).saturating_sub(branch.usage.used))
+                .max()
+                .unwrap_or(0);
+            let lower_tier = DriveManager::TIERS.get(tier_rank(&full.tier) + 1).copied();
+            let candidates = self.db.lock().unwrap().coldest_in_tier(&full.tier, usize::MAX, self.clock.now())?;
+            for (file_path, metadata) in candidates {
+                if to_free == 0 {
+                    break;
+                }
+                if self.storage.branch_of(&file_path, &full.tier).as_deref() != Some(full.serial.as_str()) || self.has_active_readers(&file_path, &full.tier) {
+                    continue;
+                }
+                let target_tier = if metadata.file_size <= room {
+                    room -= metadata.file_size;
+                    full.tier.clone()
+                } else if let Some(lower_tier) = lower_tier {
+                    lower_tier.to_string()
+                } else {
+                    warn!("Nowhere to evict {} to from branch {} in {}", file_path.display(), full.serial, full.tier);
+                    continue;
+                };
+                info!("Evicting {} from branch {} in {} to {}", file_path.display(), full.serial, full.tier, target_tier);
+                to_free = to_free.saturating_sub(metadata.file_size);
+                self.enqueue(QueuedMove {
+                    info: FileMoveInfo { src: file_path, source_tier: full.tier.clone(), target_tier, retries: 0 },
+                    priority: Priority::User,
+                    size: metadata.file_size,
+                    replies: Vec::new(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Note drives that joined a tier since the last check and, with
+    // rebalance_new_drives, seed them with existing data. The first check
+    // only learns the drives there are, since every one would look new.
+    pub fn seed_new_branches(&self) -> io::Result<()> {
+        let branches = self.storage.branch_usage()?;
+        let new: Vec<&BranchUsage> = {
+            let db = self.db.lock().unwrap();
+            let known = db.known_branches()?;
+            let new: Vec<&BranchUsage> = branches.iter().filter(|branch| !known.contains(&branch.serial)).collect();
+            for branch in &new {
+                db.add_known_branch(&branch.serial, &branch.tier, self.clock.now())?;
+            }
+            if known.is_empty() {
+                return Ok(());
+            }
+            new
+        };
+        let rebalance = self.config().get("rebalance_new_drives").and_then(Value::as_bool).unwrap_or(false);
+        for branch in new {
+            info!("Drive {} joined the {} tier", branch.serial, branch.tier);
+            events::record("drive_joined", json!({ "serial": branch.serial, "tier": branch.tier }));
+            if rebalance {
+                self.rebalance_onto(branch, &branches)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Queue background moves within `new`'s tier until it would hold its
+    // share of the tier's data by capacity. Moves within a tier go to the
+    // branch with the most room, which a new drive is. The most recently
+    // used files go first, so the directories in use get a foothold on the
+    // new drive for the files created in them next. Files grouped by
+    // keep_together rules go together. Returns the bytes queued.
+    pub fn rebalance_onto(&self, new: &BranchUsage, branches: &[BranchUsage]) -> io::Result<u64> {
+        let tier: Vec<&BranchUsage> = branches.iter().filter(|branch| branch.tier == new.tier).collect();
+        let total: u64 = tier.iter().map(|branch| branch.usage.total).sum();
+        let used: u64 = tier.iter().map(|branch| branch.usage.used).sum();
+        if total == 0 {
+            return Ok(0);
+        }
+        let share = (used as f64 * new.usage.total as f64 / total as f64) as u64;
+        let to_move = share.saturating_sub(new.usage.used);
+        let (mut candidates, on_branch, placements) = {
+            let db = self.db.lock().unwrap();
+            let unavailable = db.unavailable_files()?;
+            let candidates: Vec<(PathBuf, FileMetadata)> = db.entries()?.into_iter()
+                .filter(|(path, metadata)| metadata.tier == new.tier && !unavailable.contains(path))
+                .collect();
+            (candidates, db.branches()?, db.placements()?)
+        };
+        candidates.sort_by(|a, b| b.1.last_access_time.cmp(&a.1.last_access_time).then_with(|| a.0.cmp(&b.0)));
+        let sizes: HashMap<&Path, u64> = candidates.iter().map(|(path, metadata)| (path.as_path(), metadata.file_size)).collect();
+        let paths: Vec<PathBuf> = candidates.iter().map(|(path, _)| path.clone()).collect();
+        let movable = |path: &Path| on_branch.get(path) != Some(&new.serial) && !placements.get(path).is_some_and(|placement| placement.exclude);
+        let mut queued = HashSet::new();
+        let mut bytes = 0;
+        for (path, _) in &candidates {
+            if bytes >= to_move {
+                break;
+            }
+            if queued.contains(path) || !movable(path) || self.has_active_readers(path, &new.tier) {
+                continue;
+            }
+            let group = match self.keep_together.scope(path) {
+                Some(scope) => self.keep_together.members(path, &scope, &paths),
+                None => vec![path.clone()],
+            };
+            let group: Vec<PathBuf> = group.into_iter().filter(|member| movable(member) && !queued.contains(member)).collect();
+            let group_bytes: u64 = group.iter().map(|member| sizes.get(member.as_path()).copied().unwrap_or(0)).sum();
+            // a group that would take the drive past its share waits for
+            // the tier's usual moves
+            if bytes + group_bytes > to_move {
+                continue;
+            }
+            bytes += group_bytes;
+            for member in group {
+                queued.insert(member.clone());
+                let size = sizes.get(member.as_path()).copied().unwrap_or(0);
+                if self.args.observe {
+                    self.plan.lock().unwrap().add_move(PlannedMove { path: member.clone(), size, source_tier: new.tier.clone(), target_tier: new.tier.clone(), reason: format!("rebalancing onto new drive {}", new.serial) });
+                }
+                self.enqueue(QueuedMove {
+                    size,
+                    ..QueuedMove::background(FileMoveInfo { src: member, source_tier: new.tier.clone(), target_tier: new.tier.clone(), retries: 0 })
+                });
+            }
+        }
+        info!("Rebalancing {} files ({} bytes) onto new drive {} in {}", queued.len(), bytes, new.serial, new.tier);
+        Ok(bytes)
+    }
+
+    // Demote the coldest files of `source_tier`, one tier down unless
+    // tier_jumps sends them further
+    pub fn move_files_down(&self, source_tier: &str) -> io::Result<()> {
+        if tier_rank(source_tier) + 1 >= DriveManager::TIERS.len() {
+            return Ok(());
+        }
+        let cooldown = self.pass_setting("demotion", "promotion_cooldown_sec").as_ref().and_then(Value::as_u64).unwrap_or(PROMOTION_COOLDOWN_SEC);
+        let batch_size = self.pass_setting("demotion", "batch_size").as_ref().and_then(Value::as_u64).map_or(DEMOTION_BATCH_SIZE, |size| size as usize);
+        let moved_before = self.clock.now() - Duration::from_secs(cooldown);
+        let files_to_move = self.db.lock().unwrap().coldest_in_tier(source_tier, batch_size, moved_before)?;
+        for (file_path, metadata) in files_to_move {
+            if self.has_active_readers(&file_path, source_tier) {
+                debug!("Not demoting {}, it is being read", file_path.display());
+                continue;
+            }
+            if let Some(target_tier) = self.tier_jumps.demotion_target(&file_path, metadata.file_size, source_tier) {
+                let reason = format!("{} is over its capacity threshold and this is among its coldest files", source_tier);
+                self.queue_file_move_for(file_path, source_tier.to_string(), target_tier, reason);
+            }
+        }
+        Ok(())
+    }
+
+    // Streams the media server told us about, and any other process with the
+    // file open, whatever open_files says about deferring moves
+    fn has_active_readers(&self, file_path: &Path, tier: &str) -> bool {
+        self.is_streaming(file_path) || self.storage.open_mode(file_path, tier).is_ok_and(|mode| mode.is_some())
+    }
+
+    pub fn move_files_based_on_rules(&self) -> io::Result<()> {
+        let access_time_threshold = self.clock.now() - Duration::from_secs(self.pass_setting("promotion", "access_time_threshold").as_ref().and_then(Value::as_u64).unwrap_or(28800));
+        let access_count_threshold = self.pass_setting("promotion", "access_count_threshold").as_ref().and_then(Value::as_u64).unwrap_or(3);
+        let entries = self.db.lock().unwrap().entries()?;
+        let placements = self.db.lock().unwrap().placements()?;
+        let unavailable = self.db.lock().unwrap().unavailable_files()?;
+        for (file_path, file_info) in entries.into_iter().filter(|(path, _)| !unavailable.contains(path)) {
+            // tags, pins and tier_policy override the access rules
+            if let Some(placement) = placements.get(&file_path) {
+                if let Some(tier) = placement.tier.as_ref().filter(|tier| !placement.exclude && **tier != file_info.tier) {
+                    info!("{} belongs on tier {}", file_path.display(), tier);
+                    self.queue_file_move_for(file_path.clone(), file_info.tier.clone(), tier.clone(), "placed on it by a tag, pin or tier policy".to_string());
+                }
+                if placement.exclude || placement.tier.is_some() {
+                    continue;
+                }
+            }
+            if file_info.access_count >= access_count_threshold && file_info.last_access_time > access_time_threshold {
+                if let Some(target_tier) = self.tier_jumps.promotion_target(&file_path, file_info.file_size, &file_info.tier) {
+                    let reason = format!("read {} times, at least {} within the access time threshold", file_info.access_count, access_count_threshold);
+                    self.queue_file_move_for(file_path, file_info.tier, target_tier, reason);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn queue_file_move(&self, file_path: PathBuf, source_tier: String, target_tier: String) {
+        self.queue_file_move_for(file_path, source_tier, target_tier, "requested".to_string());
+    }
+
+    // Queue a move with what made it, for the plan an --observe check reports
+    fn queue_file_move_for(&self, file_path: PathBuf, source_tier: String, target_tier: String, reason: String) {
+        if source_tier == target_tier {
+            return;
+        }
+        for (member, member_tier) in self.group_members(&file_path, &target_tier) {
+            self.queue_single_move(member, member_tier, target_tier.clone(), format!("kept together with {}", file_path.display()));
+        }
+        self.queue_single_move(file_path, source_tier, target_tier, reason);
+    }
+
+    fn queue_single_move(&self, file_path: PathBuf, source_tier: String, target_tier: String, reason: String) {
+        if self.is_retry_scheduled(&file_path) {
+            debug!("Skipping {}, a retry is already scheduled", file_path.display());
+            return;
+        }
+        if matches!(self.db.lock().unwrap().is_quarantined(&file_path), Ok(true)) {
+            debug!("Skipping {}, it is quarantined", file_path.display());
+            return;
+        }
+        let size = self.file_metadata(&file_path).ok().flatten().map_or(0, |metadata| metadata.file_size);
+        if self.args.observe {
+            self.plan.lock().unwrap().add_move(PlannedMove { path: file_path.clone(), size, source_tier: source_tier.clone(), target_tier: target_tier.clone(), reason });
+        }
+        let pushed = self.enqueue(QueuedMove {
+            size,
+            ..QueuedMove::background(FileMoveInfo {
+                src: file_path.clone(),
+                source_tier,
+                target_tier: target_tier.clone(),
+                retries: 0,
+            })
+        });
+        match pushed {
+            Pushed::Retargeted => info!("Queued move of {} now targets {}", file_path.display(), target_tier),
+            Pushed::InFlight => debug!("Skipping {}, it is already being moved", file_path.display()),
+            Pushed::Added | Pushed::Merged => {}
+        }
+    }
+
+    // Queue a move, writing it to the journal first so a crash before it is
+    // done does not lose it
+    fn enqueue(&self, queued: QueuedMove) -> Pushed {
+        if !self.args.observe {
+            let journaled = JournaledMove { info: queued.info.clone(), user: queued.priority == Priority::User, size: queued.size, queued_at: self.clock.now() };
+            if let Err(e) = self.db.lock().unwrap().journal_move(&journaled) {
+                warn!("Failed to journal the move of {}: {}", queued.info.src.display(), e);
+            }
+        }
+        self.move_queue.push(queued)
+    }
+
+    // The other files the keep_together rules tie to `file_path` that are not
+    // on target_tier yet, with the tier each is on. Excluded files and files
+    // pinned to another tier stay where they are.
+    fn group_members(&self, file_path: &Path, target_tier: &str) -> Vec<(PathBuf, String)> {
+        let Some(scope) = self.keep_together.scope(file_path) else { return Vec::new() };
+        let db = self.db.lock().unwrap();
+        let candidates = match db.entries_under(scope.directory()) {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                warn!("Failed to look up files grouped with {}: {}", file_path.display(), e);
+                return Vec::new();
+            }
+        };
+        let paths: Vec<PathBuf> = candidates.iter().map(|(path, _)| path.clone()).collect();
+        let members = self.keep_together.members(file_path, &scope, &paths);
+        candidates.into_iter()
+            .filter(|(path, metadata)| path != file_path && metadata.tier != target_tier && members.contains(path))
+            .filter(|(path, _)| match db.placement(path) {
+                Ok(placement) => !placement.exclude && placement.tier.as_deref().is_none_or(|tier| tier == target_tier),
+                Err(_) => false,
+            })
+            .map(|(path, metadata)| (path, metadata.tier))
+            .collect()
+    }
+
+    // Queue a user-requested move ahead of all background moves. The returned
+    // receiver gets the outcome once the move completes or is abandoned.
+    // Files grouped with it by keep_together rules are moved along too.
+    pub fn migrate(&self, file_path: &Path, target_tier: &str) -> io::Result<Receiver<io::Result<()>>> {
+        if !DriveManager::TIERS.contains(&target_tier) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown tier {}", target_tier)));
+        }
+        let metadata = self.file_metadata(file_path)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not tracked", file_path.display())))?;
+        let (reply, outcome) = mpsc::channel();
+        if metadata.tier == target_tier {
+            let _ = reply.send(Ok(()));
+            return Ok(outcome);
+        }
+        info!("Queueing requested move of {} from {} to {}", file_path.display(), metadata.tier, target_tier);
+        for (member, member_tier) in self.group_members(file_path, target_tier) {
+            let size = self.file_metadata(&member)?.map_or(0, |metadata| metadata.file_size);
+            self.enqueue(QueuedMove {
+                info: FileMoveInfo { src: member, source_tier: member_tier, target_tier: target_tier.to_string(), retries: 0 },
+                priority: Priority::User,
+                size,
+                replies: Vec::new(),
+            });
+        }
+        self.enqueue(QueuedMove {
+            info: FileMoveInfo { src: file_path.to_path_buf(), source_tier: metadata.tier, target_tier: target_tier.to_string(), retries: 0 },
+            priority: Priority::User,
+            size: metadata.file_size,
+            replies: vec![reply],
+        });
+        Ok(outcome)
+    }
+
+    // Run queued moves, and any retries that come due, on the calling thread
+    // instead of the mover pool. Retries still backing off stay scheduled.
+    pub fn process_queued_moves(&self) -> ProcessedMoves {
+        let mut processed = ProcessedMoves::default();
+        if self.background_started.load(Ordering::SeqCst) {
+            warn!("Queued moves are handled by the background mover");
+            return processed;
+        }
+        loop {
+            if self.paused_for().is_some() || *self.throttle.lock().unwrap() == Throttle::Pause {
+                break;
+            }
+            if let Some(queued) = self.move_queue.try_pop() {
+                if queued.priority == Priority::Background && self.on_battery.load(Ordering::SeqCst) {
+                    self.move_queue.push(queued);
+                    break;
+                }
+                let file_info = queued.info.clone();
+                match self.move_file(queued) {
+                    MoveOutcome::Moved => processed.completed.push(file_info),
+                    MoveOutcome::RetryScheduled | MoveOutcome::Deferred | MoveOutcome::Observed => {}
+                    MoveOutcome::DeadLettered | MoveOutcome::Quarantined => processed.abandoned.push(file_info),
+                }
+            } else if self.requeue_due_retries() == 0 {
+                break;
+            }
+        }
+        processed
+    }
+
+    pub fn scheduled_retries(&self) -> usize {
+        self.retry_schedule.lock().unwrap().len()
+    }
+
+    pub fn move_file(&self, queued: QueuedMove) -> MoveOutcome {
+        if self.args.observe {
+            info!("Would move {} from {} to {}", queued.info.src.display(), queued.info.source_tier, queued.info.target_tier);
+            queued.respond(Err(io::Error::new(io::ErrorKind::PermissionDenied, "observing only, nothing is moved")));
+            return MoveOutcome::Observed;
+        }
+        let src = queued.info.src.clone();
+        let retried = queued.info.retries > 0;
+        let outcome = self.attempt_move(queued);
+        // a retry is saved apart, a deferred move is only held in memory
+        if outcome != MoveOutcome::Deferred {
+            if let Err(e) = self.db.lock().unwrap().remove_journaled_move(&src) {
+                warn!("Failed to clear {} from the move journal: {}", src.display(), e);
+            }
+        }
+        if outcome == MoveOutcome::Moved {
+            // a dead-lettered move requeued by `failures retry` starts over
+            // at no retries but still has its history
+            let db = self.db.lock().unwrap();
+            if retried || db.move_errors(&src).is_ok_and(|errors| !errors.is_empty()) {
+                if let Err(e) = db.clear_move_errors(&src) {
+                    error!("Failed to clear the failures of {}: {}", src.display(), e);
+                }
+            }
+        }
+        outcome
+    }
+
+    fn attempt_move(&self, queued: QueuedMove) -> MoveOutcome {
+        let file_info = &queued.info;
+        if tier_rank(&file_info.target_tier) > tier_rank(&file_info.source_tier) && self.is_streaming(&file_info.src) {
+            info!("Deferring demotion of {}, it is being streamed", file_info.src.display());
+            return self.defer(queued);
+        }
+        let held = self.held_drives();
+        self.storage.hold_branches(&held);
+        if let Some(serial) = self.storage.branch_of(&file_info.src, &file_info.source_tier).filter(|serial| held.contains(serial)) {
+            info!("Deferring move of {}, a running job is using drive {}", file_info.src.display(), serial);
+            return self.defer(queued);
+        }
+        match self.storage.open_mode(&file_info.src, &file_info.source_tier) {
+            Ok(mode) if self.open_file_policy.defers(mode) => {
+                info!("Deferring move of {}, it is open for {:?}", file_info.src.display(), mode.unwrap());
+                return self.defer(queued);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Could not check whether {} is open: {}", file_info.src.display(), e),
+        }
+        if let Some(GroupScope::Directory(dir)) = self.keep_together.scope(&file_info.src) {
+            match self.move_with_directory(&dir, file_info) {
+                Some(Ok(())) => {
+                    queued.respond(Ok(()));
+                    return MoveOutcome::Moved;
+                }
+                Some(Err(e)) => return self.retry_move(queued, &e),
+                None => {}
+            }
+        }
+        let links = self.link_groups.lock().unwrap().get(&file_info.src).cloned().unwrap_or_default();
+        if !links.is_empty() && self.file_metadata(&file_info.src).is_ok_and(|metadata| metadata.is_some_and(|metadata| metadata.tier == file_info.target_tier)) {
+            // moved along with another link to the same file
+            queued.respond(Ok(()));
+            return MoveOutcome::Moved;
+        }
+        self.start_transfer(file_info, queued.size);
+        let started = self.clock.now();
+        let progress = |bytes| self.update_transfer(&file_info.src, bytes);
+        let moved = if links.is_empty() {
+            self.storage.move_file(&file_info.src, &file_info.source_tier, &file_info.target_tier, &progress)
+        } else {
+            self.storage.move_link_group(&links, &file_info.source_tier, &file_info.target_tier, &progress)
+        };
+        self.finish_transfer(&file_info.src);
+        match moved {
+            Ok(()) => {
+                info!("Moved file {} from {} to {}", file_info.src.display(), file_info.source_tier, file_info.target_tier);
+                events::record("moved", json!({ "path": file_info.src, "from": file_info.source_tier, "to": file_info.target_tier, "bytes": queued.size }));
+                let db = self.db.lock().unwrap();
+                let took = self.clock.now().duration_since(started).unwrap_or_default();
+                if let Err(e) = db.record_throughput(&file_info.source_tier, &file_info.target_tier, queued.size, took) {
+                    error!("Failed to record the throughput of {} -> {}: {}", file_info.source_tier, file_info.target_tier, e);
+                }
+                let moved_paths = if links.is_empty() { std::slice::from_ref(&file_info.src) } else { links.as_slice() };
+                for path in moved_paths {
+                    if let Ok(Some(mut metadata)) = db.get(path) {
+                        metadata.tier = file_info.target_tier.clone();
+                        metadata.last_tier_move = Some(self.clock.now());
+                        if let Err(e) = db.insert(path, &metadata) {
+                            error!("Failed to record move of {}: {}", path.display(), e);
+                        }
+                    }
+                    let branch = self.storage.branch_of(path, &file_info.target_tier);
+                    if let Err(e) = branch.map_or(Ok(()), |serial| db.set_branch(path, &serial)) {
+                        error!("Failed to record branch of {}: {}", path.display(), e);
+                    }
+                }
+                queued.respond(Ok(()));
+                MoveOutcome::Moved
+            }
+            Err(e) => self.retry_move(queued, &e),
+        }
+    }
+
+    // A file in a keep_together directory goes with the whole directory when
+    // the storage can move it in one piece, as with btrfs send/receive. None
+    // when it cannot, and the file moves on its own.
+    fn move_with_directory(&self, dir: &Path, file_info: &FileMoveInfo) -> Option<io::Result<()>> {
+        if self.file_metadata(&file_info.src).ok().flatten().is_some_and(|metadata| metadata.tier == file_info.target_tier) {
+            // went with its directory already
+            return Some(Ok(()));
+        }
+        match self.storage.move_directory(dir, &file_info.source_tier, &file_info.target_tier) {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                debug!("{}", e);
+                None
+            }
+            Err(e) => Some(Err(e)),
+            Ok(()) => {
+                info!("Moved directory {} from {} to {}", dir.display(), file_info.source_tier, file_info.target_tier);
+                let db = self.db.lock().unwrap();
+                let entries = db.entries_under(dir).unwrap_or_else(|e| {
+                    error!("Failed to look up the files moved with {}: {}", dir.display(), e);
+                    Vec::new()
+                });
+                for (path, mut metadata) in entries.into_iter().filter(|(_, metadata)| metadata.tier == file_info.source_tier) {
+                    metadata.tier = file_info.target_tier.clone();
+                    metadata.last_tier_move = Some(self.clock.now());
+                    if let Err(e) = db.insert(&path, &metadata) {
+                        error!("Failed to record move of {}: {}", path.display(), e);
+                    }
+                    let branch = self.storage.branch_of(&path, &file_info.target_tier);
+                    if let Err(e) = branch.map_or(Ok(()), |serial| db.set_branch(&path, &serial)) {
+                        error!("Failed to record branch of {}: {}", path.display(), e);
+                    }
+                }
+                Some(Ok(()))
+            }
+        }
+    }
+
+    fn defer(&self, queued: QueuedMove) -> MoveOutcome {
+        let delay = self.config().get("open_file_defer_sec").and_then(Value::as_u64).unwrap_or(OPEN_FILE_DEFER_SEC);
+        self.retry_schedule.lock().unwrap().push((self.clock.now() + Duration::from_secs(delay), queued));
+        MoveOutcome::Deferred
+    }
+
+    // A media server started playing `file_path`. It is promoted along with
+    // the episodes that follow it, and kept from being demoted until the
+    // stream stops or `timeout` passes without hearing that it did.
+    pub fn stream_started(&self, file_path: &Path, next_episodes: usize, timeout: Duration) -> io::Result<()> {
+        self.streams.lock().unwrap().insert(file_path.to_path_buf(), self.clock.now() + timeout);
+        let to_promote = {
+            let db = self.db.lock().unwrap();
+            let dir = file_path.parent().unwrap_or(Path::new(""));
+            let candidates: Vec<PathBuf> = db.entries_under(dir)?.into_iter().map(|(path, _)| path).collect();
+            let mut to_promote = vec![file_path.to_path_buf()];
+            to_promote.extend(media_server::next_episodes(file_path, &candidates, next_episodes));
+            // tags still win over playback
+            to_promote.retain(|path| db.placement(path).is_ok_and(|placement| !placement.exclude && placement.tier.is_none()));
+            to_promote
+        };
+        for path in to_promote {
+            self.migrate(&path, "hot")?;
+        }
+        Ok(())
+    }
+
+    pub fn stream_stopped(&self, file_path: &Path) {
+        self.streams.lock().unwrap().remove(file_path);
+    }
+
+    fn is_streaming(&self, file_path: &Path) -> bool {
+        let now = self.clock.now();
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|_, until| *until > now);
+        streams.contains_key(file_path)
+    }
+
+    // External jobs that have said they started and not yet finished
+    pub fn running_jobs(&self) -> Vec<RunningJob> {
+        self.db.lock().unwrap().running_jobs(self.clock.now()).unwrap_or_else(|e| {
+            warn!("Failed to read running jobs: {}", e);
+            Vec::new()
+        })
+    }
+
+    // What tiering is paused for, if anything: a reload, `ctl pause` or
+    // the names of running jobs on every drive
+    fn paused_for(&self) -> Option<String> {
+        if self.stopping.load(Ordering::SeqCst) {
+            return Some("shutdown".to_string());
+        }
+        if self.reloading.load(Ordering::SeqCst) {
+            return Some("a config reload".to_string());
+        }
+        if self.paused.load(Ordering::SeqCst) {
+            return Some("ctl pause".to_string());
+        }
+        let jobs: Vec<String> = self.running_jobs().into_iter()
+            .filter(|job| external_jobs::job_for(&self.external_jobs, &job.name).drives.is_empty())
+            .map(|job| job.name)
+            .collect();
+        (!jobs.is_empty()).then(|| jobs.join(", "))
+    }
+
+    // The drives running jobs with a list of drives are working on, which
+    // moves keep off meanwhile
+    fn held_drives(&self) -> HashSet<String> {
+        self.running_jobs().iter().flat_map(|job| external_jobs::job_for(&self.external_jobs, &job.name).drives).collect()
+    }
+
+    // Hold the mover until every running job has finished. Copies already
+    // under way are left to complete; `job start` waits for them.
+    fn wait_for_jobs(&self) {
+        if let Some(names) = self.paused_for() {
+            info!("Pausing moves for {}", names);
+            while self.paused_for().is_some() {
+                self.clock.sleep(Duration::from_secs(external_jobs::PAUSE_POLL_INTERVAL));
+            }
+            info!("Resuming moves");
+        }
+    }
+
+    // Hold new moves and wait for the ones copying to finish, so the
+    // process can be replaced with nothing half done. A move that was just
+    // about to start shows up within a poll, so the wait needs two quiet
+    // ones in a row.
+    pub fn drain_for_reload(&self) {
+        self.reloading.store(true, Ordering::SeqCst);
+        self.wait_for_transfers(None);
+    }
+
+    // Whether the copies finished before `deadline`
+    fn wait_for_transfers(&self, deadline: Option<SystemTime>) -> bool {
+        let mut quiet = 0;
+        while quiet < 2 {
+            if deadline.is_some_and(|deadline| self.clock.now() >= deadline) {
+                return false;
+            }
+            quiet = if self.transfers().is_empty() { quiet + 1 } else { 0 };
+            self.clock.sleep(Duration::from_secs(1));
+        }
+        true
+    }
+
+    // Stop taking new moves, give the ones copying shutdown_timeout_sec to
+    // finish and get everything we know onto disk. Copies still going are
+    // recorded where they got to and picked up again at the next start.
+    // Returns how many were cut off.
+    pub fn shut_down(&self) -> io::Result<usize> {
+        self.stopping.store(true, Ordering::SeqCst);
+        let timeout = Duration::from_secs(self.config().get("shutdown_timeout_sec").and_then(Value::as_u64).unwrap_or(SHUTDOWN_TIMEOUT_SEC));
+        let left = if self.wait_for_transfers(Some(self.clock.now() + timeout)) { Vec::new() } else { self.transfers() };
+        for progress in &left {
+            self.save_progress(progress);
+        }
+        self.flush_opens()?;
+        self.db.lock().unwrap().flush()?;
+        Ok(left.len())
+    }
+
+    pub fn cancel_reload(&self) {
+        self.reloading.store(false, Ordering::SeqCst);
+    }
+
+    // Hold new moves until resume(), leaving the ones copying to finish.
+    // Both return whether this changed anything.
+    pub fn pause(&self) -> bool {
+        !self.paused.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn resume(&self) -> bool {
+        self.paused.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    // Moves that are copying right now, oldest first
+    pub fn transfers(&self) -> Vec<TransferProgress> {
+        let mut transfers: Vec<TransferProgress> = self.transfers.lock().unwrap().values().map(|(progress, _)| progress.clone()).collect();
+        transfers.sort_by(|a, b| a.started_at.cmp(&b.started_at).then_with(|| a.info.src.cmp(&b.info.src)));
+        transfers
+    }
+
+    // Whether a copy under way reads from the branch, so it cannot be
+    // unmounted yet
+    pub fn copying_from(&self, serial: &str) -> io::Result<bool> {
+        let transfers = self.transfers();
+        if transfers.is_empty() {
+            return Ok(false);
+        }
+        let branches = self.db.lock().unwrap().branches()?;
+        Ok(transfers.iter().any(|transfer| branches.get(&transfer.info.src).is_some_and(|branch| branch == serial)))
+    }
+
+    fn start_transfer(&self, info: &FileMoveInfo, bytes_total: u64) {
+        let now = self.clock.now();
+        let progress = TransferProgress { info: info.clone(), bytes_copied: 0, bytes_total, started_at: now, updated_at: now };
+        self.save_progress(&progress);
+        self.transfers.lock().unwrap().insert(info.src.clone(), (progress, now));
+    }
+
+    // Every update is kept in memory, but only written to the database once
+    // per PROGRESS_SAVE_INTERVAL
+    fn update_transfer(&self, src: &Path, bytes_copied: u64) {
+        let now = self.clock.now();
+        let due = {
+            let mut transfers = self.transfers.lock().unwrap();
+            let Some((progress, saved_at)) = transfers.get_mut(src) else { return };
+            progress.bytes_copied = bytes_copied;
+            progress.updated_at = now;
+            if now.duration_since(*saved_at).unwrap_or_default() < Duration::from_secs(PROGRESS_SAVE_INTERVAL) {
+                return;
+            }
+            *saved_at = now;
+            progress.clone()
+        };
+        self.save_progress(&due);
+    }
+
+    fn finish_transfer(&self, src: &Path) {
+        self.transfers.lock().unwrap().remove(src);
+        if let Err(e) = self.db.lock().unwrap().remove_progress(src) {
+            warn!("Failed to clear progress of {}: {}", src.display(), e);
+        }
+    }
+
+    fn save_progress(&self, progress: &TransferProgress) {
+        if let Err(e) = self.db.lock().unwrap().record_progress(progress) {
+            warn!("Failed to record progress of {}: {}", progress.info.src.display(), e);
+        }
+    }
+
+    pub fn validate_and_update_database(&self) -> io::Result<()> {
+        info!("Starting database validation and update");
+        let scanned: HashMap<PathBuf, String> = self.storage.scan()?.into_iter().map(|file| (file.path, file.tier)).collect();
+        let db = self.db.lock().unwrap();
+        self.record_offline_branches(&db)?;
+        let crawling = self.storage.crawling_branches();
+        let mut unavailable = db.unavailable_files()?;
+        unavailable.extend(db.branches()?.into_iter().filter(|(_, serial)| crawling.contains(serial)).map(|(path, _)| path));
+        for (relative_path, mut file_info) in db.entries()? {
+            match scanned.get(&relative_path) {
+                Some(tier) if *tier != file_info.tier => {
+                    info!("Updating tier for {} from {} to {}", relative_path.display(), file_info.tier, tier);
+                    file_info.tier = tier.clone();
+                    db.insert(&relative_path, &file_info)?;
+                }
+                Some(_) => {}
+                // kept until its branch is back or its crawl is done
+                None if unavailable.contains(&relative_path) => {}
+                None => {
+                    info!("Removing non-existent file from database: {}", relative_path.display());
+                    db.remove(&relative_path)?;
+                }
+            }
+        }
+        drop(db);
+        // pick up files that appeared since the last scan
+        self.update_file_metadata()?;
+        info!("Database validation and update completed");
+        Ok(())
+    }
+
+    // Delete scratch files past their directory's max age or size. Files
+    // something has open are left for the next run.
+    pub fn clean_scratch_dirs(&self) -> io::Result<()> {
+        if self.paused_for().is_some() {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        for dir in self.scratch_dirs.iter().filter(|dir| dir.max_age.is_some() || dir.max_bytes.is_some()) {
+            let files = self.db.lock().unwrap().entries_under(&dir.path)?;
+            let tiers: HashMap<PathBuf, String> = files.iter().map(|(path, metadata)| (path.clone(), metadata.tier.clone())).collect();
+            for path in dir.expired(files, now) {
+                let tier = &tiers[&path];
+                if self.storage.open_mode(&path, tier).is_ok_and(|mode| mode.is_some()) {
+                    debug!("Not cleaning up {}, it is open", path.display());
+                    continue;
+                }
+                if self.args.observe {
+                    info!("Would clean up scratch file {}", path.display());
+                    continue;
+                }
+                match self.storage.delete_file(&path, tier) {
+                    Ok(()) => info!("Cleaned up scratch file {}", path.display()),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        warn!("Failed to clean up scratch file {}: {}", path.display(), e);
+                        continue;
+                    }
+                }
+                self.db.lock().unwrap().remove(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Delete the files ttl_policy says have outlived their use, each noted
+    // in the event log, or only count them while ttl_policy.delete is off.
+    // Files excluded from tiering and files something has open are left.
+    pub fn expire_ttl_files(&self) -> io::Result<()> {
+        if self.ttl.is_empty() || self.paused_for().is_some() {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let expired = {
+            let db = self.db.lock().unwrap();
+            let placements = db.placements()?;
+            let files = db.entries()?.into_iter().filter(|(path, _)| !placements.get(path).is_some_and(|placement| placement.exclude)).collect();
+            self.ttl.expired(files, now)
+        };
+        if expired.is_empty() {
+            return Ok(());
+        }
+        if !self.ttl.delete {
+            info!("{} files are past their ttl_policy age; set ttl_policy.delete to true to have them deleted", expired.len());
+            return Ok(());
+        }
+        for (path, metadata) in expired {
+            if self.storage.open_mode(&path, &metadata.tier).is_ok_and(|mode| mode.is_some()) {
+                debug!("Not deleting {}, it is open", path.display());
+                continue;
+            }
+            if self.args.observe {
+                info!("Would delete {}, past its ttl_policy age", path.display());
+                continue;
+            }
+            match self.storage.delete_file(&path, &metadata.tier) {
+                Ok(()) => {
+                    let unread = now.duration_since(metadata.last_access_time).unwrap_or_default();
+                    info!("Deleted {}, unread for {} days", path.display(), unread.as_secs() / 86400);
+                    events::record("ttl_deleted", json!({
+                        "path": path, "tier": metadata.tier, "size": metadata.file_size, "last_read": to_unix(metadata.last_access_time),
+                    }));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Failed to delete {}: {}", path.display(), e);
+                    continue;
+                }
+            }
+            self.db.lock().unwrap().remove(&path)?;
+        }
+        Ok(())
+    }
+
+    pub fn file_metadata<P: AsRef<Path>>(&self, file_path: P) -> io::Result<Option<FileMetadata>> {
+        self.db.lock().unwrap().get(file_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::SystemTime;
+    use crate::args::IO_THREADS;
+    use crate::clock::ManualClock;
+    use crate::fault_injection::{Fault, FaultInjector, FaultPoint, FaultyStorage};
+    use crate::open_files::{OpenMode, OpenReader};
+    use crate::pins::Pin;
+    use crate::placement::Placement;
+    use crate::simulation::{SimDrive, SimulatedStorage};
+    use crate::storage::{BranchUsage, TierUsage};
+    use std::sync::{OnceLock, Weak};
+
+    const GB: u64 = 1 << 30;
+
+    fn start() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)
+    }
+
+    fn tiering_manager(config: Value) -> (Arc<SimulatedStorage>, Arc<ManualClock>, TieringManager) {
+        let storage = Arc::new(SimulatedStorage::new(vec![
+            SimDrive::new("nvme0", "nvme", 10 * GB),
+            SimDrive::new("ssd0", "ssd", 20 * GB),
+            SimDrive::new("hdd0", "hdd", 100 * GB),
+        ]));
+        let args = Args::parse_from(["--dryrun"]).unwrap();
+        let clock = Arc::new(ManualClock::new(start()));
+        let tm = TieringManager::with_clock(args, config, storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        (storage, clock, tm)
+    }
+
+    #[test]
+    fn test_update_file_metadata() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        let t0 = start();
+        storage.create_file("a.mkv", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.file_metadata("a.mkv").unwrap().unwrap().access_count, 1);
+        // unchanged atime is not an access
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.file_metadata("a.mkv").unwrap().unwrap().access_count, 1);
+        storage.access("a.mkv", t0 + Duration::from_secs(60));
+        tm.update_file_metadata().unwrap();
+        let metadata = tm.file_metadata("a.mkv").unwrap().unwrap();
+        assert_eq!(metadata.access_count, 2);
+        assert_eq!(metadata.tier, "hot");
+    }
+
+    #[test]
+    fn test_counted_opens() {
+        let (storage, clock, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        storage.create_file("b.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.access_watch.store(true, Ordering::SeqCst);
+        clock.advance(Duration::from_secs(60));
+        // opens between flushes count once; a file not scanned yet waits for the scan
+        tm.record_opens(&[PathBuf::from("a.mkv"), PathBuf::from("a.mkv"), PathBuf::from("new.mkv")]);
+        tm.reads_since_scan.lock().unwrap().insert(PathBuf::from("b.mkv"), ReadPattern::Sequential);
+        tm.record_opens(&[PathBuf::from("b.mkv")]);
+        assert_eq!(tm.flush_opens().unwrap(), 1);
+        let a = tm.file_metadata("a.mkv").unwrap().unwrap();
+        assert_eq!((a.access_count, a.last_access_time), (2, start() + Duration::from_secs(60)));
+        let b = tm.file_metadata("b.mkv").unwrap().unwrap();
+        assert_eq!((b.access_count, b.last_access_time), (1, start() + Duration::from_secs(60)));
+        // on a noatime mount the atime never moves, and with opens counted
+        // a newer one is not taken as another access either
+        storage.access("a.mkv", start() + Duration::from_secs(120));
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.file_metadata("a.mkv").unwrap().unwrap().access_count, 2);
+        assert_eq!(tm.flush_opens().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_move_files_down() {
+        let (storage, _, tm) = tiering_manager(json!({ "tier_capacity_threshold": 50.0 }));
+        let t0 = start();
+        for i in 0..8 {
+            storage.create_file(format!("f{}", i), GB, t0 + Duration::from_secs(i)).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        tm.check_tier_capacities().unwrap();
+        let moved = tm.process_queued_moves().completed;
+        assert_eq!(moved.len(), 8);
+        assert!(moved.iter().all(|m| m.source_tier == "hot" && m.target_tier == "warm"));
+        assert_eq!(storage.tier_of("f0").as_deref(), Some("warm"));
+        assert_eq!(tm.file_metadata("f0").unwrap().unwrap().last_tier_move, Some(t0));
+    }
+
+    #[test]
+    fn test_observe_moves_nothing() {
+        let storage = Arc::new(SimulatedStorage::new(vec![SimDrive::new("nvme0", "nvme", 10 * GB), SimDrive::new("ssd0", "ssd", 20 * GB)]));
+        let config = json!({ "tier_capacity_threshold": 50.0 });
+        let tm = TieringManager::with_clock(Args::parse_from(["--observe"]).unwrap(), config, storage.clone(), MetadataDb::open_in_memory().unwrap(), Arc::new(ManualClock::new(start())));
+        for i in 0..8 {
+            storage.create_file(format!("f{}", i), GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        tm.check_tier_capacities().unwrap();
+        let plan = tm.plan().unwrap();
+        assert!(!plan.moves.is_empty());
+        assert!(plan.moves.iter().all(|planned| planned.source_tier == "hot" && planned.target_tier == "warm"), "{:?}", plan.moves);
+        assert_eq!(plan.moves[0].reason, "hot is over its capacity threshold and this is among its coldest files");
+        assert!(tm.process_queued_moves().completed.is_empty());
+        assert_eq!(storage.tier_of("f0").as_deref(), Some("hot"));
+        assert_eq!(tm.file_metadata("f0").unwrap().unwrap().tier, "hot");
+        let moved = tm.migrate(Path::new("f0"), "warm").unwrap();
+        tm.process_queued_moves();
+        assert!(moved.recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_move_files_based_on_rules() {
+        let (storage, clock, tm) = tiering_manager(json!({ "access_count_threshold": 2 }));
+        let t0 = start();
+        storage.create_file("a.mkv", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        tm.process_queued_moves();
+        storage.access("a.mkv", t0 + Duration::from_secs(60));
+        clock.advance(Duration::from_secs(120));
+        tm.perform_tiering_check().unwrap();
+        let moved = tm.process_queued_moves().completed;
+        assert_eq!(moved.len(), 1);
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_promotion_and_demotion_passes() {
+        let (storage, clock, tm) = tiering_manager(json!({
+            "access_count_threshold": 5,
+            "promotion": { "access_count_threshold": 2 },
+            "demotion": { "tier_capacity_threshold": 5.0, "batch_size": 1 },
+        }));
+        let t0 = start();
+        storage.create_file("a.mkv", GB, t0).unwrap();
+        storage.create_file("b.mkv", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        tm.process_queued_moves();
+        storage.access("a.mkv", t0 + Duration::from_secs(60));
+        clock.advance(Duration::from_secs(120));
+        // hot is past the demotion threshold, but this pass only promotes
+        tm.perform_promotion_check().unwrap();
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("b.mkv").as_deref(), Some("hot"));
+        tm.perform_demotion_check().unwrap();
+        let moved = tm.process_queued_moves().completed;
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].src, Path::new("b.mkv"));
+    }
+
+    #[test]
+    fn test_tier_jumps() {
+        let (storage, clock, tm) = tiering_manager(json!({
+            "tier_capacity_threshold": 5.0,
+            "access_count_threshold": 2,
+            "tier_jumps": [{ "extensions": ["tar"], "demote_to": "cold" }, { "path": "photos/*", "promote_to": "warm" }],
+        }));
+        let t0 = start();
+        storage.create_file("backup.tar", GB, t0).unwrap();
+        storage.create_file("movie.mkv", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.check_tier_capacities().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("backup.tar").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("movie.mkv").as_deref(), Some("warm"));
+
+        storage.create_file("photos/a.jpg", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("photos/a.jpg".into(), "hot".to_string(), "cold".to_string());
+        tm.process_queued_moves();
+        storage.access("photos/a.jpg", t0 + Duration::from_secs(60));
+        clock.advance(Duration::from_secs(120));
+        tm.update_file_metadata().unwrap();
+        tm.move_files_based_on_rules().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("photos/a.jpg").as_deref(), Some("warm"));
+    }
+
+    #[test]
+    fn test_ingest_rules() {
+        let (storage, clock, tm) = tiering_manager(json!({ "ingest": { "delay_sec": 30, "rules": [{ "extensions": ["iso"], "tier": "cold" }] } }));
+        storage.create_file("known.iso", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        for path in ["downloads/part.tmp", "known.iso", "notes.txt"] {
+            storage.create_file(path, GB, start()).unwrap_or_default();
+            tm.apply_mount_events(&[MountEvent::Written { path: path.into(), tier: "hot".to_string() }]).unwrap();
+        }
+        tm.apply_mount_events(&[MountEvent::Renamed { from: "downloads".into(), to: "isos".into(), dir: true }]).unwrap();
+        storage.remove_file("downloads/part.tmp");
+        storage.create_file("isos/part.tmp", GB, start()).unwrap();
+        tm.apply_mount_events(&[MountEvent::Renamed { from: "isos/part.tmp".into(), to: "isos/linux.iso".into(), dir: false }]).unwrap();
+        storage.remove_file("isos/part.tmp");
+        storage.create_file("isos/linux.iso", GB, start()).unwrap();
+        // not settled yet
+        assert_eq!(tm.place_new_files(), 0);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(tm.place_new_files(), 1);
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("isos/linux.iso").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("known.iso").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("notes.txt").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_placement_tags() {
+        let (storage, clock, tm) = tiering_manager(json!({ "access_count_threshold": 2, "tier_capacity_threshold": 5.0 }));
+        let t0 = start();
+        for path in ["pinned", "excluded", "plain"] {
+            storage.create_file(path, GB, t0).unwrap();
+        }
+        storage.set_placement("pinned", Placement { tier: Some("cold".to_string()), ..Default::default() });
+        storage.set_placement("excluded", Placement { exclude: true, ..Default::default() });
+        clock.advance(Duration::from_secs(60));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("pinned").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("excluded").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("plain").as_deref(), Some("warm"));
+
+        // reads would promote it, but the pin keeps it on cold
+        storage.access("pinned", t0 + Duration::from_secs(120));
+        clock.advance(Duration::from_secs(120));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("pinned").as_deref(), Some("cold"));
+    }
+
+    #[test]
+    fn test_pins() {
+        let (storage, clock, tm) = tiering_manager(json!({ "tier_capacity_threshold": 5.0, "pins": [{ "path": "vms", "tier": "hot" }] }));
+        let t0 = start();
+        for path in ["vms/win11.qcow2", "vms/old.qcow2", "plain"] {
+            storage.create_file(path, GB, t0).unwrap();
+        }
+        storage.set_placement("vms/win11.qcow2", Placement { tier: Some("cold".to_string()), ..Default::default() });
+        // a runtime pin on top of the config's
+        tm.db.lock().unwrap().add_pin(&Pin { path: PathBuf::from("vms/old.qcow2"), tier: "cold".to_string() }, t0).unwrap();
+        clock.advance(Duration::from_secs(60));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        // the pin wins over the file's own tag, and keeps it off the demotion list
+        assert_eq!(storage.tier_of("vms/win11.qcow2").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("vms/old.qcow2").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("plain").as_deref(), Some("warm"));
+
+        assert!(tm.db.lock().unwrap().remove_pin("vms/old.qcow2").unwrap());
+        clock.advance(Duration::from_secs(60));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("vms/old.qcow2").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_staged_files() {
+        let (storage, clock, tm) = tiering_manager(json!({ "tier_capacity_threshold": 5.0 }));
+        let t0 = start();
+        for path in ["inputs/a.dat", "inputs/b.dat"] {
+            storage.create_file(path, GB, t0).unwrap();
+            storage.set_placement(path, Placement { tier: Some("cold".to_string()), ..Default::default() });
+        }
+        clock.advance(Duration::from_secs(60));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("inputs/a.dat").as_deref(), Some("cold"));
+
+        // a name a glob would match more than
+        storage.create_file("inputs/[ab].dat", GB, t0).unwrap();
+        storage.set_placement("inputs/[ab].dat", Placement { tier: Some("cold".to_string()), ..Default::default() });
+        let staged = [(PathBuf::from("inputs/a.dat"), GB), (PathBuf::from("inputs/[ab].dat"), GB)];
+        tm.db.lock().unwrap().stage_files("render", &staged, clock.now(), clock.now() + Duration::from_secs(3600)).unwrap();
+        clock.advance(Duration::from_secs(60));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        // the staging wins over the file's tag, and leaves the others alone
+        assert_eq!(storage.tier_of("inputs/a.dat").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("inputs/b.dat").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("inputs/[ab].dat").as_deref(), Some("hot"));
+        assert_eq!(tm.db.lock().unwrap().stagings(clock.now()).unwrap()[0].on_hot, 2);
+
+        assert_eq!(tm.db.lock().unwrap().release_staging("render").unwrap(), 2);
+        clock.advance(Duration::from_secs(60));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("inputs/a.dat").as_deref(), Some("cold"));
+    }
+
+    #[test]
+    fn test_tier_policy() {
+        let (storage, clock, tm) = tiering_manager(json!({ "tier_capacity_threshold": 5.0, "tier_policy": [
+            { "path": "appdata/*", "tier": "hot" },
+            { "extensions": ["iso"], "tier": "cold" },
+            { "path": "media/*", "min_age_days": 30, "tier": "cold" },
+        ] }));
+        let t0 = start();
+        for path in ["appdata/plex.db", "isos/debian.iso", "media/film.mkv", "tagged.iso"] {
+            storage.create_file(path, GB, t0).unwrap();
+        }
+        // a tag wins over the policy
+        storage.set_placement("tagged.iso", Placement { tier: Some("hot".to_string()), ..Default::default() });
+        clock.advance(Duration::from_secs(60));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("appdata/plex.db").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("isos/debian.iso").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("tagged.iso").as_deref(), Some("hot"));
+        // not old enough for its rule, so demoted by capacity like any file
+        assert_eq!(storage.tier_of("media/film.mkv").as_deref(), Some("warm"));
+
+        clock.advance(Duration::from_secs(30 * 86400));
+        tm.perform_tiering_check().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("media/film.mkv").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("appdata/plex.db").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_keep_together() {
+        let (storage, _, tm) = tiering_manager(json!({ "keep_together": [
+            { "files": ["mkv"], "sidecars": ["srt", "nfo"] },
+            { "directory": "Season *" },
+        ] }));
+        let t0 = start();
+        for path in ["movies/a.mkv", "movies/a.en.srt", "movies/a.nfo", "movies/b.mkv", "tv/Season 1/e01.mkv", "tv/Season 1/e02.mkv", "tv/Season 2/e01.mkv"] {
+            storage.create_file(path, GB, t0).unwrap();
+        }
+        storage.set_placement("movies/a.nfo", Placement { exclude: true, ..Default::default() });
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("movies/a.en.srt".into(), "hot".to_string(), "cold".to_string());
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("movies/a.mkv").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("movies/a.en.srt").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("movies/a.nfo").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("movies/b.mkv").as_deref(), Some("hot"));
+
+        let outcome = tm.migrate(Path::new("tv/Season 1/e02.mkv"), "warm").unwrap();
+        let moved = tm.process_queued_moves().completed;
+        assert!(outcome.recv().unwrap().is_ok());
+        assert_eq!(moved.len(), 2);
+        assert_eq!(storage.tier_of("tv/Season 1/e01.mkv").as_deref(), Some("warm"));
+        assert_eq!(storage.tier_of("tv/Season 2/e01.mkv").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_streams() {
+        let (storage, clock, tm) = tiering_manager(json!({ "tier_capacity_threshold": 5.0, "promotion_cooldown_sec": 0 }));
+        let t0 = start();
+        for episode in 1..=4 {
+            storage.create_file(format!("tv/S1/e0{}.mkv", episode), GB, t0).unwrap();
+            storage.move_file(Path::new(&format!("tv/S1/e0{}.mkv", episode)), "hot", "cold", &|_| {}).unwrap();
+        }
+        storage.create_file("movie.mkv", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.stream_started(Path::new("tv/S1/e01.mkv"), 2, Duration::from_secs(3600)).unwrap();
+        tm.process_queued_moves();
+        let tiers: Vec<String> = (1..=4).map(|episode| storage.tier_of(format!("tv/S1/e0{}.mkv", episode)).unwrap()).collect();
+        assert_eq!(tiers, ["hot", "hot", "hot", "cold"]);
+
+        // hot is over the threshold, but the episode being played stays
+        clock.advance(Duration::from_secs(60));
+        tm.check_tier_capacities().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("tv/S1/e01.mkv").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("tv/S1/e02.mkv").as_deref(), Some("warm"));
+
+        tm.stream_stopped(Path::new("tv/S1/e01.mkv"));
+        tm.check_tier_capacities().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("tv/S1/e01.mkv").as_deref(), Some("warm"));
+    }
+
+    #[test]
+    fn test_sequential_reads_are_not_counted() {
+        let (storage, clock, tm) = tiering_manager(json!({ "access_count_threshold": 3 }));
+        let t0 = start();
+        for path in ["movie.mkv", "index.db"] {
+            storage.create_file(path, GB, t0).unwrap();
+            storage.move_file(Path::new(path), "hot", "cold", &|_| {}).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        for round in 1..=2 {
+            for (movie_pos, db_pos) in [(0, 0), (GB / 4, GB / 2), (GB / 2, GB / 8), (GB, GB / 4)] {
+                storage.set_readers(vec![
+                    OpenReader { pid: 1, fd: 3, path: "movie.mkv".into(), pos: movie_pos },
+                    OpenReader { pid: 2, fd: 3, path: "index.db".into(), pos: db_pos },
+                ]);
+                tm.sample_reads().unwrap();
+            }
+            storage.set_readers(Vec::new());
+            tm.sample_reads().unwrap();
+            clock.advance(Duration::from_secs(3600));
+            storage.access("movie.mkv", t0 + Duration::from_secs(round * 3600));
+            storage.access("index.db", t0 + Duration::from_secs(round * 3600));
+            tm.update_file_metadata().unwrap();
+        }
+        let movie = tm.file_metadata("movie.mkv").unwrap().unwrap();
+        assert_eq!(movie.access_count, 1);
+        assert_eq!(movie.last_access_time, t0 + Duration::from_secs(7200));
+        assert_eq!(tm.file_metadata("index.db").unwrap().unwrap().access_count, 3);
+        tm.move_files_based_on_rules().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("index.db").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("movie.mkv").as_deref(), Some("cold"));
+    }
+
+    #[test]
+    fn test_demotion_protection() {
+        let (storage, clock, tm) = tiering_manager(json!({ "tier_capacity_threshold": 5.0, "open_files": "ignore" }));
+        let t0 = start();
+        for path in ["promoted.mkv", "playing.mkv", "idle.mkv"] {
+            storage.create_file(path, GB, t0).unwrap();
+        }
+        storage.move_file(Path::new("promoted.mkv"), "hot", "cold", &|_| {}).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.migrate(Path::new("promoted.mkv"), "hot").unwrap();
+        tm.process_queued_moves();
+        storage.set_open("playing.mkv", Some(OpenMode::Read));
+
+        clock.advance(Duration::from_secs(3600));
+        tm.check_tier_capacities().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("promoted.mkv").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("playing.mkv").as_deref(), Some("hot"));
+        assert_eq!(storage.tier_of("idle.mkv").as_deref(), Some("warm"));
+
+        storage.set_open("playing.mkv", None);
+        clock.advance(Duration::from_secs(PROMOTION_COOLDOWN_SEC));
+        tm.check_tier_capacities().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("promoted.mkv").as_deref(), Some("warm"));
+        assert_eq!(storage.tier_of("playing.mkv").as_deref(), Some("warm"));
+    }
+
+    #[test]
+    fn test_scratch_dirs() {
+        let (storage, clock, tm) = tiering_manager(json!({ "scratch_dirs": [{ "path": "transcode", "max_age_hours": 1 }] }));
+        let t0 = start();
+        storage.create_file("transcode/old.ts", GB, t0).unwrap();
+        storage.create_file("transcode/new.ts", GB, t0).unwrap();
+        storage.move_file(Path::new("transcode/old.ts"), "hot", "cold", &|_| {}).unwrap();
+        tm.update_file_metadata().unwrap();
+        for minutes in 1..=3 {
+            storage.access("transcode/new.ts", t0 + Duration::from_secs(minutes * 60));
+            tm.update_file_metadata().unwrap();
+        }
+        // reads are tracked for cleanup but never count towards promotion
+        let metadata = tm.file_metadata("transcode/new.ts").unwrap().unwrap();
+        assert_eq!(metadata.access_count, 1);
+        assert_eq!(metadata.last_access_time, t0 + Duration::from_secs(180));
+        tm.move_files_based_on_rules().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("transcode/old.ts").as_deref(), Some("hot"));
+
+        clock.advance(Duration::from_secs(3660));
+        tm.clean_scratch_dirs().unwrap();
+        assert_eq!(storage.tier_of("transcode/old.ts"), None);
+        assert!(tm.file_metadata("transcode/old.ts").unwrap().is_none());
+        assert_eq!(storage.tier_of("transcode/new.ts").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_ttl_policy() {
+        let config = |delete: bool| json!({ "ttl_policy": { "delete": delete, "rules": [
+            { "path": "downloads/*", "demote_after_days": 7, "delete_after_days": 30 },
+        ] } });
+        let (storage, clock, tm) = tiering_manager(config(false));
+        let t0 = start();
+        for path in ["downloads/a.iso", "downloads/open.iso", "media/b.mkv"] {
+            storage.create_file(path, GB, t0).unwrap();
+        }
+        clock.advance(Duration::from_secs(7 * 86400));
+        tm.update_file_metadata().unwrap();
+        tm.move_files_based_on_rules().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("downloads/a.iso").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("media/b.mkv").as_deref(), Some("hot"));
+
+        // past delete_after_days nothing goes until deletion is turned on
+        clock.advance(Duration::from_secs(23 * 86400));
+        tm.expire_ttl_files().unwrap();
+        assert_eq!(storage.tier_of("downloads/a.iso").as_deref(), Some("cold"));
+
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), config(true), storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        tm.update_file_metadata().unwrap();
+        storage.set_open("downloads/open.iso", Some(OpenMode::Read));
+        tm.expire_ttl_files().unwrap();
+        assert_eq!(storage.tier_of("downloads/a.iso"), None);
+        assert!(tm.file_metadata("downloads/a.iso").unwrap().is_none());
+        assert!(storage.tier_of("downloads/open.iso").is_some());
+        assert_eq!(storage.tier_of("media/b.mkv").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_external_jobs_pause_tiering() {
+        let (storage, clock, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        let now = clock.now();
+        let job = RunningJob { name: "backup".to_string(), started_at: now, expires_at: now + Duration::from_secs(3600) };
+        tm.db.lock().unwrap().start_job(&job).unwrap();
+        assert_eq!(tm.running_jobs(), [job]);
+
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().completed.is_empty());
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("hot"));
+
+        assert!(tm.db.lock().unwrap().finish_job("backup").unwrap());
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("cold"));
+
+        // a job that never finishes stops pausing tiering once it expires
+        tm.db.lock().unwrap().start_job(&RunningJob { name: "scrub".to_string(), started_at: now, expires_at: now + Duration::from_secs(60) }).unwrap();
+        assert_eq!(tm.running_jobs().len(), 1);
+        clock.advance(Duration::from_secs(60));
+        assert!(tm.running_jobs().is_empty());
+    }
+
+    #[test]
+    fn test_jobs_hold_their_drives() {
+        let storage = Arc::new(SimulatedStorage::new(vec![
+            SimDrive::new("nvme0", "nvme", 10 * GB),
+            SimDrive::new("hdd0", "hdd", 100 * GB),
+            SimDrive::new("hdd1", "hdd", 50 * GB),
+        ]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let config = json!({ "external_jobs": { "scrub-hdd0": { "drives": ["hdd0"] }, "smart-nvme0": { "drives": ["nvme0"] }, "smart-hdd1": { "drives": ["hdd1"] } } });
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), config, storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        for path in ["a.mkv", "b.mkv"] {
+            storage.create_file(path, GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        let start_job = |name: &str| {
+            let now = clock.now();
+            tm.db.lock().unwrap().start_job(&RunningJob { name: name.to_string(), started_at: now, expires_at: now + Duration::from_secs(3600) }).unwrap();
+        };
+
+        // a job on some drives leaves tiering running, and off them
+        start_job("scrub-hdd0");
+        assert_eq!(tm.paused_for(), None);
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.branch_of(Path::new("a.mkv"), "cold").as_deref(), Some("hdd1"));
+
+        // with every cold drive held the move waits, as it does when the
+        // drive it is on is held
+        start_job("smart-hdd1");
+        tm.queue_file_move("b.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().completed.is_empty());
+        assert!(tm.db.lock().unwrap().finish_job("smart-hdd1").unwrap());
+        start_job("smart-nvme0");
+        clock.advance(Duration::from_secs(OPEN_FILE_DEFER_SEC));
+        assert!(tm.process_queued_moves().completed.is_empty());
+        assert!(tm.db.lock().unwrap().finish_job("smart-nvme0").unwrap());
+        clock.advance(Duration::from_secs(OPEN_FILE_DEFER_SEC));
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.branch_of(Path::new("b.mkv"), "cold").as_deref(), Some("hdd1"));
+        assert_eq!(tm.scheduled_retries(), 0);
+    }
+
+    #[test]
+    fn test_drain_for_reload() {
+        let (storage, clock, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        let before = clock.now();
+        tm.drain_for_reload();
+        assert!(clock.now() > before);
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().completed.is_empty());
+        tm.cancel_reload();
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+    }
+
+    #[test]
+    fn test_shut_down() {
+        let (storage, clock, tm) = tiering_manager(json!({ "shutdown_timeout_sec": 30 }));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        let info = FileMoveInfo { src: "a.mkv".into(), source_tier: "hot".to_string(), target_tier: "cold".to_string(), retries: 0 };
+        tm.start_transfer(&info, GB);
+        assert_eq!(tm.shut_down().unwrap(), 1);
+        assert_eq!(clock.now(), start() + Duration::from_secs(30));
+        assert_eq!(tm.paused_for().as_deref(), Some("shutdown"));
+        assert_eq!(tm.db.lock().unwrap().transfers().unwrap().len(), 1);
+        tm.finish_transfer(Path::new("a.mkv"));
+        assert_eq!(tm.shut_down().unwrap(), 0);
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().completed.is_empty());
+    }
+
+    #[test]
+    fn test_copying_from() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        assert!(!tm.copying_from("nvme0").unwrap());
+        let info = FileMoveInfo { src: "a.mkv".into(), source_tier: "hot".to_string(), target_tier: "warm".to_string(), retries: 0 };
+        tm.start_transfer(&info, GB);
+        assert!(tm.copying_from("nvme0").unwrap());
+        assert!(!tm.copying_from("ssd0").unwrap());
+    }
+
+    #[test]
+    fn test_pause() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        assert!(tm.pause());
+        assert!(!tm.pause());
+        assert_eq!(tm.paused_for().as_deref(), Some("ctl pause"));
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().completed.is_empty());
+        assert!(tm.resume());
+        assert!(!tm.is_paused());
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+    }
+
+    #[test]
+    fn test_loop_passes() {
+        let (_, clock, tm) = tiering_manager(json!({}));
+        let started = tm.pass_started("capacity check");
+        assert!(tm.db.lock().unwrap().loop_runs().unwrap()[0].running);
+        clock.sleep(Duration::from_secs(3));
+        let result: io::Result<()> = Err(io::Error::other("no branches"));
+        tm.pass_finished("capacity check", started, &result, started + Duration::from_secs(60));
+        let run = tm.db.lock().unwrap().loop_runs().unwrap().remove(0);
+        assert!(!run.running);
+        assert_eq!((run.duration, run.error.as_deref(), run.next_run), (Duration::from_secs(3), Some("no branches"), Some(started + Duration::from_secs(60))));
+    }
+
+    #[test]
+    fn test_hardlink_farms() {
+        let farm_config = |action| json!({ "hardlink_farms": { "action": action, "min_files": 4 }, "tier_capacity_threshold": 5.0 });
+        let (storage, _, tm) = tiering_manager(farm_config("exclude"));
+        let t0 = start();
+        storage.create_file("backups/daily.0/a", GB, t0).unwrap();
+        storage.create_file("backups/daily.0/b", GB, t0).unwrap();
+        storage.link_file("backups/daily.0/a", "backups/daily.1/a").unwrap();
+        storage.link_file("backups/daily.0/b", "backups/daily.1/b").unwrap();
+        storage.link_file("backups/daily.0/b", "movies/b.mkv").unwrap();
+        storage.create_file("movies/c.mkv", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.hardlink_farms(), [PathBuf::from("backups")]);
+        // movies/b.mkv is linked into the farm, so it stays too
+        tm.check_tier_capacities().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("movies/c.mkv").as_deref(), Some("warm"));
+        for path in ["backups/daily.0/a", "backups/daily.1/b", "movies/b.mkv"] {
+            assert_eq!(storage.tier_of(path).as_deref(), Some("hot"));
+        }
+
+        let (storage, _, tm) = tiering_manager(farm_config("group"));
+        storage.create_file("backups/daily.0/a", GB, t0).unwrap();
+        for snapshot in ["daily.1", "daily.2", "daily.3"] {
+            storage.link_file("backups/daily.0/a", format!("backups/{}/a", snapshot)).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("backups/daily.2/a".into(), "hot".to_string(), "cold".to_string());
+        tm.queue_file_move("backups/daily.0/a".into(), "hot".to_string(), "cold".to_string());
+        assert_eq!(tm.process_queued_moves().completed.len(), 2);
+        for snapshot in ["daily.0", "daily.1", "daily.2", "daily.3"] {
+            let path = format!("backups/{}/a", snapshot);
+            assert_eq!(storage.tier_of(&path).as_deref(), Some("cold"));
+            assert_eq!(tm.file_metadata(&path).unwrap().unwrap().tier, "cold");
+        }
+        // still one copy
+        assert_eq!(storage.tier_usage("cold").unwrap().used, GB);
+        assert_eq!(storage.tier_usage("hot").unwrap().used, 0);
+    }
+
+    #[test]
+    fn test_load_throttling() {
+        let (storage, _, tm) = tiering_manager(json!({ "load_throttle": { "max_load_per_cpu": 2.0 }, "move_workers": { "demote": 3 } }));
+        let proc_root = tempfile::tempdir().unwrap();
+        let set_load = |load: &str| {
+            std::fs::write(proc_root.path().join("loadavg"), format!("{} 1.00 1.00 1/100 1\n", load)).unwrap();
+            std::fs::write(proc_root.path().join("diskstats"), "   8       0 sda 1 0 8 1 1 0 8 1 0 0 2\n").unwrap();
+        };
+        *tm.load_monitor.lock().unwrap() = LoadMonitor::new(proc_root.path(), 1);
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+
+        set_load("2.50");
+        assert_eq!(tm.check_load().unwrap(), Throttle::Pause);
+        assert!(tm.process_queued_moves().completed.is_empty());
+        set_load("1.60");
+        assert_eq!(tm.check_load().unwrap(), Throttle::Slow);
+        assert_eq!(tm.lane_limit("hot", "cold"), 1);
+        set_load("0.20");
+        assert_eq!(tm.check_load().unwrap(), Throttle::Normal);
+        assert_eq!(tm.lane_limit("hot", "cold"), 3);
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+    }
+
+    #[test]
+    fn test_disk_io() {
+        let (_, _, tm) = tiering_manager(json!({ "load_throttle": { "max_disk_latency_ms": 20 } }));
+        let proc_root = tempfile::tempdir().unwrap();
+        let set_stats = |reads: u64, read_ms: u64| {
+            std::fs::write(proc_root.path().join("loadavg"), "0.10 0.10 0.10 1/100 1\n").unwrap();
+            std::fs::write(proc_root.path().join("diskstats"), format!("   8       0 sda {} 0 8 {} 0 0 0 0 0 100 2\n", reads, read_ms)).unwrap();
+        };
+        *tm.load_monitor.lock().unwrap() = LoadMonitor::new(proc_root.path(), 1);
+        *tm.disk_stats.lock().unwrap() = DiskStatsCollector::new(proc_root.path());
+        tm.set_drives(vec![("WD-1".to_string(), "sda".to_string())]);
+
+        set_stats(10, 10);
+        tm.sample_disk_io().unwrap();
+        assert!(tm.disk_io().is_empty());
+        tm.clock.sleep(Duration::from_secs(60));
+        set_stats(20, 260);
+        tm.sample_disk_io().unwrap();
+        assert_eq!(tm.disk_io()["WD-1"].read_latency_ms, 25.0);
+        assert_eq!(tm.db.lock().unwrap().latest_disk_io().unwrap()[0].0, "WD-1");
+        assert_eq!(tm.check_load().unwrap(), Throttle::Pause);
+    }
+
+    #[test]
+    fn test_write_skew() {
+        let (_, _, tm) = tiering_manager(json!({ "write_skew": { "window_hours": 1, "min_gb": 1 } }));
+        let now = tm.clock.now();
+        {
+            let db = tm.db.lock().unwrap();
+            for (serial, bytes) in [("WD-1", 9 * GB), ("WD-2", GB / 2), ("WD-3", GB / 2)] {
+                db.register_drive(serial, "cold", now).unwrap();
+                db.record_disk_io(serial, now, &DiskIo { bytes_written: bytes, ..Default::default() }).unwrap();
+            }
+        }
+        tm.check_write_skew().unwrap();
+        assert_eq!(*tm.write_skewed.lock().unwrap(), HashSet::from(["WD-1".to_string()]));
+        // out of the window, nothing is written at all
+        tm.clock.sleep(Duration::from_secs(2 * 3600));
+        tm.check_write_skew().unwrap();
+        assert!(tm.write_skewed.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_archive_and_restore() {
+        let storage = Arc::new(SimulatedStorage::new(vec![SimDrive::new("hdd0", "hdd", 100 * GB)]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let config = json!({ "archive": { "bucket": "nas", "after_days": 30, "max_gb_per_check": 2 } });
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), config, storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        storage.create_file("open.mkv", GB, start()).unwrap();
+        for (index, path) in ["a.mkv", "b.mkv", "c.mkv", "pinned.mkv"].iter().enumerate() {
+            storage.create_file(path, GB, start() + Duration::from_secs(index as u64 + 1)).unwrap();
+        }
+        storage.set_placement("pinned.mkv", Placement { tier: Some("cold".to_string()), ..Default::default() });
+        storage.set_open("open.mkv", Some(OpenMode::Read));
+        clock.advance(Duration::from_secs(31 * 86400));
+        storage.access("c.mkv", clock.now());
+        tm.update_file_metadata().unwrap();
+        tm.archive_cold_files().unwrap();
+        // c was read lately, the pinned and open files stay, and b waits for the next check
+        let archived: Vec<PathBuf> = tm.db.lock().unwrap().archived_files().unwrap().into_iter().map(|file| file.path).collect();
+        assert_eq!(archived, [PathBuf::from("a.mkv"), PathBuf::from("b.mkv")]);
+        assert!(storage.size_of("a.mkv").unwrap() < GB);
+
+        // a stub is left where it is, until a read of it brings the file back
+        tm.update_file_metadata().unwrap();
+        assert!(tm.db.lock().unwrap().placement("a.mkv").unwrap().exclude);
+        tm.restore_read_archives().unwrap();
+        assert_eq!(tm.db.lock().unwrap().archived_files().unwrap().len(), 2);
+        clock.advance(Duration::from_secs(60));
+        storage.access("a.mkv", clock.now());
+        tm.update_file_metadata().unwrap();
+        tm.restore_read_archives().unwrap();
+        assert_eq!(storage.size_of("a.mkv"), Some(GB));
+        let archived: Vec<PathBuf> = tm.db.lock().unwrap().archived_files().unwrap().into_iter().map(|file| file.path).collect();
+        assert_eq!(archived, [PathBuf::from("b.mkv")]);
+        tm.update_file_metadata().unwrap();
+        assert!(!tm.db.lock().unwrap().placement("a.mkv").unwrap().exclude);
+
+        // a deleted stub is forgotten
+        storage.remove_file("b.mkv");
+        tm.restore_read_archives().unwrap();
+        assert!(tm.db.lock().unwrap().archived_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_seed_new_branches() {
+        let storage = Arc::new(SimulatedStorage::new(vec![
+            SimDrive::new("nvme0", "nvme", 10 * GB),
+            SimDrive::new("ssd0", "ssd", 20 * GB),
+            SimDrive::new("ssd1", "ssd", 20 * GB),
+        ]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let config = json!({ "rebalance_new_drives": true, "keep_together": [{ "directory": "Season *" }] });
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), config, storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        // fills nvme0, then ssd0 in the warm tier
+        for index in 0..10 {
+            storage.create_file(format!("hot/{}.mkv", index), GB, start()).unwrap();
+        }
+        for (index, path) in ["old.mkv", "tv/Season 1/e01.mkv", "tv/Season 1/e02.mkv", "tv/Season 1/e03.mkv", "a.mkv", "b.mkv"].iter().enumerate() {
+            storage.create_file(path, GB, start() + Duration::from_secs(index as u64)).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        // the first check learns the drives, ssd1 among them, so make it new
+        tm.db.lock().unwrap().add_known_branch("nvme0", "hot", start()).unwrap();
+        tm.db.lock().unwrap().add_known_branch("ssd0", "warm", start()).unwrap();
+        tm.seed_new_branches().unwrap();
+
+        // ssd1 has half the warm capacity, so it gets 3 of the 6GB
+        let mut moved: Vec<PathBuf> = tm.process_queued_moves().completed.into_iter().map(|info| info.src).collect();
+        assert_eq!(moved.len(), 3);
+        assert!(moved.iter().all(|path| storage.branch_of(path, "warm").as_deref() == Some("ssd1")));
+        // the season only goes whole, and there is not room in the share
+        // for it after the newer files
+        moved.sort();
+        assert_eq!(moved, [PathBuf::from("a.mkv"), PathBuf::from("b.mkv"), PathBuf::from("old.mkv")]);
+        tm.seed_new_branches().unwrap();
+        assert!(tm.process_queued_moves().completed.is_empty());
+    }
+
+    #[test]
+    fn test_offline_branch() {
+        let (storage, clock, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.seed_new_branches().unwrap();
+        storage.set_offline("nvme0", true);
+        // the hot pool carries on with what is left
+        storage.create_file("b.mkv", GB, start()).unwrap();
+        assert_eq!(storage.tier_of("b.mkv").as_deref(), Some("warm"));
+        tm.validate_and_update_database().unwrap();
+        let db = tm.db.lock().unwrap();
+        assert_eq!(db.offline_branches().unwrap(), [("nvme0".to_string(), start())]);
+        assert!(db.get("a.mkv").unwrap().is_some());
+        assert_eq!(db.unavailable_files().unwrap(), HashSet::from([PathBuf::from("a.mkv")]));
+        assert!(db.get("b.mkv").unwrap().is_some());
+        drop(db);
+        // nothing is queued for a file that cannot be read
+        tm.db.lock().unwrap().insert("a.mkv", &FileMetadata { access_count: 10, last_access_time: start(), file_size: GB, tier: "warm".to_string(), last_tier_move: None }).unwrap();
+        clock.advance(Duration::from_secs(60));
+        tm.move_files_based_on_rules().unwrap();
+        assert!(!tm.move_queue.is_queued_or_in_flight(Path::new("a.mkv")));
+
+        storage.set_offline("nvme0", false);
+        tm.update_file_metadata().unwrap();
+        let db = tm.db.lock().unwrap();
+        assert!(db.offline_branches().unwrap().is_empty());
+        assert_eq!(db.get("a.mkv").unwrap().unwrap().tier, "hot");
+    }
+
+    #[test]
+    fn test_drain() {
+        let storage = Arc::new(SimulatedStorage::new(vec![
+            SimDrive::new("nvme0", "nvme", 10 * GB),
+            SimDrive::new("ssd0", "ssd", 20 * GB),
+            SimDrive::new("ssd1", "ssd", 20 * GB),
+        ]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), json!({}), storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        // fills nvme0, then ssd0
+        for index in 0..13 {
+            storage.create_file(format!("{}.mkv", index), GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        tm.db.lock().unwrap().request_drain("ssd0", clock.now()).unwrap();
+        tm.advance_drains().unwrap();
+        let drain = |tm: &TieringManager| tm.db.lock().unwrap().drains().unwrap().remove(0);
+        assert_eq!((drain(&tm).state, drain(&tm).files_left), (DrainState::Moving, 3));
+
+        // written through the pool while the drain runs
+        storage.create_file("late.mkv", GB, clock.now()).unwrap();
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.process_queued_moves().completed.len(), 3);
+        tm.advance_drains().unwrap();
+        assert_eq!((drain(&tm).state, drain(&tm).files_left), (DrainState::Moving, 1));
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        tm.advance_drains().unwrap();
+        assert_eq!(drain(&tm).state, DrainState::Done);
+        assert!(["10.mkv", "12.mkv", "late.mkv"].iter().all(|path| storage.branch_of(Path::new(path), "warm").as_deref() == Some("ssd1")));
+        // a detached drive takes no more moves
+        tm.queue_file_move("0.mkv".into(), "hot".to_string(), "warm".to_string());
+        tm.process_queued_moves();
+        assert_eq!(storage.branch_of(Path::new("0.mkv"), "warm").as_deref(), Some("ssd1"));
+    }
+
+    #[test]
+    fn test_power_state() {
+        let supplies = tempfile::tempdir().unwrap();
+        let ac = supplies.path().join("AC");
+        std::fs::create_dir(&ac).unwrap();
+        std::fs::write(ac.join("type"), "Mains\n").unwrap();
+        let (storage, _, tm) = tiering_manager(json!({ "power": { "power_supply_dir": supplies.path() } }));
+        for path in ["a.mkv", "b.mkv"] {
+            storage.create_file(path, GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+
+        std::fs::write(ac.join("online"), "0\n").unwrap();
+        assert_eq!(tm.check_power().unwrap(), PowerState::Battery);
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        let requested = tm.migrate(Path::new("b.mkv"), "cold").unwrap();
+        // only the move someone asked for goes ahead
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert!(requested.recv().unwrap().is_ok());
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("hot"));
+
+        std::fs::write(ac.join("online"), "1\n").unwrap();
+        assert_eq!(tm.check_power().unwrap(), PowerState::Mains);
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("cold"));
+    }
+
+    #[test]
+    fn test_emergency_eviction() {
+        let storage = Arc::new(SimulatedStorage::new(vec![
+            SimDrive::new("nvme0", "nvme", 10 * GB),
+            SimDrive::new("nvme1", "nvme", 10 * GB),
+            SimDrive::new("ssd0", "ssd", 20 * GB),
+        ]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), json!({}), storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        // the first drive takes every new file until it is full
+        for i in 0..10 {
+            storage.create_file(format!("{}.mkv", i), GB, start() + Duration::from_secs(i)).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        // the tier is only half full
+        tm.check_tier_capacities().unwrap();
+        assert_eq!(tm.process_queued_moves().completed.len(), 0);
+
+        tm.check_branch_capacities().unwrap();
+        let evicted: Vec<PathBuf> = tm.process_queued_moves().completed.into_iter().map(|info| info.src).collect();
+        assert_eq!(evicted, [PathBuf::from("0.mkv"), PathBuf::from("1.mkv")]);
+        assert_eq!(storage.branch_of(Path::new("0.mkv"), "hot").as_deref(), Some("nvme1"));
+        assert_eq!(storage.branch_of(Path::new("2.mkv"), "hot").as_deref(), Some("nvme0"));
+
+        // with no other branch in the tier, files go down one
+        let (storage, _, tm) = tiering_manager(json!({ "emergency_capacity_threshold": 95.0 }));
+        for i in 0..10 {
+            storage.create_file(format!("{}.mkv", i), GB, start() + Duration::from_secs(i)).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        tm.check_branch_capacities().unwrap();
+        tm.process_queued_moves();
+        assert_eq!(storage.tier_of("1.mkv").as_deref(), Some("warm"));
+        assert_eq!(storage.tier_of("2.mkv").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_mergerfs_relocations() {
+        let storage = Arc::new(SimulatedStorage::new(vec![
+            SimDrive::new("nvme0", "nvme", 10 * GB),
+            SimDrive::new("nvme1", "nvme", 10 * GB),
+            SimDrive::new("ssd0", "ssd", 20 * GB),
+        ]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), json!({}), storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone());
+        storage.create_file("a.mkv", 2 * GB, start()).unwrap();
+        storage.create_file("b.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        let branches = |tm: &TieringManager| tm.db.lock().unwrap().branch_bytes().unwrap();
+        assert_eq!(branches(&tm), [("nvme0".to_string(), 3 * GB)]);
+
+        storage.relocate("a.mkv").unwrap();
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.db.lock().unwrap().branches().unwrap()[Path::new("a.mkv")], "nvme1");
+        assert_eq!(branches(&tm), [("nvme0".to_string(), GB), ("nvme1".to_string(), 2 * GB)]);
+
+        // our own moves are recorded as they happen
+        tm.queue_file_move("b.mkv".into(), "hot".to_string(), "warm".to_string());
+        tm.process_queued_moves();
+        assert_eq!(branches(&tm), [("nvme1".to_string(), 2 * GB), ("ssd0".to_string(), GB)]);
+    }
+
+    #[test]
+    fn test_mount_events() {
+        let (storage, clock, tm) = tiering_manager(json!({}));
+        for path in ["a.mkv", "gone.mkv", "shows/e01.mkv", "shows/e02.mkv"] {
+            storage.create_file(path, GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        clock.advance(Duration::from_secs(60));
+        storage.access("a.mkv", clock.now());
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.file_metadata(Path::new("a.mkv")).unwrap().unwrap().access_count, 2);
+
+        tm.apply_mount_events(&[
+            MountEvent::Renamed { from: "a.mkv".into(), to: "b.mkv".into(), dir: false },
+            MountEvent::Renamed { from: "shows".into(), to: "series".into(), dir: true },
+            MountEvent::Deleted { path: "gone.mkv".into(), dir: false },
+        ]).unwrap();
+        assert!(tm.file_metadata(Path::new("a.mkv")).unwrap().is_none());
+        assert_eq!(tm.file_metadata(Path::new("b.mkv")).unwrap().unwrap().access_count, 2);
+        assert!(tm.file_metadata(Path::new("series/e02.mkv")).unwrap().is_some());
+        assert!(tm.file_metadata(Path::new("shows/e01.mkv")).unwrap().is_none());
+        assert!(tm.file_metadata(Path::new("gone.mkv")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cold_read_promotion() {
+        let (storage, clock, tm) = tiering_manager(json!({ "cold_read_promotion": { "reads": 2, "window_sec": 60 } }));
+        for path in ["a.mkv", "pinned.mkv", "hot.mkv"] {
+            storage.create_file(path, GB, start()).unwrap();
+        }
+        for path in ["a.mkv", "pinned.mkv"] {
+            storage.move_file(Path::new(path), "hot", "cold", &|_| {}).unwrap();
+        }
+        storage.set_placement("pinned.mkv", Placement { tier: Some("cold".to_string()), ..Default::default() });
+        tm.update_file_metadata().unwrap();
+        for path in ["a.mkv", "pinned.mkv", "hot.mkv", "unknown.mkv"] {
+            assert!(!tm.record_cold_read(Path::new(path)).unwrap());
+        }
+        clock.advance(Duration::from_secs(30));
+        assert!(tm.record_cold_read(Path::new("a.mkv")).unwrap());
+        assert!(!tm.record_cold_read(Path::new("pinned.mkv")).unwrap());
+        assert!(!tm.record_cold_read(Path::new("hot.mkv")).unwrap());
+        assert!(tm.move_queue.is_queued_or_in_flight(Path::new("a.mkv")));
+        assert_eq!(tm.move_queue.len(), 1);
+    }
+
+    #[test]
+    fn test_open_files_are_deferred() {
+        let (storage, clock, tm) = tiering_manager(json!({ "open_files": "writers" }));
+        let t0 = start();
+        storage.create_file("download.iso", GB, t0).unwrap();
+        storage.create_file("stream.mkv", GB, t0).unwrap();
+        storage.set_open("download.iso", Some(OpenMode::Write));
+        storage.set_open("stream.mkv", Some(OpenMode::Read));
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("download.iso".into(), "hot".to_string(), "cold".to_string());
+        tm.queue_file_move("stream.mkv".into(), "hot".to_string(), "cold".to_string());
+        let processed = tm.process_queued_moves();
+        // only writers are deferred with this setting
+        assert_eq!(processed.completed.len(), 1);
+        assert_eq!(storage.tier_of("download.iso").as_deref(), Some("hot"));
+        assert_eq!(tm.scheduled_retries(), 1);
+
+        storage.set_open("download.iso", None);
+        clock.advance(Duration::from_secs(OPEN_FILE_DEFER_SEC));
+        let processed = tm.process_queued_moves();
+        assert_eq!(processed.completed[0].retries, 0);
+        assert_eq!(storage.tier_of("download.iso").as_deref(), Some("cold"));
+    }
+
+    #[test]
+    fn test_move_workers() {
+        let (_, _, tm) = tiering_manager(json!({ "move_workers": { "promote": 2, "demote": 4, "warm->cold": 1 } }));
+        assert_eq!(tm.move_workers("hot", "warm"), 4);
+        assert_eq!(tm.move_workers("warm", "cold"), 1);
+        assert_eq!(tm.move_workers("cold", "hot"), 2);
+        let (_, _, tm) = tiering_manager(json!({}));
+        assert_eq!(tm.move_workers("hot", "warm"), IO_THREADS);
+    }
+
+    #[test]
+    fn test_migrate_jumps_the_queue() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        let t0 = start();
+        storage.create_file("a", GB, t0).unwrap();
+        storage.create_file("b", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("a".into(), "hot".to_string(), "cold".to_string());
+        let outcome = tm.migrate(Path::new("b"), "warm").unwrap();
+        assert!(tm.migrate(Path::new("missing"), "warm").is_err());
+        assert!(tm.migrate(Path::new("b"), "lukewarm").is_err());
+        let moved = tm.process_queued_moves().completed;
+        assert_eq!(moved.iter().map(|m| m.src.to_str().unwrap()).collect::<Vec<_>>(), ["b", "a"]);
+        assert!(outcome.recv().unwrap().is_ok());
+        assert_eq!(storage.tier_of("b").as_deref(), Some("warm"));
+        // already there
+        assert!(tm.migrate(Path::new("b"), "warm").unwrap().recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_repeated_checks_queue_once() {
+        let (storage, _, tm) = tiering_manager(json!({ "tier_capacity_threshold": 50.0, "access_count_threshold": 1 }));
+        let t0 = start();
+        for i in 0..8 {
+            storage.create_file(format!("f{}", i), GB, t0).unwrap();
+        }
+        tm.perform_tiering_check().unwrap();
+        tm.perform_tiering_check().unwrap();
+        let processed = tm.process_queued_moves();
+        assert_eq!(processed.completed.len(), 8);
+        assert!(processed.abandoned.is_empty());
+    }
+
+    #[test]
+    fn test_validate_and_update_database() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        let t0 = start();
+        storage.create_file("a", GB, t0).unwrap();
+        storage.create_file("b", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        storage.remove_file("a");
+        tm.validate_and_update_database().unwrap();
+        assert!(tm.file_metadata("a").unwrap().is_none());
+        assert!(tm.file_metadata("b").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_validate_during_crawl() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", 9 * GB, start()).unwrap();
+        storage.create_file("b.mkv", 2 * GB, start()).unwrap();
+        assert_eq!(storage.branch_of(Path::new("b.mkv"), "warm").as_deref(), Some("ssd0"));
+        tm.update_file_metadata().unwrap();
+        tm.db.lock().unwrap().set_checksum(Path::new("b.mkv"), "sha256", "ab", 2 * GB, start()).unwrap();
+        // what the crawl of ssd0 has recorded so far is not pruned
+        storage.set_crawling("ssd0", true);
+        tm.validate_and_update_database().unwrap();
+        let db = tm.db.lock().unwrap();
+        assert!(db.get("b.mkv").unwrap().is_some());
+        assert_eq!(db.checksum(Path::new("b.mkv")).unwrap(), Some(("sha256".to_string(), "ab".to_string())));
+        assert!(db.offline_branches().unwrap().is_empty());
+        drop(db);
+        storage.set_crawling("ssd0", false);
+        storage.remove_file("b.mkv");
+        tm.validate_and_update_database().unwrap();
+        assert!(tm.file_metadata("b.mkv").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_failed_move_is_retried_then_dropped() {
+        let (storage, clock, tm) = tiering_manager(json!({ "retry": { "max_retries": 3, "initial_backoff_sec": 60, "jitter": 0 } }));
+        let t0 = start();
+        storage.create_file("big", GB, t0).unwrap();
+        tm.update_file_metadata().unwrap();
+        storage.remove_file("big");
+        tm.queue_file_move("big".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().abandoned.is_empty());
+        assert_eq!(tm.scheduled_retries(), 1);
+        // not due yet
+        clock.advance(Duration::from_secs(59));
+        assert!(tm.process_queued_moves().abandoned.is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert!(tm.process_queued_moves().abandoned.is_empty());
+        clock.advance(Duration::from_secs(120));
+        assert!(tm.process_queued_moves().abandoned.is_empty());
+        clock.advance(Duration::from_secs(240));
+        let processed = tm.process_queued_moves();
+        assert!(processed.completed.is_empty());
+        assert_eq!(processed.abandoned.len(), 1);
+        assert_eq!(processed.abandoned[0].retries, 3);
+        assert_eq!(tm.scheduled_retries(), 0);
+
+        // dead-lettered moves can be retried from the CLI once the cause is fixed
+        let failures = tm.db.lock().unwrap().failures().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].info.src, Path::new("big"));
+        storage.create_file("big", GB, t0).unwrap();
+        tm.db.lock().unwrap().request_retry_all().unwrap();
+        assert_eq!(tm.requeue_requested_failures().unwrap(), 1);
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.tier_of("big").as_deref(), Some("cold"));
+        assert!(tm.db.lock().unwrap().failures().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retries_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("file_metadata.db");
+        let config = json!({ "retry": { "max_retries": 3, "initial_backoff_sec": 60, "jitter": 0 } });
+        let storage = Arc::new(SimulatedStorage::new(vec![SimDrive::new("nvme0", "nvme", 10 * GB), SimDrive::new("hdd0", "hdd", 100 * GB)]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let open = || TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), config.clone(), storage.clone(), MetadataDb::open(&db_path).unwrap(), clock.clone());
+        let tm = open();
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        storage.remove_file("a.mkv");
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        tm.process_queued_moves();
+        clock.advance(Duration::from_secs(60));
+        tm.process_queued_moves();
+        drop(tm);
+
+        let tm = open();
+        assert_eq!(tm.restore_retries().unwrap(), 1);
+        assert_eq!(tm.restore_retries().unwrap(), 0);
+        {
+            let db = tm.db.lock().unwrap();
+            let pending = db.pending_retries().unwrap();
+            assert_eq!((pending[0].info.retries, pending[0].due_at), (2, start() + Duration::from_secs(180)));
+            assert_eq!(db.move_errors(Path::new("a.mkv")).unwrap().len(), 2);
+        }
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        clock.advance(Duration::from_secs(120));
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert!(tm.db.lock().unwrap().pending_retries().unwrap().is_empty());
+        assert!(tm.db.lock().unwrap().move_errors(Path::new("a.mkv")).unwrap().is_empty());
+
+        // dropped from the CLI while it waits
+        storage.create_file("b.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        storage.remove_file("b.mkv");
+        tm.queue_file_move("b.mkv".into(), "hot".to_string(), "cold".to_string());
+        tm.process_queued_moves();
+        assert!(MetadataDb::open(&db_path).unwrap().drop_move(Path::new("b.mkv")).unwrap());
+        storage.create_file("b.mkv", GB, start()).unwrap();
+        clock.advance(Duration::from_secs(60));
+        assert!(tm.process_queued_moves().completed.is_empty());
+        assert_eq!(tm.scheduled_retries(), 0);
+        assert_eq!(storage.tier_of("b.mkv").as_deref(), Some("hot"));
+    }
+
+    #[test]
+    fn test_recover_interrupted_moves() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        storage.create_file("b.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        let progress = |name: &str| TransferProgress {
+            info: FileMoveInfo { src: name.into(), source_tier: "hot".to_string(), target_tier: "cold".to_string(), retries: 0 },
+            bytes_copied: GB / 2, bytes_total: GB, started_at: start(), updated_at: start(),
+        };
+        // b.mkv's copy was renamed into place before the crash
+        storage.move_file(Path::new("b.mkv"), "hot", "cold", &|_| {}).unwrap();
+        for name in ["a.mkv", "b.mkv"] {
+            tm.db.lock().unwrap().record_progress(&progress(name)).unwrap();
+        }
+        assert_eq!(tm.recover_interrupted_moves().unwrap(), 1);
+        assert!(tm.db.lock().unwrap().transfers().unwrap().is_empty());
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("cold"));
+    }
+
+    #[test]
+    fn test_activity_heat() {
+        let (storage, _, tm) = tiering_manager(json!({ "access_tracking": "activity" }));
+        storage.create_file("a.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        // reads leave no trace here, writes do
+        storage.access("a.mkv", start() + Duration::from_secs(60));
+        tm.update_file_metadata().unwrap();
+        assert_eq!(tm.file_metadata("a.mkv").unwrap().unwrap().access_count, 1);
+        storage.write("a.mkv", start() + Duration::from_secs(120));
+        tm.update_file_metadata().unwrap();
+        let metadata = tm.file_metadata("a.mkv").unwrap().unwrap();
+        assert_eq!((metadata.access_count, metadata.last_access_time), (2, start() + Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_journaled_moves_outlive_a_crash() {
+        let (storage, _, tm) = tiering_manager(json!({}));
+        for name in ["a.mkv", "b.mkv", "c.mkv"] {
+            storage.create_file(name, GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        tm.migrate(Path::new("a.mkv"), "cold").unwrap();
+        tm.queue_file_move(PathBuf::from("b.mkv"), "hot".to_string(), "cold".to_string());
+        let journaled = tm.db.lock().unwrap().journaled_moves().unwrap();
+        assert_eq!(journaled.iter().map(|journaled| (journaled.info.src.to_str().unwrap(), journaled.user)).collect::<Vec<_>>(), [("a.mkv", true), ("b.mkv", false)]);
+        // the crash loses the queue, and c.mkv was moved before it
+        while tm.move_queue.try_pop().is_some() {}
+        let info = FileMoveInfo { src: "c.mkv".into(), source_tier: "hot".to_string(), target_tier: "cold".to_string(), retries: 0 };
+        tm.db.lock().unwrap().journal_move(&JournaledMove { info, user: false, size: GB, queued_at: start() }).unwrap();
+        storage.move_file(Path::new("c.mkv"), "hot", "cold", &|_| {}).unwrap();
+        assert_eq!(tm.recover_interrupted_moves().unwrap(), 2);
+        assert_eq!(tm.process_queued_moves().completed.len(), 2);
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("cold"));
+        assert_eq!(storage.tier_of("b.mkv").as_deref(), Some("cold"));
+        assert!(tm.db.lock().unwrap().journaled_moves().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_wait_for_next_check() {
+        let (_, clock, tm) = tiering_manager(json!({}));
+        assert!(!tm.wait_for_next_check(start() + Duration::from_secs(60)));
+        assert_eq!(clock.now(), start() + Duration::from_secs(60));
+        tm.db.lock().unwrap().request_check(clock.now()).unwrap();
+        assert!(tm.wait_for_next_check(clock.now() + Duration::from_secs(3600)));
+        assert_eq!(clock.now(), start() + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_permission_errors_are_not_retried() {
+        let (storage, _, _) = tiering_manager(json!({}));
+        let faults = FaultInjector::new();
+        faults.inject(FaultPoint::Move, Fault::Errno(libc::EPERM));
+        let faulty = Arc::new(FaultyStorage::new(storage.clone(), faults.clone()));
+        let tm = TieringManager::new(Args::parse_from(["--dryrun"]).unwrap(), json!({}), faulty, MetadataDb::open_in_memory().unwrap());
+        storage.create_file("locked.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("locked.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert_eq!(tm.process_queued_moves().abandoned.len(), 1);
+        assert_eq!(faults.triggered(&FaultPoint::Move), 1);
+        assert_eq!(tm.scheduled_retries(), 0);
+    }
+
+    #[test]
+    fn test_transient_move_failure_is_retried() {
+        let storage = Arc::new(SimulatedStorage::new(vec![SimDrive::new("nvme0", "nvme", 10 * GB), SimDrive::new("hdd0", "hdd", 100 * GB)]));
+        let faults = FaultInjector::new();
+        faults.inject_after(FaultPoint::Move, Fault::Errno(libc::EBUSY), 0, 2);
+        let mut db = MetadataDb::open_in_memory().unwrap();
+        db.inject_faults(faults.clone());
+        let faulty = Arc::new(FaultyStorage::new(storage.clone(), faults.clone()));
+        let clock = Arc::new(ManualClock::new(start()));
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), json!({}), faulty, db, clock.clone());
+        let t0 = start();
+        storage.create_file("a.mkv", GB, t0).unwrap();
+
+        faults.inject_after(FaultPoint::DbWrite, Fault::Errno(libc::EIO), 0, 1);
+        assert!(tm.update_file_metadata().is_err());
+        assert!(tm.file_metadata("a.mkv").unwrap().is_none());
+        tm.update_file_metadata().unwrap();
+
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().completed.is_empty());
+        clock.advance(Duration::from_secs(90));
+        assert!(tm.process_queued_moves().completed.is_empty());
+        clock.advance(Duration::from_secs(180));
+        let processed = tm.process_queued_moves();
+        assert_eq!(processed.completed.len(), 1);
+        assert_eq!(processed.completed[0].retries, 2);
+        assert_eq!(storage.tier_of("a.mkv").as_deref(), Some("cold"));
+        assert_eq!(tm.file_metadata("a.mkv").unwrap().unwrap().tier, "cold");
+        assert_eq!(faults.triggered(&FaultPoint::Move), 2);
+    }
+
+    #[test]
+    fn test_move_error_classes() {
+        let storage = Arc::new(SimulatedStorage::new(vec![SimDrive::new("nvme0", "nvme", 10 * GB), SimDrive::new("hdd0", "hdd", 100 * GB)]));
+        let faults = FaultInjector::new();
+        let faulty = Arc::new(FaultyStorage::new(storage.clone(), faults.clone()));
+        let clock = Arc::new(ManualClock::new(start()));
+        let config = json!({ "retry": { "max_retries": 2, "initial_backoff_sec": 60, "jitter": 0 } });
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), config, faulty, MetadataDb::open_in_memory().unwrap(), clock.clone());
+        for path in ["full.mkv", "bad.mkv"] {
+            storage.create_file(path, GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+
+        // out of space goes again straight away, to another destination
+        faults.inject_after(FaultPoint::Move, Fault::Errno(libc::ENOSPC), 0, 1);
+        tm.queue_file_move("full.mkv".into(), "hot".to_string(), "cold".to_string());
+        let processed = tm.process_queued_moves();
+        assert_eq!(processed.completed.len(), 1);
+        assert_eq!(processed.completed[0].retries, 1);
+
+        // an I/O error waits out the backoff and marks the drive suspect
+        faults.inject_after(FaultPoint::Move, Fault::Errno(libc::EIO), 2, 1);
+        tm.queue_file_move("bad.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().completed.is_empty());
+        assert_eq!(tm.scheduled_retries(), 1);
+        let suspects = tm.db.lock().unwrap().suspect_drives().unwrap();
+        assert_eq!((suspects[0].serial.as_str(), suspects[0].errors), ("nvme0", 1));
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+    }
+
+    #[test]
+    fn test_repeated_read_errors_quarantine_file() {
+        let storage = Arc::new(SimulatedStorage::new(vec![SimDrive::new("nvme0", "nvme", 10 * GB), SimDrive::new("hdd0", "hdd", 100 * GB)]));
+        let faults = FaultInjector::new();
+        let faulty = Arc::new(FaultyStorage::new(storage.clone(), faults.clone()));
+        let clock = Arc::new(ManualClock::new(start()));
+        let config = json!({ "retry": { "initial_backoff_sec": 60, "jitter": 0, "quarantine_after": 2 } });
+        let tm = TieringManager::with_clock(Args::parse_from(["--dryrun"]).unwrap(), config, faulty, MetadataDb::open_in_memory().unwrap(), clock.clone());
+        storage.create_file("bad.mkv", GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+
+        // a failed checksum counts as much as a read error
+        faults.inject(FaultPoint::Move, Fault::Errno(libc::EBADE));
+        tm.queue_file_move("bad.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert!(tm.process_queued_moves().abandoned.is_empty());
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(tm.process_queued_moves().abandoned.len(), 1);
+        assert_eq!(tm.scheduled_retries(), 0);
+        {
+            let db = tm.db.lock().unwrap();
+            let quarantined = db.quarantined_files().unwrap();
+            assert_eq!((quarantined[0].path.as_path(), quarantined[0].reason.as_str()), (Path::new("bad.mkv"), "2 read or checksum errors"));
+            assert_eq!(db.move_errors(Path::new("bad.mkv")).unwrap().len(), 2);
+            assert!(db.pending_retries().unwrap().is_empty());
+            assert!(db.failures().unwrap().is_empty());
+        }
+
+        // left out of later checks and moves until it is cleared
+        tm.update_file_metadata().unwrap();
+        assert!(tm.db.lock().unwrap().placement(Path::new("bad.mkv")).unwrap().exclude);
+        tm.queue_file_move("bad.mkv".into(), "hot".to_string(), "cold".to_string());
+        tm.process_queued_moves();
+        assert_eq!(faults.triggered(&FaultPoint::Move), 2);
+
+        faults.clear();
+        assert!(tm.db.lock().unwrap().release_file(Path::new("bad.mkv")).unwrap());
+        tm.update_file_metadata().unwrap();
+        assert!(!tm.db.lock().unwrap().placement(Path::new("bad.mkv")).unwrap().exclude);
+        tm.queue_file_move("bad.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+        assert_eq!(storage.tier_of("bad.mkv").as_deref(), Some("cold"));
+    }
+
+    // Reports half of each file copied ten seconds into the move, noting
+    // what the manager shows for it at that point
+    struct SlowStorage {
+        inner: Arc<SimulatedStorage>,
+        clock: Arc<ManualClock>,
+        tm: OnceLock<Weak<TieringManager>>,
+        seen: Mutex<Vec<(Vec<TransferProgress>, Vec<TransferProgress>)>>,
+    }
+
+    impl Storage for SlowStorage {
+        fn scan(&self) -> io::Result<Vec<ScannedFile>> {
+            self.inner.scan()
+        }
+
+        fn tier_usage(&self, tier: &str) -> io::Result<TierUsage> {
+            self.inner.tier_usage(tier)
+        }
+
+        fn file_size(&self, path: &Path, tier: &str) -> Option<u64> {
+            self.inner.file_size(path, tier)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn open_mode(&self, path: &Path, tier: &str) -> io::Result<Option<OpenMode>> {
+            self.inner.open_mode(path, tier)
+        }
+
+        fn readers(&self) -> io::Result<Vec<OpenReader>> {
+            self.inner.readers()
+        }
+
+        fn move_file(&self, path: &Path, source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+            self.clock.advance(Duration::from_secs(10));
+            progress(self.inner.size_of(path).unwrap_or(0) / 2);
+            let tm = self.tm.get().and_then(Weak::upgrade).unwrap();
+            let saved = tm.db.lock().unwrap().transfers()?;
+            self.seen.lock().unwrap().push((tm.transfers(), saved));
+            self.inner.move_file(path, source_tier, target_tier, progress)
+        }
+
+        fn move_link_group(&self, paths: &[PathBuf], source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+            self.inner.move_link_group(paths, source_tier, target_tier, progress)
+        }
+
+        fn branch_usage(&self) -> io::Result<Vec<BranchUsage>> {
+            self.inner.branch_usage()
+        }
+
+        fn branch_of(&self, path: &Path, tier: &str) -> Option<String> {
+            self.inner.branch_of(path, tier)
+        }
+
+        fn delete_file(&self, path: &Path, tier: &str) -> io::Result<()> {
+            self.inner.delete_file(path, tier)
+        }
+    }
+
+    #[test]
+    fn test_transfer_progress() {
+        let inner = Arc::new(SimulatedStorage::new(vec![SimDrive::new("nvme0", "nvme", 10 * GB), SimDrive::new("hdd0", "hdd", 100 * GB)]));
+        let clock = Arc::new(ManualClock::new(start()));
+        let storage = Arc::new(SlowStorage { inner: inner.clone(), clock: clock.clone(), tm: OnceLock::new(), seen: Mutex::new(Vec::new()) });
+        let args = Args::parse_from(["--dryrun"]).unwrap();
+        let tm = Arc::new(TieringManager::with_clock(args, json!({}), storage.clone(), MetadataDb::open_in_memory().unwrap(), clock.clone()));
+        storage.tm.set(Arc::downgrade(&tm)).unwrap();
+        inner.create_file("a.mkv", 2 * GB, start()).unwrap();
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert_eq!(tm.process_queued_moves().completed.len(), 1);
+
+        let seen = storage.seen.lock().unwrap();
+        let (live, saved) = &seen[0];
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].bytes_copied, GB);
+        assert_eq!(live[0].bytes_total, 2 * GB);
+        assert_eq!(live[0].eta(), Some(Duration::from_secs(10)));
+        // saved for other processes since it has been running long enough
+        assert_eq!(saved, live);
+        assert!(tm.transfers().is_empty());
+        assert!(tm.db.lock().unwrap().transfers().unwrap().is_empty());
+    }
+
+    // Moves a directory in one piece, noting each one it moved
+    struct DirectoryStorage {
+        inner: Arc<SimulatedStorage>,
+        moved: Mutex<Vec<PathBuf>>,
+    }
+
+    impl Storage for DirectoryStorage {
+        fn scan(&self) -> io::Result<Vec<ScannedFile>> {
+            self.inner.scan()
+        }
+
+        fn tier_usage(&self, tier: &str) -> io::Result<TierUsage> {
+            self.inner.tier_usage(tier)
+        }
+
+        fn file_size(&self, path: &Path, tier: &str) -> Option<u64> {
+            self.inner.file_size(path, tier)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            self.inner.exists(path)
+        }
+
+        fn open_mode(&self, path: &Path, tier: &str) -> io::Result<Option<OpenMode>> {
+            self.inner.open_mode(path, tier)
+        }
+
+        fn readers(&self) -> io::Result<Vec<OpenReader>> {
+            self.inner.readers()
+        }
+
+        fn move_file(&self, path: &Path, source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+            self.inner.move_file(path, source_tier, target_tier, progress)
+        }
+
+        fn move_link_group(&self, paths: &[PathBuf], source_tier: &str, target_tier: &str, progress: &dyn Fn(u64)) -> io::Result<()> {
+            self.inner.move_link_group(paths, source_tier, target_tier, progress)
+        }
+
+        fn branch_usage(&self) -> io::Result<Vec<BranchUsage>> {
+            self.inner.branch_usage()
+        }
+
+        fn branch_of(&self, path: &Path, tier: &str) -> Option<String> {
+            self.inner.branch_of(path, tier)
+        }
+
+        fn delete_file(&self, path: &Path, tier: &str) -> io::Result<()> {
+            self.inner.delete_file(path, tier)
+        }
+
+        fn move_directory(&self, dir: &Path, source_tier: &str, target_tier: &str) -> io::Result<()> {
+            for file in self.inner.scan()?.into_iter().filter(|file| file.path.starts_with(dir) && file.tier == source_tier) {
+                self.inner.move_file(&file.path, source_tier, target_tier, &|_| {})?;
+            }
+            self.moved.lock().unwrap().push(dir.to_path_buf());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_move_directory() {
+        let inner = Arc::new(SimulatedStorage::new(vec![SimDrive::new("nvme0", "nvme", 10 * GB), SimDrive::new("hdd0", "hdd", 100 * GB)]));
+        let storage = Arc::new(DirectoryStorage { inner: inner.clone(), moved: Mutex::new(Vec::new()) });
+        let args = Args::parse_from(["--dryrun"]).unwrap();
+        let config = json!({ "keep_together": [{ "directory": "Season *" }] });
+        let tm = TieringManager::with_clock(args, config, storage.clone(), MetadataDb::open_in_memory().unwrap(), Arc::new(ManualClock::new(start())));
+        for path in ["tv/Season 1/e01.mkv", "tv/Season 1/e02.mkv", "tv/Season 2/e01.mkv", "movies/a.mkv"] {
+            inner.create_file(path, GB, start()).unwrap();
+        }
+        tm.update_file_metadata().unwrap();
+        tm.queue_file_move("tv/Season 1/e01.mkv".into(), "hot".to_string(), "cold".to_string());
+        tm.queue_file_move("movies/a.mkv".into(), "hot".to_string(), "cold".to_string());
+        assert_eq!(tm.process_queued_moves().completed.len(), 3);
+        assert_eq!(*storage.moved.lock().unwrap(), [PathBuf::from("tv/Season 1")]);
+        for path in ["tv/Season 1/e01.mkv", "tv/Season 1/e02.mkv", "movies/a.mkv"] {
+            assert_eq!(inner.tier_of(path).as_deref(), Some("cold"));
+            assert_eq!(tm.file_metadata(path).unwrap().unwrap().tier, "cold");
+        }
+        assert_eq!(inner.tier_of("tv/Season 2/e01.mkv").as_deref(), Some("hot"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::executor::{command_line, Executor};
+use crate::metadata_db::{from_unix, to_unix};
+use crate::secrets::redact;
+
+pub const TRANSCRIPT_FILE: &str = "commands.jsonl";
+// how many of the most recent commands are kept
+pub const TRANSCRIPT_LIMIT: usize = 50;
+// only the end of a long stderr is kept; that is where the reason usually is
+const STDERR_LIMIT: usize = 2048;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transcript {
+    pub ran_at: i64,
+    pub command: String,
+    // None when the command could not be started or was killed by a signal
+    pub code: Option<i32>,
+    pub stderr: String,
+}
+
+impl Transcript {
+    pub fn ran_at(&self) -> SystemTime {
+        from_unix(self.ran_at)
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = redact(&String::from_utf8_lossy(stderr));
+    let stderr = stderr.trim();
+    let mut start = stderr.len().saturating_sub(STDERR_LIMIT);
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    stderr[start..].to_string()
+}
+
+// The last TRANSCRIPT_LIMIT commands the service ran, with how they exited
+// and what they wrote to stderr, kept in a file next to the database so
+// `doctor` can show why something failed
+pub struct TranscriptLog {
+    path: PathBuf,
+    entries: Mutex<VecDeque<Transcript>>,
+}
+
+impl TranscriptLog {
+    // The log in `dir`, carrying on from what an earlier run left there
+    pub fn open(dir: &Path) -> Self {
+        let path = dir.join(TRANSCRIPT_FILE);
+        let entries = Self::read(&path).unwrap_or_default().into_iter().collect();
+        Self { path, entries: Mutex::new(entries) }
+    }
+
+    pub fn read(path: &Path) -> io::Result<Vec<Transcript>> {
+        let content = fs::read_to_string(path)?;
+        Ok(content.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+
+    pub fn record(&self, transcript: Transcript) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(transcript);
+        while entries.len() > TRANSCRIPT_LIMIT {
+            entries.pop_front();
+        }
+        let content: String = entries.iter().filter_map(|entry| serde_json::to_string(entry).ok()).map(|line| line + "\n").collect();
+        // replaced whole, so a reader never sees half of it
+        let tmp = self.path.with_extension("jsonl.tmp");
+        if let Err(e) = fs::write(&tmp, content).and_then(|()| fs::rename(&tmp, &self.path)) {
+            warn!("Failed to save command transcripts to {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn recent(&self) -> Vec<Transcript> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+// Records every command run through `inner` in a TranscriptLog
+pub struct RecordingExecutor {
+    inner: Arc<dyn Executor>,
+    log: Arc<TranscriptLog>,
+}
+
+impl RecordingExecutor {
+    pub fn new(inner: Arc<dyn Executor>, log: Arc<TranscriptLog>) -> Self {
+        Self { inner, log }
+    }
+
+    fn record(&self, cmd: &[&OsStr], code: Option<i32>, stderr: String) {
+        self.log.record(Transcript { ran_at: to_unix(SystemTime::now()), command: command_line(cmd), code, stderr });
+    }
+
+    fn record_output(&self, cmd: &[&OsStr], output: io::Result<Output>) -> io::Result<Output> {
+        match &output {
+            Ok(output) => self.record(cmd, output.status.code(), stderr_tail(&output.stderr)),
+            Err(e) => self.record(cmd, None, e.to_string()),
+        }
+        output
+    }
+}
+
+impl Executor for RecordingExecutor {
+    fn status(&self, cmd: &[&OsStr]) -> io::Result<ExitStatus> {
+        let status = self.inner.status(cmd);
+        // stderr went wherever ours goes
+        match &status {
+            Ok(status) => self.record(cmd, status.code(), String::new()),
+            Err(e) => self.record(cmd, None, e.to_string()),
+        }
+        status
+    }
+
+    fn output(&self, cmd: &[&OsStr]) -> io::Result<Output> {
+        self.record_output(cmd, self.inner.output(cmd))
+    }
+
+    fn stream(&self, cmd: &[&OsStr], on_line: &mut dyn FnMut(&str)) -> io::Result<Output> {
+        self.record_output(cmd, self.inner.stream(cmd, on_line))
+    }
+
+    fn output_with_input(&self, cmd: &[&OsStr], input: &[u8]) -> io::Result<Output> {
+        self.record_output(cmd, self.inner.output_with_input(cmd, input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::SystemExecutor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_recording() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(TranscriptLog::open(dir.path()));
+        let executor = RecordingExecutor::new(Arc::new(SystemExecutor), log.clone());
+        executor.output(&["true".as_ref()]).unwrap();
+        executor.output(&["ls".as_ref(), "/nonexistent".as_ref()]).unwrap();
+        assert!(executor.output(&["/nonexistent/tool".as_ref()]).is_err());
+
+        let transcripts = TranscriptLog::read(&dir.path().join(TRANSCRIPT_FILE)).unwrap();
+        assert_eq!(transcripts, log.recent());
+        assert!(transcripts[0].succeeded());
+        assert_eq!(transcripts[1].command, "ls /nonexistent");
+        assert_eq!(transcripts[1].code, Some(2));
+        assert!(transcripts[1].stderr.contains("No such file or directory"));
+        assert_eq!(transcripts[2].code, None);
+
+        // a later run carries on, keeping only the most recent
+        let log = TranscriptLog::open(dir.path());
+        for _ in 0..TRANSCRIPT_LIMIT {
+            log.record(Transcript { ran_at: 0, command: "true".to_string(), code: Some(0), stderr: String::new() });
+        }
+        assert_eq!(log.recent().len(), TRANSCRIPT_LIMIT);
+        assert!(log.recent().iter().all(Transcript::succeeded));
+    }
+
+    #[test]
+    fn test_stderr_tail() {
+        let long = "é".repeat(STDERR_LIMIT);
+        assert!(stderr_tail(long.as_bytes()).len() <= STDERR_LIMIT);
+    }
+}
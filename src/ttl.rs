@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde_json::Value;
+use crate::drive_manager::DriveManager;
+use crate::file_metadata::FileMetadata;
+use crate::tier_rules::FileMatch;
+
+#[derive(Clone, Debug, PartialEq)]
+struct TtlRule {
+    files: FileMatch,
+    demote_after: Option<Duration>,
+    demote_to: String,
+    delete_after: Option<Duration>,
+}
+
+// Temporary data that ages out, such as downloads and caches:
+//   "ttl_policy": { "delete": false, "rules": [
+//     { "path": "downloads/*", "demote_after_days": 7, "demote_to": "cold", "delete_after_days": 30 },
+//     { "path": "cache/*", "delete_after_days": 14 }
+//   ] }
+// with the days counted from the last read. A file past demote_after_days
+// is held on demote_to, the lowest tier unless it says otherwise, as a
+// tier_policy rule would hold it. One past delete_after_days is deleted by
+// the maintenance loop, but only once delete is true; until then it is
+// only reported. The first rule that matches wins.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TtlPolicy {
+    rules: Vec<TtlRule>,
+    pub delete: bool,
+}
+
+impl TtlPolicy {
+    pub fn from_config(config: &Value) -> Self {
+        let Some(section) = config.get("ttl_policy") else { return Self::default() };
+        let days = |rule: &Value, key: &str| rule.get(key).and_then(Value::as_f64).map(|days| Duration::from_secs_f64(days.max(0.0) * 86400.0));
+        let lowest = DriveManager::TIERS[DriveManager::TIERS.len() - 1];
+        let rules = section.get("rules").and_then(Value::as_array).into_iter().flatten().filter_map(|rule| {
+            let demote_to = match rule.get("demote_to") {
+                Some(tier) => tier.as_str().filter(|tier| DriveManager::TIERS.contains(tier))?,
+                None => lowest,
+            };
+            let rule = TtlRule { files: FileMatch::from_rule(rule), demote_after: days(rule, "demote_after_days"), demote_to: demote_to.to_string(), delete_after: days(rule, "delete_after_days") };
+            (rule.demote_after.is_some() || rule.delete_after.is_some()).then_some(rule)
+        }).collect();
+        Self { rules, delete: section.get("delete").and_then(Value::as_bool).unwrap_or(false) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn rule(&self, path: &Path, size: u64) -> Option<&TtlRule> {
+        self.rules.iter().find(|rule| rule.files.matches(path, size))
+    }
+
+    // The tier a file last read at `accessed` is held on, once its rule's
+    // demote_after has passed
+    pub fn tier_for(&self, path: &Path, size: u64, accessed: SystemTime, now: SystemTime) -> Option<&str> {
+        let rule = self.rule(path, size)?;
+        let demote_after = rule.demote_after?;
+        (now.duration_since(accessed).unwrap_or_default() >= demote_after).then_some(rule.demote_to.as_str())
+    }
+
+    // The files out of `files` whose rule's delete_after has passed, least
+    // recently read first
+    pub fn expired(&self, files: Vec<(PathBuf, FileMetadata)>, now: SystemTime) -> Vec<(PathBuf, FileMetadata)> {
+        let mut expired: Vec<(PathBuf, FileMetadata)> = files.into_iter().filter(|(path, metadata)| {
+            self.rule(path, metadata.file_size).and_then(|rule| rule.delete_after)
+                .is_some_and(|delete_after| now.duration_since(metadata.last_access_time).unwrap_or_default() >= delete_after)
+        }).collect();
+        expired.sort_by(|(a_path, a), (b_path, b)| a.last_access_time.cmp(&b.last_access_time).then_with(|| a_path.cmp(b_path)));
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn file(atime: u64) -> FileMetadata {
+        FileMetadata {
+            last_access_time: SystemTime::UNIX_EPOCH + Duration::from_secs(atime),
+            access_count: 1,
+            file_size: 10,
+            tier: "hot".to_string(),
+            last_tier_move: None,
+        }
+    }
+
+    #[test]
+    fn test_ttl_policy() {
+        let policy = TtlPolicy::from_config(&json!({ "ttl_policy": { "rules": [
+            { "path": "downloads/*", "demote_after_days": 1, "demote_to": "warm", "delete_after_days": 3 },
+            { "path": "cache/*", "delete_after_days": 2 },
+            { "path": "frozen/*", "demote_after_days": 1, "demote_to": "frozen" },
+            { "path": "media/*" },
+        ] } }));
+        assert!(!policy.delete);
+        assert_eq!(policy.rules.len(), 2);
+        let day = 86400;
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(10 * day);
+        let read = |days_ago: u64| now - Duration::from_secs(days_ago * day);
+        assert_eq!(policy.tier_for(Path::new("downloads/a.iso"), 10, read(0), now), None);
+        assert_eq!(policy.tier_for(Path::new("downloads/a.iso"), 10, read(1), now), Some("warm"));
+        assert_eq!(policy.tier_for(Path::new("cache/a"), 10, read(5), now), None);
+        let files = vec![("downloads/a.iso".into(), file(8 * day)), ("cache/b".into(), file(7 * day)), ("downloads/c.iso".into(), file(6 * day)), ("media/d.mkv".into(), file(0))];
+        assert_eq!(policy.expired(files, now).into_iter().map(|(path, _)| path).collect::<Vec<PathBuf>>(), [PathBuf::from("downloads/c.iso"), PathBuf::from("cache/b")]);
+        let lowest = TtlPolicy::from_config(&json!({ "ttl_policy": { "delete": true, "rules": [{ "path": "tmp/*", "demote_after_days": 0 }] } }));
+        assert!(lowest.delete);
+        assert_eq!(lowest.tier_for(Path::new("tmp/a"), 10, now, now), Some("cold"));
+        assert!(TtlPolicy::from_config(&json!({})).is_empty());
+    }
+}
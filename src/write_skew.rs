@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use serde_json::Value;
+use crate::drive_manager::DriveManager;
+use crate::fill_strategy::FillPolicy;
+
+pub const WINDOW_HOURS: f64 = 24.0;
+pub const MAX_RATIO: f64 = 2.0;
+pub const MIN_GB: u64 = 10;
+const GB: u64 = 1 << 30;
+// create policies that send every new file to one branch until it fills
+const CONCENTRATING_POLICIES: [&str; 6] = ["ff", "epff", "lfs", "eplfs", "lus", "eplus"];
+
+// When one drive takes most of its tier's writes, from the bytes each
+// drive wrote over the last window_hours:
+//   "write_skew": { "window_hours": 24, "max_ratio": 2, "min_gb": 10 }
+// A drive writing more than max_ratio times its even share of the tier's
+// writes is skewed, once the tier wrote min_gb in the window at all. An ff
+// create policy does this on its own, wearing one SSD out early or keeping
+// one HDD seeking while the others idle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteSkewPolicy {
+    pub window: Duration,
+    pub max_ratio: f64,
+    pub min_bytes: u64,
+}
+
+// A drive writing far more than its share of its tier's writes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Skewed {
+    pub tier: String,
+    pub serial: String,
+    pub bytes: u64,
+    // how many times its even share the drive wrote
+    pub ratio: f64,
+}
+
+// Moving `bytes` of files from one drive onto another of its tier
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedMove {
+    pub from: String,
+    pub to: String,
+    pub bytes: u64,
+}
+
+impl fmt::Display for PlannedMove {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "move {:.1} GB of files from {} to {}", self.bytes as f64 / GB as f64, self.from, self.to)
+    }
+}
+
+impl Default for WriteSkewPolicy {
+    fn default() -> Self {
+        Self { window: Duration::from_secs_f64(WINDOW_HOURS * 3600.0), max_ratio: MAX_RATIO, min_bytes: MIN_GB << 30 }
+    }
+}
+
+impl WriteSkewPolicy {
+    pub fn from_config(config: &Value) -> Self {
+        let Some(section) = config.get("write_skew") else { return Self::default() };
+        let hours = section.get("window_hours").and_then(Value::as_f64).unwrap_or(WINDOW_HOURS);
+        Self {
+            window: Duration::from_secs_f64(hours.max(0.0) * 3600.0),
+            max_ratio: section.get("max_ratio").and_then(Value::as_f64).unwrap_or(MAX_RATIO),
+            min_bytes: section.get("min_gb").and_then(Value::as_u64).unwrap_or(MIN_GB) << 30,
+        }
+    }
+
+    // The skewed drives, given each tier's drives with the bytes they wrote
+    // in the window. A tier of one drive cannot be skewed.
+    pub fn skewed(&self, tiers: &HashMap<String, Vec<(String, u64)>>) -> Vec<Skewed> {
+        let mut skewed = Vec::new();
+        for (tier, drives) in tiers {
+            let total: u64 = drives.iter().map(|(_, bytes)| bytes).sum();
+            if drives.len() < 2 || total < self.min_bytes.max(1) {
+                continue;
+            }
+            for (serial, bytes) in drives {
+                let ratio = *bytes as f64 * drives.len() as f64 / total as f64;
+                if ratio > self.max_ratio {
+                    skewed.push(Skewed { tier: tier.clone(), serial: serial.clone(), bytes: *bytes, ratio });
+                }
+            }
+        }
+        skewed.sort_by(|a, b| (&a.tier, &a.serial).cmp(&(&b.tier, &b.serial)));
+        skewed
+    }
+}
+
+// The drives that wrote in the window, by their tier, with the bytes each
+// wrote. Drives the registry has no tier for are left out.
+pub fn tier_writes(branch_tiers: &HashMap<String, String>, written: &HashMap<String, u64>) -> HashMap<String, Vec<(String, u64)>> {
+    let mut tiers: HashMap<String, Vec<(String, u64)>> = HashMap::new();
+    for (serial, bytes) in written {
+        if let Some(tier) = branch_tiers.get(serial) {
+            tiers.entry(tier.clone()).or_default().push((serial.clone(), *bytes));
+        }
+    }
+    for drives in tiers.values_mut() {
+        drives.sort();
+    }
+    tiers
+}
+
+// The create policy a tier's pool runs with, the configured
+// category.create over the one its fill strategy gives
+pub fn create_policy(config: &Value, tier: &str) -> String {
+    DriveManager::configured_mergerfs_options(config).into_iter()
+        .find(|(key, _)| key == "category.create")
+        .map_or_else(|| FillPolicy::from_config(config).mergerfs_policy(tier).to_string(), |(_, value)| value)
+}
+
+// What to change so a skewed tier's writes spread out, or None when its
+// create policy already spreads them
+pub fn policy_advice(config: &Value, tier: &str) -> Option<String> {
+    let policy = create_policy(config, tier);
+    CONCENTRATING_POLICIES.contains(&policy.as_str()).then(|| format!(
+        "the {} pool creates files with {}, which fills one drive first; set fill_strategy for {} to most-free-space or round-robin to spread new files",
+        tier, policy, tier,
+    ))
+}
+
+// Moves that even out a tier's data: each drive over its even share of the
+// writes gives up what it wrote past it, to the drives under theirs in
+// proportion to how far under they are
+pub fn rebalance_plan(drives: &[(String, u64)]) -> Vec<PlannedMove> {
+    let total: u64 = drives.iter().map(|(_, bytes)| bytes).sum();
+    if drives.len() < 2 || total == 0 {
+        return Vec::new();
+    }
+    let even = total / drives.len() as u64;
+    let under: Vec<(&String, u64)> = drives.iter().filter(|(_, bytes)| *bytes < even).map(|(serial, bytes)| (serial, even - bytes)).collect();
+    let deficit: u64 = under.iter().map(|(_, bytes)| bytes).sum();
+    let mut plan = Vec::new();
+    for (from, bytes) in drives.iter().filter(|(_, bytes)| *bytes > even) {
+        let excess = bytes - even;
+        for (to, short) in &under {
+            let bytes = (excess as u128 * *short as u128 / deficit as u128) as u64;
+            if bytes > 0 {
+                plan.push(PlannedMove { from: from.clone(), to: to.to_string(), bytes });
+            }
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_skewed() {
+        let policy = WriteSkewPolicy::from_config(&json!({ "write_skew": { "window_hours": 1, "min_gb": 1 } }));
+        assert_eq!((policy.window, policy.max_ratio), (Duration::from_secs(3600), 2.0));
+        let tiers = HashMap::from([
+            ("cold".to_string(), vec![("WD-1".to_string(), 90 * GB), ("WD-2".to_string(), 5 * GB), ("WD-3".to_string(), 5 * GB)]),
+            ("hot".to_string(), vec![("N1".to_string(), 6 * GB), ("N2".to_string(), 4 * GB)]),
+            // too little written to tell
+            ("warm".to_string(), vec![("S1".to_string(), GB / 2), ("S2".to_string(), 0)]),
+        ]);
+        let skewed = policy.skewed(&tiers);
+        assert_eq!(skewed.len(), 1);
+        assert_eq!((skewed[0].tier.as_str(), skewed[0].serial.as_str()), ("cold", "WD-1"));
+        assert!((skewed[0].ratio - 2.7).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tier_writes() {
+        let branch_tiers = HashMap::from([("N1".to_string(), "hot".to_string()), ("WD-2".to_string(), "cold".to_string()), ("WD-1".to_string(), "cold".to_string())]);
+        let written = HashMap::from([("WD-2".to_string(), 5), ("WD-1".to_string(), 7), ("gone".to_string(), 9)]);
+        let tiers = tier_writes(&branch_tiers, &written);
+        assert_eq!(tiers, HashMap::from([("cold".to_string(), vec![("WD-1".to_string(), 7), ("WD-2".to_string(), 5)])]));
+    }
+
+    #[test]
+    fn test_rebalance_plan() {
+        let plan = rebalance_plan(&[("WD-1".to_string(), 90 * GB), ("WD-2".to_string(), 0), ("WD-3".to_string(), 30 * GB)]);
+        assert_eq!(plan, [
+            PlannedMove { from: "WD-1".to_string(), to: "WD-2".to_string(), bytes: 40 * GB },
+            PlannedMove { from: "WD-1".to_string(), to: "WD-3".to_string(), bytes: 10 * GB },
+        ]);
+        assert_eq!(plan[1].to_string(), "move 10.0 GB of files from WD-1 to WD-3");
+        assert!(rebalance_plan(&[("WD-1".to_string(), 10)]).is_empty());
+    }
+
+    #[test]
+    fn test_policy_advice() {
+        assert!(policy_advice(&json!({}), "hot").unwrap().contains("creates files with ff"));
+        assert_eq!(policy_advice(&json!({}), "cold"), None);
+        assert_eq!(policy_advice(&json!({ "fill_strategy": "round-robin" }), "hot"), None);
+        assert!(policy_advice(&json!({ "mergerfs_options": { "category.create": "epff" } }), "cold").is_some());
+    }
+}
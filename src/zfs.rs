@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io;
+use std::time::Duration;
+use serde_json::Value;
+use crate::executor::{checked, Executor};
+use crate::lsblk::BlockDevice;
+
+// how often datasets are reported on, unless report_sec says otherwise
+pub const REPORT_SEC: u64 = 300;
+pub const ZPOOL_MEMBERS_CMD: [&str; 3] = ["zpool", "list", "-vHPL"];
+pub const ZFS_LIST_CMD: [&str; 7] = ["zfs", "list", "-Hp", "-t", "filesystem", "-o", "name,used,avail,mountpoint"];
+pub const ZPOOL_IOSTAT_CMD: [&str; 3] = ["zpool", "iostat", "-Hp"];
+
+// A ZFS dataset as `zfs list` reports it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dataset {
+    pub name: String,
+    pub used: u64,
+    pub available: u64,
+    pub mountpoint: Option<String>,
+}
+
+impl Dataset {
+    pub fn used_percent(&self) -> f64 {
+        let total = self.used + self.available;
+        if total == 0 { 0.0 } else { self.used as f64 * 100.0 / total as f64 }
+    }
+}
+
+// A pool's I/O as `zpool iostat` reports it, averaged since import
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolIo {
+    pub name: String,
+    pub alloc: u64,
+    pub free: u64,
+    pub read_ops: u64,
+    pub write_ops: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+// Drives that belong to a zpool are never formatted or pooled, with or
+// without this section. With it, the service also reports on ZFS datasets
+// and warns when one fills up:
+//   "zfs": { "datasets": ["tank/media"], "max_used_percent": 90, "report_sec": 300 }
+// Without datasets every filesystem dataset is reported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZfsReport {
+    pub datasets: Vec<String>,
+    pub max_used_percent: Option<f64>,
+    pub interval: Duration,
+}
+
+impl ZfsReport {
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let section = config.get("zfs").filter(|section| section.is_object())?;
+        Some(Self {
+            datasets: section.get("datasets").and_then(Value::as_array)
+                .map(|datasets| datasets.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                .unwrap_or_default(),
+            max_used_percent: section.get("max_used_percent").and_then(Value::as_f64),
+            interval: Duration::from_secs(section.get("report_sec").and_then(Value::as_u64).unwrap_or(REPORT_SEC).max(1)),
+        })
+    }
+
+    // The configured datasets, or all of them
+    pub fn datasets(&self, executor: &dyn Executor) -> io::Result<Vec<Dataset>> {
+        let cmd = ZFS_LIST_CMD.map(OsStr::new);
+        let output = checked(&cmd, executor.output(&cmd)?)?;
+        Ok(parse_datasets(&String::from_utf8_lossy(&output.stdout)).into_iter()
+            .filter(|dataset| self.datasets.is_empty() || self.datasets.contains(&dataset.name))
+            .collect())
+    }
+
+    pub fn pool_io(&self, executor: &dyn Executor) -> io::Result<Vec<PoolIo>> {
+        let cmd = ZPOOL_IOSTAT_CMD.map(OsStr::new);
+        let output = checked(&cmd, executor.output(&cmd)?)?;
+        Ok(parse_pool_io(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    // The datasets filled past max_used_percent
+    pub fn over_limit<'a>(&self, datasets: &'a [Dataset]) -> Vec<&'a Dataset> {
+        let Some(limit) = self.max_used_percent else { return Vec::new() };
+        datasets.iter().filter(|dataset| dataset.used_percent() > limit).collect()
+    }
+}
+
+// The device paths in `zpool list -vHPL` output, with links resolved
+pub fn parse_member_paths(zpool_list: &str) -> HashSet<String> {
+    zpool_list.lines()
+        .filter_map(|line| line.split('\t').map(str::trim).find(|field| !field.is_empty()))
+        .filter(|field| field.starts_with("/dev/"))
+        .map(str::to_string)
+        .collect()
+}
+
+// Whether a drive, or one of its partitions, is in a zpool, by its
+// zfs_member signature or by the pools' member list. The signature catches
+// exported pools, the list members lsblk has not probed.
+pub fn is_member(device: &BlockDevice, member_paths: &HashSet<String>) -> bool {
+    [device].into_iter().chain(&device.children)
+        .any(|device| device.fstype.as_deref() == Some("zfs_member") || member_paths.contains(&device.path))
+}
+
+pub fn parse_datasets(zfs_list: &str) -> Vec<Dataset> {
+    zfs_list.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [name, used, available, mountpoint] = fields.as_slice() else { return None };
+        Some(Dataset {
+            name: name.to_string(),
+            used: used.parse().ok()?,
+            available: available.parse().ok()?,
+            mountpoint: mountpoint.starts_with('/').then(|| mountpoint.to_string()),
+        })
+    }).collect()
+}
+
+pub fn parse_pool_io(iostat: &str) -> Vec<PoolIo> {
+    iostat.lines().filter_map(|line| {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let field = |index: usize| fields.get(index)?.parse().ok();
+        Some(PoolIo {
+            name: fields.first().filter(|name| !name.is_empty())?.to_string(),
+            alloc: field(1)?,
+            free: field(2)?,
+            read_ops: field(3)?,
+            write_ops: field(4)?,
+            read_bytes: field(5)?,
+            write_bytes: field(6)?,
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn device(path: &str, fstype: Option<&str>, children: Vec<BlockDevice>) -> BlockDevice {
+        BlockDevice { path: path.to_string(), fstype: fstype.map(str::to_string), children, ..Default::default() }
+    }
+
+    #[test]
+    fn test_is_member() {
+        let members = parse_member_paths("tank\t3985729650688\t1\t3985729650687\t-\t-\t0\t0\t1.00\tONLINE\t-\n\tmirror-0\t3985729650688\t1\t-\n\t/dev/sdb1\t-\t-\t-\n\t/dev/sdc1\t-\t-\t-\n");
+        assert_eq!(members, HashSet::from(["/dev/sdb1".to_string(), "/dev/sdc1".to_string()]));
+        assert!(is_member(&device("/dev/sdb", None, vec![device("/dev/sdb1", None, Vec::new())]), &members));
+        assert!(is_member(&device("/dev/sdd", None, vec![device("/dev/sdd1", Some("zfs_member"), Vec::new())]), &HashSet::new()));
+        assert!(!is_member(&device("/dev/sda", None, vec![device("/dev/sda1", Some("xfs"), Vec::new())]), &members));
+    }
+
+    #[test]
+    fn test_report() {
+        let report = ZfsReport::from_config(&json!({ "zfs": { "datasets": ["tank/media"], "max_used_percent": 90 } })).unwrap();
+        assert_eq!(report.interval, Duration::from_secs(REPORT_SEC));
+        assert!(ZfsReport::from_config(&json!({})).is_none());
+        let datasets = parse_datasets("tank\t950\t50\t/tank\ntank/media\t900\t100\t/tank/media\ntank/vm\t10\t90\tnone\n");
+        assert_eq!(datasets[2].mountpoint, None);
+        assert_eq!(datasets[1].used_percent(), 90.0);
+        assert_eq!(report.over_limit(&datasets).iter().map(|dataset| dataset.name.as_str()).collect::<Vec<_>>(), ["tank"]);
+        assert_eq!(parse_pool_io("tank\t950\t50\t3\t4\t1024\t2048\n"), [PoolIo {
+            name: "tank".to_string(), alloc: 950, free: 50, read_ops: 3, write_ops: 4, read_bytes: 1024, write_bytes: 2048,
+        }]);
+    }
+}